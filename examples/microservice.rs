@@ -0,0 +1,114 @@
+//! A payments microservice built from the library rather than the
+//! `serve` command: an engine from [`Engine::builder`] that spills to a
+//! store and logs rejections and locks, shared by a thread per
+//! connection, with the engine's HTTP routes behind a bearer token.
+//!
+//! ```bash
+//! MICROSERVICE_TOKEN=secret cargo run --example microservice -- 127.0.0.1:8080
+//! curl -H 'Authorization: Bearer secret' --data-binary @test-files/example_input.csv \
+//!     http://127.0.0.1:8080/transactions
+//! curl -H 'Authorization: Bearer secret' http://127.0.0.1:8080/clients/1
+//! ```
+
+use payments_engine::{http::handle_request, prelude::*, spill::DirTransactionStore};
+use std::{
+    io::{BufRead, BufReader, Cursor, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+};
+
+/// Keeps requests without the service's token away from the engine.
+struct BearerAuth {
+    token: String,
+}
+
+impl BearerAuth {
+    /// Reads the headers of a request, answering 401 if they don't carry
+    /// the token. Otherwise returns the headers, to be read again by
+    /// whatever handles the request.
+    fn check(&self, input: &mut impl BufRead, output: &mut impl Write) -> Result<Option<String>> {
+        let mut headers = String::new();
+        let mut authorized = false;
+        loop {
+            let mut header = String::new();
+            input
+                .read_line(&mut header)
+                .map_err(|x| format!("error reading the request: {}", x))?;
+            if let Some((name, value)) = header.split_once(':') {
+                authorized |= name.trim().eq_ignore_ascii_case("authorization")
+                    && value.trim().strip_prefix("Bearer ") == Some(&self.token);
+            }
+            headers.push_str(&header);
+            if header.trim_end().is_empty() {
+                break;
+            }
+        }
+        if authorized {
+            return Ok(Some(headers));
+        }
+        write!(
+            output,
+            "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )
+        .map_err(|x| format!("error answering: {}", x))?;
+        Ok(None)
+    }
+}
+
+fn handle(engine: &Engine, auth: &BearerAuth, stream: TcpStream) -> Result<()> {
+    let mut output = stream.try_clone()?;
+    let mut input = BufReader::new(stream);
+    let mut first = String::new();
+    input.read_line(&mut first)?;
+    match auth.check(&mut input, &mut output)? {
+        Some(headers) => handle_request(
+            engine,
+            &first,
+            BufReader::new(Cursor::new(headers).chain(input)),
+            output,
+        ),
+        None => Ok(()),
+    }
+}
+
+fn main() -> Result<()> {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    let auth = Arc::new(BearerAuth {
+        token: std::env::var("MICROSERVICE_TOKEN").map_err(|_| "MICROSERVICE_TOKEN is not set")?,
+    });
+    let engine = Arc::new(
+        Engine::builder()
+            .precision(4)
+            .overdraft(Overdraft::Deny)
+            .store(DirTransactionStore::open(
+                std::env::temp_dir().join("microservice-spill"),
+            )?)
+            .max_resident_transactions(100_000)
+            .on_reject(|txn, reason| {
+                eprintln!("transaction {} rejected: {}", txn.txn_id(), reason.code())
+            })
+            .on_lock(|account, txn| {
+                eprintln!(
+                    "client {} locked by transaction {}",
+                    account.client_id(),
+                    txn.txn_id()
+                )
+            })
+            .build()?,
+    );
+    let listener = TcpListener::bind(&address)?;
+    eprintln!("listening on {}", address);
+    for stream in listener.incoming() {
+        let (engine, auth) = (engine.clone(), auth.clone());
+        let stream = stream?;
+        thread::spawn(move || {
+            if let Err(x) = handle(&engine, &auth, stream) {
+                eprintln!("connection failed: {}", x);
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,233 @@
+use crate::{Client, ClientId, Engine, Result};
+use std::{collections::BTreeMap, sync::Arc};
+
+/// How many accounts a page holds unless asked otherwise.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+/// A read only copy of a client's account at a point in time.
+///
+/// It is detached from the engine, so holding on to it doesn't lock
+/// anything, and cloning it is cheap.
+pub struct AccountView {
+    client_id: ClientId,
+    available: f64,
+    held: f64,
+    locked: bool,
+    open_disputes: Arc<[u32]>,
+    pending_hold: f64,
+    /// The currency of the funds, unless they are in the account's own.
+    currency: Option<Arc<str>>,
+}
+
+impl AccountView {
+    pub fn from_client(client_id: ClientId, client: &Client) -> Self {
+        let mut open_disputes: Vec<_> = client.disputed.keys().copied().collect();
+        open_disputes.sort();
+        AccountView {
+            client_id,
+            available: client.available,
+            held: client.held,
+            locked: client.locked,
+            open_disputes: open_disputes.into(),
+            pending_hold: client
+                .pending_holds
+                .iter()
+                .map(|(_, pending)| pending)
+                .sum(),
+            currency: None,
+        }
+    }
+
+    /// The account once per currency it has funds in: in its own currency
+    /// first, unless it only has funds in others, then in the others by
+    /// code.
+    pub fn per_currency(client_id: ClientId, client: &Client) -> Vec<Self> {
+        let own = AccountView::from_client(client_id, client);
+        let mut views: Vec<_> = client
+            .currencies
+            .iter()
+            .map(|(currency, balance)| AccountView {
+                available: balance.available,
+                held: balance.held,
+                pending_hold: 0.0,
+                currency: Some(currency.as_ref().into()),
+                ..own.clone()
+            })
+            .collect();
+        if own.total() != 0.0 || views.is_empty() {
+            views.insert(0, own);
+        }
+        views
+    }
+
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    pub fn available(&self) -> f64 {
+        self.available
+    }
+
+    pub fn held(&self) -> f64 {
+        self.held
+    }
+
+    pub fn total(&self) -> f64 {
+        self.available + self.held
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// The transactions currently disputed, in ascending order.
+    pub fn open_disputes(&self) -> &[u32] {
+        &self.open_disputes
+    }
+
+    /// What disputes still have to hold once funds come in, see
+    /// [`crate::policy::Policy::pending_holds`].
+    pub fn pending_hold(&self) -> f64 {
+        self.pending_hold
+    }
+
+    /// The currency of the funds, `None` for the account's own.
+    pub fn currency(&self) -> Option<&str> {
+        self.currency.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Which accounts [`Engine::accounts`] lists, and where the page starts.
+pub struct AccountQuery {
+    /// The cursor of the previous page, see [`AccountPage::next`]. The
+    /// first page if unset.
+    pub after: Option<ClientId>,
+    /// The most accounts the page holds.
+    pub limit: usize,
+    /// Only locked accounts.
+    pub locked: bool,
+    /// Only accounts with open disputes.
+    pub disputed: bool,
+    /// Only accounts whose total is at least this.
+    pub min_balance: Option<f64>,
+}
+
+impl Default for AccountQuery {
+    fn default() -> Self {
+        AccountQuery {
+            after: None,
+            limit: DEFAULT_PAGE_SIZE,
+            locked: false,
+            disputed: false,
+            min_balance: None,
+        }
+    }
+}
+
+impl AccountQuery {
+    fn matches(&self, account: &AccountView) -> bool {
+        (!self.locked || account.locked())
+            && (!self.disputed || !account.open_disputes().is_empty())
+            && self.min_balance.is_none_or(|min| account.total() >= min)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// One page of accounts, ordered by client.
+pub struct AccountPage {
+    pub accounts: Vec<AccountView>,
+    /// The cursor to ask for the next page with, as
+    /// [`AccountQuery::after`]. Unset on the last page.
+    pub next: Option<ClientId>,
+}
+
+impl Engine {
+    /// The account of a client, if the client exists. An evicted client is
+    /// read from the cold store without being loaded back.
+    pub fn account(&self, client_id: ClientId) -> Result<Option<AccountView>> {
+        if let Some(client) = self.db.clients.get(&client_id) {
+            return Ok(Some(AccountView::from_client(client_id, &client)));
+        }
+        Ok(match &self.db.cold {
+            Some(cold) => cold
+                .store
+                .get(client_id)?
+                .map(|client| AccountView::from_client(client_id, &client)),
+            None => None,
+        })
+    }
+
+    /// A page of the accounts matching `query`, in client order, evicted
+    /// clients included.
+    ///
+    /// The cursor is the last client of a page rather than an offset, so
+    /// paging through stays consistent while accounts are added: a client
+    /// is never listed twice, and clients added behind the cursor are
+    /// simply not seen.
+    pub fn accounts(&self, query: &AccountQuery) -> Result<AccountPage> {
+        let mut matching = BTreeMap::new();
+        self.db.for_each_client(|client_id, client| {
+            if query.after.is_none_or(|after| client_id > after) {
+                let account = AccountView::from_client(client_id, client);
+                if query.matches(&account) {
+                    matching.insert(client_id, account);
+                }
+            }
+        })?;
+        let limit = query.limit.max(1);
+        let more = matching.len() > limit;
+        let accounts: Vec<_> = matching.into_values().take(limit).collect();
+        Ok(AccountPage {
+            next: accounts
+                .last()
+                .filter(|_| more)
+                .map(|account| account.client_id()),
+            accounts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transaction;
+
+    #[test]
+    fn pages_through_matching_accounts() -> Result<()> {
+        let engine = Engine::default();
+        for client in 1..=5 {
+            engine.process(Transaction::deposit(client, client as u32, client as f64))?;
+        }
+        engine.process(Transaction::dispute(2, 2))?;
+        engine.process(Transaction::dispute(4, 4))?;
+        let ids = |page: &AccountPage| {
+            page.accounts
+                .iter()
+                .map(|a| a.client_id())
+                .collect::<Vec<_>>()
+        };
+
+        let mut query = AccountQuery {
+            limit: 2,
+            ..AccountQuery::default()
+        };
+        let first = engine.accounts(&query)?;
+        assert_eq!((ids(&first), first.next), (vec![1, 2], Some(2)));
+        query.after = first.next;
+        let second = engine.accounts(&query)?;
+        assert_eq!((ids(&second), second.next), (vec![3, 4], Some(4)));
+        query.after = second.next;
+        let last = engine.accounts(&query)?;
+        assert_eq!((ids(&last), last.next), (vec![5], None));
+
+        let disputed = engine.accounts(&AccountQuery {
+            disputed: true,
+            min_balance: Some(3.0),
+            ..AccountQuery::default()
+        })?;
+        assert_eq!((ids(&disputed), disputed.next), (vec![4], None));
+        Ok(())
+    }
+}
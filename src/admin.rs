@@ -0,0 +1,142 @@
+use crate::{
+    clock::{Clock, SystemClock, Timestamp},
+    labels::Labels,
+    ClientId, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+};
+
+/// Where admin operations are recorded unless `--admin-log` says
+/// otherwise.
+pub const DEFAULT_ADMIN_LOG: &str = "admin_audit.jsonl";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// An admin operation, and who performed it.
+pub struct AdminEntry {
+    pub timestamp: Timestamp,
+    pub operator: String,
+    /// e.g. `unlock`, `opening_balance` or `close_period`.
+    pub operation: String,
+    /// The client the operation changed, if it was about one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<ClientId>,
+    /// What the operation was applied to, such as a state file.
+    pub target: String,
+    /// The labels of the run that performed the operation.
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+}
+
+/// The append-only log of admin operations, one JSON line each.
+///
+/// Opening it needs the identity of the operator, so an admin operation
+/// without one is refused before it changes anything.
+pub struct AdminLog {
+    path: String,
+    operator: String,
+    labels: Labels,
+    file: File,
+}
+
+impl AdminLog {
+    /// Opens the log at `path` for `operator`, creating it if needed.
+    pub fn open(path: &str, operator: Option<&str>) -> Result<Self> {
+        let operator = operator
+            .map(str::trim)
+            .filter(|operator| !operator.is_empty())
+            .ok_or("admin operations need the --operator performing them")?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|x| format!("error opening {}: {}", path, x))?;
+        Ok(AdminLog {
+            path: path.to_string(),
+            operator: operator.to_string(),
+            labels: Labels::default(),
+            file,
+        })
+    }
+
+    /// Tags every entry with `labels`.
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Who is performing the operations.
+    pub fn operator(&self) -> &str {
+        &self.operator
+    }
+
+    /// Records that the operator performed `operation` on `target`, and
+    /// on `client` if given.
+    pub fn record(
+        &mut self,
+        operation: &str,
+        client: Option<ClientId>,
+        target: &str,
+    ) -> Result<()> {
+        let entry = AdminEntry {
+            timestamp: SystemClock.now(),
+            operator: self.operator.clone(),
+            operation: operation.to_string(),
+            client,
+            target: target.to_string(),
+            labels: self.labels.clone(),
+        };
+        let line = serde_json::to_string(&entry).map_err(|x| x.to_string())?;
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.sync_all())
+            .map_err(|x| format!("error writing {}: {}", self.path, x))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_name_the_operator() -> Result<()> {
+        let path = crate::temp_path("payments-engine-admin-test.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_string_lossy().to_string();
+        assert!(AdminLog::open(&path, None).is_err());
+        assert!(AdminLog::open(&path, Some(" ")).is_err());
+
+        AdminLog::open(&path, Some("alice"))?.record("unlock", Some(7), "state.json")?;
+        let labels: Labels = [("env".to_string(), "staging".to_string())]
+            .into_iter()
+            .collect();
+        AdminLog::open(&path, Some("bob"))?
+            .with_labels(labels.clone())
+            .record("close_period", None, "state/")?;
+        let entries: Vec<AdminEntry> = std::fs::read_to_string(&path)
+            .map_err(|x| x.to_string())?
+            .lines()
+            .map(|line| serde_json::from_str(line).map_err(|x| x.to_string().into()))
+            .collect::<Result<_>>()?;
+        let who: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.operator.as_str(),
+                    entry.operation.as_str(),
+                    entry.client,
+                )
+            })
+            .collect();
+        assert_eq!(
+            who,
+            [("alice", "unlock", Some(7)), ("bob", "close_period", None)]
+        );
+        assert!(entries[0].labels.is_empty());
+        assert_eq!(entries[1].labels, labels);
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use crate::{Result, TransactionType};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Buckets per power of ten. Neighbouring bucket bounds are about 26%
+/// apart, close enough to tell cents from dollars at a glance.
+const PER_DECADE: i32 = 10;
+
+/// The bound of the first bucket, the smallest amount the engine keeps
+/// apart from zero.
+const SMALLEST: f64 = 0.0001;
+
+/// Enough buckets to reach amounts of a trillion.
+const BUCKETS: usize = 16 * PER_DECADE as usize + 1;
+
+#[derive(Debug)]
+/// A histogram of amounts on a log scale, updated without locks. The
+/// first bucket counts amounts up to [`SMALLEST`], the last those above
+/// the bound of the one before it.
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    /// The bits of the smallest and largest amounts seen.
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            min: AtomicU64::new(f64::INFINITY.to_bits()),
+            max: AtomicU64::new(f64::NEG_INFINITY.to_bits()),
+        }
+    }
+}
+
+/// The upper bound of bucket `i`, to four significant digits, the last
+/// bucket's being infinite.
+fn bound(i: usize) -> f64 {
+    if i == BUCKETS - 1 {
+        return f64::INFINITY;
+    }
+    let bound = SMALLEST * 10f64.powf(i as f64 / PER_DECADE as f64);
+    let scale = 10f64.powi(3 - bound.log10().floor() as i32);
+    (bound * scale).round() / scale
+}
+
+/// Replaces the amount in `bits` with `pick` of it and `amount`.
+fn keep(bits: &AtomicU64, amount: f64, pick: fn(f64, f64) -> f64) {
+    let _ = bits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+        let new = pick(f64::from_bits(old), amount);
+        (new.to_bits() != old).then_some(new.to_bits())
+    });
+}
+
+impl Histogram {
+    pub fn record(&self, amount: f64) {
+        let i = match amount > SMALLEST {
+            // Rounded so an amount sitting on a bound isn't pushed into
+            // the next bucket by floating point error.
+            true => ((amount / SMALLEST).log10() * PER_DECADE as f64 - 1e-9).ceil() as usize,
+            false => 0,
+        };
+        self.buckets[i.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        keep(&self.min, amount, f64::min);
+        keep(&self.max, amount, f64::max);
+    }
+
+    /// Adds the amounts of `other` to these.
+    pub fn absorb(&self, other: &Histogram) {
+        for (count, more) in self.buckets.iter().zip(&other.buckets) {
+            count.fetch_add(more.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        let min = f64::from_bits(other.min.load(Ordering::Relaxed));
+        let max = f64::from_bits(other.max.load(Ordering::Relaxed));
+        keep(&self.min, min, f64::min);
+        keep(&self.max, max, f64::max);
+    }
+
+    /// The upper bound and count of every bucket holding amounts.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (bound(i), count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    /// A summary of the amounts recorded, none if there are none.
+    pub fn stats(&self) -> Option<AmountStats> {
+        let buckets = self.buckets();
+        let count: u64 = buckets.iter().map(|(_, count)| count).sum();
+        if count == 0 {
+            return None;
+        }
+        let min = f64::from_bits(self.min.load(Ordering::Relaxed));
+        let max = f64::from_bits(self.max.load(Ordering::Relaxed));
+        let quantile = |q: f64| {
+            let rank = (q * count as f64).ceil().max(1.0) as u64;
+            let mut seen = 0;
+            let (bound, _) = buckets
+                .iter()
+                .find(|(_, n)| {
+                    seen += n;
+                    seen >= rank
+                })
+                .copied()
+                .unwrap_or((max, 0));
+            bound.clamp(min, max)
+        };
+        Some(AmountStats {
+            count,
+            min,
+            median: quantile(0.5),
+            p95: quantile(0.95),
+            max,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// How the amounts of one transaction type are distributed. The minimum
+/// and maximum are exact, the median and 95th percentile are the upper
+/// bound of the histogram bucket they fall in.
+pub struct AmountStats {
+    pub count: u64,
+    pub min: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+/// Writes the histograms of `amounts` as csv to `path`, a row per bucket
+/// holding amounts with the transaction type, the bucket's upper bound
+/// and its count.
+pub fn write_histograms(amounts: &[(TransactionType, Vec<(f64, u64)>)], path: &str) -> Result<()> {
+    let error = |x: std::io::Error| format!("error writing {}: {}", path, x);
+    let mut out = BufWriter::new(File::create(path).map_err(error)?);
+    writeln!(out, "type,le,count").map_err(error)?;
+    for (transaction_type, buckets) in amounts {
+        for (bound, count) in buckets {
+            writeln!(out, "{},{},{}", transaction_type.as_str(), bound, count).map_err(error)?;
+        }
+    }
+    out.flush().map_err(error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amounts_are_summarized_on_a_log_scale() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.stats(), None);
+        for amount in [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 1000.0] {
+            histogram.record(amount);
+        }
+        let stats = histogram.stats().expect("amounts were recorded");
+        assert_eq!((stats.count, stats.min, stats.max), (10, 1.0, 1000.0));
+        assert!((5.0..5.0 * 1.26).contains(&stats.median), "{:?}", stats);
+        assert_eq!(stats.p95, 1000.0);
+        // 1.0 sits on a bound and stays in its bucket.
+        assert_eq!(histogram.buckets()[0], (bound(40), 1));
+
+        let other = Histogram::default();
+        other.record(0.5);
+        histogram.absorb(&other);
+        let stats = histogram.stats().expect("amounts were recorded");
+        assert_eq!((stats.count, stats.min), (11, 0.5));
+    }
+}
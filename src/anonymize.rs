@@ -0,0 +1,152 @@
+use crate::{digest::salted_hash, generator::Rng, state::StateFile, ClientId};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq)]
+/// How a state is anonymized for sharing outside the company.
+pub struct Anonymize {
+    /// Keys the client pseudonyms. Keep it secret, anyone holding it can
+    /// map pseudonyms back to client ids.
+    pub salt: String,
+    /// Scales every amount by a random factor within this fraction of 1,
+    /// e.g. `0.05` for ±5%.
+    pub perturb: Option<f64>,
+    /// Rounds every amount, after perturbing it, to a multiple of this.
+    pub bucket: Option<f64>,
+}
+
+/// A salt for a one off export, from the time and process id.
+pub fn random_salt() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let seed = salted_hash(&nanos.to_string(), &std::process::id().to_le_bytes());
+    format!("{:016x}", Rng::new(seed).next_u64())
+}
+
+/// The pseudonym of `client_id` under `salt`.
+///
+/// The salted hash of the id is applied as the rounds of a small Feistel
+/// network, which makes it a permutation of all client ids: no two clients
+/// share a pseudonym, and the anonymized state still loads. Ids below 2^16
+/// get pseudonyms below 2^16, as they did before ids were wider, and the
+/// others pseudonyms that aren't.
+pub fn pseudonym(salt: &str, client_id: ClientId) -> ClientId {
+    let short = u16::MAX as ClientId;
+    if client_id <= short {
+        let [mut left, mut right] = (client_id as u16).to_be_bytes();
+        for round in 0..4u8 {
+            let mixed = left ^ salted_hash(salt, &[round, right]) as u8;
+            left = right;
+            right = mixed;
+        }
+        return u16::from_be_bytes([left, right]).into();
+    }
+    // Walking the permutation of every id until it leaves the short ones
+    // is a permutation of the others.
+    let mut id = client_id;
+    loop {
+        let (mut left, mut right) = ((id >> 32) as u32, id as u32);
+        for round in 0..4u8 {
+            let mut input = [round; 5];
+            input[1..].copy_from_slice(&right.to_be_bytes());
+            let mixed = left ^ salted_hash(salt, &input) as u32;
+            left = right;
+            right = mixed;
+        }
+        id = (ClientId::from(left) << 32) | ClientId::from(right);
+        if id > short {
+            return id;
+        }
+    }
+}
+
+/// Anonymizes `state`: client ids are replaced by their [`pseudonym`],
+/// amounts perturbed and bucketed as asked, and memos, which are free text
+/// that may name people, dropped. Unpublished events are dropped too, they
+/// are delivery bookkeeping rather than data.
+///
+/// Perturbed balances no longer add up to the transactions behind them,
+/// so the result is for analysis, not for loading back into production.
+pub fn anonymize(mut state: StateFile, options: &Anonymize) -> StateFile {
+    let mut rng = Rng::new(salted_hash(&options.salt, b"perturb"));
+    let mut amount = |amount: f64| {
+        let mut amount = match options.perturb {
+            Some(perturb) => {
+                let unit = (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+                amount * (1.0 + perturb * (2.0 * unit - 1.0))
+            }
+            None => amount,
+        };
+        if let Some(bucket) = options.bucket.filter(|bucket| *bucket > 0.0) {
+            amount = (amount / bucket).round() * bucket;
+        }
+        amount
+    };
+    for account in &mut state.accounts {
+        account.client = pseudonym(&options.salt, account.client);
+        account.available = amount(account.available);
+        account.held = amount(account.held);
+    }
+    state.accounts.sort_by_key(|account| account.client);
+    for txn in &mut state.transactions {
+        txn.client = pseudonym(&options.salt, txn.client);
+        txn.amount = txn.amount.map(&mut amount);
+        txn.memo = None;
+    }
+    state.outbox.clear();
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{open_file_read_csv, run_engine, state::export_state, Engine};
+    use std::collections::HashSet;
+
+    #[test]
+    fn pseudonyms_are_distinct_and_keyed() {
+        let all: HashSet<_> = (0..=u16::MAX as ClientId)
+            .map(|id| pseudonym("pepper", id))
+            .collect();
+        assert_eq!(all.len(), 1 << 16);
+        let wide: HashSet<_> = (1 << 16..1 << 17)
+            .chain(ClientId::MAX - 1000..=ClientId::MAX)
+            .map(|id| pseudonym("pepper", id))
+            .collect();
+        assert_eq!(wide.len(), (1 << 16) + 1001);
+        assert!(wide.iter().all(|id| *id > u16::MAX as ClientId));
+        assert_eq!(pseudonym("pepper", 7), pseudonym("pepper", 7));
+        assert_ne!(
+            (1..100)
+                .map(|id| pseudonym("pepper", id))
+                .collect::<Vec<_>>(),
+            (1..100).map(|id| pseudonym("salt", id)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn anonymized_state_hides_clients_and_exact_amounts() -> crate::Result<()> {
+        let engine = Engine::default();
+        run_engine(
+            open_file_read_csv("test-files/example_input.csv".to_string())?,
+            &engine,
+        )?;
+        let state = export_state(&engine.db)?;
+        let options = Anonymize {
+            salt: "pepper".to_string(),
+            perturb: Some(0.1),
+            bucket: Some(5.0),
+        };
+        let anonymized = anonymize(state.clone(), &options);
+        assert_eq!(anonymized.accounts.len(), state.accounts.len());
+        for (before, after) in state.transactions.iter().zip(&anonymized.transactions) {
+            assert_eq!(after.tx, before.tx);
+            assert_eq!(after.client, pseudonym("pepper", before.client));
+            assert!(after.amount.is_some_and(|amount| amount % 5.0 == 0.0));
+            assert_eq!(after.memo, None);
+        }
+        // The same salt gives the same dataset.
+        assert_eq!(anonymize(state, &options), anonymized);
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+use crate::{
+    state::{
+        export_state, import_state, read_json, read_state, write_state, write_state_compressed,
+        AccountState, StateFile,
+    },
+    store::{DirStore, StateStore},
+    ClientId, Engine, Result, Transaction,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// The state carried from one run to the next, inside the append directory.
+/// With deltas, the last full snapshot.
+pub const STATE_FILE: &str = "state.json";
+/// The transactions of the latest run that conflicted with earlier runs.
+pub const CONFLICTS_FILE: &str = "conflicts.csv";
+
+#[derive(Debug, Default)]
+/// What is known about the runs before this one in append mode.
+pub struct PreviousRuns {
+    /// Ids of the transactions processed by earlier runs.
+    txn_ids: HashSet<u32>,
+    /// Transactions of this run whose id was already used by an earlier run.
+    conflicts: Mutex<Vec<Transaction>>,
+    /// The accounts as earlier runs left them, to tell which ones this run
+    /// changed.
+    accounts: HashMap<ClientId, AccountState>,
+    /// Whether earlier runs saved any state, which deltas can go on top of.
+    saved: bool,
+    /// The generation of the last full snapshot, and how many deltas were
+    /// written on top of it.
+    generation: u64,
+    deltas: usize,
+}
+
+impl PreviousRuns {
+    /// Whether an earlier run already processed a transaction with this id.
+    pub fn used(&self, txn_id: u32) -> bool {
+        self.txn_ids.contains(&txn_id)
+    }
+
+    /// Keeps a conflicting transaction to be reported at the end of the run.
+    pub fn conflict(&self, txn: Transaction) {
+        if let Ok(mut conflicts) = self.conflicts.lock() {
+            conflicts.push(txn);
+        }
+    }
+}
+
+/// The file of the `n`th delta on top of the full snapshot `generation`.
+pub fn delta_file(generation: u64, n: usize) -> String {
+    format!("state.{}.delta-{:04}.json", generation, n)
+}
+
+/// The generation of the full snapshot in `dir`, without loading it.
+pub fn generation(dir: &Path) -> Result<u64> {
+    #[derive(Deserialize)]
+    struct Header {
+        #[serde(default)]
+        generation: u64,
+    }
+    Ok(read_json::<Header>(&dir.join(STATE_FILE))?.generation)
+}
+
+/// The deltas on top of the full snapshot `generation` in `dir`, in order.
+fn deltas(dir: &Path, generation: u64) -> Result<Vec<PathBuf>> {
+    let mut deltas = Vec::new();
+    while dir.join(delta_file(generation, deltas.len() + 1)).exists() {
+        deltas.push(dir.join(delta_file(generation, deltas.len() + 1)));
+    }
+    Ok(deltas)
+}
+
+/// The newest file of the chain in `dir`: the last delta, or the full
+/// snapshot if there are none. `None` before the first run.
+pub fn latest_state_file(dir: &Path) -> Result<Option<PathBuf>> {
+    if !dir.join(STATE_FILE).exists() {
+        return Ok(None);
+    }
+    let deltas = deltas(dir, generation(dir)?)?;
+    Ok(Some(deltas.last().cloned().unwrap_or(dir.join(STATE_FILE))))
+}
+
+/// Applies a delta on top of `state`: its accounts replace the ones with
+/// the same client, its transactions are added, and its outbox is the
+/// outbox as of the delta.
+pub fn merge(state: &mut StateFile, delta: StateFile) {
+    let mut accounts: BTreeMap<_, _> = std::mem::take(&mut state.accounts)
+        .into_iter()
+        .map(|account| (account.client, account))
+        .collect();
+    accounts.extend(
+        delta
+            .accounts
+            .into_iter()
+            .map(|account| (account.client, account)),
+    );
+    state.accounts = accounts.into_values().collect();
+    let mut transactions: BTreeMap<_, _> = std::mem::take(&mut state.transactions)
+        .into_iter()
+        .map(|txn| (txn.tx, txn))
+        .collect();
+    transactions.extend(delta.transactions.into_iter().map(|txn| (txn.tx, txn)));
+    state.transactions = transactions.into_values().collect();
+    state.transfers.extend(delta.transfers);
+    state.transfers.sort();
+    state.transfers.dedup();
+    state.outbox = delta.outbox;
+}
+
+/// Reads the full snapshot in `dir` with every delta on top of it applied,
+/// and the number of deltas. `None` before the first run.
+pub fn read_chain(dir: &Path) -> Result<Option<(StateFile, usize)>> {
+    let path = dir.join(STATE_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut state = read_state(path.to_string_lossy().to_string())?;
+    let deltas = deltas(dir, state.generation)?;
+    for delta in &deltas {
+        let delta = read_state(delta.to_string_lossy().to_string())?;
+        if delta.generation != state.generation {
+            return Err(format!(
+                "delta of generation {} found on top of generation {}",
+                delta.generation, state.generation
+            )
+            .into());
+        }
+        merge(&mut state, delta);
+    }
+    Ok(Some((state, deltas.len())))
+}
+
+/// Writes `state` next to `path` and renames it over it, so an interrupted
+/// write leaves the old file intact. With `compression`, the file is
+/// compressed with zstd at that level.
+pub fn replace_state(state: &StateFile, path: &Path, compression: Option<u32>) -> Result<()> {
+    let staged = path.with_extension("json.tmp");
+    match compression {
+        Some(level) => write_state_compressed(state, &staged, level)?,
+        None => write_state(state, Some(staged.to_string_lossy().to_string()))?,
+    }
+    fs::rename(&staged, path)
+        .map_err(|x| format!("error saving state to {}: {}", path.display(), x).into())
+}
+
+/// Loads the state left in `dir` by earlier runs into an empty engine, so
+/// the new run continues from it. A missing directory or state file is a
+/// first run and starts from nothing.
+pub fn load_previous_runs(engine: &mut Engine, dir: &Path) -> Result<()> {
+    load_from(engine, &DirStore::new(dir, None))
+}
+
+/// Loads the state left in `store` by earlier runs into an empty engine,
+/// see [`load_previous_runs`].
+pub fn load_from(engine: &mut Engine, store: &dyn StateStore) -> Result<()> {
+    let loaded = store.load()?;
+    let saved = loaded.is_some();
+    let (accounts, generation, deltas) = match loaded {
+        Some((state, deltas)) => {
+            let accounts = state
+                .accounts
+                .iter()
+                .map(|account| (account.client, account.clone()))
+                .collect();
+            let generation = state.generation;
+            import_state(engine, state)?;
+            (accounts, generation, deltas)
+        }
+        None => Default::default(),
+    };
+    let txn_ids = engine
+        .db
+        .transactions
+        .iter()
+        .map(|txn| *txn.key())
+        .collect();
+    engine.db.previous = Some(PreviousRuns {
+        saved,
+        txn_ids,
+        conflicts: Mutex::default(),
+        accounts,
+        generation,
+        deltas,
+    });
+    Ok(())
+}
+
+/// Writes `state` as the new full snapshot in `dir`, replacing the chain
+/// of `deltas` deltas on top of the snapshot of its generation.
+pub fn write_snapshot(
+    dir: &Path,
+    mut state: StateFile,
+    deltas: usize,
+    compression: Option<u32>,
+) -> Result<()> {
+    let generation = state.generation;
+    // A new generation leaves the deltas of the old one behind.
+    if deltas > 0 {
+        state.generation += 1;
+    }
+    replace_state(&state, &dir.join(STATE_FILE), compression)?;
+    for delta in self::deltas(dir, generation)? {
+        fs::remove_file(&delta)
+            .map_err(|x| format!("error removing {}: {}", delta.display(), x))?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+/// How [`save_run`] writes the state.
+pub struct SaveOptions {
+    /// Save deltas, with a full snapshot once this many have piled up.
+    pub rebaseline_every: Option<usize>,
+    /// Compress the files with zstd at this level.
+    pub compression: Option<u32>,
+    /// Stage the files and move them into place together once all are
+    /// written, see [`DirStore::with_staging`].
+    pub atomic: bool,
+}
+
+/// Saves the state of this run to `dir` for the next one, along with the
+/// transactions that conflicted with earlier runs. Returns how many
+/// transactions conflicted.
+///
+/// With [`SaveOptions::rebaseline_every`], only the accounts this run changed and the
+/// transactions it added are saved, as a delta on top of the chain left
+/// by earlier runs, until that many deltas have piled up and a new full
+/// snapshot replaces the chain. Without it, every run saves a full
+/// snapshot. Files are written next to their final name and renamed, so
+/// an interrupted save leaves the previous state intact.
+pub fn save_run(engine: &Engine, dir: &Path, options: SaveOptions) -> Result<usize> {
+    let mut store = DirStore::new(dir, options.compression);
+    if options.atomic {
+        store = store.with_staging();
+    }
+    save_to(engine, &store, options.rebaseline_every)
+}
+
+/// Saves the state of this run to `store` for the next one, see
+/// [`save_run`].
+pub fn save_to(
+    engine: &Engine,
+    store: &dyn StateStore,
+    rebaseline_every: Option<usize>,
+) -> Result<usize> {
+    let mut state = export_state(&engine.db)?;
+    let previous = engine.db.previous.as_ref();
+    let (generation, deltas) = previous.map_or((0, 0), |p| (p.generation, p.deltas));
+    state.generation = generation;
+    match (previous, rebaseline_every) {
+        (Some(previous), Some(every)) if deltas < every && previous.saved => {
+            state
+                .accounts
+                .retain(|account| previous.accounts.get(&account.client) != Some(account));
+            state.transactions.retain(|txn| !previous.used(txn.tx));
+            store.save_delta(&state, deltas + 1)?;
+        }
+        _ => store.save_snapshot(state, deltas)?,
+    }
+
+    let conflicts: Vec<_> = match &engine.db.previous {
+        Some(previous) => previous
+            .conflicts
+            .lock()
+            .map_err(|_| "conflicts lock poisoned")?
+            .drain(..)
+            .collect(),
+        None => Vec::new(),
+    };
+    store.save_conflicts(&conflicts)?;
+    store.commit()?;
+    Ok(conflicts.len())
+}
+
+/// Writes `conflicts` as csv to `path`, in the layout of an input.
+pub fn write_conflicts(conflicts: &[Transaction], path: &Path) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(["type", "client", "tx", "amount"])?;
+    for txn in conflicts {
+        writer.write_record([
+            txn.transaction_type.as_str().to_string(),
+            txn.client_id.to_string(),
+            txn.txn_id.to_string(),
+            txn.amount.map(|x| x.to_string()).unwrap_or_default(),
+        ])?;
+    }
+    writer
+        .flush()
+        .map_err(|x| format!("error writing conflicts: {}", x))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, state::is_compressed};
+
+    fn every(deltas: usize) -> SaveOptions {
+        SaveOptions {
+            rebaseline_every: Some(deltas),
+            ..SaveOptions::default()
+        }
+    }
+
+    #[test]
+    fn deltas_chain_until_rebaselined() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-delta-test");
+        let _ = fs::remove_dir_all(&dir);
+        let reference = Engine::with_clock(SimulatedClock::new(0));
+        let runs = [
+            vec![
+                Transaction::deposit(1, 1, 2.0),
+                Transaction::deposit(2, 2, 3.0),
+            ],
+            vec![Transaction::withdrawal(1, 3, 1.0)],
+            vec![Transaction::dispute(2, 2)],
+            vec![Transaction::deposit(3, 4, 1.0)],
+        ];
+        for (run, txns) in runs.into_iter().enumerate() {
+            let mut engine = Engine::with_clock(SimulatedClock::new(0));
+            load_previous_runs(&mut engine, &dir)?;
+            for txn in txns {
+                reference.process(txn.clone())?;
+                engine.process(txn)?;
+            }
+            save_run(&engine, &dir, every(2))?;
+            let (state, deltas) = read_chain(&dir)?.expect("state was saved");
+            assert_eq!(deltas, [0, 1, 2, 0][run]);
+            assert_eq!(state.generation, [0, 0, 0, 1][run]);
+            assert_eq!(state.accounts, export_state(&reference.db)?.accounts);
+            assert_eq!(
+                state.transactions,
+                export_state(&reference.db)?.transactions
+            );
+        }
+        // A delta only holds what its run changed.
+        let mut engine = Engine::with_clock(SimulatedClock::new(0));
+        load_previous_runs(&mut engine, &dir)?;
+        engine.process(Transaction::deposit(1, 5, 1.0))?;
+        save_run(&engine, &dir, every(2))?;
+        let delta = read_state(dir.join(delta_file(1, 1)).to_string_lossy().to_string())?;
+        assert_eq!(delta.accounts.len(), 1);
+        assert_eq!(delta.transactions.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_state_reads_back() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-compressed-test");
+        let _ = fs::remove_dir_all(&dir);
+        let engine = Engine::with_clock(SimulatedClock::new(0));
+        engine.process(Transaction::deposit(1, 1, 2.0))?;
+        engine.process(Transaction::dispute(1, 1))?;
+        let options = SaveOptions {
+            compression: Some(5),
+            ..SaveOptions::default()
+        };
+        save_run(&engine, &dir, options)?;
+        let path = dir.join(STATE_FILE);
+        assert!(is_compressed(&path)?);
+        let (state, _) = read_chain(&dir)?.expect("state was saved");
+        assert_eq!(state, export_state(&engine.db)?);
+        Ok(())
+    }
+}
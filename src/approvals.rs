@@ -0,0 +1,222 @@
+use crate::{
+    clock::Timestamp,
+    locked::{is_reviewable, Decision},
+    ClientId, Database, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// An unlock waiting for a second operator to approve it.
+pub struct Approval {
+    pub id: u64,
+    pub client: ClientId,
+    /// The account's total when the unlock was requested.
+    pub total: f64,
+    pub requested_by: String,
+    pub requested_at: Timestamp,
+    /// The unlock can no longer be approved from this time on.
+    pub expires_at: Timestamp,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The unlocks of a state file waiting for approval, kept next to it.
+pub struct Approvals {
+    /// The id of the last request, so ids are never reused.
+    next_id: u64,
+    pub pending: Vec<Approval>,
+}
+
+/// The approvals file of the state file at `state`, e.g.
+/// `state.approvals.json` for `state.json`.
+pub fn approvals_path(state: &Path) -> PathBuf {
+    state.with_extension("approvals.json")
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+/// Review decisions, split by whether they need a second operator.
+pub struct Split {
+    /// Applied right away.
+    pub immediate: Vec<(ClientId, Decision)>,
+    /// Unlocks waiting for approval, with each account's total.
+    pub deferred: Vec<(ClientId, f64)>,
+}
+
+/// Splits review decisions into those applied right away and the
+/// unlocks of accounts holding more than `threshold`, which need a second
+/// operator's approval.
+///
+/// Fails if any decision names an account that isn't awaiting review.
+pub fn split_decisions(
+    db: &Database,
+    decisions: Vec<(ClientId, Decision)>,
+    threshold: Option<f64>,
+) -> Result<Split> {
+    let mut split = Split::default();
+    for (client_id, decision) in decisions {
+        if !is_reviewable(db, client_id) {
+            return Err(format!(
+                "client {} is not a locked account awaiting review, no decisions were applied",
+                client_id
+            )
+            .into());
+        }
+        let total = db
+            .clients
+            .get(&client_id)
+            .map_or(0.0, |client| client.available + client.held);
+        match threshold {
+            Some(threshold) if decision == Decision::Unlock && total > threshold => {
+                split.deferred.push((client_id, total))
+            }
+            _ => split.immediate.push((client_id, decision)),
+        }
+    }
+    Ok(split)
+}
+
+impl Approvals {
+    /// Reads the approvals at `path`, none if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(x) if x.kind() == std::io::ErrorKind::NotFound => return Ok(Approvals::default()),
+            Err(x) => return Err(format!("error opening {}: {}", path.display(), x).into()),
+        };
+        Ok(serde_json::from_reader(BufReader::new(file))
+            .map_err(|x| format!("{} is not a valid approvals file: {}", path.display(), x))?)
+    }
+
+    /// Writes the approvals to `path`, replacing it.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(|x| x.to_string())?;
+        let error = |x: std::io::Error| format!("error writing {}: {}", path.display(), x);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp).map_err(error)?;
+        writeln!(file, "{}", json).map_err(error)?;
+        file.sync_all().map_err(error)?;
+        std::fs::rename(&tmp, path).map_err(error)?;
+        Ok(())
+    }
+
+    /// Adds a pending unlock requested by `operator`, returning its id.
+    pub fn request(
+        &mut self,
+        client: ClientId,
+        total: f64,
+        operator: &str,
+        now: Timestamp,
+        ttl: Duration,
+    ) -> u64 {
+        self.next_id += 1;
+        self.pending.push(Approval {
+            id: self.next_id,
+            client,
+            total,
+            requested_by: operator.to_string(),
+            requested_at: now,
+            expires_at: now.saturating_add(ttl.as_secs()),
+        });
+        self.next_id
+    }
+
+    /// Removes and returns the pending unlock `id` as approved by
+    /// `operator`. Fails, leaving it pending, if the operator is the one
+    /// who requested it, and removes it without approving it if it has
+    /// expired.
+    pub fn approve(&mut self, id: u64, operator: &str, now: Timestamp) -> Result<Approval> {
+        let i = self
+            .pending
+            .iter()
+            .position(|approval| approval.id == id)
+            .ok_or_else(|| format!("no unlock {} is awaiting approval", id))?;
+        let approval = &self.pending[i];
+        if approval.requested_by == operator {
+            return Err(format!(
+                "unlock {} was requested by {}, it needs another operator's approval",
+                id, operator
+            )
+            .into());
+        }
+        if approval.expires_at <= now {
+            self.pending.remove(i);
+            return Err(format!("unlock {} has expired", id).into());
+        }
+        Ok(self.pending.remove(i))
+    }
+
+    /// Removes and returns the pending unlocks that have expired by `now`.
+    pub fn expire(&mut self, now: Timestamp) -> Vec<Approval> {
+        let (expired, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|approval| approval.expires_at <= now);
+        self.pending = pending;
+        expired
+    }
+}
+
+/// Prints the pending unlocks as csv to stdout.
+pub fn print_approvals(approvals: &Approvals) {
+    println!(
+        "{:>6}, {:>7}, {:>14}, {:>16}, {:>12}, {:>12}",
+        "id", "client", "total", "requested_by", "requested_at", "expires_at"
+    );
+    for approval in &approvals.pending {
+        println!(
+            "{:>6}, {:>7}, {:>14.4}, {:>16}, {:>12}, {:>12}",
+            approval.id,
+            approval.client,
+            approval.total,
+            approval.requested_by,
+            approval.requested_at,
+            approval.expires_at
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, open_file_read_csv, run_engine, Engine};
+
+    #[test]
+    fn large_unlocks_need_a_second_operator() -> Result<()> {
+        let engine = Engine::with_clock(SimulatedClock::new(50));
+        run_engine(
+            open_file_read_csv("test-files/chargeback_dispute.csv".to_string())?,
+            &engine,
+        )?;
+        let decisions = vec![(1, Decision::Unlock)];
+        let split = split_decisions(&engine.db, decisions.clone(), Some(5.0))?;
+        assert_eq!(
+            (split.immediate, split.deferred),
+            (decisions.clone(), vec![])
+        );
+        let split = split_decisions(&engine.db, decisions.clone(), Some(1.0))?;
+        assert_eq!((split.immediate, split.deferred), (vec![], vec![(1, 2.0)]));
+        assert!(split_decisions(&engine.db, vec![(2, Decision::Keep)], None).is_err());
+
+        let mut approvals = Approvals::default();
+        let ttl = Duration::from_secs(100);
+        let first = approvals.request(1, 2.0, "alice", 0, ttl);
+        let second = approvals.request(1, 2.0, "alice", 0, ttl);
+        assert_ne!(first, second);
+        assert!(approvals.approve(first, "alice", 10).is_err());
+        assert_eq!(approvals.approve(first, "bob", 10)?.client, 1);
+        assert!(approvals.approve(first, "bob", 10).is_err());
+        assert!(approvals.approve(second, "bob", 100).is_err());
+        assert!(approvals.pending.is_empty());
+
+        approvals.request(1, 2.0, "alice", 0, ttl);
+        assert!(approvals.expire(99).is_empty());
+        assert_eq!(approvals.expire(100).len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{
+    capture::Capture,
+    clock::SimulatedClock,
+    open_file_read_csv,
+    schema::Schema,
+    whatif::{diff_accounts, AccountDiff},
+    Engine, Result, Transaction,
+};
+
+/// The transactions of a csv file, such as a capture, in order.
+fn read_transactions(path: &str) -> Result<Vec<Transaction>> {
+    let mut reader = open_file_read_csv(path.to_string())?;
+    let schema = Schema::from_headers(reader.headers()?, &[])?;
+    reader
+        .records()
+        .enumerate()
+        .map(|(row, record)| {
+            schema
+                .parse(&record?)
+                .map_err(|x| format!("{}, row {}: {}", path, row + 1, x).into())
+        })
+        .collect()
+}
+
+/// Replays the capture `capture` with the late transactions of
+/// `corrections` inserted before its entry `before_seq`, counted from 1
+/// like `audit-verify` does, and returns the accounts that end up
+/// differently than in the capture as it is.
+///
+/// The capture is the log of what the engine applied, so this is the
+/// state the engine would be in had the corrections arrived on time.
+/// Corrections without a timestamp take the one of the entry they are
+/// inserted before. Nothing is written unless `commit` names a file, which
+/// then gets the merged capture to carry on from, once the differences
+/// have been approved.
+pub fn backfill(
+    capture: &str,
+    corrections: &str,
+    before_seq: usize,
+    commit: Option<String>,
+) -> Result<Vec<AccountDiff>> {
+    let entries = read_transactions(capture)?;
+    if before_seq == 0 || before_seq > entries.len() + 1 {
+        return Err(format!(
+            "--before-seq must be between 1 and {}, the entries of {} and one past them",
+            entries.len() + 1,
+            capture
+        )
+        .into());
+    }
+    let at = entries
+        .get(before_seq - 1)
+        .or(entries.last())
+        .and_then(|entry| entry.timestamp);
+    let corrections = read_transactions(corrections)?.into_iter().map(|mut txn| {
+        txn.timestamp = txn.timestamp.or(at);
+        txn
+    });
+
+    // Every entry carries its timestamp, the clock is only pinned so
+    // nothing can depend on the wall clock.
+    let original = Engine::with_clock(SimulatedClock::new(0));
+    for txn in entries.iter().cloned() {
+        original.process(txn)?;
+    }
+    let mut backfilled = Engine::with_clock(SimulatedClock::new(0));
+    if let Some(path) = commit {
+        backfilled = backfilled.with_capture(Capture::create(path, &[])?);
+    }
+    let (before, after) = entries.split_at(before_seq - 1);
+    let merged = before
+        .iter()
+        .cloned()
+        .chain(corrections)
+        .chain(after.iter().cloned());
+    for txn in merged {
+        backfilled.process(txn)?;
+    }
+    backfilled.flush_capture()?;
+    diff_accounts(&original, &backfilled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn corrections_are_applied_where_they_belong() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-backfill-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).map_err(|x| x.to_string())?;
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let capture = "type,client,tx,amount,timestamp\n\
+            deposit,1,1,5.0,100\n\
+            withdrawal,1,2,8.0,200\n\
+            deposit,2,3,1.0,300\n";
+        fs::write(path("capture.csv"), capture).map_err(|x| x.to_string())?;
+        // A deposit that should have come before the withdrawal, which
+        // then no longer bounces.
+        fs::write(
+            path("late.csv"),
+            "type,client,tx,amount\ndeposit,1,10,4.0\n",
+        )
+        .map_err(|x| x.to_string())?;
+
+        let diffs = backfill(&path("capture.csv"), &path("late.csv"), 2, None)?;
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].client_id, 1);
+        assert_eq!(diffs[0].original.as_ref().map(|a| a.available()), Some(5.0));
+        assert_eq!(diffs[0].what_if.as_ref().map(|a| a.available()), Some(1.0));
+        assert!(!dir.join("merged.csv").exists());
+
+        backfill(
+            &path("capture.csv"),
+            &path("late.csv"),
+            2,
+            Some(path("merged.csv")),
+        )?;
+        let merged = read_transactions(&path("merged.csv"))?;
+        assert_eq!(merged[1].txn_id, 10);
+        assert_eq!(merged[1].timestamp, Some(200));
+        assert!(backfill(&path("capture.csv"), &path("late.csv"), 5, None).is_err());
+        fs::remove_dir_all(&dir).map_err(|x| x.to_string())?;
+        Ok(())
+    }
+}
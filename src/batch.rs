@@ -0,0 +1,180 @@
+use crate::{
+    capture::Capture, digest::state_digest, handle_transaction, outbox::Event, plugin::Screened,
+    ClientId, Database, Engine, Result, Transaction, TransactionOutcome,
+};
+use std::sync::atomic::Ordering;
+
+#[derive(Debug, Clone, PartialEq)]
+/// What became of a batch applied with [`Engine::apply_batch`].
+pub struct BatchResult {
+    /// Whether the batch was committed. It is, only if every transaction
+    /// in it was applied.
+    pub committed: bool,
+    /// The outcome of each transaction, in the order of the batch, as
+    /// they were when the batch was tried.
+    pub outcomes: Vec<TransactionOutcome>,
+}
+
+impl Engine {
+    /// Applies a group of transactions atomically: either all of them are
+    /// applied, or none are and the state is left as it was.
+    ///
+    /// The batch is tried against a copy of just the clients and stored
+    /// transactions it touches, and only that copy is written back once
+    /// every transaction was applied. Other processing waits while a batch
+    /// is being applied, so nothing can change underneath it.
+    pub fn apply_batch(&self, batch: Vec<Transaction>) -> Result<BatchResult> {
+        let _exclusive = self.batch_lock.write().map_err(|_| "batch lock poisoned")?;
+        let view = Database::default();
+        for txn in &batch {
+            // A transfer touches the client it credits too.
+            for client_id in std::iter::once(txn.client_id).chain(txn.to_client) {
+                if let Some(client) = self.resident_client(client_id)? {
+                    view.clients.insert(client_id, client);
+                }
+            }
+            if let Some(referenced) = self.db.with_transaction(txn.txn_id, Transaction::clone)? {
+                view.transactions.insert(txn.txn_id, referenced);
+            }
+        }
+        let initial = state_digest(&view)?;
+        view.digest.store(initial, Ordering::SeqCst);
+
+        let mut outcomes = Vec::with_capacity(batch.len());
+        let mut rows = Vec::with_capacity(batch.len());
+        let mut events = Vec::new();
+        let mut posted = Vec::with_capacity(batch.len());
+        for mut txn in batch {
+            txn.timestamp.get_or_insert_with(|| self.clock.now());
+            let txn = match self.screen(&view, txn)? {
+                Screened::Pass(txn) => txn,
+                Screened::Denied(outcome, _) => {
+                    outcomes.push(outcome);
+                    continue;
+                }
+            };
+            let row = self.capture.as_ref().map(|_| Capture::row(&txn));
+            posted.push((txn.transaction_type, txn.client_id, txn.txn_id, txn.amount));
+            if self.db.outbox.is_recording() {
+                events.push(Event::of(&txn));
+            }
+            outcomes.push(handle_transaction(&view, txn)?);
+            rows.push((row, view.digest()));
+        }
+        let committed = outcomes
+            .iter()
+            .all(|outcome| *outcome == TransactionOutcome::Applied);
+        self.db.counters.absorb(&view.counters);
+        if !committed {
+            return Ok(BatchResult {
+                committed,
+                outcomes,
+            });
+        }
+
+        let base = self.db.digest();
+        if let Some(capture) = &self.capture {
+            let mut capture = capture.lock().map_err(|_| "capture lock poisoned")?;
+            for (row, digest) in rows {
+                if let Some(row) = row {
+                    capture.record(row, base.wrapping_add(digest.wrapping_sub(initial)))?;
+                }
+            }
+        }
+        self.db.latest_activity.fetch_max(
+            view.latest_activity.load(Ordering::SeqCst),
+            Ordering::SeqCst,
+        );
+        self.db
+            .digest
+            .fetch_add(view.digest().wrapping_sub(initial), Ordering::SeqCst);
+        for (client_id, client) in view.clients {
+            let replaced = self.db.clients.get(&client_id);
+            self.db.snapshots.client(client_id, replaced.as_deref());
+            drop(replaced);
+            self.db.clients.insert(client_id, client);
+        }
+        for (_, txn) in view.transactions {
+            self.db.store_transaction(txn);
+        }
+        for &(transaction_type, client_id, txn_id, amount) in &posted {
+            self.db
+                .ledger
+                .post(&self.db, transaction_type, client_id, txn_id, amount)?;
+        }
+        if self.check_invariants {
+            for (_, client_id, _, _) in posted {
+                self.db.verify_client(client_id)?;
+            }
+        }
+        for event in events {
+            self.db.outbox.record(event)?;
+        }
+        Ok(BatchResult {
+            committed,
+            outcomes,
+        })
+    }
+
+    /// A copy of a client's state, brought back into memory first if it
+    /// was evicted.
+    fn resident_client(&self, client_id: ClientId) -> Result<Option<crate::Client>> {
+        if let Some(client) = self.db.clients.get(&client_id) {
+            return Ok(Some(client.clone()));
+        }
+        let evicted = match &self.db.cold {
+            Some(cold) => cold.store.load(client_id)?,
+            None => None,
+        };
+        Ok(evicted.inspect(|client| {
+            self.db.snapshots.client(client_id, None);
+            self.db.clients.insert(client_id, client.clone());
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, i18n::Reason};
+
+    #[test]
+    fn batch_commits_all_or_nothing() -> Result<()> {
+        let engine = Engine::with_clock(SimulatedClock::new(0));
+        engine.process(Transaction::deposit(1, 1, 5.0))?;
+        let digest = engine.db.digest();
+
+        let failed = engine.apply_batch(vec![
+            Transaction::withdrawal(1, 2, 2.0),
+            Transaction::deposit(2, 3, 1.0),
+            Transaction::withdrawal(1, 4, 10.0),
+        ])?;
+        assert!(!failed.committed);
+        assert_eq!(
+            failed.outcomes[2],
+            TransactionOutcome::Rejected(Reason::InsufficientFunds)
+        );
+        assert_eq!(engine.db.clients.get(&1).map(|c| c.available), Some(5.0));
+        assert!(engine.db.clients.get(&2).is_none());
+        assert_eq!(engine.db.transactions.len(), 1);
+        assert_eq!(engine.db.digest(), digest);
+
+        let sequential = Engine::with_clock(SimulatedClock::new(0));
+        let batch = || {
+            vec![
+                Transaction::deposit(1, 1, 5.0),
+                Transaction::withdrawal(1, 2, 2.0),
+                Transaction::deposit(2, 3, 1.0),
+                Transaction::dispute(1, 1),
+            ]
+        };
+        for txn in batch() {
+            sequential.process(txn)?;
+        }
+        let batched = Engine::with_clock(SimulatedClock::new(0));
+        assert!(batched.apply_batch(batch())?.committed);
+        assert_eq!(batched.db, sequential.db);
+        assert_eq!(batched.db.digest(), sequential.db.digest());
+        Ok(())
+    }
+}
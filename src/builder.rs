@@ -0,0 +1,409 @@
+use crate::{
+    account::AccountView,
+    clock::{Clock, Timestamp},
+    currency::transaction_currency,
+    dedup::DedupWindow,
+    fees::Fees,
+    i18n::Reason,
+    plugin::Plugin,
+    policy::{Overdraft, Policy},
+    quota::Quotas,
+    schema::{sanitize_category, sanitize_memo, DEFAULT_MEMO_MAX_LEN},
+    spill::{Spill, TransactionStore, DEFAULT_MAX_RESIDENT},
+    tiering::{ColdStore, Tiering},
+    ClientId, Engine, Result, Transaction, TransactionType,
+};
+use std::time::Duration;
+
+impl Transaction {
+    /// A transaction with just its identity, the shape every constructor
+    /// starts from.
+    fn bare(transaction_type: TransactionType, client_id: ClientId, txn_id: u32) -> Self {
+        Transaction {
+            transaction_type,
+            client_id,
+            txn_id,
+            amount: None,
+            to_client: None,
+            timestamp: None,
+            memo: None,
+            category: None,
+            currency: None,
+            idempotency_key: None,
+            extras: Box::default(),
+        }
+    }
+
+    /// Credits `amount` to the client.
+    pub fn deposit(client_id: ClientId, txn_id: u32, amount: f64) -> Self {
+        Transaction {
+            amount: Some(amount),
+            ..Self::bare(TransactionType::Deposit, client_id, txn_id)
+        }
+    }
+
+    /// Debits `amount` from the client.
+    pub fn withdrawal(client_id: ClientId, txn_id: u32, amount: f64) -> Self {
+        Transaction {
+            amount: Some(amount),
+            ..Self::bare(TransactionType::Withdrawal, client_id, txn_id)
+        }
+    }
+
+    /// Disputes the client's transaction `txn_id`.
+    pub fn dispute(client_id: ClientId, txn_id: u32) -> Self {
+        Self::bare(TransactionType::Dispute, client_id, txn_id)
+    }
+
+    /// Resolves the dispute of the client's transaction `txn_id`.
+    pub fn resolve(client_id: ClientId, txn_id: u32) -> Self {
+        Self::bare(TransactionType::Resolve, client_id, txn_id)
+    }
+
+    /// Charges back the disputed transaction `txn_id`, locking the client.
+    pub fn chargeback(client_id: ClientId, txn_id: u32) -> Self {
+        Self::bare(TransactionType::ChargeBack, client_id, txn_id)
+    }
+
+    /// Moves `amount` from the client to `to_client`.
+    pub fn transfer(client_id: ClientId, txn_id: u32, to_client: ClientId, amount: f64) -> Self {
+        Transaction {
+            amount: Some(amount),
+            to_client: Some(to_client),
+            ..Self::bare(TransactionType::Transfer, client_id, txn_id)
+        }
+    }
+
+    pub fn transaction_type(&self) -> TransactionType {
+        self.transaction_type
+    }
+
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    pub fn txn_id(&self) -> u32 {
+        self.txn_id
+    }
+
+    /// The amount, if the transaction has one: a dispute, resolve or
+    /// chargeback refers to the amount of another.
+    pub fn amount(&self) -> Option<f64> {
+        self.amount
+    }
+
+    /// Starts building a transaction whose optional parts are checked by
+    /// [`TransactionBuilder::build`].
+    pub fn builder(
+        transaction_type: TransactionType,
+        client_id: ClientId,
+        txn_id: u32,
+    ) -> TransactionBuilder {
+        TransactionBuilder(Self::bare(transaction_type, client_id, txn_id))
+    }
+}
+
+#[derive(Debug)]
+/// Builds a [`Transaction`] for programmatic use, refusing combinations
+/// the engine can't process instead of leaving them to be rejected later.
+pub struct TransactionBuilder(Transaction);
+
+impl TransactionBuilder {
+    pub fn amount(mut self, amount: f64) -> Self {
+        self.0.amount = Some(amount);
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.0.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Sets the memo, sanitized as if it had been read from a file.
+    pub fn memo(mut self, memo: &str) -> Self {
+        self.0.memo = sanitize_memo(memo, DEFAULT_MEMO_MAX_LEN);
+        self
+    }
+
+    /// Sets the category, sanitized as if it had been read from a file.
+    pub fn category(mut self, category: &str) -> Self {
+        self.0.category = sanitize_category(category);
+        self
+    }
+
+    /// Sets the currency, a three letter code such as `EUR`, checked by
+    /// [`TransactionBuilder::build`].
+    pub fn currency(mut self, currency: &str) -> Self {
+        self.0.currency = Some(currency.into());
+        self
+    }
+
+    /// Sets the key retries of the transaction are recognized by, see
+    /// [`crate::dedup::DedupWindow`].
+    pub fn idempotency_key(mut self, key: &str) -> Self {
+        self.0.idempotency_key = Some(key.into());
+        self
+    }
+
+    /// The transaction, if it is well formed: deposits, withdrawals,
+    /// transfers and adjustments need a finite, positive amount, transfers
+    /// a recipient, and the other transactions must not carry an amount.
+    /// Only deposits and withdrawals keep a currency.
+    pub fn build(self) -> Result<Transaction> {
+        let mut txn = self.0;
+        txn.currency = transaction_currency(txn.transaction_type, txn.currency.as_deref())?;
+        let kind = txn.transaction_type.as_str();
+        if txn.transaction_type == TransactionType::Transfer && txn.to_client.is_none() {
+            return Err("a transfer needs a recipient".into());
+        }
+        let needs_amount = matches!(
+            txn.transaction_type,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Transfer
+                | TransactionType::CreditAdjustment
+                | TransactionType::DebitAdjustment
+        );
+        match (needs_amount, txn.amount) {
+            (true, None) => Err(format!("a {} needs an amount", kind).into()),
+            (true, Some(amount)) if !amount.is_finite() || amount <= 0.0 => {
+                Err(format!("a {} needs a positive amount, not {}", kind, amount).into())
+            }
+            (true, Some(_)) => Ok(txn),
+            (false, Some(_)) => Err(format!("a {} can't have an amount", kind).into()),
+            (false, None) => Ok(txn),
+        }
+    }
+}
+
+impl Engine {
+    /// Starts configuring an engine, see [`EngineBuilder`].
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder::default()
+    }
+}
+
+#[derive(Debug, Default)]
+/// Configures an [`Engine`] for programmatic use, as the `with_*` methods
+/// of the engine do, plus callbacks for rejected transactions and locked
+/// accounts. Settings the command line reads from flags, such as the
+/// precision or the overdraft, are set on the builder instead.
+///
+/// ```
+/// use payments_engine::{policy::Overdraft, Engine};
+///
+/// let engine = Engine::builder()
+///     .precision(2)
+///     .overdraft(Overdraft::Deny)
+///     .on_reject(|txn, reason| eprintln!("{} rejected: {}", txn.txn_id(), reason.code()))
+///     .build()?;
+/// # Ok::<(), payments_engine::PaymentsEngineError>(())
+/// ```
+pub struct EngineBuilder {
+    engine: Engine,
+    /// Set on the engine by [`EngineBuilder::build`], as they can fail.
+    tiering: Option<Tiering>,
+    quotas: Option<Quotas>,
+    /// Spills with [`EngineBuilder::store`].
+    store: Option<Box<dyn TransactionStore>>,
+    max_resident: Option<usize>,
+}
+
+impl EngineBuilder {
+    /// Reads time from `clock` rather than the system clock.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.engine.clock = Box::new(clock);
+        self
+    }
+
+    /// Handles transactions by `policy`, replacing whatever was set on it
+    /// before, so it is set first.
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.engine.db.policy = policy;
+        self
+    }
+
+    /// Keeps amounts to this many decimal places in reports and fees.
+    pub fn precision(mut self, places: u32) -> Self {
+        self.engine.db.policy.precision.places = places;
+        self
+    }
+
+    pub fn overdraft(mut self, overdraft: Overdraft) -> Self {
+        self.engine.db.policy.overdraft = Some(overdraft);
+        self
+    }
+
+    pub fn fees(mut self, fees: Fees) -> Self {
+        self.engine.db.policy.fees = fees;
+        self
+    }
+
+    /// Runs `plugin` on every transaction, after those added before it.
+    pub fn plugin(mut self, plugin: impl Plugin + 'static) -> Self {
+        self.engine.plugins.push(Box::new(plugin));
+        self
+    }
+
+    /// Spills stored transactions to `store` once there are more than
+    /// [`EngineBuilder::max_resident_transactions`] in memory, see
+    /// [`Spill`].
+    pub fn store(mut self, store: impl TransactionStore + 'static) -> Self {
+        self.store = Some(Box::new(store));
+        self
+    }
+
+    /// How many stored transactions [`EngineBuilder::store`] keeps in
+    /// memory, [`DEFAULT_MAX_RESIDENT`] by default.
+    pub fn max_resident_transactions(mut self, max_resident: usize) -> Self {
+        self.max_resident = Some(max_resident);
+        self
+    }
+
+    /// Evicts clients inactive for `evict_after` to `store`, see
+    /// [`Tiering`]. [`crate::tiering::DEFAULT_EVICT_AFTER`] is the command
+    /// line's default.
+    pub fn cold_store(mut self, store: impl ColdStore + 'static, evict_after: Duration) -> Self {
+        self.tiering = Some(Tiering {
+            store: Box::new(store),
+            evict_after,
+        });
+        self
+    }
+
+    pub fn quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = Some(quotas);
+        self
+    }
+
+    pub fn dedup_window(mut self, dedup: DedupWindow) -> Self {
+        self.engine.dedup = Some(dedup);
+        self
+    }
+
+    /// See [`Engine::with_invariant_checks`].
+    pub fn invariant_checks(mut self) -> Self {
+        self.engine.check_invariants = true;
+        self
+    }
+
+    /// See [`Engine::with_history`].
+    pub fn history(self) -> Self {
+        EngineBuilder {
+            engine: self.engine.with_history(),
+            ..self
+        }
+    }
+
+    /// Calls `hook` with every transaction the engine rejects and why,
+    /// after the callbacks added before it, see [`crate::hooks::Hooks`].
+    pub fn on_reject(
+        mut self,
+        hook: impl Fn(&Transaction, Reason) + Send + Sync + 'static,
+    ) -> Self {
+        self.engine.hooks.on_reject.push(Box::new(hook));
+        self
+    }
+
+    /// Calls `hook` with every account a transaction locks, as it is right
+    /// after, and the transaction.
+    pub fn on_lock(
+        mut self,
+        hook: impl Fn(&AccountView, &Transaction) + Send + Sync + 'static,
+    ) -> Self {
+        self.engine.hooks.on_lock.push(Box::new(hook));
+        self
+    }
+
+    /// The engine, once the cold store is read and the quotas checked.
+    pub fn build(self) -> Result<Engine> {
+        let mut engine = self.engine;
+        if let Some(store) = self.store {
+            engine = engine.with_spill(Spill {
+                store,
+                max_resident: self.max_resident.unwrap_or(DEFAULT_MAX_RESIDENT),
+            });
+        }
+        if let Some(tiering) = self.tiering {
+            engine = engine.with_tiering(tiering)?;
+        }
+        if let Some(quotas) = self.quotas {
+            engine = engine.with_quotas(quotas)?;
+        }
+        Ok(engine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_refuses_malformed_transactions() -> Result<()> {
+        let deposit = Transaction::builder(TransactionType::Deposit, 1, 1)
+            .amount(2.5)
+            .timestamp(10)
+            .memo("  rent\nMarch ")
+            .category("Housing")
+            .build()?;
+        assert_eq!(deposit.amount, Some(2.5));
+        assert_eq!(deposit.memo.as_deref(), Some("rent March"));
+        assert_eq!(deposit.category.as_deref(), Some("housing"));
+
+        assert!(Transaction::builder(TransactionType::Withdrawal, 1, 2)
+            .build()
+            .is_err());
+        assert!(Transaction::builder(TransactionType::Deposit, 1, 3)
+            .amount(f64::NAN)
+            .build()
+            .is_err());
+        assert!(Transaction::builder(TransactionType::Deposit, 1, 4)
+            .amount(-1.0)
+            .build()
+            .is_err());
+        assert!(Transaction::builder(TransactionType::Dispute, 1, 1)
+            .amount(1.0)
+            .build()
+            .is_err());
+        assert_eq!(
+            Transaction::builder(TransactionType::Dispute, 1, 1).build()?,
+            Transaction::dispute(1, 1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn built_engines_call_back_on_rejections_and_locks() -> Result<()> {
+        use std::sync::{Arc, Mutex};
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (rejected, locked) = (events.clone(), events.clone());
+        let engine = Engine::builder()
+            .precision(2)
+            .overdraft(Overdraft::AllowWithLimit(5.0))
+            .on_reject(move |txn, reason| {
+                if let Ok(mut events) = rejected.lock() {
+                    events.push(format!("{} {}", txn.txn_id(), reason.code()));
+                }
+            })
+            .on_lock(move |account, txn| {
+                if let Ok(mut events) = locked.lock() {
+                    events.push(format!("{} locked {}", txn.txn_id(), account.client_id()));
+                }
+            })
+            .build()?;
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        engine.process(Transaction::withdrawal(1, 2, 14.0))?;
+        engine.process(Transaction::withdrawal(1, 3, 2.0))?;
+        engine.process(Transaction::deposit(1, 4, 2.0))?;
+        engine.process(Transaction::dispute(1, 4))?;
+        engine.process(Transaction::chargeback(1, 4))?;
+        engine.process(Transaction::deposit(1, 5, 1.0))?;
+        assert_eq!(
+            *events.lock().map_err(|_| "poisoned")?,
+            ["3 insufficient_funds", "4 locked 1", "5 account_locked"]
+        );
+        assert_eq!(engine.db.policy.precision.places, 2);
+        Ok(())
+    }
+}
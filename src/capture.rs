@@ -0,0 +1,90 @@
+use crate::{digest::format_digest, Result, Transaction};
+use std::{fmt, fs::File};
+
+/// The position of the digest column, after the transaction fields.
+const DIGEST_COLUMN: usize = 6;
+
+/// Records every transaction the engine accepts into a file that can be
+/// fed straight back into the engine with `replay`.
+///
+/// The file uses the same layout as the csv input, plus the timestamp
+/// the engine assigned to each transaction (so a replay sees exactly the
+/// same times as the original run) and its memo. The next column holds
+/// the state digest after the transaction was applied, which
+/// `audit-verify` uses to check a replay step by step, then the client a
+/// transfer credits and the currency, followed by any extra input columns
+/// carried with the transactions.
+pub struct Capture {
+    path: String,
+    writer: csv::Writer<File>,
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Capture").field("path", &self.path).finish()
+    }
+}
+
+impl Capture {
+    /// Creates (or truncates) the capture file at `path`, with a column
+    /// for each of the `carried` extra columns.
+    pub fn create(path: String, carried: &[String]) -> Result<Self> {
+        let mut writer = csv::Writer::from_path(&path)?;
+        let headers = [
+            "type",
+            "client",
+            "tx",
+            "amount",
+            "timestamp",
+            "memo",
+            "digest",
+            "to_client",
+            "currency",
+        ];
+        writer.write_record(
+            headers
+                .iter()
+                .copied()
+                .chain(carried.iter().map(|x| x.trim())),
+        )?;
+        Ok(Capture { path, writer })
+    }
+
+    /// The fields of a transaction as they are written to the capture.
+    ///
+    /// This is taken before the transaction is handed to the engine, the
+    /// row is written with [`Capture::record`] once it has been applied.
+    pub fn row(txn: &Transaction) -> Vec<String> {
+        [
+            txn.transaction_type.as_str().to_string(),
+            txn.client_id.to_string(),
+            txn.txn_id.to_string(),
+            txn.amount
+                .map_or(String::new(), |amount| amount.to_string()),
+            txn.timestamp.map_or(String::new(), |ts| ts.to_string()),
+            txn.memo.as_deref().unwrap_or("").to_string(),
+            txn.to_client
+                .map_or(String::new(), |client| client.to_string()),
+            txn.currency.as_deref().unwrap_or("").to_string(),
+        ]
+        .into_iter()
+        .chain(txn.extras.iter().cloned())
+        .collect()
+    }
+
+    /// Appends a single transaction, with the state digest after it was
+    /// applied, to the capture.
+    pub fn record(&mut self, mut row: Vec<String>, digest: u64) -> Result<()> {
+        row.insert(DIGEST_COLUMN, format_digest(digest));
+        self.writer.write_record(row)?;
+        Ok(())
+    }
+
+    /// Flushes everything recorded so far to disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .map_err(|x| format!("error flushing capture {}: {}", self.path, x))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Seconds since the unix epoch.
+pub type Timestamp = u64;
+
+/// A source of time for the engine.
+///
+/// Anything in the engine that depends on "now" must ask the clock
+/// rather than calling `SystemTime` directly, so that tests and replays
+/// can swap in a [`SimulatedClock`] and stay deterministic.
+pub trait Clock: Debug + Send + Sync {
+    /// The current time.
+    fn now(&self) -> Timestamp;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+/// The wall clock of the machine the engine is running on.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+/// A clock that only moves when it is told to.
+///
+/// Clones share the same underlying time, so a handle can be kept
+/// to advance the clock after it has been given to the engine.
+pub struct SimulatedClock {
+    now: Arc<AtomicU64>,
+}
+
+impl SimulatedClock {
+    /// Creates a clock stopped at `start`.
+    pub fn new(start: Timestamp) -> Self {
+        SimulatedClock {
+            now: Arc::new(AtomicU64::new(start)),
+        }
+    }
+
+    /// Moves the clock forward by `secs` seconds.
+    pub fn advance(&self, secs: u64) {
+        self.now.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Timestamp {
+        self.now.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,309 @@
+use crate::{
+    append::{replace_state, STATE_FILE},
+    digest::{format_digest, salted_hash, state_digest},
+    state::{export_state, import_state, AccountState, StateFile, TransactionState, STATE_VERSION},
+    ClientId, Engine, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::TcpStream,
+    path::Path,
+};
+
+/// The line a connection to `serve` starts with to clone its state instead
+/// of sending transactions, followed by the last client already received
+/// or `-` for all of them.
+pub const CLONE_COMMAND: &str = "clone";
+/// Clients sent per chunk.
+pub const CHUNK_CLIENTS: usize = 1000;
+/// The chunks received so far, in the directory being cloned into, so an
+/// interrupted transfer can pick up where it stopped.
+pub const PART_FILE: &str = "clone.part";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+/// A line of a clone stream.
+pub enum Frame {
+    /// The accounts and transactions of the clients after the previous
+    /// chunk's, up to and including `last`.
+    Chunk {
+        last: ClientId,
+        accounts: Vec<AccountState>,
+        transactions: Vec<TransactionState>,
+        digest: String,
+    },
+    /// Ends the stream with the size and digest of the whole state, the
+    /// chunks sent before a resume included.
+    Done {
+        accounts: usize,
+        transactions: usize,
+        digest: String,
+    },
+}
+
+/// Digests the accounts and transactions of a chunk one by one, so chunks
+/// can be added up to the digest of the whole state however they are cut.
+fn chunk_digest(accounts: &[AccountState], transactions: &[TransactionState]) -> Result<u64> {
+    let mut digest = 0u64;
+    for account in accounts {
+        let bytes = serde_json::to_vec(account).map_err(|x| x.to_string())?;
+        digest = digest.wrapping_add(salted_hash("account", &bytes));
+    }
+    for txn in transactions {
+        let bytes = serde_json::to_vec(txn).map_err(|x| x.to_string())?;
+        digest = digest.wrapping_add(salted_hash("transaction", &bytes));
+    }
+    Ok(digest)
+}
+
+fn parse_digest(digest: &str) -> Result<u64> {
+    u64::from_str_radix(digest, 16).map_err(|_| format!("invalid digest {}", digest).into())
+}
+
+/// Streams the state of `engine` to `out` in chunks of [`CHUNK_CLIENTS`]
+/// clients, starting after client `after`, and ends with a [`Frame::Done`].
+/// Events waiting in the outbox are not sent, the source publishes them.
+pub fn send_state(engine: &Engine, after: Option<ClientId>, out: impl Write) -> Result<()> {
+    let state = export_state(&engine.db)?;
+    let digest = chunk_digest(&state.accounts, &state.transactions)?;
+    let done = Frame::Done {
+        accounts: state.accounts.len(),
+        transactions: state.transactions.len(),
+        digest: format_digest(digest),
+    };
+    let mut clients: BTreeMap<ClientId, (Vec<AccountState>, Vec<TransactionState>)> =
+        BTreeMap::new();
+    for account in state.accounts {
+        clients.entry(account.client).or_default().0.push(account);
+    }
+    for txn in state.transactions {
+        clients.entry(txn.client).or_default().1.push(txn);
+    }
+    let clients: Vec<_> = clients
+        .into_iter()
+        .filter(|(client, _)| after.is_none_or(|after| *client > after))
+        .collect();
+    let mut out = BufWriter::new(out);
+    let error = |x: io::Error| format!("error sending the state: {}", x);
+    for chunk in clients.chunks(CHUNK_CLIENTS) {
+        let mut accounts = Vec::new();
+        let mut transactions = Vec::new();
+        for (_, (client_accounts, client_transactions)) in chunk {
+            accounts.extend(client_accounts.iter().cloned());
+            transactions.extend(client_transactions.iter().cloned());
+        }
+        let frame = Frame::Chunk {
+            last: chunk[chunk.len() - 1].0,
+            digest: format_digest(chunk_digest(&accounts, &transactions)?),
+            accounts,
+            transactions,
+        };
+        serde_json::to_writer(&mut out, &frame).map_err(|x| x.to_string())?;
+        writeln!(out).map_err(error)?;
+    }
+    serde_json::to_writer(&mut out, &done).map_err(|x| x.to_string())?;
+    writeln!(out).map_err(error)?;
+    out.flush().map_err(error)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What [`clone_state`] received.
+pub struct Cloned {
+    pub accounts: usize,
+    pub transactions: usize,
+    /// Chunks received by earlier, interrupted transfers.
+    pub resumed_chunks: usize,
+    /// The state digest of the clone, as `audit-verify` computes it.
+    pub digest: u64,
+}
+
+/// Clones the state of the engine served at `addr` into the append
+/// directory `dir`, which must not hold a state yet.
+///
+/// Every chunk is checked against its digest and kept in [`PART_FILE`] as
+/// it arrives; running again after an interruption asks only for the
+/// clients after the last chunk kept. The whole state is then checked
+/// against the digest the source sent and loaded into an engine, which
+/// checks it is consistent, before it is written as `state.json`. If the
+/// source changed between an interrupted transfer and its resume the
+/// digests differ: the chunks kept are dropped and the clone must be run
+/// again from the start.
+pub fn clone_state(addr: &str, dir: &Path) -> Result<Cloned> {
+    if dir.join(STATE_FILE).exists() {
+        return Err(format!("{} already holds a state", dir.display()).into());
+    }
+    fs::create_dir_all(dir).map_err(|x| format!("error creating {}: {}", dir.display(), x))?;
+    let part = dir.join(PART_FILE);
+    let mut accounts = Vec::new();
+    let mut transactions = Vec::new();
+    let mut digest = 0u64;
+    let mut last = None;
+    let mut resumed_chunks = 0;
+    // The length of the chunks kept whole, anything after them is dropped.
+    let mut kept_len = 0;
+    if part.exists() {
+        let file =
+            File::open(&part).map_err(|x| format!("error opening {}: {}", part.display(), x))?;
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|x| format!("error reading {}: {}", part.display(), x))?;
+            // A chunk cut short by the interruption is asked for again.
+            let Ok(Frame::Chunk {
+                last: chunk_last,
+                accounts: chunk_accounts,
+                transactions: chunk_transactions,
+                ..
+            }) = serde_json::from_str(&line)
+            else {
+                break;
+            };
+            digest = digest.wrapping_add(chunk_digest(&chunk_accounts, &chunk_transactions)?);
+            accounts.extend(chunk_accounts);
+            transactions.extend(chunk_transactions);
+            last = Some(chunk_last);
+            resumed_chunks += 1;
+            kept_len += line.len() as u64 + 1;
+        }
+    }
+
+    let stream =
+        TcpStream::connect(addr).map_err(|x| format!("error connecting to {}: {}", addr, x))?;
+    let mut request = stream.try_clone().map_err(|x| x.to_string())?;
+    let after = last.map_or("-".to_string(), |last| last.to_string());
+    writeln!(request, "{} {}", CLONE_COMMAND, after)
+        .map_err(|x| format!("error asking {} for its state: {}", addr, x))?;
+    let mut kept = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&part)
+        .and_then(|kept| kept.set_len(kept_len).map(|_| kept))
+        .map_err(|x| format!("error opening {}: {}", part.display(), x))?;
+    let mut done = None;
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|x| format!("error receiving from {}: {}", addr, x))?;
+        match serde_json::from_str(&line)
+            .map_err(|x| format!("unexpected answer from {}: {}", addr, x))?
+        {
+            Frame::Chunk {
+                last: chunk_last,
+                accounts: chunk_accounts,
+                transactions: chunk_transactions,
+                digest: chunk,
+            } => {
+                let received = chunk_digest(&chunk_accounts, &chunk_transactions)?;
+                if received != parse_digest(&chunk)? {
+                    return Err(format!("chunk up to client {} is corrupt", chunk_last).into());
+                }
+                writeln!(kept, "{}", line)
+                    .and_then(|_| kept.flush())
+                    .map_err(|x| format!("error writing {}: {}", part.display(), x))?;
+                digest = digest.wrapping_add(received);
+                accounts.extend(chunk_accounts);
+                transactions.extend(chunk_transactions);
+            }
+            Frame::Done {
+                accounts: total_accounts,
+                transactions: total_transactions,
+                digest: total,
+            } => {
+                done = Some((total_accounts, total_transactions, parse_digest(&total)?));
+                break;
+            }
+        }
+    }
+    let Some((total_accounts, total_transactions, total)) = done else {
+        return Err(format!(
+            "{} closed the connection before the end of the state, run again to resume",
+            addr
+        )
+        .into());
+    };
+    if (accounts.len(), transactions.len(), digest) != (total_accounts, total_transactions, total) {
+        fs::remove_file(&part).map_err(|x| format!("error removing {}: {}", part.display(), x))?;
+        return Err(format!(
+            "the state of {} changed since the clone started, run again to start over",
+            addr
+        )
+        .into());
+    }
+
+    accounts.sort_by_key(|account| account.client);
+    transactions.sort_by_key(|txn| txn.tx);
+    let state = StateFile {
+        version: STATE_VERSION,
+        generation: 0,
+        rows: 0,
+        accounts,
+        transactions,
+        transfers: Vec::new(),
+        outbox: Vec::new(),
+    };
+    let engine = Engine::default();
+    import_state(&engine, state.clone())?;
+    replace_state(&state, &dir.join(STATE_FILE), None)?;
+    fs::remove_file(&part).map_err(|x| format!("error removing {}: {}", part.display(), x))?;
+    Ok(Cloned {
+        accounts: total_accounts,
+        transactions: total_transactions,
+        resumed_chunks,
+        digest: state_digest(&engine.db)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        serve::{serve, Handoff},
+        Transaction,
+    };
+    use std::{net::TcpListener, thread, time::Duration};
+
+    #[test]
+    fn clones_resume_after_the_last_chunk_kept() -> Result<()> {
+        let engine = Engine::default();
+        for client in 1..=1500 {
+            engine.process(Transaction::deposit(client, client as u32, 1.0))?;
+        }
+        engine.process(Transaction::dispute(7, 7))?;
+        let dir = crate::temp_path("payments-engine-clone-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).map_err(|x| x.to_string())?;
+        // A transfer interrupted in the middle of its second chunk.
+        let mut sent = Vec::new();
+        send_state(&engine, None, &mut sent)?;
+        let sent = String::from_utf8_lossy(&sent);
+        let first = sent.lines().next().unwrap_or_default();
+        fs::write(
+            dir.join(PART_FILE),
+            format!("{}\n{{\"chunk\":{{\"la", first),
+        )
+        .map_err(|x| x.to_string())?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|x| x.to_string())?;
+        let addr = listener.local_addr().map_err(|x| x.to_string())?;
+        let cloned = thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                serve(
+                    &engine,
+                    listener,
+                    Some(Duration::from_millis(200)),
+                    Handoff::default(),
+                )
+            });
+            let cloned = clone_state(&addr.to_string(), &dir);
+            server.join().expect("server panicked")?;
+            cloned
+        })?;
+        assert_eq!(cloned.resumed_chunks, 1);
+        assert_eq!(cloned.accounts, 1500);
+        assert_eq!(cloned.digest, state_digest(&engine.db)?);
+        assert!(!dir.join(PART_FILE).exists());
+        assert!(clone_state(&addr.to_string(), &dir).is_err());
+        fs::remove_dir_all(&dir).map_err(|x| x.to_string())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+use crate::{account::AccountView, open_file_read_csv, run_engine, ClientId, Engine, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    process::Command,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// An account as the csv report shows it, balances rounded to the four
+/// decimal places it is printed with.
+pub struct ReportRow {
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+}
+
+fn round(x: f64) -> f64 {
+    (x * 10_000.0).round() / 10_000.0
+}
+
+impl ReportRow {
+    fn new(available: f64, held: f64, locked: bool) -> Self {
+        ReportRow {
+            available: round(available),
+            held: round(held),
+            total: round(available + held),
+            locked,
+        }
+    }
+}
+
+/// The accounts of a csv report, by client.
+pub type Report = BTreeMap<ClientId, ReportRow>;
+
+/// What the reference is compared against.
+pub enum Reference {
+    /// A program run as `sh -c '<command> <input>'`, printing its report to
+    /// stdout, such as another implementation or an older build.
+    Command(String),
+    /// A report saved from an earlier run.
+    Report(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A client whose account differs from the reference. Either side is
+/// `None` if the client is missing from that report.
+pub struct ReportDiff {
+    pub client_id: ClientId,
+    pub ours: Option<ReportRow>,
+    pub reference: Option<ReportRow>,
+}
+
+/// Parses a csv report by its `client`, `available`, `held` and `locked`
+/// columns, and its `total` column if it has one, otherwise the total is
+/// what is available and held. Other columns are ignored.
+pub fn parse_report(text: &str) -> Result<Report> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(text.as_bytes());
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("report has no {} column", name))
+    };
+    let (client, available, held, locked) = (
+        column("client")?,
+        column("available")?,
+        column("held")?,
+        column("locked")?,
+    );
+    let total = column("total").ok();
+    let mut report = Report::new();
+    for record in reader.records() {
+        let record = record?;
+        let field = |column: usize| record.get(column).unwrap_or("");
+        let mut row = ReportRow::new(
+            field(available).parse()?,
+            field(held).parse()?,
+            field(locked)
+                .parse()
+                .map_err(|x| format!("invalid locked value: {}", x))?,
+        );
+        if let Some(total) = total {
+            row.total = round(field(total).parse()?);
+        }
+        report.insert(field(client).parse()?, row);
+    }
+    Ok(report)
+}
+
+/// Runs `input` through this engine and through `reference`, and returns
+/// the clients whose accounts differ between the two reports, by client.
+pub fn compare(input: String, reference: &Reference) -> Result<Vec<ReportDiff>> {
+    let engine = Engine::default();
+    run_engine(open_file_read_csv(input.clone())?, &engine)?;
+    let mut ours = Report::new();
+    engine.db.for_each_client(|client_id, client| {
+        let account = AccountView::from_client(client_id, client);
+        let row = ReportRow::new(account.available(), account.held(), account.locked());
+        ours.insert(client_id, row);
+    })?;
+    let mut theirs = match reference {
+        Reference::Command(command) => {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(format!("{} \"$1\"", command))
+                .arg("sh")
+                .arg(&input)
+                .output()
+                .map_err(|x| format!("error running reference {}: {}", command, x))?;
+            if !output.status.success() {
+                return Err(format!("reference {} failed: {}", command, output.status).into());
+            }
+            parse_report(&String::from_utf8_lossy(&output.stdout))?
+        }
+        Reference::Report(path) => read_report(path)?,
+    };
+    let clients: BTreeSet<ClientId> = ours.keys().chain(theirs.keys()).copied().collect();
+    Ok(clients
+        .into_iter()
+        .map(|client_id| ReportDiff {
+            client_id,
+            ours: ours.remove(&client_id),
+            reference: theirs.remove(&client_id),
+        })
+        .filter(|diff| diff.ours != diff.reference)
+        .collect())
+}
+
+/// Reads the csv report at `path`, see [`parse_report`].
+pub fn read_report(path: &str) -> Result<Report> {
+    parse_report(
+        &std::fs::read_to_string(path).map_err(|x| format!("error reading {}: {}", path, x))?,
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A value of a client's account that two reports disagree on.
+pub struct Discrepancy {
+    pub client_id: ClientId,
+    /// `available`, `held`, `total` or `locked`, or `client` if the client
+    /// is missing from one of the reports.
+    pub field: &'static str,
+    pub left: String,
+    pub right: String,
+    /// Left less right, for balances.
+    pub difference: Option<f64>,
+}
+
+/// The values of each client's account that differ between the reports
+/// `left` and `right`, by client: balances more than `tolerance` apart,
+/// the lock, and clients only one of them has.
+pub fn reconcile(left: &Report, right: &Report, tolerance: f64) -> Vec<Discrepancy> {
+    let clients: BTreeSet<ClientId> = left.keys().chain(right.keys()).copied().collect();
+    let mut discrepancies = Vec::new();
+    for client_id in clients {
+        let (ours, theirs) = match (left.get(&client_id), right.get(&client_id)) {
+            (Some(ours), Some(theirs)) => (ours, theirs),
+            (ours, _) => {
+                let present = |row: bool| match row {
+                    true => "present".to_string(),
+                    false => "missing".to_string(),
+                };
+                discrepancies.push(Discrepancy {
+                    client_id,
+                    field: "client",
+                    left: present(ours.is_some()),
+                    right: present(ours.is_none()),
+                    difference: None,
+                });
+                continue;
+            }
+        };
+        for (field, a, b) in [
+            ("available", ours.available, theirs.available),
+            ("held", ours.held, theirs.held),
+            ("total", ours.total, theirs.total),
+        ] {
+            let difference = round(a - b);
+            if difference.abs() > tolerance {
+                discrepancies.push(Discrepancy {
+                    client_id,
+                    field,
+                    left: format!("{:.4}", a),
+                    right: format!("{:.4}", b),
+                    difference: Some(difference),
+                });
+            }
+        }
+        if ours.locked != theirs.locked {
+            discrepancies.push(Discrepancy {
+                client_id,
+                field: "locked",
+                left: ours.locked.to_string(),
+                right: theirs.locked.to_string(),
+                difference: None,
+            });
+        }
+    }
+    discrepancies
+}
+
+/// Prints discrepancies as csv to stdout, with the columns
+/// `client,field,left,right,difference`.
+pub fn print_reconcile(discrepancies: &[Discrepancy]) {
+    println!("client,field,left,right,difference");
+    for discrepancy in discrepancies {
+        println!(
+            "{},{},{},{},{}",
+            discrepancy.client_id,
+            discrepancy.field,
+            discrepancy.left,
+            discrepancy.right,
+            discrepancy
+                .difference
+                .map(|x| format!("{:.4}", x))
+                .unwrap_or_default()
+        );
+    }
+}
+
+/// Prints the differences as csv to stdout, each value of a client in
+/// this engine's report followed by its value in the reference.
+pub fn print_compare(diffs: &[ReportDiff]) {
+    println!(
+        "{:>7}, {:>12}, {:>12}, {:>12}, {:>12}, {:>12}, {:>12}",
+        "client", "available", "reference", "held", "reference", "locked", "reference"
+    );
+    let show = |row: &Option<ReportRow>, f: fn(&ReportRow) -> String| {
+        row.as_ref().map_or("-".to_string(), f)
+    };
+    for diff in diffs {
+        println!(
+            "{:>7}, {:>12}, {:>12}, {:>12}, {:>12}, {:>12}, {:>12}",
+            diff.client_id,
+            show(&diff.ours, |row| format!("{:.4}", row.available)),
+            show(&diff.reference, |row| format!("{:.4}", row.available)),
+            show(&diff.ours, |row| format!("{:.4}", row.held)),
+            show(&diff.reference, |row| format!("{:.4}", row.held)),
+            show(&diff.ours, |row| row.locked.to_string()),
+            show(&diff.reference, |row| row.locked.to_string()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_against_a_saved_report() -> Result<()> {
+        let path = crate::temp_path("payments-engine-compare-test.csv");
+        std::fs::write(
+            &path,
+            "client, available, held, total, locked\n\
+             1, 1.5, 0.0, 1.5, false\n\
+             2, 2.0, 1.0, 3.0, false\n\
+             3, 9.0, 0.0, 9.0, false\n",
+        )
+        .map_err(|x| x.to_string())?;
+        let reference = Reference::Report(path.to_string_lossy().to_string());
+        let diffs = compare("test-files/example_input.csv".to_string(), &reference)?;
+        let clients: Vec<_> = diffs.iter().map(|diff| diff.client_id).collect();
+        assert_eq!(clients, vec![2, 3]);
+        assert_eq!(diffs[1].ours, None);
+        Ok(())
+    }
+
+    #[test]
+    fn reconciles_two_reports_within_a_tolerance() -> Result<()> {
+        let ours = parse_report(
+            "client,available,held,total,locked\n\
+             1,1.5,0.0,1.5,false\n\
+             2,2.0,1.0,3.0,false\n\
+             3,9.0,0.0,9.0,true\n",
+        )?;
+        let upstream = parse_report(
+            "client,available,held,locked\n\
+             1,1.5001,0.0,false\n\
+             2,2.5,1.0,false\n\
+             3,9.0,0.0,false\n\
+             4,1.0,0.0,false\n",
+        )?;
+        let discrepancies = reconcile(&ours, &upstream, 0.001);
+        let found: Vec<_> = discrepancies
+            .iter()
+            .map(|x| (x.client_id, x.field, x.difference))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (2, "available", Some(-0.5)),
+                (2, "total", Some(-0.5)),
+                (3, "locked", None),
+                (4, "client", None),
+            ]
+        );
+        assert_eq!(discrepancies[3].left, "missing");
+        assert_eq!(reconcile(&ours, &upstream, 0.0).len(), 6);
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use crate::Result;
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    process::{Child, ChildStdout, Command, Stdio},
+    thread::JoinHandle,
+};
+
+/// The first bytes of every gzip member.
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+/// The first bytes of every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How an input file is compressed, told by its first bytes so an archive
+/// is read the same whatever it is named.
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// How `file` is compressed, if it is, leaving it at its start.
+    pub fn detect(file: &mut File) -> io::Result<Option<Compression>> {
+        let mut magic = [0; ZSTD_MAGIC.len()];
+        let mut len = 0;
+        while len < magic.len() {
+            match file.read(&mut magic[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        file.seek(SeekFrom::Start(0))?;
+        Ok(match &magic[..len] {
+            magic if magic.starts_with(&ZSTD_MAGIC) => Some(Compression::Zstd),
+            magic if magic.starts_with(&GZIP_MAGIC) => Some(Compression::Gzip),
+            _ => None,
+        })
+    }
+
+    /// The command the files are decompressed with.
+    pub fn command(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+/// `path` without the extension of a compressed file, so `x.jsonl.gz` is
+/// told apart from `x.csv.gz` by what comes before.
+pub fn strip_extension(path: &str) -> &str {
+    [".gz", ".zst"]
+        .iter()
+        .find_map(|extension| path.strip_suffix(extension))
+        .unwrap_or(path)
+}
+
+/// An input file, decompressed as it is read if it is compressed.
+pub enum Input<R> {
+    Plain(R),
+    Decompressed(Decompressed),
+}
+
+impl<R: Read> Read for Input<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::Plain(inner) => inner.read(buf),
+            Input::Decompressed(inner) => inner.read(buf),
+        }
+    }
+}
+
+impl<R: Read + Send + 'static> Input<R> {
+    /// `inner`, the content of the file `name`, decompressed if it is
+    /// compressed as `compression` says.
+    pub fn new(inner: R, compression: Option<Compression>, name: &str) -> Result<Self> {
+        match compression {
+            None => Ok(Input::Plain(inner)),
+            Some(compression) => Ok(Input::Decompressed(Decompressed::spawn(
+                inner,
+                compression,
+                name,
+            )?)),
+        }
+    }
+}
+
+/// Opens the file at `path`, decompressing it as it is read if it is
+/// compressed with gzip or zstd.
+pub fn open_input(path: &str) -> Result<Input<File>> {
+    let mut file = File::open(path).map_err(|x| format!("error opening {}: {}", path, x))?;
+    let compression =
+        Compression::detect(&mut file).map_err(|x| format!("error reading {}: {}", path, x))?;
+    Input::new(file, compression, path)
+}
+
+/// A compressed file streamed through `gzip` or `zstd`, like compressed
+/// state files are, so a multi-GB archive is never decompressed to disk
+/// or held in memory. The compressed bytes are fed to the command from a
+/// thread of their own, so whatever reads them (and counts them for
+/// progress) sees the file as it is on disk.
+pub struct Decompressed {
+    child: Child,
+    stdout: ChildStdout,
+    feeder: Option<JoinHandle<io::Result<u64>>>,
+    compression: Compression,
+    name: String,
+}
+
+impl Decompressed {
+    fn spawn(
+        mut inner: impl Read + Send + 'static,
+        compression: Compression,
+        name: &str,
+    ) -> Result<Self> {
+        // A failure is reported by `finish` with the name of the file, not
+        // by whatever the command prints.
+        let mut child = Command::new(compression.command())
+            .args(["-d", "-c", "-q"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|x| match x.kind() {
+                ErrorKind::NotFound => format!(
+                    "{} is compressed with {}, but {} was not found on the PATH to decompress it",
+                    name,
+                    compression.command(),
+                    compression.command()
+                ),
+                _ => format!("error running {}: {}", compression.command(), x),
+            })?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let feeder = std::thread::spawn(move || io::copy(&mut inner, &mut stdin));
+        Ok(Decompressed {
+            child,
+            stdout,
+            feeder: Some(feeder),
+            compression,
+            name: name.to_string(),
+        })
+    }
+
+    /// Waits for the command once its output ended, failing if it didn't
+    /// get to the end of a valid file.
+    fn finish(&mut self) -> io::Result<()> {
+        let fed = match self.feeder.take() {
+            Some(feeder) => feeder
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("the feeding thread panicked"))),
+            None => return Ok(()),
+        };
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} failed to read {}",
+                    self.compression.command(),
+                    self.name
+                ),
+            ));
+        }
+        fed.map(|_| ())
+            .map_err(|x| io::Error::new(x.kind(), format!("error reading {}: {}", self.name, x)))
+    }
+}
+
+impl Read for Decompressed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.finish()?;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for Decompressed {
+    fn drop(&mut self) {
+        // A run that stopped before the end of the input leaves the command
+        // waiting to write the rest.
+        if self.feeder.is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{open_file_read_csv, run_engine, state::export_state, Engine};
+
+    #[test]
+    fn compressed_inputs_read_like_plain_ones() -> Result<()> {
+        let plain = Engine::default();
+        run_engine(
+            open_file_read_csv("test-files/example_input.csv".to_string())?,
+            &plain,
+        )?;
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let path = crate::temp_path(&format!(
+                "payments-engine-compressed-test.{}",
+                compression.command()
+            ));
+            let compressed = match Command::new(compression.command())
+                .args(["-c", "test-files/example_input.csv"])
+                .output()
+            {
+                Err(x) if x.kind() == ErrorKind::NotFound => {
+                    eprintln!("skipping {}, it isn't installed", compression.command());
+                    continue;
+                }
+                compressed => compressed?,
+            };
+            if !compressed.status.success() {
+                return Err(format!("{} failed", compression.command()).into());
+            }
+            std::fs::write(&path, &compressed.stdout)?;
+            assert_eq!(
+                Compression::detect(&mut File::open(&path)?)?,
+                Some(compression)
+            );
+            let engine = Engine::default();
+            run_engine(open_file_read_csv(path.display().to_string())?, &engine)?;
+            assert_eq!(export_state(&engine.db)?, export_state(&plain.db)?);
+
+            // A file cut short fails rather than reading as a shorter input.
+            std::fs::write(&path, &compressed.stdout[..compressed.stdout.len() / 2])?;
+            let engine = Engine::default();
+            assert!(run_engine(open_file_read_csv(path.display().to_string())?, &engine).is_err());
+            std::fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
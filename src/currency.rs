@@ -0,0 +1,51 @@
+use crate::{Result, TransactionType};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The funds of an account in one currency.
+pub struct Balance {
+    pub available: f64,
+    pub held: f64,
+}
+
+impl Balance {
+    pub fn is_zero(&self) -> bool {
+        self.available == 0.0 && self.held == 0.0
+    }
+}
+
+/// Parses the `currency` of a transaction, a three letter code such as
+/// `EUR`, in any case.
+pub fn parse_currency(code: &str) -> Result<Box<str>> {
+    let code = code.trim();
+    if code.len() != 3 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(format!("invalid currency {:?}, expected a code like EUR", code).into());
+    }
+    Ok(code.to_ascii_uppercase().into())
+}
+
+/// The currency of a transaction of `transaction_type` whose `currency`
+/// column says `currency`. Only deposits and withdrawals have one of their
+/// own. Disputes, resolves and chargebacks are in the currency of the
+/// transaction they refer to, whatever their row says, and transfers and
+/// admin operations in the account's own currency.
+pub fn transaction_currency(
+    transaction_type: TransactionType,
+    currency: Option<&str>,
+) -> Result<Option<Box<str>>> {
+    match (transaction_type, currency) {
+        (_, None) => Ok(None),
+        (TransactionType::Deposit | TransactionType::Withdrawal, Some(code)) => {
+            parse_currency(code).map(Some)
+        }
+        (TransactionType::Dispute | TransactionType::Resolve | TransactionType::ChargeBack, _) => {
+            Ok(None)
+        }
+        (_, Some(_)) => Err(format!(
+            "a {} is in the account's own currency, it can't have one",
+            transaction_type.as_str()
+        )
+        .into()),
+    }
+}
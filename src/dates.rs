@@ -0,0 +1,153 @@
+use crate::clock::Timestamp;
+use serde::Deserialize;
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+pub const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(try_from = "String")]
+/// A day of the proleptic Gregorian calendar, in UTC.
+pub struct Date {
+    /// Days since 1970-01-01.
+    days: i64,
+}
+
+impl Date {
+    pub fn from_ymd(year: i64, month: u32, day: u32) -> Self {
+        // Howard Hinnant's days_from_civil.
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = month as i64;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        Date {
+            days: era * 146_097 + day_of_era - 719_468,
+        }
+    }
+
+    /// The day `timestamp` falls on.
+    pub fn from_timestamp(timestamp: Timestamp) -> Self {
+        Date {
+            days: (timestamp / SECONDS_PER_DAY) as i64,
+        }
+    }
+
+    /// The year, month and day of the date.
+    pub fn ymd(self) -> (i64, u32, u32) {
+        // Howard Hinnant's civil_from_days.
+        let days = self.days + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        (year, month, day)
+    }
+
+    /// Whether the date is a Saturday or a Sunday.
+    pub fn is_weekend(self) -> bool {
+        // 1970-01-01 was a Thursday, counting from Sunday as 0.
+        matches!((self.days + 4).rem_euclid(7), 0 | 6)
+    }
+
+    pub fn next(self) -> Self {
+        Date {
+            days: self.days + 1,
+        }
+    }
+}
+
+impl FromStr for Date {
+    type Err = String;
+
+    /// Parses a `YYYY-MM-DD` date.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid date {:?}, expected YYYY-MM-DD", s);
+        let mut parts = s.trim().splitn(3, '-');
+        let mut part = || parts.next().ok_or_else(invalid);
+        let year: i64 = part()?.parse().map_err(|_| invalid())?;
+        let month: u32 = part()?.parse().map_err(|_| invalid())?;
+        let day: u32 = part()?.parse().map_err(|_| invalid())?;
+        let date = Date::from_ymd(year, month, day);
+        // Out of range months or days roll over into another date.
+        if date.ymd() != (year, month, day) {
+            return Err(invalid());
+        }
+        Ok(date)
+    }
+}
+
+impl TryFrom<String> for Date {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = self.ymd();
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+/// The days of a region that aren't business days besides weekends,
+/// written as a list of `YYYY-MM-DD` dates.
+pub struct Calendar {
+    holidays: BTreeSet<Date>,
+}
+
+impl Calendar {
+    pub fn is_business_day(&self, date: Date) -> bool {
+        !date.is_weekend() && !self.holidays.contains(&date)
+    }
+
+    /// The business days after the day of `from` up to and including the
+    /// day of `to`, so a span within one day is 0 business days long.
+    pub fn business_days_between(&self, from: Timestamp, to: Timestamp) -> u64 {
+        let (mut day, to) = (Date::from_timestamp(from), Date::from_timestamp(to));
+        let mut days = 0;
+        while day < to {
+            day = day.next();
+            if self.is_business_day(day) {
+                days += 1;
+            }
+        }
+        days
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn business_days_skip_weekends_and_holidays() -> Result<(), String> {
+        let date = |s: &str| s.parse::<Date>();
+        assert_eq!(Date::from_timestamp(0), date("1970-01-01")?);
+        assert_eq!(date("2024-02-29")?.to_string(), "2024-02-29");
+        assert!(date("2023-02-29").is_err());
+        assert!(date("2024-01-06")?.is_weekend());
+
+        let calendar: Calendar =
+            serde_json::from_str(r#"["2024-01-01"]"#).map_err(|x| x.to_string())?;
+        let midnight = |s: &str| date(s).map(|date| date.days as u64 * SECONDS_PER_DAY);
+        // Friday 2023-12-29 to Tuesday 2024-01-02, over a weekend and New Year.
+        let (friday, tuesday) = (midnight("2023-12-29")?, midnight("2024-01-02")?);
+        assert_eq!(calendar.business_days_between(friday, tuesday), 1);
+        assert_eq!(
+            Calendar::default().business_days_between(friday, tuesday),
+            2
+        );
+        assert_eq!(calendar.business_days_between(tuesday, tuesday + 3_600), 0);
+        Ok(())
+    }
+}
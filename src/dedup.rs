@@ -0,0 +1,165 @@
+use crate::{clock::Timestamp, Result, Transaction, TransactionType};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// What makes two submissions the same for a [`DedupWindow`].
+pub enum DedupBy {
+    /// The `idempotency_key` column. Transactions without one are never
+    /// taken for repeats.
+    #[default]
+    Key,
+    /// The client, type, amount, currency and recipient. Only deposits,
+    /// withdrawals and transfers are compared, the others refer to a
+    /// transaction by id and are deduplicated by it with `--idempotent`.
+    Fingerprint,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    /// When each submission was last let through.
+    at: HashMap<Box<[u8]>, Timestamp>,
+    /// The submissions in the order they were let through, to forget
+    /// them once they leave the window.
+    order: VecDeque<(Timestamp, Box<[u8]>)>,
+}
+
+/// Suppresses a submission that repeats one let through within a window,
+/// such as a retry resent by an upstream that didn't see its answer. The
+/// retry usually has a transaction id of its own, which is why this is
+/// separate from the engine's deduplication by id.
+///
+/// Repeats are told by the transactions' own timestamps, so a replay is
+/// deduplicated the same way as live traffic. It is approximate: two
+/// genuine transactions that look alike within the window are taken for
+/// one.
+#[derive(Debug)]
+pub struct DedupWindow {
+    window: Duration,
+    by: DedupBy,
+    seen: Mutex<Seen>,
+    suppressed: AtomicU64,
+}
+
+impl DedupWindow {
+    pub fn new(window: Duration, by: DedupBy) -> Self {
+        DedupWindow {
+            window,
+            by,
+            seen: Mutex::default(),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether `txn` repeats a submission within the window, in which case
+    /// it is counted as suppressed. Otherwise it is remembered, whether or
+    /// not it is then applied.
+    pub fn repeats(&self, txn: &Transaction) -> Result<bool> {
+        let Some(key) = self.key(txn) else {
+            return Ok(false);
+        };
+        let now = txn.timestamp.unwrap_or_default();
+        let mut seen = self.seen.lock().map_err(|_| "dedup lock poisoned")?;
+        let cutoff = now.saturating_sub(self.window.as_secs());
+        while seen.order.front().is_some_and(|(at, _)| *at < cutoff) {
+            let Some((at, key)) = seen.order.pop_front() else {
+                break;
+            };
+            if seen.at.get(&key) == Some(&at) {
+                seen.at.remove(&key);
+            }
+        }
+        if seen.at.get(&key).is_some_and(|at| *at >= cutoff) {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return Ok(true);
+        }
+        seen.at.insert(key.clone(), now);
+        seen.order.push_back((now, key));
+        Ok(false)
+    }
+
+    /// What `txn` is compared by, if it is compared at all.
+    fn key(&self, txn: &Transaction) -> Option<Box<[u8]>> {
+        match self.by {
+            DedupBy::Key => txn
+                .idempotency_key
+                .as_deref()
+                .map(|key| key.as_bytes().into()),
+            DedupBy::Fingerprint => {
+                let compared = matches!(
+                    txn.transaction_type,
+                    TransactionType::Deposit
+                        | TransactionType::Withdrawal
+                        | TransactionType::Transfer
+                );
+                let amount = txn.amount.filter(|_| compared)?;
+                let mut key = Vec::new();
+                key.extend(txn.client_id.to_le_bytes());
+                key.extend(txn.transaction_type.as_str().as_bytes());
+                key.extend(amount.to_bits().to_le_bytes());
+                key.extend(txn.to_client.unwrap_or_default().to_le_bytes());
+                key.extend(txn.currency.as_deref().unwrap_or_default().as_bytes());
+                Some(key.into())
+            }
+        }
+    }
+
+    /// Submissions suppressed as repeats so far.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Engine, TransactionOutcome};
+
+    #[test]
+    fn retries_within_the_window_are_suppressed() -> Result<()> {
+        let deposit = |tx, at, key: &str| {
+            Transaction::builder(TransactionType::Deposit, 1, tx)
+                .amount(2.0)
+                .timestamp(at)
+                .idempotency_key(key)
+                .build()
+        };
+        let window = DedupWindow::new(Duration::from_secs(30), DedupBy::Key);
+        let engine = Engine::default().with_dedup_window(window);
+        let outcomes = [
+            engine.process(deposit(1, 100, "a")?)?,
+            engine.process(deposit(2, 120, "a")?)?,
+            engine.process(deposit(3, 120, "b")?)?,
+            engine.process(deposit(4, 131, "a")?)?,
+        ];
+        assert_eq!(
+            outcomes.map(|outcome| outcome == TransactionOutcome::Duplicate),
+            [false, true, false, false]
+        );
+        assert_eq!(engine.metrics()?.suppressed_duplicates, Some(1));
+
+        // By fingerprint, only deposits, withdrawals and transfers of the
+        // same amount are alike.
+        let window = DedupWindow::new(Duration::from_secs(30), DedupBy::Fingerprint);
+        let engine = Engine::default().with_dedup_window(window);
+        let at = |txn: Transaction, at| Transaction {
+            timestamp: Some(at),
+            ..txn
+        };
+        engine.process(at(Transaction::deposit(1, 1, 2.0), 100))?;
+        engine.process(at(Transaction::deposit(1, 2, 2.0), 110))?;
+        engine.process(at(Transaction::deposit(1, 3, 3.0), 110))?;
+        engine.process(at(Transaction::dispute(1, 1), 110))?;
+        assert_eq!(engine.metrics()?.suppressed_duplicates, Some(1));
+        let account = engine.account(1)?.ok_or("no account")?;
+        assert_eq!((account.available(), account.held()), (3.0, 2.0));
+        Ok(())
+    }
+}
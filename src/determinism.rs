@@ -0,0 +1,224 @@
+use crate::{
+    clock::SimulatedClock,
+    digest::{format_digest, state_digest},
+    generator::Rng,
+    open_file_read_csv,
+    schema::Schema,
+    state::{export_state, StateFile},
+    ClientId, Database, Engine, Result, Transaction,
+};
+use dashmap::DashMap;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How one run of the audit applies the input.
+pub struct Schedule {
+    /// Threads applying transactions at once. Each client is applied by
+    /// one thread, in input order.
+    pub threads: usize,
+    /// Shards of the engine's maps, which decide the order they are
+    /// iterated in.
+    pub shards: usize,
+    /// Shuffles the order clients are handed to the threads in. `None`
+    /// applies the input in file order on one thread, the baseline the
+    /// other runs are checked against.
+    pub seed: Option<u64>,
+}
+
+impl Schedule {
+    /// The schedule of run `run`: the first is the baseline, the others
+    /// cycle through 1, 2, 4 and 8 threads and 4 to 64 shards, with
+    /// clients in a different order each time.
+    pub fn of(run: usize) -> Self {
+        match run {
+            0 => Schedule {
+                threads: 1,
+                shards: 4,
+                seed: None,
+            },
+            _ => Schedule {
+                threads: 1 << ((run - 1) % 4),
+                shards: 4 << ((run - 1) % 5),
+                seed: Some(run as u64),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The outcome of one run of the audit.
+pub struct AuditRun {
+    pub schedule: Schedule,
+    /// The digest the engine kept up to date as it went.
+    pub digest: u64,
+    /// Set if the kept digest doesn't match one computed from the final
+    /// state, i.e. some change went unaccounted for.
+    pub digest_drift: bool,
+    /// Clients whose account differs from the baseline.
+    pub clients: Vec<ClientId>,
+    /// Stored transactions that differ from the baseline.
+    pub transactions: Vec<u32>,
+}
+
+impl AuditRun {
+    /// Whether the run ended in exactly the baseline's state.
+    pub fn is_deterministic(&self) -> bool {
+        !self.digest_drift && self.clients.is_empty() && self.transactions.is_empty()
+    }
+}
+
+/// Runs `input` through a fresh engine `runs` times, each under another
+/// [`Schedule`], and compares every final state with the first run's.
+///
+/// Every run reads time from the same simulated clock, so only the
+/// schedule differs between them. A state that changes with the thread
+/// count or the order clients or map entries are visited in points at a
+/// code path whose result depends on timing.
+pub fn determinism_audit(input: String, runs: usize) -> Result<Vec<AuditRun>> {
+    let mut reader = open_file_read_csv(input)?;
+    let schema = Schema::from_headers(reader.headers()?, &[])?;
+    let txns = reader
+        .records()
+        .map(|record| schema.parse(&record?))
+        .collect::<Result<Vec<_>>>()?;
+    let mut baseline: Option<StateFile> = None;
+    let mut audit = Vec::new();
+    for run in 0..runs.max(1) {
+        let schedule = Schedule::of(run);
+        let engine = run_schedule(&txns, schedule)?;
+        let digest = engine.db.digest();
+        let state = export_state(&engine.db)?;
+        let expected = baseline.get_or_insert_with(|| state.clone());
+        audit.push(AuditRun {
+            schedule,
+            digest,
+            digest_drift: digest != state_digest(&engine.db)?,
+            clients: differing(&expected.accounts, &state.accounts, |a| a.client),
+            transactions: differing(&expected.transactions, &state.transactions, |t| t.tx),
+        });
+    }
+    Ok(audit)
+}
+
+/// The keys of the entries that differ between `a` and `b`, including
+/// those only in one of them.
+fn differing<T: PartialEq, K: Ord + Copy>(a: &[T], b: &[T], key: impl Fn(&T) -> K) -> Vec<K> {
+    let (a, b): (BTreeMap<_, _>, BTreeMap<_, _>) = (
+        a.iter().map(|x| (key(x), x)).collect(),
+        b.iter().map(|x| (key(x), x)).collect(),
+    );
+    let keys: BTreeSet<_> = a.keys().chain(b.keys()).copied().collect();
+    keys.into_iter().filter(|k| a.get(k) != b.get(k)).collect()
+}
+
+fn run_schedule(txns: &[Transaction], schedule: Schedule) -> Result<Engine> {
+    let mut engine = Engine::with_clock(SimulatedClock::new(0));
+    engine.db = Database {
+        clients: DashMap::with_shard_amount(schedule.shards),
+        transactions: DashMap::with_shard_amount(schedule.shards),
+        ..Database::default()
+    };
+    let Some(seed) = schedule.seed else {
+        for txn in txns {
+            engine.process(txn.clone())?;
+        }
+        return Ok(engine);
+    };
+    let mut by_client = BTreeMap::<ClientId, Vec<&Transaction>>::new();
+    for txn in txns {
+        by_client.entry(txn.client_id).or_default().push(txn);
+    }
+    let mut clients: Vec<_> = by_client.into_values().collect();
+    let mut rng = Rng::new(seed);
+    for i in (1..clients.len()).rev() {
+        clients.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+    let mut workers = vec![Vec::new(); schedule.threads.max(1)];
+    for (i, txns) in clients.into_iter().enumerate() {
+        let n = workers.len();
+        workers[i % n].push(txns);
+    }
+    std::thread::scope(|scope| {
+        let engine = &engine;
+        let threads: Vec<_> = workers
+            .into_iter()
+            .map(|clients| {
+                scope.spawn(move || {
+                    clients
+                        .into_iter()
+                        .flatten()
+                        .try_for_each(|txn| engine.process(txn.clone()).map(|_| ()))
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .try_for_each(|thread| thread.join().expect("audit thread panicked"))
+    })?;
+    Ok(engine)
+}
+
+/// Prints a line per run as csv to stdout, and the clients and
+/// transactions of the runs that differ from the baseline to stderr.
+pub fn print_audit(audit: &[AuditRun]) {
+    println!(
+        "{:>4}, {:>7}, {:>6}, {:>16}, {:>13}",
+        "run", "threads", "shards", "digest", "deterministic"
+    );
+    for (run, result) in audit.iter().enumerate() {
+        println!(
+            "{:>4}, {:>7}, {:>6}, {:>16}, {:>13}",
+            run,
+            result.schedule.threads,
+            result.schedule.shards,
+            format_digest(result.digest),
+            result.is_deterministic()
+        );
+        if result.digest_drift {
+            tracing::warn!("run {}: the kept digest drifted from the state", run);
+        }
+        if !result.clients.is_empty() {
+            tracing::warn!("run {}: clients differ: {:?}", run, result.clients);
+        }
+        if !result.transactions.is_empty() {
+            tracing::warn!(
+                "run {}: transactions differ: {:?}",
+                run,
+                result.transactions
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_same_input_ends_in_the_same_state() -> Result<()> {
+        let audit = determinism_audit("test-files/long_transaction_history.csv".to_string(), 6)?;
+        assert_eq!(audit.len(), 6);
+        assert!(audit.iter().all(AuditRun::is_deterministic));
+        Ok(())
+    }
+
+    #[test]
+    fn order_dependent_input_is_flagged() -> Result<()> {
+        // Both clients deposit under the same transaction id, and the one
+        // applied last is the one kept for disputes.
+        let path = crate::temp_path("payments-engine-determinism-test.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,1,7.0\n",
+        )
+        .map_err(|x| x.to_string())?;
+        let audit = determinism_audit(path.to_string_lossy().to_string(), 8)?;
+        let flagged: Vec<_> = audit
+            .iter()
+            .filter(|run| run.schedule.threads == 1 && !run.is_deterministic())
+            .collect();
+        assert!(!flagged.is_empty());
+        assert_eq!(flagged[0].transactions, vec![1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{Client, ClientId, Database, Result};
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64 bit FNV-1a, which is stable across platforms and releases
+/// unlike the standard library's hasher.
+struct Fnv(u64);
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+/// A digest of everything stored for a single client.
+///
+/// Balances are hashed by their exact bit patterns, so two digests
+/// only match if the balances are identical byte for byte.
+pub fn client_digest(client_id: ClientId, client: &Client) -> u64 {
+    let mut hasher = Fnv(FNV_OFFSET);
+    // Ids that fit in 2 bytes are hashed as 2 bytes, so their accounts keep
+    // the digests they had before ids were wider.
+    match u16::try_from(client_id) {
+        Ok(client_id) => hasher.write(&client_id.to_le_bytes()),
+        Err(_) => hasher.write(&client_id.to_le_bytes()),
+    }
+    hasher.write(&client.available.to_bits().to_le_bytes());
+    hasher.write(&client.held.to_bits().to_le_bytes());
+    hasher.write(&[client.locked as u8]);
+    // Written only once set, so accounts that were never closed keep the
+    // digests they had before accounts could be closed.
+    if client.closed {
+        hasher.write(b"closed");
+    }
+    // When a dispute was opened is bookkeeping for reports, not a balance,
+    // so only which transactions are disputed is part of the digest.
+    let mut disputed: Vec<_> = client.disputed.keys().collect();
+    disputed.sort();
+    for txn_id in disputed {
+        hasher.write(&txn_id.to_le_bytes());
+    }
+    // Only there with the pending holds policy, so other digests stay as
+    // they were.
+    for (txn_id, pending) in &client.pending_holds {
+        hasher.write(b"pending");
+        hasher.write(&txn_id.to_le_bytes());
+        hasher.write(&pending.to_bits().to_le_bytes());
+    }
+    // Likewise only there for accounts with queued deposits, in the order
+    // they came in.
+    for txn_id in &client.queued {
+        hasher.write(b"queued");
+        hasher.write(&txn_id.to_le_bytes());
+    }
+    // Likewise only there for transactions disputed in part, in
+    // transaction order.
+    let mut disputed_amounts: Vec<_> = client.disputed_amounts.iter().collect();
+    disputed_amounts.sort_by_key(|(txn_id, _)| **txn_id);
+    for (txn_id, amount) in disputed_amounts {
+        hasher.write(b"partial");
+        hasher.write(&txn_id.to_le_bytes());
+        hasher.write(&amount.to_bits().to_le_bytes());
+    }
+    // Disputes settled for good, in transaction order.
+    let mut settled: Vec<_> = client.settled.iter().collect();
+    settled.sort_by_key(|(txn_id, _)| **txn_id);
+    for (txn_id, state) in settled {
+        hasher.write(state.as_str().as_bytes());
+        hasher.write(&txn_id.to_le_bytes());
+    }
+    // Likewise only there for accounts with funds in other currencies,
+    // in the order of their codes.
+    for (currency, balance) in &client.currencies {
+        hasher.write(currency.as_bytes());
+        hasher.write(&balance.available.to_bits().to_le_bytes());
+        hasher.write(&balance.held.to_bits().to_le_bytes());
+    }
+    hasher.0
+}
+
+/// A digest of the state of every client.
+///
+/// This is the wrapping sum of the client digests, which makes it
+/// independent of iteration order and lets the engine keep it up to date
+/// incrementally by swapping out the digest of the client that changed.
+/// Clients evicted to a cold store are included.
+pub fn state_digest(db: &Database) -> Result<u64> {
+    let mut digest = 0u64;
+    db.for_each_client(|client_id, client| {
+        digest = digest.wrapping_add(client_digest(client_id, client))
+    })?;
+    Ok(digest)
+}
+
+/// A hash of `bytes` keyed by `salt`, stable across platforms. It is not
+/// a cryptographic hash: without the salt the values can't be predicted,
+/// but they aren't meant to withstand deliberate analysis.
+pub fn salted_hash(salt: &str, bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv(FNV_OFFSET);
+    hasher.write(salt.as_bytes());
+    hasher.write(&[0]);
+    hasher.write(bytes);
+    hasher.0
+}
+
+/// The textual form of a digest used in files and reports.
+pub fn format_digest(digest: u64) -> String {
+    format!("{:016x}", digest)
+}
@@ -0,0 +1,260 @@
+use crate::{
+    clock::Timestamp,
+    dates::{Calendar, SECONDS_PER_DAY},
+    i18n::Reason,
+    ClientId, Database, Engine, Result, Transaction, TransactionOutcome, TransactionType,
+};
+use std::{sync::atomic::Ordering, time::Duration};
+
+/// The memo of the chargebacks [`auto_chargeback`] generates, which marks
+/// them in the capture as raised by the policy rather than received.
+pub const AUTO_CHARGEBACK_MEMO: &str = "auto-generated: dispute unresolved";
+
+/// The age buckets of the report, by the most days a dispute in the
+/// bucket can have been open. The last bucket takes everything older.
+pub const BUCKETS: [(&str, Option<u64>); 3] =
+    [("0-7d", Some(7)), ("8-30d", Some(30)), (">30d", None)];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Where a deposit or withdrawal is in its dispute lifecycle. A
+/// transaction is disputed at most once: a dispute is resolved or charged
+/// back for good.
+pub enum TxnState {
+    #[default]
+    Posted,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl TxnState {
+    /// The state a dispute, resolve or chargeback takes a transaction in
+    /// this state to, or why it can't.
+    pub fn next(self, transaction_type: TransactionType) -> std::result::Result<Self, Reason> {
+        use TransactionType::*;
+        match (self, transaction_type) {
+            (TxnState::Posted, Dispute) => Ok(TxnState::Disputed),
+            (TxnState::Disputed, Resolve) => Ok(TxnState::Resolved),
+            (TxnState::Disputed, ChargeBack) => Ok(TxnState::ChargedBack),
+            (_, Dispute) => Err(Reason::AlreadyDisputed),
+            (_, Resolve | ChargeBack) => Err(Reason::NotDisputed),
+            _ => Err(Reason::Unprocessable),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TxnState::Posted => "posted",
+            TxnState::Disputed => "disputed",
+            TxnState::Resolved => "resolved",
+            TxnState::ChargedBack => "charged_back",
+        }
+    }
+}
+
+impl std::str::FromStr for TxnState {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "posted" => Ok(TxnState::Posted),
+            "disputed" => Ok(TxnState::Disputed),
+            "resolved" => Ok(TxnState::Resolved),
+            "charged_back" => Ok(TxnState::ChargedBack),
+            _ => Err(format!("unknown transaction state {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A dispute that hasn't been resolved or charged back yet.
+pub struct OpenDispute {
+    pub client_id: ClientId,
+    pub txn_id: u32,
+    /// The amount held for the dispute.
+    pub amount: f64,
+    pub opened: Timestamp,
+    /// Seconds the dispute has been open for.
+    pub age: u64,
+}
+
+impl OpenDispute {
+    /// The index in [`BUCKETS`] of the bucket the dispute falls in.
+    pub fn bucket(&self) -> usize {
+        let days = self.age / SECONDS_PER_DAY;
+        BUCKETS
+            .iter()
+            .position(|(_, max)| max.is_none_or(|max| days <= max))
+            .unwrap_or(BUCKETS.len() - 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// How the age of a dispute is counted.
+pub enum Aging {
+    /// Every second counts.
+    #[default]
+    Calendar,
+    /// Only whole business days count, skipping weekends and the holidays
+    /// of the client's region.
+    BusinessDays,
+}
+
+impl Aging {
+    /// Seconds between `from` and `to`, as counted by this aging.
+    pub fn age(self, calendar: &Calendar, from: Timestamp, to: Timestamp) -> u64 {
+        match self {
+            Aging::Calendar => to.saturating_sub(from),
+            Aging::BusinessDays => calendar.business_days_between(from, to) * SECONDS_PER_DAY,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Bucket {
+    pub disputes: u64,
+    pub amount: f64,
+}
+
+/// Every open dispute of every client, aged as of `now`, oldest first.
+pub fn open_disputes(db: &Database, now: Timestamp, aging: Aging) -> Result<Vec<OpenDispute>> {
+    let mut disputes = Vec::new();
+    db.for_each_client(|client_id, client| {
+        let calendar = &db.policy.regions.rules_for(client_id, now).holidays;
+        for (txn_id, opened) in &client.disputed {
+            disputes.push(OpenDispute {
+                client_id,
+                txn_id: *txn_id,
+                // Filled in with the whole transaction's below, unless only
+                // a part of it is disputed.
+                amount: client.disputed_amounts.get(txn_id).copied().unwrap_or(0.0),
+                opened: *opened,
+                age: aging.age(calendar, *opened, now),
+            });
+        }
+    })?;
+    for dispute in disputes.iter_mut().filter(|dispute| dispute.amount == 0.0) {
+        dispute.amount = db
+            .with_transaction(dispute.txn_id, |txn| txn.amount)?
+            .flatten()
+            .unwrap_or_default();
+    }
+    disputes.sort_by_key(|dispute| (dispute.opened, dispute.client_id, dispute.txn_id));
+    Ok(disputes)
+}
+
+/// Counts the disputes, and sums their amounts, per age bucket.
+pub fn age_buckets(disputes: &[OpenDispute]) -> [Bucket; BUCKETS.len()] {
+    let mut buckets = [Bucket::default(); BUCKETS.len()];
+    for dispute in disputes {
+        let bucket = &mut buckets[dispute.bucket()];
+        bucket.disputes += 1;
+        bucket.amount += dispute.amount;
+    }
+    buckets
+}
+
+/// The disputes that have been open for longer than `sla`.
+pub fn breaching(disputes: &[OpenDispute], sla: Duration) -> impl Iterator<Item = &OpenDispute> {
+    disputes
+        .iter()
+        .filter(move |dispute| dispute.age > sla.as_secs())
+}
+
+/// Charges back every dispute that has been open for the policy's
+/// `auto_chargeback_after` or longer, oldest first, returning the disputes
+/// charged back. Nothing is done if the policy doesn't set it.
+///
+/// Time is that of the transactions, so a dispute is due once the latest
+/// transaction seen is that much younger than it. Each chargeback is
+/// processed like a received one, so it is captured, published and
+/// notified of, timestamped at the time it fell due and marked with
+/// [`AUTO_CHARGEBACK_MEMO`].
+pub fn auto_chargeback(engine: &Engine) -> Result<Vec<OpenDispute>> {
+    let Some(after) = engine.db.policy.auto_chargeback_after else {
+        return Ok(Vec::new());
+    };
+    let now = engine.db.latest_activity.load(Ordering::SeqCst);
+    let mut charged = Vec::new();
+    for dispute in open_disputes(&engine.db, now, Aging::Calendar)? {
+        if dispute.age < after.as_secs() {
+            continue;
+        }
+        let chargeback = Transaction {
+            timestamp: Some(dispute.opened.saturating_add(after.as_secs())),
+            memo: Some(AUTO_CHARGEBACK_MEMO.into()),
+            ..Transaction::chargeback(dispute.client_id, dispute.txn_id)
+        };
+        if engine.process(chargeback)? == TransactionOutcome::Applied {
+            charged.push(dispute);
+        }
+    }
+    Ok(charged)
+}
+
+/// Prints the age buckets as csv to stdout.
+pub fn print_disputes_report(buckets: &[Bucket; BUCKETS.len()]) {
+    println!("{:>7}, {:>12}, {:>12}", "age", "disputes", "amount");
+    for ((name, _), bucket) in BUCKETS.iter().zip(buckets) {
+        println!(
+            "{:>7}, {:>12}, {:>12.4}",
+            name, bucket.disputes, bucket.amount
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disputes_fall_in_their_age_bucket() {
+        let dispute = |days: u64| OpenDispute {
+            client_id: 1,
+            txn_id: 1,
+            amount: 2.0,
+            opened: 0,
+            age: days * SECONDS_PER_DAY + 1,
+        };
+        let disputes = [dispute(0), dispute(7), dispute(8), dispute(30), dispute(31)];
+        let buckets = age_buckets(&disputes);
+        assert_eq!(
+            buckets.map(|bucket| bucket.disputes),
+            [2, 2, 1],
+            "{:?}",
+            buckets
+        );
+        assert_eq!(buckets[0].amount, 4.0);
+        let sla = Duration::from_secs(30 * SECONDS_PER_DAY);
+        assert_eq!(breaching(&disputes, sla).count(), 2);
+    }
+
+    #[test]
+    fn stale_disputes_are_charged_back() -> Result<()> {
+        let day = |days: u64| Some(days * SECONDS_PER_DAY);
+        let engine = Engine::default().with_policy(crate::policy::Policy {
+            auto_chargeback_after: Some(Duration::from_secs(30 * SECONDS_PER_DAY)),
+            ..crate::policy::Policy::default()
+        });
+        for txn in [
+            Transaction::deposit(1, 1, 5.0),
+            Transaction::deposit(2, 2, 5.0),
+            Transaction::dispute(1, 1),
+            Transaction::dispute(2, 2),
+            Transaction::deposit(3, 3, 1.0),
+        ]
+        .into_iter()
+        .zip([day(0), day(0), day(1), day(20), day(40)])
+        {
+            engine.process(Transaction {
+                timestamp: txn.1,
+                ..txn.0
+            })?;
+        }
+        let charged = auto_chargeback(&engine)?;
+        assert_eq!(charged.iter().map(|d| d.client_id).collect::<Vec<_>>(), [1]);
+        assert!(engine.db.clients.get(&1).is_some_and(|c| c.locked));
+        assert!(engine.db.clients.get(&2).is_some_and(|c| !c.locked));
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{
+    account::AccountView, i18n::Reason, whatif::accounts, ClientId, Engine, Result, RunSummary,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+/// A client whose account a dry run would change, with its balances
+/// afterwards and how far they moved.
+pub struct AccountChange {
+    pub client: ClientId,
+    pub available: f64,
+    pub held: f64,
+    pub available_delta: f64,
+    pub held_delta: f64,
+    pub total_delta: f64,
+    pub locked: bool,
+    /// The account wasn't locked before the run.
+    pub locks: bool,
+    /// The client had no account before the run.
+    pub new: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+/// A transaction a dry run would reject.
+pub struct Rejection {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub client: ClientId,
+    pub tx: u32,
+    pub reason: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+/// What a dry run would do to the state it started from.
+pub struct Impact {
+    pub rows: u64,
+    pub processed: u64,
+    pub rejected: u64,
+    /// Rows that couldn't be parsed or broke a consistency rule.
+    pub skipped: u64,
+    /// The accounts that would change, by client.
+    pub changes: Vec<AccountChange>,
+    /// The clients whose accounts would lock.
+    pub locks: Vec<ClientId>,
+    /// The transactions that would be rejected, in the order they were
+    /// handled.
+    pub rejections: Vec<Rejection>,
+}
+
+/// A run whose effect on the state is reported rather than kept. It
+/// starts from the state the engine holds, loaded from `--append-state`
+/// or a snapshot, and collects the transactions the engine rejects.
+/// Saving the state afterwards is up to the caller, which a dry run
+/// doesn't do.
+pub struct DryRun {
+    before: BTreeMap<ClientId, AccountView>,
+    rejections: Arc<Mutex<Vec<Rejection>>>,
+}
+
+impl DryRun {
+    /// Starts a dry run on `engine` as its state is now.
+    pub fn start(engine: &mut Engine) -> Result<Self> {
+        let rejections = Arc::new(Mutex::new(Vec::new()));
+        let collected = rejections.clone();
+        engine
+            .hooks
+            .on_reject
+            .push(Box::new(move |txn, reason: Reason| {
+                if let Ok(mut rejections) = collected.lock() {
+                    rejections.push(Rejection {
+                        kind: txn.transaction_type.as_str(),
+                        client: txn.client_id,
+                        tx: txn.txn_id,
+                        reason: reason.code(),
+                    });
+                }
+            }));
+        Ok(DryRun {
+            before: accounts(engine)?,
+            rejections,
+        })
+    }
+
+    /// What the run that ended with `summary` did to the state of
+    /// `engine` since the dry run started.
+    pub fn impact(self, engine: &Engine, summary: &RunSummary) -> Result<Impact> {
+        let precision = engine.db.policy.precision;
+        let mut changes = Vec::new();
+        for (client, after) in accounts(engine)? {
+            let before = self.before.get(&client);
+            if before == Some(&after) {
+                continue;
+            }
+            let (available, held, locked) = before.map_or((0.0, 0.0, false), |before| {
+                (before.available(), before.held(), before.locked())
+            });
+            changes.push(AccountChange {
+                client,
+                available: after.available(),
+                held: after.held(),
+                available_delta: precision.round(after.available() - available),
+                held_delta: precision.round(after.held() - held),
+                total_delta: precision.round(after.total() - available - held),
+                locked: after.locked(),
+                locks: after.locked() && !locked,
+                new: before.is_none(),
+            });
+        }
+        let locks = changes
+            .iter()
+            .filter(|change| change.locks)
+            .map(|change| change.client)
+            .collect();
+        let rejections = std::mem::take(
+            &mut *self
+                .rejections
+                .lock()
+                .map_err(|_| "rejections lock poisoned")?,
+        );
+        Ok(Impact {
+            rows: summary.rows,
+            processed: summary.processed,
+            rejected: summary.rejected,
+            skipped: summary.errors,
+            changes,
+            locks,
+            rejections,
+        })
+    }
+}
+
+/// Writes `impact` as pretty JSON.
+pub fn write_impact(impact: &Impact, mut out: impl Write) -> Result<()> {
+    serde_json::to_writer_pretty(&mut out, impact).map_err(|x| x.to_string())?;
+    writeln!(out).map_err(|x| format!("error writing the dry run impact: {}", x).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, run_transactions, RunOptions, Transaction};
+
+    #[test]
+    fn impact_lists_changes_locks_and_rejections() -> Result<()> {
+        let mut engine = Engine::with_clock(SimulatedClock::new(100));
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        engine.process(Transaction::deposit(2, 2, 5.0))?;
+        engine.process(Transaction::deposit(3, 3, 1.0))?;
+
+        let dry_run = DryRun::start(&mut engine)?;
+        let batch = [
+            Transaction::withdrawal(1, 4, 2.5),
+            Transaction::dispute(2, 2),
+            Transaction::chargeback(2, 2),
+            Transaction::withdrawal(3, 5, 7.0),
+            Transaction::deposit(4, 6, 3.0),
+        ];
+        let summary = run_transactions(batch.into_iter().map(Ok), &engine, &RunOptions::default())?;
+        let impact = dry_run.impact(&engine, &summary)?;
+        let changes: Vec<_> = impact
+            .changes
+            .iter()
+            .map(|change| (change.client, change.total_delta, change.locks, change.new))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (1, -2.5, false, false),
+                (2, -5.0, true, false),
+                (4, 3.0, false, true)
+            ]
+        );
+        assert_eq!(impact.locks, [2]);
+        assert_eq!(
+            impact.rejections,
+            [Rejection {
+                kind: "withdrawal",
+                client: 3,
+                tx: 5,
+                reason: "insufficient_funds"
+            }]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{RecordError, Result};
+use std::{fs::File, path::Path};
+
+/// The columns of an `--errors` file.
+pub const ERROR_COLUMNS: [&str; 3] = ["input", "row", "error"];
+
+/// Where a run reports the rows it skipped: a csv file with a line per
+/// row, or stderr.
+#[derive(Debug)]
+pub struct ErrorLog {
+    sidecar: Option<csv::Writer<File>>,
+}
+
+impl ErrorLog {
+    /// Reports to a csv file at `path`, created with its header even if
+    /// no row is skipped, or to stderr if not given.
+    pub fn open(path: Option<&Path>) -> Result<Self> {
+        let sidecar = match path {
+            Some(path) => {
+                let mut writer = csv::Writer::from_path(path)?;
+                writer.write_record(ERROR_COLUMNS)?;
+                Some(writer)
+            }
+            None => None,
+        };
+        Ok(ErrorLog { sidecar })
+    }
+
+    pub fn skip(&mut self, error: &RecordError) -> Result<()> {
+        match &mut self.sidecar {
+            Some(writer) => writer.write_record([
+                error.input.as_deref().unwrap_or_default(),
+                &error.row.to_string(),
+                &error.error.to_string(),
+            ])?,
+            None => tracing::warn!(
+                input = error.input.as_deref().unwrap_or_default(),
+                row = error.row,
+                "{}",
+                error
+            ),
+        }
+        Ok(())
+    }
+
+    /// Flushes the rows reported so far.
+    pub fn finish(self) -> Result<()> {
+        if let Some(mut writer) = self.sidecar {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{inputs::read_inputs, run_transactions, validation::Mode, Engine, RunOptions};
+
+    #[test]
+    fn bad_rows_are_skipped_and_listed() -> Result<()> {
+        let input = crate::temp_path("payments-engine-errors-test.csv");
+        let sidecar = crate::temp_path("payments-engine-errors-test.errors.csv");
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\ndeposit,1,1,2.0\nrefund,1,2,1.0\ndeposit,1,x,1.0\ndeposit,1,3,1.0\n",
+        )?;
+        let options = RunOptions {
+            errors: Some(sidecar.clone()),
+            ..RunOptions::default()
+        };
+        let engine = Engine::default();
+        let inputs = [input.to_string_lossy().to_string()];
+        let summary = run_transactions(read_inputs(&inputs, &options)?, &engine, &options)?;
+        assert_eq!((summary.rows, summary.processed, summary.errors), (4, 2, 2));
+        assert_eq!(engine.account(1)?.map(|a| a.available()), Some(3.0));
+        let mut reader = csv::Reader::from_path(&sidecar)?;
+        let rows: Vec<(String, u64, String)> = reader
+            .deserialize()
+            .collect::<std::result::Result<_, _>>()?;
+        assert_eq!(rows[0].1, 2);
+        assert_eq!(rows[0].2, "unknown transaction type \"refund\"");
+        assert_eq!(rows[1].1, 3);
+
+        // In strict mode the run stops at the first of them.
+        let options = RunOptions {
+            mode: Mode::Strict,
+            ..RunOptions::default()
+        };
+        let engine = Engine::default();
+        let stopped = run_transactions(read_inputs(&inputs, &options)?, &engine, &options);
+        assert!(stopped.is_err_and(|x| x.to_string().contains("row 2: unknown transaction type")));
+        assert_eq!(engine.account(1)?.map(|a| a.available()), Some(2.0));
+        std::fs::remove_file(&input)?;
+        std::fs::remove_file(&sidecar)?;
+        Ok(())
+    }
+}
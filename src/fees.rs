@@ -0,0 +1,228 @@
+use crate::{ledger::Ledger, open_file_read_csv, ClientId, Result, TransactionType};
+use serde::Deserialize;
+use std::{collections::HashMap, fs::File, io::BufReader, io::Write};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// The fee of a transaction: a flat amount plus a percentage of the
+/// transaction's amount.
+pub struct Fee {
+    pub flat: f64,
+    pub percent: f64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// The fees of deposits and withdrawals. Unset ones are free, or for a
+/// tier, those of the default schedule.
+pub struct Schedule {
+    pub deposit: Option<Fee>,
+    pub withdrawal: Option<Fee>,
+}
+
+impl Schedule {
+    fn of(&self, transaction_type: TransactionType) -> Option<Fee> {
+        match transaction_type {
+            TransactionType::Deposit => self.deposit,
+            TransactionType::Withdrawal => self.withdrawal,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FeeConfig {
+    /// The schedule of clients without a tier, or of a tier not listed.
+    #[serde(default)]
+    default: Schedule,
+    #[serde(default)]
+    tiers: HashMap<String, Schedule>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+/// The fees charged on deposits and withdrawals, by client tier.
+///
+/// Clients belong to the tier named in the `tier` column of the client
+/// metadata. The default is no fees at all.
+pub struct Fees {
+    default: Schedule,
+    tiers: HashMap<String, Schedule>,
+    clients: HashMap<ClientId, String>,
+}
+
+impl Fees {
+    /// Fees of `default` for every client, see [`Fees::with_tier`].
+    pub fn new(default: Schedule) -> Self {
+        Fees {
+            default,
+            ..Fees::default()
+        }
+    }
+
+    /// Puts `clients` in a tier charged by `schedule`.
+    pub fn with_tier(mut self, tier: &str, schedule: Schedule, clients: &[ClientId]) -> Self {
+        self.tiers.insert(tier.to_string(), schedule);
+        for client_id in clients {
+            self.clients.insert(*client_id, tier.to_string());
+        }
+        self
+    }
+
+    /// Reads the fee schedules from the JSON file `config`, and the tier of
+    /// each client from the `client` and `tier` columns of the csv
+    /// `clients`, if given.
+    pub fn load(config: String, clients: Option<String>) -> Result<Self> {
+        let file = File::open(&config).map_err(|x| format!("error opening {}: {}", config, x))?;
+        let parsed: FeeConfig = serde_json::from_reader(BufReader::new(file))
+            .map_err(|x| format!("{} is not a valid fees file: {}", config, x))?;
+        let mut fees = Fees {
+            default: parsed.default,
+            tiers: parsed.tiers,
+            clients: HashMap::new(),
+        };
+        if fees.tiers.is_empty() {
+            return Ok(fees);
+        }
+        let clients = clients.ok_or("fee tiers need --client-metadata naming each tier")?;
+        let mut reader = open_file_read_csv(clients.clone())?;
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|h| h.trim().eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("{} has no {} column", clients, name))
+        };
+        let (client, tier) = (column("client")?, column("tier")?);
+        for record in reader.records() {
+            let record = record?;
+            let client_id = record
+                .get(client)
+                .unwrap_or("")
+                .trim()
+                .parse::<ClientId>()?;
+            let name = record.get(tier).unwrap_or("").trim();
+            if !name.is_empty() {
+                fees.clients.insert(client_id, name.to_string());
+            }
+        }
+        Ok(fees)
+    }
+
+    /// The fee `client_id` pays for a transaction of `transaction_type`
+    /// moving `amount`, never more than the amount itself. Only deposits
+    /// and withdrawals have fees.
+    pub fn fee(&self, client_id: ClientId, transaction_type: TransactionType, amount: f64) -> f64 {
+        let tier = self
+            .clients
+            .get(&client_id)
+            .and_then(|tier| self.tiers.get(tier));
+        tier.and_then(|schedule| schedule.of(transaction_type))
+            .or_else(|| self.default.of(transaction_type))
+            .map_or(0.0, |fee| {
+                (fee.flat + amount * fee.percent / 100.0).clamp(0.0, amount.max(0.0))
+            })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+/// The fees of one client.
+pub struct FeeTotals {
+    /// Fees taken on deposits and withdrawals.
+    pub charged: f64,
+    /// Fees given back as the transactions they were taken on were charged
+    /// back.
+    pub reversed: f64,
+}
+
+impl FeeTotals {
+    pub fn net(&self) -> f64 {
+        self.charged - self.reversed
+    }
+}
+
+/// Writes the fees of each client that paid any as csv, with the columns
+/// `client,charged,reversed,net`, in client order.
+pub fn write_fees_report(ledger: &Ledger, out: impl Write) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["client", "charged", "reversed", "net"])?;
+    for (client_id, totals) in ledger.fees()? {
+        writer.write_record([
+            client_id.to_string(),
+            format!("{:.4}", totals.charged),
+            format!("{:.4}", totals.reversed),
+            format!("{:.4}", totals.net()),
+        ])?;
+    }
+    writer
+        .flush()
+        .map_err(|x| format!("error writing the fees report: {}", x).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        i18n::Reason, ledger::LedgerAccount, policy::Policy, Engine, Transaction,
+        TransactionOutcome,
+    };
+
+    #[test]
+    fn fees_are_charged_by_tier_and_reversed_by_chargebacks() -> Result<()> {
+        let default = Schedule {
+            deposit: Some(Fee {
+                flat: 0.5,
+                percent: 0.0,
+            }),
+            withdrawal: Some(Fee {
+                flat: 0.0,
+                percent: 10.0,
+            }),
+        };
+        let gold = Schedule {
+            deposit: Some(Fee::default()),
+            withdrawal: None,
+        };
+        let engine = Engine::default().with_policy(Policy {
+            fees: Fees::new(default).with_tier("gold", gold, &[2]),
+            ..Policy::default()
+        });
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        engine.process(Transaction::deposit(2, 2, 10.0))?;
+        // 9.5 available, 9.0 and its 0.9 fee don't fit.
+        assert_eq!(
+            engine.process(Transaction::withdrawal(1, 3, 9.0))?,
+            TransactionOutcome::Rejected(Reason::InsufficientFunds)
+        );
+        engine.process(Transaction::withdrawal(1, 4, 5.0))?;
+        engine.process(Transaction::withdrawal(2, 5, 5.0))?;
+        let available = |client_id| -> Result<f64> {
+            Ok(engine.account(client_id)?.ok_or("no account")?.available())
+        };
+        assert_eq!((available(1)?, available(2)?), (4.0, 4.5));
+
+        engine.process(Transaction::dispute(1, 1))?;
+        engine.process(Transaction::chargeback(1, 1))?;
+        let account = engine.account(1)?.ok_or("no account")?;
+        assert_eq!((account.available(), account.held()), (-5.5, 0.0));
+        let fees = engine.db.ledger.fees()?;
+        assert_eq!(
+            fees.get(&1),
+            Some(&FeeTotals {
+                charged: 1.0,
+                reversed: 0.5
+            })
+        );
+        assert_eq!(fees.get(&2).map(FeeTotals::net), Some(0.5));
+        let books = engine.db.ledger.trial_balance()?;
+        assert_eq!(books[&LedgerAccount::Fees].balance(), -1.0);
+
+        let mut report = Vec::new();
+        write_fees_report(&engine.db.ledger, &mut report)?;
+        assert_eq!(
+            String::from_utf8_lossy(&report),
+            "client,charged,reversed,net\n1,1.0000,0.5000,0.5000\n2,0.5000,0.0000,0.5000\n"
+        );
+        Ok(())
+    }
+}
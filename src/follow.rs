@@ -0,0 +1,287 @@
+use crate::{
+    inputs::schema_for, schema::line_record, schema::Schema, PaymentsEngineError, RecordError,
+    Result, RunOptions, Transaction,
+};
+use std::{
+    fs::{File, Metadata},
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+/// How a followed file is watched.
+pub struct FollowOptions {
+    /// How long to wait before looking for new rows again once every row
+    /// was read.
+    pub poll_interval: Duration,
+    /// How often the report is flushed while following.
+    pub flush_every: Duration,
+    /// Stops following once no row was appended for this long, or never
+    /// if unset.
+    pub idle_exit: Option<Duration>,
+}
+
+impl Default for FollowOptions {
+    fn default() -> Self {
+        FollowOptions {
+            poll_interval: Duration::from_millis(200),
+            flush_every: Duration::from_secs(10),
+            idle_exit: None,
+        }
+    }
+}
+
+/// What tells a file apart from the one that replaces it when it is
+/// rotated: its device and inode.
+type Identity = Option<(u64, u64)>;
+
+#[cfg(unix)]
+fn identity(metadata: &Metadata) -> Identity {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Rotation is only noticed as truncation where files have no identity.
+#[cfg(not(unix))]
+fn identity(_metadata: &Metadata) -> Identity {
+    None
+}
+
+/// The transactions of a csv file as rows are appended to it, like
+/// `tail -f`: once every row was read it waits for more rather than end.
+///
+/// A file rotated away, moved or deleted and replaced by a new one at the
+/// same path, is read to its end and then the new file from its start,
+/// header first. A file truncated is read again from its start. Rows are
+/// read a line at a time, so a quoted field can't span lines, and a line
+/// is only read once its newline was written. Every
+/// [`FollowOptions::flush_every`] the transactions read so far are handed
+/// to `flush`, to write an updated report.
+pub struct Follow<'a> {
+    path: PathBuf,
+    reader: BufReader<File>,
+    identity: Identity,
+    /// How far into the current file has been read.
+    read: u64,
+    /// What was read of a line whose newline wasn't written yet.
+    partial: String,
+    /// The schema of the current file's header, once it was read.
+    schema: Option<Schema>,
+    /// The rows read, across every file.
+    row: u64,
+    options: FollowOptions,
+    run_options: RunOptions,
+    flush: Box<dyn FnMut() -> Result<()> + 'a>,
+    flushed: Instant,
+    appended: Instant,
+    ended: bool,
+}
+
+impl<'a> Follow<'a> {
+    /// Follows the csv file at `path`, from its start, parsing its rows
+    /// as `run_options` says.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        options: FollowOptions,
+        run_options: &RunOptions,
+        flush: impl FnMut() -> Result<()> + 'a,
+    ) -> Result<Self> {
+        let path = path.into();
+        let (reader, identity) = Self::reopen(&path)?;
+        Ok(Follow {
+            path,
+            reader,
+            identity,
+            read: 0,
+            partial: String::new(),
+            schema: None,
+            row: 0,
+            options,
+            run_options: run_options.clone(),
+            flush: Box::new(flush),
+            flushed: Instant::now(),
+            appended: Instant::now(),
+            ended: false,
+        })
+    }
+
+    fn reopen(path: &PathBuf) -> Result<(BufReader<File>, Identity)> {
+        let file =
+            File::open(path).map_err(|x| format!("error opening {}: {}", path.display(), x))?;
+        let metadata = file
+            .metadata()
+            .map_err(|x| format!("error reading {}: {}", path.display(), x))?;
+        Ok((BufReader::new(file), identity(&metadata)))
+    }
+
+    /// Whether the file at the path is no longer the one being read, or
+    /// was truncated below what was read of it. A path missing for now,
+    /// between a rotation and the new file being created, is neither.
+    fn replaced(&self) -> bool {
+        match std::fs::metadata(&self.path) {
+            Ok(metadata) => identity(&metadata) != self.identity || metadata.len() < self.read,
+            Err(_) => false,
+        }
+    }
+
+    /// Handles a complete line, returning the transaction of a row.
+    fn line(&mut self, line: &str) -> Option<Result<Transaction>> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        let Some(schema) = &self.schema else {
+            match line_record(line).and_then(|headers| schema_for(&headers, &self.run_options)) {
+                Ok(schema) => self.schema = Some(schema),
+                Err(x) => {
+                    self.ended = true;
+                    return Some(Err(x));
+                }
+            }
+            return None;
+        };
+        self.row += 1;
+        let txn = line_record(line)
+            .and_then(|record| schema.parse(&record))
+            .map_err(|error| {
+                PaymentsEngineError::Record(Box::new(RecordError {
+                    input: Some(self.path.display().to_string()),
+                    row: self.row,
+                    error,
+                }))
+            });
+        Some(txn)
+    }
+}
+
+impl Iterator for Follow<'_> {
+    type Item = Result<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.ended {
+            if self.flushed.elapsed() >= self.options.flush_every {
+                self.flushed = Instant::now();
+                if let Err(x) = (self.flush)() {
+                    return Some(Err(x));
+                }
+            }
+            let read = match self.reader.read_line(&mut self.partial) {
+                Ok(read) => read,
+                Err(x) => {
+                    self.ended = true;
+                    let error = format!("error reading {}: {}", self.path.display(), x);
+                    return Some(Err(error.into()));
+                }
+            };
+            self.read += read as u64;
+            if self.partial.ends_with('\n') {
+                self.appended = Instant::now();
+                let line = std::mem::take(&mut self.partial);
+                match self.line(&line) {
+                    Some(txn) => return Some(txn),
+                    None => continue,
+                }
+            }
+            if self.replaced() {
+                // The old file was read to its end, a last line without a
+                // newline is complete now that nothing more will be
+                // written to it.
+                let line = std::mem::take(&mut self.partial);
+                tracing::info!(file = %self.path.display(), "following the new file");
+                match Self::reopen(&self.path) {
+                    Ok((reader, identity)) => {
+                        (self.reader, self.identity) = (reader, identity);
+                        self.read = 0;
+                    }
+                    Err(x) => {
+                        self.ended = true;
+                        return Some(Err(x));
+                    }
+                }
+                let txn = self.line(&line);
+                self.schema = None;
+                match txn {
+                    Some(txn) => return Some(txn),
+                    None => continue,
+                }
+            }
+            if self
+                .options
+                .idle_exit
+                .is_some_and(|idle| self.appended.elapsed() >= idle)
+            {
+                self.ended = true;
+                break;
+            }
+            thread::sleep(self.options.poll_interval);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, io::Write};
+
+    #[test]
+    fn appended_and_rotated_rows_are_followed() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-follow-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("settled.csv");
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n")?;
+        let options = FollowOptions {
+            poll_interval: Duration::from_millis(5),
+            flush_every: Duration::ZERO,
+            idle_exit: Some(Duration::from_millis(100)),
+        };
+        let flushes = Cell::new(0);
+        let mut follow = Follow::open(&path, options, &RunOptions::default(), || {
+            flushes.set(flushes.get() + 1);
+            Ok(())
+        })?;
+        let tx = |txn: Option<Result<Transaction>>| -> Result<u32> {
+            Ok(txn.ok_or("the file ended")??.txn_id)
+        };
+        assert_eq!(tx(follow.next())?, 1);
+
+        // A row written in two goes is read once it is complete.
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+        write!(file, "deposit,1,2,")?;
+        file.flush()?;
+        let writer = thread::spawn(move || -> std::io::Result<()> {
+            thread::sleep(Duration::from_millis(20));
+            writeln!(file, "2.0")?;
+            file.flush()
+        });
+        assert_eq!(tx(follow.next())?, 2);
+        writer.join().map_err(|_| "writer panicked")??;
+
+        // The file is rotated, its last row without a newline, and a new
+        // one with its own header takes its place.
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+        write!(file, "deposit,1,3,3.0")?;
+        std::fs::rename(&path, dir.join("settled.csv.1"))?;
+        std::fs::write(&path, "client,type,tx,amount\n2,deposit,4,4.0\n")?;
+        assert_eq!(tx(follow.next())?, 3);
+        assert_eq!(tx(follow.next())?, 4);
+
+        // Truncated, it is read again from the start.
+        std::fs::write(&path, "type,client,tx,amount\n")?;
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"bogus,1,5,\n")?;
+        let err = follow
+            .next()
+            .ok_or("the file ended")?
+            .expect_err("not a type");
+        assert!(err.to_string().contains("settled.csv, row 5"));
+        assert!(follow.next().is_none());
+        assert!(flushes.get() > 0);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{
+    clock::Timestamp,
+    currency::transaction_currency,
+    schema::{sanitize_category, sanitize_memo, DEFAULT_MEMO_MAX_LEN},
+    ClientId, Result, RunOptions, Transaction, TransactionType,
+};
+use serde::Deserialize;
+use std::{io::Read, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// How transactions are read and the report is written.
+pub enum Format {
+    /// Comma separated values with a header row.
+    Csv,
+    /// JSON Lines: one JSON object per line.
+    Json,
+}
+
+impl Format {
+    /// The format a file's extension stands for: `.json`, `.jsonl` and
+    /// `.ndjson` are JSON Lines, anything else is csv.
+    pub fn detect(path: &str) -> Format {
+        let extension = Path::new(path)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("json" | "jsonl" | "ndjson") => Format::Json,
+            _ => Format::Csv,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+/// A transaction as a JSON object, with the fields named like the csv
+/// columns. Fields the engine doesn't know are ignored.
+struct JsonTransaction {
+    #[serde(rename = "type")]
+    kind: String,
+    client: ClientId,
+    tx: u32,
+    amount: Option<f64>,
+    #[serde(alias = "to")]
+    to_client: Option<ClientId>,
+    timestamp: Option<Timestamp>,
+    memo: Option<String>,
+    #[serde(alias = "merchant")]
+    category: Option<String>,
+    currency: Option<String>,
+    idempotency_key: Option<String>,
+}
+
+/// The transactions of a JSON Lines reader, with the memo length and
+/// precision of `options`. Carried columns only apply to csv.
+pub fn parse_json<'a, R: Read + 'a>(
+    reader: R,
+    options: &RunOptions,
+) -> impl Iterator<Item = Result<Transaction>> + 'a {
+    let memo_max_len = options.memo_max_len.unwrap_or(DEFAULT_MEMO_MAX_LEN);
+    let precision = options.precision.unwrap_or_default();
+    serde_json::Deserializer::from_reader(reader)
+        .into_iter::<JsonTransaction>()
+        .map(move |parsed| {
+            let parsed = parsed.map_err(|x| format!("invalid transaction: {}", x))?;
+            let transaction_type: TransactionType = parsed.kind.as_str().try_into()?;
+            let currency = parsed.currency.as_deref().filter(|x| !x.trim().is_empty());
+            let currency = transaction_currency(transaction_type, currency)?;
+            Ok(Transaction {
+                transaction_type,
+                client_id: parsed.client,
+                txn_id: parsed.tx,
+                amount: parsed.amount.map(|amount| precision.round(amount)),
+                to_client: parsed.to_client,
+                timestamp: parsed.timestamp,
+                memo: parsed
+                    .memo
+                    .and_then(|memo| sanitize_memo(&memo, memo_max_len)),
+                category: parsed.category.as_deref().and_then(sanitize_category),
+                currency,
+                idempotency_key: parsed
+                    .idempotency_key
+                    .filter(|key| !key.trim().is_empty())
+                    .map(Into::into),
+                extras: Box::default(),
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_json_lines() -> Result<()> {
+        assert_eq!(Format::detect("in.NDJSON"), Format::Json);
+        assert_eq!(Format::detect("in.csv"), Format::Csv);
+        let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 2.5, "source": "app"}
+{"type": "dispute", "client": 1, "tx": 1, "memo": "not\nmine"}
+"#;
+        let txns =
+            parse_json(input.as_bytes(), &RunOptions::default()).collect::<Result<Vec<_>>>()?;
+        assert_eq!(txns[0], Transaction::deposit(1, 1, 2.5));
+        assert_eq!(txns[1].memo.as_deref(), Some("not mine"));
+        let bad = parse_json(r#"{"type": "deposit"}"#.as_bytes(), &RunOptions::default());
+        assert!(bad.collect::<Result<Vec<_>>>().is_err());
+        Ok(())
+    }
+}
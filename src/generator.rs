@@ -0,0 +1,145 @@
+use crate::{ClientId, Transaction};
+
+/// A small deterministic pseudo random number generator (splitmix64).
+///
+/// We don't need cryptographic quality, only that the same seed
+/// always produces the same data on every platform and version.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n.max(1)
+    }
+
+    /// Returns true with probability `p`.
+    pub fn chance(&mut self, p: f64) -> bool {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64 <= p
+    }
+}
+
+/// Generates a random but plausible stream of transactions.
+///
+/// Most rows are well formed, disputes reference the client's own
+/// transactions and resolves/chargebacks reference open disputes.
+/// A fraction of rows are deliberately wrong (disputing someone else's
+/// transaction, resolving something that isn't disputed, disputing
+/// twice, ...) since those are where the bugs live.
+#[derive(Debug)]
+pub struct Generator {
+    rng: Rng,
+    clients: ClientId,
+    next_txn_id: u32,
+    /// Deposits and withdrawals made by each client.
+    posted: Vec<Vec<u32>>,
+    /// Transactions each client has disputed.
+    disputed: Vec<Vec<u32>>,
+}
+
+/// The fraction of rows which are intentionally erroneous.
+const NOISE_RATE: f64 = 0.05;
+/// The fraction of settled disputes which end in a chargeback.
+const CHARGEBACK_RATE: f64 = 0.02;
+
+impl Generator {
+    pub fn new(seed: u64, clients: ClientId) -> Self {
+        let clients = clients.max(1);
+        Generator {
+            rng: Rng::new(seed),
+            clients,
+            next_txn_id: 1,
+            posted: vec![Vec::new(); clients as usize],
+            disputed: vec![Vec::new(); clients as usize],
+        }
+    }
+
+    /// A random amount with up to four decimal places.
+    fn amount(&mut self) -> f64 {
+        (1 + self.rng.below(10_000_000)) as f64 / 10_000.0
+    }
+
+    /// Picks a random id from `ids`, or any id handed out so far if `ids` is empty.
+    fn pick(rng: &mut Rng, ids: &[u32], next_txn_id: u32) -> u32 {
+        match ids.len() {
+            0 => 1 + rng.below(next_txn_id as u64) as u32,
+            n => ids[rng.below(n as u64) as usize],
+        }
+    }
+
+    fn posting(
+        &mut self,
+        client: ClientId,
+        posting: fn(ClientId, u32, f64) -> Transaction,
+    ) -> Transaction {
+        let txn_id = self.next_txn_id;
+        self.next_txn_id += 1;
+        self.posted[client as usize - 1].push(txn_id);
+        posting(client, txn_id, self.amount())
+    }
+
+    /// Produces the next transaction in the stream.
+    pub fn next_transaction(&mut self) -> Transaction {
+        let client = 1 + self.rng.below(self.clients) as ClientId;
+        let index = client as usize - 1;
+        let noise = self.rng.chance(NOISE_RATE);
+        let (referencing, txn_id): (fn(ClientId, u32) -> Transaction, u32) =
+            match self.rng.below(100) {
+                0..=44 => return self.posting(client, Transaction::deposit),
+                45..=74 => return self.posting(client, Transaction::withdrawal),
+                75..=86 => {
+                    let owner = if noise {
+                        self.rng.below(self.clients) as usize
+                    } else {
+                        index
+                    };
+                    let txn_id = Self::pick(&mut self.rng, &self.posted[owner], self.next_txn_id);
+                    self.disputed[index].push(txn_id);
+                    (Transaction::dispute, txn_id)
+                }
+                _ => {
+                    let txn_id = if noise {
+                        Self::pick(&mut self.rng, &self.posted[index], self.next_txn_id)
+                    } else {
+                        let txn_id =
+                            Self::pick(&mut self.rng, &self.disputed[index], self.next_txn_id);
+                        self.disputed[index].retain(|id| *id != txn_id);
+                        txn_id
+                    };
+                    let referencing = if self.rng.chance(CHARGEBACK_RATE) {
+                        Transaction::chargeback
+                    } else {
+                        Transaction::resolve
+                    };
+                    (referencing, txn_id)
+                }
+            };
+        referencing(client, txn_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_transactions() {
+        let mut a = Generator::new(7, 10);
+        let mut b = Generator::new(7, 10);
+        for _ in 0..1_000 {
+            assert_eq!(a.next_transaction(), b.next_transaction());
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{account::AccountView, ClientId, Engine, Result, Transaction, TransactionOutcome};
+use std::{
+    sync::mpsc::{channel, Sender},
+    thread::{self, JoinHandle},
+};
+
+/// A request to a shard, carrying the channel its reply is sent on.
+enum Request {
+    Process(Transaction, Sender<Result<TransactionOutcome>>),
+    Account(ClientId, Sender<Result<Option<AccountView>>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How transactions submitted through an [`EngineHandle`] are applied
+/// relative to each other, trading throughput for ordering.
+pub enum Isolation {
+    /// One engine on one thread applies every transaction, in the order
+    /// they were submitted across all callers. The state after any prefix
+    /// of that order is exactly what a single threaded run over it
+    /// produces, and any transaction can refer to any other.
+    Serialized,
+    /// Clients are spread over `shards` engines by `client_id % shards`,
+    /// each on its own thread. Each client's transactions are applied in
+    /// the order they were submitted, but transactions of clients on
+    /// different shards have no order between them. A client disputing a
+    /// transaction that lives on another shard is rejected like any other
+    /// transaction it doesn't own.
+    PerClient { shards: usize },
+}
+
+#[derive(Debug, Clone)]
+/// A cloneable, thread safe handle to a set of engines running on
+/// their own threads, see [`Isolation`] for how they share the work.
+///
+/// Each shard owns its clients (and their transactions) outright. Callers
+/// never share a lock: each request goes down the owning shard's channel
+/// and the answer comes back on a channel of its own.
+pub struct EngineHandle {
+    shards: Vec<Sender<Request>>,
+}
+
+/// Joins the shard threads and hands back their engines.
+#[derive(Debug)]
+pub struct Shards(Vec<JoinHandle<Engine>>);
+
+impl EngineHandle {
+    /// Starts the engine threads `isolation` calls for, each with an
+    /// engine built by `engine`.
+    ///
+    /// The threads run until every handle has been dropped, after which
+    /// [`Shards::join`] returns the engines.
+    pub fn spawn(isolation: Isolation, engine: impl Fn() -> Engine) -> (Self, Shards) {
+        let shards = match isolation {
+            Isolation::Serialized => 1,
+            Isolation::PerClient { shards } => shards.max(1),
+        };
+        let (senders, threads) = (0..shards)
+            .map(|_| {
+                let (sender, receiver) = channel::<Request>();
+                let engine = engine();
+                let thread = thread::spawn(move || {
+                    for request in receiver {
+                        match request {
+                            Request::Process(txn, reply) => {
+                                let _ = reply.send(engine.process(txn));
+                            }
+                            Request::Account(client_id, reply) => {
+                                let _ = reply.send(engine.account(client_id));
+                            }
+                        }
+                    }
+                    engine
+                });
+                (sender, thread)
+            })
+            .unzip();
+        (EngineHandle { shards: senders }, Shards(threads))
+    }
+
+    fn shard(&self, client_id: ClientId) -> &Sender<Request> {
+        &self.shards[client_id as usize % self.shards.len()]
+    }
+
+    /// Processes a transaction on the shard owning its client, waiting
+    /// until it has been handled.
+    pub fn process(&self, txn: Transaction) -> Result<TransactionOutcome> {
+        let (reply, answer) = channel();
+        self.shard(txn.client_id)
+            .send(Request::Process(txn, reply))
+            .map_err(|_| "engine shard has shut down")?;
+        answer.recv().map_err(|_| "engine shard has shut down")?
+    }
+
+    /// The current account of a client, if the client exists.
+    pub fn account(&self, client_id: ClientId) -> Result<Option<AccountView>> {
+        let (reply, answer) = channel();
+        self.shard(client_id)
+            .send(Request::Account(client_id, reply))
+            .map_err(|_| "engine shard has shut down")?;
+        answer.recv().map_err(|_| "engine shard has shut down")?
+    }
+}
+
+impl Shards {
+    /// Waits for every shard to stop and returns their engines, in shard order.
+    pub fn join(self) -> Result<Vec<Engine>> {
+        self.0
+            .into_iter()
+            .map(|thread| thread.join().map_err(|_| "engine shard panicked".into()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, open_file_read_csv, run_engine, schema::Schema};
+
+    #[test]
+    fn sharded_engines_match_a_single_engine() -> Result<()> {
+        let path = "test-files/long_transaction_history.csv";
+        let single = Engine::default();
+        run_engine(open_file_read_csv(path.to_string())?, &single)?;
+
+        let (handle, shards) =
+            EngineHandle::spawn(Isolation::PerClient { shards: 3 }, Engine::default);
+        let mut reader = open_file_read_csv(path.to_string())?;
+        let schema = Schema::from_headers(reader.headers()?, &[])?;
+        let txns = reader
+            .records()
+            .map(|record| schema.parse(&record?))
+            .collect::<Result<Vec<_>>>()?;
+        // One submitting thread per client, all running at once.
+        let mut by_client = std::collections::BTreeMap::<ClientId, Vec<Transaction>>::new();
+        for txn in txns {
+            by_client.entry(txn.client_id).or_default().push(txn);
+        }
+        thread::scope(|scope| {
+            let submitters: Vec<_> = by_client
+                .into_values()
+                .map(|txns| {
+                    let handle = handle.clone();
+                    scope.spawn(move || {
+                        txns.into_iter()
+                            .try_for_each(|txn| handle.process(txn).map(|_| ()))
+                    })
+                })
+                .collect();
+            submitters
+                .into_iter()
+                .try_for_each(|submitter| submitter.join().expect("submitter panicked"))
+        })?;
+
+        for entry in single.db.clients.iter() {
+            let client_id = entry.key();
+            let account = handle.account(*client_id)?.expect("client exists");
+            assert_eq!(single.account(*client_id)?, Some(account));
+        }
+        drop(handle);
+        let engines = shards.join()?;
+        let clients: usize = engines.iter().map(|engine| engine.db.clients.len()).sum();
+        assert_eq!(clients, single.db.clients.len());
+        Ok(())
+    }
+
+    #[test]
+    fn serialized_handle_matches_a_single_engine() -> Result<()> {
+        let path = "test-files/long_transaction_history.csv";
+        let single = Engine::with_clock(SimulatedClock::new(0));
+        run_engine(open_file_read_csv(path.to_string())?, &single)?;
+
+        let (handle, shards) = EngineHandle::spawn(Isolation::Serialized, || {
+            Engine::with_clock(SimulatedClock::new(0))
+        });
+        let mut reader = open_file_read_csv(path.to_string())?;
+        let schema = Schema::from_headers(reader.headers()?, &[])?;
+        for record in reader.records() {
+            handle.process(schema.parse(&record?)?)?;
+        }
+        drop(handle);
+        let engines = shards.join()?;
+        assert_eq!(engines.len(), 1);
+        assert_eq!(engines[0].db, single.db);
+        assert_eq!(engines[0].db.digest(), single.db.digest());
+        Ok(())
+    }
+}
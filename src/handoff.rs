@@ -0,0 +1,398 @@
+use crate::{
+    append::load_previous_runs,
+    state::{import_state, StateFile},
+    Engine, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    os::{
+        fd::{AsRawFd, OwnedFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+/// Descriptors passed per message, below the kernel's limit of 253.
+const FDS_PER_MESSAGE: usize = 250;
+
+/// What the new process answers once it has taken over.
+const TAKEN_OVER: &str = "ok";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// A connection handed over between two rows, so the new process answers
+/// its next row as the old one would have.
+pub struct InFlight {
+    /// The header line of its rows, `None` if it hadn't sent its first line
+    /// yet.
+    pub header: Option<String>,
+    /// The rows answered on it so far, the offset the new process carries
+    /// on from.
+    pub rows: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// What an engine hands over besides its sockets.
+struct Handover {
+    state: StateFile,
+    /// The connections handed over, in the order of their descriptors,
+    /// which follow the listener's.
+    connections: Vec<InFlight>,
+}
+
+/// What a new process took over from the old one.
+#[derive(Debug)]
+pub struct TakenOver {
+    /// The socket the old process listened on, with the connections it
+    /// hadn't accepted yet still waiting.
+    pub listener: TcpListener,
+    pub connections: Vec<(TcpStream, InFlight)>,
+}
+
+/// Listens at `path` for a new process to hand over to, replacing a socket
+/// left there, such as the one of the process this one took over from.
+pub fn bind_handoff(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        std::fs::remove_file(path)
+            .map_err(|x| format!("error replacing {}: {}", path.display(), x))?;
+    }
+    UnixListener::bind(path)
+        .map_err(|x| format!("error listening on {}: {}", path.display(), x).into())
+}
+
+/// Hands the state of `engine`, its `listener` and the `connections` it
+/// stopped between two rows over to the new process on `successor`. The
+/// new process has taken over once this returns, until then this one may
+/// carry on serving.
+pub fn hand_over(
+    engine: &Engine,
+    successor: &UnixStream,
+    listener: &TcpListener,
+    connections: &[(TcpStream, InFlight)],
+) -> Result<()> {
+    let error = |x: io::Error| format!("error handing over: {}", x);
+    successor.set_nonblocking(false).map_err(error)?;
+    let handover = Handover {
+        state: crate::state::export_state(&engine.db)?,
+        connections: connections.iter().map(|(_, at)| at.clone()).collect(),
+    };
+    let fds: Vec<_> = std::iter::once(listener.as_raw_fd())
+        .chain(connections.iter().map(|(stream, _)| stream.as_raw_fd()))
+        .collect();
+    // The descriptors go first, a buffered read of the handover would
+    // drop any it read past.
+    let mut remaining = fds.len();
+    for batch in fds.chunks(FDS_PER_MESSAGE) {
+        remaining -= batch.len();
+        send_fds(successor, &(remaining as u32).to_le_bytes(), batch).map_err(error)?;
+    }
+    let mut out = successor;
+    serde_json::to_writer(&mut out, &handover).map_err(|x| x.to_string())?;
+    writeln!(out).map_err(error)?;
+    let mut answer = String::new();
+    BufReader::new(successor)
+        .read_line(&mut answer)
+        .map_err(error)?;
+    match answer.trim_end() {
+        TAKEN_OVER => Ok(()),
+        _ => Err("the new process didn't take over".into()),
+    }
+}
+
+/// Takes over from the engine serving with a handoff socket at `socket`:
+/// its state goes into `engine`, which must be empty, and its listening
+/// socket and open connections are returned to serve on. With `state_dir`,
+/// the engine carries on from the earlier runs of that `--append-state`
+/// directory as the old process did.
+///
+/// Only the state is handed over. Metrics, and the submissions remembered
+/// by a dedup window, start afresh.
+pub fn take_over(
+    engine: &mut Engine,
+    socket: &Path,
+    state_dir: Option<&Path>,
+) -> Result<TakenOver> {
+    let error = |x: io::Error| format!("error taking over from {}: {}", socket.display(), x);
+    let old = UnixStream::connect(socket).map_err(error)?;
+    let mut fds = Vec::new();
+    loop {
+        let mut remaining = [0; 4];
+        let (read, received) = recv_fds(&old, &mut remaining, FDS_PER_MESSAGE).map_err(error)?;
+        if read != remaining.len() {
+            return Err(format!("{} closed before handing over", socket.display()).into());
+        }
+        fds.extend(received);
+        if u32::from_le_bytes(remaining) == 0 {
+            break;
+        }
+    }
+    let mut line = String::new();
+    BufReader::new(&old).read_line(&mut line).map_err(error)?;
+    let handover: Handover = serde_json::from_str(&line)
+        .map_err(|x| format!("invalid handover from {}: {}", socket.display(), x))?;
+    let mut fds = fds.into_iter();
+    let listener = TcpListener::from(fds.next().ok_or("no listening socket was handed over")?);
+    if fds.len() != handover.connections.len() {
+        return Err(format!(
+            "{} connections were handed over with {} sockets",
+            handover.connections.len(),
+            fds.len()
+        )
+        .into());
+    }
+    let connections = fds.map(TcpStream::from).zip(handover.connections).collect();
+
+    if let Some(dir) = state_dir {
+        let mut saved = Engine::default();
+        load_previous_runs(&mut saved, dir)?;
+        engine.db.previous = saved.db.previous.take();
+    }
+    import_state(engine, handover.state)?;
+    let mut old = &old;
+    writeln!(old, "{}", TAKEN_OVER).map_err(error)?;
+    Ok(TakenOver {
+        listener,
+        connections,
+    })
+}
+
+#[cfg(target_os = "linux")]
+/// Passing descriptors with `SCM_RIGHTS`, which the standard library has
+/// no stable interface for yet.
+mod scm {
+    use std::{
+        ffi::{c_int, c_void},
+        io,
+        mem::size_of,
+        os::fd::{FromRawFd, OwnedFd, RawFd},
+        ptr,
+    };
+
+    const SOL_SOCKET: c_int = 1;
+    const SCM_RIGHTS: c_int = 1;
+    const MSG_CTRUNC: c_int = 0x8;
+    const MSG_NOSIGNAL: c_int = 0x4000;
+    const MSG_CMSG_CLOEXEC: c_int = 0x4000_0000;
+
+    #[repr(C)]
+    struct IoVec {
+        base: *mut c_void,
+        len: usize,
+    }
+
+    #[repr(C)]
+    struct MsgHdr {
+        name: *mut c_void,
+        name_len: u32,
+        iov: *mut IoVec,
+        iov_len: usize,
+        control: *mut c_void,
+        control_len: usize,
+        flags: c_int,
+    }
+
+    #[repr(C)]
+    struct CmsgHdr {
+        len: usize,
+        level: c_int,
+        kind: c_int,
+    }
+
+    extern "C" {
+        fn sendmsg(fd: c_int, msg: *const MsgHdr, flags: c_int) -> isize;
+        fn recvmsg(fd: c_int, msg: *mut MsgHdr, flags: c_int) -> isize;
+    }
+
+    /// `CMSG_LEN` of `fds` descriptors.
+    fn control_len(fds: usize) -> usize {
+        size_of::<CmsgHdr>() + fds * size_of::<c_int>()
+    }
+
+    /// `CMSG_SPACE` of `fds` descriptors, in words so it is aligned.
+    fn control_buffer(fds: usize) -> Vec<usize> {
+        vec![0; control_len(fds).div_ceil(size_of::<usize>())]
+    }
+
+    pub fn send(socket: RawFd, bytes: &[u8], fds: &[RawFd]) -> io::Result<()> {
+        let mut control = control_buffer(fds.len());
+        let header = control.as_mut_ptr().cast::<CmsgHdr>();
+        // SAFETY: the buffer is aligned for a header and sized for it and
+        // the descriptors after it.
+        unsafe {
+            header.write(CmsgHdr {
+                len: control_len(fds.len()),
+                level: SOL_SOCKET,
+                kind: SCM_RIGHTS,
+            });
+            let data = header.add(1).cast::<c_int>();
+            for (i, fd) in fds.iter().enumerate() {
+                data.add(i).write_unaligned(*fd);
+            }
+        }
+        let mut iov = IoVec {
+            base: bytes.as_ptr().cast_mut().cast(),
+            len: bytes.len(),
+        };
+        let msg = MsgHdr {
+            name: ptr::null_mut(),
+            name_len: 0,
+            iov: &mut iov,
+            iov_len: 1,
+            control: control.as_mut_ptr().cast(),
+            control_len: control.len() * size_of::<usize>(),
+            flags: 0,
+        };
+        // SAFETY: the message points at buffers that outlive the call, and
+        // sendmsg only reads them.
+        let sent = unsafe { sendmsg(socket, &msg, MSG_NOSIGNAL) };
+        match sent {
+            sent if sent < 0 => Err(io::Error::last_os_error()),
+            sent if sent as usize != bytes.len() => Err(io::ErrorKind::WriteZero.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn recv(
+        socket: RawFd,
+        bytes: &mut [u8],
+        max_fds: usize,
+    ) -> io::Result<(usize, Vec<OwnedFd>)> {
+        let mut control = control_buffer(max_fds);
+        let mut iov = IoVec {
+            base: bytes.as_mut_ptr().cast(),
+            len: bytes.len(),
+        };
+        let mut msg = MsgHdr {
+            name: ptr::null_mut(),
+            name_len: 0,
+            iov: &mut iov,
+            iov_len: 1,
+            control: control.as_mut_ptr().cast(),
+            control_len: control.len() * size_of::<usize>(),
+            flags: 0,
+        };
+        // SAFETY: the message points at buffers that outlive the call and
+        // are as long as it says.
+        let read = unsafe { recvmsg(socket, &mut msg, MSG_CMSG_CLOEXEC) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut fds = Vec::new();
+        if msg.control_len >= size_of::<CmsgHdr>() {
+            // SAFETY: the kernel wrote a header of the length it says at
+            // the start of the aligned buffer.
+            unsafe {
+                let header = control.as_ptr().cast::<CmsgHdr>().read();
+                if (header.level, header.kind) == (SOL_SOCKET, SCM_RIGHTS) {
+                    let count = (header.len - size_of::<CmsgHdr>()) / size_of::<c_int>();
+                    let data = control.as_ptr().cast::<CmsgHdr>().add(1).cast::<c_int>();
+                    for i in 0..count {
+                        fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                    }
+                }
+            }
+        }
+        if msg.flags & MSG_CTRUNC != 0 {
+            return Err(io::Error::other("too many descriptors were passed"));
+        }
+        Ok((read as usize, fds))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_fds(socket: &UnixStream, bytes: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    scm::send(socket.as_raw_fd(), bytes, fds)
+}
+
+#[cfg(target_os = "linux")]
+fn recv_fds(
+    socket: &UnixStream,
+    bytes: &mut [u8],
+    max_fds: usize,
+) -> io::Result<(usize, Vec<OwnedFd>)> {
+    scm::recv(socket.as_raw_fd(), bytes, max_fds)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_fds(_socket: &UnixStream, _bytes: &[u8], _fds: &[RawFd]) -> io::Result<()> {
+    Err(io::Error::other("handing over is only supported on Linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv_fds(
+    _socket: &UnixStream,
+    _bytes: &mut [u8],
+    _max_fds: usize,
+) -> io::Result<(usize, Vec<OwnedFd>)> {
+    Err(io::Error::other("taking over is only supported on Linux"))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::serve::{serve, Handoff};
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn a_new_process_carries_on_an_open_connection() -> Result<()> {
+        let path = crate::temp_path("payments-engine-handoff-test.sock");
+        let old = Engine::default();
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|x| x.to_string())?;
+        let addr = listener.local_addr().map_err(|x| x.to_string())?;
+        let handoff = Handoff {
+            socket: Some(bind_handoff(&path)?),
+            adopted: Vec::new(),
+        };
+        let mut new = Engine::default();
+        thread::scope(|scope| -> Result<()> {
+            let server = scope.spawn(|| serve(&old, listener, None, handoff));
+            let mut stream = TcpStream::connect(addr).map_err(|x| x.to_string())?;
+            let mut answers = BufReader::new(stream.try_clone().map_err(|x| x.to_string())?);
+            let mut ask = |stream: &mut TcpStream, row: &str| -> Result<String> {
+                writeln!(stream, "{}", row).map_err(|x| x.to_string())?;
+                let mut answer = String::new();
+                answers.read_line(&mut answer).map_err(|x| x.to_string())?;
+                Ok(answer.trim_end().to_string())
+            };
+            writeln!(stream, "type,client,tx,amount").map_err(|x| x.to_string())?;
+            assert_eq!(ask(&mut stream, "deposit,1,1,2.0")?, "applied");
+
+            let taken = take_over(&mut new, &path, None)?;
+            let served = server.join().expect("server panicked")?;
+            assert!(served.handed_off);
+            assert_eq!(taken.connections.len(), 1);
+            assert_eq!(taken.connections[0].1.rows, 1);
+
+            let handoff = Handoff {
+                socket: None,
+                adopted: taken.connections,
+            };
+            let server = scope.spawn(|| {
+                serve(
+                    &new,
+                    taken.listener,
+                    Some(Duration::from_millis(200)),
+                    handoff,
+                )
+            });
+            // The same connection is answered by the new process, which
+            // already has the deposit.
+            assert_eq!(ask(&mut stream, "withdrawal,1,2,0.5")?, "applied");
+            assert_eq!(
+                ask(&mut stream, "deposit,1,1,1.0")?,
+                "rejected,duplicate_txn_id"
+            );
+            drop((stream, answers));
+            let mut other = TcpStream::connect(addr).map_err(|x| x.to_string())?;
+            writeln!(other, "type,client,tx,amount\ndeposit,2,3,1.0").map_err(|x| x.to_string())?;
+            drop(other);
+            server.join().expect("server panicked")?;
+            Ok(())
+        })?;
+        assert_eq!(new.account(1)?.map(|a| a.available()), Some(1.5));
+        assert_eq!(new.account(2)?.map(|a| a.available()), Some(1.0));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
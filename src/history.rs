@@ -0,0 +1,143 @@
+use crate::{
+    account::AccountView, clock::Timestamp, spend::Period, ClientId, Result, Transaction,
+    TransactionType,
+};
+use dashmap::DashMap;
+use std::io::Write;
+
+#[derive(Debug, Clone, PartialEq)]
+/// A transaction applied to a client's account, with the account's
+/// balances after it.
+pub struct HistoryEntry {
+    pub txn_id: u32,
+    pub transaction_type: TransactionType,
+    pub timestamp: Timestamp,
+    /// The amount of the transaction, or for a dispute, resolve or
+    /// chargeback, of the transaction it refers to.
+    pub amount: Option<f64>,
+    pub available: f64,
+    pub held: f64,
+    pub locked: bool,
+}
+
+impl HistoryEntry {
+    /// The entry of `txn` having left the account as `account`, `amount`
+    /// being the one it moved.
+    pub fn new(txn: &Transaction, amount: Option<f64>, account: &AccountView) -> Self {
+        HistoryEntry {
+            txn_id: txn.txn_id,
+            transaction_type: txn.transaction_type,
+            timestamp: txn.timestamp.unwrap_or_default(),
+            amount,
+            available: account.available(),
+            held: account.held(),
+            locked: account.locked(),
+        }
+    }
+
+    /// The running balance, available and held.
+    pub fn total(&self) -> f64 {
+        self.available + self.held
+    }
+}
+
+#[derive(Debug, Default)]
+/// The transactions applied to each client, in the order they were
+/// applied, see [`crate::Engine::with_history`]. The engine otherwise
+/// only keeps transactions by id, which doesn't tell what a client went
+/// through or in what order.
+pub struct History {
+    clients: DashMap<ClientId, Vec<HistoryEntry>>,
+}
+
+impl History {
+    pub fn record(&self, client_id: ClientId, entry: HistoryEntry) {
+        self.clients.entry(client_id).or_default().push(entry);
+    }
+
+    /// The transactions applied to `client_id` within `period`, oldest
+    /// first.
+    pub fn of(&self, client_id: ClientId, period: Period) -> Vec<HistoryEntry> {
+        self.clients
+            .get(&client_id)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter(|entry| period.contains(entry.timestamp))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Writes `entries` as csv, with the columns
+/// `timestamp,tx,type,amount,available,held,total,locked`.
+pub fn write_history(entries: &[HistoryEntry], out: impl Write) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "timestamp",
+        "tx",
+        "type",
+        "amount",
+        "available",
+        "held",
+        "total",
+        "locked",
+    ])?;
+    for entry in entries {
+        writer.write_record([
+            entry.timestamp.to_string(),
+            entry.txn_id.to_string(),
+            entry.transaction_type.as_str().to_string(),
+            entry
+                .amount
+                .map(|amount| format!("{:.4}", amount))
+                .unwrap_or_default(),
+            format!("{:.4}", entry.available),
+            format!("{:.4}", entry.held),
+            format!("{:.4}", entry.total()),
+            entry.locked.to_string(),
+        ])?;
+    }
+    writer
+        .flush()
+        .map_err(|x| format!("error writing the history: {}", x).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, Engine};
+
+    #[test]
+    fn each_clients_transactions_are_kept_in_order() -> Result<()> {
+        let engine = Engine::with_clock(SimulatedClock::new(100)).with_history();
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        engine.process(Transaction::deposit(2, 2, 4.0))?;
+        engine.process(Transaction::withdrawal(1, 3, 50.0))?;
+        engine.process(Transaction::dispute(1, 1))?;
+        engine.process(Transaction::resolve(1, 1))?;
+        let history = engine.history(1, Period::default());
+        let entries: Vec<_> = history
+            .iter()
+            .map(|entry| (entry.txn_id, entry.amount, entry.available, entry.held))
+            .collect();
+        // The rejected withdrawal isn't part of it.
+        assert_eq!(
+            entries,
+            [
+                (1, Some(10.0), 10.0, 0.0),
+                (1, Some(10.0), 0.0, 10.0),
+                (1, Some(10.0), 10.0, 0.0)
+            ]
+        );
+        let mut out = Vec::new();
+        write_history(&engine.history(2, Period::default()), &mut out)?;
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "timestamp,tx,type,amount,available,held,total,locked\n100,2,deposit,4.0000,4.0000,0.0000,4.0000,false\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use crate::{account::AccountView, i18n::Reason, Transaction};
+use std::fmt;
+
+/// Called with a transaction the engine rejected and why.
+pub type OnReject = Box<dyn Fn(&Transaction, Reason) + Send + Sync>;
+/// Called with an account that was just locked and the transaction that
+/// locked it.
+pub type OnLock = Box<dyn Fn(&AccountView, &Transaction) + Send + Sync>;
+
+#[derive(Default)]
+/// Callbacks the engine invokes as it handles transactions, for embedders
+/// to wire in their own alerting, see
+/// [`crate::builder::EngineBuilder::on_reject`] and
+/// [`crate::builder::EngineBuilder::on_lock`].
+///
+/// They run on the thread that processed the transaction, once it is
+/// handled, so they see the state it left. A callback must not process
+/// transactions on the same engine itself.
+pub struct Hooks {
+    pub on_reject: Vec<OnReject>,
+    pub on_lock: Vec<OnLock>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_reject", &self.on_reject.len())
+            .field("on_lock", &self.on_lock.len())
+            .finish()
+    }
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.on_reject.is_empty() && self.on_lock.is_empty()
+    }
+
+    pub fn rejected(&self, txn: &Transaction, reason: Reason) {
+        for hook in &self.on_reject {
+            hook(txn, reason);
+        }
+    }
+
+    pub fn locked(&self, account: &AccountView, txn: &Transaction) {
+        for hook in &self.on_lock {
+            hook(account, txn);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use crate::{
+    format::Format,
+    output::{write_report, ReportWriter},
+    serve::{answer_rows, POLL_INTERVAL},
+    ClientId, Engine, Result,
+};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+/// The content type of the Prometheus text format.
+const PROMETHEUS: &str = "text/plain; version=0.0.4";
+
+/// Whether `line`, the first of a connection, is the request line of an
+/// HTTP/1 request, such as `GET /report HTTP/1.1`.
+pub fn is_request_line(line: &str) -> bool {
+    let parts: Vec<_> = line.split_whitespace().collect();
+    matches!(parts[..], [_, path, version] if path.starts_with('/') && version.starts_with("HTTP/1."))
+}
+
+/// A response, before it is written.
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    fn text(status: u16, text: &str) -> Self {
+        Response::new(status, "text/plain", format!("{}\n", text).into_bytes())
+    }
+}
+
+/// The reason phrase of the status codes answered with.
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Answers the HTTP request whose request line was `first`, reading its
+/// headers and body from `input`. One request is served per connection:
+///
+/// - `POST /transactions` applies the csv rows of the body, its header
+///   first, and answers one line per row as a TCP connection does.
+/// - `GET /clients/<id>` answers the report row of one client.
+/// - `GET /report` answers the report of every client.
+/// - `GET /metrics` answers the engine's metrics for Prometheus.
+///
+/// Reports are csv, or JSON Lines with `?format=json`.
+pub fn handle_request(
+    engine: &Engine,
+    first: &str,
+    mut input: impl BufRead,
+    output: impl Write,
+) -> Result<()> {
+    let parts: Vec<_> = first.split_whitespace().collect();
+    let [method, target, _] = parts[..] else {
+        return Err(format!("invalid request line {:?}", first.trim_end()).into());
+    };
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        input
+            .read_line(&mut header)
+            .map_err(|x| format!("error reading the request: {}", x))?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<u64>().ok();
+            }
+        }
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let format = match query.split('&').any(|param| param == "format=json") {
+        true => Format::Json,
+        false => Format::Csv,
+    };
+    let content_type = match format {
+        Format::Csv => "text/csv",
+        Format::Json => "application/x-ndjson",
+    };
+    let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+    let response = match (method, &segments[..]) {
+        ("POST", ["transactions"]) => match content_length {
+            Some(length) => {
+                let mut answers = Vec::new();
+                match answer_rows(engine, input.take(length), &mut answers) {
+                    Ok(_) => Response::new(200, "text/plain", answers),
+                    Err(x) => Response::text(400, &x.to_string()),
+                }
+            }
+            None => Response::text(411, "a Content-Length is needed"),
+        },
+        ("GET", ["clients", client_id]) => match client_id.parse::<ClientId>() {
+            Ok(client_id) => match engine.account(client_id)? {
+                Some(account) => {
+                    let mut row = Vec::new();
+                    let mut report = ReportWriter::new(&engine.db, &mut row, format)?;
+                    report.write(&account)?;
+                    report.finish()?;
+                    Response::new(200, content_type, row)
+                }
+                None => Response::text(404, &format!("no client {}", client_id)),
+            },
+            Err(_) => Response::text(400, &format!("invalid client {}", client_id)),
+        },
+        ("GET", ["report"]) => {
+            let mut report = Vec::new();
+            match write_report(&engine.db, &mut report, format) {
+                Ok(()) => Response::new(200, content_type, report),
+                Err(x) => Response::text(500, &x.to_string()),
+            }
+        }
+        ("GET", ["metrics"]) => {
+            Response::new(200, PROMETHEUS, engine.metrics()?.prometheus().into())
+        }
+        (_, ["transactions"] | ["clients", _] | ["report"] | ["metrics"]) => {
+            Response::text(405, &format!("{} is not allowed on {}", method, path))
+        }
+        _ => Response::text(404, &format!("nothing at {}", path)),
+    };
+    write_response(output, &response)
+}
+
+fn write_response(mut output: impl Write, response: &Response) -> Result<()> {
+    write!(
+        output,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    )
+    .and_then(|_| output.write_all(&response.body))
+    .and_then(|_| output.flush())
+    .map_err(|x| format!("error answering: {}", x).into())
+}
+
+/// Answers scrapes of `GET /metrics` on `listener`, one at a time, until
+/// `done` is set. Nothing else is served there, so the port can be open
+/// to a monitoring network the transactions port isn't.
+pub fn serve_metrics(engine: &Engine, listener: TcpListener, done: &AtomicBool) -> Result<()> {
+    listener
+        .set_nonblocking(true)
+        .map_err(|x| format!("error listening for scrapes: {}", x))?;
+    while !done.load(Ordering::SeqCst) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(x) if x.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(x) => return Err(format!("error accepting a scrape: {}", x).into()),
+        };
+        let scrape = || -> Result<()> {
+            let error = |x: std::io::Error| format!("error reading a scrape: {}", x);
+            stream.set_nonblocking(false).map_err(error)?;
+            stream
+                .set_read_timeout(Some(POLL_INTERVAL * 20))
+                .map_err(error)?;
+            let mut input = BufReader::new(&stream);
+            let mut first = String::new();
+            input.read_line(&mut first).map_err(error)?;
+            match first.split_whitespace().nth(1) {
+                Some("/metrics") => handle_request(engine, &first, input, &stream),
+                _ => write_response(
+                    &stream,
+                    &Response::text(404, "only /metrics is served here"),
+                ),
+            }
+        };
+        if let Err(x) = scrape() {
+            tracing::warn!("{}", x);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serve::handle_connection;
+
+    /// The status line and the body of the answer to `request`.
+    fn ask(engine: &Engine, request: &str) -> Result<(String, String)> {
+        let mut answer = Vec::new();
+        handle_connection(engine, request.as_bytes(), &mut answer)?;
+        let answer = String::from_utf8(answer).map_err(|x| x.to_string())?;
+        let (head, body) = answer.split_once("\r\n\r\n").ok_or("no end of headers")?;
+        let status = head.lines().next().unwrap_or_default().to_string();
+        Ok((status, body.to_string()))
+    }
+
+    #[test]
+    fn transactions_go_in_and_balances_come_out() -> Result<()> {
+        let engine = Engine::default();
+        let rows = "type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,1,2,5.0\n";
+        let request = format!(
+            "POST /transactions HTTP/1.1\r\nHost: localhost\r\ncontent-length: {}\r\n\r\n{}",
+            rows.len(),
+            rows
+        );
+        let (status, body) = ask(&engine, &request)?;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, "applied\nrejected,insufficient_funds\n");
+
+        let (status, body) = ask(&engine, "GET /clients/1?format=json HTTP/1.1\r\n\r\n")?;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.contains("\"available\":2.0"));
+        let (_, body) = ask(&engine, "GET /report HTTP/1.1\r\n\r\n")?;
+        assert_eq!(body.lines().count(), 2);
+        assert!(body.lines().nth(1).is_some_and(|row| row.starts_with("1,")));
+
+        let statuses = [
+            ("GET /clients/2 HTTP/1.1\r\n\r\n", "HTTP/1.1 404 Not Found"),
+            (
+                "GET /clients/x HTTP/1.1\r\n\r\n",
+                "HTTP/1.1 400 Bad Request",
+            ),
+            (
+                "DELETE /report HTTP/1.1\r\n\r\n",
+                "HTTP/1.1 405 Method Not Allowed",
+            ),
+            (
+                "POST /transactions HTTP/1.1\r\n\r\n",
+                "HTTP/1.1 411 Length Required",
+            ),
+            (
+                "POST /transactions HTTP/1.1\r\nContent-Length: 5\r\n\r\n{bad}",
+                "HTTP/1.1 400 Bad Request",
+            ),
+        ];
+        for (request, expected) in statuses {
+            assert_eq!(ask(&engine, request)?.0, expected);
+        }
+
+        let (status, body) = ask(&engine, "GET /metrics HTTP/1.1\r\n\r\n")?;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.contains("\npayments_engine_transactions_total{type=\"deposit\"} 1\n"));
+        assert!(
+            body.contains("payments_engine_rejections_total{reason=\"insufficient_funds\"} 1\n")
+        );
+        assert!(body.contains("payments_engine_disputes_total{outcome=\"opened\"} 0\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,422 @@
+use std::sync::OnceLock;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// The languages user facing messages are available in.
+pub enum Lang {
+    #[default]
+    En,
+    Es,
+    De,
+}
+
+static LANG: OnceLock<Lang> = OnceLock::new();
+
+/// Sets the language of user facing messages for the rest of the process.
+/// Only the first call has any effect.
+pub fn set_lang(lang: Lang) {
+    let _ = LANG.set(lang);
+}
+
+/// The language user facing messages are written in.
+pub fn lang() -> Lang {
+    LANG.get().copied().unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Why a transaction was not applied.
+///
+/// The [`Reason::code`] is the same in every language, so logs can be
+/// matched on by machines no matter which language the text is in.
+pub enum Reason {
+    AccountLocked,
+    InsufficientFunds,
+    NotClientTransaction,
+    NotDisputed,
+    Unprocessable,
+    /// The transaction id was already used by an earlier run in append mode.
+    ConflictingTxnId,
+    /// The policy doesn't allow disputing this kind of transaction.
+    NotDisputable,
+    /// The amount is above the limit of the client's region.
+    OverLimit,
+    /// The disputed transaction is older than the client's region allows.
+    DisputeWindowClosed,
+    /// A plugin denied the transaction.
+    Denied,
+    /// The client's tenant is over one of its quotas.
+    QuotaExceeded,
+    /// The client a transfer credits is locked.
+    RecipientLocked,
+    /// The transaction id is kept for transactions the engine generates.
+    ReservedTxnId,
+    /// The transaction id was already used by another transaction.
+    DuplicateTxnId,
+    /// An admin operation, without [`crate::Policy::allow_admin_ops`].
+    AdminOpsDisabled,
+    /// The account was closed.
+    AccountClosed,
+    /// The hold of a disputed deposit would take the available funds
+    /// below what [`crate::Policy::overdraft`] allows.
+    OverdraftLimit,
+    /// The transaction was disputed before, whether or not the dispute is
+    /// still open.
+    AlreadyDisputed,
+    /// A dispute's amount is more than that of the transaction it refers
+    /// to, or isn't positive.
+    InvalidDisputeAmount,
+}
+
+impl Reason {
+    /// Every reason.
+    pub const ALL: [Reason; 19] = [
+        Reason::AccountLocked,
+        Reason::InsufficientFunds,
+        Reason::NotClientTransaction,
+        Reason::NotDisputed,
+        Reason::Unprocessable,
+        Reason::ConflictingTxnId,
+        Reason::NotDisputable,
+        Reason::OverLimit,
+        Reason::DisputeWindowClosed,
+        Reason::Denied,
+        Reason::QuotaExceeded,
+        Reason::RecipientLocked,
+        Reason::ReservedTxnId,
+        Reason::DuplicateTxnId,
+        Reason::AdminOpsDisabled,
+        Reason::AccountClosed,
+        Reason::OverdraftLimit,
+        Reason::AlreadyDisputed,
+        Reason::InvalidDisputeAmount,
+    ];
+
+    /// The position of the reason in [`Reason::ALL`].
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// The language neutral code of the reason.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Reason::AccountLocked => "account_locked",
+            Reason::InsufficientFunds => "insufficient_funds",
+            Reason::NotClientTransaction => "not_client_transaction",
+            Reason::NotDisputed => "not_disputed",
+            Reason::Unprocessable => "unprocessable",
+            Reason::ConflictingTxnId => "conflicting_txn_id",
+            Reason::NotDisputable => "not_disputable",
+            Reason::OverLimit => "over_limit",
+            Reason::DisputeWindowClosed => "dispute_window_closed",
+            Reason::Denied => "denied",
+            Reason::QuotaExceeded => "quota_exceeded",
+            Reason::RecipientLocked => "recipient_locked",
+            Reason::ReservedTxnId => "reserved_txn_id",
+            Reason::DuplicateTxnId => "duplicate_txn_id",
+            Reason::AdminOpsDisabled => "admin_ops_disabled",
+            Reason::AccountClosed => "account_closed",
+            Reason::OverdraftLimit => "overdraft_limit",
+            Reason::AlreadyDisputed => "already_disputed",
+            Reason::InvalidDisputeAmount => "invalid_dispute_amount",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Every user facing message in the catalog.
+pub enum Key {
+    Rejected(Reason),
+    /// How many transactions of a run were rejected.
+    RejectedCount,
+    /// How many rows of a run were skipped as they couldn't be read.
+    SkippedCount,
+    /// How many rows of a run repeated transactions already handled.
+    DuplicateCount,
+    Sampled,
+    StoppedEarly,
+    /// How many transactions conflicted with earlier runs in append mode.
+    Conflicts,
+    /// A dispute has been open for longer than the SLA allows.
+    SlaBreached,
+    /// A client's country has no rule set, the default one is used.
+    UnknownRegion,
+    /// A page of accounts was listed and more follow.
+    NextPage,
+}
+
+/// The template for `key` in `lang`. Placeholders in braces are filled in
+/// by [`message`].
+fn template(lang: Lang, key: Key) -> &'static str {
+    use Key::*;
+    use Lang::*;
+    use Reason::*;
+    match (lang, key) {
+        (En, Rejected(AccountLocked)) => "Client {client} is locked, aborting transaction {txn}",
+        (En, Rejected(InsufficientFunds)) => {
+            "Client {client} has insufficient funds for transaction {txn}"
+        }
+        (En, Rejected(NotClientTransaction)) => {
+            "Client {client} attempted to {type} transaction {txn}, which is not its transaction"
+        }
+        (En, Rejected(NotDisputed)) => {
+            "Client {client} attempted to {type} transaction {txn}, which is not disputed"
+        }
+        (En, Rejected(Unprocessable)) => {
+            "Transaction {txn} ({type}) of client {client} could not be processed"
+        }
+        (En, RejectedCount) => "{rejected} of {processed} transactions were rejected.",
+        (En, SkippedCount) => "{skipped} of {rows} rows were skipped as invalid.",
+        (En, Rejected(ConflictingTxnId)) => {
+            "Transaction {txn} of client {client} reuses the id of a transaction from an earlier run"
+        }
+        (En, Rejected(NotDisputable)) => {
+            "Client {client} attempted to {type} transaction {txn}, which the policy doesn't allow disputing"
+        }
+        (En, Rejected(OverLimit)) => {
+            "Transaction {txn} of client {client} is over the limit of its region"
+        }
+        (En, Rejected(DisputeWindowClosed)) => {
+            "Client {client} attempted to {type} transaction {txn}, which is too old to dispute in its region"
+        }
+        (En, Rejected(Denied)) => "Transaction {txn} of client {client} was denied: {why}",
+        (En, Rejected(QuotaExceeded)) => {
+            "Transaction {txn} of client {client} exceeds the {quota} quota of tenant {tenant}"
+        }
+        (En, Rejected(RecipientLocked)) => {
+            "Client {to} is locked, aborting transfer {txn} from client {client}"
+        }
+        (En, Rejected(ReservedTxnId)) => {
+            "Transaction {txn} of client {client} uses an id reserved for generated transactions"
+        }
+        (En, Rejected(DuplicateTxnId)) => {
+            "Transaction {txn} of client {client} reuses the id of an earlier transaction"
+        }
+        (En, DuplicateCount) => "{duplicates} of {processed} transactions repeated earlier ones and were ignored.",
+        (En, Rejected(AdminOpsDisabled)) => {
+            "Admin operation {txn} on client {client} ignored, admin operations are not allowed"
+        }
+        (En, Rejected(AccountClosed)) => {
+            "Client {client} is closed, aborting transaction {txn}"
+        }
+        (En, Rejected(OverdraftLimit)) => {
+            "Client {client} attempted to {type} transaction {txn}, which would take its available funds below the overdraft limit"
+        }
+        (En, Rejected(AlreadyDisputed)) => {
+            "Client {client} attempted to {type} transaction {txn}, which was already disputed"
+        }
+        (En, Rejected(InvalidDisputeAmount)) => {
+            "Client {client} attempted to {type} more of transaction {txn} than its amount"
+        }
+        (En, UnknownRegion) => {
+            "Warning: region {region} has no rule set, its clients get the default rules"
+        }
+        (En, Conflicts) => {
+            "{count} transactions conflicted with earlier runs and were not applied, see {path}."
+        }
+        (En, SlaBreached) => {
+            "Dispute of transaction {txn} of client {client} has been open for {days} days, past the SLA"
+        }
+        (En, NextPage) => "More accounts follow, continue with --after {after}.",
+        (En, Sampled) => "Sampled {processed} of {rows} rows read.",
+        (En, StoppedEarly) => {
+            "Stopped early, {reason}. Processed {rows} rows, resume from row {resume}."
+        }
+
+        (Es, Rejected(AccountLocked)) => {
+            "El cliente {client} está bloqueado, se cancela la transacción {txn}"
+        }
+        (Es, Rejected(InsufficientFunds)) => {
+            "El cliente {client} no tiene fondos suficientes para la transacción {txn}"
+        }
+        (Es, Rejected(NotClientTransaction)) => {
+            "El cliente {client} intentó {type} la transacción {txn}, que no es suya"
+        }
+        (Es, Rejected(NotDisputed)) => {
+            "El cliente {client} intentó {type} la transacción {txn}, que no está en disputa"
+        }
+        (Es, Rejected(Unprocessable)) => {
+            "No se pudo procesar la transacción {txn} ({type}) del cliente {client}"
+        }
+        (Es, RejectedCount) => "Se rechazaron {rejected} de {processed} transacciones.",
+        (Es, SkippedCount) => "Se omitieron {skipped} de {rows} filas por no ser válidas.",
+        (Es, Rejected(ConflictingTxnId)) => {
+            "La transacción {txn} del cliente {client} reutiliza el id de una transacción de una ejecución anterior"
+        }
+        (Es, Rejected(NotDisputable)) => {
+            "El cliente {client} intentó {type} la transacción {txn}, que la política no permite disputar"
+        }
+        (Es, Rejected(OverLimit)) => {
+            "La transacción {txn} del cliente {client} supera el límite de su región"
+        }
+        (Es, Rejected(DisputeWindowClosed)) => {
+            "El cliente {client} intentó {type} la transacción {txn}, demasiado antigua para disputarla en su región"
+        }
+        (Es, Rejected(Denied)) => "La transacción {txn} del cliente {client} fue denegada: {why}",
+        (Es, Rejected(QuotaExceeded)) => {
+            "La transacción {txn} del cliente {client} supera la cuota {quota} del inquilino {tenant}"
+        }
+        (Es, Rejected(RecipientLocked)) => {
+            "El cliente {to} está bloqueado, se cancela la transferencia {txn} del cliente {client}"
+        }
+        (Es, Rejected(ReservedTxnId)) => {
+            "La transacción {txn} del cliente {client} usa un id reservado para transacciones generadas"
+        }
+        (Es, Rejected(DuplicateTxnId)) => {
+            "La transacción {txn} del cliente {client} reutiliza el id de una transacción anterior"
+        }
+        (Es, DuplicateCount) => "Se ignoraron {duplicates} de {processed} transacciones que repetían otras anteriores.",
+        (Es, Rejected(AdminOpsDisabled)) => {
+            "Se ignora la operación administrativa {txn} del cliente {client}, no se permiten operaciones administrativas"
+        }
+        (Es, Rejected(AccountClosed)) => {
+            "El cliente {client} está cerrado, se cancela la transacción {txn}"
+        }
+        (Es, Rejected(OverdraftLimit)) => {
+            "El cliente {client} intentó {type} la transacción {txn}, lo que dejaría sus fondos disponibles por debajo del límite de descubierto"
+        }
+        (Es, Rejected(AlreadyDisputed)) => {
+            "El cliente {client} intentó {type} la transacción {txn}, que ya fue disputada"
+        }
+        (Es, Rejected(InvalidDisputeAmount)) => {
+            "El cliente {client} intentó {type} más que el importe de la transacción {txn}"
+        }
+        (Es, UnknownRegion) => {
+            "Aviso: la región {region} no tiene reglas, sus clientes usan las reglas por defecto"
+        }
+        (Es, Conflicts) => {
+            "{count} transacciones entraron en conflicto con ejecuciones anteriores y no se aplicaron, ver {path}."
+        }
+        (Es, SlaBreached) => {
+            "La disputa de la transacción {txn} del cliente {client} lleva {days} días abierta, superando el SLA"
+        }
+        (Es, NextPage) => "Hay más cuentas, continuar con --after {after}.",
+        (Es, Sampled) => "Se muestrearon {processed} de {rows} filas leídas.",
+        (Es, StoppedEarly) => {
+            "Detenido antes de tiempo, {reason}. Se procesaron {rows} filas, reanudar desde la fila {resume}."
+        }
+
+        (De, Rejected(AccountLocked)) => {
+            "Kunde {client} ist gesperrt, Transaktion {txn} wird abgebrochen"
+        }
+        (De, Rejected(InsufficientFunds)) => {
+            "Kunde {client} hat nicht genügend Guthaben für Transaktion {txn}"
+        }
+        (De, Rejected(NotClientTransaction)) => {
+            "Kunde {client} versuchte {type} für Transaktion {txn}, die nicht seine ist"
+        }
+        (De, Rejected(NotDisputed)) => {
+            "Kunde {client} versuchte {type} für Transaktion {txn}, die nicht angefochten ist"
+        }
+        (De, Rejected(Unprocessable)) => {
+            "Transaktion {txn} ({type}) von Kunde {client} konnte nicht verarbeitet werden"
+        }
+        (De, RejectedCount) => "{rejected} von {processed} Transaktionen wurden abgelehnt.",
+        (De, SkippedCount) => "{skipped} von {rows} Zeilen wurden als ungültig übersprungen.",
+        (De, Rejected(ConflictingTxnId)) => {
+            "Transaktion {txn} von Kunde {client} verwendet die ID einer Transaktion aus einem früheren Lauf erneut"
+        }
+        (De, Rejected(NotDisputable)) => {
+            "Kunde {client} versuchte {type} für Transaktion {txn}, deren Anfechtung die Richtlinie nicht erlaubt"
+        }
+        (De, Rejected(OverLimit)) => {
+            "Transaktion {txn} von Kunde {client} überschreitet das Limit seiner Region"
+        }
+        (De, Rejected(DisputeWindowClosed)) => {
+            "Kunde {client} versuchte {type} für Transaktion {txn}, die in seiner Region zu alt für eine Anfechtung ist"
+        }
+        (De, Rejected(Denied)) => "Transaktion {txn} von Kunde {client} wurde abgelehnt: {why}",
+        (De, Rejected(QuotaExceeded)) => {
+            "Transaktion {txn} von Kunde {client} überschreitet das Kontingent {quota} von Mandant {tenant}"
+        }
+        (De, Rejected(RecipientLocked)) => {
+            "Kunde {to} ist gesperrt, Überweisung {txn} von Kunde {client} wird abgebrochen"
+        }
+        (De, Rejected(ReservedTxnId)) => {
+            "Transaktion {txn} von Kunde {client} verwendet eine für erzeugte Transaktionen reservierte ID"
+        }
+        (De, Rejected(DuplicateTxnId)) => {
+            "Transaktion {txn} von Kunde {client} verwendet die ID einer früheren Transaktion erneut"
+        }
+        (De, DuplicateCount) => "{duplicates} von {processed} Transaktionen wiederholten frühere und wurden ignoriert.",
+        (De, Rejected(AdminOpsDisabled)) => {
+            "Verwaltungsvorgang {txn} für Kunde {client} ignoriert, Verwaltungsvorgänge sind nicht erlaubt"
+        }
+        (De, Rejected(AccountClosed)) => {
+            "Kunde {client} ist geschlossen, Transaktion {txn} wird abgebrochen"
+        }
+        (De, Rejected(OverdraftLimit)) => {
+            "Kunde {client} versuchte {type} für Transaktion {txn}, was sein verfügbares Guthaben unter das Überziehungslimit bringen würde"
+        }
+        (De, Rejected(AlreadyDisputed)) => {
+            "Kunde {client} versuchte {type} für Transaktion {txn}, die bereits angefochten wurde"
+        }
+        (De, Rejected(InvalidDisputeAmount)) => {
+            "Kunde {client} versuchte {type} für mehr als den Betrag von Transaktion {txn}"
+        }
+        (De, UnknownRegion) => {
+            "Warnung: Region {region} hat keine Regeln, ihre Kunden erhalten die Standardregeln"
+        }
+        (De, Conflicts) => {
+            "{count} Transaktionen standen im Konflikt mit früheren Läufen und wurden nicht angewendet, siehe {path}."
+        }
+        (De, SlaBreached) => {
+            "Die Anfechtung von Transaktion {txn} von Kunde {client} ist seit {days} Tagen offen, länger als das SLA erlaubt"
+        }
+        (De, NextPage) => "Weitere Konten folgen, weiter mit --after {after}.",
+        (De, Sampled) => "{processed} von {rows} gelesenen Zeilen ausgewählt.",
+        (De, StoppedEarly) => {
+            "Vorzeitig angehalten, {reason}. {rows} Zeilen verarbeitet, fortsetzen ab Zeile {resume}."
+        }
+    }
+}
+
+/// Renders `key` in the current language, filling in the placeholders
+/// from `args`.
+pub fn message(key: Key, args: &[(&str, &dyn std::fmt::Display)]) -> String {
+    args.iter()
+        .fold(template(lang(), key).to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), &value.to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_language_fills_the_same_placeholders() {
+        let keys = Reason::ALL.into_iter().map(Key::Rejected).chain([
+            Key::RejectedCount,
+            Key::SkippedCount,
+            Key::DuplicateCount,
+            Key::Sampled,
+            Key::StoppedEarly,
+            Key::Conflicts,
+            Key::SlaBreached,
+            Key::UnknownRegion,
+            Key::NextPage,
+        ]);
+        let placeholders = |text: &str| {
+            let mut names: Vec<_> = text
+                .split('{')
+                .skip(1)
+                .filter_map(|x| x.split('}').next())
+                .map(str::to_string)
+                .collect();
+            names.sort();
+            names
+        };
+        for key in keys {
+            let english = placeholders(template(Lang::En, key));
+            for lang in [Lang::Es, Lang::De] {
+                assert_eq!(
+                    placeholders(template(lang, key)),
+                    english,
+                    "{:?} {:?}",
+                    lang,
+                    key
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use crate::{Database, Result};
+use std::{
+    fmt::Debug,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// The first transaction id of the range [`ReservedRange`] keeps for the
+/// transactions the engine generates itself.
+pub const INTERNAL_IDS_START: u32 = 0xF000_0000;
+
+/// Hands out the ids of the transactions the engine generates itself, such
+/// as fees, interest or adjustments, so they never collide with the ids of
+/// the input. See [`crate::Engine::process_internal`].
+///
+/// Input transactions carrying an id the allocator claims are rejected.
+pub trait IdAllocator: Debug + Send + Sync {
+    /// A new id no transaction of `db` uses yet.
+    fn allocate(&self, db: &Database) -> Result<u32>;
+    /// Whether `txn_id` belongs to generated transactions rather than to
+    /// the input.
+    fn is_internal(&self, txn_id: u32) -> bool;
+}
+
+#[derive(Debug)]
+/// Allocates ids upwards from [`INTERNAL_IDS_START`], below `u32::MAX`,
+/// skipping those already stored, so an engine loaded from an earlier run
+/// goes on where it stopped.
+pub struct ReservedRange {
+    start: u32,
+    next: AtomicU32,
+}
+
+impl ReservedRange {
+    /// Reserves the ids from `start` up.
+    pub fn new(start: u32) -> Self {
+        ReservedRange {
+            start,
+            next: AtomicU32::new(start),
+        }
+    }
+}
+
+impl Default for ReservedRange {
+    fn default() -> Self {
+        ReservedRange::new(INTERNAL_IDS_START)
+    }
+}
+
+impl IdAllocator for ReservedRange {
+    fn allocate(&self, db: &Database) -> Result<u32> {
+        loop {
+            let id = self
+                .next
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |id| id.checked_add(1))
+                .map_err(|_| "the reserved transaction ids are used up")?;
+            if !db.has_transaction(id)? {
+                return Ok(id);
+            }
+        }
+    }
+
+    fn is_internal(&self, txn_id: u32) -> bool {
+        txn_id >= self.start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{i18n::Reason, Engine, Transaction, TransactionOutcome};
+
+    #[test]
+    fn generated_ids_never_collide_with_the_input() -> Result<()> {
+        let engine = Engine::default();
+        engine.process_internal(Transaction::deposit(1, INTERNAL_IDS_START, 1.0))?;
+        let id = engine.next_internal_id()?;
+        assert_eq!(id, INTERNAL_IDS_START + 1);
+        engine.process_internal(Transaction::deposit(1, id, 2.0))?;
+        assert_eq!(
+            engine.process(Transaction::deposit(2, INTERNAL_IDS_START + 2, 1.0))?,
+            TransactionOutcome::Rejected(Reason::ReservedTxnId)
+        );
+        // Disputes refer to an existing transaction, whoever made it.
+        assert_eq!(
+            engine.process(Transaction::dispute(1, id))?,
+            TransactionOutcome::Applied
+        );
+        assert!(ReservedRange::new(u32::MAX).allocate(&engine.db).is_err());
+        Ok(())
+    }
+}
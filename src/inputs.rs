@@ -0,0 +1,223 @@
+use crate::{
+    compressed::{strip_extension, Compression, Input},
+    format::{parse_json, Format},
+    pay,
+    schema::{csv_reader, Schema},
+    validation::checked,
+    Result, RunOptions, Transaction,
+};
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// The input name that reads from standard input.
+pub const STDIN: &str = "-";
+
+/// The transactions of the inputs of a run, in order.
+pub type Transactions = Box<dyn Iterator<Item = Result<Transaction>>>;
+
+/// The transactions of a csv reader, parsed with the column map, carried
+/// columns, memo length, precision and field count rule of `options`.
+pub fn parse_csv<'a, R: Read + 'a>(
+    mut reader: csv::Reader<R>,
+    options: &RunOptions,
+) -> Result<impl Iterator<Item = Result<Transaction>> + 'a> {
+    let schema = schema_for(reader.headers()?, options)?;
+    // Every row is read into the same record, rather than a new one each.
+    let mut record = csv::ByteRecord::new();
+    Ok(std::iter::from_fn(move || {
+        span!("parse");
+        match reader.read_byte_record(&mut record) {
+            Ok(true) => Some(schema.parse_bytes(&record)),
+            Ok(false) => None,
+            Err(x) => Some(Err(x.into())),
+        }
+    }))
+}
+
+/// The schema of csv with `headers`, reading rows with the column map,
+/// carried columns, memo length, precision and field count rule of
+/// `options`.
+pub(crate) fn schema_for(headers: &csv::StringRecord, options: &RunOptions) -> Result<Schema> {
+    let headers = options.column_map.rename(headers)?;
+    let mut schema =
+        Schema::from_headers(&headers, &options.carry_columns)?.with_fields(options.fields);
+    if let Some(memo_max_len) = options.memo_max_len {
+        schema = schema.with_memo_max_len(memo_max_len);
+    }
+    if let Some(precision) = options.precision {
+        schema = schema.with_precision(precision);
+    }
+    Ok(schema)
+}
+
+#[derive(Debug, Clone, Default)]
+/// How far through its inputs a run is: the bytes read so far, out of
+/// their total size unless one of them is standard input or compiled.
+pub struct InputPosition {
+    read: Arc<AtomicU64>,
+    total: Option<u64>,
+}
+
+impl InputPosition {
+    pub fn offset(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> Option<u64> {
+        self.total
+    }
+}
+
+/// A reader adding the bytes it reads to an [`InputPosition`].
+struct Counted<R> {
+    inner: R,
+    read: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// The transactions of every input in turn, as if they were one file.
+/// [`STDIN`] reads from standard input, `.pay` files are read as compiled
+/// by `compile`, and anything else is a file in the format of
+/// `options`, or that its extension stands for if unset (see
+/// [`Format::detect`]). Standard input is csv unless `options` say
+/// otherwise.
+///
+/// Each csv input has its own header, so their columns needn't be in the
+/// same order. Every input is opened up front, so a missing one fails the
+/// run before anything is processed. Rows are checked against the
+/// consistency rules of `options` one input at a time, and a bad row
+/// fails with its input and row number.
+pub fn read_inputs(inputs: &[String], options: &RunOptions) -> Result<Transactions> {
+    Ok(read_inputs_at(inputs, options)?.0)
+}
+
+/// Like [`read_inputs`], with the position the run is at in the inputs,
+/// for [`crate::progress::Progress`].
+pub fn read_inputs_at(
+    inputs: &[String],
+    options: &RunOptions,
+) -> Result<(Transactions, InputPosition)> {
+    let mut position = InputPosition {
+        total: Some(0),
+        ..InputPosition::default()
+    };
+    if inputs.iter().filter(|input| *input == STDIN).count() > 1 {
+        return Err("standard input can only be read once".into());
+    }
+    let mut transactions: Transactions = Box::new(std::iter::empty());
+    for input in inputs {
+        let format = match input.as_str() {
+            STDIN => options.format.unwrap_or(Format::Csv),
+            path => options
+                .format
+                .unwrap_or_else(|| Format::detect(strip_extension(path))),
+        };
+        if input == STDIN || pay::is_pay(input) {
+            position.total = None;
+        }
+        let mut open = || -> Result<Input<Counted<File>>> {
+            let error = |x: std::io::Error| format!("error reading {}: {}", input, x);
+            let mut file =
+                File::open(input).map_err(|x| format!("error opening {}: {}", input, x))?;
+            let size = file.metadata().map_err(error)?.len();
+            let compression = Compression::detect(&mut file).map_err(error)?;
+            position.total = position.total.map(|total| total + size);
+            // Compressed inputs count the bytes read off the disk, so their
+            // progress is out of the size of the file too.
+            let counted = Counted {
+                inner: file,
+                read: position.read.clone(),
+            };
+            Input::new(counted, compression, input)
+        };
+        let parsed: Box<dyn Iterator<Item = Result<Transaction>>> = match format {
+            _ if pay::is_pay(input) => Box::new(pay::read_pay(input.clone())?),
+            Format::Csv if input == STDIN => {
+                Box::new(parse_csv(csv_reader(std::io::stdin()), options)?)
+            }
+            Format::Csv => Box::new(parse_csv(csv_reader(open()?), options)?),
+            Format::Json if input == STDIN => Box::new(parse_json(std::io::stdin(), options)),
+            Format::Json => Box::new(parse_json(BufReader::new(open()?), options)),
+        };
+        let checked = checked(parsed, options.consistency, input);
+        transactions = Box::new(transactions.chain(InInput::new(checked, input)));
+    }
+    Ok((transactions, position))
+}
+
+/// The transactions of one input, within an `input` span from when its
+/// first row is read until its last one is, so the events of processing
+/// its rows carry the file they came from. The span is at the `error`
+/// level so no log level leaves it out.
+struct InInput<I> {
+    inner: I,
+    span: tracing::Span,
+    entered: Option<tracing::span::EnteredSpan>,
+}
+
+impl<I> InInput<I> {
+    fn new(inner: I, input: &str) -> Self {
+        InInput {
+            inner,
+            span: tracing::error_span!("input", file = input),
+            entered: None,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<Transaction>>> Iterator for InInput<I> {
+    type Item = Result<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.entered.is_none() {
+            self.entered = Some(self.span.clone().entered());
+        }
+        let next = self.inner.next();
+        if next.is_none() {
+            self.entered = None;
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{run_transactions, Engine};
+
+    #[test]
+    fn inputs_are_processed_in_order() -> Result<()> {
+        let (first, second) = (
+            crate::temp_path("payments-engine-inputs-first.csv"),
+            crate::temp_path("payments-engine-inputs-second.csv"),
+        );
+        std::fs::write(&first, "type,client,tx,amount\ndeposit,1,1,5.0")
+            .map_err(|x| x.to_string())?;
+        // Columns in another order, the withdrawal needs the deposit first.
+        std::fs::write(&second, "client,type,amount,tx\n1,withdrawal,3.0,2\n")
+            .map_err(|x| x.to_string())?;
+        let inputs = [first, second].map(|path| path.to_string_lossy().to_string());
+        let options = RunOptions::default();
+        let engine = Engine::default();
+        let summary = run_transactions(read_inputs(&inputs, &options)?, &engine, &options)?;
+        assert_eq!((summary.rows, summary.rejected), (2, 0));
+        assert_eq!(engine.account(1)?.map(|a| a.available()), Some(2.0));
+
+        assert!(read_inputs(&[STDIN.to_string(), STDIN.to_string()], &options).is_err());
+        assert!(read_inputs(&[inputs[0].clone(), "missing.csv".to_string()], &options).is_err());
+        Ok(())
+    }
+}
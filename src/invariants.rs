@@ -0,0 +1,241 @@
+use crate::{
+    ledger::{LedgerAccount, Totals, TOLERANCE},
+    Client, ClientId, Database, PaymentsEngineError, Result,
+};
+use std::{collections::BTreeMap, fmt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A rule the state of the engine keeps to after every transaction.
+pub enum Invariant {
+    /// Balances are numbers, so every total is what is available plus
+    /// what is held.
+    FiniteBalances,
+    /// What is held is what the open disputes hold: their amounts, less
+    /// what pending holds have yet to take, per currency.
+    HeldByDisputes,
+    /// Nothing is held below zero.
+    HeldNotNegative,
+    /// The available funds are no lower than the overdraft policy lets
+    /// holds take them. Chargebacks taking what pending holds couldn't are
+    /// the exception, so it isn't checked with pending holds.
+    WithinOverdraft,
+    /// The funds of the clients are what the ledger owes them.
+    Ledger,
+}
+
+impl Invariant {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Invariant::FiniteBalances => "finite_balances",
+            Invariant::HeldByDisputes => "held_by_disputes",
+            Invariant::HeldNotNegative => "held_not_negative",
+            Invariant::WithinOverdraft => "within_overdraft",
+            Invariant::Ledger => "ledger",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An invariant the state broke.
+pub struct Violation {
+    pub invariant: Invariant,
+    /// The client whose account broke it, unless it is about every client.
+    pub client_id: Option<ClientId>,
+    /// The transaction after which it was found, when checking as they
+    /// are applied, see [`crate::Engine::with_invariant_checks`].
+    pub txn_id: Option<u32>,
+    pub detail: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(txn_id) = self.txn_id {
+            write!(f, "transaction {} broke ", txn_id)?;
+        }
+        write!(f, "invariant {}", self.invariant.as_str())?;
+        if let Some(client_id) = self.client_id {
+            write!(f, " for client {}", client_id)?;
+        }
+        write!(f, ": {}", self.detail)
+    }
+}
+
+impl From<Violation> for PaymentsEngineError {
+    fn from(violation: Violation) -> Self {
+        PaymentsEngineError::Invariant(Box::new(violation))
+    }
+}
+
+/// Whether `a` and `b` are the same amount, give or take floating point
+/// drift.
+fn close(a: f64, b: f64) -> bool {
+    (a - b).abs() <= TOLERANCE * (1.0 + a.abs().max(b.abs()))
+}
+
+impl Database {
+    /// Checks every account against the invariants, and the funds of all of
+    /// them against the ledger. Fails with the first [`Violation`] found,
+    /// as [`PaymentsEngineError::Invariant`].
+    pub fn verify(&self) -> Result<()> {
+        let mut first = None;
+        let (mut available, mut held) = (0.0, 0.0);
+        self.for_each_client(|client_id, client| {
+            available +=
+                client.available + client.currencies.values().map(|x| x.available).sum::<f64>();
+            held += client.held + client.currencies.values().map(|x| x.held).sum::<f64>();
+            if first.is_none() {
+                first = Some(self.check(client_id, client));
+            }
+        })?;
+        first.transpose()?;
+        let books = self.ledger.trial_balance()?;
+        let owed = |account| -books.get(&account).map_or(0.0, Totals::balance);
+        for (account, funds) in [
+            (LedgerAccount::ClientAvailable, available),
+            (LedgerAccount::ClientHeld, held),
+        ] {
+            if !close(owed(account), funds) {
+                return Err(Violation {
+                    invariant: Invariant::Ledger,
+                    client_id: None,
+                    txn_id: None,
+                    detail: format!(
+                        "the accounts hold {:.4} but {} is {:.4} in the ledger",
+                        funds,
+                        account,
+                        owed(account)
+                    ),
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the account of `client_id` against the invariants, if the
+    /// client exists, see [`Database::verify`].
+    pub fn verify_client(&self, client_id: ClientId) -> Result<()> {
+        if let Some(client) = self.clients.get(&client_id) {
+            return self.check(client_id, &client);
+        }
+        match &self.cold {
+            Some(cold) => match cold.store.get(client_id)? {
+                Some(client) => self.check(client_id, &client),
+                None => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn check(&self, client_id: ClientId, client: &Client) -> Result<()> {
+        let violation = |invariant, detail| -> Result<()> {
+            Err(Violation {
+                invariant,
+                client_id: Some(client_id),
+                txn_id: None,
+                detail,
+            }
+            .into())
+        };
+        let balances = std::iter::once((None, client.available, client.held)).chain(
+            client
+                .currencies
+                .iter()
+                .map(|(currency, balance)| (Some(&**currency), balance.available, balance.held)),
+        );
+        // What the open disputes hold, by currency.
+        let mut disputed: BTreeMap<Option<Box<str>>, f64> = BTreeMap::new();
+        for txn_id in client.disputed.keys() {
+            let (amount, currency) = self
+                .with_transaction(*txn_id, |txn| (txn.amount, txn.currency.clone()))?
+                .unwrap_or_default();
+            let amount = client.disputed_amount(*txn_id, amount.unwrap_or_default());
+            *disputed.entry(currency).or_default() += amount;
+        }
+        let pending: f64 = client
+            .pending_holds
+            .iter()
+            .map(|(_, pending)| pending)
+            .sum();
+        for (currency, available, held) in balances {
+            let name = currency.unwrap_or("the account's own currency");
+            if !available.is_finite() || !held.is_finite() {
+                return violation(
+                    Invariant::FiniteBalances,
+                    format!("{} available and {} held in {}", available, held, name),
+                );
+            }
+            if held < -TOLERANCE {
+                return violation(
+                    Invariant::HeldNotNegative,
+                    format!("{:.4} held in {}", held, name),
+                );
+            }
+            let expected = disputed
+                .remove(&currency.map(Into::into))
+                .unwrap_or_default()
+                - if currency.is_none() { pending } else { 0.0 };
+            if !close(held, expected) {
+                return violation(
+                    Invariant::HeldByDisputes,
+                    format!(
+                        "{:.4} held in {} but the open disputes hold {:.4}",
+                        held, name, expected
+                    ),
+                );
+            }
+            let floor = self.policy.hold_floor();
+            if currency.is_none() && !self.policy.pending_holds && available < floor - TOLERANCE {
+                return violation(
+                    Invariant::WithinOverdraft,
+                    format!("{:.4} available, below {:.4}", available, floor),
+                );
+            }
+        }
+        if let Some((currency, amount)) = disputed.into_iter().find(|(_, amount)| *amount != 0.0) {
+            return violation(
+                Invariant::HeldByDisputes,
+                format!(
+                    "nothing held in {} but the open disputes hold {:.4}",
+                    currency.as_deref().unwrap_or("the account's own currency"),
+                    amount
+                ),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{open_file_read_csv, run_engine, Engine, Transaction};
+
+    #[test]
+    fn the_engine_keeps_its_invariants() -> Result<()> {
+        let engine = Engine::default().with_invariant_checks();
+        run_engine(
+            open_file_read_csv("test-files/long_transaction_history.csv".to_string())?,
+            &engine,
+        )?;
+        engine.db.verify()?;
+
+        engine.process(Transaction::deposit(1, 100, 5.0))?;
+        engine.process(Transaction::dispute(1, 100))?;
+        if let Some(mut client) = engine.db.clients.get_mut(&1) {
+            client.held += 1.0;
+        }
+        let err = engine
+            .process(Transaction::deposit(1, 101, 1.0))
+            .expect_err("held was tampered with");
+        let PaymentsEngineError::Invariant(violation) = err else {
+            return Err(err);
+        };
+        assert_eq!(
+            (violation.invariant, violation.client_id, violation.txn_id),
+            (Invariant::HeldByDisputes, Some(1), Some(101))
+        );
+        assert!(engine.db.verify().is_err());
+        Ok(())
+    }
+}
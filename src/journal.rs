@@ -0,0 +1,252 @@
+use crate::{
+    account::AccountView, clock::Timestamp, format::Format, i18n::Reason, policy::LockedAccounts,
+    ClientId, Result, Transaction, TransactionOutcome, TransactionType,
+};
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+/// What became of one transaction the engine was handed, as
+/// [`crate::Engine::process`] reports it to the journal.
+pub enum EngineEvent {
+    /// The transaction changed the state, leaving its client's account as
+    /// `account`. `let_through` is the policy that let it through if the
+    /// account was locked, see [`crate::policy::Policy::locked_accounts`].
+    Applied {
+        txn: Transaction,
+        account: AccountView,
+        let_through: Option<LockedAccounts>,
+    },
+    /// The transaction was refused for `reason` and changed nothing. `why`
+    /// is the reason a plugin or rule gave for denying it.
+    Rejected {
+        txn: Transaction,
+        reason: Reason,
+        why: Option<String>,
+    },
+    /// The transaction repeated one already handled and was ignored.
+    Duplicate { txn: Transaction },
+}
+
+impl EngineEvent {
+    /// The event of `txn` having had `outcome`, with the account of its
+    /// client afterwards, and the locked accounts policy if it was locked
+    /// before.
+    pub fn new(
+        txn: Transaction,
+        outcome: TransactionOutcome,
+        account: Option<AccountView>,
+        let_through: Option<LockedAccounts>,
+    ) -> Result<Self> {
+        Ok(match outcome {
+            TransactionOutcome::Applied => EngineEvent::Applied {
+                account: account.ok_or("an applied transaction left no account")?,
+                txn,
+                let_through,
+            },
+            TransactionOutcome::Rejected(reason) => EngineEvent::Rejected {
+                txn,
+                reason,
+                why: None,
+            },
+            TransactionOutcome::Duplicate => EngineEvent::Duplicate { txn },
+        })
+    }
+
+    /// The event with why a plugin or rule denied the transaction, if one
+    /// did.
+    pub fn with_why(mut self, denied: Option<String>) -> Self {
+        if let EngineEvent::Rejected { why, .. } = &mut self {
+            *why = denied;
+        }
+        self
+    }
+
+    pub fn txn(&self) -> &Transaction {
+        match self {
+            EngineEvent::Applied { txn, .. }
+            | EngineEvent::Rejected { txn, .. }
+            | EngineEvent::Duplicate { txn } => txn,
+        }
+    }
+}
+
+#[derive(Serialize)]
+/// An event as a line of the journal.
+struct Row<'a> {
+    seq: u64,
+    event: &'static str,
+    reason: Option<&'static str>,
+    why: Option<&'a str>,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    client: ClientId,
+    tx: u32,
+    to_client: Option<ClientId>,
+    amount: Option<f64>,
+    timestamp: Option<Timestamp>,
+    available: Option<f64>,
+    held: Option<f64>,
+    locked: Option<bool>,
+}
+
+/// Where the lines of a journal go.
+enum Sink {
+    Csv(Box<csv::Writer<File>>),
+    Json(BufWriter<File>),
+}
+
+/// An append-only record of every transaction the engine handles, applied
+/// or not, in the order they were handled. Each line has a sequence number
+/// following on from the lines already in the file, the event (`applied`,
+/// `queued`, `rejected` or `duplicate`), the reason code of a rejection or
+/// the locked accounts policy that let a transaction through to a locked
+/// account, why a plugin or rule denied a transaction, the transaction,
+/// and the client's balances after an applied one.
+pub struct Journal {
+    path: String,
+    seq: u64,
+    sink: Sink,
+}
+
+impl std::fmt::Debug for Journal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Journal")
+            .field("path", &self.path)
+            .field("seq", &self.seq)
+            .finish()
+    }
+}
+
+impl Journal {
+    /// Opens the journal at `path` to append to, creating it if needed.
+    pub fn open(path: &str, format: Format) -> Result<Self> {
+        let error = |x: std::io::Error| format!("error opening {}: {}", path, x);
+        let lines = match File::open(path) {
+            Ok(file) => BufReader::new(file).lines().count() as u64,
+            Err(x) if x.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(x) => return Err(error(x).into()),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(error)?;
+        let (seq, sink) = match format {
+            Format::Csv => {
+                let writer = csv::WriterBuilder::new()
+                    .has_headers(lines == 0)
+                    .from_writer(file);
+                (lines.saturating_sub(1), Sink::Csv(Box::new(writer)))
+            }
+            Format::Json => (lines, Sink::Json(BufWriter::new(file))),
+        };
+        Ok(Journal {
+            path: path.to_string(),
+            seq,
+            sink,
+        })
+    }
+
+    pub fn record(&mut self, event: &EngineEvent) -> Result<()> {
+        self.seq += 1;
+        let txn = event.txn();
+        let why = match event {
+            EngineEvent::Rejected { why, .. } => why.as_deref(),
+            _ => None,
+        };
+        let (name, reason, account) = match event {
+            EngineEvent::Applied {
+                account,
+                let_through: Some(locked),
+                ..
+            } => {
+                let name = match txn.transaction_type {
+                    TransactionType::Deposit => "queued",
+                    _ => "applied",
+                };
+                (name, Some(locked.as_str()), Some(account))
+            }
+            EngineEvent::Applied { account, .. } => ("applied", None, Some(account)),
+            EngineEvent::Rejected { reason, .. } => ("rejected", Some(reason.code()), None),
+            EngineEvent::Duplicate { .. } => ("duplicate", None, None),
+        };
+        let row = Row {
+            seq: self.seq,
+            event: name,
+            reason,
+            why,
+            kind: txn.transaction_type.as_str(),
+            client: txn.client_id,
+            tx: txn.txn_id,
+            to_client: txn.to_client,
+            amount: txn.amount,
+            timestamp: txn.timestamp,
+            available: account.map(AccountView::available),
+            held: account.map(AccountView::held),
+            locked: account.map(AccountView::locked),
+        };
+        let error = |x: std::io::Error| format!("error writing {}: {}", self.path, x);
+        match &mut self.sink {
+            Sink::Csv(writer) => writer.serialize(row)?,
+            Sink::Json(out) => {
+                serde_json::to_writer(&mut *out, &row).map_err(|x| x.to_string())?;
+                writeln!(out).map_err(error)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the lines recorded so far to the file.
+    pub fn flush(&mut self) -> Result<()> {
+        match &mut self.sink {
+            Sink::Csv(writer) => writer.flush(),
+            Sink::Json(out) => out.flush(),
+        }
+        .map_err(|x| format!("error writing {}: {}", self.path, x).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, rules::Rules, Engine};
+    use std::fs;
+
+    #[test]
+    fn every_transaction_is_journaled_in_order() -> Result<()> {
+        let path = crate::temp_path("payments-engine-journal-test.csv");
+        let path = path.to_string_lossy().to_string();
+        let _ = fs::remove_file(&path);
+        for run in 0..2 {
+            let engine = Engine::with_clock(SimulatedClock::new(100))
+                .with_plugin(Rules::compile(
+                    r#"when amount > 50 then reject "LimitExceeded""#,
+                )?)
+                .with_journal(Journal::open(&path, Format::Csv)?);
+            engine.process(Transaction::deposit(1, 1 + run, 2.0))?;
+            engine.process(Transaction::withdrawal(1, 10 + run, 5.0))?;
+            engine.process(Transaction::withdrawal(1, 20 + run, 60.0))?;
+            engine.flush_journal()?;
+        }
+        let journal = fs::read_to_string(&path).map_err(|x| x.to_string())?;
+        let lines: Vec<_> = journal.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "seq,event,reason,why,type,client,tx,to_client,amount,timestamp,available,held,locked",
+                "1,applied,,,deposit,1,1,,2.0,100,2.0,0.0,false",
+                "2,rejected,insufficient_funds,,withdrawal,1,10,,5.0,100,,,",
+                "3,rejected,denied,LimitExceeded,withdrawal,1,20,,60.0,100,,,",
+                "4,applied,,,deposit,1,2,,2.0,100,2.0,0.0,false",
+                "5,rejected,insufficient_funds,,withdrawal,1,11,,5.0,100,,,",
+                "6,rejected,denied,LimitExceeded,withdrawal,1,21,,60.0,100,,,",
+            ]
+        );
+        fs::remove_file(&path).map_err(|x| x.to_string())?;
+        Ok(())
+    }
+}
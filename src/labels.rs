@@ -0,0 +1,169 @@
+use crate::{
+    clock::{Clock, SystemClock, Timestamp},
+    digest::format_digest,
+    Engine, Result, RunSummary,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+/// `key=value` pairs naming a run, e.g. `env=staging` or
+/// `pipeline=nightly`, carried by its manifest, its admin log entries and
+/// its metrics, so runs writing to the same sinks can be told apart.
+pub struct Labels(BTreeMap<String, String>);
+
+impl Labels {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+impl FromIterator<(String, String)> for Labels {
+    /// A key given twice keeps its last value.
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(labels: I) -> Self {
+        Labels(labels.into_iter().collect())
+    }
+}
+
+impl fmt::Display for Labels {
+    /// The labels as Prometheus writes them between braces, values quoted
+    /// and escaped, e.g. `env="staging",pipeline="nightly"`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.iter().enumerate() {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            write!(f, "{}{}=\"{}\"", if i > 0 { "," } else { "" }, key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a `key=value` label for the command line. Keys are letters,
+/// digits and underscores, not starting with a digit, so they are valid
+/// metric label names too.
+pub fn parse_label(s: &str) -> std::result::Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value, got {:?}", s))?;
+    let key = key.trim();
+    let valid = key
+        .chars()
+        .enumerate()
+        .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+    if key.is_empty() || !valid {
+        return Err(format!(
+            "label {:?} must be letters, digits and underscores, not starting with a digit",
+            key
+        ));
+    }
+    Ok((key.to_string(), value.trim().to_string()))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+/// What a batch run read and what became of it, written with
+/// `--manifest` for whatever picks up the run's output.
+pub struct RunManifest {
+    /// When the run started and finished, in seconds since the epoch.
+    pub started: Timestamp,
+    pub finished: Timestamp,
+    pub inputs: Vec<String>,
+    pub rows: u64,
+    pub processed: u64,
+    pub rejected: u64,
+    pub duplicates: u64,
+    pub errors: u64,
+    /// Why the run stopped early, if a limit stopped it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stopped: Option<String>,
+    /// The digest of the state the run ended in.
+    pub digest: String,
+    #[serde(skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+}
+
+impl RunManifest {
+    /// The manifest of a run of `engine` over `inputs` that started at
+    /// `started` and ended with `summary`.
+    pub fn new(
+        engine: &Engine,
+        inputs: &[String],
+        started: Timestamp,
+        summary: &RunSummary,
+    ) -> Self {
+        RunManifest {
+            started,
+            finished: SystemClock.now(),
+            inputs: inputs.to_vec(),
+            rows: summary.rows,
+            processed: summary.processed,
+            rejected: summary.rejected,
+            duplicates: summary.duplicates,
+            errors: summary.errors,
+            stopped: summary.stopped.map(|stopped| stopped.reason.to_string()),
+            digest: format_digest(engine.db.digest()),
+            labels: engine.labels().clone(),
+        }
+    }
+
+    /// Writes the manifest to `path` as pretty JSON.
+    pub fn write(&self, path: &str) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(|x| x.to_string())?;
+        std::fs::write(path, json + "\n")
+            .map_err(|x| format!("error writing manifest {}: {}", path, x).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_reach_the_manifest_and_the_metrics() -> Result<()> {
+        assert_eq!(
+            parse_label(" env = staging "),
+            Ok(("env".to_string(), "staging".to_string()))
+        );
+        assert!(parse_label("env").is_err());
+        assert!(parse_label("2env=x").is_err());
+        assert!(parse_label("env-name=x").is_err());
+        let labels: Labels = [
+            ("pipeline", "nightly \"b\""),
+            ("env", "staging"),
+            ("env", "prod"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        assert_eq!(labels.to_string(), r#"env="prod",pipeline="nightly \"b\"""#);
+
+        let engine = Engine::default().with_labels(labels);
+        engine.process(crate::Transaction::deposit(1, 1, 2.0))?;
+        let metrics = engine.metrics()?.to_string();
+        assert!(metrics
+            .lines()
+            .any(|line| line == r#"accounts{env="prod",pipeline="nightly \"b\""} 1"#));
+        assert!(metrics
+            .lines()
+            .any(|line| line
+                == r#"amount_max{type="deposit",env="prod",pipeline="nightly \"b\""} 2"#));
+        let summary = RunSummary {
+            rows: 1,
+            processed: 1,
+            ..RunSummary::default()
+        };
+        let manifest = RunManifest::new(&engine, &["in.csv".to_string()], 0, &summary);
+        let json = serde_json::to_value(&manifest).map_err(|x| x.to_string())?;
+        assert_eq!(json["labels"]["env"], "prod");
+        assert_eq!(json["rows"], 1);
+        Ok(())
+    }
+}
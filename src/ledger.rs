@@ -0,0 +1,375 @@
+use crate::{
+    fees::FeeTotals, open_file_read_csv, schema::Schema, spend::Period, Client, ClientId, Database,
+    Engine, Result, TransactionOutcome, TransactionType,
+};
+use std::{collections::BTreeMap, fmt, sync::Mutex};
+
+/// How far apart debits and credits may be before the books are
+/// considered out of balance, to allow for floating point drift.
+pub(crate) const TOLERANCE: f64 = 1e-6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// The accounts of the double-entry ledger the engine's transactions are
+/// posted to. Client funds are liabilities owed to the clients, cash is
+/// the asset backing them.
+pub enum LedgerAccount {
+    /// Money received from clients and not paid back yet.
+    Cash,
+    /// Funds clients can use.
+    ClientAvailable,
+    /// Funds held while a dispute is open.
+    ClientHeld,
+    /// Withdrawals under dispute, which may come back from the client's
+    /// bank.
+    Receivable,
+    /// Corrections made to client funds by the back office.
+    Adjustments,
+    /// Balances the engine didn't build up itself, carried over from
+    /// another system or an earlier run.
+    Opening,
+    /// Fees taken from clients on deposits and withdrawals, less those
+    /// given back with chargebacks.
+    Fees,
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LedgerAccount::Cash => "cash",
+            LedgerAccount::ClientAvailable => "client_available",
+            LedgerAccount::ClientHeld => "client_held",
+            LedgerAccount::Receivable => "receivable",
+            LedgerAccount::Adjustments => "adjustments",
+            LedgerAccount::Opening => "opening",
+            LedgerAccount::Fees => "fees",
+        })
+    }
+}
+
+/// The debit and credit sides of an applied transaction. Disputes,
+/// resolves and chargebacks move the amount of the transaction they refer
+/// to, of type `disputed`.
+pub fn postings(
+    transaction_type: TransactionType,
+    disputed: Option<TransactionType>,
+) -> &'static [(LedgerAccount, LedgerAccount)] {
+    use LedgerAccount::*;
+    let withdrawal = disputed == Some(TransactionType::Withdrawal);
+    match transaction_type {
+        TransactionType::Deposit => &[(Cash, ClientAvailable)],
+        TransactionType::Withdrawal => &[(ClientAvailable, Cash)],
+        TransactionType::Dispute if withdrawal => &[(Receivable, ClientHeld)],
+        TransactionType::Dispute => &[(ClientAvailable, ClientHeld)],
+        TransactionType::Resolve if withdrawal => &[(ClientHeld, Receivable)],
+        TransactionType::Resolve => &[(ClientHeld, ClientAvailable)],
+        // The money comes back from the client's bank and is theirs again.
+        TransactionType::ChargeBack if withdrawal => {
+            &[(ClientHeld, ClientAvailable), (Cash, Receivable)]
+        }
+        // The money goes back to the client's bank.
+        TransactionType::ChargeBack => &[(ClientHeld, Cash)],
+        // From one client to another, which nets out in the ledger.
+        TransactionType::Transfer => &[(ClientAvailable, ClientAvailable)],
+        // Corrections the business pays for, or recovers.
+        TransactionType::CreditAdjustment => &[(Adjustments, ClientAvailable)],
+        TransactionType::DebitAdjustment => &[(ClientAvailable, Adjustments)],
+        TransactionType::Unlock | TransactionType::Close => &[],
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+/// What was posted to one ledger account.
+pub struct Totals {
+    pub debits: f64,
+    pub credits: f64,
+}
+
+impl Totals {
+    /// Debits less credits.
+    pub fn balance(&self) -> f64 {
+        self.debits - self.credits
+    }
+}
+
+/// Debits and credits per ledger account, ordered by account.
+pub type TrialBalance = BTreeMap<LedgerAccount, Totals>;
+
+/// Posts `amount` to `ledger` for a transaction of `transaction_type`,
+/// and its `fee`: taken from the client, or given back by a chargeback.
+fn post(
+    ledger: &mut TrialBalance,
+    transaction_type: TransactionType,
+    disputed: Option<TransactionType>,
+    amount: f64,
+    fee: f64,
+) {
+    use LedgerAccount::*;
+    for &(debit, credit) in postings(transaction_type, disputed) {
+        ledger.entry(debit).or_default().debits += amount;
+        ledger.entry(credit).or_default().credits += amount;
+    }
+    if fee != 0.0 {
+        let (debit, credit) = match transaction_type {
+            TransactionType::ChargeBack => (Fees, ClientAvailable),
+            _ => (ClientAvailable, Fees),
+        };
+        ledger.entry(debit).or_default().debits += fee;
+        ledger.entry(credit).or_default().credits += fee;
+    }
+}
+
+/// The amount an applied transaction moves, the type of the transaction
+/// a dispute, resolve or chargeback refers to, and the fee taken, or
+/// given back by a chargeback.
+fn moved(
+    db: &Database,
+    transaction_type: TransactionType,
+    client_id: ClientId,
+    txn_id: u32,
+    amount: Option<f64>,
+) -> Result<(f64, Option<TransactionType>, f64)> {
+    let (amount, disputed) = match transaction_type {
+        TransactionType::Dispute | TransactionType::Resolve | TransactionType::ChargeBack => db
+            .with_transaction(txn_id, |txn| (txn.amount, Some(txn.transaction_type)))?
+            .unwrap_or_default(),
+        _ => (amount, None),
+    };
+    let partial = match disputed {
+        Some(_) => db.disputed_amount(client_id, txn_id),
+        None => None,
+    };
+    let amount = partial.or(amount).unwrap_or_default();
+    let fee = match (transaction_type, disputed) {
+        // Only charging back the whole transaction gives its fee back.
+        (TransactionType::ChargeBack, Some(_)) if partial.is_some() => 0.0,
+        (TransactionType::ChargeBack, Some(disputed)) => db.policy.fee(client_id, disputed, amount),
+        (TransactionType::Deposit | TransactionType::Withdrawal, _) => {
+            db.policy.fee(client_id, transaction_type, amount)
+        }
+        _ => 0.0,
+    };
+    Ok((amount, disputed, fee))
+}
+
+#[derive(Debug, Default)]
+/// The books of an engine, posted to as it applies transactions. Accounts
+/// it didn't build up itself, seeded, imported or already in the cold
+/// store, are posted as opening balances.
+pub struct Ledger {
+    books: Mutex<TrialBalance>,
+    /// The fees of each client, ordered by client.
+    fees: Mutex<BTreeMap<ClientId, FeeTotals>>,
+}
+
+impl Ledger {
+    /// Posts a transaction of `db` that was just applied.
+    pub fn post(
+        &self,
+        db: &Database,
+        transaction_type: TransactionType,
+        client_id: ClientId,
+        txn_id: u32,
+        amount: Option<f64>,
+    ) -> Result<()> {
+        let (amount, disputed, fee) = moved(db, transaction_type, client_id, txn_id, amount)?;
+        let mut ledger = self.books.lock().map_err(|_| "ledger lock poisoned")?;
+        post(&mut ledger, transaction_type, disputed, amount, fee);
+        if fee != 0.0 {
+            let mut fees = self.fees.lock().map_err(|_| "ledger lock poisoned")?;
+            let totals = fees.entry(client_id).or_default();
+            match transaction_type {
+                TransactionType::ChargeBack => totals.reversed += fee,
+                _ => totals.charged += fee,
+            }
+        }
+        Ok(())
+    }
+
+    /// Posts the balances of a client loaded rather than transacted.
+    pub fn open(&self, client: &Client) -> Result<()> {
+        use LedgerAccount::*;
+        let mut ledger = self.books.lock().map_err(|_| "ledger lock poisoned")?;
+        for (account, amount) in [
+            (ClientAvailable, client.available),
+            (ClientHeld, client.held),
+        ] {
+            ledger.entry(Opening).or_default().debits += amount;
+            ledger.entry(account).or_default().credits += amount;
+        }
+        Ok(())
+    }
+
+    pub fn trial_balance(&self) -> Result<TrialBalance> {
+        Ok(self
+            .books
+            .lock()
+            .map_err(|_| "ledger lock poisoned")?
+            .clone())
+    }
+
+    /// The fees of each client that paid any, see [`crate::fees`].
+    pub fn fees(&self) -> Result<BTreeMap<ClientId, FeeTotals>> {
+        Ok(self
+            .fees
+            .lock()
+            .map_err(|_| "ledger lock poisoned")?
+            .clone())
+    }
+
+    /// What backs the funds of the clients: the cash, and what stands in
+    /// for it until it is received or paid out.
+    pub fn cash(&self) -> Result<f64> {
+        use LedgerAccount::*;
+        let ledger = self.trial_balance()?;
+        Ok([Cash, Receivable, Adjustments, Opening, Fees]
+            .iter()
+            .map(|account| ledger.get(account).map_or(0.0, Totals::balance))
+            .sum())
+    }
+}
+
+/// Runs `input` through the engine and posts every transaction it applied
+/// within `period` to the ledger.
+///
+/// Fails unless the books balance: debits must equal credits and, over
+/// the whole input, the client accounts of the ledger must match the
+/// balances the engine ended up with.
+pub fn trial_balance(input: String, period: Period) -> Result<TrialBalance> {
+    let mut reader = open_file_read_csv(input)?;
+    let schema = Schema::from_headers(reader.headers()?, &[])?;
+    let engine = Engine::default();
+    let mut ledger = TrialBalance::new();
+    for record in reader.records() {
+        let mut txn = schema.parse(&record?)?;
+        let timestamp = *txn.timestamp.get_or_insert_with(|| engine.clock.now());
+        let (transaction_type, client_id, txn_id) =
+            (txn.transaction_type, txn.client_id, txn.txn_id);
+        let amount = txn.amount;
+        if engine.process(txn)? != TransactionOutcome::Applied || !period.contains(timestamp) {
+            continue;
+        }
+        let (amount, disputed, fee) =
+            moved(&engine.db, transaction_type, client_id, txn_id, amount)?;
+        post(&mut ledger, transaction_type, disputed, amount, fee);
+    }
+    check_balanced(&ledger)?;
+    if period == Period::default() {
+        reconcile(&engine, &ledger)?;
+    }
+    Ok(ledger)
+}
+
+fn check_balanced(ledger: &TrialBalance) -> Result<()> {
+    let debits: f64 = ledger.values().map(|totals| totals.debits).sum();
+    let credits: f64 = ledger.values().map(|totals| totals.credits).sum();
+    if (debits - credits).abs() > TOLERANCE {
+        return Err(format!(
+            "the books don't balance: {:.4} debited, {:.4} credited",
+            debits, credits
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Checks the client accounts of the ledger against the engine's balances.
+fn reconcile(engine: &Engine, ledger: &TrialBalance) -> Result<()> {
+    let (mut available, mut held) = (0.0, 0.0);
+    engine.db.for_each_client(|_, client| {
+        available += client.available;
+        held += client.held;
+    })?;
+    let owed = |account| -ledger.get(&account).map_or(0.0, Totals::balance);
+    for (account, expected) in [
+        (LedgerAccount::ClientAvailable, available),
+        (LedgerAccount::ClientHeld, held),
+    ] {
+        if (owed(account) - expected).abs() > TOLERANCE {
+            return Err(format!(
+                "the books don't balance: {} is {:.4} in the ledger but {:.4} in the accounts",
+                account,
+                owed(account),
+                expected
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Prints a trial balance as csv to stdout, with a total row.
+pub fn print_trial_balance(ledger: &TrialBalance) {
+    println!(
+        "{:>16}, {:>14}, {:>14}, {:>14}",
+        "account", "debits", "credits", "balance"
+    );
+    let mut total = Totals::default();
+    for (account, totals) in ledger {
+        println!(
+            "{:>16}, {:>14.4}, {:>14.4}, {:>14.4}",
+            account.to_string(),
+            totals.debits,
+            totals.credits,
+            totals.balance()
+        );
+        total.debits += totals.debits;
+        total.credits += totals.credits;
+    }
+    println!(
+        "{:>16}, {:>14.4}, {:>14.4}, {:>14.4}",
+        "total",
+        total.debits,
+        total.credits,
+        total.balance()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn books_balance_over_a_history() -> Result<()> {
+        let ledger = trial_balance(
+            "test-files/long_transaction_history.csv".to_string(),
+            Period::default(),
+        )?;
+        let cash = ledger[&LedgerAccount::Cash].balance();
+        let owed: f64 = [
+            LedgerAccount::ClientAvailable,
+            LedgerAccount::ClientHeld,
+            LedgerAccount::Receivable,
+        ]
+        .iter()
+        .map(|account| ledger.get(account).map_or(0.0, Totals::balance))
+        .sum();
+        assert!((cash + owed).abs() < TOLERANCE);
+        Ok(())
+    }
+
+    #[test]
+    fn the_engine_keeps_its_books() -> Result<()> {
+        let ledger = trial_balance(
+            "test-files/long_transaction_history.csv".to_string(),
+            Period::default(),
+        )?;
+        let engine = Engine::default();
+        crate::run_engine(
+            open_file_read_csv("test-files/long_transaction_history.csv".to_string())?,
+            &engine,
+        )?;
+        assert_eq!(engine.db.ledger.trial_balance()?, ledger);
+
+        let seeded = Engine::default();
+        let balances = [crate::opening::OpeningBalance {
+            client: 1,
+            available: 5.0,
+            held: 1.0,
+            locked: false,
+        }];
+        seeded.seed_accounts(&balances, None)?;
+        seeded.process(crate::Transaction::deposit(1, 1, 2.0))?;
+        assert_eq!(seeded.db.ledger.cash()?, 8.0);
+        Ok(())
+    }
+}
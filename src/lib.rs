@@ -0,0 +1,3181 @@
+//! A payments engine: it applies deposits, withdrawals, disputes,
+//! resolutions and chargebacks to client accounts.
+//!
+//! Embed it by creating an [`Engine`] and handing it each [`Transaction`]
+//! with [`Engine::process`], then read accounts back with
+//! [`Engine::account`]. The `payments-engine` binary is a command line
+//! front end over this library.
+//!
+//! ```
+//! use payments_engine::{Engine, Transaction, TransactionOutcome};
+//!
+//! let engine = Engine::default();
+//! engine.process(Transaction::deposit(1, 1, 2.0))?;
+//! let outcome = engine.process(Transaction::withdrawal(1, 2, 5.0))?;
+//! assert!(matches!(outcome, TransactionOutcome::Rejected(_)));
+//! let account = engine.account(1)?.expect("client 1 has an account");
+//! assert_eq!(account.available(), 2.0);
+//! # Ok::<(), payments_engine::PaymentsEngineError>(())
+//! ```
+//!
+//! The items embedders can rely on across minor versions are gathered in
+//! [`prelude`].
+
+/// Times the rest of the enclosing block as a stage of the `--profile`
+/// output. Compiles to nothing without the `profiling` feature.
+#[cfg(feature = "profiling")]
+macro_rules! span {
+    ($name:literal) => {
+        let _span = crate::profile::Span::enter($name);
+    };
+}
+#[cfg(not(feature = "profiling"))]
+macro_rules! span {
+    ($name:literal) => {};
+}
+
+pub mod account;
+pub mod admin;
+#[cfg(feature = "alloc-count")]
+#[doc(hidden)]
+pub mod alloc;
+pub mod amounts;
+pub mod anonymize;
+pub mod append;
+pub mod approvals;
+pub mod audit;
+pub mod backfill;
+pub mod batch;
+pub mod builder;
+pub mod capture;
+pub mod clock;
+#[cfg(feature = "server")]
+pub mod clone;
+pub mod compare;
+pub mod compressed;
+pub mod currency;
+pub mod dates;
+pub mod dedup;
+#[doc(hidden)]
+pub mod determinism;
+pub mod digest;
+pub mod disputes;
+pub mod dryrun;
+pub mod errors;
+pub mod fees;
+pub mod follow;
+pub mod format;
+#[doc(hidden)]
+pub mod generator;
+pub mod handle;
+#[cfg(feature = "server")]
+pub mod handoff;
+pub mod history;
+pub mod hooks;
+#[cfg(feature = "server")]
+pub mod http;
+pub mod i18n;
+pub mod ids;
+pub mod inputs;
+pub mod invariants;
+pub mod journal;
+pub mod labels;
+pub mod ledger;
+pub mod limits;
+pub mod locked;
+pub mod logging;
+pub mod metrics;
+pub mod notify;
+pub mod opening;
+pub mod outbox;
+pub mod outcomes;
+pub mod output;
+pub mod pay;
+pub mod period;
+pub mod pipeline;
+pub mod plugin;
+pub mod policy;
+pub mod precision;
+pub mod prelude;
+pub mod preview;
+#[cfg(feature = "profiling")]
+#[doc(hidden)]
+pub mod profile;
+pub mod progress;
+pub mod query;
+pub mod quota;
+pub mod regions;
+pub mod report;
+pub mod rules;
+pub mod sample;
+pub mod sar;
+pub mod schema;
+#[cfg(feature = "server")]
+pub mod serve;
+#[doc(hidden)]
+pub mod shape;
+#[doc(hidden)]
+pub mod simulate;
+pub mod sink;
+pub mod snapshot;
+#[doc(hidden)]
+pub mod soak;
+pub mod source;
+pub mod spend;
+pub mod spill;
+pub mod state;
+pub mod statement;
+pub mod store;
+#[cfg(feature = "async")]
+pub mod stream;
+#[doc(hidden)]
+pub mod stress;
+pub mod summary;
+pub mod tiering;
+pub mod validation;
+pub mod wal;
+pub mod whatif;
+
+use append::PreviousRuns;
+use capture::Capture;
+use clock::{Clock, SystemClock, Timestamp};
+use currency::Balance;
+use dashmap::{
+    mapref::{entry::Entry, one::RefMut},
+    DashMap, DashSet,
+};
+use dedup::DedupWindow;
+use digest::client_digest;
+use disputes::TxnState;
+use errors::ErrorLog;
+use format::Format;
+use history::{History, HistoryEntry};
+use i18n::{message, Key, Reason};
+use ids::{IdAllocator, ReservedRange};
+use journal::{EngineEvent, Journal};
+use limits::{LimitTracker, RejectRate, RunLimits, Stopped};
+use locked::{Lock, LockReason};
+use metrics::{Counters, Metrics};
+use notify::{Notifications, Payload, Template, Watch};
+use outbox::Event;
+use outcomes::{OutcomeLog, OutcomeRecord, RowStatus};
+use plugin::{Plugin, Screened};
+use policy::{LockedAccounts, Policy};
+use quota::Quotas;
+use regions::Regions;
+use sample::Sample;
+use schema::Schema;
+use snapshot::SnapshotOptions;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::File,
+    num::{ParseFloatError, ParseIntError},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, RwLock,
+    },
+};
+use tiering::{Preload, Tiering};
+use validation::{Consistency, Inconsistency, Mode};
+use wal::Wal;
+
+pub type Result<T> = std::result::Result<T, PaymentsEngineError>;
+
+/// Where a test keeps its file or directory `name`: in the temp dir, under
+/// a name no other test of this run and no concurrent run of the suite
+/// uses.
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    std::env::temp_dir().join(format!(
+        "{}-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed),
+        name
+    ))
+}
+
+/// The id of a client. Inputs that numbered clients with smaller ids
+/// parse the same.
+pub type ClientId = u64;
+/// The id of a transaction.
+pub type TxnId = u32;
+/// An amount of funds.
+pub type Money = f64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PaymentsEngineError {
+    /// A row or a value in it that can't be parsed.
+    #[error("{0}")]
+    ParseError(String),
+    /// Reading or writing an input, a file or a connection failed.
+    #[error("{0}")]
+    IoError(#[from] std::io::Error),
+    #[error("unknown transaction type {0:?}")]
+    InvalidTransactionType(String),
+    /// A transaction id used twice where ids must be unique.
+    #[error("transaction {0} appears twice")]
+    DuplicateTxnId(u32),
+    /// A row breaking one of the [`Consistency`] rules.
+    #[error("{0}")]
+    Inconsistent(Inconsistency),
+    /// A row of an input that failed, with where it was.
+    #[error("{0}")]
+    Record(Box<RecordError>),
+    /// The state broke an invariant, see [`Database::verify`].
+    #[error("{0}")]
+    Invariant(Box<invariants::Violation>),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<String> for PaymentsEngineError {
+    fn from(s: String) -> Self {
+        PaymentsEngineError::Other(s)
+    }
+}
+impl From<&str> for PaymentsEngineError {
+    fn from(s: &str) -> Self {
+        PaymentsEngineError::Other(s.to_string())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+/// A row that couldn't be processed. Runs skip such rows and go on, see
+/// [`RunOptions::errors`].
+pub struct RecordError {
+    /// The input the row is in, `-` for standard input, if known.
+    pub input: Option<String>,
+    /// The row's number, counting from 1 after the header.
+    pub row: u64,
+    pub error: PaymentsEngineError,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(input) = &self.input {
+            write!(f, "{}, ", input)?;
+        }
+        write!(f, "row {}: {}", self.row, self.error)
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    transaction_type: TransactionType,
+    client_id: ClientId,
+    txn_id: u32,
+    amount: Option<f64>,
+    /// The client a transfer credits, `client_id` being the one it debits.
+    to_client: Option<ClientId>,
+    /// When the transaction happened. Rows without one are stamped
+    /// with the engine's clock when they are processed.
+    timestamp: Option<Timestamp>,
+    /// A free text description of the transaction, already sanitized.
+    memo: Option<Box<str>>,
+    /// What the money was spent on, from the `category` (or `merchant`)
+    /// column, lowercased.
+    category: Option<Box<str>>,
+    /// The currency of the amount, from the `currency` column. Without one
+    /// the transaction is in the account's own currency.
+    currency: Option<Box<str>>,
+    /// The key the submitter gave the transaction for its retries to be
+    /// recognized, from the `idempotency_key` column, see
+    /// [`dedup::DedupWindow`]. Dropped once the transaction is handled.
+    idempotency_key: Option<Box<str>>,
+    /// Extra input columns carried along with the transaction, in the
+    /// order they were asked for with `--carry-column`.
+    extras: Box<[String]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// This is the type of transaction that is representative of a
+/// single row within the CSV file.
+pub enum TransactionType {
+    /// A deposit is a credit to the client's account.
+    /// Meaning this should increase the client's balance.
+    Deposit,
+    /// A withdrawal is a debit to the client's account.
+    /// Meaning this should decrease the client's balance.
+    Withdrawal,
+    /// When a client claims the transaction was erroneous,
+    /// and should be flagged to be reverted.
+    /// This will put on hold the client's funds by the amount
+    /// of the referenced transaction.
+    Dispute,
+    /// This happens when a client has a dispute and the dispute
+    /// is resolved (or fails), meaning the funds should be returned to the
+    /// client's account. And no transactions are reverted.
+    /// The transaction must refer to a disputed transaction,
+    /// and not the dispute transaction itself. If the transaction
+    /// is not current disputed, we abort the transaction, and ignore it.
+    Resolve,
+    /// This happens when a client has a dispute and the dispute
+    /// is successful
+    ///
+    /// The transaction must refer to a disputed transaction,
+    /// and not the dispute transaction itself. If the transaction
+    /// is not current disputed, we abort the transaction, and ignore it.
+    ChargeBack,
+    /// Moves funds from the client's available balance to another
+    /// client's, both or neither.
+    Transfer,
+    /// Lifts the lock of an account, such as one left by a chargeback.
+    /// Like the other admin operations, only applied with
+    /// [`Policy::allow_admin_ops`], and applied to locked accounts too.
+    Unlock,
+    /// Adds the amount to the client's available funds, correcting a
+    /// mistake. Adjustments are not stored, so they can't be disputed.
+    CreditAdjustment,
+    /// Takes the amount from the client's available funds, which must
+    /// cover it.
+    DebitAdjustment,
+    /// Closes the account for good: it stays locked, and can't be
+    /// unlocked.
+    Close,
+}
+
+/// Opens a csv and returns a reader, decompressing the file as it is read
+/// if it is compressed with gzip or zstd.
+pub fn open_file_read_csv(filename: String) -> Result<csv::Reader<compressed::Input<File>>> {
+    Ok(schema::csv_reader(compressed::open_input(&filename)?))
+}
+impl From<csv::Error> for PaymentsEngineError {
+    fn from(err: csv::Error) -> Self {
+        if !err.is_io_error() {
+            return PaymentsEngineError::ParseError(err.to_string());
+        }
+        match err.into_kind() {
+            csv::ErrorKind::Io(x) => PaymentsEngineError::IoError(x),
+            kind => PaymentsEngineError::ParseError(format!("{:?}", kind)),
+        }
+    }
+}
+
+impl From<ParseIntError> for PaymentsEngineError {
+    fn from(err: ParseIntError) -> Self {
+        PaymentsEngineError::ParseError(err.to_string())
+    }
+}
+
+impl From<ParseFloatError> for PaymentsEngineError {
+    fn from(err: ParseFloatError) -> Self {
+        PaymentsEngineError::ParseError(err.to_string())
+    }
+}
+
+impl TransactionType {
+    /// Every transaction type.
+    pub const ALL: [TransactionType; 10] = [
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::ChargeBack,
+        TransactionType::Transfer,
+        TransactionType::Unlock,
+        TransactionType::CreditAdjustment,
+        TransactionType::DebitAdjustment,
+        TransactionType::Close,
+    ];
+
+    /// The position of the transaction type in [`TransactionType::ALL`].
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// The name of the transaction type as it appears in the csv.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::ChargeBack => "chargeback",
+            TransactionType::Transfer => "transfer",
+            TransactionType::Unlock => "unlock",
+            TransactionType::CreditAdjustment => "credit_adjustment",
+            TransactionType::DebitAdjustment => "debit_adjustment",
+            TransactionType::Close => "close",
+        }
+    }
+
+    /// Whether this is an operation of the back office rather than of the
+    /// client, see [`Policy::allow_admin_ops`].
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Unlock
+                | TransactionType::CreditAdjustment
+                | TransactionType::DebitAdjustment
+                | TransactionType::Close
+        )
+    }
+}
+
+#[derive(Default, Debug)]
+/// This is the main data structure that we will use to store
+/// all of the transactions.
+///
+/// Both maps are sharded concurrent maps, so transactions of independent
+/// clients can be applied from several threads at once without a global
+/// lock. A client's entry stays locked while a transaction is applied to
+/// it, which keeps every client's updates atomic.
+pub struct Database {
+    pub transactions: DashMap<u32, Transaction>,
+    pub clients: DashMap<ClientId, Client>,
+    /// The ids of the transfers applied. Transfers aren't stored, but
+    /// their ids can't be used again.
+    pub transfers: DashSet<u32>,
+    /// Digest of the state of every client, see [`digest::state_digest`].
+    /// Kept up to date as each transaction is applied.
+    pub digest: AtomicU64,
+    /// The latest transaction timestamp seen, which is "now" when deciding
+    /// which clients are inactive.
+    pub latest_activity: AtomicU64,
+    /// Where inactive clients are evicted to, if anywhere. Evicted clients
+    /// are still part of the state and of its digest.
+    pub cold: Option<Tiering>,
+    /// What has been handled so far, see [`Engine::metrics`].
+    pub counters: Counters,
+    /// Set in append mode, the transactions of earlier runs.
+    pub previous: Option<PreviousRuns>,
+    /// The rules transactions are handled by.
+    pub policy: Policy,
+    /// Events of applied transactions waiting to be published.
+    pub outbox: outbox::Outbox,
+    /// Keeps what changes while a snapshot is taken in the background.
+    pub snapshots: snapshot::Snapshots,
+    /// The books of what was applied, see [`ledger::Ledger`].
+    pub ledger: ledger::Ledger,
+    /// Where stored transactions are spilled to, if anywhere. Spilled
+    /// transactions are still part of the state.
+    pub spill: Option<spill::Spill>,
+    /// Set once any account holds funds in a currency other than its own,
+    /// which gives reports a currency column.
+    pub multi_currency: AtomicBool,
+}
+
+impl Database {
+    /// The digest of the current state of every client.
+    pub fn digest(&self) -> u64 {
+        self.digest.load(Ordering::SeqCst)
+    }
+
+    /// Stores a deposit or withdrawal for later disputes.
+    pub fn store_transaction(&self, txn: Transaction) {
+        if self.snapshots.is_active() {
+            let replaced = self.transactions.get(&txn.txn_id);
+            self.snapshots.transaction(txn.txn_id, replaced.as_deref());
+        }
+        self.transactions.insert(txn.txn_id, txn);
+    }
+
+    /// Calls `f` on the stored transaction `txn_id`, in memory or spilled.
+    pub fn with_transaction<T>(
+        &self,
+        txn_id: u32,
+        f: impl FnOnce(&Transaction) -> T,
+    ) -> Result<Option<T>> {
+        if let Some(txn) = self.transactions.get(&txn_id) {
+            return Ok(Some(f(&txn)));
+        }
+        match &self.spill {
+            Some(spill) => Ok(spill.store.get(txn_id)?.as_ref().map(f)),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the deposit `txn_id` is queued until the locked account of
+    /// `client_id` is unlocked, see [`LockedAccounts::QueueDeposits`].
+    pub fn queued(&self, client_id: ClientId, txn_id: u32) -> bool {
+        self.policy.locked_accounts == LockedAccounts::QueueDeposits
+            && self
+                .clients
+                .get(&client_id)
+                .is_some_and(|client| client.queued.contains(&txn_id))
+    }
+
+    /// What was disputed of the transaction `txn_id` of `client_id`, if
+    /// only a part of it was.
+    pub fn disputed_amount(&self, client_id: ClientId, txn_id: u32) -> Option<f64> {
+        self.clients
+            .get(&client_id)
+            .and_then(|client| client.disputed_amounts.get(&txn_id).copied())
+    }
+
+    /// Whether a transaction `txn_id` is stored, in memory or spilled, or
+    /// was a transfer.
+    pub fn has_transaction(&self, txn_id: u32) -> Result<bool> {
+        match &self.spill {
+            _ if self.transactions.contains_key(&txn_id) => Ok(true),
+            _ if self.transfers.contains(&txn_id) => Ok(true),
+            Some(spill) => spill.store.contains(txn_id),
+            None => Ok(false),
+        }
+    }
+
+    /// Visits every stored transaction, both those in memory and those
+    /// spilled.
+    pub fn for_each_transaction(&self, mut f: impl FnMut(&Transaction)) -> Result<()> {
+        self.transactions.iter().for_each(|entry| f(entry.value()));
+        match &self.spill {
+            Some(spill) => spill.store.for_each(&mut |txn| {
+                // One stored again since it was spilled is newer in memory.
+                if !self.transactions.contains_key(&txn.txn_id) {
+                    f(txn);
+                }
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Spills the least recently stored transactions beyond those the
+    /// spill keeps in memory, returning how many were spilled.
+    ///
+    /// A transaction is written to the store before it leaves memory, so
+    /// it is never seen as missing from both.
+    pub fn spill_oldest(&self) -> Result<usize> {
+        let Some(spill) = &self.spill else {
+            return Ok(0);
+        };
+        let excess = self.transactions.len().saturating_sub(spill.max_resident);
+        if excess == 0 {
+            return Ok(0);
+        }
+        let mut stored: Vec<_> = self
+            .transactions
+            .iter()
+            .map(|entry| (entry.timestamp.unwrap_or_default(), *entry.key()))
+            .collect();
+        let excess = excess.min(stored.len());
+        stored.select_nth_unstable(excess - 1);
+        for (_, txn_id) in &stored[..excess] {
+            let Some(txn) = self.transactions.get(txn_id).map(|txn| txn.clone()) else {
+                continue;
+            };
+            spill.store.store(&txn)?;
+            self.transactions
+                .remove_if(txn_id, |_, current| *current == txn);
+        }
+        Ok(excess)
+    }
+
+    /// Visits every client, both those in memory and those evicted.
+    pub fn for_each_client(&self, mut f: impl FnMut(ClientId, &Client)) -> Result<()> {
+        self.clients
+            .iter()
+            .for_each(|entry| f(*entry.key(), entry.value()));
+        match &self.cold {
+            Some(cold) => cold.store.for_each(&mut f),
+            None => Ok(()),
+        }
+    }
+
+    /// Notes that `client` holds funds in other currencies, if it does, see
+    /// [`Database::multi_currency`].
+    pub fn note_currencies(&self, client: &Client) {
+        if !client.currencies.is_empty() {
+            self.multi_currency.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether `client` has been inactive for longer than
+    /// [`Policy::dormant_after`].
+    pub fn is_dormant(&self, client: &Client) -> bool {
+        let now = self.latest_activity.load(Ordering::SeqCst);
+        self.policy
+            .dormant_after
+            .is_some_and(|after| client.last_activity < now.saturating_sub(after.as_secs()))
+    }
+
+    /// The oldest activity a client may have and stay in memory: that of
+    /// the tiering, or sooner for dormant clients.
+    fn hot_cutoff(&self, cold: &Tiering) -> Timestamp {
+        let now = self.latest_activity.load(Ordering::SeqCst);
+        let dormant = self
+            .policy
+            .dormant_after
+            .map_or(0, |after| now.saturating_sub(after.as_secs()));
+        cold.cutoff(now).max(dormant)
+    }
+
+    /// Evicts the clients that have been inactive for longer than the
+    /// tiering allows, and dormant ones, returning how many were evicted.
+    ///
+    /// Each shard stays locked while it is swept, so a client is never
+    /// seen as missing from both memory and the cold store.
+    pub fn evict_inactive(&self) -> Result<usize> {
+        match &self.cold {
+            Some(cold) => self.evict_before(cold, self.hot_cutoff(cold)),
+            None => Ok(0),
+        }
+    }
+
+    /// Evicts every client still in memory once a run is over, so the
+    /// next run over the same cold store starts from all of them,
+    /// returning how many were evicted.
+    pub fn evict_all(&self) -> Result<usize> {
+        match &self.cold {
+            Some(cold) => self.evict_before(cold, Timestamp::MAX),
+            None => Ok(0),
+        }
+    }
+
+    /// Evicts the clients last active before `cutoff`.
+    fn evict_before(&self, cold: &Tiering, cutoff: Timestamp) -> Result<usize> {
+        let mut evicted = 0;
+        let mut error = None;
+        self.clients.retain(|client_id, client| {
+            if error.is_some() || client.last_activity >= cutoff {
+                return true;
+            }
+            match cold.store.store(*client_id, client) {
+                Ok(()) => {
+                    evicted += 1;
+                    false
+                }
+                Err(err) => {
+                    error = Some(err);
+                    true
+                }
+            }
+        });
+        match error {
+            Some(err) => Err(err),
+            None => Ok(evicted),
+        }
+    }
+
+    /// Loads clients from the cold store into memory ahead of their
+    /// transactions, returning how many were loaded.
+    pub fn preload(&self, preload: Preload) -> Result<usize> {
+        let Some(cold) = &self.cold else {
+            return Ok(0);
+        };
+        let cutoff = match preload {
+            Preload::None => return Ok(0),
+            Preload::Hot => self.hot_cutoff(cold),
+            Preload::All => 0,
+        };
+        let mut hot = Vec::new();
+        cold.store.for_each(&mut |client_id, client| {
+            if client.last_activity >= cutoff {
+                hot.push(client_id);
+            }
+        })?;
+        for client_id in &hot {
+            if let Some(client) = cold.store.load(*client_id)? {
+                self.clients.insert(*client_id, client);
+            }
+        }
+        Ok(hot.len())
+    }
+}
+
+/// Whether two maps hold the same keys with equal values.
+pub fn same_entries<K, V>(a: &DashMap<K, V>, b: &DashMap<K, V>) -> bool
+where
+    K: Eq + std::hash::Hash,
+    V: PartialEq,
+{
+    a.len() == b.len()
+        && a.iter()
+            .all(|entry| b.get(entry.key()).is_some_and(|other| *other == *entry))
+}
+
+impl PartialEq for Database {
+    fn eq(&self, other: &Self) -> bool {
+        same_entries(&self.clients, &other.clients)
+            && same_entries(&self.transactions, &other.transactions)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// This struct represents the state of a single client's account.
+pub struct Client {
+    /// The client's available balance
+    available: f64,
+    /// The client's held balance if there was a dispute
+    held: f64,
+    /// Is the client's account is locked from a charge back
+    locked: bool,
+    /// What locked the account, if it is locked.
+    lock: Option<Lock>,
+    /// Closed after review. A closed account stays locked for good.
+    closed: bool,
+    /// Disputed transactions, with when each dispute was opened.
+    disputed: HashMap<u32, Timestamp>,
+    /// What was disputed of transactions disputed for less than their
+    /// amount. Kept once the dispute is settled, as what it moved.
+    disputed_amounts: HashMap<u32, f64>,
+    /// Transactions whose dispute was resolved or charged back, which
+    /// can't be disputed again.
+    settled: HashMap<u32, TxnState>,
+    /// What disputes still have to hold, oldest first, see
+    /// [`Policy::pending_holds`].
+    pending_holds: Vec<(u32, f64)>,
+    /// Deposits received while the account was locked, to be credited
+    /// once it is unlocked, see [`policy::LockedAccounts::QueueDeposits`].
+    queued: Vec<u32>,
+    /// The funds in currencies other than the account's own, by currency.
+    /// `available` and `held` are those in the account's own currency.
+    currencies: BTreeMap<Box<str>, Balance>,
+    /// Timestamp of the client's latest transaction. This is bookkeeping
+    /// for tiering, not account state, so it isn't compared or digested.
+    last_activity: Timestamp,
+}
+
+impl PartialEq for Client {
+    fn eq(&self, other: &Self) -> bool {
+        self.available == other.available
+            && self.held == other.held
+            && self.locked == other.locked
+            && self.closed == other.closed
+            && self.disputed == other.disputed
+            && self.disputed_amounts == other.disputed_amounts
+            && self.settled == other.settled
+            && self.pending_holds == other.pending_holds
+            && self.queued == other.queued
+            && self.currencies == other.currencies
+    }
+}
+
+impl Client {
+    /// Where the transaction `txn_id` of the client is in its dispute
+    /// lifecycle.
+    fn txn_state(&self, txn_id: u32) -> TxnState {
+        match self.disputed.contains_key(&txn_id) {
+            true => TxnState::Disputed,
+            false => self.settled.get(&txn_id).copied().unwrap_or_default(),
+        }
+    }
+
+    /// What a dispute of the transaction `txn_id`, of `amount`, holds: all
+    /// of it unless only a part was disputed.
+    fn disputed_amount(&self, txn_id: u32, amount: f64) -> f64 {
+        self.disputed_amounts
+            .get(&txn_id)
+            .copied()
+            .unwrap_or(amount)
+    }
+
+    /// Moves the transaction `txn_id` to `state`, as of `at`, after
+    /// [`TxnState::next`] allowed it.
+    fn set_txn_state(&mut self, txn_id: u32, state: TxnState, at: Timestamp) {
+        self.disputed.remove(&txn_id);
+        self.settled.remove(&txn_id);
+        match state {
+            TxnState::Posted => {}
+            TxnState::Disputed => {
+                self.disputed.insert(txn_id, at);
+            }
+            TxnState::Resolved | TxnState::ChargedBack => {
+                self.settled.insert(txn_id, state);
+            }
+        }
+    }
+
+    /// Holds what the available funds allow of the pending holds, oldest
+    /// first.
+    fn take_pending_holds(&mut self) {
+        for (_, pending) in &mut self.pending_holds {
+            let hold = pending.min(self.available.max(0.0));
+            self.available -= hold;
+            self.held += hold;
+            *pending -= hold;
+        }
+        self.pending_holds.retain(|(_, pending)| *pending > 0.0);
+    }
+
+    /// Swaps the funds in the account's own currency with those in
+    /// `currency`, so a transaction in it applies to `available` and `held`
+    /// like any other. Swapping again swaps them back. A currency nothing
+    /// is left in is dropped.
+    fn swap_currency(&mut self, currency: &str) {
+        let balance = self.currencies.entry(currency.into()).or_default();
+        std::mem::swap(&mut self.available, &mut balance.available);
+        std::mem::swap(&mut self.held, &mut balance.held);
+        if balance.is_zero() {
+            self.currencies.remove(currency);
+        }
+    }
+
+    /// Drops the pending hold of the dispute of `txn_id`, returning what
+    /// it still had to hold.
+    fn drop_pending_hold(&mut self, txn_id: u32) -> f64 {
+        match self.pending_holds.iter().position(|(id, _)| *id == txn_id) {
+            Some(i) => self.pending_holds.remove(i).1,
+            None => 0.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+/// The engine ties the database to the clock that timestamps
+/// incoming transactions.
+///
+/// An engine is `Send + Sync`: every part of it, including the clock,
+/// may be moved to or shared with other threads. Processing only takes
+/// `&self`, so threads may apply transactions of different clients at
+/// the same time. Each client's transactions must still be submitted in
+/// order, from one thread at a time, since their order matters.
+pub struct Engine {
+    pub db: Database,
+    clock: Box<dyn Clock>,
+    /// Where accepted transactions are recorded, if anywhere.
+    capture: Option<Mutex<Capture>>,
+    /// Where the notices owed to clients are recorded, if anywhere.
+    notifications: Option<Mutex<Notifications>>,
+    /// Where what became of every transaction is recorded, if anywhere.
+    journal: Option<Mutex<Journal>>,
+    /// The transactions applied to each client, if kept.
+    history: Option<History>,
+    /// Where transactions are logged before they are applied, if anywhere.
+    wal: Option<Mutex<Wal>>,
+    /// Called as transactions are rejected and accounts locked.
+    hooks: hooks::Hooks,
+    /// Taken exclusively while a batch is applied, and shared by every
+    /// other transaction, see [`Engine::apply_batch`].
+    batch_lock: RwLock<()>,
+    /// Inspect every transaction before it is handled, in order.
+    plugins: Vec<Box<dyn Plugin>>,
+    /// Limits what each tenant's clients may hold and send, if set.
+    quotas: Option<Quotas>,
+    /// Hands out the ids of the transactions the engine generates.
+    ids: Box<dyn IdAllocator>,
+    /// Name the run in its metrics and manifest.
+    labels: labels::Labels,
+    /// Suppresses retried submissions, if set.
+    dedup: Option<DedupWindow>,
+    /// Checks the accounts each transaction touched after applying it.
+    check_invariants: bool,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::with_clock(SystemClock)
+    }
+}
+
+impl Engine {
+    /// Creates an empty engine that reads time from `clock`.
+    pub fn with_clock(clock: impl Clock + 'static) -> Self {
+        Engine {
+            db: Database::default(),
+            clock: Box::new(clock),
+            capture: None,
+            notifications: None,
+            journal: None,
+            history: None,
+            wal: None,
+            hooks: hooks::Hooks::default(),
+            batch_lock: RwLock::default(),
+            plugins: Vec::new(),
+            quotas: None,
+            ids: Box::new(ReservedRange::default()),
+            labels: labels::Labels::default(),
+            dedup: None,
+            check_invariants: false,
+        }
+    }
+
+    /// Checks the invariants of the accounts each transaction touches after
+    /// it is applied, see [`Database::verify_client`]. The first
+    /// transaction breaking one fails with the [`invariants::Violation`].
+    pub fn with_invariant_checks(mut self) -> Self {
+        self.check_invariants = true;
+        self
+    }
+
+    /// Records every transaction the engine processes to `capture`.
+    pub fn with_capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(Mutex::new(capture));
+        self
+    }
+
+    /// Records the notices owed to clients for the disputes the engine
+    /// applies to `notifications`.
+    pub fn with_notifications(mut self, notifications: Notifications) -> Self {
+        self.notifications = Some(Mutex::new(notifications));
+        self
+    }
+
+    /// Allocates the ids of generated transactions with `ids` rather than
+    /// from [`ids::INTERNAL_IDS_START`] up.
+    pub fn with_ids(mut self, ids: impl IdAllocator + 'static) -> Self {
+        self.ids = Box::new(ids);
+        self
+    }
+
+    /// Records what becomes of every transaction the engine is handed,
+    /// applied or rejected, to `journal`.
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(Mutex::new(journal));
+        self
+    }
+
+    /// Appends every transaction the engine processes to the write-ahead
+    /// log `wal` before applying it, see [`wal::recover`]. Batches of
+    /// [`Engine::apply_batch`] aren't logged.
+    pub fn with_wal(mut self, wal: Wal) -> Self {
+        self.wal = Some(Mutex::new(wal));
+        self
+    }
+
+    /// Empties the write-ahead log, if there is one, once the state is
+    /// saved or the run is over, see [`Wal::commit`].
+    pub fn commit_wal(&self) -> Result<()> {
+        match &self.wal {
+            Some(wal) => wal
+                .lock()
+                .map_err(|_| "write-ahead log lock poisoned")?
+                .commit(),
+            None => Ok(()),
+        }
+    }
+
+    /// Keeps the transactions applied to each client in order, with the
+    /// balances they left, see [`Engine::history`].
+    pub fn with_history(mut self) -> Self {
+        self.history = Some(History::default());
+        self
+    }
+
+    /// The transactions applied to `client_id` within `period`, oldest
+    /// first, if the engine keeps them, see [`Engine::with_history`].
+    pub fn history(&self, client_id: ClientId, period: spend::Period) -> Vec<HistoryEntry> {
+        self.history
+            .as_ref()
+            .map(|history| history.of(client_id, period))
+            .unwrap_or_default()
+    }
+
+    /// Handles transactions by `policy` rather than the default one.
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.db.policy = policy;
+        self
+    }
+
+    /// Holds each tenant to its quota. Accounts and transactions the
+    /// engine already holds count against it, so set this after loading
+    /// earlier runs.
+    pub fn with_quotas(mut self, quotas: Quotas) -> Result<Self> {
+        quotas.count_existing(&self.db)?;
+        self.quotas = Some(quotas);
+        Ok(self)
+    }
+
+    /// Keeps only active clients in memory, evicting the others to the
+    /// tiering's cold store.
+    ///
+    /// Clients already in the store, from an earlier run, are part of the
+    /// engine's state from the start.
+    pub fn with_tiering(mut self, tiering: Tiering) -> Result<Self> {
+        let (mut latest, mut error) = (0, None);
+        tiering.store.for_each(&mut |_, client| {
+            latest = latest.max(client.last_activity);
+            self.db.note_currencies(client);
+            if error.is_none() {
+                error = self.db.ledger.open(client).err();
+            }
+        })?;
+        if let Some(err) = error {
+            return Err(err);
+        }
+        self.db.cold = Some(tiering);
+        self.db.latest_activity.fetch_max(latest, Ordering::SeqCst);
+        let digest = digest::state_digest(&self.db)?;
+        self.db.digest.store(digest, Ordering::SeqCst);
+        Ok(self)
+    }
+
+    /// Suppresses transactions repeating one submitted within the window of
+    /// `dedup`, answering them as duplicates.
+    pub fn with_dedup_window(mut self, dedup: DedupWindow) -> Self {
+        self.dedup = Some(dedup);
+        self
+    }
+
+    /// Tags the metrics of the engine with `labels`.
+    pub fn with_labels(mut self, labels: labels::Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// The labels of the run, see [`labels::Labels`].
+    pub fn labels(&self) -> &labels::Labels {
+        &self.labels
+    }
+
+    /// Keeps at most the spill's `max_resident` stored transactions in
+    /// memory, spilling the others to its store.
+    pub fn with_spill(mut self, spill: spill::Spill) -> Self {
+        self.db.spill = Some(spill);
+        self
+    }
+
+    /// Flushes the capture, if there is one, to disk.
+    pub fn flush_capture(&self) -> Result<()> {
+        match &self.capture {
+            Some(capture) => capture.lock().map_err(|_| "capture lock poisoned")?.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes the notifications, if there are any, to disk.
+    pub fn flush_notifications(&self) -> Result<()> {
+        match &self.notifications {
+            Some(notifications) => notifications
+                .lock()
+                .map_err(|_| "notifications lock poisoned")?
+                .flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the journal recorded so far to its file.
+    pub fn flush_journal(&self) -> Result<()> {
+        match &self.journal {
+            Some(journal) => journal.lock().map_err(|_| "journal lock poisoned")?.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts of what the engine has handled, and the size of its state.
+    pub fn metrics(&self) -> Result<Metrics> {
+        let mut metrics = metrics::collect(&self.db)?;
+        if let Some(quotas) = &self.quotas {
+            metrics.tenants = quotas.usage()?;
+        }
+        metrics.suppressed_duplicates = self.dedup.as_ref().map(DedupWindow::suppressed);
+        metrics.labels = self.labels.clone();
+        Ok(metrics)
+    }
+
+    /// Processes the transactions of a csv reader one at a time, as the
+    /// returned iterator is advanced, yielding the outcome of each.
+    ///
+    /// Nothing is read ahead, so the caller can watch progress, or stop
+    /// early by simply not asking for more.
+    pub fn process_reader<'a, R: std::io::Read + 'a>(
+        &'a self,
+        mut reader: csv::Reader<R>,
+    ) -> Result<impl Iterator<Item = Result<TransactionOutcome>> + 'a> {
+        let schema = Schema::from_headers(reader.headers()?, &[])?;
+        Ok(reader
+            .into_records()
+            .map(move |record| self.process(schema.parse(&record?)?)))
+    }
+
+    /// Processes a single transaction, stamping it with the engine's
+    /// clock if it doesn't carry its own timestamp. Deposits, withdrawals
+    /// and transfers with an id kept for generated transactions are
+    /// rejected.
+    pub fn process(&self, txn: Transaction) -> Result<TransactionOutcome> {
+        self.journaled(txn, false)
+    }
+
+    /// A new id for a transaction the engine generates itself.
+    pub fn next_internal_id(&self) -> Result<u32> {
+        self.ids.allocate(&self.db)
+    }
+
+    /// Processes a transaction the engine generated itself, with an id
+    /// from [`Engine::next_internal_id`], like [`Engine::process`] does
+    /// any other.
+    pub fn process_internal(&self, txn: Transaction) -> Result<TransactionOutcome> {
+        self.journaled(txn, true)
+    }
+
+    /// Applies `txn` and records what became of it to the journal and the
+    /// history.
+    fn journaled(&self, mut txn: Transaction, internal: bool) -> Result<TransactionOutcome> {
+        txn.timestamp.get_or_insert_with(|| self.clock.now());
+        // Applied while holding the log, so it is replayed in the order
+        // transactions were really applied in.
+        let _logged = match &self.wal {
+            Some(wal) => {
+                let mut wal = wal.lock().map_err(|_| "write-ahead log lock poisoned")?;
+                wal.append(&txn, internal)?;
+                Some(wal)
+            }
+            None => None,
+        };
+        if self.journal.is_none() && self.history.is_none() && self.hooks.is_empty() {
+            return Ok(self.apply(txn, internal)?.0);
+        }
+        let (copy, client_id) = (txn.clone(), txn.client_id);
+        let locked_accounts = self.db.policy.locked_accounts;
+        let was_locked = match self.hooks.on_lock.is_empty()
+            && (self.journal.is_none() || locked_accounts == LockedAccounts::RejectAll)
+        {
+            true => false,
+            false => self
+                .account(client_id)?
+                .is_some_and(|account| account.locked()),
+        };
+        let (outcome, why) = self.apply(txn, internal)?;
+        if let TransactionOutcome::Rejected(reason) = outcome {
+            self.hooks.rejected(&copy, reason);
+        }
+        if !self.hooks.on_lock.is_empty() && !was_locked {
+            if let Some(account) = self
+                .account(client_id)?
+                .filter(account::AccountView::locked)
+            {
+                self.hooks.locked(&account, &copy);
+            }
+        }
+        if let Some(history) = &self.history {
+            if outcome == TransactionOutcome::Applied {
+                self.record_history(history, &copy)?;
+            }
+        }
+        let Some(journal) = &self.journal else {
+            return Ok(outcome);
+        };
+        let let_through =
+            (was_locked && !copy.transaction_type.is_admin()).then_some(locked_accounts);
+        let event =
+            EngineEvent::new(copy, outcome, self.account(client_id)?, let_through)?.with_why(why);
+        journal
+            .lock()
+            .map_err(|_| "journal lock poisoned")?
+            .record(&event)?;
+        Ok(outcome)
+    }
+
+    /// Records the applied `txn` to the history of its client, and of the
+    /// client it transferred to.
+    fn record_history(&self, history: &History, txn: &Transaction) -> Result<()> {
+        let amount = match txn.amount {
+            Some(amount) => Some(amount),
+            None => self
+                .db
+                .with_transaction(txn.txn_id, |referenced| referenced.amount)?
+                .flatten(),
+        };
+        for client_id in std::iter::once(txn.client_id).chain(txn.to_client) {
+            if let Some(account) = self.account(client_id)? {
+                history.record(client_id, HistoryEntry::new(txn, amount, &account));
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies `txn`, returning what became of it and, if a plugin denied
+    /// it, why.
+    fn apply(
+        &self,
+        mut txn: Transaction,
+        internal: bool,
+    ) -> Result<(TransactionOutcome, Option<String>)> {
+        span!("process");
+        if let Some(dedup) = self.dedup.as_ref().filter(|_| !internal) {
+            if dedup.repeats(&txn)? {
+                return Ok((TransactionOutcome::Duplicate, None));
+            }
+        }
+        txn.idempotency_key = None;
+        let new_id = matches!(
+            txn.transaction_type,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer
+        );
+        if !internal && new_id && self.ids.is_internal(txn.txn_id) {
+            return Ok((reject(&self.db, Reason::ReservedTxnId, &txn), None));
+        }
+        // A transfer changes two clients, nothing else may run meanwhile.
+        let transfer = txn.transaction_type == TransactionType::Transfer;
+        let _shared = (!transfer)
+            .then(|| self.batch_lock.read())
+            .transpose()
+            .map_err(|_| "batch lock poisoned")?;
+        let _exclusive = transfer
+            .then(|| self.batch_lock.write())
+            .transpose()
+            .map_err(|_| "batch lock poisoned")?;
+        let txn = match self.screen(&self.db, txn)? {
+            Screened::Pass(txn) => txn,
+            Screened::Denied(outcome, why) => return Ok((outcome, Some(why))),
+        };
+        if let Some(quotas) = &self.quotas {
+            let new_account = self.account(txn.client_id)?.is_none();
+            if let Some((tenant, exceeded)) = quotas.admit(&txn, new_account)? {
+                let args: [(&str, &dyn std::fmt::Display); 2] =
+                    [("quota", &exceeded.as_str()), ("tenant", &tenant)];
+                return Ok((
+                    reject_with(&self.db, Reason::QuotaExceeded, &txn, &args),
+                    None,
+                ));
+            }
+        }
+        let admitted = (txn.transaction_type, txn.client_id);
+        let checked = self
+            .check_invariants
+            .then_some((txn.txn_id, txn.client_id, txn.to_client));
+        let posted = (txn.transaction_type, txn.client_id, txn.txn_id, txn.amount);
+        let event = self.db.outbox.is_recording().then(|| Event::of(&txn));
+        let notice = self.notifications.as_ref().map(|notifications| {
+            let payload = Payload {
+                tx: txn.txn_id,
+                amount: None,
+                timestamp: txn.timestamp.unwrap_or_default(),
+                balance: None,
+            };
+            let template = Template::for_type(txn.transaction_type);
+            (notifications, txn.client_id, template, payload)
+        });
+        let threshold = match notice {
+            Some((notifications, client_id, ..)) => notifications
+                .lock()
+                .map_err(|_| "notifications lock poisoned")?
+                .threshold(client_id),
+            None => None,
+        };
+        let watch = match threshold {
+            Some(threshold) => Some(Watch {
+                client: txn.client_id,
+                threshold,
+                transaction_type: txn.transaction_type,
+                before: self
+                    .account(txn.client_id)?
+                    .map_or(0.0, |account| account.available()),
+            }),
+            None => None,
+        };
+        let outcome = match &self.capture {
+            None => handle_transaction(&self.db, txn)?,
+            Some(capture) => {
+                // Applying while holding the capture lock keeps the captured
+                // order, and the digests recorded with it, identical to the
+                // order transactions were really applied in.
+                let mut capture = capture.lock().map_err(|_| "capture lock poisoned")?;
+                let row = Capture::row(&txn);
+                let outcome = handle_transaction(&self.db, txn)?;
+                span!("capture");
+                capture.record(row, self.db.digest())?;
+                outcome
+            }
+        };
+        if let Some(quotas) = self
+            .quotas
+            .as_ref()
+            .filter(|_| outcome != TransactionOutcome::Applied)
+        {
+            quotas.release(admitted.0, admitted.1)?;
+        }
+        if outcome == TransactionOutcome::Applied {
+            let (transaction_type, client_id, txn_id, amount) = posted;
+            // Queued deposits are posted once they are credited.
+            if transaction_type != TransactionType::Deposit || !self.db.queued(client_id, txn_id) {
+                self.db
+                    .ledger
+                    .post(&self.db, transaction_type, client_id, txn_id, amount)?;
+            }
+        }
+        if let (Some(event), TransactionOutcome::Applied) = (event, outcome) {
+            self.db.outbox.record(event)?;
+        }
+        if let (
+            Some((notifications, client_id, template, mut payload)),
+            TransactionOutcome::Applied,
+        ) = (notice, outcome)
+        {
+            payload.amount = self
+                .db
+                .with_transaction(payload.tx, |txn| txn.amount)?
+                .flatten();
+            let mut notifications = notifications
+                .lock()
+                .map_err(|_| "notifications lock poisoned")?;
+            if let Some(template) = template {
+                notifications.record(client_id, template, &payload)?;
+            }
+            if let Some(watch) = watch {
+                let after = self
+                    .account(client_id)?
+                    .map_or(0.0, |account| account.available());
+                notifications.check(watch, after, &payload)?;
+            }
+        }
+        if let Some((txn_id, client_id, to_client)) = checked {
+            for client_id in std::iter::once(client_id).chain(to_client) {
+                self.db.verify_client(client_id).map_err(|err| match err {
+                    PaymentsEngineError::Invariant(mut violation) => {
+                        violation.txn_id = Some(txn_id);
+                        PaymentsEngineError::Invariant(violation)
+                    }
+                    err => err,
+                })?;
+            }
+        }
+        Ok((outcome, None))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What became of a transaction the engine handled.
+pub enum TransactionOutcome {
+    Applied,
+    /// The transaction was refused and left the state untouched.
+    Rejected(Reason),
+    /// The transaction repeats one already handled and was ignored, with
+    /// [`Policy::idempotent`].
+    Duplicate,
+}
+
+/// Reports a transaction that was not applied, prefixed with the
+/// language neutral reason code, and counts it.
+pub fn reject(db: &Database, reason: Reason, txn: &Transaction) -> TransactionOutcome {
+    reject_with(db, reason, txn, &[])
+}
+
+/// Like [`reject`], with more arguments for the message.
+pub fn reject_with(
+    db: &Database,
+    reason: Reason,
+    txn: &Transaction,
+    args: &[(&str, &dyn std::fmt::Display)],
+) -> TransactionOutcome {
+    db.counters.rejected(reason);
+    let common: [(&str, &dyn std::fmt::Display); 3] = [
+        ("client", &txn.client_id),
+        ("txn", &txn.txn_id),
+        ("type", &txn.transaction_type.as_str()),
+    ];
+    let args: Vec<_> = common.iter().chain(args).copied().collect();
+    let text = message(Key::Rejected(reason), &args);
+    tracing::warn!(
+        client_id = txn.client_id,
+        txn_id = txn.txn_id,
+        reason = reason.code(),
+        "{}",
+        text
+    );
+    TransactionOutcome::Rejected(reason)
+}
+
+/// What [`handle_transaction`] needs of the transaction a dispute, resolve
+/// or chargeback refers to, copied out of the map.
+pub struct Referenced {
+    client_id: ClientId,
+    amount: Option<f64>,
+    transaction_type: TransactionType,
+    timestamp: Option<Timestamp>,
+    currency: Option<Box<str>>,
+}
+
+impl Referenced {
+    /// Whether `txn` is the same row as this transaction: same type,
+    /// client and amount. Timestamps differ when the engine stamped them.
+    fn repeated_by(&self, txn: &Transaction) -> bool {
+        (self.transaction_type, self.client_id, self.amount)
+            == (txn.transaction_type, txn.client_id, txn.amount)
+    }
+}
+
+/// Handles a single transaction and updates the database accordingly.
+///
+/// Disputing a deposit holds its amount back from the client's available
+/// funds until it is resolved, or charged back and gone. Disputing a
+/// withdrawal holds its amount for the client, without touching the funds
+/// available: a resolve drops it, a chargeback pays it back to them. A
+/// dispute with an amount, up to the transaction's, only holds that part,
+/// which its resolve or chargeback then settles.
+///
+/// Rejections are not errors, they come back as the outcome. Errors are
+/// reserved for failures of the engine itself, such as the cold store.
+pub fn handle_transaction(db: &Database, txn: Transaction) -> Result<TransactionOutcome> {
+    span!("handle_transaction");
+    db.counters.processed(&txn);
+    let transaction_type = txn.transaction_type;
+    let outcome = apply_transaction(db, txn)?;
+    if outcome == TransactionOutcome::Applied {
+        db.counters.applied(transaction_type);
+    }
+    Ok(outcome)
+}
+
+/// [`handle_transaction`] once the transaction was counted.
+fn apply_transaction(db: &Database, txn: Transaction) -> Result<TransactionOutcome> {
+    if txn.transaction_type == TransactionType::Transfer {
+        return handle_transfer(db, txn);
+    }
+    if txn.transaction_type.is_admin() {
+        return handle_admin(db, txn);
+    }
+    let (mut client, before) = client_entry(db, txn.client_id, txn.timestamp)?;
+    if client.locked && !let_through_locked(db, &client, &txn) {
+        return Ok(reject(db, Reason::AccountLocked, &txn));
+    }
+    // Only copy out what is needed from the referenced transaction, holding
+    // on to it would keep its shard locked while deposits insert below.
+    let referenced = db.with_transaction(txn.txn_id, |referenced| Referenced {
+        client_id: referenced.client_id,
+        amount: referenced.amount,
+        transaction_type: referenced.transaction_type,
+        timestamp: referenced.timestamp,
+        currency: referenced.currency.clone(),
+    })?;
+    // Disputes, resolves and chargebacks are in the currency of the
+    // transaction they refer to.
+    let currency = match (txn.transaction_type, &referenced) {
+        (TransactionType::Deposit | TransactionType::Withdrawal, _) => txn.currency.clone(),
+        (_, Some(referenced)) => referenced.currency.clone(),
+        (_, None) => None,
+    };
+    if let Some(currency) = &currency {
+        client.swap_currency(currency);
+    }
+    let rules = db
+        .policy
+        .regions
+        .rules_for(txn.client_id, txn.timestamp.unwrap_or_default());
+    let outcome = match (&txn.transaction_type, referenced, txn.amount) {
+        (TransactionType::Deposit | TransactionType::Withdrawal, Some(referenced), _)
+            if db.policy.idempotent && referenced.repeated_by(&txn) =>
+        {
+            TransactionOutcome::Duplicate
+        }
+        (TransactionType::Deposit | TransactionType::Withdrawal, Some(_), Some(_))
+            if db.previous.as_ref().is_some_and(|p| p.used(txn.txn_id)) =>
+        {
+            let outcome = reject(db, Reason::ConflictingTxnId, &txn);
+            if let Some(previous) = &db.previous {
+                previous.conflict(txn);
+            }
+            outcome
+        }
+        (TransactionType::Deposit | TransactionType::Withdrawal, Some(_), _) => {
+            reject(db, Reason::DuplicateTxnId, &txn)
+        }
+        (TransactionType::Deposit | TransactionType::Withdrawal, None, _)
+            if db.transfers.contains(&txn.txn_id) =>
+        {
+            reject(db, Reason::DuplicateTxnId, &txn)
+        }
+        (TransactionType::Deposit, _, Some(_)) if client.locked => {
+            client.queued.push(txn.txn_id);
+            db.store_transaction(txn);
+            TransactionOutcome::Applied
+        }
+        (TransactionType::Deposit, _, Some(amount)) => {
+            client.available += amount - db.policy.fee(txn.client_id, txn.transaction_type, amount);
+            // Pending holds are in the account's own currency.
+            if currency.is_none() {
+                client.take_pending_holds();
+            }
+            db.store_transaction(txn);
+            TransactionOutcome::Applied
+        }
+        (TransactionType::Withdrawal, _, Some(amount)) => {
+            let fee = db.policy.fee(txn.client_id, txn.transaction_type, amount);
+            if rules.max_withdrawal.is_some_and(|max| amount > max) {
+                reject(db, Reason::OverLimit, &txn)
+            } else if client.available - amount - fee < db.policy.withdrawal_floor() {
+                reject(db, Reason::InsufficientFunds, &txn)
+            } else {
+                client.available -= amount + fee;
+                // Only a withdrawal that was paid out is stored, one that
+                // wasn't has nothing to dispute or pay back.
+                db.store_transaction(txn);
+                TransactionOutcome::Applied
+            }
+        }
+        (
+            TransactionType::Dispute,
+            Some(Referenced {
+                client_id,
+                amount: Some(amount),
+                transaction_type: disputed,
+                timestamp: posted,
+                ..
+            }),
+            _,
+        ) => {
+            let age = txn
+                .timestamp
+                .unwrap_or_default()
+                .saturating_sub(posted.unwrap_or_default());
+            // A dispute with an amount only holds that part of the
+            // transaction, such as a partial chargeback from a card network.
+            let whole = amount;
+            let amount = txn.amount.unwrap_or(whole);
+            // What the client already spent of a disputed deposit is held
+            // once it comes in again, if the policy says so.
+            let pending = match disputed {
+                TransactionType::Deposit if db.policy.pending_holds && currency.is_none() => {
+                    (amount - client.available.max(0.0)).max(0.0)
+                }
+                _ => 0.0,
+            };
+            if client_id != txn.client_id {
+                reject(db, Reason::NotClientTransaction, &txn)
+            } else if !amount.is_finite() || amount <= 0.0 || amount > whole {
+                reject(db, Reason::InvalidDisputeAmount, &txn)
+            } else if db.policy.idempotent && client.disputed.contains_key(&txn.txn_id) {
+                TransactionOutcome::Duplicate
+            } else if let Err(reason) = client.txn_state(txn.txn_id).next(txn.transaction_type) {
+                reject(db, reason, &txn)
+            } else if !db.policy.disputable(disputed) {
+                reject(db, Reason::NotDisputable, &txn)
+            } else if rules
+                .dispute_window
+                .or(db.policy.dispute_window)
+                .is_some_and(|window| age > window.as_secs())
+            {
+                reject(db, Reason::DisputeWindowClosed, &txn)
+            } else if disputed == TransactionType::Deposit
+                && client.available - (amount - pending) < db.policy.hold_floor()
+            {
+                reject(db, Reason::OverdraftLimit, &txn)
+            } else {
+                client.held += amount - pending;
+                // A disputed deposit's funds are held back from the client,
+                // a disputed withdrawal's are held for the client in case
+                // they are paid back.
+                if disputed == TransactionType::Deposit {
+                    client.available -= amount - pending;
+                }
+                if pending > 0.0 {
+                    client.pending_holds.push((txn.txn_id, pending));
+                }
+                if amount < whole {
+                    client.disputed_amounts.insert(txn.txn_id, amount);
+                }
+                let at = txn.timestamp.unwrap_or_default();
+                client.set_txn_state(txn.txn_id, TxnState::Disputed, at);
+                TransactionOutcome::Applied
+            }
+        }
+        (
+            TransactionType::Resolve,
+            Some(Referenced {
+                client_id,
+                amount: Some(amount),
+                transaction_type: disputed,
+                ..
+            }),
+            _,
+        ) => {
+            if client_id != txn.client_id {
+                reject(db, Reason::NotClientTransaction, &txn)
+            } else if let Err(reason) = client.txn_state(txn.txn_id).next(txn.transaction_type) {
+                reject(db, reason, &txn)
+            } else {
+                client.set_txn_state(txn.txn_id, TxnState::Resolved, 0);
+                let amount = client.disputed_amount(txn.txn_id, amount);
+                let held = amount - client.drop_pending_hold(txn.txn_id);
+                if disputed == TransactionType::Deposit {
+                    tracing::debug!(client_id, txn_id = txn.txn_id, held, "released");
+                    client.available += held;
+                }
+                client.held -= held;
+                TransactionOutcome::Applied
+            }
+        }
+        (
+            TransactionType::ChargeBack,
+            Some(Referenced {
+                client_id,
+                amount: Some(amount),
+                transaction_type: disputed,
+                ..
+            }),
+            _,
+        ) => {
+            if client_id != txn.client_id {
+                reject(db, Reason::NotClientTransaction, &txn)
+            } else if let Err(reason) = client.txn_state(txn.txn_id).next(txn.transaction_type) {
+                reject(db, reason, &txn)
+            } else {
+                client.set_txn_state(txn.txn_id, TxnState::ChargedBack, 0);
+                let whole = !client.disputed_amounts.contains_key(&txn.txn_id);
+                let amount = client.disputed_amount(txn.txn_id, amount);
+                let pending = client.drop_pending_hold(txn.txn_id);
+                client.held -= amount - pending;
+                // A charged back withdrawal is paid back to the client, what
+                // a charged back deposit had yet to hold is taken from the
+                // client now.
+                match disputed {
+                    TransactionType::Withdrawal => client.available += amount,
+                    _ => client.available -= pending,
+                }
+                // The fee taken on the transaction goes back with it, unless
+                // only part of it is charged back.
+                if whole {
+                    client.available += db.policy.fee(client_id, disputed, amount);
+                }
+                // An account already locked keeps what locked it first.
+                if !client.locked {
+                    client.locked = true;
+                    client.lock = Some(Lock {
+                        reason: LockReason::Chargeback,
+                        txn_id: txn.txn_id,
+                        timestamp: txn.timestamp.unwrap_or_default(),
+                    });
+                }
+                TransactionOutcome::Applied
+            }
+        }
+        _ => reject(db, Reason::Unprocessable, &txn),
+    };
+    if let Some(currency) = &currency {
+        client.swap_currency(currency);
+        db.note_currencies(&client);
+    }
+    let after = client_digest(client.key().to_owned(), &client);
+    db.digest
+        .fetch_add(after.wrapping_sub(before), Ordering::SeqCst);
+    Ok(outcome)
+}
+
+/// Whether [`Policy::locked_accounts`] lets `txn` through to the locked
+/// account of `client`: a resolve or chargeback of a dispute it has open,
+/// or a deposit to queue.
+fn let_through_locked(db: &Database, client: &Client, txn: &Transaction) -> bool {
+    let locked = db.policy.locked_accounts;
+    match txn.transaction_type {
+        TransactionType::Resolve | TransactionType::ChargeBack => {
+            locked.settles_disputes() && client.disputed.contains_key(&txn.txn_id)
+        }
+        TransactionType::Deposit => locked == LockedAccounts::QueueDeposits && !client.closed,
+        _ => false,
+    }
+}
+
+/// Unlocks the account of `client`, crediting the deposits queued while it
+/// was locked, in the order they came in.
+pub(crate) fn unlock(db: &Database, client: &mut Client) -> Result<()> {
+    client.locked = false;
+    client.lock = None;
+    for txn_id in std::mem::take(&mut client.queued) {
+        let Some((client_id, amount, currency)) = db.with_transaction(txn_id, |txn| {
+            (txn.client_id, txn.amount, txn.currency.clone())
+        })?
+        else {
+            continue;
+        };
+        let amount = amount.unwrap_or_default();
+        if let Some(currency) = &currency {
+            client.swap_currency(currency);
+        }
+        client.available += amount - db.policy.fee(client_id, TransactionType::Deposit, amount);
+        db.ledger.post(
+            db,
+            TransactionType::Deposit,
+            client_id,
+            txn_id,
+            Some(amount),
+        )?;
+        match &currency {
+            Some(currency) => {
+                client.swap_currency(currency);
+                db.note_currencies(client);
+            }
+            // Pending holds are in the account's own currency.
+            None => client.take_pending_holds(),
+        }
+    }
+    Ok(())
+}
+
+/// The entry of a client, loaded back from the cold store or created if it
+/// isn't resident, with its digest before anything changes. The client's
+/// last activity is moved up to `timestamp`.
+///
+/// The entry keeps the client's shard locked until it is dropped.
+fn client_entry(
+    db: &Database,
+    client_id: ClientId,
+    timestamp: Option<Timestamp>,
+) -> Result<(RefMut<'_, ClientId, Client>, u64)> {
+    let (mut client, before) = match db.clients.entry(client_id) {
+        Entry::Occupied(entry) => {
+            db.snapshots.client(client_id, Some(entry.get()));
+            let before = client_digest(client_id, entry.get());
+            (entry.into_ref(), before)
+        }
+        Entry::Vacant(entry) => {
+            db.snapshots.client(client_id, None);
+            // The entry keeps the shard locked, so an eviction can't race
+            // with loading the client back.
+            let evicted = match &db.cold {
+                Some(cold) => cold.store.load(client_id)?,
+                None => None,
+            };
+            match evicted {
+                Some(evicted) => {
+                    let before = client_digest(client_id, &evicted);
+                    (entry.insert(evicted), before)
+                }
+                None => (entry.insert(Client::default()), 0),
+            }
+        }
+    };
+    if let Some(timestamp) = timestamp {
+        client.last_activity = client.last_activity.max(timestamp);
+        db.latest_activity.fetch_max(timestamp, Ordering::SeqCst);
+    }
+    Ok((client, before))
+}
+
+/// Moves the amount of a transfer from its client's available funds to
+/// its `to_client`'s. It is rejected, changing neither, if either account
+/// is locked or the source doesn't have the amount available.
+///
+/// The two clients are changed one after the other, so nothing else may
+/// be applied at the same time: [`Engine::process`] takes the batch lock
+/// exclusively for transfers. Transfers are not stored, so they can't be
+/// disputed, but their ids are kept so a repeated one is rejected.
+fn handle_transfer(db: &Database, txn: Transaction) -> Result<TransactionOutcome> {
+    let (Some(to_client), Some(amount)) = (txn.to_client, txn.amount) else {
+        return Ok(reject(db, Reason::Unprocessable, &txn));
+    };
+    if to_client == txn.client_id || !amount.is_finite() || amount <= 0.0 {
+        return Ok(reject(db, Reason::Unprocessable, &txn));
+    }
+    if db.has_transaction(txn.txn_id)? {
+        return Ok(reject(db, Reason::DuplicateTxnId, &txn));
+    }
+    // The source is only looked up, a transfer from a client without an
+    // account mustn't open one.
+    let source = match db.clients.get(&txn.client_id) {
+        Some(client) => Some((client.locked, client.available)),
+        None => match &db.cold {
+            Some(cold) => cold
+                .store
+                .get(txn.client_id)?
+                .map(|client| (client.locked, client.available)),
+            None => None,
+        },
+    };
+    match source {
+        Some((true, _)) => return Ok(reject(db, Reason::AccountLocked, &txn)),
+        Some((false, available)) if available - amount >= db.policy.withdrawal_floor() => {}
+        _ => return Ok(reject(db, Reason::InsufficientFunds, &txn)),
+    }
+    let credit = |client_id, amount: f64| -> Result<bool> {
+        let (mut client, before) = client_entry(db, client_id, txn.timestamp)?;
+        if client.locked {
+            return Ok(false);
+        }
+        client.available += amount;
+        let after = client_digest(client_id, &client);
+        db.digest
+            .fetch_add(after.wrapping_sub(before), Ordering::SeqCst);
+        Ok(true)
+    };
+    if !credit(to_client, amount)? {
+        let args: [(&str, &dyn std::fmt::Display); 1] = [("to", &to_client)];
+        return Ok(reject_with(db, Reason::RecipientLocked, &txn, &args));
+    }
+    credit(txn.client_id, -amount)?;
+    db.snapshots.transfer(txn.txn_id);
+    db.transfers.insert(txn.txn_id);
+    Ok(TransactionOutcome::Applied)
+}
+
+/// Applies an admin operation, if [`Policy::allow_admin_ops`] allows them.
+/// They apply to locked accounts, which they are meant to sort out, but
+/// not to closed ones.
+fn handle_admin(db: &Database, txn: Transaction) -> Result<TransactionOutcome> {
+    if !db.policy.allow_admin_ops {
+        return Ok(reject(db, Reason::AdminOpsDisabled, &txn));
+    }
+    let (mut client, before) = client_entry(db, txn.client_id, txn.timestamp)?;
+    let amount = txn
+        .amount
+        .filter(|amount| amount.is_finite() && *amount > 0.0);
+    let outcome = match (txn.transaction_type, amount) {
+        _ if client.closed => reject(db, Reason::AccountClosed, &txn),
+        (TransactionType::Unlock, None) if client.locked => {
+            unlock(db, &mut client)?;
+            TransactionOutcome::Applied
+        }
+        (TransactionType::CreditAdjustment, Some(amount)) => {
+            client.available += amount;
+            TransactionOutcome::Applied
+        }
+        (TransactionType::DebitAdjustment, Some(amount)) => {
+            if client.available - amount < db.policy.withdrawal_floor() {
+                reject(db, Reason::InsufficientFunds, &txn)
+            } else {
+                client.available -= amount;
+                TransactionOutcome::Applied
+            }
+        }
+        (TransactionType::Close, None) => {
+            client.closed = true;
+            client.locked = true;
+            TransactionOutcome::Applied
+        }
+        _ => reject(db, Reason::Unprocessable, &txn),
+    };
+    let after = client_digest(client.key().to_owned(), &client);
+    db.digest
+        .fetch_add(after.wrapping_sub(before), Ordering::SeqCst);
+    Ok(outcome)
+}
+
+/// Loads in the database with the given csv file.
+/// This is designed in such a way that a Reader is inputted
+/// and a possibly shared db can be used across multiple threads.
+/// This is written to easily allow synchronization oh parsing data
+/// and loading into a database.
+///
+/// Rows that can't be parsed are skipped and returned with their row
+/// numbers.
+pub fn run_engine(
+    mut reader: csv::Reader<impl std::io::Read>,
+    engine: &Engine,
+) -> Result<Vec<RecordError>> {
+    let schema = Schema::from_headers(reader.headers()?, &[])?;
+    let mut skipped = Vec::new();
+    for (i, record) in reader.into_records().enumerate() {
+        match record
+            .map_err(Into::into)
+            .and_then(|record| schema.parse(&record))
+        {
+            Ok(txn) => {
+                engine.process(txn)?;
+            }
+            Err(x @ PaymentsEngineError::IoError(_)) => return Err(x),
+            Err(error) => skipped.push(RecordError {
+                input: None,
+                row: i as u64 + 1,
+                error,
+            }),
+        }
+    }
+    Ok(skipped)
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+/// Controls how much of an input [`run_engine_with`] processes.
+pub struct RunOptions {
+    pub limits: RunLimits,
+    pub sample: Option<Sample>,
+    /// The input columns fields are read from, if not named like them.
+    pub column_map: schema::ColumnMap,
+    /// Extra columns to keep with each transaction.
+    pub carry_columns: Vec<String>,
+    /// Memos longer than this are truncated, the default applies if unset.
+    pub memo_max_len: Option<usize>,
+    /// How amounts are rounded as they are read, the default applies if
+    /// unset.
+    pub precision: Option<precision::Precision>,
+    /// How csv rows with another number of fields than their header are
+    /// read.
+    pub fields: schema::Fields,
+    /// Snapshots taken in the background while the input is processed.
+    pub snapshots: Option<SnapshotOptions>,
+    /// The rules rows of [`inputs::read_inputs`] are checked against.
+    pub consistency: Consistency,
+    /// Whether a row that can't be parsed or breaks a rule is skipped or
+    /// stops the run.
+    pub mode: Mode,
+    /// The format of the inputs of [`inputs::read_inputs`], by default
+    /// told by their extension.
+    pub format: Option<Format>,
+    /// Aborts the run if too many of its first rows are rejected.
+    pub max_reject_rate: Option<RejectRate>,
+    /// Worker threads applying the rows, see [`pipeline::run_sharded`].
+    /// Rows are applied on the calling thread when this is 0 or 1.
+    pub threads: usize,
+    /// Where to list the rows skipped as a [`RecordError`], stderr if
+    /// unset. See [`errors::ErrorLog`].
+    pub errors: Option<PathBuf>,
+    /// Where to record what became of each row, see
+    /// [`outcomes::OutcomeLog`]. Not recorded if unset.
+    pub outcomes: Option<PathBuf>,
+    /// Rows at the start of the input already handled before the snapshot
+    /// the engine was restored from, see [`Database::restore`]. They are
+    /// skipped but counted, so row numbers stay those of the input.
+    pub resume_after: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+/// How much of an input was processed.
+pub struct RunSummary {
+    /// Rows read from the input.
+    pub rows: u64,
+    /// Rows handed to the engine, fewer than `rows` when sampling.
+    pub processed: u64,
+    /// Processed rows the engine rejected.
+    pub rejected: u64,
+    /// Processed rows ignored as repeats, with [`Policy::idempotent`].
+    pub duplicates: u64,
+    /// Rows skipped as they couldn't be parsed or broke a consistency
+    /// rule. Counted in `rows`, not in `processed`.
+    pub errors: u64,
+    /// Set if a limit stopped the run before the end of the input.
+    pub stopped: Option<Stopped>,
+}
+
+/// Like [`run_engine`], but only processes the rows picked by the sample
+/// and stops cleanly between rows once any of the limits is exceeded.
+pub fn run_engine_with(
+    reader: csv::Reader<impl std::io::Read>,
+    engine: &Engine,
+    options: &RunOptions,
+) -> Result<RunSummary> {
+    run_transactions(inputs::parse_csv(reader, options)?, engine, options)
+}
+
+/// The part of [`run_engine_with`] after parsing, for inputs that aren't
+/// csv.
+pub fn run_transactions(
+    transactions: impl Iterator<Item = Result<Transaction>>,
+    engine: &Engine,
+    options: &RunOptions,
+) -> Result<RunSummary> {
+    let transactions = transactions.skip(options.resume_after as usize);
+    if options.threads > 1 {
+        return pipeline::run_sharded(transactions, engine, options, options.threads);
+    }
+    let tracker = LimitTracker::start(options.limits);
+    let mut summary = RunSummary {
+        rows: options.resume_after,
+        ..RunSummary::default()
+    };
+    let mut window_rejected = 0;
+    let mut skipped = ErrorLog::open(options.errors.as_deref())?;
+    let mut outcomes = OutcomeLog::open(options.outcomes.as_deref())?;
+    span!("run_engine");
+    std::thread::scope(|scope| {
+        let mut snapshots = Vec::new();
+        for txn in transactions {
+            if options.sample.is_some_and(|s| s.exhausted(summary.rows)) {
+                break;
+            }
+            if let Some(stopped) = tracker.check(summary.rows) {
+                summary.stopped = Some(stopped);
+                break;
+            }
+            let row = summary.rows;
+            summary.rows += 1;
+            let mut record = OutcomeRecord {
+                row: summary.rows,
+                client_id: 0,
+                status: RowStatus::Skipped,
+                balances: 0,
+            };
+            let rejected = match txn {
+                Ok(txn)
+                    if options
+                        .sample
+                        .is_none_or(|s| s.includes(row, txn.client_id)) =>
+                {
+                    summary.processed += 1;
+                    record.client_id = txn.client_id;
+                    if let Some(wal) = &engine.wal {
+                        wal.lock()
+                            .map_err(|_| "write-ahead log lock poisoned")?
+                            .at_row(summary.rows);
+                    }
+                    let outcome = engine.process(txn)?;
+                    if outcomes.is_open() {
+                        record.status = RowStatus::new(&outcome);
+                        record.balances = outcomes::balances_hash(engine, record.client_id)?;
+                    }
+                    summary.duplicates += (outcome == TransactionOutcome::Duplicate) as u64;
+                    let rejected = matches!(outcome, TransactionOutcome::Rejected(_));
+                    summary.rejected += rejected as u64;
+                    rejected
+                }
+                Ok(txn) => {
+                    record.client_id = txn.client_id;
+                    false
+                }
+                // A skipped row counts as rejected for the reject rate, a
+                // malformed input is mostly rows that can't be parsed.
+                Err(PaymentsEngineError::Record(error)) => {
+                    options.mode.check(&error)?;
+                    summary.errors += 1;
+                    skipped.skip(&error)?;
+                    record.status = RowStatus::Error;
+                    true
+                }
+                Err(x) => return Err(x),
+            };
+            outcomes.record(record)?;
+            if let Some(rate) = options
+                .max_reject_rate
+                .filter(|rate| rejected && row < rate.within)
+            {
+                window_rejected += 1;
+                rate.check(summary.rows, window_rejected, false)?;
+            }
+            if summary.rows.is_multiple_of(tiering::SWEEP_EVERY_ROWS) {
+                span!("evict");
+                engine.db.evict_inactive()?;
+                engine.db.spill_oldest()?;
+            }
+            if let Some(options) = &options.snapshots {
+                // A boundary reached while the last snapshot is still being
+                // taken is skipped rather than waited for.
+                if summary.rows.is_multiple_of(options.every)
+                    && snapshot::begin(&engine.db, summary.rows)?
+                {
+                    snapshots.push(scope.spawn(|| snapshot::write(&engine.db, options)));
+                }
+            }
+        }
+        snapshots
+            .into_iter()
+            .try_for_each(|snapshot| snapshot.join().map_err(|_| "snapshot thread panicked")?)?;
+        if let Some(rate) = options.max_reject_rate {
+            rate.check(summary.rows, window_rejected, true)?;
+        }
+        skipped.finish()?;
+        outcomes.finish()?;
+        Ok(summary)
+    })
+}
+
+/// The default policy, with the rule sets of `--regions` if given.
+pub fn regional_policy(regions: Option<String>, clients: Option<String>) -> Result<Policy> {
+    Ok(Policy {
+        regions: match regions {
+            Some(regions) => Regions::load(regions, clients)?,
+            None => Regions::default(),
+        },
+        ..Policy::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        append::{load_previous_runs, save_run, SaveOptions},
+        audit::verify_capture,
+        clock::SimulatedClock,
+        ledger::LedgerAccount,
+        output::{write_accounts, ReportWriter},
+        policy::PolicyOverride,
+        state::{export_state, import_state},
+        tiering::DirColdStore,
+        whatif::what_if,
+    };
+    use dashmap::mapref::one::Ref;
+
+    /// The account of a client, which must exist.
+    fn account(engine: &Engine, client_id: ClientId) -> account::AccountView {
+        engine
+            .account(client_id)
+            .expect("account is readable")
+            .expect("client exists")
+    }
+
+    /// The state of a client, which must exist.
+    fn client(db: &Database, client_id: ClientId) -> Ref<'_, ClientId, Client> {
+        db.clients.get(&client_id).expect("client exists")
+    }
+
+    #[test]
+    fn integration_test_read_example_input() -> Result<()> {
+        let reader = open_file_read_csv("test-files/example_input.csv".to_string())?;
+        let engine = Engine::default();
+        run_engine(reader, &engine)?;
+        // The withdrawal rejected for insufficient funds isn't stored.
+        assert_eq!(engine.db.transactions.len(), 4);
+        assert_eq!(engine.db.clients.len(), 2);
+        assert_eq!(account(&engine, 1).available(), 1.5);
+        assert_eq!(account(&engine, 2).available(), 2.0);
+        println!("{:?}", engine.db);
+        Ok(())
+    }
+
+    #[test]
+    /// Tests this case stated in the problem statement.
+    /// > Likewise, transaction IDs (tx) are globally unique, though are also not guaranteed to be ordered.
+    fn order_does_not_matter() -> Result<()> {
+        let reader_0 = open_file_read_csv("test-files/example_input_out_of_order.csv".to_string())?;
+        let reader_1 = open_file_read_csv("test-files/example_input.csv".to_string())?;
+        let engine_0 = Engine::with_clock(SimulatedClock::new(0));
+        let engine_1 = Engine::with_clock(SimulatedClock::new(0));
+        run_engine(reader_0, &engine_0)?;
+        run_engine(reader_1, &engine_1)?;
+        assert!(same_entries(&engine_0.db.clients, &engine_1.db.clients));
+        Ok(())
+    }
+
+    #[test]
+    fn metrics_count_what_was_handled() -> Result<()> {
+        let reader = open_file_read_csv("test-files/chargeback_dispute.csv".to_string())?;
+        let engine = Engine::default();
+        run_engine(reader, &engine)?;
+        let metrics = engine.metrics()?;
+        let processed = |transaction_type| {
+            metrics
+                .processed
+                .iter()
+                .find(|(t, _)| *t == transaction_type)
+                .map(|(_, count)| *count)
+        };
+        assert_eq!(processed(TransactionType::Deposit), Some(2));
+        assert_eq!(processed(TransactionType::Dispute), Some(1));
+        assert_eq!(processed(TransactionType::ChargeBack), Some(1));
+        assert_eq!(metrics.applied, metrics.processed);
+        assert!(metrics.rows_per_second().is_some());
+        assert!(metrics.rejected.iter().all(|(_, count)| *count == 0));
+        assert_eq!(metrics.accounts, 1);
+        assert_eq!(metrics.locked_accounts, 1);
+        assert_eq!(metrics.open_disputes, 0);
+        assert_eq!(metrics.stored_transactions, 2);
+        assert!(metrics.estimated_memory_bytes > 0);
+        Ok(())
+    }
+
+    #[test]
+    /// Every rejection comes back with its reason instead of vanishing into the log.
+    fn rejections_are_returned_with_their_reason() -> Result<()> {
+        let engine = Engine::default();
+        assert_eq!(
+            engine.process(Transaction::deposit(1, 1, 1.0))?,
+            TransactionOutcome::Applied
+        );
+        assert_eq!(
+            engine.process(Transaction::withdrawal(1, 2, 5.0))?,
+            TransactionOutcome::Rejected(Reason::InsufficientFunds)
+        );
+        assert_eq!(
+            engine.process(Transaction::dispute(2, 1))?,
+            TransactionOutcome::Rejected(Reason::NotClientTransaction)
+        );
+        assert_eq!(
+            engine.process(Transaction::resolve(1, 1))?,
+            TransactionOutcome::Rejected(Reason::NotDisputed)
+        );
+        assert_eq!(
+            engine.process(Transaction::dispute(1, 9))?,
+            TransactionOutcome::Rejected(Reason::Unprocessable)
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// Outcomes come out as they are processed and the rest of the input
+    /// is left alone once the caller stops asking.
+    fn process_reader_is_lazy() -> Result<()> {
+        let reader = open_file_read_csv("test-files/resolved_non_disputed.csv".to_string())?;
+        let engine = Engine::default();
+        let mut outcomes = engine.process_reader(reader)?;
+        assert_eq!(
+            outcomes.next().transpose()?,
+            Some(TransactionOutcome::Applied)
+        );
+        assert_eq!(engine.metrics()?.stored_transactions, 1);
+        let outcomes: Vec<_> = outcomes.collect::<Result<_>>()?;
+        assert_eq!(
+            outcomes.last(),
+            Some(&TransactionOutcome::Rejected(Reason::NotDisputed))
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// Dispute a deposit transaction.
+    fn test_dispute_deposit() -> Result<()> {
+        let reader = open_file_read_csv("test-files/dispute_deposit.csv".to_string())?;
+        let engine = Engine::default();
+        run_engine(reader, &engine)?;
+        assert_eq!(engine.db.transactions.len(), 2);
+        assert_eq!(engine.db.clients.len(), 1);
+        assert_eq!(account(&engine, 1).available(), 2.0);
+        assert_eq!(account(&engine, 1).held(), 1.0);
+        assert_eq!(account(&engine, 1).open_disputes(), &[1]);
+        Ok(())
+    }
+    #[test]
+    fn test_dispute_invalid_transaction_id() -> Result<()> {
+        let reader =
+            open_file_read_csv("test-files/dispute_invalid_transaction_id.csv".to_string())?;
+        let engine = Engine::default();
+        run_engine(reader, &engine)?;
+        assert_eq!(engine.db.transactions.len(), 2);
+        assert_eq!(engine.db.clients.len(), 1);
+        assert_eq!(account(&engine, 1).available(), 3.0);
+        assert_eq!(account(&engine, 1).held(), 0.0);
+        Ok(())
+    }
+    #[test]
+    fn test_dispute_withdrawal() -> Result<()> {
+        let reader = open_file_read_csv("test-files/dispute_withdrawal.csv".to_string())?;
+        let engine = Engine::default();
+        run_engine(reader, &engine)?;
+        assert_eq!(engine.db.transactions.len(), 3);
+        assert_eq!(engine.db.clients.len(), 1);
+        // The withdrawn funds are held for the client, the rest stays
+        // available.
+        assert_eq!(account(&engine, 1).available(), 2.5);
+        assert_eq!(account(&engine, 1).held(), 1.5);
+        engine.process(Transaction::chargeback(1, 2))?;
+        assert_eq!(account(&engine, 1).available(), 4.0);
+        assert_eq!(account(&engine, 1).held(), 0.0);
+        assert!(account(&engine, 1).locked());
+
+        let engine = Engine::default();
+        engine.process(Transaction::deposit(1, 1, 2.0))?;
+        engine.process(Transaction::withdrawal(1, 2, 1.5))?;
+        engine.process(Transaction::dispute(1, 2))?;
+        engine.process(Transaction::resolve(1, 2))?;
+        assert_eq!(account(&engine, 1).available(), 0.5);
+        assert_eq!(account(&engine, 1).held(), 0.0);
+
+        // A withdrawal that was rejected paid nothing out, so there is
+        // nothing to dispute or pay back.
+        let engine = Engine::default();
+        engine.process(Transaction::deposit(1, 1, 1.0))?;
+        engine.process(Transaction::withdrawal(1, 2, 100.0))?;
+        assert_eq!(
+            engine.process(Transaction::dispute(1, 2))?,
+            TransactionOutcome::Rejected(Reason::Unprocessable)
+        );
+        engine.process(Transaction::chargeback(1, 2))?;
+        assert_eq!(account(&engine, 1).available(), 1.0);
+        assert!(!account(&engine, 1).locked());
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_txn_ids() -> Result<()> {
+        let engine = Engine::default();
+        engine.process(Transaction::deposit(1, 1, 2.0))?;
+        engine.process(Transaction::dispute(1, 1))?;
+        assert_eq!(
+            engine.process(Transaction::withdrawal(2, 1, 1.0))?,
+            TransactionOutcome::Rejected(Reason::DuplicateTxnId)
+        );
+        assert_eq!(
+            engine.process(Transaction::deposit(1, 1, 2.0))?,
+            TransactionOutcome::Rejected(Reason::DuplicateTxnId)
+        );
+
+        let engine = Engine::default().with_policy(Policy {
+            idempotent: true,
+            ..Policy::default()
+        });
+        for _ in 0..2 {
+            engine.process(Transaction::deposit(1, 1, 2.0))?;
+            engine.process(Transaction::deposit(1, 2, 1.0))?;
+            engine.process(Transaction::dispute(1, 1))?;
+        }
+        assert_eq!(
+            engine.process(Transaction::dispute(1, 1))?,
+            TransactionOutcome::Duplicate
+        );
+        assert_eq!(account(&engine, 1).available(), 1.0);
+        assert_eq!(account(&engine, 1).held(), 2.0);
+        // Same id, different amount: not a repeat.
+        assert_eq!(
+            engine.process(Transaction::deposit(1, 2, 5.0))?,
+            TransactionOutcome::Rejected(Reason::DuplicateTxnId)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_client_mismatch() -> Result<()> {
+        let reader = open_file_read_csv("test-files/dispute_client_mismatch.csv".to_string())?;
+        let engine = Engine::default();
+        run_engine(reader, &engine)?;
+        assert_eq!(engine.db.transactions.len(), 2);
+        assert_eq!(engine.db.clients.len(), 2);
+        assert_eq!(account(&engine, 1).available(), 1.0);
+        assert_eq!(account(&engine, 1).held(), 0.0);
+        assert_eq!(account(&engine, 2).available(), 2.0);
+        assert_eq!(account(&engine, 2).held(), 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_disputed_deposit() -> Result<()> {
+        let reader = open_file_read_csv("test-files/resolved_dispute.csv".to_string())?;
+        let engine = Engine::default();
+        run_engine(reader, &engine)?;
+        assert_eq!(engine.db.transactions.len(), 2);
+        assert_eq!(engine.db.clients.len(), 1);
+        assert_eq!(account(&engine, 1).available(), 3.0);
+        assert_eq!(account(&engine, 1).held(), 0.0);
+        Ok(())
+    }
+    #[test]
+    fn test_resolved_non_disputed() -> Result<()> {
+        let reader = open_file_read_csv("test-files/resolved_non_disputed.csv".to_string())?;
+        let engine = Engine::default();
+        run_engine(reader, &engine)?;
+        assert_eq!(engine.db.transactions.len(), 2);
+        assert_eq!(engine.db.clients.len(), 1);
+        assert_eq!(account(&engine, 1).available(), 3.0);
+        assert_eq!(account(&engine, 1).held(), 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chargeback_dispute() -> Result<()> {
+        let reader = open_file_read_csv("test-files/chargeback_dispute.csv".to_string())?;
+        let engine = Engine::default();
+        run_engine(reader, &engine)?;
+        assert_eq!(engine.db.transactions.len(), 2);
+        assert_eq!(engine.db.clients.len(), 1);
+        assert_eq!(account(&engine, 1).available(), 2.0);
+        assert_eq!(account(&engine, 1).held(), 0.0);
+        assert!(account(&engine, 1).locked());
+        Ok(())
+    }
+
+    #[test]
+    /// A transaction is disputed at most once, and a dispute only settled
+    /// once.
+    fn disputes_follow_the_lifecycle() -> Result<()> {
+        let mut engine = Engine::default();
+        engine.db.policy.allow_admin_ops = true;
+        engine.process(Transaction::deposit(1, 1, 2.0))?;
+        engine.process(Transaction::deposit(1, 2, 3.0))?;
+        let outcomes = [
+            engine.process(Transaction::dispute(1, 1))?,
+            engine.process(Transaction::dispute(1, 1))?,
+            engine.process(Transaction::resolve(1, 1))?,
+            engine.process(Transaction::dispute(1, 1))?,
+            engine.process(Transaction::chargeback(1, 1))?,
+        ];
+        assert_eq!(
+            outcomes,
+            [
+                TransactionOutcome::Applied,
+                TransactionOutcome::Rejected(Reason::AlreadyDisputed),
+                TransactionOutcome::Applied,
+                TransactionOutcome::Rejected(Reason::AlreadyDisputed),
+                TransactionOutcome::Rejected(Reason::NotDisputed),
+            ]
+        );
+        assert_eq!(
+            (account(&engine, 1).available(), account(&engine, 1).held()),
+            (5.0, 0.0)
+        );
+
+        // Unlocked after a chargeback, the account still can't dispute the
+        // transaction charged back.
+        engine.process(Transaction::dispute(1, 2))?;
+        engine.process(Transaction::chargeback(1, 2))?;
+        engine.process(Transaction::builder(TransactionType::Unlock, 1, 9).build()?)?;
+        assert_eq!(
+            engine.process(Transaction::dispute(1, 2))?,
+            TransactionOutcome::Rejected(Reason::AlreadyDisputed)
+        );
+        assert_eq!(account(&engine, 1).available(), 2.0);
+
+        // The lifecycle is part of the state.
+        let state = export_state(&engine.db)?;
+        assert_eq!(state.accounts[0].settled.len(), 2);
+        let restored = Engine::default();
+        import_state(&restored, state)?;
+        assert_eq!(restored.db.digest(), engine.db.digest());
+        assert_eq!(
+            handle_transaction(&restored.db, Transaction::dispute(1, 1))?,
+            TransactionOutcome::Rejected(Reason::AlreadyDisputed)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn partial_disputes_hold_and_settle_their_amount() -> Result<()> {
+        let engine = Engine::default();
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        engine.process(Transaction::withdrawal(1, 2, 4.0))?;
+        let partial = |txn_id, amount| Transaction {
+            amount: Some(amount),
+            ..Transaction::dispute(1, txn_id)
+        };
+        for amount in [5.0, f64::NAN] {
+            assert_eq!(
+                engine.process(partial(2, amount))?,
+                TransactionOutcome::Rejected(Reason::InvalidDisputeAmount)
+            );
+        }
+        engine.process(partial(1, 2.5))?;
+        engine.process(partial(2, 1.5))?;
+        let balances = |engine: &Engine| -> Result<(f64, f64, bool)> {
+            let account = engine.account(1)?.ok_or("no account")?;
+            Ok((account.available(), account.held(), account.locked()))
+        };
+        assert_eq!(balances(&engine)?, (3.5, 4.0, false));
+
+        // The parts disputed are part of the state.
+        let restored = Engine::default();
+        import_state(&restored, export_state(&engine.db)?)?;
+        assert_eq!(restored.db.digest(), engine.db.digest());
+
+        engine.process(Transaction::resolve(1, 1))?;
+        assert_eq!(balances(&engine)?, (6.0, 1.5, false));
+        engine.process(Transaction::chargeback(1, 2))?;
+        assert_eq!(balances(&engine)?, (7.5, 0.0, true));
+        engine.db.verify()?;
+        let books = engine.db.ledger.trial_balance()?;
+        assert_eq!(books[&LedgerAccount::Cash].balance(), 7.5);
+        assert_eq!(books[&LedgerAccount::Receivable].balance(), 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer() -> Result<()> {
+        let reader = open_file_read_csv("test-files/transfers.csv".to_string())?;
+        let engine = Engine::default();
+        run_engine(reader, &engine)?;
+        assert_eq!(account(&engine, 1).available(), 3.0);
+        assert_eq!(account(&engine, 2).available(), 2.0);
+        assert_eq!(account(&engine, 3).available(), 0.0);
+        let metrics = engine.metrics()?;
+        let rejected = |reason| {
+            metrics
+                .rejected
+                .iter()
+                .find(|(r, _)| *r == reason)
+                .map(|(_, count)| *count)
+        };
+        assert_eq!(rejected(Reason::InsufficientFunds), Some(1));
+        assert_eq!(rejected(Reason::RecipientLocked), Some(1));
+        assert_eq!(rejected(Reason::AccountLocked), Some(1));
+        assert_eq!(engine.db.digest(), digest::state_digest(&engine.db)?);
+        Ok(())
+    }
+
+    #[test]
+    /// A transfer's id can't be used again, by a retry of it or by any
+    /// other transaction, and one from a client without an account
+    /// doesn't open one.
+    fn transfer_ids_are_not_reused() -> Result<()> {
+        let engine = Engine::default();
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        let duplicate = TransactionOutcome::Rejected(Reason::DuplicateTxnId);
+        assert_eq!(
+            engine.process(Transaction::transfer(1, 5, 2, 2.0))?,
+            TransactionOutcome::Applied
+        );
+        assert_eq!(
+            engine.process(Transaction::transfer(1, 5, 2, 2.0))?,
+            duplicate
+        );
+        assert_eq!(engine.process(Transaction::deposit(2, 5, 3.0))?, duplicate);
+        assert_eq!(
+            engine.process(Transaction::withdrawal(1, 5, 1.0))?,
+            duplicate
+        );
+        assert_eq!(account(&engine, 1).available(), 8.0);
+        assert_eq!(account(&engine, 2).available(), 2.0);
+
+        assert_eq!(
+            engine.process(Transaction::transfer(9, 6, 2, 1.0))?,
+            TransactionOutcome::Rejected(Reason::InsufficientFunds)
+        );
+        assert!(engine.account(9)?.is_none());
+        assert_eq!(engine.db.digest(), digest::state_digest(&engine.db)?);
+
+        // The ids outlive the run in its state.
+        let restored = Engine::default();
+        import_state(&restored, export_state(&engine.db)?)?;
+        assert_eq!(
+            restored.process(Transaction::transfer(1, 5, 2, 2.0))?,
+            duplicate
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_admin_ops() -> Result<()> {
+        let run = |allow_admin_ops| -> Result<Engine> {
+            let reader = open_file_read_csv("test-files/admin_ops.csv".to_string())?;
+            let mut engine = Engine::default();
+            engine.db.policy.allow_admin_ops = allow_admin_ops;
+            run_engine(reader, &engine)?;
+            Ok(engine)
+        };
+        let engine = run(false)?;
+        assert_eq!(account(&engine, 1).available(), 0.0);
+        assert!(account(&engine, 1).locked());
+        let metrics = engine.metrics()?;
+        assert!(metrics.rejected.contains(&(Reason::AdminOpsDisabled, 5)));
+
+        let engine = run(true)?;
+        assert_eq!(account(&engine, 1).available(), 2.5);
+        assert!(account(&engine, 1).locked());
+        assert!(engine
+            .db
+            .clients
+            .get(&1)
+            .is_some_and(|client| client.closed));
+        let metrics = engine.metrics()?;
+        assert!(metrics.rejected.contains(&(Reason::InsufficientFunds, 1)));
+        assert!(metrics.rejected.contains(&(Reason::AccountLocked, 1)));
+        assert_eq!(
+            handle_transaction(
+                &engine.db,
+                Transaction::builder(TransactionType::Unlock, 1, 9).build()?
+            )?,
+            TransactionOutcome::Rejected(Reason::AccountClosed)
+        );
+        assert_eq!(engine.db.digest(), digest::state_digest(&engine.db)?);
+        Ok(())
+    }
+
+    #[test]
+    /// Rows without a timestamp are stamped with the engine's clock.
+    fn transactions_are_stamped_with_engine_clock() -> Result<()> {
+        let reader = open_file_read_csv("test-files/example_input.csv".to_string())?;
+        let engine = Engine::with_clock(SimulatedClock::new(1_000));
+        run_engine(reader, &engine)?;
+        assert!(engine
+            .db
+            .transactions
+            .iter()
+            .all(|txn| txn.timestamp == Some(1_000)));
+        Ok(())
+    }
+
+    #[test]
+    /// Resolving the same dispute twice must only release the funds once.
+    fn test_resolve_twice() -> Result<()> {
+        let reader = open_file_read_csv("test-files/resolved_twice.csv".to_string())?;
+        let engine = Engine::default();
+        run_engine(reader, &engine)?;
+        assert_eq!(account(&engine, 1).available(), 3.0);
+        assert_eq!(account(&engine, 1).held(), 0.0);
+        Ok(())
+    }
+
+    #[test]
+    /// A captured run replays to exactly the same state, timestamps included.
+    fn capture_replays_to_same_state() -> Result<()> {
+        let path = crate::temp_path("payments-engine-capture-test.csv");
+        let path = path.to_string_lossy().to_string();
+        let reader = open_file_read_csv("test-files/long_transaction_history.csv".to_string())?;
+        let engine = Engine::with_clock(SimulatedClock::new(1_000))
+            .with_capture(Capture::create(path.clone(), &[])?);
+        run_engine(reader, &engine)?;
+        engine.flush_capture()?;
+
+        let replayed = Engine::with_clock(SimulatedClock::new(0));
+        run_engine(open_file_read_csv(path)?, &replayed)?;
+        assert_eq!(engine.db, replayed.db);
+        Ok(())
+    }
+
+    #[test]
+    /// The incrementally maintained digest matches one computed from scratch.
+    fn engine_digest_tracks_state() -> Result<()> {
+        let reader = open_file_read_csv("test-files/long_transaction_history.csv".to_string())?;
+        let engine = Engine::default();
+        run_engine(reader, &engine)?;
+        assert_eq!(engine.db.digest(), digest::state_digest(&engine.db)?);
+        Ok(())
+    }
+
+    #[test]
+    /// audit-verify accepts an untouched capture and pinpoints a tampered entry.
+    fn audit_verify_finds_first_divergence() -> Result<()> {
+        let path = crate::temp_path("payments-engine-audit-test.csv");
+        let path = path.to_string_lossy().to_string();
+        let reader = open_file_read_csv("test-files/long_transaction_history.csv".to_string())?;
+        let engine = Engine::with_clock(SimulatedClock::new(1_000))
+            .with_capture(Capture::create(path.clone(), &[])?);
+        run_engine(reader, &engine)?;
+        engine.flush_capture()?;
+
+        let verification = verify_capture(path.clone())?;
+        assert_eq!(verification.entries, 18);
+        assert_eq!(verification.digest, engine.db.digest());
+
+        let tampered = std::fs::read_to_string(&path)
+            .map_err(|x| x.to_string())?
+            .replacen("deposit,1,2,2", "deposit,1,2,3", 1);
+        std::fs::write(&path, tampered).map_err(|x| x.to_string())?;
+        let err = verify_capture(path).expect_err("tampered capture must not verify");
+        assert!(err.to_string().starts_with("state diverged at sequence 2:"));
+        Ok(())
+    }
+
+    #[test]
+    /// --max-rows stops between rows and reports how far it got.
+    fn max_rows_stops_cleanly() -> Result<()> {
+        let reader = open_file_read_csv("test-files/long_transaction_history.csv".to_string())?;
+        let engine = Engine::default();
+        let options = RunOptions {
+            limits: RunLimits {
+                max_rows: Some(3),
+                ..RunLimits::default()
+            },
+            ..RunOptions::default()
+        };
+        let summary = run_engine_with(reader, &engine, &options)?;
+        assert_eq!(summary.stopped.map(|stopped| stopped.rows), Some(3));
+        assert_eq!(summary.rejected, 0);
+        assert_eq!(engine.db.transactions.len(), 2);
+        assert_eq!(account(&engine, 1).held(), 1.0);
+        Ok(())
+    }
+
+    #[test]
+    /// A run is aborted when too many of its first rows are rejected,
+    /// whether it runs on one thread or several.
+    fn max_reject_rate_aborts_the_run() -> Result<()> {
+        // One of the five rows is rejected.
+        let inputs = ["test-files/example_input.csv".to_string()];
+        for threads in [1, 2] {
+            let options = |max| RunOptions {
+                max_reject_rate: Some(RejectRate { max, within: 100 }),
+                threads,
+                ..RunOptions::default()
+            };
+            let run = |max| {
+                let options = options(max);
+                run_transactions(
+                    inputs::read_inputs(&inputs, &options)?,
+                    &Engine::default(),
+                    &options,
+                )
+            };
+            assert!(run(0.25).is_ok());
+            let err = run(0.1).expect_err("20% of rows were rejected");
+            assert!(err.to_string().contains("1 of the first 5 rows"), "{}", err);
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Sampling clients keeps each sampled client's history intact.
+    fn sample_keeps_whole_clients() -> Result<()> {
+        let full = Engine::with_clock(SimulatedClock::new(0));
+        run_engine(
+            open_file_read_csv("test-files/long_transaction_history.csv".to_string())?,
+            &full,
+        )?;
+        let sampled = Engine::with_clock(SimulatedClock::new(0));
+        let options = RunOptions {
+            sample: Some(Sample::Clients { rate: 0.5, seed: 3 }),
+            ..RunOptions::default()
+        };
+        let summary = run_engine_with(
+            open_file_read_csv("test-files/long_transaction_history.csv".to_string())?,
+            &sampled,
+            &options,
+        )?;
+        assert_eq!(summary.rows, 18);
+        assert!(summary.processed < summary.rows);
+        assert!(!sampled.db.clients.is_empty());
+        for entry in sampled.db.clients.iter() {
+            assert_eq!(*entry, *client(&full.db, *entry.key()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Unknown columns, wherever they are, don't change the outcome.
+    fn extra_columns_are_ignored() -> Result<()> {
+        let plain = Engine::with_clock(SimulatedClock::new(0));
+        run_engine(
+            open_file_read_csv("test-files/example_input.csv".to_string())?,
+            &plain,
+        )?;
+        let extra = Engine::with_clock(SimulatedClock::new(0));
+        run_engine(
+            open_file_read_csv("test-files/extra_columns.csv".to_string())?,
+            &extra,
+        )?;
+        assert!(same_entries(&plain.db.clients, &extra.db.clients));
+        Ok(())
+    }
+
+    #[test]
+    /// Exporting, importing and exporting again gives the same state, and
+    /// the imported engine carries on exactly like the original.
+    fn state_round_trips_through_json() -> Result<()> {
+        let original = Engine::with_clock(SimulatedClock::new(7));
+        run_engine(
+            open_file_read_csv("test-files/long_transaction_history.csv".to_string())?,
+            &original,
+        )?;
+        let exported = export_state(&original.db)?;
+        let json = serde_json::to_string(&exported).map_err(|x| x.to_string())?;
+        let parsed: state::StateFile = serde_json::from_str(&json).map_err(|x| x.to_string())?;
+
+        let imported = Engine::with_clock(SimulatedClock::new(7));
+        import_state(&imported, parsed)?;
+        assert_eq!(export_state(&imported.db)?, exported);
+        assert_eq!(imported.db, original.db);
+        assert_eq!(imported.db.digest(), original.db.digest());
+
+        let mut inconsistent = exported;
+        inconsistent.accounts[0].disputed.push(u32::MAX);
+        assert!(import_state(&Engine::default(), inconsistent).is_err());
+        Ok(())
+    }
+
+    #[test]
+    /// A second run continues from the first, and transactions reusing ids
+    /// from the first run are set aside instead of overwriting them.
+    fn append_runs_reject_conflicting_ids() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-append-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut first = Engine::default();
+        load_previous_runs(&mut first, &dir)?;
+        run_engine(
+            open_file_read_csv("test-files/dispute_deposit.csv".to_string())?,
+            &first,
+        )?;
+        assert_eq!(save_run(&first, &dir, SaveOptions::default())?, 0);
+
+        let mut second = Engine::default();
+        load_previous_runs(&mut second, &dir)?;
+        let summary = run_engine_with(
+            open_file_read_csv("test-files/example_input.csv".to_string())?,
+            &second,
+            &RunOptions::default(),
+        )?;
+        // Transactions 1 and 2 were used by the first run, which leaves
+        // client 2 without the funds for transaction 5.
+        assert_eq!(summary.rejected, 3);
+        assert_eq!(save_run(&second, &dir, SaveOptions::default())?, 2);
+        assert_eq!(account(&second, 1).held(), 1.0);
+        assert_eq!(
+            second.db.transactions.get(&1).and_then(|txn| txn.amount),
+            Some(1.0)
+        );
+        let conflicts =
+            std::fs::read_to_string(dir.join(append::CONFLICTS_FILE)).map_err(|x| x.to_string())?;
+        assert_eq!(conflicts.lines().count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    /// Replaying under a policy that refuses disputes of withdrawals shows
+    /// which clients would have ended up differently.
+    fn what_if_reports_changed_accounts() -> Result<()> {
+        let path = crate::temp_path("payments-engine-what-if-test.csv");
+        let path = path.to_string_lossy().to_string();
+        let engine = Engine::with_clock(SimulatedClock::new(1_000))
+            .with_capture(Capture::create(path.clone(), &[])?);
+        run_engine(
+            open_file_read_csv("test-files/dispute_withdrawal.csv".to_string())?,
+            &engine,
+        )?;
+        engine.flush_capture()?;
+
+        assert!(what_if(path.clone(), Policy::default())?.is_empty());
+        let policy =
+            Policy::default().with(PolicyOverride::Dispute(policy::DisputePolicy::DepositsOnly));
+        let diffs = what_if(path, policy)?;
+        assert_eq!(diffs.len(), 1);
+        let held = |account: &Option<account::AccountView>| account.as_ref().map(|a| a.held());
+        assert_eq!(held(&diffs[0].original), Some(1.5));
+        assert_eq!(held(&diffs[0].what_if), Some(0.0));
+        Ok(())
+    }
+
+    #[test]
+    /// Embedders rely on being able to share the engine across threads.
+    fn engine_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Engine>();
+    }
+
+    #[test]
+    /// Clients processed from several threads at once end up exactly as
+    /// they would processing everything on one thread.
+    fn engine_processes_clients_concurrently() -> Result<()> {
+        let path = "test-files/long_transaction_history.csv";
+        let sequential = Engine::with_clock(SimulatedClock::new(0));
+        run_engine(open_file_read_csv(path.to_string())?, &sequential)?;
+
+        let mut reader = open_file_read_csv(path.to_string())?;
+        let schema = Schema::from_headers(reader.headers()?, &[])?;
+        let mut by_client = std::collections::BTreeMap::<ClientId, Vec<Transaction>>::new();
+        for record in reader.records() {
+            let txn = schema.parse(&record?)?;
+            by_client.entry(txn.client_id).or_default().push(txn);
+        }
+        let concurrent = Engine::with_clock(SimulatedClock::new(0));
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = by_client
+                .into_values()
+                .map(|txns| {
+                    let engine = &concurrent;
+                    scope.spawn(move || {
+                        txns.into_iter()
+                            .try_for_each(|txn| engine.process(txn).map(|_| ()))
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("worker panicked"))
+        })?;
+
+        assert_eq!(sequential.db, concurrent.db);
+        assert_eq!(sequential.db.digest(), concurrent.db.digest());
+        Ok(())
+    }
+
+    #[test]
+    /// Evicting every client after each row and loading them back on
+    /// demand ends in the same state as keeping everything in memory.
+    fn evicted_clients_load_back_on_demand() -> Result<()> {
+        let path = "test-files/long_transaction_history.csv";
+        let resident = Engine::with_clock(SimulatedClock::new(0));
+        run_engine(open_file_read_csv(path.to_string())?, &resident)?;
+
+        let dir = crate::temp_path("payments-engine-tiering-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let tiered = Engine::with_clock(SimulatedClock::new(0)).with_tiering(Tiering {
+            store: Box::new(DirColdStore::open(&dir)?),
+            evict_after: std::time::Duration::ZERO,
+        })?;
+        let mut reader = open_file_read_csv(path.to_string())?;
+        let schema = Schema::from_headers(reader.headers()?, &[])?;
+        let mut evicted = 0;
+        for record in reader.records() {
+            tiered.process(schema.parse(&record?)?)?;
+            // Every client was last active at or before "now", push the
+            // clock forward so all of them count as inactive.
+            tiered.db.latest_activity.fetch_add(1, Ordering::SeqCst);
+            evicted += tiered.db.evict_inactive()?;
+        }
+        assert!(evicted > 0);
+        assert!(tiered.db.clients.is_empty());
+        assert_eq!(tiered.db.digest(), resident.db.digest());
+        assert_eq!(digest::state_digest(&tiered.db)?, resident.db.digest());
+        let mut cold = 0;
+        tiered.db.for_each_client(|client_id, evicted| {
+            cold += 1;
+            assert_eq!(*evicted, *client(&resident.db, client_id));
+        })?;
+        assert_eq!(cold, resident.db.clients.len());
+
+        // A later run over the same store starts from the evicted state.
+        let reopened = Engine::with_clock(SimulatedClock::new(0)).with_tiering(Tiering {
+            store: Box::new(DirColdStore::open(&dir)?),
+            evict_after: std::time::Duration::ZERO,
+        })?;
+        assert_eq!(reopened.db.digest(), resident.db.digest());
+        assert_eq!(reopened.db.preload(Preload::None)?, 0);
+        // Every client was last active at the same time, so all are hot.
+        assert_eq!(reopened.db.preload(Preload::Hot)?, cold);
+        assert!(same_entries(&reopened.db.clients, &resident.db.clients));
+        Ok(())
+    }
+
+    #[test]
+    /// Clients still in memory at the end of a run are evicted too, so
+    /// the next run over the store starts from every client.
+    fn runs_continue_from_the_cold_store() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-cold-runs-test");
+        let tiering = || -> Result<Tiering> {
+            Ok(Tiering {
+                store: Box::new(DirColdStore::open(&dir)?),
+                evict_after: tiering::DEFAULT_EVICT_AFTER,
+            })
+        };
+        let first = Engine::with_clock(SimulatedClock::new(0)).with_tiering(tiering()?)?;
+        run_engine(
+            open_file_read_csv("test-files/example_input.csv".to_string())?,
+            &first,
+        )?;
+        assert_eq!(first.db.evict_inactive()?, 0);
+        assert_eq!(first.db.evict_all()?, 2);
+
+        for preload in [Preload::Hot, Preload::All] {
+            let second = Engine::with_clock(SimulatedClock::new(0)).with_tiering(tiering()?)?;
+            assert_eq!(second.db.preload(preload)?, 2);
+            assert_eq!(second.db.digest(), first.db.digest());
+            assert_eq!(
+                second.account(1)?.map(|account| account.available()),
+                Some(1.5)
+            );
+            // Preloaded clients left the store, the end of the run puts
+            // them back.
+            assert_eq!(second.db.evict_all()?, 2);
+        }
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn pending_holds_fill_from_later_deposits() -> Result<()> {
+        let engine = Engine::default().with_policy(Policy {
+            pending_holds: true,
+            ..Policy::default()
+        });
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        engine.process(Transaction::withdrawal(1, 2, 8.0))?;
+        engine.process(Transaction::dispute(1, 1))?;
+        engine.process(Transaction::deposit(1, 3, 5.0))?;
+        let account = engine.account(1)?.ok_or("no account")?;
+        assert_eq!(
+            (account.available(), account.held(), account.pending_hold()),
+            (0.0, 7.0, 3.0)
+        );
+        let mut out = Vec::new();
+        write_accounts(ReportWriter::new(&engine.db, &mut out, Format::Csv)?)?;
+        assert_eq!(
+            String::from_utf8(out).map_err(|x| x.to_string())?,
+            "client,available,held,total,locked,pending_hold\n1,0.0000,7.0000,7.0000,false,3.0000\n"
+        );
+
+        // The pending hold is part of the state.
+        let restored = Engine::default();
+        import_state(&restored, export_state(&engine.db)?)?;
+        assert_eq!(restored.db.digest(), engine.db.digest());
+
+        // A chargeback takes what was still to be held from the client.
+        engine.process(Transaction::chargeback(1, 1))?;
+        let account = engine.account(1)?.ok_or("no account")?;
+        assert_eq!((account.available(), account.held()), (-3.0, 0.0));
+
+        // Once filled, a resolve gives back what was held.
+        restored.process(Transaction::deposit(1, 4, 4.0))?;
+        restored.process(Transaction::resolve(1, 1))?;
+        let account = restored.account(1)?.ok_or("no account")?;
+        assert_eq!(
+            (account.available(), account.held(), account.pending_hold()),
+            (11.0, 0.0, 0.0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn locked_accounts_settle_disputes_and_queue_deposits() -> Result<()> {
+        let path = crate::temp_path("payments-engine-locked-accounts-test.csv");
+        let path = path.to_string_lossy().to_string();
+        let _ = std::fs::remove_file(&path);
+        let engine = Engine::with_clock(SimulatedClock::new(100))
+            .with_policy(Policy {
+                locked_accounts: LockedAccounts::QueueDeposits,
+                allow_admin_ops: true,
+                ..Policy::default()
+            })
+            .with_invariant_checks()
+            .with_journal(Journal::open(&path, Format::Csv)?);
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        engine.process(Transaction::deposit(1, 2, 5.0))?;
+        engine.process(Transaction::dispute(1, 1))?;
+        engine.process(Transaction::dispute(1, 2))?;
+        engine.process(Transaction::chargeback(1, 2))?;
+        // Locked, the open dispute is still settled and the deposit waits.
+        engine.process(Transaction::resolve(1, 1))?;
+        engine.process(Transaction::deposit(1, 3, 4.0))?;
+        assert_eq!(
+            engine.process(Transaction::withdrawal(1, 4, 1.0))?,
+            TransactionOutcome::Rejected(Reason::AccountLocked)
+        );
+        let account = engine.account(1)?.ok_or("no account")?;
+        assert_eq!((account.available(), account.held()), (10.0, 0.0));
+
+        // The queued deposit is part of the state.
+        let restored = Engine::default();
+        import_state(&restored, export_state(&engine.db)?)?;
+        assert_eq!(restored.db.digest(), engine.db.digest());
+
+        engine.process(Transaction::builder(TransactionType::Unlock, 1, 9).build()?)?;
+        let account = engine.account(1)?.ok_or("no account")?;
+        assert_eq!((account.available(), account.locked()), (14.0, false));
+        engine.flush_journal()?;
+        let journal = std::fs::read_to_string(&path).map_err(|x| x.to_string())?;
+        let events: Vec<_> = journal
+            .lines()
+            .skip(6)
+            .map(|line| line.split(',').take(5).collect::<Vec<_>>().join(","))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "6,applied,queue_deposits,,resolve",
+                "7,queued,queue_deposits,,deposit",
+                "8,rejected,account_locked,,withdrawal",
+                "9,applied,,,unlock",
+            ]
+        );
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn disputes_are_only_taken_within_the_window() -> Result<()> {
+        let day = 24 * 60 * 60;
+        let engine = Engine::default().with_policy(Policy {
+            dispute_window: Some(std::time::Duration::from_secs(60 * day)),
+            ..Policy::default()
+        });
+        let at = |txn: Transaction, at| Transaction {
+            timestamp: Some(at),
+            ..txn
+        };
+        engine.process(at(Transaction::deposit(1, 1, 2.0), 0))?;
+        engine.process(at(Transaction::deposit(1, 2, 3.0), 10 * day))?;
+        assert_eq!(
+            engine.process(at(Transaction::dispute(1, 1), 61 * day))?,
+            TransactionOutcome::Rejected(Reason::DisputeWindowClosed)
+        );
+        assert_eq!(
+            engine.process(at(Transaction::dispute(1, 2), 61 * day))?,
+            TransactionOutcome::Applied
+        );
+        let details = engine.client_details(1)?.ok_or("no client 1")?;
+        assert_eq!(details.open_disputes, [(2, 61 * day)]);
+        assert_eq!(details.history[1].timestamp, Some(10 * day));
+        Ok(())
+    }
+
+    #[test]
+    fn overdrafts_are_limited_by_the_policy() -> Result<()> {
+        let engine = Engine::default().with_policy(Policy {
+            overdraft: Some(policy::Overdraft::AllowWithLimit(5.0)),
+            ..Policy::default()
+        });
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        engine.process(Transaction::deposit(1, 2, 6.0))?;
+        assert_eq!(
+            engine.process(Transaction::withdrawal(1, 3, 20.0))?,
+            TransactionOutcome::Applied
+        );
+        // Holding either deposit would take the client below -5.
+        assert_eq!(
+            engine.process(Transaction::dispute(1, 2))?,
+            TransactionOutcome::Rejected(Reason::OverdraftLimit)
+        );
+        assert_eq!(
+            engine.process(Transaction::withdrawal(1, 4, 2.0))?,
+            TransactionOutcome::Rejected(Reason::InsufficientFunds)
+        );
+        engine.process(Transaction::deposit(1, 5, 5.0))?;
+        engine.process(Transaction::dispute(1, 2))?;
+        let account = engine.account(1)?.ok_or("no account")?;
+        assert_eq!((account.available(), account.held()), (-5.0, 6.0));
+
+        // Transfers and debit adjustments take funds out down to the same
+        // limit.
+        let engine = Engine::default().with_policy(Policy {
+            overdraft: Some(policy::Overdraft::AllowWithLimit(5.0)),
+            allow_admin_ops: true,
+            ..Policy::default()
+        });
+        let debit = |txn_id, amount| {
+            Transaction::builder(TransactionType::DebitAdjustment, 1, txn_id)
+                .amount(amount)
+                .build()
+        };
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        assert_eq!(
+            engine.process(Transaction::transfer(1, 2, 2, 13.0))?,
+            TransactionOutcome::Applied
+        );
+        assert_eq!(engine.process(debit(3, 2.0)?)?, TransactionOutcome::Applied);
+        assert_eq!(
+            engine.process(debit(4, 0.5)?)?,
+            TransactionOutcome::Rejected(Reason::InsufficientFunds)
+        );
+        assert_eq!(
+            engine.process(Transaction::transfer(1, 5, 2, 0.5))?,
+            TransactionOutcome::Rejected(Reason::InsufficientFunds)
+        );
+        assert_eq!(
+            engine.account(1)?.map(|account| account.available()),
+            Some(-5.0)
+        );
+
+        // Denying overdrafts keeps disputes from going below zero too.
+        let engine = Engine::default().with_policy(Policy {
+            overdraft: Some(policy::Overdraft::Deny),
+            ..Policy::default()
+        });
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        engine.process(Transaction::withdrawal(1, 2, 1.0))?;
+        assert_eq!(
+            engine.process(Transaction::dispute(1, 1))?,
+            TransactionOutcome::Rejected(Reason::OverdraftLimit)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn currencies_are_kept_apart() -> Result<()> {
+        let engine = Engine::default();
+        let eur = |transaction_type, txn_id, amount| {
+            Transaction::builder(transaction_type, 1, txn_id)
+                .amount(amount)
+                .currency("eur")
+                .build()
+        };
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        engine.process(eur(TransactionType::Deposit, 2, 5.0)?)?;
+        engine.process(eur(TransactionType::Withdrawal, 3, 2.0)?)?;
+        assert_eq!(
+            engine.process(eur(TransactionType::Withdrawal, 4, 9.0)?)?,
+            TransactionOutcome::Rejected(Reason::InsufficientFunds)
+        );
+        // The dispute is in euros, like the deposit it refers to.
+        engine.process(Transaction::dispute(1, 2))?;
+        let mut out = Vec::new();
+        write_accounts(ReportWriter::new(&engine.db, &mut out, Format::Csv)?.with_totals())?;
+        assert_eq!(
+            String::from_utf8(out).map_err(|x| x.to_string())?,
+            "client,available,held,total,locked,currency\n\
+            1,10.0000,0.0000,10.0000,false,\n\
+            1,-2.0000,5.0000,3.0000,false,EUR\n\
+            total,10.0000,0.0000,10.0000,,\n\
+            total,-2.0000,5.0000,3.0000,,EUR\n"
+        );
+
+        let restored = Engine::default();
+        import_state(&restored, export_state(&engine.db)?)?;
+        assert_eq!(restored.db.digest(), engine.db.digest());
+        restored.process(Transaction::resolve(1, 2))?;
+        let eur_funds = &client(&restored.db, 1).currencies["EUR"];
+        assert_eq!((eur_funds.available, eur_funds.held), (3.0, 0.0));
+        // Adjustments are in the account's own currency.
+        assert!(eur(TransactionType::CreditAdjustment, 5, 1.0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dormant_accounts_are_archived_and_left_out() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-dormant-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let engine = Engine::with_clock(SimulatedClock::new(0))
+            .with_policy(Policy {
+                dormant_after: Some(std::time::Duration::from_secs(60)),
+                ..Policy::default()
+            })
+            .with_tiering(Tiering {
+                store: Box::new(DirColdStore::open(&dir)?),
+                evict_after: std::time::Duration::from_secs(3600),
+            })?;
+        for (client_id, timestamp) in [(1, 0), (2, 100)] {
+            let mut txn = Transaction::deposit(client_id, client_id as u32, 5.0);
+            txn.timestamp = Some(timestamp);
+            engine.process(txn)?;
+        }
+        // Client 1 is dormant well before the tiering would evict it.
+        assert_eq!(engine.db.evict_inactive()?, 1);
+        assert!(!engine.db.clients.contains_key(&1));
+
+        let report = |include_dormant: bool| -> Result<String> {
+            let mut out = Vec::new();
+            let mut report = ReportWriter::new(&engine.db, &mut out, Format::Csv)?.with_totals();
+            if include_dormant {
+                report = report.with_dormant();
+            }
+            write_accounts(report)?;
+            String::from_utf8(out).map_err(|x| x.to_string().into())
+        };
+        let active = report(false)?;
+        assert!(active.lines().any(|row| row.starts_with("2,")));
+        assert!(!active.lines().any(|row| row.starts_with("1,")));
+        assert!(report(true)?.lines().any(|row| row.starts_with("1,")));
+        std::fs::remove_dir_all(&dir).map_err(|x| x.to_string())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+/// Caps on how much work a single run may do.
+pub struct RunLimits {
+    /// Stop after processing this many rows.
+    pub max_rows: Option<u64>,
+    /// Stop once the run has taken this long.
+    pub max_runtime: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Why a run stopped before reaching the end of its input.
+pub enum StopReason {
+    MaxRows(u64),
+    MaxRuntime(Duration),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::MaxRows(rows) => write!(f, "reached --max-rows {}", rows),
+            StopReason::MaxRuntime(runtime) => {
+                write!(f, "reached --max-runtime {}s", runtime.as_secs_f64())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A run that was stopped early, and how far it got.
+pub struct Stopped {
+    pub reason: StopReason,
+    /// Rows fully processed before stopping. The input can be resumed
+    /// from the row after this one.
+    pub rows: u64,
+}
+
+/// Tracks a run against its limits.
+#[derive(Debug)]
+pub struct LimitTracker {
+    limits: RunLimits,
+    /// Only read with a `max_runtime`, as there is no clock to read on
+    /// `wasm32-unknown-unknown`.
+    started: Option<Instant>,
+}
+
+impl LimitTracker {
+    pub fn start(limits: RunLimits) -> Self {
+        LimitTracker {
+            limits,
+            started: limits.max_runtime.map(|_| Instant::now()),
+        }
+    }
+
+    /// Checks whether another row may be processed after `rows` rows.
+    pub fn check(&self, rows: u64) -> Option<Stopped> {
+        let reason = match self.limits {
+            RunLimits {
+                max_rows: Some(max),
+                ..
+            } if rows >= max => StopReason::MaxRows(max),
+            RunLimits {
+                max_runtime: Some(max),
+                ..
+            } if self.started.is_some_and(|started| started.elapsed() >= max) => {
+                StopReason::MaxRuntime(max)
+            }
+            _ => return None,
+        };
+        Some(Stopped { reason, rows })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Aborts a run when too many of its first rows are rejected, as the
+/// input is then most likely malformed.
+pub struct RejectRate {
+    /// The largest fraction of rows that may be rejected.
+    pub max: f64,
+    /// How many rows at the start of the input are counted.
+    pub within: u64,
+}
+
+/// The default for `--reject-rate-window`.
+pub const DEFAULT_REJECT_RATE_WINDOW: u64 = 1_000;
+
+impl RejectRate {
+    /// Fails once `rejected` of the first `rows` rows, counting only those
+    /// within the window, are over the limit. While the run goes on that is
+    /// when the window can't get back under it however the rest goes;
+    /// once the input has `ended`, a shorter input is held to the rate of
+    /// the rows it had.
+    pub fn check(&self, rows: u64, rejected: u64, ended: bool) -> crate::Result<()> {
+        let counted = match ended {
+            true => rows.min(self.within),
+            false => self.within,
+        };
+        if rejected as f64 <= self.max * counted as f64 {
+            return Ok(());
+        }
+        Err(format!(
+            "{} of the first {} rows were rejected, more than --max-reject-rate {}, \
+             the input looks malformed so the run was aborted and nothing was saved",
+            rejected,
+            rows.min(self.within),
+            self.max
+        )
+        .into())
+    }
+}
+
+/// Parses durations such as `90`, `90s`, `15m`, `2h` or `30d`. Plain numbers are seconds.
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", s))?;
+    let seconds = match unit {
+        "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 60.0 * 60.0,
+        "d" => number * 60.0 * 60.0 * 24.0,
+        _ => return Err(format!("unknown duration unit {:?} in {:?}", unit, s)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|x| format!("invalid duration {:?}: {}", s, x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_duration("1.5h"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(604_800)));
+        assert!(parse_duration("10w").is_err());
+        assert!(parse_duration("99999999999999999999h").is_err());
+    }
+
+    #[test]
+    fn reject_rate_aborts_once_out_of_reach() {
+        let rate = RejectRate {
+            max: 0.1,
+            within: 100,
+        };
+        assert!(rate.check(20, 10, false).is_ok());
+        assert!(rate.check(20, 11, false).is_err());
+        assert!(rate.check(20, 2, true).is_ok());
+        assert!(rate.check(20, 3, true).is_err());
+        assert!(rate.check(1_000, 10, true).is_ok());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::{clock::Timestamp, digest::state_digest, ClientId, Database, Result};
+use std::{collections::BTreeMap, io, str::FromStr, sync::atomic::Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Why an account was locked.
+pub enum LockReason {
+    Chargeback,
+}
+
+impl LockReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockReason::Chargeback => "chargeback",
+        }
+    }
+}
+
+impl FromStr for LockReason {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "chargeback" => Ok(LockReason::Chargeback),
+            _ => Err(format!("unknown lock reason {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// What locked an account, for the people reviewing it.
+pub struct Lock {
+    pub reason: LockReason,
+    /// The transaction that caused the lock.
+    pub txn_id: u32,
+    pub timestamp: Timestamp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What operations decided to do with a locked account.
+pub enum Decision {
+    /// Lift the lock, the account processes transactions again.
+    Unlock,
+    /// Leave the account locked, to be reviewed again later.
+    Keep,
+    /// Close the account for good. It stays locked and leaves the queue.
+    Close,
+}
+
+impl Decision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Unlock => "unlock",
+            Decision::Keep => "keep",
+            Decision::Close => "close",
+        }
+    }
+}
+
+impl FromStr for Decision {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "unlock" => Ok(Decision::Unlock),
+            "keep" => Ok(Decision::Keep),
+            "close" => Ok(Decision::Close),
+            _ => Err(format!(
+                "unknown decision {:?}, expected unlock, keep or close",
+                s
+            )),
+        }
+    }
+}
+
+/// Writes the locked accounts that haven't been closed as a csv work
+/// queue, by client. The `decision` column is left empty for reviewers
+/// to fill in, after which the file can be read back by
+/// [`read_decisions`].
+pub fn export_queue(db: &Database, out: impl io::Write) -> Result<usize> {
+    let mut queue = BTreeMap::new();
+    db.for_each_client(|client_id, client| {
+        if client.locked && !client.closed {
+            queue.insert(client_id, (client.lock, client.available, client.held));
+        }
+    })?;
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record([
+        "client",
+        "reason",
+        "tx",
+        "timestamp",
+        "available",
+        "held",
+        "total",
+        "decision",
+    ])?;
+    for (client_id, (lock, available, held)) in &queue {
+        let field = |f: fn(&Lock) -> String| lock.as_ref().map(f).unwrap_or_default();
+        writer.write_record([
+            client_id.to_string(),
+            field(|lock| lock.reason.as_str().to_string()),
+            field(|lock| lock.txn_id.to_string()),
+            field(|lock| lock.timestamp.to_string()),
+            available.to_string(),
+            held.to_string(),
+            (available + held).to_string(),
+            String::new(),
+        ])?;
+    }
+    writer
+        .flush()
+        .map_err(|x| format!("error writing locked accounts: {}", x))?;
+    Ok(queue.len())
+}
+
+/// Reads the `client` and `decision` columns of a reviewed work queue.
+/// Rows without a decision yet are skipped.
+pub fn read_decisions(reader: csv::Reader<impl io::Read>) -> Result<Vec<(ClientId, Decision)>> {
+    let mut reader = reader;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("decisions have no {} column", name))
+    };
+    let (client, decision) = (column("client")?, column("decision")?);
+    let mut decisions = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let decision = record.get(decision).unwrap_or("").trim();
+        if decision.is_empty() {
+            continue;
+        }
+        let client = record
+            .get(client)
+            .unwrap_or("")
+            .trim()
+            .parse::<ClientId>()?;
+        decisions.push((client, decision.parse::<Decision>()?));
+    }
+    Ok(decisions)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+/// How many accounts each decision was applied to.
+pub struct Applied {
+    pub unlocked: u64,
+    pub kept: u64,
+    pub closed: u64,
+}
+
+/// Whether a client is a locked account awaiting review.
+pub fn is_reviewable(db: &Database, client_id: ClientId) -> bool {
+    db.clients
+        .get(&client_id)
+        .is_some_and(|client| client.locked && !client.closed)
+}
+
+/// Applies review decisions in bulk. Every decision is checked first, and
+/// if any of them refers to an account that isn't awaiting review none
+/// are applied.
+pub fn apply_decisions(db: &Database, decisions: &[(ClientId, Decision)]) -> Result<Applied> {
+    for (client_id, _) in decisions {
+        if !is_reviewable(db, *client_id) {
+            return Err(format!(
+                "client {} is not a locked account awaiting review, no decisions were applied",
+                client_id
+            )
+            .into());
+        }
+    }
+    let mut applied = Applied::default();
+    for (client_id, decision) in decisions {
+        if let Some(mut client) = db.clients.get_mut(client_id) {
+            match decision {
+                Decision::Unlock => {
+                    crate::unlock(db, &mut client)?;
+                    applied.unlocked += 1;
+                }
+                Decision::Keep => applied.kept += 1,
+                Decision::Close => {
+                    client.closed = true;
+                    applied.closed += 1;
+                }
+            }
+        }
+    }
+    db.digest.store(state_digest(db)?, Ordering::SeqCst);
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, open_file_read_csv, run_engine, Engine, Transaction};
+
+    #[test]
+    fn reviewed_queue_is_applied_in_bulk() -> Result<()> {
+        let engine = Engine::with_clock(SimulatedClock::new(50));
+        run_engine(
+            open_file_read_csv("test-files/chargeback_dispute.csv".to_string())?,
+            &engine,
+        )?;
+        let mut queue = Vec::new();
+        assert_eq!(export_queue(&engine.db, &mut queue)?, 1);
+        let queue = String::from_utf8(queue).map_err(|x| x.to_string())?;
+        assert_eq!(queue.lines().nth(1), Some("1,chargeback,1,50,2,0,2,"));
+
+        let reviewed = queue.replace("2,0,2,\n", "2,0,2,unlock\n");
+        let decisions = read_decisions(csv::Reader::from_reader(reviewed.as_bytes()))?;
+        assert_eq!(decisions, vec![(1, Decision::Unlock)]);
+        assert!(apply_decisions(&engine.db, &[(2, Decision::Close)]).is_err());
+        assert_eq!(apply_decisions(&engine.db, &decisions)?.unlocked, 1);
+        assert!(!engine.db.clients.get(&1).is_some_and(|c| c.locked));
+        assert_eq!(engine.db.digest(), state_digest(&engine.db)?);
+        assert_eq!(
+            engine.process(Transaction::deposit(1, 9, 1.0))?,
+            crate::TransactionOutcome::Applied
+        );
+        Ok(())
+    }
+}
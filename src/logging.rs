@@ -0,0 +1,237 @@
+use crate::Result;
+use serde_json::{Map, Value};
+use std::{fmt, io::IsTerminal, time::SystemTime};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    filter::LevelFilter,
+    fmt::{
+        format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields, SubscriberBuilder,
+    },
+    registry::LookupSpan,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// How log events are written to stderr.
+pub enum LogFormat {
+    /// A line of text, for people.
+    #[default]
+    Pretty,
+    /// A JSON object per line with the event's fields and those of its
+    /// spans, for log pipelines.
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// The least severe log events written.
+pub enum LogLevel {
+    Error,
+    /// Rejected transactions and skipped rows, and worse.
+    Warn,
+    /// The summary of a run, and worse.
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Writes the log events of the process to stderr from now on. The
+/// library only emits events, embedders install a subscriber of their
+/// own.
+pub fn init(format: LogFormat, level: LogLevel) -> Result<()> {
+    let builder = SubscriberBuilder::default()
+        .with_writer(std::io::stderr)
+        .with_max_level(level);
+    let installed = match format {
+        LogFormat::Pretty => builder
+            .without_time()
+            .with_target(false)
+            .with_ansi(std::io::stderr().is_terminal())
+            .try_init(),
+        LogFormat::Json => builder
+            .event_format(JsonLines)
+            .fmt_fields(JsonFields)
+            .try_init(),
+    };
+    installed.map_err(|x| format!("error setting up logging: {}", x).into())
+}
+
+/// Collects the fields of an event or span into a JSON object.
+struct Fields<'a>(&'a mut Map<String, Value>);
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = format!("{:?}", value);
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+/// Keeps the fields of a span as a JSON object, for [`JsonLines`].
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: tracing_subscriber::field::RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut map = Map::new();
+        fields.record(&mut Fields(&mut map));
+        write!(writer, "{}", Value::Object(map))
+    }
+}
+
+/// Writes each event as a JSON object on a line of its own: when it
+/// happened in seconds since the epoch, its level and message, its fields,
+/// and the spans it happened in, outermost first, each with its name and
+/// fields.
+struct JsonLines;
+
+impl<S, N> FormatEvent<S, N> for JsonLines
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut line = Map::new();
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs());
+        line.insert("timestamp".to_string(), timestamp.into());
+        line.insert("level".to_string(), level(event.metadata().level()).into());
+        line.insert("target".to_string(), event.metadata().target().into());
+        event.record(&mut Fields(&mut line));
+        let spans: Vec<_> = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| {
+                let extensions = span.extensions();
+                let mut fields = extensions
+                    .get::<FormattedFields<N>>()
+                    .and_then(|fields| serde_json::from_str(&fields.fields).ok())
+                    .unwrap_or_else(|| Value::Object(Map::new()));
+                fields["name"] = span.name().into();
+                fields
+            })
+            .collect();
+        if !spans.is_empty() {
+            line.insert("spans".to_string(), spans.into());
+        }
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+fn level(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "error",
+        Level::WARN => "warn",
+        Level::INFO => "info",
+        Level::DEBUG => "debug",
+        Level::TRACE => "trace",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Engine, Transaction};
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            match self.0.lock() {
+                Ok(mut bytes) => bytes.write(buf),
+                Err(_) => Ok(0),
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rejections_are_logged_as_json_with_their_input() -> Result<()> {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = SubscriberBuilder::default()
+            .with_writer(move || writer.clone())
+            .with_max_level(LogLevel::Warn)
+            .event_format(JsonLines)
+            .fmt_fields(JsonFields)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || -> Result<()> {
+            let _input = tracing::error_span!("input", file = "a.csv").entered();
+            let engine = Engine::default();
+            engine.process(Transaction::deposit(1, 1, 2.0))?;
+            engine.process(Transaction::withdrawal(1, 2, 5.0))?;
+            Ok(())
+        })?;
+        let bytes = captured.0.lock().map_err(|_| "capture lock poisoned")?;
+        let lines: Vec<Value> = String::from_utf8_lossy(&bytes)
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|x| x.to_string())?;
+        assert_eq!(lines.len(), 1);
+        let line = &lines[0];
+        assert_eq!(line["level"], "warn");
+        assert_eq!(
+            (&line["client_id"], &line["txn_id"], &line["reason"]),
+            (&1.into(), &2.into(), &"insufficient_funds".into())
+        );
+        assert_eq!(
+            line["spans"][0],
+            serde_json::json!({"name": "input", "file": "a.csv"})
+        );
+        Ok(())
+    }
+}
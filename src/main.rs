@@ -1,405 +1,2116 @@
-use csv::{self, StringRecord};
-use std::{
-    collections::{HashMap, HashSet},
-    fs::File,
-    num::{ParseFloatError, ParseIntError},
-};
-
-type Result<T> = std::result::Result<T, PaymentsEngineError>;
-type FloatResult<T> = std::result::Result<T, ParseFloatError>;
-
-#[derive(Debug)]
-struct PaymentsEngineError(String);
-
-impl From<String> for PaymentsEngineError {
-    fn from(s: String) -> Self {
-        PaymentsEngineError(s)
-    }
-}
-impl From<&str> for PaymentsEngineError {
-    fn from(s: &str) -> Self {
-        PaymentsEngineError(s.to_string())
-    }
-}
-#[derive(Debug, PartialEq)]
-struct Transaction {
-    transaction_type: TransactionType,
-    client_id: u16,
-    txn_id: u32,
-    amount: Option<f64>,
-}
+//! The command line interface of the engine, a thin wrapper over the
+//! `payments_engine` library.
 
-#[derive(Debug, PartialEq)]
-/// This is the type of transaction that is representative of a
-/// single row within the CSV file.
-enum TransactionType {
-    /// A deposit is a credit to the client's account.
-    /// Meaning this should increase the client's balance.
-    Deposit,
-    /// A withdrawal is a debit to the client's account.
-    /// Meaning this should decrease the client's balance.
-    Withdrawal,
-    /// When a client claims the transaction was erroneous,
-    /// and should be flagged to be reverted.
-    /// This will put on hold the client's funds by the amount
-    /// of the referenced transaction.
-    Dispute,
-    /// This happens when a client has a dispute and the dispute
-    /// is resolved (or fails), meaning the funds should be returned to the
-    /// client's account. And no transactions are reverted.
-    /// The transaction must refer to a disputed transaction,
-    /// and not the dispute transaction itself. If the transaction
-    /// is not current disputed, we abort the transaction, and ignore it.
-    Resolve,
-    /// This happens when a client has a dispute and the dispute
-    /// is successful
-    ///
-    /// The transaction must refer to a disputed transaction,
-    /// and not the dispute transaction itself. If the transaction
-    /// is not current disputed, we abort the transaction, and ignore it.
-    ChargeBack,
-}
-
-/// Gets the passed in file name from
-fn get_file_name_from_args() -> Result<String> {
-    std::env::args()
-        .nth(1)
-        .ok_or("Must contain at least one argument".into())
-}
+use clap::{Parser, Subcommand};
+use payments_engine::{
+    account::{self, AccountQuery},
+    admin::{self, AdminLog},
+    amounts::write_histograms,
+    anonymize::{self, random_salt, Anonymize},
+    append::{self, load_previous_runs, save_run, SaveOptions},
+    approvals::{approvals_path, print_approvals, split_decisions, Approvals},
+    audit::verify_capture,
+    backfill::backfill,
+    capture::Capture,
+    clock::{Clock, SimulatedClock, SystemClock, Timestamp},
+    compare::{compare, print_compare, print_reconcile, read_report, reconcile, Reference},
+    determinism::{determinism_audit, print_audit},
+    digest::format_digest,
+    disputes::{
+        age_buckets, auto_chargeback, breaching, open_disputes, print_disputes_report, Aging,
+    },
+    dryrun::{write_impact, DryRun},
+    fees::{write_fees_report, Fees},
+    follow::{Follow, FollowOptions},
+    format::Format,
+    history::write_history,
+    i18n::{message, set_lang, Key, Lang},
+    inputs::{read_inputs, read_inputs_at, InputPosition},
+    journal::Journal,
+    labels::{parse_label, Labels, RunManifest},
+    ledger::{print_trial_balance, trial_balance},
+    limits::{parse_duration, RejectRate, RunLimits, DEFAULT_REJECT_RATE_WINDOW},
+    locked::{apply_decisions, export_queue, read_decisions, Decision},
+    logging::{self, LogFormat, LogLevel},
+    notify::Notifications,
+    open_file_read_csv,
+    opening::{read_opening_balances, OpeningAudit},
+    outbox::{dispatch, last_published},
+    outcomes::{diff_outcomes, print_outcome_diffs, read_outcomes},
+    output::{open_output, write_report, ReportWriter},
+    pay,
+    period::close_period,
+    plugin::ProcessPlugin,
+    policy::{parse_override, LockedAccounts, Overdraft, Policy, PolicyOverride},
+    precision::{self, Precision, Rounding},
+    progress::{Progress, ProgressFormat},
+    query::write_client_details,
+    quota::Quotas,
+    regional_policy,
+    report::{write_selected, ReportOptions, SortOrder},
+    rules::Rules,
+    run_engine, run_transactions,
+    sample::Sample,
+    sar::{sar_extract, write_sar, Patterns},
+    schema::{self, Fields},
+    shape::{generate, write_csv, Shape},
+    simulate::{run_simulation, SimulationConfig},
+    sink::{parse_sink, SinkSpec, Sinks},
+    snapshot::SnapshotOptions,
+    soak,
+    spend::{print_spend_report, spend_report, Period},
+    spill::{self, DirTransactionStore, Spill},
+    state::{export_state, import_state, read_state, rewrite_state, write_state},
+    statement::{statement, statement_history, StatementFormat},
+    summary::{exit_status, JsonSummary, EXIT_FATAL},
+    tiering::{self, DirColdStore, Preload, Tiering},
+    validation::{Consistency, Mode},
+    wal::{recover, Wal},
+    whatif::{print_what_if, what_if},
+    ClientId, Engine, Result, RunOptions, Transaction,
+};
+#[cfg(feature = "server")]
+use payments_engine::{
+    clone::clone_state,
+    dedup::{DedupBy, DedupWindow},
+    handoff::{bind_handoff, take_over},
+    http::serve_metrics,
+    serve::{activated_listener, handle_connection, serve, Handoff, Served},
+};
+use std::{fs::File, process::ExitCode, sync::atomic::Ordering};
+use tracing::{info, warn};
 
-/// Opens a csv and returns a reader
-fn open_file_read_csv(filename: String) -> Result<csv::Reader<File>> {
-    let file = File::open(filename).map_err(|x| format!("error code: {}", x))?;
-    Ok(csv::Reader::from_reader(file))
-}
-impl From<csv::Error> for PaymentsEngineError {
-    fn from(err: csv::Error) -> Self {
-        PaymentsEngineError(format!("{}", err))
-    }
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    group = clap::ArgGroup::new("state_files").multiple(true).args(["append_state", "snapshot_dir"])
+)]
+/// Command line arguments for the engine.
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// The csv files of transactions to process, one after the other as
+    /// if they were one file. `-` reads from standard input.
+    #[arg(required = true)]
+    input: Vec<String>,
+    /// Pins the engine clock to this unix timestamp instead of using
+    /// the system time, so replays are deterministic.
+    #[arg(long, env = "PAYMENTS_ENGINE_CLOCK_START")]
+    clock_start: Option<Timestamp>,
+    /// Records every processed transaction to this file so the run can
+    /// be reproduced later with `replay`.
+    #[arg(long, env = "PAYMENTS_ENGINE_CAPTURE")]
+    capture: Option<String>,
+    /// Appends what became of every transaction, applied or rejected with
+    /// its reason code, to this journal. JSON Lines for a `.jsonl` file or
+    /// with `--format json`, csv otherwise.
+    #[arg(long, env = "PAYMENTS_ENGINE_AUDIT")]
+    audit: Option<String>,
+    /// Stops cleanly after processing this many rows.
+    #[arg(long, env = "PAYMENTS_ENGINE_MAX_ROWS")]
+    max_rows: Option<u64>,
+    /// Stops cleanly once the run has taken this long (e.g. `90s`, `15m`, `2h`).
+    #[arg(long, env = "PAYMENTS_ENGINE_MAX_RUNTIME", value_parser = parse_duration)]
+    max_runtime: Option<std::time::Duration>,
+    /// Aborts the run, saving nothing, once more than this fraction (e.g.
+    /// `0.05`) of the first `--reject-rate-window` rows are rejected, as
+    /// the input is then most likely malformed.
+    #[arg(long, env = "PAYMENTS_ENGINE_MAX_REJECT_RATE")]
+    max_reject_rate: Option<f64>,
+    /// How many rows at the start of the input `--max-reject-rate` counts.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_REJECT_RATE_WINDOW",
+        default_value_t = DEFAULT_REJECT_RATE_WINDOW,
+        requires = "max_reject_rate"
+    )]
+    reject_rate_window: u64,
+    /// Processes only this fraction of clients (e.g. `0.01`), with their
+    /// complete history, for a quick preview of a large file.
+    #[arg(long, env = "PAYMENTS_ENGINE_SAMPLE", conflicts_with = "head")]
+    sample: Option<Sample>,
+    /// Seed deciding which clients `--sample` picks.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_SAMPLE_SEED",
+        default_value_t = 0,
+        requires = "sample"
+    )]
+    sample_seed: u64,
+    /// Applies the rows on this many worker threads, each owning the
+    /// clients whose id modulo N is its own, while the input is read.
+    /// Can't be combined with `--snapshot-every`.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_THREADS",
+        default_value_t = 1,
+        conflicts_with = "snapshot_every",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    threads: u64,
+    /// Processes only the first N rows.
+    #[arg(long, env = "PAYMENTS_ENGINE_HEAD")]
+    head: Option<u64>,
+    /// Reads a field of the transactions from a column of another name,
+    /// as `<field>=<column>`, e.g. `type=operation,client=customer_id`.
+    #[arg(long = "map", env = "PAYMENTS_ENGINE_MAP", value_delimiter = ',', value_parser = schema::parse_mapping)]
+    map: Vec<(String, String)>,
+    /// An extra input column to carry through to the capture file.
+    /// Columns that aren't known or carried are ignored.
+    #[arg(long = "carry-column", env = "PAYMENTS_ENGINE_CARRY_COLUMN")]
+    carry_columns: Vec<String>,
+    /// Memos longer than this many characters are truncated.
+    #[arg(long, env = "PAYMENTS_ENGINE_MEMO_MAX_LEN", default_value_t = schema::DEFAULT_MEMO_MAX_LEN)]
+    memo_max_len: usize,
+    /// How csv rows with another number of fields than the header are
+    /// read: `exact` rejects them, `trailing` reads fields missing at the
+    /// end as empty and ignores trailing commas, `any` also ignores extra
+    /// fields that aren't empty.
+    #[arg(long, env = "PAYMENTS_ENGINE_FIELDS", value_enum, default_value_t = Fields::Trailing)]
+    fields: Fields,
+    /// The decimal places amounts are rounded to, as they are read and in
+    /// the report, up to 8.
+    #[arg(long, env = "PAYMENTS_ENGINE_PRECISION", value_parser = precision::parse_places, default_value_t = precision::DEFAULT_PLACES)]
+    precision: u32,
+    /// How amounts are brought to `--precision` decimal places.
+    #[arg(long, env = "PAYMENTS_ENGINE_ROUNDING", value_enum, default_value_t = Rounding::HalfUp)]
+    rounding: Rounding,
+    /// Lets resolve and chargeback rows carry an amount, which is ignored.
+    /// Without it such a row is invalid. A dispute's amount is the part of
+    /// the transaction it disputes.
+    #[arg(long, env = "PAYMENTS_ENGINE_ALLOW_DISPUTE_AMOUNTS", global = true)]
+    allow_dispute_amounts: bool,
+    /// Makes a dispute, resolve or chargeback of a transaction that isn't
+    /// earlier in the same input file invalid.
+    #[arg(long, env = "PAYMENTS_ENGINE_REQUIRE_INTRA_FILE_REFS", global = true)]
+    require_intra_file_refs: bool,
+    /// What to do with a row that can't be parsed or breaks a rule:
+    /// `lenient` skips it and counts it, `strict` stops the run there with
+    /// its row number.
+    #[arg(long, env = "PAYMENTS_ENGINE_MODE", value_enum, default_value_t = Mode::Lenient)]
+    mode: Mode,
+    /// Lists the rows skipped as they can't be parsed or break a rule in
+    /// this csv file, with their input and row number, instead of on
+    /// stderr.
+    #[arg(long, env = "PAYMENTS_ENGINE_ERRORS")]
+    errors: Option<String>,
+    /// Records what became of each row in this binary file: whether it was
+    /// applied or why not, and a hash of its client's balances after it.
+    /// Compare the files of two runs with `diff-outcomes`.
+    #[arg(long, env = "PAYMENTS_ENGINE_OUTCOMES")]
+    outcomes: Option<String>,
+    /// Reads the inputs and writes the report in this format, instead of
+    /// telling it from the file extension: `.json`, `.jsonl` and `.ndjson`
+    /// files are JSON Lines, anything else (and stdin) csv.
+    #[arg(long, env = "PAYMENTS_ENGINE_FORMAT", value_enum, global = true)]
+    format: Option<Format>,
+    /// Language of user facing messages. Reason codes are the same in
+    /// every language.
+    #[arg(long, env = "PAYMENTS_ENGINE_LANG", value_enum, global = true, default_value_t = Lang::En)]
+    lang: Lang,
+    /// How messages are written to stderr: `pretty` lines for people, or
+    /// `json`, an object per line with the fields of each event, such as
+    /// the client, transaction and reason of a rejection, and the input
+    /// file it was read from.
+    #[arg(long, env = "PAYMENTS_ENGINE_LOG_FORMAT", value_enum, global = true, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+    /// The least severe messages written: rejected transactions and
+    /// skipped rows are `warn`, the summary of a run `info`.
+    #[arg(long, env = "PAYMENTS_ENGINE_LOG_LEVEL", value_enum, global = true, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+    /// Charges back disputes still open this long (e.g. `30d`) after they
+    /// were opened, once the input has been processed. The chargebacks are
+    /// captured with the memo `auto-generated: dispute unresolved`.
+    #[arg(long, env = "PAYMENTS_ENGINE_AUTO_CHARGEBACK_AFTER", value_parser = parse_duration)]
+    auto_chargeback_after: Option<std::time::Duration>,
+    /// Rejects disputes of transactions older than this (e.g. `60d`) by
+    /// their timestamps with `dispute_window_closed`. A window set for the
+    /// client's region by `--regions` applies instead.
+    #[arg(long, env = "PAYMENTS_ENGINE_DISPUTE_WINDOW", value_parser = parse_duration)]
+    dispute_window: Option<std::time::Duration>,
+    /// Ignores rows repeating a transaction already handled instead of
+    /// rejecting them as duplicates, so a partly processed input can be
+    /// run again.
+    #[arg(long, env = "PAYMENTS_ENGINE_IDEMPOTENT")]
+    idempotent: bool,
+    /// Applies the admin rows `unlock`, `credit_adjustment`,
+    /// `debit_adjustment` and `close` instead of rejecting them.
+    #[arg(long, env = "PAYMENTS_ENGINE_ALLOW_ADMIN_OPS")]
+    allow_admin_ops: bool,
+    /// Disputes of deposits hold only the available funds, and hold the
+    /// rest from later deposits rather than leave the account negative.
+    /// The report gains a `pending_hold` column.
+    #[arg(long, env = "PAYMENTS_ENGINE_PENDING_HOLDS")]
+    pending_holds: bool,
+    /// How far withdrawals, transfers, debit adjustments and the holds of
+    /// disputed deposits may take available funds below zero: `deny`,
+    /// `allow` or `allow-with-limit:<amount>`. Without it withdrawals,
+    /// transfers and debits may not and holds may as far as they need. Disputes refused by it are rejected
+    /// as `overdraft_limit`.
+    #[arg(long, env = "PAYMENTS_ENGINE_OVERDRAFT")]
+    overdraft: Option<Overdraft>,
+    /// What locked accounts may still be sent: `reject-all`, the default,
+    /// `settle-disputes` to let resolves and chargebacks of open disputes
+    /// through, or `queue-deposits` to also keep deposits until the
+    /// account is unlocked.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_LOCKED_ACCOUNTS",
+        default_value = "reject-all"
+    )]
+    locked_accounts: LockedAccounts,
+    /// Evicts inactive clients to this directory and loads them back when
+    /// they transact again, keeping only active clients in memory.
+    #[arg(long, env = "PAYMENTS_ENGINE_COLD_DIR")]
+    cold_dir: Option<String>,
+    /// How long a client must go without a transaction before it is evicted
+    /// to `--cold-dir` (e.g. `30m`, `2h`). Defaults to an hour.
+    #[arg(long, env = "PAYMENTS_ENGINE_EVICT_AFTER", value_parser = parse_duration, requires = "cold_dir")]
+    evict_after: Option<std::time::Duration>,
+    /// How many clients of `--cold-dir` to load into memory before
+    /// processing starts.
+    #[arg(long, env = "PAYMENTS_ENGINE_PRELOAD", value_enum, default_value_t = Preload::None, requires = "cold_dir")]
+    preload: Preload,
+    /// Spills the stored transactions beyond `--max-resident-transactions`
+    /// to this directory, least recently stored first, so memory stays
+    /// bounded on huge inputs. Disputes still find spilled transactions,
+    /// reading them back from the directory.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_SPILL_DIR",
+        conflicts_with = "snapshot_every"
+    )]
+    spill_dir: Option<String>,
+    /// How many stored transactions `--spill-dir` keeps in memory.
+    #[arg(long, env = "PAYMENTS_ENGINE_MAX_RESIDENT_TRANSACTIONS", default_value_t = spill::DEFAULT_MAX_RESIDENT, requires = "spill_dir")]
+    max_resident_transactions: usize,
+    /// Continues from the state earlier runs left in this directory and
+    /// saves the new state there, so batch files can be processed one run
+    /// at a time. Transactions reusing the id of one from an earlier run
+    /// are rejected and listed in `conflicts.csv`.
+    #[arg(
+        long,
+        visible_alias = "state-dir",
+        env = "PAYMENTS_ENGINE_APPEND_STATE",
+        conflicts_with = "cold_dir"
+    )]
+    append_state: Option<String>,
+    /// Saves only what each run changed to `--append-state`, as a delta on
+    /// top of the last full snapshot, and writes a new full snapshot once
+    /// this many deltas have piled up.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_REBASELINE_EVERY",
+        requires = "append_state"
+    )]
+    rebaseline_every: Option<usize>,
+    /// Saves the state of the run to `--append-state` only if every row of
+    /// the input was processed: a run that stops early saves nothing. The
+    /// files are staged and moved into place together, so an interrupted
+    /// save leaves no partial state behind either.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_ATOMIC_FILE",
+        requires = "append_state",
+        conflicts_with = "snapshot_every"
+    )]
+    atomic_file: bool,
+    /// Processes the input on top of the state loaded with
+    /// `--append-state` or `--resume-from` without saving anything, and
+    /// writes what it would change as JSON in place of the report: the
+    /// balance deltas of every account that would change, the accounts
+    /// that would lock and the transactions that would be rejected. Can't
+    /// be combined with the options that record what a run did, such as
+    /// `--audit` or `--manifest`, as nothing is done for real.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_DRY_RUN",
+        conflicts_with_all = [
+            "wal", "cold_dir", "spill_dir", "snapshot_every", "events", "follow", "sinks",
+            "audit", "capture", "outcomes", "notifications", "manifest", "fees_report"
+        ]
+    )]
+    dry_run: bool,
+    /// Compresses the state files of `--append-state` and the snapshots of
+    /// `--snapshot-dir` with zstd at this level, from 1 (fastest) to 19
+    /// (smallest). Compressed files are read back whatever this is set to.
+    #[arg(long, env = "PAYMENTS_ENGINE_COMPRESS", requires = "state_files", value_parser = clap::value_parser!(u32).range(1..=19))]
+    compress: Option<u32>,
+    /// Snapshots the state every this many rows to `--snapshot-dir`. Each
+    /// snapshot is taken on a background thread while processing goes on,
+    /// and holds the state exactly as it was after its row. Can't be
+    /// combined with `--cold-dir`, evicted clients are not snapshotted.
+    #[arg(
+        long, env = "PAYMENTS_ENGINE_SNAPSHOT_EVERY",
+        alias = "checkpoint-every",
+        requires = "snapshot_dir",
+        conflicts_with = "cold_dir",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    snapshot_every: Option<u64>,
+    /// Where `--snapshot-every` writes its snapshots, as
+    /// `snapshot-<rows>.json`.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_SNAPSHOT_DIR",
+        requires = "snapshot_every"
+    )]
+    snapshot_dir: Option<String>,
+    /// Restores the state from a snapshot of `--snapshot-dir` and carries
+    /// on with the rows of the input after it, for a run that was
+    /// interrupted. The input must be the same as the run's.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_RESUME_FROM",
+        conflicts_with = "append_state"
+    )]
+    resume_from: Option<String>,
+    /// Appends every transaction to this write-ahead log before applying
+    /// it, so a run killed midway can be started again with the same
+    /// arguments: the transactions of the log are replayed, on top of the
+    /// snapshot of `--resume-from` if given, and the input carries on
+    /// after the last row they came from. The log is emptied once the run
+    /// is over and reported.
+    #[arg(long, env = "PAYMENTS_ENGINE_WAL")]
+    wal: Option<String>,
+    /// Publishes an event for every applied transaction to this file, one
+    /// JSON line each. The events are saved with the state of
+    /// `--append-state` and published once it is saved, exactly once.
+    #[arg(long, env = "PAYMENTS_ENGINE_EVENTS", requires = "append_state")]
+    events: Option<String>,
+    /// Writes the notices owed to clients for the disputes, resolutions
+    /// and chargebacks of the run to this csv, one row each with the
+    /// client, the template and its payload as JSON.
+    #[arg(long, env = "PAYMENTS_ENGINE_NOTIFICATIONS")]
+    notifications: Option<String>,
+    /// Per region rule sets (withdrawal limits, dispute windows, currency)
+    /// as JSON, applied to each client by its country.
+    #[arg(long, env = "PAYMENTS_ENGINE_REGIONS", global = true)]
+    regions: Option<String>,
+    /// A csv with the `client` and, for `--regions`, the `country` or, for
+    /// `--quotas`, the `tenant` or, for `--fees`, the `tier` of each
+    /// client. Clients not in it get the default rule set and fees and
+    /// aren't held to any quota. Optional
+    /// `low_balance` and `large_transaction` columns set thresholds clients
+    /// are sent `--notifications` of.
+    #[arg(long, env = "PAYMENTS_ENGINE_CLIENT_METADATA", global = true)]
+    client_metadata: Option<String>,
+    /// Per tenant quotas (max accounts, max stored transactions, max
+    /// transactions per second) as JSON. Transactions over a quota are
+    /// rejected with `quota_exceeded`. Needs `--client-metadata` with a
+    /// `tenant` column.
+    #[arg(long, env = "PAYMENTS_ENGINE_QUOTAS", global = true)]
+    quotas: Option<String>,
+    /// Fees charged on deposits and withdrawals, flat and/or a percentage
+    /// of the amount, as JSON: a `default` schedule and per client `tiers`
+    /// overriding it, which need `--client-metadata` with a `tier` column.
+    /// Chargebacks give the fee back.
+    #[arg(long, env = "PAYMENTS_ENGINE_FEES")]
+    fees: Option<String>,
+    /// Writes the fees each client was charged, had reversed and paid in
+    /// total to this csv after the run.
+    #[arg(long, env = "PAYMENTS_ENGINE_FEES_REPORT")]
+    fees_report: Option<String>,
+    /// Who is performing an admin operation (`import-accounts`,
+    /// `close-period`, `locked import-decisions`). Required by them, and
+    /// recorded with each in `--admin-log`.
+    #[arg(long, env = "PAYMENTS_ENGINE_OPERATOR", global = true)]
+    operator: Option<String>,
+    /// The append-only log admin operations are recorded in.
+    #[arg(long, env = "PAYMENTS_ENGINE_ADMIN_LOG", global = true, default_value = admin::DEFAULT_ADMIN_LOG)]
+    admin_log: String,
+    /// Tags the run with `key=value`, e.g. `--label env=staging`, in its
+    /// manifest, its admin log entries and its metrics. Repeatable.
+    #[arg(long = "label", env = "PAYMENTS_ENGINE_LABEL", global = true, value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+    /// Writes a JSON manifest of the run to this file once it is done: its
+    /// inputs, what became of their rows, the final state digest and the
+    /// labels.
+    #[arg(long, env = "PAYMENTS_ENGINE_MANIFEST")]
+    manifest: Option<String>,
+    /// Writes a JSON summary of the run to this file once it is done: the
+    /// exit code, what became of the rows, rejections by reason, locked
+    /// accounts and how long the run took. The process exits with 0 if
+    /// every row was applied, 1 if rows were rejected or skipped or a limit
+    /// stopped the run, and 2 if it failed, a panic included.
+    #[arg(long, env = "PAYMENTS_ENGINE_SUMMARY_JSON")]
+    summary_json: Option<String>,
+    /// Runs this command (with `sh -c`) as a plugin that allows, denies or
+    /// modifies every transaction before it is processed. Plugins run in
+    /// the order they are given.
+    #[arg(long = "plugin", env = "PAYMENTS_ENGINE_PLUGIN")]
+    plugins: Vec<String>,
+    /// A file of rules such as `when type == withdrawal && amount > 10000
+    /// then reject "LimitExceeded"`, checked before any `--plugin`.
+    #[arg(long, env = "PAYMENTS_ENGINE_RULES")]
+    rules: Option<String>,
+    /// Writes the statistical profile of the run's input (transaction mix,
+    /// amount distributions) to this JSON file, for `generate
+    /// --from-profile`. No transaction or client id is written to it.
+    #[arg(long, env = "PAYMENTS_ENGINE_EXPORT_PROFILE")]
+    export_profile: Option<String>,
+    /// Where to write the report of the accounts, stdout if not given.
+    #[arg(long, env = "PAYMENTS_ENGINE_OUTPUT", short)]
+    output: Option<String>,
+    /// Keeps the input open once every row was read, like `tail -f`, and
+    /// processes rows as they are appended, following the file when it is
+    /// rotated. The report is rewritten to `--output` every
+    /// `--flush-every` meanwhile. Takes a single csv file.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_FOLLOW",
+        requires = "output",
+        conflicts_with = "resume_from"
+    )]
+    follow: bool,
+    /// How often `--follow` rewrites the report.
+    #[arg(long, env = "PAYMENTS_ENGINE_FLUSH_EVERY", value_parser = parse_duration, default_value = "10s", requires = "follow")]
+    flush_every: std::time::Duration,
+    /// Stops `--follow` once no row was appended for this long.
+    #[arg(long, env = "PAYMENTS_ENGINE_FOLLOW_IDLE_EXIT", value_parser = parse_duration, requires = "follow")]
+    follow_idle_exit: Option<std::time::Duration>,
+    /// Writes the report to this sink instead, `<format>:<target>` with
+    /// format `csv` or `json` and target a file, `-` for stdout, or `|`
+    /// and a command fed the report, e.g. `json:|aws s3 cp - s3://b/r.jsonl`.
+    /// Repeat it to write the report to several sinks at once.
+    #[arg(long = "sink", env = "PAYMENTS_ENGINE_SINK", value_parser = parse_sink, conflicts_with = "output")]
+    sinks: Vec<SinkSpec>,
+    /// Ends the report with the available, held and total funds of every
+    /// account summed per currency, checked against the engine's ledger.
+    #[arg(long, env = "PAYMENTS_ENGINE_TOTALS")]
+    totals: bool,
+    /// The order of the accounts in the report: by `client` id, or by
+    /// `total`, largest first.
+    #[arg(long, env = "PAYMENTS_ENGINE_SORT", value_enum, default_value_t = SortOrder::Client)]
+    sort: SortOrder,
+    /// Reports locked accounts only.
+    #[arg(long, env = "PAYMENTS_ENGINE_ONLY_LOCKED")]
+    only_locked: bool,
+    /// Reports these clients only, e.g. `1,5,9`.
+    #[arg(long, env = "PAYMENTS_ENGINE_CLIENTS", value_delimiter = ',')]
+    clients: Vec<ClientId>,
+    /// Reports accounts with a total of at least this only.
+    #[arg(long, env = "PAYMENTS_ENGINE_MIN_TOTAL")]
+    min_total: Option<f64>,
+    /// Accounts without a transaction for this long (e.g. `90d`) are
+    /// dormant: they are left out of the report, and evicted to
+    /// `--cold-dir` if given, even before `--evict-after`.
+    #[arg(long, env = "PAYMENTS_ENGINE_DORMANT_AFTER", value_parser = parse_duration)]
+    dormant_after: Option<std::time::Duration>,
+    /// Reports dormant accounts too.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_INCLUDE_DORMANT",
+        requires = "dormant_after"
+    )]
+    include_dormant: bool,
+    /// Prints the engine's metrics to stderr after the run, including the
+    /// minimum, median, 95th percentile and maximum amount of each
+    /// transaction type.
+    #[arg(long, env = "PAYMENTS_ENGINE_METRICS")]
+    metrics: bool,
+    /// Prints a table of the run to stderr at its end: the transactions
+    /// of each type handled and applied, the rejections by reason, the
+    /// disputes opened, resolved and charged back, and the rows per
+    /// second.
+    #[arg(long, env = "PAYMENTS_ENGINE_STATS")]
+    stats: bool,
+    /// Checks the accounts each transaction touched against the engine's
+    /// invariants after it is applied, and every account and the ledger
+    /// at the end of the run. Stops at the first transaction breaking one.
+    #[arg(long, env = "PAYMENTS_ENGINE_CHECK_INVARIANTS")]
+    check_invariants: bool,
+    /// Reports progress (rows, rejections, rate, offset in the input and
+    /// time left) to stderr every `--progress-interval`, as text or as
+    /// JSON lines.
+    #[arg(long, env = "PAYMENTS_ENGINE_PROGRESS_FORMAT")]
+    progress_format: Option<ProgressFormat>,
+    /// How often to report progress.
+    #[arg(long, env = "PAYMENTS_ENGINE_PROGRESS_INTERVAL", value_parser = parse_duration, default_value = "10s")]
+    progress_interval: std::time::Duration,
+    /// Sends progress to the Unix socket at this path instead of stderr.
+    #[arg(
+        long,
+        env = "PAYMENTS_ENGINE_PROGRESS_SOCKET",
+        requires = "progress_format"
+    )]
+    progress_socket: Option<String>,
+    /// Writes a histogram of the amounts of each transaction type to this
+    /// csv, with log scale buckets, to spot amounts in the wrong unit.
+    #[arg(long, env = "PAYMENTS_ENGINE_AMOUNT_HISTOGRAM")]
+    amount_histogram: Option<String>,
+    /// Writes how long each stage of the run took to this file, as folded
+    /// stacks for flamegraph tools.
+    #[cfg(feature = "profiling")]
+    #[arg(long, env = "PAYMENTS_ENGINE_PROFILE")]
+    profile: Option<String>,
 }
 
-impl From<ParseIntError> for PaymentsEngineError {
-    fn from(err: ParseIntError) -> Self {
-        PaymentsEngineError(format!("{}", err))
-    }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Runs a deterministic randomized scenario, checking the engine's
+    /// invariants after every transaction.
+    Simulate {
+        /// Seed for the data generator. The same seed always replays
+        /// the same scenario.
+        #[arg(long, env = "PAYMENTS_ENGINE_SEED", default_value_t = 0)]
+        seed: u64,
+        /// Number of simulated days.
+        #[arg(long, env = "PAYMENTS_ENGINE_DAYS", default_value_t = 30)]
+        days: u32,
+        /// Number of distinct clients.
+        #[arg(long, env = "PAYMENTS_ENGINE_CLIENTS", default_value_t = 100)]
+        clients: ClientId,
+        /// Transactions generated per simulated day.
+        #[arg(long, env = "PAYMENTS_ENGINE_PER_DAY", default_value_t = 1_000)]
+        per_day: u32,
+    },
+    /// Drives generated transactions through the engine at a steady rate,
+    /// reporting latency percentiles and memory as it goes. Fails if an
+    /// invariant breaks or p99 latency goes over `--max-p99`.
+    Soak {
+        /// Transactions per second, e.g. `50k/s`.
+        #[arg(long, env = "PAYMENTS_ENGINE_RATE", value_parser = soak::parse_rate, default_value = "10k/s")]
+        rate: u64,
+        /// How long to run for, e.g. `1h`.
+        #[arg(long, env = "PAYMENTS_ENGINE_DURATION", value_parser = parse_duration, default_value = "1m")]
+        duration: std::time::Duration,
+        /// How often to report progress.
+        #[arg(long, env = "PAYMENTS_ENGINE_INTERVAL", value_parser = parse_duration, default_value = "10s")]
+        interval: std::time::Duration,
+        /// Fails once an interval's p99 latency is above this many
+        /// microseconds.
+        #[arg(long, env = "PAYMENTS_ENGINE_MAX_P99_US")]
+        max_p99_us: Option<u64>,
+        /// Seed for the data generator.
+        #[arg(long, env = "PAYMENTS_ENGINE_SEED", default_value_t = 0)]
+        seed: u64,
+        /// Number of distinct clients.
+        #[arg(long, env = "PAYMENTS_ENGINE_CLIENTS", default_value_t = 10_000)]
+        clients: ClientId,
+        /// Evicts inactive clients to this directory, to include the cold
+        /// store in the run.
+        #[arg(long, env = "PAYMENTS_ENGINE_COLD_DIR")]
+        cold_dir: Option<String>,
+    },
+    /// Generates fake transactions shaped like a profile written with
+    /// `--export-profile`, as csv.
+    Generate {
+        /// The profile of the data to imitate.
+        #[arg(long, env = "PAYMENTS_ENGINE_FROM_PROFILE")]
+        from_profile: String,
+        /// How many transactions to generate, as many as were profiled if
+        /// not given.
+        #[arg(long, env = "PAYMENTS_ENGINE_ROWS")]
+        rows: Option<u64>,
+        /// Seed for the data generator. The same seed and profile always
+        /// generate the same transactions.
+        #[arg(long, env = "PAYMENTS_ENGINE_SEED", default_value_t = 0)]
+        seed: u64,
+        /// Where to write the transactions, stdout if not given.
+        #[arg(long, env = "PAYMENTS_ENGINE_OUTPUT", short)]
+        output: Option<String>,
+    },
+    /// Checks input files without processing them, listing every row that
+    /// can't be parsed or breaks a consistency rule. Fails if any does.
+    Validate {
+        /// The csv files of transactions to check, `-` for standard input.
+        #[arg(required = true)]
+        inputs: Vec<String>,
+    },
+    /// Compiles a csv file into a binary `.pay` file, which the default
+    /// command and `replay` read without parsing text.
+    Compile {
+        /// The csv file of transactions.
+        input: String,
+        /// Where to write the compiled file, conventionally `*.pay`.
+        output: String,
+    },
+    /// Replays a file recorded with `--capture`, using the recorded
+    /// timestamps instead of the system clock.
+    Replay {
+        /// The capture file to replay.
+        capture: String,
+        /// Replays under a different policy, e.g.
+        /// `--override dispute_policy=deposits-only`, and prints how each
+        /// client's account would differ instead of the report.
+        #[arg(long = "override", env = "PAYMENTS_ENGINE_OVERRIDE", value_parser = parse_override)]
+        overrides: Vec<PolicyOverride>,
+    },
+    /// Replays a file recorded with `--capture` and checks that the state
+    /// after every entry matches the digest recorded by the original run.
+    AuditVerify {
+        /// The capture file to verify.
+        capture: String,
+    },
+    /// Replays a capture with late transactions inserted where they
+    /// belong, and prints the accounts that would change, for approval.
+    Backfill {
+        /// The capture file recorded with `--capture`.
+        capture: String,
+        /// The csv file of late transactions.
+        corrections: String,
+        /// The entry of the capture, counted from 1, the corrections go
+        /// before.
+        #[arg(long, env = "PAYMENTS_ENGINE_BEFORE_SEQ")]
+        before_seq: usize,
+        /// Writes the capture with the corrections merged in to this file.
+        #[arg(long, env = "PAYMENTS_ENGINE_COMMIT")]
+        commit: Option<String>,
+    },
+    /// Summarizes the withdrawals in a file by client and category.
+    SpendReport {
+        /// The csv file of transactions, with a `category` or `merchant` column.
+        input: String,
+        /// Only count withdrawals at or after this unix timestamp.
+        #[arg(long, env = "PAYMENTS_ENGINE_FROM")]
+        from: Option<Timestamp>,
+        /// Only count withdrawals before this unix timestamp.
+        #[arg(long, env = "PAYMENTS_ENGINE_TO")]
+        to: Option<Timestamp>,
+    },
+    /// Writes the statement of one client, the transactions of a file that
+    /// moved its funds, for personal finance tools to import, or as csv
+    /// every transaction applied to it with the running balance.
+    Statement {
+        /// The csv file of transactions.
+        input: String,
+        #[arg(long, env = "PAYMENTS_ENGINE_CLIENT")]
+        client: ClientId,
+        /// The file format, `--format` being the one of the input.
+        #[arg(
+            long,
+            env = "PAYMENTS_ENGINE_STATEMENT_FORMAT",
+            value_enum,
+            default_value = "ofx"
+        )]
+        statement_format: StatementFormat,
+        /// Only list transactions at or after this unix timestamp.
+        #[arg(long, env = "PAYMENTS_ENGINE_FROM")]
+        from: Option<Timestamp>,
+        /// Only list transactions before this unix timestamp.
+        #[arg(long, env = "PAYMENTS_ENGINE_TO")]
+        to: Option<Timestamp>,
+        /// The currency of the amounts, for OFX.
+        #[arg(long, env = "PAYMENTS_ENGINE_CURRENCY", default_value = "USD")]
+        currency: String,
+        /// Where to write the statement, stdout if not given.
+        #[arg(long, env = "PAYMENTS_ENGINE_OUTPUT", short)]
+        output: Option<String>,
+    },
+    /// Posts the transactions of a file to a double-entry ledger and prints
+    /// the debits and credits of each ledger account, failing if the books
+    /// don't balance.
+    TrialBalance {
+        /// The csv file of transactions to process.
+        input: String,
+        /// Only post transactions at or after this unix timestamp.
+        #[arg(long, env = "PAYMENTS_ENGINE_FROM")]
+        from: Option<Timestamp>,
+        /// Only post transactions before this unix timestamp.
+        #[arg(long, env = "PAYMENTS_ENGINE_TO")]
+        to: Option<Timestamp>,
+    },
+    /// Runs a file through the engine several times with different thread
+    /// counts, map shardings and client orders, and checks every run ends
+    /// in the same state. Fails if any doesn't.
+    DeterminismAudit {
+        /// The csv file of transactions to process.
+        input: String,
+        /// How many times to run it, the first run being the baseline.
+        #[arg(long, env = "PAYMENTS_ENGINE_RUNS", default_value_t = 8, value_parser = clap::value_parser!(u64).range(2..))]
+        runs: u64,
+    },
+    /// Runs a file through this engine and a reference, and lists the
+    /// accounts whose balances or lock differ. Fails if any do.
+    Compare {
+        /// The csv file of transactions to process.
+        input: String,
+        /// A program printing its report for the input, run as
+        /// `sh -c '<reference> <input>'`, e.g. another implementation or an
+        /// older build.
+        #[arg(
+            long,
+            env = "PAYMENTS_ENGINE_REFERENCE",
+            required_unless_present = "reference_report",
+            conflicts_with = "reference_report"
+        )]
+        reference: Option<String>,
+        /// A report saved from an earlier run to compare against instead.
+        #[arg(long, env = "PAYMENTS_ENGINE_REFERENCE_REPORT")]
+        reference_report: Option<String>,
+    },
+    /// Lists the differences between two account reports, such as this
+    /// engine's and an export of the ledger it replaces: per client, the
+    /// balances more than `--tolerance` apart, a differing lock, and
+    /// clients only one of them has. Fails if there are any.
+    Reconcile {
+        /// The csv report on the left.
+        left: String,
+        /// The csv report on the right.
+        right: String,
+        /// How far apart balances may be and still agree.
+        #[arg(long, env = "PAYMENTS_ENGINE_TOLERANCE", default_value_t = 0.0)]
+        tolerance: f64,
+    },
+    /// Lists the rows two runs over the same input recorded differently
+    /// with `--outcomes`, by status or by their client's balances after
+    /// them. Fails if any differ.
+    DiffOutcomes {
+        /// The outcomes file of the first run.
+        ours: String,
+        /// The outcomes file of the run to compare it with.
+        theirs: String,
+    },
+    /// Serves transactions over TCP: each connection sends csv rows, header
+    /// first, and gets an answer line per row. HTTP requests on the same
+    /// port submit transactions and query balances and the report. Takes
+    /// the listening socket from systemd when socket activated.
+    #[cfg(feature = "server")]
+    Serve {
+        /// The address to listen on, e.g. `127.0.0.1:7070`. Not needed when
+        /// socket activated.
+        #[arg(long, env = "PAYMENTS_ENGINE_LISTEN")]
+        listen: Option<String>,
+        /// Serves a single connection on stdin and stdout instead, as
+        /// started by inetd.
+        #[arg(long, env = "PAYMENTS_ENGINE_STDIO", conflicts_with = "listen")]
+        stdio: bool,
+        /// Exits once no connection has been open for this long, e.g. `10m`.
+        #[arg(long, env = "PAYMENTS_ENGINE_IDLE_EXIT", value_parser = parse_duration)]
+        idle_exit: Option<std::time::Duration>,
+        /// Continues from the state of this `--append-state` directory and
+        /// saves the state back there on exit.
+        #[arg(long, env = "PAYMENTS_ENGINE_STATE")]
+        state: Option<String>,
+        /// Answers a transaction repeating one submitted within this long
+        /// before, e.g. `30s`, as a duplicate without applying it, for
+        /// upstreams that retry with new transaction ids.
+        #[arg(long, env = "PAYMENTS_ENGINE_DEDUP_WINDOW", value_parser = parse_duration)]
+        dedup_window: Option<std::time::Duration>,
+        /// What makes a transaction a repeat within `--dedup-window`.
+        #[arg(
+            long,
+            env = "PAYMENTS_ENGINE_DEDUP_BY",
+            value_enum,
+            default_value_t = DedupBy::Key,
+            requires = "dedup_window"
+        )]
+        dedup_by: DedupBy,
+        /// Listens on this Unix socket for a new process to hand over to,
+        /// see `--takeover`.
+        #[arg(long, env = "PAYMENTS_ENGINE_HANDOFF", conflicts_with = "stdio")]
+        handoff: Option<String>,
+        /// Takes over from the engine serving with `--handoff` at this
+        /// socket: its state, its open connections, and the socket it
+        /// listens on, so an upgrade turns no transaction away. Linux only.
+        #[arg(
+            long,
+            env = "PAYMENTS_ENGINE_TAKEOVER",
+            conflicts_with_all = ["listen", "stdio"]
+        )]
+        takeover: Option<String>,
+        /// Serves the engine's metrics for Prometheus to scrape at
+        /// `/metrics` on this address, e.g. `0.0.0.0:9100`.
+        #[arg(long, env = "PAYMENTS_ENGINE_METRICS_LISTEN")]
+        metrics_listen: Option<String>,
+        /// Appends every transaction to this write-ahead log before
+        /// applying it. Started again after being killed, the transactions
+        /// of the log are replayed on top of the state of `--state` before
+        /// serving. The log is emptied once the state is saved on exit, or
+        /// started afresh when taking over, the state taken over holding
+        /// them already.
+        #[arg(long, env = "PAYMENTS_ENGINE_WAL")]
+        wal: Option<String>,
+    },
+    /// Copies the state of an engine running `serve` into a new state
+    /// directory, to start a replica or a staging environment from. Run it
+    /// again after an interruption to resume the transfer.
+    #[cfg(feature = "server")]
+    CloneState {
+        /// The address the source engine serves on, e.g.
+        /// `other-engine:7070`.
+        #[arg(long, env = "PAYMENTS_ENGINE_FROM")]
+        from: String,
+        /// The directory to write the state to, for `serve --state` or
+        /// `--append-state`. It must not hold a state yet.
+        #[arg(long, env = "PAYMENTS_ENGINE_TO")]
+        to: String,
+    },
+    /// Processes a file and extracts the transactions matching suspicious
+    /// patterns, in the fixed layout filed with the regulator.
+    SarExtract {
+        /// The csv file of transactions to process.
+        input: String,
+        /// A JSON file of the patterns to look for and their settings. All
+        /// patterns are looked for with default settings without one.
+        #[arg(long, env = "PAYMENTS_ENGINE_PATTERNS")]
+        patterns: Option<String>,
+        /// Where to write the extract, stdout if not given.
+        #[arg(short, long, env = "PAYMENTS_ENGINE_OUTPUT")]
+        output: Option<String>,
+    },
+    /// Processes a file and buckets the disputes still open at the end by age.
+    DisputesReport {
+        /// The csv file of transactions to process.
+        input: String,
+        /// Warns about every dispute open for longer than this, e.g. `30d`.
+        #[arg(long, env = "PAYMENTS_ENGINE_SLA", value_parser = parse_duration)]
+        sla: Option<std::time::Duration>,
+        /// The unix timestamp disputes are aged to, by default the latest
+        /// timestamp in the input.
+        #[arg(long, env = "PAYMENTS_ENGINE_NOW")]
+        now: Option<Timestamp>,
+        /// Whether ages count every day, or only business days in the
+        /// calendar of each client's region.
+        #[arg(long, env = "PAYMENTS_ENGINE_AGING", value_enum, default_value_t = Aging::Calendar)]
+        aging: Aging,
+    },
+    /// Processes a file and lists its accounts a page at a time, in client
+    /// order. Prints the cursor of the next page to stderr if there is one.
+    Accounts {
+        /// The csv file of transactions to process.
+        input: String,
+        /// Lists the page after this cursor, as printed after the last one.
+        #[arg(long, env = "PAYMENTS_ENGINE_AFTER")]
+        after: Option<ClientId>,
+        /// The most accounts a page holds.
+        #[arg(long, env = "PAYMENTS_ENGINE_LIMIT", default_value_t = account::DEFAULT_PAGE_SIZE)]
+        limit: usize,
+        /// Only lists locked accounts.
+        #[arg(long, env = "PAYMENTS_ENGINE_LOCKED")]
+        locked: bool,
+        /// Only lists accounts with open disputes.
+        #[arg(long, env = "PAYMENTS_ENGINE_DISPUTED")]
+        disputed: bool,
+        /// Only lists accounts whose total is at least this.
+        #[arg(long, env = "PAYMENTS_ENGINE_MIN_BALANCE")]
+        min_balance: Option<f64>,
+    },
+    /// Prints one client's account, open disputes and transaction history,
+    /// after processing a file, continuing from a state directory, or both.
+    Query {
+        /// The client to show.
+        #[arg(long, env = "PAYMENTS_ENGINE_CLIENT")]
+        client: ClientId,
+        /// A csv file of transactions to process first.
+        #[arg(required_unless_present = "state")]
+        input: Option<String>,
+        /// Continues from the state of this `--append-state` directory,
+        /// which is left as it was.
+        #[arg(long, env = "PAYMENTS_ENGINE_STATE")]
+        state: Option<String>,
+    },
+    /// Seeds accounts with opening balances migrated from another system
+    /// and writes the resulting state as canonical JSON, for
+    /// `import-state` or `--append-state` to process transactions on top of.
+    ImportAccounts {
+        /// A csv file with `client` and `available` columns, and optionally
+        /// `held` and `locked`.
+        accounts: String,
+        /// Where to write the state, stdout if not given.
+        #[arg(long, env = "PAYMENTS_ENGINE_OUTPUT", short)]
+        output: Option<String>,
+        /// Records an opening-balance entry for every seeded account to
+        /// this file, with the state digest after it.
+        #[arg(long, env = "PAYMENTS_ENGINE_AUDIT")]
+        audit: Option<String>,
+    },
+    /// Closes the current accounting period of an `--append-state`
+    /// directory: archives its closing balances and transactions under
+    /// `periods/<period>/` and starts the next period from those balances.
+    ClosePeriod {
+        /// The `--append-state` directory.
+        state: String,
+        /// The name of the period being closed, e.g. `2024-05`.
+        period: String,
+    },
+    /// Processes a file and writes the resulting state as canonical JSON.
+    ExportState {
+        /// The csv file of transactions to process.
+        input: String,
+        /// Where to write the state, stdout if not given.
+        #[arg(long, env = "PAYMENTS_ENGINE_OUTPUT", short)]
+        output: Option<String>,
+        /// Replaces client ids with salted pseudonyms and drops memos, so
+        /// the state can be shared outside the company.
+        #[arg(long, env = "PAYMENTS_ENGINE_ANONYMIZE")]
+        anonymize: bool,
+        /// The salt of the pseudonyms. A random one, never written
+        /// anywhere, is used if not given; give one to get the same
+        /// pseudonyms across exports.
+        #[arg(long, env = "PAYMENTS_ENGINE_SALT", requires = "anonymize")]
+        salt: Option<String>,
+        /// Scales amounts by a random factor within this fraction of 1,
+        /// e.g. `0.05` for ±5%.
+        #[arg(long, env = "PAYMENTS_ENGINE_PERTURB", requires = "anonymize")]
+        perturb: Option<f64>,
+        /// Rounds amounts to a multiple of this, e.g. `10`.
+        #[arg(long, env = "PAYMENTS_ENGINE_BUCKET", requires = "anonymize")]
+        bucket: Option<f64>,
+    },
+    /// Loads a state written by `export-state`, optionally processes more
+    /// transactions on top of it, and prints the report.
+    ImportState {
+        /// The JSON state file.
+        state: String,
+        /// A csv file of transactions to process after loading the state.
+        input: Option<String>,
+    },
+    /// Reviews locked accounts.
+    Locked {
+        #[command(subcommand)]
+        command: LockedCommand,
+    },
+    /// Prints a shell completion script, e.g.
+    /// `payments-engine completions bash > /etc/bash_completion.d/payments-engine`.
+    #[cfg(feature = "completions")]
+    Completions {
+        /// The shell to generate completions for.
+        shell: clap_complete::Shell,
+    },
 }
 
-impl From<ParseFloatError> for PaymentsEngineError {
-    fn from(err: ParseFloatError) -> Self {
-        PaymentsEngineError(format!("{}", err))
-    }
+#[derive(Subcommand, Debug)]
+enum LockedCommand {
+    /// Writes the locked accounts awaiting review as a csv work queue, with
+    /// an empty `decision` column to fill in with unlock, keep or close.
+    Export {
+        /// The state file, as written by `export-state` or `--append-state`.
+        #[arg(long, env = "PAYMENTS_ENGINE_STATE")]
+        state: String,
+        /// Where to write the queue, stdout if not given.
+        #[arg(long, env = "PAYMENTS_ENGINE_OUTPUT", short)]
+        output: Option<String>,
+    },
+    /// Applies the decisions of a reviewed work queue to the state file.
+    ImportDecisions {
+        /// The state file, which is updated in place.
+        #[arg(long, env = "PAYMENTS_ENGINE_STATE")]
+        state: String,
+        /// The reviewed queue, with `client` and `decision` columns.
+        decisions: String,
+        /// Unlocks of accounts holding more than this total aren't applied
+        /// but wait for a second operator to `approve` them.
+        #[arg(long, env = "PAYMENTS_ENGINE_APPROVAL_THRESHOLD")]
+        approval_threshold: Option<f64>,
+        /// How long an unlock waits for approval before it expires, e.g.
+        /// `48h`.
+        #[arg(long, env = "PAYMENTS_ENGINE_APPROVAL_TTL", value_parser = parse_duration, default_value = "7d")]
+        approval_ttl: std::time::Duration,
+    },
+    /// Lists the unlocks waiting for approval.
+    Approvals {
+        /// The state file the unlocks were requested for.
+        #[arg(long, env = "PAYMENTS_ENGINE_STATE")]
+        state: String,
+    },
+    /// Approves a pending unlock and applies it to the state file. The
+    /// operator must not be the one who requested it.
+    Approve {
+        /// The state file, which is updated in place.
+        #[arg(long, env = "PAYMENTS_ENGINE_STATE")]
+        state: String,
+        /// The id of the unlock, as listed by `approvals`.
+        id: u64,
+    },
+    /// Drops the pending unlocks that have expired.
+    Expire {
+        /// The state file the unlocks were requested for.
+        #[arg(long, env = "PAYMENTS_ENGINE_STATE")]
+        state: String,
+    },
 }
 
-impl TryFrom<&StringRecord> for Transaction {
-    type Error = PaymentsEngineError;
-    fn try_from(record: &StringRecord) -> Result<Self> {
-        Ok(Transaction {
-            transaction_type: record.try_into()?,
-            client_id: record[1].replace(" ", "").parse::<u16>()?,
-            txn_id: record[2].replace(" ", "").parse::<u32>()?,
-            amount: record
-                .get(3)
-                .as_ref()
-                .map_or::<FloatResult<_>, _>(Ok(None), |x| match x.replace(" ", "").as_str() {
-                    "" => Ok(None),
-                    x => Ok(Some(x.parse::<f64>()?)),
-                })?,
-        })
-    }
-}
-
-impl TryFrom<&StringRecord> for TransactionType {
-    type Error = PaymentsEngineError;
-    fn try_from(record: &StringRecord) -> Result<Self> {
-        Ok(match record.get(0) {
-            Some("deposit") => TransactionType::Deposit,
-            Some("withdrawal") => TransactionType::Withdrawal,
-            Some("dispute") => TransactionType::Dispute,
-            Some("resolve") => TransactionType::Resolve,
-            Some("chargeback") => TransactionType::ChargeBack,
-            _ => panic!("Unknown transaction type"),
-        })
+fn main() -> ExitCode {
+    // A panic is a failure like any other, so it exits with 2 rather than
+    // the 101 of Rust, once the panic hook printed it.
+    match std::panic::catch_unwind(run) {
+        Ok(Ok(code)) => code,
+        Ok(Err(x)) => {
+            eprintln!("Error: {}", x);
+            ExitCode::from(EXIT_FATAL)
+        }
+        Err(_) => ExitCode::from(EXIT_FATAL),
     }
 }
 
-#[derive(Default, Debug, PartialEq)]
-/// This is the main data structure that we will use to store
-/// all of the transactions.
-struct Database {
-    transactions: HashMap<u32, Transaction>,
-    clients: HashMap<u16, Client>,
-}
-
-#[derive(Debug, PartialEq, Default)]
-/// This struct represents the state of a single client's account.
-struct Client {
-    /// The client's available balance
-    available: f64,
-    /// The client's held balance if there was a dispute
-    held: f64,
-    /// Is the client's account is locked from a charge back
-    locked: bool,
-    /// Disputed transactions
-    disputed: HashSet<u32>,
-}
-
-/// Handles a single transaction and updates the database accordingly.
-fn handle_transaction(db: &mut Database, txn: Transaction) -> Result<()> {
-    let client = db.clients.entry(txn.client_id).or_insert(Client::default());
-    if client.locked {
-        eprintln!(
-            "Client {} is locked, aborting transaction {}",
-            txn.client_id, txn.txn_id
-        );
-        return Ok(());
-    }
-    match (
-        &txn.transaction_type,
-        db.transactions.get(&txn.txn_id),
-        txn.amount,
-    ) {
-        (TransactionType::Deposit, _, Some(amount)) => {
-            client.available += amount;
-            db.transactions.insert(txn.txn_id, txn);
-        }
-        (TransactionType::Withdrawal, _, Some(amount)) => {
-            if client.available - amount < 0.0 {
-                eprintln!("Client {} has insufficient funds", txn.client_id);
-            } else {
-                client.available -= amount;
+/// Runs the command, returning the status the process exits with unless
+/// it fails.
+fn run() -> Result<ExitCode> {
+    let cli = Cli::parse();
+    set_lang(cli.lang);
+    logging::init(cli.log_format, cli.log_level)?;
+    let consistency = Consistency {
+        allow_dispute_amounts: cli.allow_dispute_amounts,
+        require_intra_file_refs: cli.require_intra_file_refs,
+    };
+    let report_format = cli.format.unwrap_or(Format::Csv);
+    let labels: Labels = cli.labels.iter().cloned().collect();
+    match cli.command {
+        Some(Command::Simulate {
+            seed,
+            days,
+            clients,
+            per_day,
+        }) => {
+            let report = run_simulation(&SimulationConfig {
+                seed,
+                days,
+                clients,
+                transactions_per_day: per_day,
+            })?;
+            println!(
+                "Simulated {} transactions for {} clients over {} days (seed {}), {} accounts locked. All invariants held.",
+                report.transactions, report.clients, days, seed, report.locked
+            );
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Soak {
+            rate,
+            duration,
+            interval,
+            max_p99_us,
+            seed,
+            clients,
+            cold_dir,
+        }) => {
+            let mut engine = Engine::default();
+            if let Some(dir) = cold_dir {
+                engine = engine.with_tiering(Tiering {
+                    store: Box::new(DirColdStore::open(dir)?),
+                    evict_after: tiering::DEFAULT_EVICT_AFTER,
+                })?;
             }
-            db.transactions.insert(txn.txn_id, txn);
-        }
-        (
-            TransactionType::Dispute,
-            Some(Transaction {
-                client_id,
-                amount: Some(amount),
-                txn_id,
-                ..
-            }),
-            ..,
-        ) => {
-            if *client_id != txn.client_id {
-                eprintln!(
-                    "Client {} attempted to dispute transaction {}. Which was not it's transaction",
-                    txn.client_id, txn.txn_id
-                );
+            let config = soak::SoakConfig {
+                seed,
+                clients,
+                rate,
+                duration,
+                interval,
+                max_p99: max_p99_us.map(std::time::Duration::from_micros),
+            };
+            soak::run_soak(&config, &engine, |stats| {
+                info!(
+                    "{:>8.1?}: {} transactions, p50 {:?}, p99 {:?}, max {:?}, ~{} bytes",
+                    stats.elapsed,
+                    stats.transactions,
+                    stats.p50,
+                    stats.p99,
+                    stats.max,
+                    stats.memory_bytes
+                )
+            })?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Replay { capture, overrides }) if !overrides.is_empty() => {
+            let policy = overrides.into_iter().fold(Policy::default(), Policy::with);
+            print_what_if(&what_if(capture, policy)?, "what_if");
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Replay { capture, .. }) => {
+            // Every captured row carries its timestamp, the clock is only
+            // pinned so nothing in a replay can depend on the wall clock.
+            let engine = Engine::with_clock(SimulatedClock::new(0));
+            if pay::is_pay(&capture) {
+                run_transactions(pay::read_pay(capture)?, &engine, &RunOptions::default())?;
             } else {
-                client.held += amount;
-                client.available -= amount;
-                client.disputed.insert(*txn_id);
+                for skipped in run_engine(open_file_read_csv(capture)?, &engine)? {
+                    warn!("{}", skipped);
+                }
             }
+            write_report(&engine.db, open_output(None)?, report_format)?;
+            return Ok(ExitCode::SUCCESS);
         }
-        (
-            TransactionType::Resolve,
-            Some(Transaction {
-                client_id,
-                amount: Some(amount),
-                txn_id,
-                ..
-            }),
-            ..,
-        ) => {
-            if *client_id != txn.client_id {
-                eprintln!(
-                    "Client {} attempted to resolve transaction {}. Which was not it's transaction",
-                    txn.client_id, txn.txn_id
-                );
-            } else {
-                if client.disputed.contains(&txn_id) {
-                    client.available += dbg!(amount);
-                    client.held -= amount;
-                } else {
-                    eprintln!(
-                        "Client {} attempted to resolve transaction {}. Which was not disputed",
-                        txn.client_id, txn.txn_id
+        Some(Command::Generate {
+            from_profile,
+            rows,
+            seed,
+            output,
+        }) => {
+            let shape = Shape::load(&from_profile)?;
+            let txns = generate(&shape, rows.unwrap_or(shape.rows), seed);
+            match output {
+                Some(path) => write_csv(
+                    &txns,
+                    File::create(&path).map_err(|x| format!("error creating {}: {}", path, x))?,
+                )?,
+                None => write_csv(&txns, std::io::stdout().lock())?,
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Validate { inputs }) => {
+            let options = RunOptions {
+                consistency,
+                format: cli.format,
+                ..RunOptions::default()
+            };
+            let mut rows = 0;
+            let mut bad = 0;
+            for txn in read_inputs(&inputs, &options)? {
+                rows += 1;
+                if let Err(x) = txn {
+                    bad += 1;
+                    println!("{}", x);
+                }
+            }
+            if bad > 0 {
+                return Err(format!("{} of {} rows are invalid", bad, rows).into());
+            }
+            info!("All {} rows are valid", rows);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Compile { input, output }) => {
+            let count = pay::compile(input, &output)?;
+            info!("Compiled {} transactions to {}", count, output);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::AuditVerify { capture }) => {
+            let verification = verify_capture(capture)?;
+            println!(
+                "Verified {} entries, final state digest {}",
+                verification.entries,
+                format_digest(verification.digest)
+            );
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Backfill {
+            capture,
+            corrections,
+            before_seq,
+            commit,
+        }) => {
+            let committed = commit.clone();
+            let diffs = backfill(&capture, &corrections, before_seq, commit)?;
+            print_what_if(&diffs, "backfilled");
+            match committed {
+                Some(path) => info!("Wrote the merged capture to {}.", path),
+                None => info!(
+                    "{} accounts would change, nothing was written: approve with --commit <file>.",
+                    diffs.len()
+                ),
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::SpendReport { input, from, to }) => {
+            print_spend_report(&spend_report(input, Period { from, to })?);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Statement {
+            input,
+            client,
+            statement_format,
+            from,
+            to,
+            currency,
+            output,
+        }) => {
+            let period = Period { from, to };
+            let mut out = open_output(output.as_deref())?;
+            let text = match statement_format {
+                StatementFormat::Ofx => statement(input, client, period)?.to_ofx(&currency),
+                StatementFormat::Qif => statement(input, client, period)?.to_qif(),
+                StatementFormat::Csv => {
+                    write_history(&statement_history(input, client, period)?, out)?;
+                    return Ok(ExitCode::SUCCESS);
+                }
+            };
+            out.write_all(text.as_bytes())
+                .map_err(|x| format!("error writing the statement: {}", x))?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Compare {
+            input,
+            reference,
+            reference_report,
+        }) => {
+            let reference = reference
+                .map(Reference::Command)
+                .or(reference_report.map(Reference::Report))
+                .ok_or("a reference is required")?;
+            let diffs = compare(input, &reference)?;
+            print_compare(&diffs);
+            if !diffs.is_empty() {
+                return Err(format!("{} accounts differ from the reference", diffs.len()).into());
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Reconcile {
+            left,
+            right,
+            tolerance,
+        }) => {
+            let discrepancies = reconcile(&read_report(&left)?, &read_report(&right)?, tolerance);
+            print_reconcile(&discrepancies);
+            if !discrepancies.is_empty() {
+                return Err(format!("the reports differ in {} values", discrepancies.len()).into());
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::DiffOutcomes { ours, theirs }) => {
+            let diffs = diff_outcomes(
+                &read_outcomes(std::path::Path::new(&ours))?,
+                &read_outcomes(std::path::Path::new(&theirs))?,
+            );
+            print_outcome_diffs(&diffs);
+            if let Some(first) = diffs.first() {
+                return Err(format!(
+                    "{} rows differ between the runs, the first is row {}",
+                    diffs.len(),
+                    first.row
+                )
+                .into());
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::DeterminismAudit { input, runs }) => {
+            let audit = determinism_audit(input, runs as usize)?;
+            print_audit(&audit);
+            let differ = audit.iter().filter(|run| !run.is_deterministic()).count();
+            if differ > 0 {
+                return Err(format!(
+                    "{} of {} runs differ from the first, the result depends on scheduling",
+                    differ,
+                    audit.len()
+                )
+                .into());
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::TrialBalance { input, from, to }) => {
+            print_trial_balance(&trial_balance(input, Period { from, to })?);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Accounts {
+            input,
+            after,
+            limit,
+            locked,
+            disputed,
+            min_balance,
+        }) => {
+            let engine =
+                Engine::default().with_policy(regional_policy(cli.regions, cli.client_metadata)?);
+            for skipped in run_engine(open_file_read_csv(input)?, &engine)? {
+                warn!("{}", skipped);
+            }
+            let page = engine.accounts(&AccountQuery {
+                after,
+                limit,
+                locked,
+                disputed,
+                min_balance,
+            })?;
+            let mut report = ReportWriter::new(&engine.db, open_output(None)?, report_format)?;
+            for account in &page.accounts {
+                report.write(account)?;
+            }
+            report.finish()?;
+            if let Some(after) = page.next {
+                info!("{}", message(Key::NextPage, &[("after", &after)]));
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Query {
+            client,
+            input,
+            state,
+        }) => {
+            let mut engine =
+                Engine::default().with_policy(regional_policy(cli.regions, cli.client_metadata)?);
+            if let Some(dir) = &state {
+                load_previous_runs(&mut engine, std::path::Path::new(dir))?;
+            }
+            if let Some(input) = input {
+                for skipped in run_engine(open_file_read_csv(input)?, &engine)? {
+                    warn!("{}", skipped);
+                }
+            }
+            let details = engine
+                .client_details(client)?
+                .ok_or_else(|| format!("no client {}", client))?;
+            write_client_details(&engine, &details, open_output(None)?, report_format)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        #[cfg(feature = "server")]
+        Some(Command::Serve {
+            listen,
+            stdio,
+            idle_exit,
+            state,
+            dedup_window,
+            dedup_by,
+            handoff,
+            takeover,
+            metrics_listen,
+            wal,
+        }) => {
+            let mut engine = Engine::default()
+                .with_policy(regional_policy(cli.regions, cli.client_metadata.clone())?);
+            let state_dir = state.as_deref().map(std::path::Path::new);
+            let taken_over = match &takeover {
+                Some(socket) => Some(take_over(
+                    &mut engine,
+                    std::path::Path::new(socket),
+                    state_dir,
+                )?),
+                None => {
+                    if let Some(dir) = state_dir {
+                        load_previous_runs(&mut engine, dir)?;
+                    }
+                    None
+                }
+            };
+            if let Some(quotas) = cli.quotas {
+                engine = engine.with_quotas(Quotas::load(quotas, cli.client_metadata)?)?;
+            }
+            if let Some(window) = dedup_window {
+                engine = engine.with_dedup_window(DedupWindow::new(window, dedup_by));
+            }
+            if let Some(path) = &wal {
+                let wal = match taken_over {
+                    Some(_) => Wal::create(path)?,
+                    None => {
+                        let recovered = recover(std::path::Path::new(path), &engine, 0)?;
+                        if recovered.entries > 0 {
+                            info!(
+                                "Recovered {} transactions from the write-ahead log.",
+                                recovered.entries
+                            );
+                        }
+                        Wal::open(path)?
+                    }
+                };
+                engine = engine.with_wal(wal);
+            }
+            let scrapes = match &metrics_listen {
+                Some(addr) => Some(
+                    std::net::TcpListener::bind(addr)
+                        .map_err(|x| format!("error listening on {}: {}", addr, x))?,
+                ),
+                None => None,
+            };
+            let done = std::sync::atomic::AtomicBool::new(false);
+            let served = std::thread::scope(|scope| -> Result<Option<Served>> {
+                let scraped =
+                    scrapes.map(|listener| scope.spawn(|| serve_metrics(&engine, listener, &done)));
+                let serving = || -> Result<Option<Served>> {
+                    if stdio {
+                        let (input, output) = (std::io::stdin().lock(), std::io::stdout().lock());
+                        handle_connection(&engine, input, output)?;
+                        return Ok(None);
+                    }
+                    let (listener, adopted) = match (taken_over, activated_listener()?, listen) {
+                        (Some(taken_over), _, _) => (taken_over.listener, taken_over.connections),
+                        (None, Some(listener), _) => (listener, Vec::new()),
+                        (None, None, Some(addr)) => (
+                            std::net::TcpListener::bind(&addr)
+                                .map_err(|x| format!("error listening on {}: {}", addr, x))?,
+                            Vec::new(),
+                        ),
+                        (None, None, None) => {
+                            return Err("serve needs --listen, --stdio or --takeover".into())
+                        }
+                    };
+                    let socket = match &handoff {
+                        Some(path) => Some(bind_handoff(std::path::Path::new(path))?),
+                        None => None,
+                    };
+                    let handoff = Handoff { socket, adopted };
+                    serve(&engine, listener, idle_exit, handoff).map(Some)
+                };
+                let served = serving();
+                done.store(true, Ordering::SeqCst);
+                if let Some(scraped) = scraped {
+                    scraped.join().map_err(|_| "metrics thread panicked")??;
+                }
+                served
+            })?;
+            if let Some(served) = served {
+                if served.handed_off {
+                    // The new process carries on with the state, and saves
+                    // it.
+                    info!(
+                        "Handed over to a new process after {} connections.",
+                        served.connections
                     );
+                    return Ok(ExitCode::SUCCESS);
                 }
+                if let Some(path) = &handoff {
+                    let _ = std::fs::remove_file(path);
+                }
+                info!("Idle, exiting after {} connections.", served.connections);
+            }
+            if let Some(suppressed) = engine.metrics()?.suppressed_duplicates {
+                info!("{} retried transactions were suppressed.", suppressed);
+            }
+            if let Some(dir) = state_dir {
+                save_run(&engine, dir, SaveOptions::default())?;
             }
+            engine.commit_wal()?;
+            return Ok(ExitCode::SUCCESS);
         }
-        (
-            TransactionType::ChargeBack,
-            Some(Transaction {
-                client_id,
-                amount: Some(amount),
-                txn_id,
-                ..
-            }),
-            ..,
-        ) => {
-            if *client_id != txn.client_id {
-                eprintln!(
-                    "Client {} attempted to chargeback transaction {}. Which was not it's transaction",
-                    txn.client_id, txn.txn_id
-                );
-            } else {
-                if client.disputed.contains(&txn_id) {
-                    client.held -= amount;
-                    client.locked = true;
-                } else {
-                    eprintln!(
-                        "Client {} attempted to chargeback transaction {}. Which was not disputed",
-                        txn.client_id, txn.txn_id
+        #[cfg(feature = "server")]
+        Some(Command::CloneState { from, to }) => {
+            let addr = from.strip_prefix("tcp://").unwrap_or(&from);
+            let cloned = clone_state(addr, std::path::Path::new(&to))?;
+            if cloned.resumed_chunks > 0 {
+                info!("Resumed after {} chunks.", cloned.resumed_chunks);
+            }
+            println!(
+                "Cloned {} accounts and {} transactions, state digest {}.",
+                cloned.accounts,
+                cloned.transactions,
+                format_digest(cloned.digest)
+            );
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::SarExtract {
+            input,
+            patterns,
+            output,
+        }) => {
+            let patterns = match patterns {
+                Some(path) => Patterns::load(path)?,
+                None => Patterns::default(),
+            };
+            let flagged = sar_extract(input, &patterns)?;
+            match output {
+                Some(path) => write_sar(
+                    &flagged,
+                    File::create(&path).map_err(|x| format!("error creating {}: {}", path, x))?,
+                )?,
+                None => write_sar(&flagged, std::io::stdout().lock())?,
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::DisputesReport {
+            input,
+            sla,
+            now,
+            aging,
+        }) => {
+            let engine =
+                Engine::default().with_policy(regional_policy(cli.regions, cli.client_metadata)?);
+            for skipped in run_engine(open_file_read_csv(input)?, &engine)? {
+                warn!("{}", skipped);
+            }
+            let now = now.unwrap_or_else(|| engine.db.latest_activity.load(Ordering::SeqCst));
+            let disputes = open_disputes(&engine.db, now, aging)?;
+            if let Some(sla) = sla {
+                for dispute in breaching(&disputes, sla) {
+                    warn!(
+                        "{}",
+                        message(
+                            Key::SlaBreached,
+                            &[
+                                ("client", &dispute.client_id),
+                                ("txn", &dispute.txn_id),
+                                ("days", &(dispute.age / (24 * 60 * 60))),
+                            ]
+                        )
                     );
                 }
             }
+            print_disputes_report(&age_buckets(&disputes));
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::ImportAccounts {
+            accounts,
+            output,
+            audit,
+        }) => {
+            let mut admin = AdminLog::open(&cli.admin_log, cli.operator.as_deref())?
+                .with_labels(labels.clone());
+            let engine = Engine::default();
+            let balances = read_opening_balances(open_file_read_csv(accounts.clone())?)?;
+            let mut audit = audit.as_deref().map(OpeningAudit::create).transpose()?;
+            engine.seed_accounts(&balances, audit.as_mut())?;
+            write_state(&export_state(&engine.db)?, output)?;
+            for balance in &balances {
+                admin.record("opening_balance", Some(balance.client), &accounts)?;
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::ClosePeriod { state, period }) => {
+            let mut admin = AdminLog::open(&cli.admin_log, cli.operator.as_deref())?
+                .with_labels(labels.clone());
+            let closed = close_period(std::path::Path::new(&state), &period)?;
+            admin.record("close_period", None, &format!("{} {}", state, period))?;
+            println!(
+                "Closed {} with {} accounts, archived {} transactions to {} and carried {} disputed ones over.",
+                period,
+                closed.accounts,
+                closed.archived,
+                closed.archive.display(),
+                closed.carried
+            );
+            return Ok(ExitCode::SUCCESS);
         }
-        _ => eprintln!("Unknown transaction type"),
+        Some(Command::ExportState {
+            input,
+            output,
+            anonymize,
+            salt,
+            perturb,
+            bucket,
+        }) => {
+            let engine = Engine::default();
+            for skipped in run_engine(open_file_read_csv(input)?, &engine)? {
+                warn!("{}", skipped);
+            }
+            let mut state = export_state(&engine.db)?;
+            if anonymize {
+                let options = Anonymize {
+                    salt: salt.unwrap_or_else(random_salt),
+                    perturb,
+                    bucket,
+                };
+                state = anonymize::anonymize(state, &options);
+            }
+            write_state(&state, output)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::ImportState { state, input }) => {
+            let engine = Engine::default();
+            import_state(&engine, read_state(state)?)?;
+            if let Some(input) = input {
+                for skipped in run_engine(open_file_read_csv(input)?, &engine)? {
+                    warn!("{}", skipped);
+                }
+            }
+            write_report(&engine.db, open_output(None)?, report_format)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Locked { command }) => {
+            match command {
+                LockedCommand::Export { state, output } => {
+                    let engine = Engine::default();
+                    import_state(&engine, read_state(state)?)?;
+                    match output {
+                        Some(path) => export_queue(
+                            &engine.db,
+                            File::create(&path)
+                                .map_err(|x| format!("error creating {}: {}", path, x))?,
+                        )?,
+                        None => export_queue(&engine.db, std::io::stdout().lock())?,
+                    };
+                }
+                LockedCommand::ImportDecisions {
+                    state,
+                    decisions,
+                    approval_threshold,
+                    approval_ttl,
+                } => {
+                    let mut admin = AdminLog::open(&cli.admin_log, cli.operator.as_deref())?
+                        .with_labels(labels.clone());
+                    let engine = Engine::default();
+                    import_state(&engine, read_state(state.clone())?)?;
+                    let decisions = read_decisions(open_file_read_csv(decisions)?)?;
+                    let split = split_decisions(&engine.db, decisions, approval_threshold)?;
+                    let applied = apply_decisions(&engine.db, &split.immediate)?;
+                    rewrite_state(&engine.db, &state)?;
+                    for (client_id, decision) in &split.immediate {
+                        admin.record(decision.as_str(), Some(*client_id), &state)?;
+                    }
+                    println!(
+                        "Unlocked {}, kept {} and closed {} accounts.",
+                        applied.unlocked, applied.kept, applied.closed
+                    );
+                    if !split.deferred.is_empty() {
+                        let path = approvals_path(std::path::Path::new(&state));
+                        let mut approvals = Approvals::load(&path)?;
+                        let now = SystemClock.now();
+                        for (client_id, total) in split.deferred {
+                            let id = approvals.request(
+                                client_id,
+                                total,
+                                admin.operator(),
+                                now,
+                                approval_ttl,
+                            );
+                            admin.record("request_unlock", Some(client_id), &state)?;
+                            println!("Unlock {} of client {} awaits approval.", id, client_id);
+                        }
+                        approvals.save(&path)?;
+                    }
+                }
+                LockedCommand::Approvals { state } => {
+                    let path = approvals_path(std::path::Path::new(&state));
+                    print_approvals(&Approvals::load(&path)?);
+                }
+                LockedCommand::Approve { state, id } => {
+                    let mut admin = AdminLog::open(&cli.admin_log, cli.operator.as_deref())?
+                        .with_labels(labels.clone());
+                    let path = approvals_path(std::path::Path::new(&state));
+                    let mut approvals = Approvals::load(&path)?;
+                    let approved = approvals.approve(id, admin.operator(), SystemClock.now());
+                    // An expired unlock is dropped even though it can't be approved.
+                    approvals.save(&path)?;
+                    let approval = approved?;
+                    let engine = Engine::default();
+                    import_state(&engine, read_state(state.clone())?)?;
+                    apply_decisions(&engine.db, &[(approval.client, Decision::Unlock)])?;
+                    rewrite_state(&engine.db, &state)?;
+                    admin.record("unlock", Some(approval.client), &state)?;
+                    println!(
+                        "Unlocked client {}, requested by {}.",
+                        approval.client, approval.requested_by
+                    );
+                }
+                LockedCommand::Expire { state } => {
+                    let mut admin = AdminLog::open(&cli.admin_log, cli.operator.as_deref())?
+                        .with_labels(labels.clone());
+                    let path = approvals_path(std::path::Path::new(&state));
+                    let mut approvals = Approvals::load(&path)?;
+                    let expired = approvals.expire(SystemClock.now());
+                    approvals.save(&path)?;
+                    for approval in &expired {
+                        admin.record("expire_unlock", Some(approval.client), &state)?;
+                    }
+                    println!("Expired {} unlocks.", expired.len());
+                }
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        #[cfg(feature = "completions")]
+        Some(Command::Completions { shell }) => {
+            let mut command = <Cli as clap::CommandFactory>::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+            return Ok(ExitCode::SUCCESS);
+        }
+        None => {}
     }
-    Ok(())
-}
-
-/// Loads in the database with the given csv file.
-/// This is designed in such a way that a Reader is inputted
-/// and a possibly shared db can be used across multiple threads.
-/// This is written to easily allow synchronization oh parsing data
-/// and loading into a database.
-fn run_engine(mut reader: csv::Reader<File>, mut db: &mut Database) -> Result<()> {
-    for record in reader.records() {
-        let txn = Transaction::try_from(&record?)?;
-        handle_transaction(&mut db, txn)?;
-    }
-    Ok(())
-}
-
-fn main() -> Result<()> {
-    let filename = get_file_name_from_args()?;
-    let reader = open_file_read_csv(filename)?;
-    let mut db = Database::default();
+    let mut engine = match cli.clock_start {
+        Some(start) => Engine::with_clock(SimulatedClock::new(start)),
+        None => Engine::default(),
+    };
+    engine = engine.with_labels(labels);
+    if cli.check_invariants {
+        engine = engine.with_invariant_checks();
+    }
+    if let Some(path) = cli.capture {
+        engine = engine.with_capture(Capture::create(path, &cli.carry_columns)?);
+    }
+    if let Some(path) = &cli.audit {
+        let format = cli.format.unwrap_or_else(|| Format::detect(path));
+        engine = engine.with_journal(Journal::open(path, format)?);
+    }
+    if let Some(path) = cli.notifications {
+        let mut notifications = Notifications::create(path)?;
+        if let Some(clients) = &cli.client_metadata {
+            notifications = notifications.with_thresholds(clients)?;
+        }
+        engine = engine.with_notifications(notifications);
+    }
+    if let Some(rules) = cli.rules {
+        engine = engine.with_plugin(Rules::load(rules)?);
+    }
+    for command in cli.plugins {
+        engine = engine.with_plugin(ProcessPlugin::spawn(command)?);
+    }
+    if cli.regions.is_some() {
+        engine = engine.with_policy(regional_policy(cli.regions, cli.client_metadata.clone())?);
+    }
+    engine.db.policy.auto_chargeback_after = cli.auto_chargeback_after;
+    engine.db.policy.dispute_window = cli.dispute_window;
+    engine.db.policy.idempotent = cli.idempotent;
+    engine.db.policy.allow_admin_ops = cli.allow_admin_ops;
+    let precision = Precision {
+        places: cli.precision,
+        rounding: cli.rounding,
+    };
+    engine.db.policy.dormant_after = cli.dormant_after;
+    engine.db.policy.pending_holds = cli.pending_holds;
+    engine.db.policy.overdraft = cli.overdraft;
+    engine.db.policy.locked_accounts = cli.locked_accounts;
+    engine.db.policy.precision = precision;
+    if let Some(path) = cli.fees {
+        engine.db.policy.fees = Fees::load(path, cli.client_metadata.clone())?;
+    }
+    if let Some(dir) = &cli.append_state {
+        let dir = std::path::Path::new(dir);
+        if let Some(sink) = &cli.events {
+            // Events left behind by a run that stopped before publishing.
+            dispatch(dir, std::path::Path::new(sink))?;
+            engine
+                .db
+                .outbox
+                .start(last_published(std::path::Path::new(sink))?);
+        }
+        load_previous_runs(&mut engine, dir)?;
+    }
+    let resume_after = match &cli.resume_from {
+        Some(path) => engine.db.restore(std::path::Path::new(path))?,
+        None => 0,
+    };
+    if let Some(dir) = cli.cold_dir {
+        engine = engine.with_tiering(Tiering {
+            store: Box::new(DirColdStore::open(dir)?),
+            evict_after: cli.evict_after.unwrap_or(tiering::DEFAULT_EVICT_AFTER),
+        })?;
+        engine.db.preload(cli.preload)?;
+    }
+    if let Some(dir) = cli.spill_dir {
+        engine = engine.with_spill(Spill {
+            store: Box::new(DirTransactionStore::open(dir)?),
+            max_resident: cli.max_resident_transactions,
+        });
+    }
+    if let Some(quotas) = cli.quotas {
+        engine = engine.with_quotas(Quotas::load(quotas, cli.client_metadata)?)?;
+    }
+    let mut resume_after = resume_after;
+    if let Some(path) = &cli.wal {
+        let recovered = recover(std::path::Path::new(path), &engine, resume_after)?;
+        if recovered.entries > 0 {
+            info!(
+                "Recovered {} transactions from the write-ahead log, carrying on after row {}.",
+                recovered.entries,
+                recovered.rows.max(resume_after)
+            );
+        }
+        resume_after = resume_after.max(recovered.rows);
+        engine = engine.with_wal(Wal::open(path)?);
+    }
+    let dry_run = match cli.dry_run {
+        true => Some(DryRun::start(&mut engine)?),
+        false => None,
+    };
 
-    run_engine(reader, &mut db)?;
-    println!(
-        "{:>7}, {:>12}, {:>12}, {:>12}, {:>12}",
-        "client", "available", "held", "total", "locked"
-    );
-    db.clients.iter().for_each(|(client_id, client)| {
-        println!(
-            "{:>7}, {:>12.4}, {:>12.4}, {:>12.4}, {:>12}",
-            client_id,
-            client.available,
-            client.held,
-            client.available + client.held,
-            client.locked
+    let options = RunOptions {
+        limits: RunLimits {
+            max_rows: cli.max_rows,
+            max_runtime: cli.max_runtime,
+        },
+        sample: match (cli.sample, cli.head) {
+            (Some(Sample::Clients { rate, .. }), _) => Some(Sample::Clients {
+                rate,
+                seed: cli.sample_seed,
+            }),
+            (_, Some(n)) => Some(Sample::Head(n)),
+            (sample, None) => sample,
+        },
+        column_map: schema::ColumnMap(cli.map),
+        carry_columns: cli.carry_columns,
+        memo_max_len: Some(cli.memo_max_len),
+        precision: Some(precision),
+        fields: cli.fields,
+        consistency,
+        mode: cli.mode,
+        format: cli.format,
+        max_reject_rate: cli.max_reject_rate.map(|max| RejectRate {
+            max,
+            within: cli.reject_rate_window,
+        }),
+        threads: cli.threads as usize,
+        errors: cli.errors.map(Into::into),
+        outcomes: cli.outcomes.map(Into::into),
+        snapshots: cli
+            .snapshot_every
+            .zip(cli.snapshot_dir)
+            .map(|(every, dir)| SnapshotOptions {
+                every,
+                dir: dir.into(),
+                compression: cli.compress,
+            }),
+        resume_after,
+    };
+    let selected = ReportOptions {
+        sort: cli.sort,
+        include_dormant: cli.include_dormant,
+        only_locked: cli.only_locked,
+        clients: cli.clients,
+        min_total: cli.min_total,
+    };
+    let report_format = match (cli.format, &cli.output) {
+        (Some(format), _) => format,
+        (None, Some(output)) => Format::detect(output),
+        (None, None) => Format::Csv,
+    };
+    let (transactions, position) = match (cli.follow, cli.input.as_slice(), &cli.output) {
+        (false, ..) => read_inputs_at(&cli.input, &options)?,
+        (true, [input], Some(output))
+            if input != "-"
+                && cli.format.unwrap_or_else(|| Format::detect(input)) == Format::Csv =>
+        {
+            let follow = FollowOptions {
+                flush_every: cli.flush_every,
+                idle_exit: cli.follow_idle_exit,
+                ..FollowOptions::default()
+            };
+            let flush = || rewrite_report(&engine, output, report_format, cli.totals, &selected);
+            let transactions: Box<dyn Iterator<Item = Result<Transaction>>> =
+                Box::new(Follow::open(input, follow, &options, flush)?);
+            (transactions, InputPosition::default())
+        }
+        (true, ..) => return Err("--follow takes a single csv file".into()),
+    };
+    let transactions: Box<dyn Iterator<Item = Result<Transaction>>> = match cli.progress_format {
+        Some(format) => {
+            let out = Progress::open(cli.progress_socket.as_deref())?;
+            let progress = Progress::new(out, format, cli.progress_interval, position);
+            Box::new(progress.watch(transactions, &engine))
+        }
+        None => transactions,
+    };
+    let started = SystemClock.now();
+    let timer = std::time::Instant::now();
+    let summary = run_transactions(transactions, &engine, &options)?;
+    let charged = auto_chargeback(&engine)?;
+    if !charged.is_empty() {
+        info!(
+            "Charged back {} disputes left unresolved for too long.",
+            charged.len()
         );
-    });
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn integration_test_read_example_input() -> Result<()> {
-        let reader = open_file_read_csv("test-files/example_input.csv".to_string())?;
-        let mut db = Database::default();
-        run_engine(reader, &mut db)?;
-        assert_eq!(db.transactions.len(), 5);
-        assert_eq!(db.clients.len(), 2);
-        assert_eq!(db.clients[&1].available, 1.5);
-        assert_eq!(db.clients[&2].available, 2.0);
-        println!("{:?}", db);
-        Ok(())
     }
-
-    #[test]
-    /// Tests this case stated in the problem statement.
-    /// > Likewise, transaction IDs (tx) are globally unique, though are also not guaranteed to be ordered.
-    fn order_does_not_matter() -> Result<()> {
-        let reader_0 = open_file_read_csv("test-files/example_input_out_of_order.csv".to_string())?;
-        let reader_1 = open_file_read_csv("test-files/example_input.csv".to_string())?;
-        let mut db_0 = Database::default();
-        let mut db_1 = Database::default();
-        run_engine(reader_0, &mut db_0)?;
-        run_engine(reader_1, &mut db_1)?;
-        assert_eq!(db_0.clients, db_1.clients);
-        Ok(())
+    if cli.check_invariants {
+        engine.db.verify()?;
     }
-
-    #[test]
-    /// Dispute a deposit transaction.
-    fn test_dispute_deposit() -> Result<()> {
-        let reader = open_file_read_csv("test-files/dispute_deposit.csv".to_string())?;
-        let mut db = Database::default();
-        run_engine(reader, &mut db)?;
-        assert_eq!(db.transactions.len(), 2);
-        assert_eq!(db.clients.len(), 1);
-        assert_eq!(db.clients[&1].available, 2.0);
-        assert_eq!(db.clients[&1].held, 1.0);
-        Ok(())
+    engine.flush_capture()?;
+    engine.flush_notifications()?;
+    engine.flush_journal()?;
+    if options.sample.is_some() {
+        info!(
+            "{}",
+            message(
+                Key::Sampled,
+                &[("processed", &summary.processed), ("rows", &summary.rows)]
+            )
+        );
     }
-    #[test]
-    fn test_dispute_invalid_transaction_id() -> Result<()> {
-        let reader =
-            open_file_read_csv("test-files/dispute_invalid_transaction_id.csv".to_string())?;
-        let mut db = Database::default();
-        run_engine(reader, &mut db)?;
-        assert_eq!(db.transactions.len(), 2);
-        assert_eq!(db.clients.len(), 1);
-        assert_eq!(db.clients[&1].available, 3.0);
-        assert_eq!(db.clients[&1].held, 0.0);
-        Ok(())
+    if summary.errors > 0 {
+        info!(
+            "{}",
+            message(
+                Key::SkippedCount,
+                &[("skipped", &summary.errors), ("rows", &summary.rows)]
+            )
+        );
     }
-    #[test]
-    fn test_dispute_withdrawal() -> Result<()> {
-        let reader = open_file_read_csv("test-files/dispute_withdrawal.csv".to_string())?;
-        let mut db = Database::default();
-        run_engine(reader, &mut db)?;
-        assert_eq!(db.transactions.len(), 3);
-        assert_eq!(db.clients.len(), 1);
-        assert_eq!(db.clients[&1].available, 1.0);
-        assert_eq!(db.clients[&1].held, 1.5);
-        Ok(())
+    if summary.duplicates > 0 {
+        info!(
+            "{}",
+            message(
+                Key::DuplicateCount,
+                &[
+                    ("duplicates", &summary.duplicates),
+                    ("processed", &summary.processed)
+                ]
+            )
+        );
+    }
+    if summary.rejected > 0 {
+        info!(
+            "{}",
+            message(
+                Key::RejectedCount,
+                &[
+                    ("rejected", &summary.rejected),
+                    ("processed", &summary.processed)
+                ]
+            )
+        );
+    }
+    if let Some(stopped) = summary.stopped {
+        warn!(
+            "{}",
+            message(
+                Key::StoppedEarly,
+                &[
+                    ("reason", &stopped.reason),
+                    ("rows", &stopped.rows),
+                    ("resume", &(stopped.rows + 1)),
+                ]
+            )
+        );
+    }
+    if let Some(path) = &cli.manifest {
+        RunManifest::new(&engine, &cli.input, started, &summary).write(path)?;
+    }
+    if let Some(dir) = cli.append_state.as_ref().filter(|_| !cli.dry_run) {
+        let dir = std::path::Path::new(dir);
+        if let Some(stopped) = summary.stopped.as_ref().filter(|_| cli.atomic_file) {
+            return Err(format!(
+                "stopped after {} rows, before the end of the input: nothing was saved",
+                stopped.rows
+            )
+            .into());
+        }
+        let options = SaveOptions {
+            rebaseline_every: cli.rebaseline_every,
+            compression: cli.compress,
+            atomic: cli.atomic_file,
+        };
+        let started = std::time::Instant::now();
+        let conflicts = save_run(&engine, dir, options)?;
+        if cli.metrics {
+            if let Some(path) = append::latest_state_file(dir)? {
+                let bytes = std::fs::metadata(&path)
+                    .map_err(|x| format!("error reading {}: {}", path.display(), x))?
+                    .len();
+                info!(
+                    "state saved to {} in {} ms: {} bytes ({})",
+                    path.display(),
+                    started.elapsed().as_millis(),
+                    bytes,
+                    cli.compress
+                        .map_or("uncompressed".to_string(), |level| format!(
+                            "zstd level {}",
+                            level
+                        ))
+                );
+            }
+        }
+        if conflicts > 0 {
+            let path = dir.join(append::CONFLICTS_FILE);
+            warn!(
+                "{}",
+                message(
+                    Key::Conflicts,
+                    &[("count", &conflicts), ("path", &path.display())]
+                )
+            );
+        }
+        if let Some(sink) = &cli.events {
+            dispatch(dir, std::path::Path::new(sink))?;
+        }
     }
+    if let Some(path) = &cli.export_profile {
+        Shape::of(&engine)?.save(path)?;
+    }
+    if let Some(path) = &cli.amount_histogram {
+        write_histograms(&engine.db.counters.histograms(), path)?;
+    }
+    if let Some(path) = &cli.fees_report {
+        write_fees_report(&engine.db.ledger, open_output(Some(path))?)?;
+    }
+    if cli.metrics {
+        info!("{}", engine.metrics()?);
+    }
+    if cli.stats {
+        info!("\n{}", engine.metrics()?.summary_table());
+    }
+    #[cfg(feature = "profiling")]
+    if let Some(path) = &cli.profile {
+        payments_engine::profile::write_profile(path)?;
+    }
+    if let Some(dry_run) = dry_run {
+        let impact = dry_run.impact(&engine, &summary)?;
+        info!(
+            "Dry run: {} accounts would change, {} would lock and {} transactions would be rejected. Nothing was saved.",
+            impact.changes.len(),
+            impact.locks.len(),
+            impact.rejections.len()
+        );
+        write_impact(&impact, open_output(cli.output.as_deref())?)?;
+    } else if !cli.sinks.is_empty() {
+        let sinks = cli
+            .sinks
+            .iter()
+            .map(|sink| sink.open(&engine.db, cli.totals))
+            .collect::<Result<_>>()?;
+        write_selected(&engine.db, Box::new(Sinks(sinks)), &selected)?;
+    } else if let (true, Some(output)) = (cli.follow, &cli.output) {
+        rewrite_report(&engine, output, report_format, cli.totals, &selected)?;
+    } else {
+        let mut report = ReportWriter::new(
+            &engine.db,
+            open_output(cli.output.as_deref())?,
+            report_format,
+        )?;
+        if cli.totals {
+            report = report.with_totals();
+        }
+        write_selected(&engine.db, Box::new(report), &selected)?;
+    }
+    // The run is over and reported, running again mustn't replay it.
+    engine.commit_wal()?;
+    // The next run over the cold store starts from the clients left there.
+    engine.db.evict_all()?;
+    if let Some(path) = &cli.summary_json {
+        JsonSummary::new(&engine, &summary, timer.elapsed())?.write(path)?;
+    }
+    Ok(ExitCode::from(exit_status(&summary)))
+}
 
-    #[test]
-    fn test_dispute_client_mismatch() -> Result<()> {
-        let reader = open_file_read_csv("test-files/dispute_client_mismatch.csv".to_string())?;
-        let mut db = Database::default();
-        run_engine(reader, &mut db)?;
-        assert_eq!(db.transactions.len(), 2);
-        assert_eq!(db.clients.len(), 2);
-        assert_eq!(db.clients[&1].available, 1.0);
-        assert_eq!(db.clients[&1].held, 0.0);
-        assert_eq!(db.clients[&2].available, 2.0);
-        assert_eq!(db.clients[&2].held, 0.0);
-        Ok(())
+/// Writes the report to a file next to `path` and moves it over `path`,
+/// so what reads the report while `--follow` rewrites it never sees half
+/// of one.
+fn rewrite_report(
+    engine: &Engine,
+    path: &str,
+    format: Format,
+    totals: bool,
+    selected: &ReportOptions,
+) -> Result<()> {
+    let partial = format!("{}.partial", path);
+    let mut report = ReportWriter::new(&engine.db, open_output(Some(&partial))?, format)?;
+    if totals {
+        report = report.with_totals();
     }
+    write_selected(&engine.db, Box::new(report), selected)?;
+    std::fs::rename(&partial, path).map_err(|x| format!("error replacing {}: {}", path, x).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
 
     #[test]
-    fn test_resolve_disputed_deposit() -> Result<()> {
-        let reader = open_file_read_csv("test-files/resolved_dispute.csv".to_string())?;
-        let mut db = Database::default();
-        run_engine(reader, &mut db)?;
-        assert_eq!(db.transactions.len(), 2);
-        assert_eq!(db.clients.len(), 1);
-        assert_eq!(db.clients[&1].available, 3.0);
-        assert_eq!(db.clients[&1].held, 0.0);
-        Ok(())
+    /// The clap definition is consistent, which is also what completions are generated from.
+    fn cli_definition_is_valid() {
+        Cli::command().debug_assert();
     }
+
     #[test]
-    fn test_resolved_non_disputed() -> Result<()> {
-        let reader = open_file_read_csv("test-files/resolved_non_disputed.csv".to_string())?;
-        let mut db = Database::default();
-        run_engine(reader, &mut db)?;
-        assert_eq!(db.transactions.len(), 2);
-        assert_eq!(db.clients.len(), 1);
-        assert_eq!(db.clients[&1].available, 3.0);
-        assert_eq!(db.clients[&1].held, 0.0);
+    /// Options fall back to their environment variable, and the command
+    /// line wins over both it and the default.
+    fn options_can_come_from_the_environment() -> Result<()> {
+        std::env::set_var("PAYMENTS_ENGINE_MEMO_MAX_LEN", "12");
+        let parse = |args: &[&str]| Cli::try_parse_from(args).map_err(|x| x.to_string());
+        let from_env = parse(&["payments-engine", "in.csv"])?;
+        let from_cli = parse(&["payments-engine", "in.csv", "--memo-max-len", "3"])?;
+        std::env::remove_var("PAYMENTS_ENGINE_MEMO_MAX_LEN");
+        let default = parse(&["payments-engine", "in.csv"])?;
+        assert_eq!(from_env.memo_max_len, 12);
+        assert_eq!(from_cli.memo_max_len, 3);
+        assert_eq!(default.memo_max_len, schema::DEFAULT_MEMO_MAX_LEN);
         Ok(())
     }
 
     #[test]
-    fn test_chargeback_dispute() -> Result<()> {
-        let reader = open_file_read_csv("test-files/chargeback_dispute.csv".to_string())?;
-        let mut db = Database::default();
-        run_engine(reader, &mut db)?;
-        assert_eq!(db.transactions.len(), 2);
-        assert_eq!(db.clients.len(), 1);
-        assert_eq!(db.clients[&1].available, 2.0);
-        assert_eq!(db.clients[&1].held, 0.0);
-        assert_eq!(db.clients[&1].locked, true);
-        Ok(())
+    /// A dry run refuses every option that would write what it did to a
+    /// file, so none of them records changes that were never made.
+    fn dry_runs_leave_every_output_file_untouched() {
+        let outputs = [
+            "--wal",
+            "--events",
+            "--audit",
+            "--capture",
+            "--outcomes",
+            "--notifications",
+            "--manifest",
+            "--fees-report",
+        ];
+        for output in outputs {
+            let args = [
+                "payments-engine",
+                "in.csv",
+                "--append-state",
+                "state",
+                "--dry-run",
+                output,
+                "out",
+            ];
+            assert!(Cli::try_parse_from(args).is_err(), "{} was allowed", output);
+        }
+        let args = ["payments-engine", "in.csv", "--append-state", "state"];
+        assert!(Cli::try_parse_from(args.into_iter().chain(["--dry-run"])).is_ok());
     }
 }
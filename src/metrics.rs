@@ -0,0 +1,453 @@
+use crate::{
+    amounts::{AmountStats, Histogram},
+    i18n::Reason,
+    labels::Labels,
+    quota::Usage,
+    ClientId, Database, Transaction, TransactionType,
+};
+use std::{
+    fmt,
+    mem::size_of,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+/// Running counts kept by the database as transactions are handled.
+pub struct Counters {
+    processed: [AtomicU64; TransactionType::ALL.len()],
+    applied: [AtomicU64; TransactionType::ALL.len()],
+    rejected: [AtomicU64; Reason::ALL.len()],
+    /// The amounts of transactions handled, by type.
+    amounts: [Histogram; TransactionType::ALL.len()],
+    /// When the first transaction was handled, for the throughput.
+    started: OnceLock<Instant>,
+}
+
+impl Counters {
+    pub fn processed(&self, txn: &Transaction) {
+        // wasm32-unknown-unknown has no clock to read, so the throughput
+        // is left out there, see [`crate::preview::preview_csv`].
+        #[cfg(not(target_arch = "wasm32"))]
+        self.started.get_or_init(Instant::now);
+        let index = txn.transaction_type.index();
+        self.processed[index].fetch_add(1, Ordering::Relaxed);
+        if let Some(amount) = txn.amount {
+            self.amounts[index].record(amount);
+        }
+    }
+
+    pub fn applied(&self, transaction_type: TransactionType) {
+        self.applied[transaction_type.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self, reason: Reason) {
+        self.rejected[reason.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The transactions handled and rejected so far, of any type and for
+    /// any reason.
+    pub fn totals(&self) -> (u64, u64) {
+        let sum = |counters: &[AtomicU64]| {
+            counters
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .sum()
+        };
+        (sum(&self.processed), sum(&self.rejected))
+    }
+
+    /// Adds the counts of `other` to these.
+    pub fn absorb(&self, other: &Counters) {
+        let pairs = self
+            .processed
+            .iter()
+            .zip(&other.processed)
+            .chain(self.applied.iter().zip(&other.applied))
+            .chain(self.rejected.iter().zip(&other.rejected));
+        for (count, more) in pairs {
+            count.fetch_add(more.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        if let Some(started) = other.started.get() {
+            self.started.get_or_init(|| *started);
+        }
+        for (amounts, more) in self.amounts.iter().zip(&other.amounts) {
+            amounts.absorb(more);
+        }
+    }
+
+    /// The histogram of the amounts of every transaction type that had
+    /// any, see [`Histogram::buckets`].
+    pub fn histograms(&self) -> Vec<(TransactionType, Vec<(f64, u64)>)> {
+        TransactionType::ALL
+            .into_iter()
+            .zip(&self.amounts)
+            .map(|(transaction_type, amounts)| (transaction_type, amounts.buckets()))
+            .filter(|(_, buckets)| !buckets.is_empty())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A point in time view of what an engine has done and holds, for
+/// embedders to feed into their own monitoring.
+pub struct Metrics {
+    /// Transactions handled, by type, whether or not they were applied.
+    pub processed: Vec<(TransactionType, u64)>,
+    /// Transactions applied, by type. The disputes applied were opened,
+    /// the resolves and chargebacks applied settled one.
+    pub applied: Vec<(TransactionType, u64)>,
+    /// Transactions rejected, by reason.
+    pub rejected: Vec<(Reason, u64)>,
+    /// How the amounts of the transactions handled are distributed, by
+    /// type, for the types that had any. Amounts in cents where dollars
+    /// were expected stand out as a hundredfold jump.
+    pub amounts: Vec<(TransactionType, AmountStats)>,
+    /// Clients held in memory.
+    pub accounts: usize,
+    /// Clients evicted to the cold store.
+    pub cold_accounts: usize,
+    pub locked_accounts: usize,
+    pub open_disputes: usize,
+    /// Deposits and withdrawals kept in memory for later disputes.
+    pub stored_transactions: usize,
+    /// Deposits and withdrawals spilled to the transaction store.
+    pub spilled_transactions: usize,
+    /// A rough lower bound of the memory used by clients and stored
+    /// transactions. Map overhead and the text of memos and extra
+    /// columns are not counted.
+    pub estimated_memory_bytes: usize,
+    /// Allocations made by the process so far and the bytes they asked
+    /// for, with the `alloc-count` feature.
+    pub allocations: Option<(u64, u64)>,
+    /// Submissions suppressed as retries, with `--dedup-window`.
+    pub suppressed_duplicates: Option<u64>,
+    /// What each tenant holds and was refused, with `--quotas`.
+    pub tenants: Vec<(String, Usage)>,
+    /// The labels of the run, added to every line.
+    pub labels: Labels,
+    /// The time since the first transaction was handled, if one was.
+    pub elapsed: Option<Duration>,
+}
+
+/// Collects the metrics of `db`. Evicted clients are counted without
+/// being loaded back.
+pub fn collect(db: &Database) -> crate::Result<Metrics> {
+    let counts = |counters: &[AtomicU64]| {
+        counters
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect::<Vec<_>>()
+    };
+    let mut metrics = Metrics {
+        processed: TransactionType::ALL
+            .into_iter()
+            .zip(counts(&db.counters.processed))
+            .collect(),
+        applied: TransactionType::ALL
+            .into_iter()
+            .zip(counts(&db.counters.applied))
+            .collect(),
+        rejected: Reason::ALL
+            .into_iter()
+            .zip(counts(&db.counters.rejected))
+            .collect(),
+        amounts: TransactionType::ALL
+            .into_iter()
+            .zip(&db.counters.amounts)
+            .filter_map(|(transaction_type, amounts)| Some((transaction_type, amounts.stats()?)))
+            .collect(),
+        accounts: db.clients.len(),
+        cold_accounts: 0,
+        locked_accounts: 0,
+        open_disputes: 0,
+        stored_transactions: db.transactions.len(),
+        spilled_transactions: 0,
+        estimated_memory_bytes: 0,
+        #[cfg(feature = "alloc-count")]
+        allocations: Some(crate::alloc::allocations()),
+        #[cfg(not(feature = "alloc-count"))]
+        allocations: None,
+        suppressed_duplicates: None,
+        tenants: Vec::new(),
+        labels: Labels::default(),
+        elapsed: db.counters.started.get().map(Instant::elapsed),
+    };
+    let mut resident_disputes = 0;
+    for entry in db.clients.iter() {
+        resident_disputes += entry.disputed.len();
+    }
+    db.for_each_client(|_, client| {
+        metrics.locked_accounts += client.locked as usize;
+        metrics.open_disputes += client.disputed.len();
+    })?;
+    if let Some(cold) = &db.cold {
+        cold.store
+            .for_each(&mut |_, _| metrics.cold_accounts += 1)?;
+    }
+    if let Some(spill) = &db.spill {
+        metrics.spilled_transactions = spill.store.count()?;
+    }
+    metrics.estimated_memory_bytes = metrics.accounts * size_of::<(ClientId, crate::Client)>()
+        + resident_disputes * size_of::<u32>()
+        + metrics.stored_transactions * size_of::<(u32, crate::Transaction)>();
+    Ok(metrics)
+}
+
+impl fmt::Display for Metrics {
+    /// One `name value` line per metric, easy to grep or scrape, with the
+    /// labels of the run as `name{key="value"} value`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.labels.is_empty() {
+            return self.write_lines(f);
+        }
+        let mut lines = String::new();
+        self.write_lines(&mut lines)?;
+        for (i, line) in lines.lines().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let (name, value) = line.rsplit_once(' ').unwrap_or((line, ""));
+            match name.strip_suffix('}') {
+                Some(name) => write!(f, "{},{}}} {}", name, self.labels, value)?,
+                None => write!(f, "{}{{{}}} {}", name, self.labels, value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Metrics {
+    /// Transactions handled per second since the first one was.
+    pub fn rows_per_second(&self) -> Option<f64> {
+        let elapsed = self.elapsed?.as_secs_f64();
+        let rows: u64 = self.processed.iter().map(|(_, count)| count).sum();
+        (elapsed > 0.0).then(|| rows as f64 / elapsed)
+    }
+
+    /// The applied transactions of `transaction_type`.
+    fn applied(&self, transaction_type: TransactionType) -> u64 {
+        self.applied
+            .iter()
+            .find(|(t, _)| *t == transaction_type)
+            .map_or(0, |(_, count)| *count)
+    }
+
+    /// The metrics in the Prometheus text format, every sample with the
+    /// labels of the run, for `--metrics-listen` to be scraped.
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            out += &format!("# HELP payments_engine_{} {}\n", name, help);
+            out += &format!("# TYPE payments_engine_{} {}\n", name, kind);
+            for (labels, value) in samples {
+                let labels = match (labels.is_empty(), self.labels.is_empty()) {
+                    (true, true) => String::new(),
+                    (false, true) => format!("{{{}}}", labels),
+                    (true, false) => format!("{{{}}}", self.labels),
+                    (false, false) => format!("{{{},{}}}", labels, self.labels),
+                };
+                out += &format!("payments_engine_{}{} {}\n", name, labels, value);
+            }
+        };
+        let by_type = |counts: &[(TransactionType, u64)]| -> Vec<_> {
+            counts
+                .iter()
+                .map(|(t, count)| (format!("type=\"{}\"", t.as_str()), count.to_string()))
+                .collect()
+        };
+        let gauge = |value: &dyn fmt::Display| [(String::new(), value.to_string())];
+        family(
+            "transactions_total",
+            "counter",
+            "Transactions handled, by type, whether or not they were applied.",
+            &by_type(&self.processed),
+        );
+        family(
+            "applied_total",
+            "counter",
+            "Transactions applied, by type.",
+            &by_type(&self.applied),
+        );
+        let rejected: Vec<_> = self
+            .rejected
+            .iter()
+            .map(|(reason, count)| (format!("reason=\"{}\"", reason.code()), count.to_string()))
+            .collect();
+        family(
+            "rejections_total",
+            "counter",
+            "Transactions rejected, by reason.",
+            &rejected,
+        );
+        let disputes = [
+            ("opened", TransactionType::Dispute),
+            ("resolved", TransactionType::Resolve),
+            ("charged_back", TransactionType::ChargeBack),
+        ]
+        .map(|(outcome, t)| {
+            (
+                format!("outcome=\"{}\"", outcome),
+                self.applied(t).to_string(),
+            )
+        });
+        family(
+            "disputes_total",
+            "counter",
+            "Disputes opened, resolved and charged back.",
+            &disputes,
+        );
+        if let Some(rate) = self.rows_per_second() {
+            family(
+                "rows_per_second",
+                "gauge",
+                "Transactions handled per second since the first one.",
+                &gauge(&format!("{:.3}", rate)),
+            );
+        }
+        let gauges: [(&str, &str, usize); 7] = [
+            ("accounts", "Clients held in memory.", self.accounts),
+            (
+                "cold_accounts",
+                "Clients evicted to the cold store.",
+                self.cold_accounts,
+            ),
+            ("locked_accounts", "Locked accounts.", self.locked_accounts),
+            (
+                "open_disputes",
+                "Disputes not yet resolved or charged back.",
+                self.open_disputes,
+            ),
+            (
+                "stored_transactions",
+                "Deposits and withdrawals kept in memory for disputes.",
+                self.stored_transactions,
+            ),
+            (
+                "spilled_transactions",
+                "Deposits and withdrawals spilled to the transaction store.",
+                self.spilled_transactions,
+            ),
+            (
+                "estimated_memory_bytes",
+                "A rough lower bound of the memory used by clients and transactions.",
+                self.estimated_memory_bytes,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            family(name, "gauge", help, &gauge(&value));
+        }
+        if let Some(suppressed) = self.suppressed_duplicates {
+            family(
+                "suppressed_duplicates_total",
+                "counter",
+                "Submissions suppressed as retries.",
+                &gauge(&suppressed),
+            );
+        }
+        out
+    }
+
+    /// A table of what a run did, for people: the transactions of each
+    /// type handled and applied, the rejections by reason, the disputes
+    /// and the throughput.
+    pub fn summary_table(&self) -> String {
+        let mut table = format!("{:<20} {:>12} {:>12}\n", "type", "handled", "applied");
+        for (transaction_type, handled) in self.processed.iter().filter(|(_, n)| *n > 0) {
+            table += &format!(
+                "{:<20} {:>12} {:>12}\n",
+                transaction_type.as_str(),
+                handled,
+                self.applied(*transaction_type)
+            );
+        }
+        let rejected: Vec<_> = self.rejected.iter().filter(|(_, n)| *n > 0).collect();
+        if !rejected.is_empty() {
+            table += &format!("\n{:<20} {:>12}\n", "rejected", "count");
+            for (reason, count) in rejected {
+                table += &format!("{:<20} {:>12}\n", reason.code(), count);
+            }
+        }
+        table += &format!(
+            "\ndisputes opened {}, resolved {}, charged back {}",
+            self.applied(TransactionType::Dispute),
+            self.applied(TransactionType::Resolve),
+            self.applied(TransactionType::ChargeBack)
+        );
+        if let (Some(elapsed), Some(rate)) = (self.elapsed, self.rows_per_second()) {
+            table += &format!("\n{:.3}s, {:.0} rows/s", elapsed.as_secs_f64(), rate);
+        }
+        table
+    }
+
+    fn write_lines(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        for (transaction_type, count) in &self.processed {
+            writeln!(f, "processed_{} {}", transaction_type.as_str(), count)?;
+        }
+        for (transaction_type, count) in &self.applied {
+            writeln!(f, "applied_{} {}", transaction_type.as_str(), count)?;
+        }
+        for (reason, count) in &self.rejected {
+            writeln!(f, "rejected_{} {}", reason.code(), count)?;
+        }
+        for (transaction_type, stats) in &self.amounts {
+            let name = transaction_type.as_str();
+            writeln!(f, "amount_min{{type=\"{}\"}} {}", name, stats.min)?;
+            writeln!(f, "amount_median{{type=\"{}\"}} {}", name, stats.median)?;
+            writeln!(f, "amount_p95{{type=\"{}\"}} {}", name, stats.p95)?;
+            writeln!(f, "amount_max{{type=\"{}\"}} {}", name, stats.max)?;
+        }
+        writeln!(f, "accounts {}", self.accounts)?;
+        writeln!(f, "cold_accounts {}", self.cold_accounts)?;
+        writeln!(f, "locked_accounts {}", self.locked_accounts)?;
+        writeln!(f, "open_disputes {}", self.open_disputes)?;
+        writeln!(f, "stored_transactions {}", self.stored_transactions)?;
+        writeln!(f, "spilled_transactions {}", self.spilled_transactions)?;
+        if let Some(suppressed) = self.suppressed_duplicates {
+            writeln!(f, "suppressed_duplicates {}", suppressed)?;
+        }
+        if let Some(rate) = self.rows_per_second() {
+            writeln!(f, "rows_per_second {:.0}", rate)?;
+        }
+        for (tenant, usage) in &self.tenants {
+            writeln!(
+                f,
+                "tenant_accounts{{tenant=\"{}\"}} {}",
+                tenant, usage.accounts
+            )?;
+            writeln!(
+                f,
+                "tenant_stored_transactions{{tenant=\"{}\"}} {}",
+                tenant, usage.stored_transactions
+            )?;
+            writeln!(
+                f,
+                "tenant_rejected_quota_exceeded{{tenant=\"{}\"}} {}",
+                tenant, usage.rejected
+            )?;
+        }
+        write!(f, "estimated_memory_bytes {}", self.estimated_memory_bytes)?;
+        if let Some((allocations, bytes)) = self.allocations {
+            write!(f, "\nallocations {}", allocations)?;
+            write!(f, "\nallocated_bytes {}", bytes)?;
+            let rows: u64 = self.processed.iter().map(|(_, count)| count).sum();
+            if rows > 0 {
+                let per_million = |n: u64| n as f64 * 1_000_000.0 / rows as f64;
+                write!(
+                    f,
+                    "\nallocations_per_million_rows {:.0}",
+                    per_million(allocations)
+                )?;
+                write!(
+                    f,
+                    "\nallocated_bytes_per_million_rows {:.0}",
+                    per_million(bytes)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
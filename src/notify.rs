@@ -0,0 +1,298 @@
+use crate::{clock::Timestamp, open_file_read_csv, ClientId, Result, TransactionType};
+use serde::Serialize;
+use std::{collections::HashMap, fmt, fs::File};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The notices clients must be sent about their disputes, and about the
+/// thresholds they asked to be told of.
+pub enum Template {
+    DisputeOpened,
+    DisputeResolved,
+    /// A chargeback, which also locks the account.
+    AccountLocked,
+    /// The available balance fell below the client's threshold.
+    LowBalance,
+    /// A deposit or withdrawal of at least the client's threshold.
+    LargeTransaction,
+}
+
+impl Template {
+    /// The notice owed for an applied transaction of `transaction_type`,
+    /// if any.
+    pub fn for_type(transaction_type: TransactionType) -> Option<Self> {
+        match transaction_type {
+            TransactionType::Dispute => Some(Template::DisputeOpened),
+            TransactionType::Resolve => Some(Template::DisputeResolved),
+            TransactionType::ChargeBack => Some(Template::AccountLocked),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Template::DisputeOpened => "dispute_opened",
+            Template::DisputeResolved => "dispute_resolved",
+            Template::AccountLocked => "account_locked",
+            Template::LowBalance => "low_balance",
+            Template::LargeTransaction => "large_transaction",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+/// What a notice is about, filled into its template.
+pub struct Payload {
+    /// The disputed transaction, or the one that crossed a threshold.
+    pub tx: u32,
+    /// Its amount.
+    pub amount: Option<f64>,
+    /// When the dispute was opened, resolved or charged back, or the
+    /// transaction applied.
+    pub timestamp: Timestamp,
+    /// The available balance after a low balance transaction.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<f64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+/// The thresholds a client wants to be notified of crossing.
+pub struct Threshold {
+    /// Notify when the available balance falls below this.
+    pub low_balance: Option<f64>,
+    /// Notify of deposits and withdrawals of at least this.
+    pub large_transaction: Option<f64>,
+}
+
+/// A transaction being applied to a client with thresholds, and the
+/// client's available balance before it.
+#[derive(Debug, Clone, Copy)]
+pub struct Watch {
+    pub client: ClientId,
+    pub threshold: Threshold,
+    pub transaction_type: TransactionType,
+    pub before: f64,
+}
+
+/// The notices owed to clients for a run, one row each with the client,
+/// the template and its payload as JSON, for the comms system to send.
+pub struct Notifications {
+    path: String,
+    writer: csv::Writer<File>,
+    thresholds: HashMap<ClientId, Threshold>,
+}
+
+impl fmt::Debug for Notifications {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Notifications")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl Notifications {
+    /// Creates (or truncates) the notifications file at `path`.
+    pub fn create(path: String) -> Result<Self> {
+        let mut writer = csv::Writer::from_path(&path)?;
+        writer.write_record(["client", "template", "payload"])?;
+        Ok(Notifications {
+            path,
+            writer,
+            thresholds: HashMap::new(),
+        })
+    }
+
+    /// Reads the thresholds of each client from the optional
+    /// `low_balance` and `large_transaction` columns of the csv `clients`.
+    /// Empty cells leave that threshold unset.
+    pub fn with_thresholds(mut self, clients: &str) -> Result<Self> {
+        let mut reader = open_file_read_csv(clients.to_string())?;
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|h| h.trim().eq_ignore_ascii_case(name))
+        };
+        let client = column("client").ok_or_else(|| format!("{} has no client column", clients))?;
+        let (low_balance, large_transaction) = (column("low_balance"), column("large_transaction"));
+        if low_balance.is_none() && large_transaction.is_none() {
+            return Ok(self);
+        }
+        for record in reader.records() {
+            let record = record?;
+            let amount = |column: Option<usize>| -> Result<Option<f64>> {
+                match column.and_then(|i| record.get(i)).map(str::trim) {
+                    None | Some("") => Ok(None),
+                    Some(amount) => Ok(Some(amount.parse::<f64>().map_err(|x| {
+                        format!("{} has an invalid threshold {}: {}", clients, amount, x)
+                    })?)),
+                }
+            };
+            let threshold = Threshold {
+                low_balance: amount(low_balance)?,
+                large_transaction: amount(large_transaction)?,
+            };
+            if threshold != Threshold::default() {
+                let client_id = record
+                    .get(client)
+                    .unwrap_or("")
+                    .trim()
+                    .parse::<ClientId>()?;
+                self.thresholds.insert(client_id, threshold);
+            }
+        }
+        Ok(self)
+    }
+
+    /// The thresholds of `client`, if it set any.
+    pub fn threshold(&self, client: ClientId) -> Option<Threshold> {
+        self.thresholds.get(&client).copied()
+    }
+
+    /// Records the notices owed for the thresholds the watched transaction
+    /// crossed, now that it was applied leaving `after` available. The
+    /// low balance notice is only sent as the balance falls below the
+    /// threshold, not for every transaction while it stays there.
+    pub fn check(&mut self, watch: Watch, after: f64, payload: &Payload) -> Result<()> {
+        let Watch {
+            client,
+            threshold,
+            transaction_type,
+            before,
+        } = watch;
+        if threshold
+            .low_balance
+            .is_some_and(|low| before >= low && after < low)
+        {
+            let payload = Payload {
+                balance: Some(after),
+                ..payload.clone()
+            };
+            self.record(client, Template::LowBalance, &payload)?;
+        }
+        let moves_money = matches!(
+            transaction_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+        if moves_money
+            && threshold
+                .large_transaction
+                .zip(payload.amount)
+                .is_some_and(|(large, amount)| amount >= large)
+        {
+            self.record(client, Template::LargeTransaction, payload)?;
+        }
+        Ok(())
+    }
+
+    /// Appends a notice for `client`.
+    pub fn record(
+        &mut self,
+        client: ClientId,
+        template: Template,
+        payload: &Payload,
+    ) -> Result<()> {
+        let payload = serde_json::to_string(payload).map_err(|x| x.to_string())?;
+        self.writer
+            .write_record([client.to_string(), template.as_str().to_string(), payload])?;
+        Ok(())
+    }
+
+    /// Flushes everything recorded so far to disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .map_err(|x| format!("error flushing notifications {}: {}", self.path, x))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, Engine, Transaction};
+
+    #[test]
+    fn disputes_owe_notices() -> Result<()> {
+        let path = crate::temp_path("payments-engine-notify-test.csv");
+        let engine = Engine::with_clock(SimulatedClock::new(100))
+            .with_notifications(Notifications::create(path.to_string_lossy().to_string())?);
+        engine.process(Transaction::deposit(1, 1, 2.5))?;
+        engine.process(Transaction::deposit(1, 2, 1.0))?;
+        engine.process(Transaction::dispute(1, 1))?;
+        engine.process(Transaction::resolve(1, 1))?;
+        engine.process(Transaction::dispute(1, 2))?;
+        engine.process(Transaction::chargeback(1, 2))?;
+        // Rejected, nothing is owed.
+        engine.process(Transaction::resolve(1, 2))?;
+        engine.flush_notifications()?;
+
+        let mut reader = csv::Reader::from_path(&path)?;
+        let rows: Vec<_> = reader
+            .records()
+            .map(|record| Ok(record?.iter().map(str::to_string).collect::<Vec<_>>()))
+            .collect::<Result<_>>()?;
+        let templates: Vec<_> = rows.iter().map(|row| row[1].as_str()).collect();
+        assert_eq!(
+            templates,
+            [
+                "dispute_opened",
+                "dispute_resolved",
+                "dispute_opened",
+                "account_locked"
+            ]
+        );
+        assert_eq!(rows[0][2], r#"{"tx":1,"amount":2.5,"timestamp":100}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn crossing_a_threshold_owes_a_notice() -> Result<()> {
+        let (path, clients) = (
+            crate::temp_path("payments-engine-threshold-test.csv"),
+            crate::temp_path("payments-engine-threshold-clients.csv"),
+        );
+        std::fs::write(
+            &clients,
+            "client,country,low_balance,large_transaction\n1,DE,5.0,100\n2,DE,,\n",
+        )
+        .map_err(|x| x.to_string())?;
+        let notifications = Notifications::create(path.to_string_lossy().to_string())?
+            .with_thresholds(&clients.to_string_lossy())?;
+        assert_eq!(notifications.threshold(2), None);
+        let engine = Engine::with_clock(SimulatedClock::new(100)).with_notifications(notifications);
+        engine.process(Transaction::deposit(1, 1, 150.0))?;
+        engine.process(Transaction::withdrawal(1, 2, 146.0))?;
+        // Already below the threshold, nothing more is owed.
+        engine.process(Transaction::withdrawal(1, 3, 1.0))?;
+        engine.process(Transaction::deposit(2, 4, 150.0))?;
+        engine.flush_notifications()?;
+
+        let mut reader = csv::Reader::from_path(&path)?;
+        let rows: Vec<_> = reader
+            .records()
+            .map(|record| Ok(record?.iter().map(str::to_string).collect::<Vec<_>>()))
+            .collect::<Result<_>>()?;
+        let notices: Vec<_> = rows
+            .iter()
+            .map(|row| (row[1].as_str(), row[2].as_str()))
+            .collect();
+        assert_eq!(
+            notices,
+            [
+                (
+                    "large_transaction",
+                    r#"{"tx":1,"amount":150.0,"timestamp":100}"#
+                ),
+                (
+                    "low_balance",
+                    r#"{"tx":2,"amount":146.0,"timestamp":100,"balance":4.0}"#
+                ),
+                (
+                    "large_transaction",
+                    r#"{"tx":2,"amount":146.0,"timestamp":100}"#
+                ),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{
+    digest::{client_digest, format_digest},
+    Client, ClientId, Engine, Result,
+};
+use std::{fs::File, io, sync::atomic::Ordering};
+
+#[derive(Debug, Clone, PartialEq)]
+/// A client's balances carried over from another system, the starting
+/// point its transactions here are applied to.
+pub struct OpeningBalance {
+    pub client: ClientId,
+    pub available: f64,
+    pub held: f64,
+    pub locked: bool,
+}
+
+/// Reads the `client`, `available`, `held` and `locked` columns of an
+/// accounts file. `held` and `locked` are optional, and default to 0 and
+/// false when the column or the value is missing.
+pub fn read_opening_balances(reader: csv::Reader<impl io::Read>) -> Result<Vec<OpeningBalance>> {
+    let mut reader = reader;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(name))
+    };
+    let client = column("client").ok_or("accounts have no client column")?;
+    let available = column("available").ok_or("accounts have no available column")?;
+    let (held, locked) = (column("held"), column("locked"));
+    let mut balances = Vec::new();
+    for (row, record) in reader.records().enumerate() {
+        let record = record?;
+        let field = |column: Option<usize>| {
+            column
+                .and_then(|column| record.get(column))
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+        };
+        let invalid = |x: &dyn std::fmt::Display| format!("row {}: {}", row + 1, x);
+        let balance = OpeningBalance {
+            client: field(Some(client))
+                .unwrap_or("")
+                .parse()
+                .map_err(|x| invalid(&x))?,
+            available: field(Some(available))
+                .unwrap_or("")
+                .parse()
+                .map_err(|x| invalid(&x))?,
+            held: field(held)
+                .map_or(Ok(0.0), str::parse)
+                .map_err(|x| invalid(&x))?,
+            locked: field(locked)
+                .map_or(Ok(false), str::parse)
+                .map_err(|x| invalid(&x))?,
+        };
+        if !balance.available.is_finite() || !balance.held.is_finite() || balance.held < 0.0 {
+            return Err(format!(
+                "row {}: client {} has invalid balances",
+                row + 1,
+                balance.client
+            )
+            .into());
+        }
+        balances.push(balance);
+    }
+    Ok(balances)
+}
+
+/// The opening-balance entries of a seeding, one per account with the
+/// state digest after it was seeded, the way a capture records
+/// transactions.
+pub struct OpeningAudit {
+    writer: csv::Writer<File>,
+}
+
+impl OpeningAudit {
+    /// Creates (or truncates) the audit file at `path`.
+    pub fn create(path: &str) -> Result<Self> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record(["entry", "client", "available", "held", "locked", "digest"])?;
+        Ok(OpeningAudit { writer })
+    }
+
+    fn record(&mut self, balance: &OpeningBalance, digest: u64) -> Result<()> {
+        self.writer.write_record([
+            "opening_balance".to_string(),
+            balance.client.to_string(),
+            balance.available.to_string(),
+            balance.held.to_string(),
+            balance.locked.to_string(),
+            format_digest(digest),
+        ])?;
+        Ok(())
+    }
+}
+
+impl Engine {
+    /// Seeds accounts with their opening balances before any transaction
+    /// is processed. Every balance is checked first, and if any client
+    /// appears twice or already exists, none are seeded.
+    ///
+    /// Each seeded account is recorded to `audit` if given.
+    pub fn seed_accounts(
+        &self,
+        balances: &[OpeningBalance],
+        mut audit: Option<&mut OpeningAudit>,
+    ) -> Result<usize> {
+        let mut seen = std::collections::HashSet::new();
+        for balance in balances {
+            if !seen.insert(balance.client) || self.account(balance.client)?.is_some() {
+                return Err(format!("client {} already has an account", balance.client).into());
+            }
+        }
+        for balance in balances {
+            let client = Client {
+                available: balance.available,
+                held: balance.held,
+                locked: balance.locked,
+                ..Client::default()
+            };
+            self.db
+                .digest
+                .fetch_add(client_digest(balance.client, &client), Ordering::SeqCst);
+            self.db.ledger.open(&client)?;
+            self.db.clients.insert(balance.client, client);
+            if let Some(audit) = audit.as_deref_mut() {
+                audit.record(balance, self.db.digest())?;
+            }
+        }
+        if let Some(audit) = audit {
+            audit
+                .writer
+                .flush()
+                .map_err(|x| format!("error writing opening balances: {}", x))?;
+        }
+        Ok(balances.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{digest::state_digest, Transaction, TransactionOutcome};
+
+    #[test]
+    fn seeded_accounts_take_transactions() -> Result<()> {
+        let input = "client,available,held,locked\n1,10.0,2.5,false\n2,3.0,,true\n";
+        let balances = read_opening_balances(csv::Reader::from_reader(input.as_bytes()))?;
+        let engine = Engine::default();
+        assert_eq!(engine.seed_accounts(&balances, None)?, 2);
+        assert_eq!(engine.db.digest(), state_digest(&engine.db)?);
+        assert!(engine.seed_accounts(&balances[..1], None).is_err());
+
+        engine.process(Transaction::withdrawal(1, 1, 4.0))?;
+        let account = engine.account(1)?.expect("client was seeded");
+        assert_eq!((account.available(), account.held()), (6.0, 2.5));
+        assert_ne!(
+            engine.process(Transaction::deposit(2, 2, 1.0))?,
+            TransactionOutcome::Applied
+        );
+        Ok(())
+    }
+}
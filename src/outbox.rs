@@ -0,0 +1,213 @@
+use crate::{
+    append::{latest_state_file, replace_state},
+    state::{is_compressed, read_state, DEFAULT_COMPRESSION},
+    ClientId, Result, Transaction,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A transaction that changed an account, published to the event sink.
+pub struct Event {
+    /// Numbers events in the order they were recorded, across runs. Sinks
+    /// can tell an event they already have by it.
+    pub seq: u64,
+    pub client: ClientId,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub amount: Option<f64>,
+}
+
+impl Event {
+    /// The event for `txn`, numbered once it is recorded.
+    pub fn of(txn: &Transaction) -> Self {
+        Event {
+            seq: 0,
+            client: txn.client_id,
+            tx: txn.txn_id,
+            kind: txn.transaction_type.as_str().to_string(),
+            amount: txn.amount,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Events recorded with the state they describe but not yet published.
+///
+/// The outbox is saved as part of the state, so events are kept exactly
+/// when the changes they describe are, and [`dispatch`] publishes them
+/// afterwards.
+pub struct Outbox {
+    /// Whether new events are recorded. Pending events carried in a state
+    /// are kept either way.
+    recording: bool,
+    /// The events, and the sequence number of the last one ever recorded.
+    pending: Mutex<(Vec<Event>, u64)>,
+}
+
+impl Outbox {
+    /// Starts recording events, numbering them after `published`.
+    pub fn start(&mut self, published: u64) {
+        self.recording = true;
+        if let Ok((_, last)) = self.pending.get_mut() {
+            *last = (*last).max(published);
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Numbers `event` and adds it to the outbox.
+    pub fn record(&self, mut event: Event) -> Result<()> {
+        let mut pending = self.pending.lock().map_err(|_| "outbox lock poisoned")?;
+        let (events, last) = &mut *pending;
+        *last += 1;
+        event.seq = *last;
+        events.push(event);
+        Ok(())
+    }
+
+    /// Puts back the pending events of a saved state.
+    pub fn restore(&self, saved: Vec<Event>) -> Result<()> {
+        let mut pending = self.pending.lock().map_err(|_| "outbox lock poisoned")?;
+        let (events, last) = &mut *pending;
+        *last = saved.iter().map(|event| event.seq).fold(*last, u64::max);
+        events.extend(saved);
+        Ok(())
+    }
+
+    /// The events not published yet, in order.
+    pub fn pending(&self) -> Result<Vec<Event>> {
+        let pending = self.pending.lock().map_err(|_| "outbox lock poisoned")?;
+        Ok(pending.0.clone())
+    }
+}
+
+/// The sequence number of the last event in the sink at `path`, 0 if there
+/// is none.
+pub fn last_published(path: &Path) -> Result<u64> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(x) if x.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(x) => return Err(format!("error opening {}: {}", path.display(), x).into()),
+    };
+    let mut last = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|x| format!("error reading {}: {}", path.display(), x))?;
+        // A line cut short by a crash was never published.
+        if let Ok(event) = serde_json::from_str::<Event>(&line) {
+            last = last.max(event.seq);
+        }
+    }
+    Ok(last)
+}
+
+/// Publishes the events pending in the state saved in `dir` to the sink,
+/// one JSON line each appended to `sink`, then clears them from the
+/// state. Returns how many were published.
+///
+/// Events the sink already has, because an earlier dispatch stopped
+/// between publishing and clearing, are skipped, so every event is
+/// published exactly once however often this is interrupted and rerun.
+pub fn dispatch(dir: &Path, sink: &Path) -> Result<usize> {
+    let Some(path) = latest_state_file(dir)? else {
+        return Ok(0);
+    };
+    let mut state = read_state(path.to_string_lossy().to_string())?;
+    if state.outbox.is_empty() {
+        return Ok(0);
+    }
+    let published = last_published(sink)?;
+    let fresh: Vec<_> = std::mem::take(&mut state.outbox)
+        .into_iter()
+        .filter(|event| event.seq > published)
+        .collect();
+    let error = |x: std::io::Error| format!("error publishing to {}: {}", sink.display(), x);
+    let mut out = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .read(true)
+        .open(sink)
+        .map_err(error)?;
+    // Start on a fresh line if a crash cut the last one short.
+    let len = out.metadata().map_err(error)?.len();
+    if len > 0 {
+        let mut last = [0];
+        out.seek(SeekFrom::Start(len - 1)).map_err(error)?;
+        out.read_exact(&mut last).map_err(error)?;
+        if last[0] != b'\n' {
+            writeln!(out).map_err(error)?;
+        }
+    }
+    for event in &fresh {
+        let line = serde_json::to_string(event).map_err(|x| x.to_string())?;
+        writeln!(out, "{}", line).map_err(error)?;
+    }
+    out.sync_all().map_err(error)?;
+
+    let compression = is_compressed(&path)?.then_some(DEFAULT_COMPRESSION);
+    replace_state(&state, &path, compression)?;
+    Ok(fresh.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        append::{load_previous_runs, save_run, SaveOptions},
+        clock::SimulatedClock,
+        Engine,
+    };
+
+    #[test]
+    fn events_are_published_exactly_once() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-outbox-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let sink = dir.join("events.jsonl");
+        let published = || -> Result<Vec<u64>> {
+            let events = std::fs::read_to_string(&sink).map_err(|x| x.to_string())?;
+            events
+                .lines()
+                .map(|line| {
+                    Ok(serde_json::from_str::<Event>(line)
+                        .map_err(|x| x.to_string())?
+                        .seq)
+                })
+                .collect()
+        };
+
+        let mut first = Engine::with_clock(SimulatedClock::new(0));
+        first.db.outbox.start(last_published(&sink)?);
+        load_previous_runs(&mut first, &dir)?;
+        first.process(Transaction::deposit(1, 1, 2.0))?;
+        first.process(Transaction::withdrawal(1, 2, 5.0))?;
+        first.process(Transaction::withdrawal(1, 3, 1.0))?;
+        save_run(&first, &dir, SaveOptions::default())?;
+        assert_eq!(dispatch(&dir, &sink)?, 2);
+        assert_eq!(published()?, [1, 2]);
+        assert_eq!(dispatch(&dir, &sink)?, 0);
+
+        // Stopping between publishing and clearing the outbox leaves the
+        // published events in the state.
+        save_run(&first, &dir, SaveOptions::default())?;
+        assert_eq!(dispatch(&dir, &sink)?, 0);
+        assert_eq!(published()?, [1, 2]);
+
+        let mut second = Engine::with_clock(SimulatedClock::new(0));
+        second.db.outbox.start(last_published(&sink)?);
+        load_previous_runs(&mut second, &dir)?;
+        second.process(Transaction::deposit(1, 4, 1.0))?;
+        save_run(&second, &dir, SaveOptions::default())?;
+        assert_eq!(dispatch(&dir, &sink)?, 1);
+        assert_eq!(published()?, [1, 2, 3]);
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{digest::client_digest, i18n::Reason, ClientId, Engine, Result, TransactionOutcome};
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+/// The first bytes of an `--outcomes` file, the last one its version.
+const MAGIC: [u8; 8] = *b"PEOUTCM\x02";
+
+/// The bytes of a record: the row, the client, the status, the reason and
+/// the hash of the client's balances, little endian.
+const RECORD_LEN: usize = 8 + 8 + 1 + 1 + 8;
+
+/// The bytes of a record in files of the first version, whose clients
+/// took 2 bytes.
+const NARROW_RECORD_LEN: usize = 8 + 2 + 1 + 1 + 8;
+
+/// Written in place of a reason by the records that have none.
+const NO_REASON: u8 = u8::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What became of an input row.
+pub enum RowStatus {
+    Applied,
+    Rejected(Reason),
+    Duplicate,
+    /// The row couldn't be parsed or broke a consistency rule, and was
+    /// skipped.
+    Error,
+    /// The row was left out by `--sample`.
+    Skipped,
+}
+
+impl RowStatus {
+    pub fn new(outcome: &TransactionOutcome) -> Self {
+        match outcome {
+            TransactionOutcome::Applied => RowStatus::Applied,
+            TransactionOutcome::Rejected(reason) => RowStatus::Rejected(*reason),
+            TransactionOutcome::Duplicate => RowStatus::Duplicate,
+        }
+    }
+
+    fn encode(&self) -> [u8; 2] {
+        match self {
+            RowStatus::Applied => [0, NO_REASON],
+            RowStatus::Rejected(reason) => [
+                1,
+                Reason::ALL.iter().position(|r| r == reason).unwrap_or(0) as u8,
+            ],
+            RowStatus::Duplicate => [2, NO_REASON],
+            RowStatus::Error => [3, NO_REASON],
+            RowStatus::Skipped => [4, NO_REASON],
+        }
+    }
+
+    fn decode(status: u8, reason: u8) -> Result<Self> {
+        Ok(match status {
+            0 => RowStatus::Applied,
+            1 => RowStatus::Rejected(
+                *Reason::ALL
+                    .get(reason as usize)
+                    .ok_or_else(|| format!("unknown reason {} in an outcomes file", reason))?,
+            ),
+            2 => RowStatus::Duplicate,
+            3 => RowStatus::Error,
+            4 => RowStatus::Skipped,
+            _ => return Err(format!("unknown status {} in an outcomes file", status).into()),
+        })
+    }
+}
+
+impl std::fmt::Display for RowStatus {
+    /// As a TCP connection answers a row, e.g. `rejected,insufficient_funds`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RowStatus::Applied => write!(f, "applied"),
+            RowStatus::Rejected(reason) => write!(f, "rejected,{}", reason.code()),
+            RowStatus::Duplicate => write!(f, "duplicate"),
+            RowStatus::Error => write!(f, "error"),
+            RowStatus::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What became of one input row, as kept by an `--outcomes` file.
+pub struct OutcomeRecord {
+    /// The row's number, counting from 1 after the header, across the
+    /// inputs of the run.
+    pub row: u64,
+    /// The client of the row, 0 for rows that couldn't be parsed.
+    pub client_id: ClientId,
+    pub status: RowStatus,
+    /// The digest of the client's account after the row, see
+    /// [`client_digest`], 0 if the client has no account. Rows that
+    /// didn't reach the engine have 0 too.
+    pub balances: u64,
+}
+
+impl OutcomeRecord {
+    fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut bytes = [0; RECORD_LEN];
+        bytes[..8].copy_from_slice(&self.row.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.client_id.to_le_bytes());
+        bytes[16..18].copy_from_slice(&self.status.encode());
+        bytes[18..].copy_from_slice(&self.balances.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes a record of [`RECORD_LEN`] bytes, or of
+    /// [`NARROW_RECORD_LEN`] from a file of the first version.
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let u64_at =
+            |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap_or_default());
+        let (client_id, at) = match bytes.len() {
+            NARROW_RECORD_LEN => (u16::from_le_bytes([bytes[8], bytes[9]]).into(), 10),
+            _ => (u64_at(8), 16),
+        };
+        Ok(OutcomeRecord {
+            row: u64_at(0),
+            client_id,
+            status: RowStatus::decode(bytes[at], bytes[at + 1])?,
+            balances: u64_at(at + 2),
+        })
+    }
+}
+
+/// The digest of the account of `client_id` as it is now, 0 without one.
+pub fn balances_hash(engine: &Engine, client_id: ClientId) -> Result<u64> {
+    if let Some(client) = engine.db.clients.get(&client_id) {
+        return Ok(client_digest(client_id, &client));
+    }
+    Ok(match &engine.db.cold {
+        Some(cold) => cold
+            .store
+            .get(client_id)?
+            .map_or(0, |client| client_digest(client_id, &client)),
+        None => 0,
+    })
+}
+
+/// Where a run records what became of each of its rows, if anywhere: a
+/// compact binary file of fixed size records, so runs over millions of
+/// rows can be kept and compared row by row.
+#[derive(Debug)]
+pub struct OutcomeLog {
+    file: Option<BufWriter<File>>,
+}
+
+impl OutcomeLog {
+    /// Records to a file at `path`, or nowhere if not given.
+    pub fn open(path: Option<&Path>) -> Result<Self> {
+        let file = match path {
+            Some(path) => {
+                let mut file = BufWriter::new(File::create(path).map_err(|x| {
+                    format!("error creating outcomes file {}: {}", path.display(), x)
+                })?);
+                file.write_all(&MAGIC)
+                    .map_err(|x| format!("error writing outcomes: {}", x))?;
+                Some(file)
+            }
+            None => None,
+        };
+        Ok(OutcomeLog { file })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn record(&mut self, record: OutcomeRecord) -> Result<()> {
+        match &mut self.file {
+            Some(file) => file
+                .write_all(&record.encode())
+                .map_err(|x| format!("error writing outcomes: {}", x).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes the records written so far.
+    pub fn finish(self) -> Result<()> {
+        match self.file {
+            Some(mut file) => file
+                .flush()
+                .map_err(|x| format!("error writing outcomes: {}", x).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads the records of the outcomes file at `path`, in row order.
+pub fn read_outcomes(path: &Path) -> Result<Vec<OutcomeRecord>> {
+    let context = |x: std::io::Error| format!("error reading outcomes {}: {}", path.display(), x);
+    let mut reader = BufReader::new(File::open(path).map_err(context)?);
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic).map_err(context)?;
+    let record_len = match magic {
+        MAGIC => RECORD_LEN,
+        _ if magic[..7] == MAGIC[..7] && magic[7] == 1 => NARROW_RECORD_LEN,
+        _ => return Err(format!("{} is not an outcomes file", path.display()).into()),
+    };
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).map_err(context)?;
+    if bytes.len() % record_len != 0 {
+        return Err(format!("outcomes file {} is truncated", path.display()).into());
+    }
+    bytes
+        .chunks_exact(record_len)
+        .map(OutcomeRecord::decode)
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A row that became something else in one run than in another. Either
+/// side is `None` if that run has no such row.
+pub struct OutcomeDiff {
+    pub row: u64,
+    pub ours: Option<OutcomeRecord>,
+    pub theirs: Option<OutcomeRecord>,
+}
+
+/// The rows of two outcomes files that differ, by row. A row differs if
+/// its status or its client's balances do, so the first of them is where
+/// two runs over the same input parted ways.
+pub fn diff_outcomes(ours: &[OutcomeRecord], theirs: &[OutcomeRecord]) -> Vec<OutcomeDiff> {
+    let (mut ours, mut theirs) = (ours.iter().peekable(), theirs.iter().peekable());
+    let mut diffs = Vec::new();
+    loop {
+        let order = match (ours.peek(), theirs.peek()) {
+            (None, None) => return diffs,
+            (Some(a), Some(b)) => a.row.cmp(&b.row),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+        };
+        let (a, b) = match order {
+            Ordering::Equal => (ours.next().copied(), theirs.next().copied()),
+            Ordering::Less => (ours.next().copied(), None),
+            Ordering::Greater => (None, theirs.next().copied()),
+        };
+        if a != b {
+            let row = a.or(b).map_or(0, |record| record.row);
+            diffs.push(OutcomeDiff {
+                row,
+                ours: a,
+                theirs: b,
+            });
+        }
+    }
+}
+
+/// Prints the differences as csv to stdout, each row's status and
+/// balances in the first run followed by those in the second.
+pub fn print_outcome_diffs(diffs: &[OutcomeDiff]) {
+    println!("row,client,status,other_status,balances,other_balances");
+    let show = |record: &Option<OutcomeRecord>| {
+        record.map_or(("-".to_string(), "-".to_string()), |record| {
+            (
+                format!("\"{}\"", record.status),
+                format!("{:016x}", record.balances),
+            )
+        })
+    };
+    for diff in diffs {
+        let client_id = diff
+            .ours
+            .or(diff.theirs)
+            .map_or(0, |record| record.client_id);
+        let ((status, balances), (other_status, other_balances)) =
+            (show(&diff.ours), show(&diff.theirs));
+        println!(
+            "{},{},{},{},{},{}",
+            diff.row, client_id, status, other_status, balances, other_balances
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{inputs::read_inputs, run_transactions, RunOptions};
+
+    #[test]
+    fn runs_are_compared_row_by_row() -> Result<()> {
+        let mut outcomes = Vec::new();
+        for (run, first) in [("a", "2.0"), ("b", "5.0")] {
+            let input = crate::temp_path(&format!("payments-engine-outcomes-{}.csv", run));
+            let path = crate::temp_path(&format!("payments-engine-outcomes-{}.bin", run));
+            std::fs::write(
+                &input,
+                format!(
+                    "type,client,tx,amount\ndeposit,1,1,{}\nwithdrawal,1,2,3.0\ndeposit,1,x,1.0\ndeposit,2,3,1.0\n",
+                    first
+                ),
+            )?;
+            let options = RunOptions {
+                outcomes: Some(path.clone()),
+                ..RunOptions::default()
+            };
+            let inputs = [input.to_string_lossy().to_string()];
+            run_transactions(
+                read_inputs(&inputs, &options)?,
+                &Engine::default(),
+                &options,
+            )?;
+            outcomes.push(read_outcomes(&path)?);
+            std::fs::remove_file(&input)?;
+            std::fs::remove_file(&path)?;
+        }
+        let statuses: Vec<_> = outcomes[0].iter().map(|record| record.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                RowStatus::Applied,
+                RowStatus::Rejected(Reason::InsufficientFunds),
+                RowStatus::Error,
+                RowStatus::Applied,
+            ]
+        );
+        // The deposit of a different amount and the withdrawal it now
+        // covers differ, the rows after them don't.
+        let diffs = diff_outcomes(&outcomes[0], &outcomes[1]);
+        let rows: Vec<_> = diffs.iter().map(|diff| diff.row).collect();
+        assert_eq!(rows, [1, 2]);
+        assert_eq!(
+            diffs[1].theirs.map(|record| record.status),
+            Some(RowStatus::Applied)
+        );
+        assert_eq!(diff_outcomes(&outcomes[0], &outcomes[0][..2]).len(), 2);
+        Ok(())
+    }
+}
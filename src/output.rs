@@ -0,0 +1,356 @@
+use crate::{
+    account::AccountView,
+    format::Format,
+    ledger::TOLERANCE,
+    precision::Precision,
+    report::{write_selected, ReportOptions},
+    sink::Sink,
+    ClientId, Database, Result,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    sync::atomic::Ordering,
+};
+
+/// The columns of the report, in order. With regions configured, or
+/// accounts holding funds in other currencies, a `currency` column follows
+/// them, and with pending holds a
+/// `pending_hold` column comes last.
+pub const REPORT_COLUMNS: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+/// Opens the file at `path` to write a report to, stdout if not given.
+pub fn open_output(path: Option<&str>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).map_err(|x| format!("error creating {}: {}", path, x))?,
+        )),
+        None => Box::new(io::stdout().lock()),
+    })
+}
+
+/// How the rows of a report are written.
+enum Encoding<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Json(W),
+}
+
+#[derive(Serialize)]
+/// An account as a line of a JSON report.
+struct JsonAccount<'a> {
+    client: ClientId,
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending_hold: Option<f64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+/// The sums of the accounts of one currency, the footer of a report.
+pub struct CurrencyTotals {
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+}
+
+impl CurrencyTotals {
+    fn add(&mut self, account: &AccountView) {
+        self.available += account.available();
+        self.held += account.held();
+        self.total += account.total();
+    }
+}
+
+#[derive(Serialize)]
+/// A footer as a line of a JSON report.
+struct JsonTotals<'a> {
+    totals: bool,
+    available: f64,
+    held: f64,
+    total: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<&'a str>,
+}
+
+/// Writes accounts as a report, a row at a time as they are handed to it.
+/// In csv, fields are quoted where needed and amounts always have the
+/// decimal places of [`crate::policy::Policy::precision`], four by default.
+/// In JSON, every account is an object on its own line with the same
+/// fields, amounts rounded to those places.
+pub struct ReportWriter<'a, W: Write> {
+    db: &'a Database,
+    /// Whether the report has a currency column.
+    currencies: bool,
+    /// Whether the report has a pending hold column.
+    pending_holds: bool,
+    precision: Precision,
+    /// The sums so far per currency, if the report ends with them.
+    totals: Option<BTreeMap<Option<String>, CurrencyTotals>>,
+    /// Whether dormant accounts are written, see
+    /// [`crate::policy::Policy::dormant_after`].
+    include_dormant: bool,
+    /// The funds of the dormant accounts left out, which the ledger still
+    /// holds.
+    dormant_total: f64,
+    encoding: Encoding<W>,
+}
+
+impl<'a, W: Write> ReportWriter<'a, W> {
+    /// Starts a report of the accounts of `db` on `out`, writing the
+    /// header of a csv report.
+    pub fn new(db: &'a Database, out: W, format: Format) -> Result<Self> {
+        let currencies =
+            db.policy.regions.is_configured() || db.multi_currency.load(Ordering::SeqCst);
+        let pending_holds = db.policy.pending_holds;
+        let encoding = match format {
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(out);
+                let currency = currencies.then_some("currency");
+                let pending_hold = pending_holds.then_some("pending_hold");
+                writer.write_record(
+                    REPORT_COLUMNS
+                        .into_iter()
+                        .chain(currency)
+                        .chain(pending_hold),
+                )?;
+                Encoding::Csv(Box::new(writer))
+            }
+            Format::Json => Encoding::Json(out),
+        };
+        Ok(ReportWriter {
+            db,
+            currencies,
+            pending_holds,
+            precision: db.policy.precision,
+            totals: None,
+            include_dormant: false,
+            dormant_total: 0.0,
+            encoding,
+        })
+    }
+
+    /// Ends the report with a row of totals per currency, a single one
+    /// without regions, and checks them against the engine's ledger when
+    /// it finishes. Only a report of every account balances.
+    pub fn with_totals(mut self) -> Self {
+        self.totals = Some(BTreeMap::new());
+        self
+    }
+
+    /// Writes dormant accounts too, which [`write_accounts`] leaves out
+    /// otherwise.
+    pub fn with_dormant(mut self) -> Self {
+        self.include_dormant = true;
+        self
+    }
+
+    /// Notes an account left out of the report, whose funds still count
+    /// towards the ledger the totals are checked against.
+    pub fn leave_out(&mut self, account: &AccountView) {
+        self.dormant_total += account.total();
+    }
+
+    pub fn write(&mut self, account: &AccountView) -> Result<()> {
+        let currency = match self.currencies {
+            true if account.currency().is_some() => account.currency().map(str::to_string),
+            true => {
+                let now = self.db.latest_activity.load(Ordering::SeqCst);
+                let rules = self.db.policy.regions.rules_for(account.client_id(), now);
+                Some(rules.currency.clone().unwrap_or_default())
+            }
+            false => None,
+        };
+        if let Some(totals) = &mut self.totals {
+            totals.entry(currency.clone()).or_default().add(account);
+        }
+        let pending_hold = self.pending_holds.then_some(account.pending_hold());
+        let precision = self.precision;
+        match &mut self.encoding {
+            Encoding::Csv(writer) => {
+                let record = [
+                    account.client_id().to_string(),
+                    precision.format(account.available()),
+                    precision.format(account.held()),
+                    precision.format(account.total()),
+                    account.locked().to_string(),
+                ];
+                let pending_hold = pending_hold.map(|pending| precision.format(pending));
+                writer.write_record(record.into_iter().chain(currency).chain(pending_hold))?;
+            }
+            Encoding::Json(out) => {
+                let line = JsonAccount {
+                    client: account.client_id(),
+                    available: precision.round(account.available()),
+                    held: precision.round(account.held()),
+                    total: precision.round(account.total()),
+                    locked: account.locked(),
+                    currency: currency.as_deref(),
+                    pending_hold: pending_hold.map(|pending| precision.round(pending)),
+                };
+                serde_json::to_writer(&mut *out, &line).map_err(|x| x.to_string())?;
+                writeln!(out).map_err(|x| format!("error writing the report: {}", x))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the totals, if the report has them, and flushes the rows
+    /// written so far to the underlying writer.
+    pub fn finish(mut self) -> Result<()> {
+        if let Some(totals) = self.totals.take() {
+            self.check_totals(&totals)?;
+            for (currency, sums) in &totals {
+                self.write_totals(currency.as_deref(), sums)?;
+            }
+        }
+        match self.encoding {
+            Encoding::Csv(mut writer) => writer.flush(),
+            Encoding::Json(mut out) => out.flush(),
+        }
+        .map_err(|x| format!("error writing the report: {}", x))?;
+        Ok(())
+    }
+}
+
+impl<W: Write> ReportWriter<'_, W> {
+    fn write_totals(&mut self, currency: Option<&str>, sums: &CurrencyTotals) -> Result<()> {
+        let precision = self.precision;
+        match &mut self.encoding {
+            Encoding::Csv(writer) => {
+                let record = [
+                    "total".to_string(),
+                    precision.format(sums.available),
+                    precision.format(sums.held),
+                    precision.format(sums.total),
+                    String::new(),
+                ];
+                let currency = currency.filter(|_| self.currencies).map(str::to_string);
+                let pending_hold = self.pending_holds.then(String::new);
+                writer.write_record(record.into_iter().chain(currency).chain(pending_hold))?;
+            }
+            Encoding::Json(out) => {
+                let line = JsonTotals {
+                    totals: true,
+                    available: precision.round(sums.available),
+                    held: precision.round(sums.held),
+                    total: precision.round(sums.total),
+                    currency,
+                };
+                serde_json::to_writer(&mut *out, &line).map_err(|x| x.to_string())?;
+                writeln!(out).map_err(|x| format!("error writing the report: {}", x))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if the funds of the accounts written don't add up to what the
+    /// ledger holds for the clients, which would mean accounts were left
+    /// out or counted twice.
+    fn check_totals(&self, totals: &BTreeMap<Option<String>, CurrencyTotals>) -> Result<()> {
+        let reported = totals.values().map(|sums| sums.total).sum::<f64>() + self.dormant_total;
+        let cash = self.db.ledger.cash()?;
+        if (reported - cash).abs() > TOLERANCE {
+            return Err(format!(
+                "the report totals {:.4} but the ledger holds {:.4} for the clients",
+                reported, cash
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Writes the final state of every client as a report to `out`.
+pub fn write_report(db: &Database, out: impl Write, format: Format) -> Result<()> {
+    write_accounts(ReportWriter::new(db, out, format)?)
+}
+
+/// Writes every client of the report's database to it in client order, a
+/// row per currency it has funds in, but for dormant ones unless the
+/// report includes them, and finishes it.
+pub fn write_accounts<W: Write>(report: ReportWriter<'_, W>) -> Result<()> {
+    let (db, include_dormant) = (report.db, report.include_dormant);
+    write_to_sink(db, Box::new(report), include_dormant)
+}
+
+/// Like [`write_accounts`], to any [`Sink`], such as [`crate::sink::Sinks`]
+/// writing to several at once. [`write_selected`] sorts and filters the
+/// accounts otherwise.
+pub fn write_to_sink(db: &Database, sink: Box<dyn Sink + '_>, include_dormant: bool) -> Result<()> {
+    let options = ReportOptions {
+        include_dormant,
+        ..ReportOptions::default()
+    };
+    write_selected(db, sink, &options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Engine, Transaction};
+
+    #[test]
+    fn the_report_is_valid_csv() -> Result<()> {
+        let engine = Engine::default();
+        engine.process(Transaction::deposit(1, 1, 1.5))?;
+        engine.process(Transaction::deposit(2, 2, 2.0))?;
+        engine.process(Transaction::dispute(2, 2))?;
+        let mut out = Vec::new();
+        write_report(&engine.db, &mut out, Format::Csv)?;
+        let mut reader = csv::Reader::from_reader(out.as_slice());
+        assert_eq!(reader.headers()?, REPORT_COLUMNS.as_slice());
+        let mut rows: Vec<Vec<String>> = reader
+            .records()
+            .map(|record| Ok(record?.iter().map(str::to_string).collect()))
+            .collect::<Result<_>>()?;
+        rows.sort();
+        assert_eq!(
+            rows,
+            [
+                ["1", "1.5000", "0.0000", "1.5000", "false"],
+                ["2", "0.0000", "2.0000", "2.0000", "false"],
+            ]
+        );
+
+        let mut out = Vec::new();
+        write_report(&engine.db, &mut out, Format::Json)?;
+        let mut lines: Vec<_> = String::from_utf8_lossy(&out)
+            .lines()
+            .map(str::to_string)
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                r#"{"client":1,"available":1.5,"held":0.0,"total":1.5,"locked":false}"#,
+                r#"{"client":2,"available":0.0,"held":2.0,"total":2.0,"locked":false}"#,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn totals_are_checked_against_the_ledger() -> Result<()> {
+        let engine = Engine::default();
+        engine.process(Transaction::deposit(1, 1, 1.5))?;
+        engine.process(Transaction::deposit(2, 2, 2.0))?;
+        engine.process(Transaction::dispute(2, 2))?;
+        let mut out = Vec::new();
+        write_accounts(ReportWriter::new(&engine.db, &mut out, Format::Csv)?.with_totals())?;
+        let report = String::from_utf8_lossy(&out);
+        assert_eq!(report.lines().last(), Some("total,1.5000,2.0000,3.5000,"));
+
+        // A report missing an account doesn't add up.
+        let mut out = Vec::new();
+        let mut report = ReportWriter::new(&engine.db, &mut out, Format::Json)?.with_totals();
+        report.write(&engine.account(1)?.ok_or("no account")?)?;
+        assert!(report.finish().is_err());
+        Ok(())
+    }
+}
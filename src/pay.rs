@@ -0,0 +1,226 @@
+use crate::{open_file_read_csv, schema::Schema, ClientId, Result, Transaction, TransactionType};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+};
+
+/// Starts every `.pay` file, the last byte is the format version.
+const MAGIC: [u8; 4] = *b"PAY\x02";
+
+// Bits of a record's flags byte, after the three bits of the type.
+const AMOUNT: u8 = 1 << 3;
+const TIMESTAMP: u8 = 1 << 4;
+const MEMO: u8 = 1 << 5;
+const CATEGORY: u8 = 1 << 6;
+const TO_CLIENT: u8 = 1 << 7;
+
+/// Whether `path` names a compiled file rather than a csv.
+pub fn is_pay(path: &str) -> bool {
+    path.ends_with(".pay")
+}
+
+/// Compiles the csv at `input` into the binary format at `output`, so later
+/// runs over it skip parsing text. Returns the number of transactions.
+///
+/// Each transaction is one record, all integers little endian: a flags
+/// byte holding the type (its index in the csv's order of types) and which
+/// optional fields follow, the client (`u64`) and id (`u32`), then the
+/// amount (`f64`), timestamp (`u64`), memo (`u16` length and UTF-8),
+/// category (`u8` length and UTF-8) and client a transfer credits (`u64`)
+/// when present. Files of the first version, whose clients were `u16`,
+/// are still read. Extra columns are not kept, and transactions in another
+/// currency than the account's own can't be compiled.
+pub fn compile(input: String, output: &str) -> Result<u64> {
+    let mut reader = open_file_read_csv(input)?;
+    let schema = Schema::from_headers(reader.headers()?, &[])?;
+    let error = |x: std::io::Error| format!("error writing {}: {}", output, x);
+    let mut out = BufWriter::new(File::create(output).map_err(error)?);
+    out.write_all(&MAGIC).map_err(error)?;
+    let mut count = 0;
+    for record in reader.records() {
+        let txn = schema.parse(&record?)?;
+        if let Some(currency) = &txn.currency {
+            return Err(format!(
+                "transaction {} is in {}, the binary format has no currencies",
+                txn.txn_id, currency
+            )
+            .into());
+        }
+        write_record(&mut out, &txn).map_err(error)?;
+        count += 1;
+    }
+    out.flush().map_err(error)?;
+    Ok(count)
+}
+
+fn write_record(out: &mut impl Write, txn: &Transaction) -> std::io::Result<()> {
+    let memo = txn.memo.as_deref().map(str::as_bytes);
+    let category = txn.category.as_deref().map(str::as_bytes);
+    let mut flags = txn.transaction_type.index() as u8;
+    flags |= if txn.amount.is_some() { AMOUNT } else { 0 };
+    flags |= if txn.timestamp.is_some() {
+        TIMESTAMP
+    } else {
+        0
+    };
+    flags |= if memo.is_some() { MEMO } else { 0 };
+    flags |= if category.is_some() { CATEGORY } else { 0 };
+    flags |= if txn.to_client.is_some() {
+        TO_CLIENT
+    } else {
+        0
+    };
+    out.write_all(&[flags])?;
+    out.write_all(&txn.client_id.to_le_bytes())?;
+    out.write_all(&txn.txn_id.to_le_bytes())?;
+    if let Some(amount) = txn.amount {
+        out.write_all(&amount.to_le_bytes())?;
+    }
+    if let Some(timestamp) = txn.timestamp {
+        out.write_all(&timestamp.to_le_bytes())?;
+    }
+    // Sanitizing already capped memos and categories well below these.
+    if let Some(memo) = memo {
+        let len = memo.len().min(u16::MAX as usize);
+        out.write_all(&(len as u16).to_le_bytes())?;
+        out.write_all(&memo[..len])?;
+    }
+    if let Some(category) = category {
+        let len = category.len().min(u8::MAX as usize);
+        out.write_all(&[len as u8])?;
+        out.write_all(&category[..len])?;
+    }
+    if let Some(to_client) = txn.to_client {
+        out.write_all(&to_client.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Reads the transactions of a file written by [`compile`], one at a time.
+pub fn read_pay(path: String) -> Result<impl Iterator<Item = Result<Transaction>>> {
+    let file = File::open(&path).map_err(|x| format!("error opening {}: {}", path, x))?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0; 4];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|x| format!("error reading {}: {}", path, x))?;
+    let narrow = match magic {
+        MAGIC => false,
+        [b'P', b'A', b'Y', 1] => true,
+        _ => return Err(format!("{} is not a compiled transaction file", path).into()),
+    };
+    Ok(std::iter::from_fn(move || {
+        let mut flags = [0];
+        match reader.read_exact(&mut flags) {
+            Ok(()) => Some(
+                read_record(&mut reader, flags[0], narrow)
+                    .map_err(|x| format!("error reading {}: {}", path, x).into()),
+            ),
+            Err(x) if x.kind() == ErrorKind::UnexpectedEof => None,
+            Err(x) => Some(Err(format!("error reading {}: {}", path, x).into())),
+        }
+    }))
+}
+
+fn read_record(
+    reader: &mut impl Read,
+    flags: u8,
+    narrow: bool,
+) -> std::result::Result<Transaction, String> {
+    fn bytes<const N: usize>(reader: &mut impl Read) -> std::result::Result<[u8; N], String> {
+        let mut buffer = [0; N];
+        reader.read_exact(&mut buffer).map_err(|x| x.to_string())?;
+        Ok(buffer)
+    }
+    fn text(reader: &mut impl Read, len: usize) -> std::result::Result<Box<str>, String> {
+        let mut buffer = vec![0; len];
+        reader.read_exact(&mut buffer).map_err(|x| x.to_string())?;
+        Ok(String::from_utf8(buffer).map_err(|x| x.to_string())?.into())
+    }
+    let transaction_type = *TransactionType::ALL
+        .get((flags & 0b111) as usize)
+        .ok_or_else(|| format!("unknown transaction type {}", flags & 0b111))?;
+    let client = |reader: &mut _| match narrow {
+        true => bytes(reader).map(|x| u16::from_le_bytes(x).into()),
+        false => bytes(reader).map(ClientId::from_le_bytes),
+    };
+    let client_id = client(reader)?;
+    let txn_id = u32::from_le_bytes(bytes(reader)?);
+    let amount = match flags & AMOUNT {
+        0 => None,
+        _ => Some(f64::from_le_bytes(bytes(reader)?)),
+    };
+    let timestamp = match flags & TIMESTAMP {
+        0 => None,
+        _ => Some(u64::from_le_bytes(bytes(reader)?)),
+    };
+    let memo = match flags & MEMO {
+        0 => None,
+        _ => {
+            let len = u16::from_le_bytes(bytes(reader)?) as usize;
+            Some(text(reader, len)?)
+        }
+    };
+    let category = match flags & CATEGORY {
+        0 => None,
+        _ => {
+            let [len] = bytes(reader)?;
+            Some(text(reader, len as usize)?)
+        }
+    };
+    let to_client = match flags & TO_CLIENT {
+        0 => None,
+        _ => Some(client(reader)?),
+    };
+    Ok(Transaction {
+        transaction_type,
+        client_id,
+        txn_id,
+        amount,
+        to_client,
+        timestamp,
+        memo,
+        category,
+        currency: None,
+        idempotency_key: None,
+        extras: Box::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiled_files_read_back_as_the_csv() -> Result<()> {
+        let input = "test-files/spend_by_category.csv".to_string();
+        let output = crate::temp_path("payments-engine-compile-test.pay");
+        let output = output.to_string_lossy().to_string();
+        let count = compile(input.clone(), &output)?;
+
+        let mut reader = open_file_read_csv(input)?;
+        let schema = Schema::from_headers(reader.headers()?, &[])?;
+        let expected = reader
+            .records()
+            .map(|record| schema.parse(&record?))
+            .collect::<Result<Vec<_>>>()?;
+        let compiled = read_pay(output)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(compiled.len() as u64, count);
+        assert_eq!(compiled, expected);
+
+        // Files of the first version, with narrow clients, still read.
+        let narrow = crate::temp_path("payments-engine-compile-v1-test.pay");
+        let mut bytes = b"PAY\x01".to_vec();
+        bytes.push(TransactionType::Deposit.index() as u8 | AMOUNT);
+        bytes.extend(7u16.to_le_bytes());
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend(2.5f64.to_le_bytes());
+        std::fs::write(&narrow, bytes)?;
+        let narrow = read_pay(narrow.to_string_lossy().to_string())?;
+        assert_eq!(
+            narrow.collect::<Result<Vec<_>>>()?,
+            [Transaction::deposit(7, 1, 2.5)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use crate::{
+    append::{read_chain, write_snapshot, STATE_FILE},
+    opening::{OpeningAudit, OpeningBalance},
+    state::{is_compressed, DEFAULT_COMPRESSION},
+    Engine, Result,
+};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The opening-balance entries of the current period, in an append
+/// directory.
+pub const OPENING_FILE: &str = "opening_balances.csv";
+/// Where closed periods are archived in an append directory, one
+/// directory each.
+pub const PERIODS_DIR: &str = "periods";
+
+#[derive(Debug, Clone, PartialEq)]
+/// What closing a period did.
+pub struct ClosedPeriod {
+    /// Accounts whose closing balances were written.
+    pub accounts: usize,
+    /// Transactions moved to the archive.
+    pub archived: usize,
+    /// Transactions kept for the next period because they are disputed.
+    pub carried: usize,
+    /// The directory the period was archived to.
+    pub archive: PathBuf,
+}
+
+/// Closes the current period of the append directory `dir` under the name
+/// `period`, e.g. `2024-05`.
+///
+/// The closing balance of every account and the period's transactions are
+/// written to `periods/<period>/`, along with the opening-balance entries
+/// the period started with. The state is then saved as a new full
+/// snapshot without the archived transactions, and the closing balances
+/// are recorded as the opening-balance entries of the next period.
+///
+/// Transactions under an open dispute stay in the state so the dispute can
+/// still be resolved or charged back, every other one is archived and can
+/// no longer be disputed. The state is only replaced once the archive is
+/// written, so an interrupted close loses nothing, but its partial archive
+/// has to be removed before closing the period again.
+pub fn close_period(dir: &Path, period: &str) -> Result<ClosedPeriod> {
+    let (mut state, deltas) =
+        read_chain(dir)?.ok_or_else(|| format!("{} holds no state to close", dir.display()))?;
+    let archive = dir.join(PERIODS_DIR).join(period);
+    if archive.exists() {
+        return Err(format!("period {} is already closed", period).into());
+    }
+    fs::create_dir_all(&archive)
+        .map_err(|x| format!("error creating {}: {}", archive.display(), x))?;
+
+    let mut closing = csv::Writer::from_path(archive.join("closing_balances.csv"))?;
+    closing.write_record(["client", "available", "held", "total", "locked"])?;
+    for account in &state.accounts {
+        closing.write_record([
+            account.client.to_string(),
+            account.available.to_string(),
+            account.held.to_string(),
+            (account.available + account.held).to_string(),
+            account.locked.to_string(),
+        ])?;
+    }
+    closing
+        .flush()
+        .map_err(|x| format!("error writing closing balances: {}", x))?;
+
+    let disputed: HashSet<u32> = state
+        .accounts
+        .iter()
+        .flat_map(|account| account.disputed.iter().copied())
+        .collect();
+    let (carried, archived): (Vec<_>, Vec<_>) = std::mem::take(&mut state.transactions)
+        .into_iter()
+        .partition(|txn| disputed.contains(&txn.tx));
+    // The same layout as the input, so the period can be processed again.
+    let mut transactions = csv::Writer::from_path(archive.join("transactions.csv"))?;
+    transactions.write_record([
+        "type",
+        "client",
+        "tx",
+        "amount",
+        "timestamp",
+        "memo",
+        "category",
+        "currency",
+    ])?;
+    for txn in &archived {
+        transactions.write_record([
+            txn.kind.clone(),
+            txn.client.to_string(),
+            txn.tx.to_string(),
+            txn.amount
+                .map_or(String::new(), |amount| amount.to_string()),
+            txn.timestamp.map_or(String::new(), |ts| ts.to_string()),
+            txn.memo.clone().unwrap_or_default(),
+            txn.category.clone().unwrap_or_default(),
+            txn.currency.clone().unwrap_or_default(),
+        ])?;
+    }
+    transactions
+        .flush()
+        .map_err(|x| format!("error writing archived transactions: {}", x))?;
+
+    let opening = dir.join(OPENING_FILE);
+    if opening.exists() {
+        fs::rename(&opening, archive.join(OPENING_FILE))
+            .map_err(|x| format!("error archiving {}: {}", opening.display(), x))?;
+    }
+    let balances: Vec<_> = state
+        .accounts
+        .iter()
+        .map(|account| OpeningBalance {
+            client: account.client,
+            available: account.available,
+            held: account.held,
+            locked: account.locked,
+        })
+        .collect();
+    let mut audit = OpeningAudit::create(&opening.to_string_lossy())?;
+    Engine::default().seed_accounts(&balances, Some(&mut audit))?;
+
+    let compression = is_compressed(&dir.join(STATE_FILE))?.then_some(DEFAULT_COMPRESSION);
+    let closed = ClosedPeriod {
+        accounts: state.accounts.len(),
+        archived: archived.len(),
+        carried: carried.len(),
+        archive,
+    };
+    state.transactions = carried;
+    write_snapshot(dir, state, deltas, compression)?;
+    Ok(closed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        append::{load_previous_runs, save_run, SaveOptions},
+        clock::SimulatedClock,
+        Transaction, TransactionOutcome,
+    };
+
+    #[test]
+    fn closing_archives_all_but_disputed_transactions() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-period-test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut engine = Engine::with_clock(SimulatedClock::new(0));
+        load_previous_runs(&mut engine, &dir)?;
+        engine.process(Transaction::deposit(1, 1, 5.0))?;
+        engine.process(Transaction::deposit(1, 2, 3.0))?;
+        engine.process(Transaction::dispute(1, 2))?;
+        save_run(&engine, &dir, SaveOptions::default())?;
+
+        let closed = close_period(&dir, "2024-05")?;
+        assert_eq!(
+            (closed.accounts, closed.archived, closed.carried),
+            (1, 1, 1)
+        );
+        assert!(dir.join(OPENING_FILE).exists());
+        assert!(close_period(&dir, "2024-05").is_err());
+
+        let mut next = Engine::with_clock(SimulatedClock::new(0));
+        load_previous_runs(&mut next, &dir)?;
+        let account = next.account(1)?.expect("account carried over");
+        assert_eq!((account.available(), account.held()), (5.0, 3.0));
+        assert_eq!(
+            next.process(Transaction::resolve(1, 2))?,
+            TransactionOutcome::Applied
+        );
+        assert_ne!(
+            next.process(Transaction::dispute(1, 1))?,
+            TransactionOutcome::Applied
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{
+    errors::ErrorLog, limits::LimitTracker, tiering, Engine, PaymentsEngineError, Result,
+    RunOptions, RunSummary, Transaction, TransactionOutcome,
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+/// Rows queued for each worker before the reader waits for it to catch up.
+const QUEUE_DEPTH: usize = 4096;
+
+#[derive(Debug, Default)]
+/// What one worker did with its share of the input.
+struct Shard {
+    processed: u64,
+    rejected: u64,
+    duplicates: u64,
+    /// The first row the worker failed on, after which it stopped.
+    error: Option<(u64, PaymentsEngineError)>,
+}
+
+/// Like [`crate::run_transactions`], with the rows spread over `threads`
+/// workers by `client_id % threads` while this thread reads the input.
+///
+/// Every client is applied by a single worker, in input order, so each
+/// account ends up as it would in a single threaded run. Transactions of
+/// different clients reusing the same id are the exception: which of them
+/// is applied and which rejected as a duplicate depends on timing, as
+/// `determinism-audit` shows.
+///
+/// The workers' results are merged in input order: the summary counts
+/// every row handed out, and the error reported is the one of the earliest
+/// row that failed, or the run is aborted for `--max-reject-rate`. Snapshots
+/// can't be taken, as rows finish out of order.
+pub fn run_sharded(
+    transactions: impl Iterator<Item = Result<Transaction>>,
+    engine: &Engine,
+    options: &RunOptions,
+    threads: usize,
+) -> Result<RunSummary> {
+    if options.snapshots.is_some() {
+        return Err("snapshots can't be taken with more than one thread".into());
+    }
+    if options.outcomes.is_some() {
+        return Err("outcomes can't be recorded with more than one thread".into());
+    }
+    if engine.wal.is_some() {
+        return Err("a write-ahead log can't be kept with more than one thread".into());
+    }
+    let tracker = LimitTracker::start(options.limits);
+    let mut summary = RunSummary {
+        rows: options.resume_after,
+        ..RunSummary::default()
+    };
+    let mut read_error = None;
+    // Rejections among the rows `--max-reject-rate` counts, by any worker.
+    let window_rejected = AtomicU64::new(0);
+    let within = options.max_reject_rate.map_or(0, |rate| rate.within);
+    let mut skipped = ErrorLog::open(options.errors.as_deref())?;
+    let shards = thread::scope(|scope| {
+        let (queues, workers): (Vec<_>, Vec<_>) = (0..threads.max(1))
+            .map(|_| {
+                let (queue, rows) = mpsc::sync_channel::<(u64, Transaction)>(QUEUE_DEPTH);
+                let window_rejected = &window_rejected;
+                let worker = scope.spawn(move || {
+                    let mut shard = Shard::default();
+                    for (row, txn) in rows {
+                        match engine.process(txn) {
+                            Ok(TransactionOutcome::Applied) => shard.processed += 1,
+                            Ok(TransactionOutcome::Duplicate) => {
+                                shard.processed += 1;
+                                shard.duplicates += 1;
+                            }
+                            Ok(TransactionOutcome::Rejected(_)) => {
+                                shard.processed += 1;
+                                shard.rejected += 1;
+                                if row < within {
+                                    window_rejected.fetch_add(1, Ordering::Relaxed);
+                                }
+                            }
+                            Err(x) => {
+                                shard.error = Some((row, x));
+                                break;
+                            }
+                        }
+                    }
+                    shard
+                });
+                (queue, worker)
+            })
+            .unzip();
+        for txn in transactions {
+            if options.sample.is_some_and(|s| s.exhausted(summary.rows)) {
+                break;
+            }
+            if let Some(stopped) = tracker.check(summary.rows) {
+                summary.stopped = Some(stopped);
+                break;
+            }
+            if let Some(rate) = options.max_reject_rate {
+                // Workers lag behind the reader, so this catches a bad
+                // input a little later than a single threaded run.
+                let rejected = window_rejected.load(Ordering::Relaxed);
+                if let Err(x) = rate.check(summary.rows, rejected, false) {
+                    read_error = Some((summary.rows, x));
+                    break;
+                }
+            }
+            let row = summary.rows;
+            let txn = match txn {
+                Ok(txn) => txn,
+                Err(PaymentsEngineError::Record(error)) => {
+                    if let Err(x) = options.mode.check(&error) {
+                        read_error = Some((row, x));
+                        break;
+                    }
+                    summary.rows += 1;
+                    summary.errors += 1;
+                    skipped.skip(&error)?;
+                    if row < within {
+                        window_rejected.fetch_add(1, Ordering::Relaxed);
+                    }
+                    continue;
+                }
+                Err(x) => {
+                    read_error = Some((row, x));
+                    break;
+                }
+            };
+            summary.rows += 1;
+            if options
+                .sample
+                .is_none_or(|s| s.includes(row, txn.client_id))
+            {
+                let queue = &queues[txn.client_id as usize % queues.len()];
+                // A worker that has failed stops taking rows, the error is
+                // reported once they all finish.
+                if queue.send((row, txn)).is_err() {
+                    break;
+                }
+            }
+            if summary.rows.is_multiple_of(tiering::SWEEP_EVERY_ROWS) {
+                engine.db.evict_inactive()?;
+                engine.db.spill_oldest()?;
+            }
+        }
+        drop(queues);
+        Ok::<_, PaymentsEngineError>(
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("pipeline worker panicked"))
+                .collect::<Vec<_>>(),
+        )
+    })?;
+    let mut errors = Vec::new();
+    for shard in shards {
+        summary.processed += shard.processed;
+        summary.rejected += shard.rejected;
+        summary.duplicates += shard.duplicates;
+        errors.extend(shard.error);
+    }
+    errors.extend(read_error);
+    skipped.finish()?;
+    if let Some(rate) = options.max_reject_rate {
+        let rejected = window_rejected.load(Ordering::Relaxed);
+        if let Err(x) = rate.check(summary.rows, rejected, true) {
+            errors.push((summary.rows, x));
+        }
+    }
+    match errors.into_iter().min_by_key(|(row, _)| *row) {
+        Some((_, x)) => Err(x),
+        None => Ok(summary),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::SimulatedClock, inputs::read_inputs, run_transactions, state::export_state,
+    };
+
+    #[test]
+    fn sharded_runs_end_in_the_same_state() -> Result<()> {
+        let inputs = ["test-files/long_transaction_history.csv".to_string()];
+        let options = RunOptions::default();
+        let single = Engine::with_clock(SimulatedClock::new(0));
+        let expected = run_transactions(read_inputs(&inputs, &options)?, &single, &options)?;
+        for threads in [2, 3, 8] {
+            let options = RunOptions {
+                threads,
+                ..RunOptions::default()
+            };
+            let sharded = Engine::with_clock(SimulatedClock::new(0));
+            let summary = run_transactions(read_inputs(&inputs, &options)?, &sharded, &options)?;
+            assert_eq!(summary, expected);
+            assert_eq!(export_state(&sharded.db)?, export_state(&single.db)?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+use crate::{
+    i18n::Reason, reject_with, state::TransactionState, Database, Engine, Result, Transaction,
+    TransactionOutcome,
+};
+use serde::Deserialize;
+use std::{
+    fmt::Debug,
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::Mutex,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+/// What a plugin decided about a transaction.
+pub enum Verdict {
+    Allow,
+    /// Rejects the transaction, for the given reason.
+    Deny(String),
+    /// Processes this transaction instead. The client and id must stay
+    /// the same.
+    Modify(Transaction),
+}
+
+/// Custom logic that inspects every transaction before the engine
+/// handles it.
+pub trait Plugin: Debug + Send + Sync {
+    fn inspect(&self, txn: &Transaction) -> Result<Verdict>;
+}
+
+/// A transaction that made it past the plugins, or the rejection of one
+/// that didn't and why it was denied.
+pub enum Screened {
+    Pass(Transaction),
+    Denied(TransactionOutcome, String),
+}
+
+impl Engine {
+    /// Runs a plugin on every transaction, after the plugins added before it.
+    pub fn with_plugin(mut self, plugin: impl Plugin + 'static) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    /// Runs `txn` past every plugin. A denied transaction is counted and
+    /// reported in `db` like any other rejection, but never reaches the
+    /// state or the capture.
+    pub fn screen(&self, db: &Database, mut txn: Transaction) -> Result<Screened> {
+        for plugin in &self.plugins {
+            match plugin.inspect(&txn)? {
+                Verdict::Allow => {}
+                Verdict::Deny(why) => {
+                    db.counters.processed(&txn);
+                    let outcome = reject_with(db, Reason::Denied, &txn, &[("why", &why)]);
+                    return Ok(Screened::Denied(outcome, why));
+                }
+                Verdict::Modify(modified) => {
+                    if (modified.client_id, modified.txn_id) != (txn.client_id, txn.txn_id) {
+                        return Err(format!(
+                            "plugin {:?} changed the client or id of transaction {}",
+                            plugin, txn.txn_id
+                        )
+                        .into());
+                    }
+                    txn = Transaction {
+                        extras: txn.extras,
+                        ..modified
+                    };
+                }
+            }
+        }
+        Ok(Screened::Pass(txn))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "decision", rename_all = "lowercase", deny_unknown_fields)]
+/// A line a [`ProcessPlugin`] answers with.
+enum Reply {
+    Allow,
+    Deny { reason: String },
+    Modify { transaction: TransactionState },
+}
+
+#[derive(Debug)]
+/// A plugin running as a separate program, so rules can be shipped
+/// without rebuilding the engine.
+///
+/// Every transaction is written to the program's standard input as one
+/// line of JSON, in the form of the state file's transactions, and the
+/// program answers each with one line: `{"decision":"allow"}`,
+/// `{"decision":"deny","reason":"..."}` or
+/// `{"decision":"modify","transaction":{...}}`.
+pub struct ProcessPlugin {
+    command: String,
+    child: Mutex<(Child, ChildStdin, BufReader<ChildStdout>)>,
+}
+
+impl ProcessPlugin {
+    /// Starts `command` with `sh -c`.
+    pub fn spawn(command: String) -> Result<Self> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|x| format!("error starting plugin {}: {}", command, x))?;
+        let (stdin, stdout) = match (child.stdin.take(), child.stdout.take()) {
+            (Some(stdin), Some(stdout)) => (stdin, BufReader::new(stdout)),
+            _ => return Err(format!("plugin {} has no stdin or stdout", command).into()),
+        };
+        Ok(ProcessPlugin {
+            command,
+            child: Mutex::new((child, stdin, stdout)),
+        })
+    }
+}
+
+impl Plugin for ProcessPlugin {
+    fn inspect(&self, txn: &Transaction) -> Result<Verdict> {
+        let failed = |x: &dyn std::fmt::Display| format!("plugin {} failed: {}", self.command, x);
+        let mut child = self.child.lock().map_err(|_| "plugin lock poisoned")?;
+        let (_, stdin, stdout) = &mut *child;
+        let request =
+            serde_json::to_string(&TransactionState::from(txn)).map_err(|x| failed(&x))?;
+        writeln!(stdin, "{}", request)
+            .and_then(|_| stdin.flush())
+            .map_err(|x| failed(&x))?;
+        let mut line = String::new();
+        if stdout.read_line(&mut line).map_err(|x| failed(&x))? == 0 {
+            return Err(failed(&"it exited").into());
+        }
+        Ok(match serde_json::from_str(&line).map_err(|x| failed(&x))? {
+            Reply::Allow => Verdict::Allow,
+            Reply::Deny { reason } => Verdict::Deny(reason),
+            Reply::Modify { transaction } => Verdict::Modify(transaction.try_into()?),
+        })
+    }
+}
+
+impl Drop for ProcessPlugin {
+    fn drop(&mut self) {
+        if let Ok(child) = self.child.get_mut() {
+            let _ = child.0.kill();
+            let _ = child.0.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimulatedClock;
+
+    #[test]
+    fn plugins_allow_deny_and_modify() -> Result<()> {
+        // Denies transaction 2, halves transaction 3 and allows the rest.
+        let script = r#"while read line; do
+            case "$line" in
+                *'"tx":2,'*) echo '{"decision":"deny","reason":"too risky"}' ;;
+                *'"tx":3,'*) echo '{"decision":"modify","transaction":{"tx":3,"client":1,"type":"deposit","amount":0.5}}' ;;
+                *) echo '{"decision":"allow"}' ;;
+            esac
+        done"#;
+        let engine = Engine::with_clock(SimulatedClock::new(0))
+            .with_plugin(ProcessPlugin::spawn(script.to_string())?);
+        assert_eq!(
+            engine.process(Transaction::deposit(1, 1, 1.0))?,
+            TransactionOutcome::Applied
+        );
+        assert_eq!(
+            engine.process(Transaction::deposit(1, 2, 1.0))?,
+            TransactionOutcome::Rejected(Reason::Denied)
+        );
+        assert_eq!(
+            engine.process(Transaction::deposit(1, 3, 1.0))?,
+            TransactionOutcome::Applied
+        );
+        let account = engine.account(1)?.expect("client exists");
+        assert_eq!(account.available(), 1.5);
+        assert!(engine.db.transactions.get(&2).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+use crate::{
+    fees::Fees, limits::parse_duration, precision::Precision, regions::Regions, ClientId,
+    TransactionType,
+};
+use std::{str::FromStr, time::Duration};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Which stored transactions a client may dispute.
+pub enum DisputePolicy {
+    /// Deposits and withdrawals alike, each held the way
+    /// [`crate::handle_transaction`] describes.
+    #[default]
+    Symmetric,
+    /// Only deposits, disputes of withdrawals are rejected.
+    DepositsOnly,
+}
+
+impl FromStr for DisputePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace(['-', '_'], "").as_str() {
+            "symmetric" => Ok(DisputePolicy::Symmetric),
+            "depositsonly" => Ok(DisputePolicy::DepositsOnly),
+            _ => Err(format!(
+                "unknown dispute policy {:?}, expected symmetric or deposits-only",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// What a locked account may still be sent. Transfers and withdrawals are
+/// always rejected, admin operations always apply.
+pub enum LockedAccounts {
+    /// Nothing, not even the resolves and chargebacks of disputes opened
+    /// before the account was locked.
+    #[default]
+    RejectAll,
+    /// Resolves and chargebacks of disputes already open, so they don't
+    /// stay open for as long as the account is locked.
+    SettleDisputes,
+    /// Like [`LockedAccounts::SettleDisputes`], and deposits are queued
+    /// rather than rejected: they are kept without being credited until
+    /// the account is unlocked. Closed accounts still reject them.
+    QueueDeposits,
+}
+
+impl LockedAccounts {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockedAccounts::RejectAll => "reject_all",
+            LockedAccounts::SettleDisputes => "settle_disputes",
+            LockedAccounts::QueueDeposits => "queue_deposits",
+        }
+    }
+
+    /// Whether resolves and chargebacks of open disputes are let through.
+    pub fn settles_disputes(&self) -> bool {
+        *self != LockedAccounts::RejectAll
+    }
+}
+
+impl FromStr for LockedAccounts {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace(['-', '_'], "").as_str() {
+            "rejectall" => Ok(LockedAccounts::RejectAll),
+            "settledisputes" => Ok(LockedAccounts::SettleDisputes),
+            "queuedeposits" => Ok(LockedAccounts::QueueDeposits),
+            _ => Err(format!(
+                "unknown locked accounts policy {:?}, expected reject-all, settle-disputes or queue-deposits",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// How far below zero a client's available funds may go.
+pub enum Overdraft {
+    /// Never below zero.
+    Deny,
+    /// Without limit.
+    Allow,
+    /// Down to minus the amount.
+    AllowWithLimit(f64),
+}
+
+impl Overdraft {
+    /// The lowest the available funds may go.
+    pub fn floor(&self) -> f64 {
+        match self {
+            Overdraft::Deny => 0.0,
+            Overdraft::Allow => f64::NEG_INFINITY,
+            Overdraft::AllowWithLimit(limit) => -limit,
+        }
+    }
+}
+
+impl FromStr for Overdraft {
+    type Err = String;
+    /// Parses `deny`, `allow` or `allow-with-limit` followed by the limit,
+    /// e.g. `allow-with-limit:100`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase().replace('_', "-");
+        if let Some(limit) = s.strip_prefix("allow-with-limit") {
+            let limit = limit.trim_start_matches([':', '=', ' ']);
+            return match limit.parse::<f64>() {
+                Ok(limit) if limit.is_finite() && limit >= 0.0 => {
+                    Ok(Overdraft::AllowWithLimit(limit))
+                }
+                _ => Err(format!("expected a limit of zero or more, got {:?}", limit)),
+            };
+        }
+        match s.as_str() {
+            "deny" => Ok(Overdraft::Deny),
+            "allow" => Ok(Overdraft::Allow),
+            _ => Err(format!(
+                "unknown overdraft policy {:?}, expected deny, allow or allow-with-limit:<amount>",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// The configurable rules the engine applies. The default is the
+/// behaviour of the engine before any rule was configurable.
+pub struct Policy {
+    pub dispute: DisputePolicy,
+    /// Rules that depend on the region of the client.
+    pub regions: Regions,
+    /// Disputes still open this long after they were opened are charged
+    /// back automatically at the end of a run.
+    pub auto_chargeback_after: Option<Duration>,
+    /// How old a transaction can be and still be disputed, for clients
+    /// whose region sets no window of its own.
+    pub dispute_window: Option<Duration>,
+    /// Ignore deposits and withdrawals repeating one already handled, with
+    /// the same id, type, client and amount, and disputes of transactions
+    /// already disputed, rather than reject them. Rerunning an input that
+    /// was partly processed then changes nothing twice.
+    pub idempotent: bool,
+    /// Apply the admin operations `unlock`, `credit_adjustment`,
+    /// `debit_adjustment` and `close`, rather than reject them.
+    pub allow_admin_ops: bool,
+    /// Accounts without activity for this long are dormant: they are left
+    /// out of reports, and archived to the cold store if there is one.
+    pub dormant_after: Option<Duration>,
+    /// Disputes of deposits hold only what is available, and queue the
+    /// rest as a pending hold taken from later deposits as they arrive,
+    /// rather than push the available funds below zero.
+    pub pending_holds: bool,
+    /// How far withdrawals and the holds of disputed deposits may take the
+    /// available funds below zero. Without one, withdrawals may not and
+    /// holds may as far as they need.
+    pub overdraft: Option<Overdraft>,
+    /// How amounts are rounded in reports. Inputs are rounded the same way
+    /// as they are read, see [`crate::RunOptions::precision`].
+    pub precision: Precision,
+    /// What deposits and withdrawals cost, see [`Fees`].
+    pub fees: Fees,
+    /// What locked accounts may still be sent.
+    pub locked_accounts: LockedAccounts,
+}
+
+impl Policy {
+    /// Whether a transaction of this type may be disputed.
+    pub fn disputable(&self, transaction_type: TransactionType) -> bool {
+        match self.dispute {
+            DisputePolicy::Symmetric => true,
+            DisputePolicy::DepositsOnly => transaction_type == TransactionType::Deposit,
+        }
+    }
+
+    /// The lowest a withdrawal, transfer or debit adjustment may leave the
+    /// available funds.
+    pub fn withdrawal_floor(&self) -> f64 {
+        self.overdraft.map_or(0.0, |overdraft| overdraft.floor())
+    }
+
+    /// The lowest the hold of a disputed deposit may leave the available
+    /// funds.
+    pub fn hold_floor(&self) -> f64 {
+        self.overdraft
+            .map_or(f64::NEG_INFINITY, |overdraft| overdraft.floor())
+    }
+
+    /// The fee of a deposit or withdrawal, rounded like amounts are.
+    pub fn fee(&self, client_id: ClientId, transaction_type: TransactionType, amount: f64) -> f64 {
+        self.precision
+            .round(self.fees.fee(client_id, transaction_type, amount))
+    }
+
+    /// This policy with one setting changed.
+    pub fn with(mut self, setting: PolicyOverride) -> Self {
+        match setting {
+            PolicyOverride::Dispute(dispute) => self.dispute = dispute,
+            PolicyOverride::AutoChargeback(after) => self.auto_chargeback_after = Some(after),
+            PolicyOverride::DisputeWindow(window) => self.dispute_window = Some(window),
+            PolicyOverride::Overdraft(overdraft) => self.overdraft = Some(overdraft),
+            PolicyOverride::LockedAccounts(locked) => self.locked_accounts = locked,
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// One policy setting given as `key=value`, e.g. `dispute_policy=deposits-only`.
+pub enum PolicyOverride {
+    Dispute(DisputePolicy),
+    /// `auto_chargeback_after=30d`
+    AutoChargeback(Duration),
+    /// `dispute_window=60d`
+    DisputeWindow(Duration),
+    /// `overdraft=allow-with-limit:100`
+    Overdraft(Overdraft),
+    /// `locked_accounts=settle-disputes`
+    LockedAccounts(LockedAccounts),
+}
+
+/// Parses a `key=value` policy override for the command line.
+pub fn parse_override(s: &str) -> Result<PolicyOverride, String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value, got {:?}", s))?;
+    match key.trim() {
+        "dispute_policy" => Ok(PolicyOverride::Dispute(value.trim().parse()?)),
+        "auto_chargeback_after" => Ok(PolicyOverride::AutoChargeback(parse_duration(
+            value.trim(),
+        )?)),
+        "dispute_window" => Ok(PolicyOverride::DisputeWindow(parse_duration(value.trim())?)),
+        "overdraft" => Ok(PolicyOverride::Overdraft(value.parse()?)),
+        "locked_accounts" => Ok(PolicyOverride::LockedAccounts(value.trim().parse()?)),
+        key => Err(format!("unknown policy setting {:?}", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_overrides() {
+        assert_eq!(
+            parse_override("dispute_policy=DepositsOnly"),
+            Ok(PolicyOverride::Dispute(DisputePolicy::DepositsOnly))
+        );
+        assert_eq!(
+            parse_override("dispute_policy = symmetric"),
+            Ok(PolicyOverride::Dispute(DisputePolicy::Symmetric))
+        );
+        assert_eq!(
+            parse_override("auto_chargeback_after=30d"),
+            Ok(PolicyOverride::AutoChargeback(Duration::from_secs(
+                30 * 24 * 60 * 60
+            )))
+        );
+        assert_eq!(
+            parse_override("dispute_window=60d"),
+            Ok(PolicyOverride::DisputeWindow(Duration::from_secs(
+                60 * 24 * 60 * 60
+            )))
+        );
+        assert_eq!(
+            parse_override("overdraft=allow-with-limit:50"),
+            Ok(PolicyOverride::Overdraft(Overdraft::AllowWithLimit(50.0)))
+        );
+        assert_eq!(
+            parse_override("locked_accounts=Queue-Deposits"),
+            Ok(PolicyOverride::LockedAccounts(
+                LockedAccounts::QueueDeposits
+            ))
+        );
+        assert_eq!("Allow".parse(), Ok(Overdraft::Allow));
+        assert!("allow-with-limit -5".parse::<Overdraft>().is_err());
+        assert!(parse_override("fees=off").is_err());
+        assert!(parse_override("dispute_policy").is_err());
+    }
+}
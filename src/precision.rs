@@ -0,0 +1,95 @@
+/// The decimal places amounts have unless configured otherwise.
+pub const DEFAULT_PLACES: u32 = 4;
+
+/// The most decimal places amounts can be kept to. An `f64` holds about
+/// 15 significant digits, which leaves room for amounts in the millions.
+pub const MAX_PLACES: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// How an amount is brought to the configured decimal places.
+pub enum Rounding {
+    /// Halves are rounded away from zero, `0.00005` to `0.0001`.
+    #[default]
+    HalfUp,
+    /// Halves are rounded to the even neighbour, `0.00005` to `0.0000` and
+    /// `0.00015` to `0.0002`, so rounding errors don't add up one way.
+    Bankers,
+    /// Extra places are dropped, towards zero.
+    Truncate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How many decimal places amounts are kept to, applied the same way when
+/// they are read and when they are reported.
+pub struct Precision {
+    pub places: u32,
+    pub rounding: Rounding,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Precision {
+            places: DEFAULT_PLACES,
+            rounding: Rounding::default(),
+        }
+    }
+}
+
+impl Precision {
+    /// `amount` rounded to the decimal places.
+    pub fn round(&self, amount: f64) -> f64 {
+        let scale = 10f64.powi(self.places as i32);
+        // Scaling is inexact, 0.29 * 100 is a hair under 29, so the scaled
+        // amount is snapped to a millionth first, far below what rounding
+        // decides on.
+        let scaled = (amount * scale * 1e6).round() / 1e6;
+        let rounded = match self.rounding {
+            Rounding::HalfUp => scaled.round(),
+            Rounding::Bankers => scaled.round_ties_even(),
+            Rounding::Truncate => scaled.trunc(),
+        };
+        rounded / scale
+    }
+
+    /// `amount` rounded and written with exactly the decimal places.
+    pub fn format(&self, amount: f64) -> String {
+        format!("{:.*}", self.places as usize, self.round(amount))
+    }
+}
+
+/// Parses `--precision`, a number of decimal places up to [`MAX_PLACES`].
+pub fn parse_places(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(places) if places <= MAX_PLACES => Ok(places),
+        _ => Err(format!(
+            "expected a number of decimal places from 0 to {}, got {:?}",
+            MAX_PLACES, s
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amounts_round_as_configured() {
+        let at = |places, rounding| Precision { places, rounding };
+        let cases = [
+            (at(2, Rounding::HalfUp), 1.005, "1.01"),
+            (at(2, Rounding::HalfUp), -1.005, "-1.01"),
+            (at(2, Rounding::Bankers), 1.005, "1.00"),
+            (at(2, Rounding::Bankers), 1.015, "1.02"),
+            (at(2, Rounding::Truncate), 0.29, "0.29"),
+            (at(2, Rounding::Truncate), 1.999, "1.99"),
+            (at(8, Rounding::HalfUp), 0.123456789, "0.12345679"),
+            (at(0, Rounding::HalfUp), 2.5, "3"),
+        ];
+        for (precision, amount, expected) in cases {
+            assert_eq!(precision.format(amount), expected, "{:?}", precision);
+        }
+        assert_eq!(Precision::default().format(1.5), "1.5000");
+        assert!(parse_places("9").is_err());
+    }
+}
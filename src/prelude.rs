@@ -0,0 +1,62 @@
+//! The stable surface of the library, for embedders:
+//!
+//! ```
+//! use payments_engine::prelude::*;
+//! ```
+//!
+//! What is exported here only changes in a breaking way with a new major
+//! version. The other modules are public for the `payments-engine`
+//! binary and may change in any release; those hidden from the docs are
+//! the binary's own machinery.
+
+pub use crate::{
+    account::AccountView,
+    builder::{EngineBuilder, TransactionBuilder},
+    handle::{EngineHandle, Isolation, Shards},
+    i18n::Reason,
+    policy::Overdraft,
+    ClientId, Engine, Money, PaymentsEngineError as EngineError, Result, Transaction,
+    TransactionOutcome, TransactionType, TxnId,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Names every item of the prelude with the types embedders rely on,
+    /// so changing one breaks this test before it breaks them.
+    #[test]
+    fn the_prelude_is_enough_to_embed_the_engine() -> Result<()> {
+        let (client, tx, amount): (ClientId, TxnId, Money) = (1, 1, 2.0);
+        let engine = Engine::default();
+        let outcome: TransactionOutcome =
+            engine.process(Transaction::deposit(client, tx, amount))?;
+        assert_eq!(outcome, TransactionOutcome::Applied);
+        let txn: Transaction = Transaction::builder(TransactionType::Withdrawal, client, 2)
+            .amount(5.0)
+            .build()?;
+        assert_eq!(
+            engine.process(txn)?,
+            TransactionOutcome::Rejected(Reason::InsufficientFunds)
+        );
+        let account: Option<AccountView> = engine.account(client)?;
+        assert_eq!(account.map(|account| account.available()), Some(amount));
+        let builder: EngineBuilder = Engine::builder().overdraft(Overdraft::Deny);
+        let engine = builder.on_reject(|_, _| ()).build()?;
+        assert_eq!(
+            engine.process(Transaction::withdrawal(client, tx, amount))?,
+            TransactionOutcome::Rejected(Reason::InsufficientFunds)
+        );
+        let error: EngineError = "unknown".into();
+        assert!(!error.to_string().is_empty());
+        let (handle, shards): (EngineHandle, Shards) =
+            EngineHandle::spawn(Isolation::Serialized, Engine::default);
+        assert_eq!(
+            handle.process(Transaction::deposit(client, tx, amount))?,
+            TransactionOutcome::Applied
+        );
+        drop(handle);
+        assert_eq!(shards.join()?.len(), 1);
+        Ok(())
+    }
+}
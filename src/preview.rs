@@ -0,0 +1,52 @@
+use crate::{
+    clock::SimulatedClock, format::Format, output::write_report, run_engine, schema::csv_reader,
+    Engine, Result,
+};
+
+/// Processes the csv transactions in `input` on a fresh engine and returns
+/// the report of every account as a JSON array, for tools that preview a
+/// settlement file without running the engine on it for real.
+///
+/// Nothing is read from or written to files, the environment or the
+/// system clock, so that it can run where they don't exist, such as
+/// `wasm32-unknown-unknown` in a browser, built without the `cli`
+/// feature. Transactions without a timestamp
+/// are taken at time 0. Rows that can't be parsed are skipped, as in a
+/// lenient run.
+pub fn preview_csv(input: &[u8]) -> Result<String> {
+    let engine = Engine::with_clock(SimulatedClock::new(0));
+    run_engine(csv_reader(input), &engine)?;
+    let mut lines = Vec::new();
+    write_report(&engine.db, &mut lines, Format::Json)?;
+    let accounts = String::from_utf8(lines).map_err(|x| x.to_string())?;
+    Ok(format!(
+        "[{}]",
+        accounts.lines().collect::<Vec<_>>().join(",")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previews_are_the_report_of_the_input() -> Result<()> {
+        let input = std::fs::read("test-files/example_input.csv")?;
+        let report: serde_json::Value =
+            serde_json::from_str(&preview_csv(&input)?).map_err(|x| x.to_string())?;
+        let clients: Vec<_> = report
+            .as_array()
+            .ok_or("the report isn't an array")?
+            .iter()
+            .map(|account| (account["client"].clone(), account["total"].clone()))
+            .collect();
+        assert_eq!(
+            clients,
+            [
+                (serde_json::json!(1), serde_json::json!(1.5)),
+                (serde_json::json!(2), serde_json::json!(2.0))
+            ]
+        );
+        Ok(())
+    }
+}
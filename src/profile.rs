@@ -0,0 +1,104 @@
+use crate::Result;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    sync::Mutex,
+    time::Instant,
+};
+
+/// Microseconds spent in each stack of spans, excluding time in the spans
+/// nested within, by the stack's names joined with `;`.
+static SELF_TIME: Mutex<BTreeMap<String, u128>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// The spans open on this thread, innermost last, with the time spent
+    /// in their children so far.
+    static OPEN: RefCell<Vec<(&'static str, u128)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Times the code from here until it is dropped as `name`, nested in the
+/// span that was open on this thread when it was entered. Use it through
+/// the `span!` macro, which compiles to nothing without the `profiling`
+/// feature.
+pub struct Span {
+    started: Instant,
+}
+
+impl Span {
+    pub fn enter(name: &'static str) -> Self {
+        OPEN.with(|open| open.borrow_mut().push((name, 0)));
+        Span {
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let total = self.started.elapsed().as_nanos();
+        OPEN.with(|open| {
+            let mut open = open.borrow_mut();
+            let stack = open
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(";");
+            let Some((_, children)) = open.pop() else {
+                return;
+            };
+            if let Some((_, parent)) = open.last_mut() {
+                *parent += total;
+            }
+            if let Ok(mut self_time) = SELF_TIME.lock() {
+                *self_time.entry(stack).or_default() += total.saturating_sub(children);
+            }
+        });
+    }
+}
+
+/// Writes the time recorded so far in the folded stack format read by
+/// flamegraph tools (`inferno-flamegraph`, `flamegraph.pl`): one line per
+/// stack, with the microseconds spent in it.
+pub fn write_profile(path: &str) -> Result<()> {
+    let self_time = SELF_TIME.lock().map_err(|_| "profile lock poisoned")?;
+    let error = |x: std::io::Error| format!("error writing profile to {}: {}", path, x);
+    let mut out = BufWriter::new(File::create(path).map_err(error)?);
+    for (stack, nanos) in self_time.iter() {
+        writeln!(out, "{} {}", stack, nanos / 1_000).map_err(error)?;
+    }
+    out.flush().map_err(error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_spans_are_folded_into_stacks() -> Result<()> {
+        {
+            span!("outer_test_span");
+            for _ in 0..2 {
+                span!("inner_test_span");
+                std::thread::sleep(std::time::Duration::from_millis(2));
+            }
+        }
+        let path = crate::temp_path("payments-engine-profile-test.folded");
+        let path = path.to_string_lossy().to_string();
+        write_profile(&path)?;
+        let folded = std::fs::read_to_string(&path).map_err(|x| x.to_string())?;
+        let micros = |stack: &str| {
+            folded.lines().find_map(|line| {
+                line.strip_prefix(stack)?
+                    .strip_prefix(' ')?
+                    .parse::<u128>()
+                    .ok()
+            })
+        };
+        assert!(micros("outer_test_span;inner_test_span").is_some_and(|us| us >= 4_000));
+        assert!(micros("outer_test_span").is_some_and(|us| us < 4_000));
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+use crate::{inputs::InputPosition, Engine, PaymentsEngineError, Result, Transaction};
+use serde::Serialize;
+use std::{
+    fmt,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// How progress is reported.
+pub enum ProgressFormat {
+    /// A line of text, for people.
+    Text,
+    /// A JSON object per line, for machines.
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+/// How far a run has got.
+pub struct ProgressEvent {
+    /// Seconds since the run started.
+    pub elapsed: f64,
+    /// Rows read from the input, counted from its start even when resuming.
+    pub rows: u64,
+    /// Transactions the engine handled, and rejected.
+    pub processed: u64,
+    pub rejected: u64,
+    /// Rows skipped as they couldn't be parsed or broke a rule.
+    pub errors: u64,
+    /// Rows read per second since the run started.
+    pub rate: f64,
+    /// Bytes of the inputs read.
+    pub offset: u64,
+    /// Seconds left at the pace so far, when the size of the inputs is
+    /// known.
+    pub eta: Option<f64>,
+    /// Whether this is the last event of the run.
+    pub done: bool,
+}
+
+impl fmt::Display for ProgressEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} rows, {} processed, {} rejected, {} skipped, {:.0} rows/s",
+            self.rows, self.processed, self.rejected, self.errors, self.rate
+        )?;
+        match self.eta {
+            Some(eta) if !self.done => write!(f, ", {:.0}s left", eta),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reports a [`ProgressEvent`] every interval while a run reads its
+/// transactions, and a last one once it has read them all.
+pub struct Progress {
+    out: Box<dyn Write>,
+    format: ProgressFormat,
+    interval: Duration,
+    position: InputPosition,
+    started: Instant,
+    next: Duration,
+    rows: u64,
+    errors: u64,
+}
+
+impl Progress {
+    pub fn new(
+        out: Box<dyn Write>,
+        format: ProgressFormat,
+        interval: Duration,
+        position: InputPosition,
+    ) -> Self {
+        Progress {
+            out,
+            format,
+            interval,
+            position,
+            started: Instant::now(),
+            next: interval,
+            rows: 0,
+            errors: 0,
+        }
+    }
+
+    /// Where to report to: the Unix socket at `socket`, which something
+    /// must be listening on, or stderr.
+    pub fn open(socket: Option<&str>) -> Result<Box<dyn Write>> {
+        match socket {
+            #[cfg(unix)]
+            Some(path) => Ok(Box::new(
+                std::os::unix::net::UnixStream::connect(path)
+                    .map_err(|x| format!("error connecting to {}: {}", path, x))?,
+            )),
+            #[cfg(not(unix))]
+            Some(_) => Err("progress can only be sent to a socket on Unix".into()),
+            None => Ok(Box::new(io::stderr())),
+        }
+    }
+
+    fn event(&self, engine: &Engine, done: bool) -> ProgressEvent {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let (processed, rejected) = engine.db.counters.totals();
+        let offset = self.position.offset();
+        let eta = self
+            .position
+            .total()
+            .filter(|_| offset > 0)
+            .map(|total| elapsed * total.saturating_sub(offset) as f64 / offset as f64);
+        ProgressEvent {
+            elapsed,
+            rows: self.rows,
+            processed,
+            rejected,
+            errors: self.errors,
+            rate: match elapsed {
+                0.0 => 0.0,
+                elapsed => self.rows as f64 / elapsed,
+            },
+            offset,
+            eta,
+            done,
+        }
+    }
+
+    fn report(&mut self, event: &ProgressEvent) -> Result<()> {
+        match self.format {
+            ProgressFormat::Text => writeln!(self.out, "{}", event),
+            ProgressFormat::Json => serde_json::to_writer(&mut self.out, event)
+                .map_err(io::Error::from)
+                .and_then(|_| writeln!(self.out)),
+        }
+        .and_then(|_| self.out.flush())
+        .map_err(|x| format!("error reporting progress: {}", x).into())
+    }
+
+    /// `transactions`, reporting progress as they are read. Reporting
+    /// stops with a warning if it fails, the run goes on.
+    pub fn watch<'a>(
+        mut self,
+        transactions: impl Iterator<Item = Result<Transaction>> + 'a,
+        engine: &'a Engine,
+    ) -> impl Iterator<Item = Result<Transaction>> + 'a {
+        let mut transactions = transactions.fuse();
+        let mut reporting = true;
+        std::iter::from_fn(move || {
+            let next = transactions.next();
+            match &next {
+                Some(Ok(_)) => self.rows += 1,
+                Some(Err(PaymentsEngineError::Record(_))) => {
+                    self.rows += 1;
+                    self.errors += 1;
+                }
+                _ => {}
+            }
+            let done = next.is_none();
+            if reporting && (done || self.started.elapsed() >= self.next) {
+                self.next = self.started.elapsed() + self.interval;
+                let event = self.event(engine, done);
+                if let Err(x) = self.report(&event) {
+                    tracing::warn!("{}, no more progress is reported", x);
+                    reporting = false;
+                }
+                reporting &= !done;
+            }
+            next
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{inputs::read_inputs_at, run_transactions, RecordError, RunOptions};
+    use std::sync::{Arc, Mutex};
+
+    /// A sink the test can read back.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn progress_is_reported_as_json_lines() -> Result<()> {
+        let input = "test-files/long_transaction_history.csv".to_string();
+        let options = RunOptions::default();
+        let (transactions, position) = read_inputs_at(std::slice::from_ref(&input), &options)?;
+        let engine = Engine::default();
+        let out = Shared::default();
+        let progress = Progress::new(
+            Box::new(out.clone()),
+            ProgressFormat::Json,
+            Duration::ZERO,
+            position,
+        );
+        let bad = std::iter::once(Err(PaymentsEngineError::Record(Box::new(RecordError {
+            input: None,
+            row: 0,
+            error: "bad row".into(),
+        }))));
+        let summary = run_transactions(
+            progress.watch(transactions.chain(bad), &engine),
+            &engine,
+            &options,
+        )?;
+        let written =
+            String::from_utf8(out.0.lock().unwrap().clone()).map_err(|x| x.to_string())?;
+        let events: Vec<serde_json::Value> = written
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|x| x.to_string())?;
+        assert!(events.len() > 1);
+        let last = events.last().ok_or("no events")?;
+        assert_eq!(last["rows"], summary.rows);
+        assert_eq!(last["errors"], 1);
+        assert_eq!(last["done"], true);
+        let size = std::fs::metadata(&input).map_err(|x| x.to_string())?.len();
+        assert_eq!(last["offset"], size);
+        assert_eq!(last["eta"], 0.0);
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{
+    account::AccountView, clock::Timestamp, format::Format, output::ReportWriter, ClientId, Engine,
+    Result, Transaction,
+};
+use std::io::Write;
+
+/// The columns of the history `query` prints after the account.
+pub const HISTORY_COLUMNS: [&str; 5] = ["tx", "type", "amount", "timestamp", "disputed_at"];
+
+#[derive(Debug, Clone, PartialEq)]
+/// What the engine holds about one client.
+pub struct ClientDetails {
+    pub account: AccountView,
+    /// The transactions disputed and not yet resolved or charged back,
+    /// with when each dispute was opened, in transaction order.
+    pub open_disputes: Vec<(u32, Timestamp)>,
+    /// The deposits and withdrawals of the client the engine keeps for
+    /// disputes, in transaction order. Rejected ones aren't kept.
+    /// Disputes, resolves and chargebacks aren't kept, what they did shows
+    /// in the account and its disputes.
+    pub history: Vec<Transaction>,
+}
+
+impl Engine {
+    /// The account, open disputes and history of a client, if the client
+    /// exists. An evicted client is read from the cold store and spilled
+    /// transactions from the transaction store, without loading them back.
+    pub fn client_details(&self, client_id: ClientId) -> Result<Option<ClientDetails>> {
+        let Some(account) = self.account(client_id)? else {
+            return Ok(None);
+        };
+        let disputed = match self.db.clients.get(&client_id) {
+            Some(client) => client.disputed.clone(),
+            None => match &self.db.cold {
+                Some(cold) => cold
+                    .store
+                    .get(client_id)?
+                    .map(|client| client.disputed)
+                    .unwrap_or_default(),
+                None => Default::default(),
+            },
+        };
+        let mut open_disputes: Vec<_> = disputed.into_iter().collect();
+        open_disputes.sort();
+        let mut history = Vec::new();
+        self.db.for_each_transaction(|txn| {
+            if txn.client_id == client_id {
+                history.push(txn.clone());
+            }
+        })?;
+        history.sort_by_key(|txn| txn.txn_id);
+        Ok(Some(ClientDetails {
+            account,
+            open_disputes,
+            history,
+        }))
+    }
+}
+
+/// Writes `details` to `out` in `format`. As csv, the report row of the
+/// account comes first, then after an empty line the history, each
+/// transaction with when its dispute was opened if it is disputed. As
+/// JSON, a single object with the report fields, the open disputes and
+/// the history.
+pub fn write_client_details(
+    engine: &Engine,
+    details: &ClientDetails,
+    mut out: impl Write,
+    format: Format,
+) -> Result<()> {
+    let precision = engine.db.policy.precision;
+    let disputed_at = |txn_id: u32| {
+        details
+            .open_disputes
+            .iter()
+            .find(|(disputed, _)| *disputed == txn_id)
+            .map(|(_, opened)| *opened)
+    };
+    let error = |x: std::io::Error| format!("error writing the client: {}", x);
+    match format {
+        Format::Csv => {
+            let mut report = ReportWriter::new(&engine.db, &mut out, format)?;
+            report.write(&details.account)?;
+            report.finish()?;
+            writeln!(out).map_err(error)?;
+            let mut writer = csv::Writer::from_writer(&mut out);
+            writer.write_record(HISTORY_COLUMNS)?;
+            for txn in &details.history {
+                writer.write_record([
+                    txn.txn_id.to_string(),
+                    txn.transaction_type.as_str().to_string(),
+                    txn.amount.map(|x| precision.format(x)).unwrap_or_default(),
+                    txn.timestamp.map(|x| x.to_string()).unwrap_or_default(),
+                    disputed_at(txn.txn_id)
+                        .map(|x| x.to_string())
+                        .unwrap_or_default(),
+                ])?;
+            }
+            writer.flush().map_err(error)?;
+        }
+        Format::Json => {
+            let mut row = Vec::new();
+            let mut report = ReportWriter::new(&engine.db, &mut row, format)?;
+            report.write(&details.account)?;
+            report.finish()?;
+            let mut json: serde_json::Value =
+                serde_json::from_slice(&row).map_err(|x| x.to_string())?;
+            json["open_disputes"] = details
+                .open_disputes
+                .iter()
+                .map(|(tx, opened)| serde_json::json!({ "tx": tx, "opened": opened }))
+                .collect();
+            json["history"] = details
+                .history
+                .iter()
+                .map(|txn| {
+                    serde_json::json!({
+                        "tx": txn.txn_id,
+                        "type": txn.transaction_type.as_str(),
+                        "amount": txn.amount.map(|x| precision.round(x)),
+                        "timestamp": txn.timestamp,
+                        "disputed_at": disputed_at(txn.txn_id),
+                    })
+                })
+                .collect();
+            writeln!(out, "{}", json).map_err(error)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimulatedClock;
+
+    #[test]
+    fn one_client_is_shown_with_its_history() -> Result<()> {
+        let engine = Engine::with_clock(SimulatedClock::new(100));
+        engine.process(Transaction::deposit(1, 1, 2.0))?;
+        engine.process(Transaction::deposit(2, 2, 5.0))?;
+        engine.process(Transaction::withdrawal(1, 3, 0.5))?;
+        engine.process(Transaction::dispute(1, 1))?;
+        assert_eq!(engine.client_details(3)?, None);
+        let details = engine.client_details(1)?.ok_or("no client 1")?;
+        assert_eq!(details.open_disputes, [(1, 100)]);
+
+        let mut csv = Vec::new();
+        write_client_details(&engine, &details, &mut csv, Format::Csv)?;
+        assert_eq!(
+            String::from_utf8_lossy(&csv),
+            "client,available,held,total,locked\n\
+             1,-0.5000,2.0000,1.5000,false\n\
+             \n\
+             tx,type,amount,timestamp,disputed_at\n\
+             1,deposit,2.0000,100,100\n\
+             3,withdrawal,0.5000,100,\n"
+        );
+        let mut json = Vec::new();
+        write_client_details(&engine, &details, &mut json, Format::Json)?;
+        let json: serde_json::Value = serde_json::from_slice(&json).map_err(|x| x.to_string())?;
+        assert_eq!(json["history"][1]["tx"], 3);
+        assert_eq!(json["open_disputes"][0]["opened"], 100);
+        Ok(())
+    }
+}
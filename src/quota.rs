@@ -0,0 +1,278 @@
+use crate::{
+    clock::Timestamp, open_file_read_csv, ClientId, Database, Result, Transaction, TransactionType,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufReader,
+    sync::Mutex,
+};
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// The limits of one tenant. Unset limits aren't enforced.
+pub struct Quota {
+    /// Accounts the tenant's clients may hold.
+    pub max_accounts: Option<usize>,
+    /// Deposits and withdrawals stored for later disputes.
+    pub max_transactions: Option<usize>,
+    /// Transactions per second, by the transactions' own timestamps so a
+    /// replayed file is limited the same way as live traffic.
+    pub max_rate: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QuotaConfig {
+    /// The quota of tenants not listed in `tenants`.
+    #[serde(default)]
+    default: Quota,
+    #[serde(default)]
+    tenants: HashMap<String, Quota>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The quota a transaction was rejected for.
+pub enum Exceeded {
+    Accounts,
+    Transactions,
+    Rate,
+}
+
+impl Exceeded {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Exceeded::Accounts => "max_accounts",
+            Exceeded::Transactions => "max_transactions",
+            Exceeded::Rate => "max_rate",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+/// What a tenant holds and has been refused.
+pub struct Usage {
+    pub accounts: usize,
+    pub stored_transactions: usize,
+    /// Transactions rejected for exceeding a quota.
+    pub rejected: u64,
+    /// The second the rate is being counted in, and the transactions
+    /// admitted in it so far.
+    second: Timestamp,
+    in_second: u64,
+}
+
+/// Per tenant quotas, checked as transactions are ingested.
+///
+/// Clients belong to the tenant named in the `tenant` column of the client
+/// metadata. Clients without one aren't limited.
+#[derive(Debug)]
+pub struct Quotas {
+    default: Quota,
+    limits: HashMap<String, Quota>,
+    tenants: HashMap<ClientId, String>,
+    usage: Mutex<BTreeMap<String, Usage>>,
+}
+
+impl Quotas {
+    /// Reads the quotas from the JSON file `config`, and the tenant of each
+    /// client from the `client` and `tenant` columns of the csv `clients`.
+    pub fn load(config: String, clients: Option<String>) -> Result<Self> {
+        let file = File::open(&config).map_err(|x| format!("error opening {}: {}", config, x))?;
+        let parsed: QuotaConfig = serde_json::from_reader(BufReader::new(file))
+            .map_err(|x| format!("{} is not a valid quotas file: {}", config, x))?;
+        let clients = clients.ok_or("--quotas needs --client-metadata naming each tenant")?;
+        let mut reader = open_file_read_csv(clients.clone())?;
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|h| h.trim().eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("{} has no {} column", clients, name))
+        };
+        let (client, tenant) = (column("client")?, column("tenant")?);
+        let mut tenants = HashMap::new();
+        for record in reader.records() {
+            let record = record?;
+            let client_id = record
+                .get(client)
+                .unwrap_or("")
+                .trim()
+                .parse::<ClientId>()?;
+            let name = record.get(tenant).unwrap_or("").trim();
+            if !name.is_empty() {
+                tenants.insert(client_id, name.to_string());
+            }
+        }
+        Ok(Quotas {
+            default: parsed.default,
+            limits: parsed.tenants,
+            tenants,
+            usage: Mutex::default(),
+        })
+    }
+
+    /// Counts the accounts and stored transactions `db` already holds,
+    /// e.g. from earlier runs, against their tenants.
+    pub fn count_existing(&self, db: &Database) -> Result<()> {
+        let mut usage = self.usage.lock().map_err(|_| "quota lock poisoned")?;
+        db.for_each_client(|client_id, _| {
+            if let Some(tenant) = self.tenants.get(&client_id) {
+                usage.entry(tenant.clone()).or_default().accounts += 1;
+            }
+        })?;
+        db.for_each_transaction(|txn| {
+            if let Some(tenant) = self.tenants.get(&txn.client_id) {
+                usage.entry(tenant.clone()).or_default().stored_transactions += 1;
+            }
+        })
+    }
+
+    /// Checks `txn` against the quota of its client's tenant, returning the
+    /// tenant and the quota it would exceed, if any. `new_account` says
+    /// whether the client has no account yet.
+    ///
+    /// An admitted transaction is counted right away, so concurrent ones
+    /// can't overshoot a quota together. Use [`Quotas::release`] if it
+    /// isn't applied after all.
+    pub fn admit(
+        &self,
+        txn: &Transaction,
+        new_account: bool,
+    ) -> Result<Option<(String, Exceeded)>> {
+        let Some(tenant) = self.tenants.get(&txn.client_id) else {
+            return Ok(None);
+        };
+        let quota = self.limits.get(tenant).unwrap_or(&self.default);
+        let mut usage = self.usage.lock().map_err(|_| "quota lock poisoned")?;
+        let usage = usage.entry(tenant.clone()).or_default();
+        let timestamp = txn.timestamp.unwrap_or_default();
+        let in_second = match timestamp > usage.second {
+            true => 1,
+            false => usage.in_second + 1,
+        };
+        let stores = stores(txn.transaction_type);
+        let exceeded = if quota.max_rate.is_some_and(|max| in_second > max) {
+            Some(Exceeded::Rate)
+        } else if new_account && quota.max_accounts.is_some_and(|max| usage.accounts >= max) {
+            Some(Exceeded::Accounts)
+        } else if stores
+            && quota
+                .max_transactions
+                .is_some_and(|max| usage.stored_transactions >= max)
+        {
+            Some(Exceeded::Transactions)
+        } else {
+            None
+        };
+        if let Some(exceeded) = exceeded {
+            usage.rejected += 1;
+            return Ok(Some((tenant.clone(), exceeded)));
+        }
+        usage.second = usage.second.max(timestamp);
+        usage.in_second = in_second;
+        usage.accounts += new_account as usize;
+        usage.stored_transactions += stores as usize;
+        Ok(None)
+    }
+
+    /// Gives back what an admitted transaction was counted for if it
+    /// wasn't applied. Its client's account is kept, as the engine opens
+    /// it either way.
+    pub fn release(&self, transaction_type: TransactionType, client_id: ClientId) -> Result<()> {
+        let Some(tenant) = self.tenants.get(&client_id) else {
+            return Ok(());
+        };
+        if stores(transaction_type) {
+            let mut usage = self.usage.lock().map_err(|_| "quota lock poisoned")?;
+            if let Some(usage) = usage.get_mut(tenant) {
+                usage.stored_transactions = usage.stored_transactions.saturating_sub(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// The usage of every tenant seen so far, by name.
+    pub fn usage(&self) -> Result<Vec<(String, Usage)>> {
+        let usage = self.usage.lock().map_err(|_| "quota lock poisoned")?;
+        Ok(usage
+            .iter()
+            .map(|(tenant, usage)| (tenant.clone(), usage.clone()))
+            .collect())
+    }
+}
+
+/// Whether an applied transaction of this type is stored for disputes.
+fn stores(transaction_type: TransactionType) -> bool {
+    matches!(
+        transaction_type,
+        TransactionType::Deposit | TransactionType::Withdrawal
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, i18n::Reason, Engine, TransactionOutcome};
+
+    #[test]
+    fn tenants_are_held_to_their_quotas() -> Result<()> {
+        let (config, clients) = (
+            crate::temp_path("payments-engine-quota-test.json"),
+            crate::temp_path("payments-engine-quota-test.csv"),
+        );
+        std::fs::write(
+            &config,
+            r#"{"default": {"max_accounts": 2, "max_transactions": 3},
+                "tenants": {"busy": {"max_rate": 2}}}"#,
+        )
+        .map_err(|x| x.to_string())?;
+        std::fs::write(
+            &clients,
+            "client,tenant\n1,small\n2,small\n3,small\n4,busy\n",
+        )
+        .map_err(|x| x.to_string())?;
+        let quotas = Quotas::load(
+            config.to_string_lossy().to_string(),
+            Some(clients.to_string_lossy().to_string()),
+        )?;
+        let engine = Engine::with_clock(SimulatedClock::new(0)).with_quotas(quotas)?;
+        let mut tx = 0;
+        let mut deposit = |client_id, timestamp| {
+            tx += 1;
+            engine.process(Transaction {
+                timestamp: Some(timestamp),
+                ..Transaction::deposit(client_id, tx, 1.0)
+            })
+        };
+        let quota_exceeded = TransactionOutcome::Rejected(Reason::QuotaExceeded);
+        assert_eq!(deposit(1, 0)?, TransactionOutcome::Applied);
+        assert_eq!(deposit(2, 0)?, TransactionOutcome::Applied);
+        assert_eq!(deposit(3, 0)?, quota_exceeded);
+        assert_eq!(deposit(1, 0)?, TransactionOutcome::Applied);
+        assert_eq!(deposit(2, 0)?, quota_exceeded);
+        // Clients without a tenant aren't limited.
+        assert_eq!(deposit(5, 0)?, TransactionOutcome::Applied);
+
+        assert_eq!(deposit(4, 10)?, TransactionOutcome::Applied);
+        assert_eq!(deposit(4, 10)?, TransactionOutcome::Applied);
+        assert_eq!(deposit(4, 10)?, quota_exceeded);
+        assert_eq!(deposit(4, 11)?, TransactionOutcome::Applied);
+
+        let usage = engine.metrics()?.tenants;
+        let counts: Vec<_> = usage
+            .iter()
+            .map(|(tenant, usage)| {
+                (
+                    tenant.as_str(),
+                    usage.accounts,
+                    usage.stored_transactions,
+                    usage.rejected,
+                )
+            })
+            .collect();
+        assert_eq!(counts, [("busy", 1, 3, 1), ("small", 2, 3, 2)]);
+        Ok(())
+    }
+}
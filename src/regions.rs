@@ -0,0 +1,286 @@
+use crate::{
+    clock::Timestamp,
+    dates::{Calendar, Date},
+    i18n::{message, Key},
+    limits::parse_duration,
+    open_file_read_csv, ClientId, Result,
+};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    io::BufReader,
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The rules transactions of clients in one region are held to. Every
+/// rule is optional, an unset rule doesn't restrict anything.
+pub struct RuleSet {
+    /// Withdrawals above this amount are rejected.
+    #[serde(default)]
+    pub max_withdrawal: Option<f64>,
+    /// Transactions older than this, e.g. `"60d"`, can no longer be
+    /// disputed.
+    #[serde(default, deserialize_with = "duration")]
+    pub dispute_window: Option<Duration>,
+    /// The currency the region's amounts are in, shown in the report.
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// `YYYY-MM-DD` dates that aren't business days, besides weekends.
+    #[serde(default)]
+    pub holidays: Calendar,
+}
+
+fn duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| parse_duration(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The rule sets of every region at one point in time.
+struct RuleSets {
+    /// Used for clients without a known country, or whose country has no
+    /// rule set of its own.
+    #[serde(default)]
+    default: RuleSet,
+    /// Rule sets by country code, e.g. `"DE"`.
+    #[serde(default)]
+    regions: HashMap<String, RuleSet>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The rule sets in force from a date on, replacing the earlier ones
+/// entirely.
+struct Version {
+    /// The first `YYYY-MM-DD` day, in UTC, the version applies to.
+    effective: Date,
+    #[serde(default)]
+    default: RuleSet,
+    #[serde(default)]
+    regions: HashMap<String, RuleSet>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The `--regions` file: the rule sets in force from the start, and the
+/// versions that replace them over time.
+struct RegionConfig {
+    #[serde(default)]
+    default: RuleSet,
+    #[serde(default)]
+    regions: HashMap<String, RuleSet>,
+    #[serde(default)]
+    versions: Vec<Version>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Which rule set applies to each client, and when.
+pub struct Regions {
+    /// The rule sets by the day they take effect, in order. The first one
+    /// is in force from the start.
+    versions: Vec<(Date, RuleSets)>,
+    /// Country code of each client with metadata.
+    countries: HashMap<ClientId, String>,
+}
+
+impl Default for Regions {
+    fn default() -> Self {
+        Regions {
+            versions: vec![(Date::from_timestamp(0), RuleSets::default())],
+            countries: HashMap::new(),
+        }
+    }
+}
+
+fn country_code(country: &str) -> String {
+    country.trim().to_uppercase()
+}
+
+impl Regions {
+    /// Reads the rule sets from the JSON file `config`, and the country of
+    /// each client from the `client` and `country` columns of the csv
+    /// `clients`. Countries without a rule set of their own are warned
+    /// about once each, their clients get the default rule set.
+    pub fn load(config: String, clients: Option<String>) -> Result<Self> {
+        let file = File::open(&config).map_err(|x| format!("error opening {}: {}", config, x))?;
+        let parsed: RegionConfig = serde_json::from_reader(BufReader::new(file))
+            .map_err(|x| format!("{} is not a valid regions file: {}", config, x))?;
+        let rule_sets = |default, regions: HashMap<String, RuleSet>| RuleSets {
+            default,
+            regions: regions
+                .into_iter()
+                .map(|(country, rules)| (country_code(&country), rules))
+                .collect(),
+        };
+        let mut versions = vec![(
+            Date::from_timestamp(0),
+            rule_sets(parsed.default, parsed.regions),
+        )];
+        let mut dated = parsed.versions;
+        dated.sort_by_key(|version| version.effective);
+        for pair in dated.windows(2) {
+            if pair[0].effective == pair[1].effective {
+                return Err(format!(
+                    "{} has two versions effective {}",
+                    config, pair[0].effective
+                )
+                .into());
+            }
+        }
+        versions.extend(dated.into_iter().map(|version| {
+            (
+                version.effective,
+                rule_sets(version.default, version.regions),
+            )
+        }));
+        let mut countries = HashMap::new();
+        if let Some(clients) = clients {
+            let mut reader = open_file_read_csv(clients.clone())?;
+            let headers = reader.headers()?.clone();
+            let column = |name: &str| {
+                headers
+                    .iter()
+                    .position(|h| h.trim().eq_ignore_ascii_case(name))
+                    .ok_or_else(|| format!("{} has no {} column", clients, name))
+            };
+            let (client, country) = (column("client")?, column("country")?);
+            for record in reader.records() {
+                let record = record?;
+                let client_id = record
+                    .get(client)
+                    .unwrap_or("")
+                    .trim()
+                    .parse::<ClientId>()?;
+                countries.insert(client_id, country_code(record.get(country).unwrap_or("")));
+            }
+        }
+        let unknown: BTreeSet<_> = countries
+            .values()
+            .filter(|country| {
+                !versions
+                    .iter()
+                    .any(|(_, rules)| rules.regions.contains_key(*country))
+            })
+            .collect();
+        for region in unknown {
+            tracing::warn!("{}", message(Key::UnknownRegion, &[("region", region)]));
+        }
+        Ok(Regions {
+            versions,
+            countries,
+        })
+    }
+
+    /// Whether any rule sets were loaded at all.
+    pub fn is_configured(&self) -> bool {
+        *self != Regions::default()
+    }
+
+    /// The rule set in force for a client at `at`, from the latest version
+    /// effective on or before that day.
+    pub fn rules_for(&self, client_id: ClientId, at: Timestamp) -> &RuleSet {
+        let day = Date::from_timestamp(at);
+        let (_, rules) = self
+            .versions
+            .iter()
+            .rev()
+            .find(|(effective, _)| *effective <= day)
+            .unwrap_or(&self.versions[0]);
+        self.countries
+            .get(&client_id)
+            .and_then(|country| rules.regions.get(country))
+            .unwrap_or(&rules.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::SimulatedClock, i18n::Reason, policy::Policy, Engine, Transaction,
+        TransactionOutcome,
+    };
+
+    #[test]
+    fn clients_get_their_region_or_the_default() -> Result<()> {
+        let config = crate::temp_path("payments-engine-regions-test.json");
+        let clients = crate::temp_path("payments-engine-regions-test.csv");
+        std::fs::write(
+            &config,
+            r#"{
+                "default": { "currency": "USD" },
+                "regions": { "de": { "max_withdrawal": 500, "dispute_window": "60d", "currency": "EUR" } }
+            }"#,
+        )
+        .map_err(|x| x.to_string())?;
+        std::fs::write(&clients, "client,country\n1,DE\n2,FR\n").map_err(|x| x.to_string())?;
+        let regions = Regions::load(
+            config.to_string_lossy().to_string(),
+            Some(clients.to_string_lossy().to_string()),
+        )?;
+        assert!(regions.is_configured());
+        assert_eq!(regions.rules_for(1, 0).max_withdrawal, Some(500.0));
+        assert_eq!(
+            regions.rules_for(1, 0).dispute_window,
+            Some(Duration::from_secs(60 * 24 * 60 * 60))
+        );
+        assert_eq!(regions.rules_for(2, 0).currency.as_deref(), Some("USD"));
+        assert_eq!(regions.rules_for(3, 0).max_withdrawal, None);
+
+        let clock = SimulatedClock::new(0);
+        let engine = Engine::with_clock(clock.clone()).with_policy(Policy {
+            regions,
+            ..Policy::default()
+        });
+        engine.process(Transaction::deposit(1, 1, 1_000.0))?;
+        engine.process(Transaction::deposit(2, 2, 1_000.0))?;
+        assert_eq!(
+            engine.process(Transaction::withdrawal(1, 3, 600.0))?,
+            TransactionOutcome::Rejected(Reason::OverLimit)
+        );
+        assert_eq!(
+            engine.process(Transaction::withdrawal(2, 4, 600.0))?,
+            TransactionOutcome::Applied
+        );
+        clock.advance(61 * 24 * 60 * 60);
+        assert_eq!(
+            engine.process(Transaction::dispute(1, 1))?,
+            TransactionOutcome::Rejected(Reason::DisputeWindowClosed)
+        );
+        assert_eq!(
+            engine.process(Transaction::dispute(2, 2))?,
+            TransactionOutcome::Applied
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn the_version_in_force_at_the_transaction_applies() -> Result<()> {
+        let config = crate::temp_path("payments-engine-regions-versions-test.json");
+        std::fs::write(
+            &config,
+            r#"{
+                "default": { "max_withdrawal": 100 },
+                "versions": [
+                    { "effective": "2024-06-01", "default": { "max_withdrawal": 300 } },
+                    { "effective": "2024-01-01", "default": { "max_withdrawal": 200 } }
+                ]
+            }"#,
+        )
+        .map_err(|x| x.to_string())?;
+        let regions = Regions::load(config.to_string_lossy().to_string(), None)?;
+        // 2023-12-31, 2024-01-01 and 2024-07-01 at noon.
+        let limit = |at| regions.rules_for(1, at).max_withdrawal;
+        assert_eq!(limit(1_704_024_000), Some(100.0));
+        assert_eq!(limit(1_704_110_400), Some(200.0));
+        assert_eq!(limit(1_719_835_200), Some(300.0));
+        Ok(())
+    }
+}
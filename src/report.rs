@@ -0,0 +1,124 @@
+use crate::{account::AccountView, sink::Sink, ClientId, Database, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// The order of the accounts in a report.
+pub enum SortOrder {
+    /// By client id, and by currency within a client.
+    #[default]
+    Client,
+    /// Largest total first, ties by client id.
+    Total,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Which accounts a report lists, and in what order. By default every
+/// account but dormant ones, by client id, so the same state always gives
+/// the same report.
+pub struct ReportOptions {
+    pub sort: SortOrder,
+    /// Lists dormant accounts too, see
+    /// [`crate::policy::Policy::dormant_after`].
+    pub include_dormant: bool,
+    /// Only locked accounts.
+    pub only_locked: bool,
+    /// Only the accounts of these clients, every client's if empty.
+    pub clients: Vec<ClientId>,
+    /// Only accounts whose total is at least this.
+    pub min_total: Option<f64>,
+}
+
+impl ReportOptions {
+    /// Whether `account` passes the filters, dormancy aside.
+    pub fn includes(&self, account: &AccountView) -> bool {
+        (!self.only_locked || account.locked())
+            && (self.clients.is_empty() || self.clients.contains(&account.client_id()))
+            && self.min_total.is_none_or(|min| account.total() >= min)
+    }
+}
+
+/// Writes the accounts of `db` selected by `options` to `sink` in their
+/// order, a row per currency each has funds in, and finishes it. The
+/// accounts left out are told to the sink, so totals it checks against
+/// the ledger still balance.
+pub fn write_selected(
+    db: &Database,
+    mut sink: Box<dyn Sink + '_>,
+    options: &ReportOptions,
+) -> Result<()> {
+    let mut accounts = Vec::new();
+    db.for_each_client(|client_id, client| {
+        let listed = options.include_dormant || !db.is_dormant(client);
+        for account in AccountView::per_currency(client_id, client) {
+            let listed = listed && options.includes(&account);
+            accounts.push((account, listed));
+        }
+    })?;
+    // Stable, so the currencies of a client stay in their order.
+    match options.sort {
+        SortOrder::Client => accounts.sort_by_key(|(account, _)| account.client_id()),
+        SortOrder::Total => accounts.sort_by(|(a, _), (b, _)| {
+            b.total()
+                .total_cmp(&a.total())
+                .then(a.client_id().cmp(&b.client_id()))
+        }),
+    }
+    for (account, listed) in &accounts {
+        match listed {
+            true => sink.write(account)?,
+            false => sink.leave_out(account),
+        }
+    }
+    sink.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{format::Format, output::ReportWriter, Engine, Transaction};
+
+    fn report(engine: &Engine, options: &ReportOptions) -> Result<String> {
+        let mut out = Vec::new();
+        let writer = ReportWriter::new(&engine.db, &mut out, Format::Csv)?.with_totals();
+        write_selected(&engine.db, Box::new(writer), options)?;
+        let report = String::from_utf8_lossy(&out);
+        // Just the clients, in order.
+        Ok(report
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap_or_default())
+            .filter(|client| *client != "total")
+            .collect::<Vec<_>>()
+            .join(" "))
+    }
+
+    #[test]
+    fn reports_are_sorted_and_filtered() -> Result<()> {
+        let engine = Engine::default();
+        for client in [9, 3, 7, 1, 5] {
+            engine.process(Transaction::deposit(client, client as u32, client as f64))?;
+        }
+        engine.process(Transaction::dispute(7, 7))?;
+        engine.process(Transaction::chargeback(7, 7))?;
+        assert_eq!(report(&engine, &ReportOptions::default())?, "1 3 5 7 9");
+        let by_total = ReportOptions {
+            sort: SortOrder::Total,
+            ..ReportOptions::default()
+        };
+        assert_eq!(report(&engine, &by_total)?, "9 5 3 1 7");
+        let locked = ReportOptions {
+            only_locked: true,
+            ..ReportOptions::default()
+        };
+        assert_eq!(report(&engine, &locked)?, "7");
+        // The totals of a filtered report still check out against the
+        // ledger.
+        let selected = ReportOptions {
+            clients: vec![9, 1, 5, 2],
+            min_total: Some(4.0),
+            ..ReportOptions::default()
+        };
+        assert_eq!(report(&engine, &selected)?, "5 9");
+        Ok(())
+    }
+}
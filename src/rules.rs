@@ -0,0 +1,564 @@
+use crate::{
+    clock::Timestamp,
+    limits::parse_duration,
+    plugin::{Plugin, Verdict},
+    ClientId, Result, Transaction, TransactionType,
+};
+use std::{collections::HashMap, fmt, fs, sync::Mutex};
+
+/// The transactions of a client the rules let through, with their
+/// timestamps.
+type Recent = [(TransactionType, Timestamp)];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A property of a transaction a rule can look at.
+enum Field {
+    Type,
+    Client,
+    Tx,
+    Amount,
+    Timestamp,
+    Memo,
+    Category,
+    /// How many transactions of the type the rules let through for the
+    /// client in the window of seconds before this one.
+    Count(TransactionType, u64),
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "type" => Field::Type,
+            "client" => Field::Client,
+            "tx" => Field::Tx,
+            "amount" => Field::Amount,
+            "timestamp" => Field::Timestamp,
+            "memo" => Field::Memo,
+            "category" => Field::Category,
+            _ => return None,
+        })
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(
+            self,
+            Field::Client | Field::Tx | Field::Amount | Field::Timestamp | Field::Count(..)
+        )
+    }
+
+    fn value(self, txn: &Transaction, recent: &Recent) -> Option<Value> {
+        let number = |n: f64| Some(Value::Number(n));
+        let text = |s: &str| Some(Value::Text(s.to_string()));
+        match self {
+            Field::Type => text(txn.transaction_type.as_str()),
+            Field::Client => number(txn.client_id as f64),
+            Field::Tx => number(txn.txn_id.into()),
+            Field::Amount => txn.amount.and_then(number),
+            Field::Timestamp => txn.timestamp.and_then(|t| number(t as f64)),
+            Field::Memo => txn.memo.as_deref().and_then(text),
+            Field::Category => txn.category.as_deref().and_then(text),
+            Field::Count(kind, window) => {
+                let now = txn.timestamp.unwrap_or_default();
+                let count = recent
+                    .iter()
+                    .filter(|(seen, at)| *seen == kind && now.saturating_sub(*at) < window)
+                    .count();
+                number(count as f64)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+enum Value {
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Compare(Field, Op, Value),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+impl Condition {
+    fn holds(&self, txn: &Transaction, recent: &Recent) -> bool {
+        match self {
+            Condition::Compare(field, op, expected) => {
+                // A transaction without the field only differs from things.
+                let Some(actual) = field.value(txn, recent) else {
+                    return *op == Op::Ne;
+                };
+                match op {
+                    Op::Eq => actual == *expected,
+                    Op::Ne => actual != *expected,
+                    Op::Lt => actual < *expected,
+                    Op::Le => actual <= *expected,
+                    Op::Gt => actual > *expected,
+                    Op::Ge => actual >= *expected,
+                }
+            }
+            Condition::Not(inner) => !inner.holds(txn, recent),
+            Condition::And(left, right) => left.holds(txn, recent) && right.holds(txn, recent),
+            Condition::Or(left, right) => left.holds(txn, recent) || right.holds(txn, recent),
+        }
+    }
+
+    /// The longest window a count in the condition looks back over.
+    fn window(&self) -> Option<u64> {
+        match self {
+            Condition::Compare(Field::Count(_, window), ..) => Some(*window),
+            Condition::Compare(..) => None,
+            Condition::Not(inner) => inner.window(),
+            Condition::And(left, right) | Condition::Or(left, right) => {
+                left.window().max(right.window())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    Allow,
+    Reject(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    when: Condition,
+    then: Action,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Number(f64),
+    Text(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Comma,
+}
+
+fn tokenize(line: &str) -> std::result::Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => text.push(c),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+            tokens.push(Token::Text(text));
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                number.push(c);
+                chars.next();
+            }
+            let number = number
+                .parse()
+                .map_err(|_| format!("invalid number {}", number))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_alphanumeric() || c == '_' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        } else {
+            chars.next();
+            let next = chars.peek().copied();
+            let (token, pair) = match (c, next) {
+                ('=', Some('=')) => (Token::Op(Op::Eq), true),
+                ('!', Some('=')) => (Token::Op(Op::Ne), true),
+                ('<', Some('=')) => (Token::Op(Op::Le), true),
+                ('>', Some('=')) => (Token::Op(Op::Ge), true),
+                ('&', Some('&')) => (Token::And, true),
+                ('|', Some('|')) => (Token::Or, true),
+                ('<', _) => (Token::Op(Op::Lt), false),
+                ('>', _) => (Token::Op(Op::Gt), false),
+                ('!', _) => (Token::Not, false),
+                ('(', _) => (Token::Open, false),
+                (')', _) => (Token::Close, false),
+                (',', _) => (Token::Comma, false),
+                _ => return Err(format!("unexpected {:?}", c)),
+            };
+            if pair {
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::Number(number) => write!(f, "{}", number),
+            Token::Text(text) => write!(f, "{:?}", text),
+            Token::Op(op) => write!(
+                f,
+                "{}",
+                match op {
+                    Op::Eq => "==",
+                    Op::Ne => "!=",
+                    Op::Lt => "<",
+                    Op::Le => "<=",
+                    Op::Gt => ">",
+                    Op::Ge => ">=",
+                }
+            ),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Not => write!(f, "!"),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+            Token::Comma => write!(f, ","),
+        }
+    }
+}
+
+/// Names a token, or the end of the rule, for error messages.
+fn found(token: Option<Token>) -> String {
+    token.map_or("the end of the rule".to_string(), |token| token.to_string())
+}
+
+/// A recursive descent parser over the tokens of one rule.
+struct Parser {
+    tokens: std::iter::Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser {
+    fn expect_word(&mut self, word: &str) -> std::result::Result<(), String> {
+        match self.tokens.next() {
+            Some(Token::Word(w)) if w == word => Ok(()),
+            other => Err(format!("expected {}, found {}", word, found(other))),
+        }
+    }
+
+    fn rule(&mut self) -> std::result::Result<Rule, String> {
+        self.expect_word("when")?;
+        let when = self.or()?;
+        self.expect_word("then")?;
+        let then = match self.tokens.next() {
+            Some(Token::Word(w)) if w == "allow" => Action::Allow,
+            Some(Token::Word(w)) if w == "reject" => match self.tokens.next() {
+                Some(Token::Text(reason)) => Action::Reject(reason),
+                other => return Err(format!("expected a quoted reason, found {}", found(other))),
+            },
+            other => return Err(format!("expected allow or reject, found {}", found(other))),
+        };
+        match self.tokens.next() {
+            None => Ok(Rule { when, then }),
+            Some(extra) => Err(format!("unexpected {} after the action", extra)),
+        }
+    }
+
+    fn or(&mut self) -> std::result::Result<Condition, String> {
+        let mut condition = self.and()?;
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> std::result::Result<Condition, String> {
+        let mut condition = self.not()?;
+        while self.tokens.next_if_eq(&Token::And).is_some() {
+            condition = Condition::And(Box::new(condition), Box::new(self.not()?));
+        }
+        Ok(condition)
+    }
+
+    fn not(&mut self) -> std::result::Result<Condition, String> {
+        match self.tokens.next() {
+            Some(Token::Not) => Ok(Condition::Not(Box::new(self.not()?))),
+            Some(Token::Open) => {
+                let condition = self.or()?;
+                match self.tokens.next() {
+                    Some(Token::Close) => Ok(condition),
+                    other => Err(format!("expected ), found {}", found(other))),
+                }
+            }
+            Some(Token::Word(name)) => self.compare(&name),
+            other => Err(format!("expected a condition, found {}", found(other))),
+        }
+    }
+
+    fn compare(&mut self, name: &str) -> std::result::Result<Condition, String> {
+        let field = match name {
+            "count" => self.count()?,
+            name => Field::parse(name).ok_or_else(|| format!("unknown field {}", name))?,
+        };
+        let op = match self.tokens.next() {
+            Some(Token::Op(op)) => op,
+            other => return Err(format!("expected a comparison, found {}", found(other))),
+        };
+        let value = match (self.tokens.next(), field.is_numeric()) {
+            (Some(Token::Number(n)), true) => Value::Number(n),
+            (Some(Token::Word(w) | Token::Text(w)), false) => Value::Text(w),
+            (other, numeric) => {
+                let kind = if numeric {
+                    "a number"
+                } else {
+                    "a word or string"
+                };
+                return Err(format!(
+                    "{} must be compared to {}, found {}",
+                    name,
+                    kind,
+                    found(other)
+                ));
+            }
+        };
+        if !field.is_numeric() && !matches!(op, Op::Eq | Op::Ne) {
+            return Err(format!("{} can only be compared with == or !=", name));
+        }
+        if let (Field::Type, Value::Text(kind)) = (field, &value) {
+            if !TransactionType::ALL.iter().any(|t| t.as_str() == kind) {
+                return Err(format!("unknown transaction type {}", kind));
+            }
+        }
+        Ok(Condition::Compare(field, op, value))
+    }
+
+    /// The arguments of `count(<type>, <window>)`, after its name.
+    fn count(&mut self) -> std::result::Result<Field, String> {
+        match self.tokens.next() {
+            Some(Token::Open) => {}
+            other => return Err(format!("expected (, found {}", found(other))),
+        }
+        let kind = match self.tokens.next() {
+            Some(Token::Word(kind)) => TransactionType::ALL
+                .into_iter()
+                .find(|t| t.as_str() == kind)
+                .ok_or_else(|| format!("unknown transaction type {}", kind))?,
+            other => {
+                return Err(format!(
+                    "expected a transaction type, found {}",
+                    found(other)
+                ))
+            }
+        };
+        match self.tokens.next() {
+            Some(Token::Comma) => {}
+            other => return Err(format!("expected ,, found {}", found(other))),
+        }
+        // The tokens split a window like 1d into its number and unit.
+        let window = match self.tokens.next() {
+            Some(Token::Number(n)) => match self.tokens.next_if(|t| matches!(t, Token::Word(_))) {
+                Some(Token::Word(unit)) => format!("{}{}", n, unit),
+                _ => n.to_string(),
+            },
+            other => return Err(format!("expected a window, found {}", found(other))),
+        };
+        let window = parse_duration(&window)?.as_secs();
+        match self.tokens.next() {
+            Some(Token::Close) => Ok(Field::Count(kind, window)),
+            other => Err(format!("expected ), found {}", found(other))),
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Rules written in a small declarative language, checked against every
+/// transaction. The first rule whose condition holds decides; transactions
+/// no rule matches are allowed.
+///
+/// One rule per line, blank lines and lines starting with `#` are skipped:
+///
+/// ```text
+/// when type == withdrawal && amount > 10000 then reject "LimitExceeded"
+/// when client == 7 || category == gambling then reject "Blocked"
+/// when type == dispute && count(dispute, 1d) >= 3 then reject "TooManyDisputes"
+/// when !(amount >= 0.01) then allow
+/// ```
+///
+/// `count(<type>, <window>)` is how many transactions of the type the
+/// rules let through for the same client within the window before this
+/// one, whether or not the engine went on to apply them.
+pub struct Rules {
+    rules: Vec<Rule>,
+    /// The longest window a rule counts over, if any rule counts.
+    window: Option<u64>,
+    recent: Mutex<HashMap<ClientId, Vec<(TransactionType, Timestamp)>>>,
+}
+
+impl Rules {
+    /// Compiles the rules in `source`, reporting the line of the first
+    /// one that isn't valid.
+    pub fn compile(source: &str) -> Result<Self> {
+        let rules: Vec<Rule> = source
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|(i, line)| {
+                let tokens = tokenize(line)?;
+                Parser {
+                    tokens: tokens.into_iter().peekable(),
+                }
+                .rule()
+                .map_err(|x| format!("rule on line {} is invalid: {}", i + 1, x))
+            })
+            .collect::<std::result::Result<_, String>>()?;
+        Ok(Rules {
+            window: rules.iter().filter_map(|rule| rule.when.window()).max(),
+            rules,
+            recent: Mutex::default(),
+        })
+    }
+
+    /// The verdict of the first rule that holds for `txn`, with the
+    /// client's `recent` transactions.
+    fn decide(&self, txn: &Transaction, recent: &Recent) -> Verdict {
+        let matched = self.rules.iter().find(|rule| rule.when.holds(txn, recent));
+        match matched.map(|rule| &rule.then) {
+            None | Some(Action::Allow) => Verdict::Allow,
+            Some(Action::Reject(reason)) => Verdict::Deny(reason.clone()),
+        }
+    }
+
+    /// Reads and compiles the rules in the file at `path`.
+    pub fn load(path: String) -> Result<Self> {
+        let source =
+            fs::read_to_string(&path).map_err(|x| format!("error reading {}: {}", path, x))?;
+        Rules::compile(&source).map_err(|x| format!("{}: {}", path, x).into())
+    }
+}
+
+impl Plugin for Rules {
+    fn inspect(&self, txn: &Transaction) -> Result<Verdict> {
+        let Some(window) = self.window else {
+            return Ok(self.decide(txn, &[]));
+        };
+        let mut recent = self.recent.lock().map_err(|_| "rules lock poisoned")?;
+        let seen = recent.entry(txn.client_id).or_default();
+        let now = txn.timestamp.unwrap_or_default();
+        seen.retain(|(_, at)| now.saturating_sub(*at) < window);
+        let verdict = self.decide(txn, seen);
+        if verdict == Verdict::Allow {
+            seen.push((txn.transaction_type, now));
+        }
+        Ok(verdict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_matching_rule_decides() -> Result<()> {
+        let rules = Rules::compile(
+            r#"
+            # Client 1 is trusted.
+            when client == 1 then allow
+            when type == withdrawal && amount > 10000 then reject "LimitExceeded"
+            when !(category != "gambling") || amount < 0.01 then reject "Blocked"
+            "#,
+        )?;
+        let verdict = |txn: Transaction| rules.inspect(&txn);
+        let limit = Verdict::Deny("LimitExceeded".to_string());
+        assert_eq!(verdict(Transaction::withdrawal(2, 1, 20_000.0))?, limit);
+        assert_eq!(
+            verdict(Transaction::withdrawal(1, 2, 20_000.0))?,
+            Verdict::Allow
+        );
+        assert_eq!(
+            verdict(Transaction::deposit(2, 3, 20_000.0))?,
+            Verdict::Allow
+        );
+        assert_eq!(
+            verdict(Transaction::deposit(2, 4, 0.001))?,
+            Verdict::Deny("Blocked".to_string())
+        );
+        let gambling = Transaction::builder(TransactionType::Withdrawal, 2, 5)
+            .amount(1.0)
+            .category("gambling")
+            .build()?;
+        assert_eq!(verdict(gambling)?, Verdict::Deny("Blocked".to_string()));
+        assert_eq!(verdict(Transaction::dispute(2, 1))?, Verdict::Allow);
+        Ok(())
+    }
+
+    #[test]
+    fn counts_look_back_over_their_window() -> Result<()> {
+        let rules = Rules::compile(
+            r#"when type == dispute && count(dispute, 1d) >= 3 then reject "TooManyDisputes""#,
+        )?;
+        let dispute = |client, tx, timestamp| {
+            Transaction::builder(TransactionType::Dispute, client, tx)
+                .timestamp(timestamp)
+                .build()
+        };
+        let denied = Verdict::Deny("TooManyDisputes".to_string());
+        for tx in 1..=3 {
+            assert_eq!(
+                rules.inspect(&dispute(1, tx, 100 * tx as u64)?)?,
+                Verdict::Allow
+            );
+        }
+        assert_eq!(rules.inspect(&dispute(1, 4, 400)?)?, denied);
+        // Denied disputes don't count, and neither do other clients'.
+        assert_eq!(rules.inspect(&dispute(1, 5, 500)?)?, denied);
+        assert_eq!(rules.inspect(&dispute(2, 6, 600)?)?, Verdict::Allow);
+        // A day after the first, it has left the window.
+        assert_eq!(rules.inspect(&dispute(1, 7, 86_500)?)?, Verdict::Allow);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_rules_are_refused_with_their_line() {
+        for (source, error) in [
+            ("when amount > 5 then reject LimitExceeded", "quoted reason"),
+            ("\nwhen type == refund then allow", "line 2"),
+            ("when memo > \"a\" then allow", "== or !="),
+            ("when amount == big then allow", "a number"),
+            ("when (amount > 5 then allow", "expected )"),
+            (
+                "when count(refund, 1d) > 1 then allow",
+                "unknown transaction type",
+            ),
+            (
+                "when count(dispute, 1w) > 1 then allow",
+                "unknown duration unit",
+            ),
+        ] {
+            let Err(message) = Rules::compile(source) else {
+                panic!("{} compiled", source);
+            };
+            assert!(
+                message.to_string().contains(error),
+                "{}: {}",
+                source,
+                message
+            );
+        }
+    }
+}
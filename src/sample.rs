@@ -0,0 +1,57 @@
+use crate::{generator::Rng, ClientId};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A deterministic subset of the input to process instead of all of it.
+pub enum Sample {
+    /// Only the first `n` rows.
+    Head(u64),
+    /// Roughly this fraction of clients, with all of their rows.
+    ///
+    /// Sampling whole clients rather than individual rows keeps every
+    /// dispute, resolve and chargeback next to the transaction it refers
+    /// to, so the sampled balances are real balances of real clients.
+    Clients { rate: f64, seed: u64 },
+}
+
+impl Sample {
+    /// Whether the row at `row` (0 based) for `client_id` is part of the sample.
+    pub fn includes(&self, row: u64, client_id: ClientId) -> bool {
+        match *self {
+            Sample::Head(n) => row < n,
+            Sample::Clients { rate, seed } => Rng::new(seed ^ client_id).chance(rate),
+        }
+    }
+
+    /// Whether no row at or after `row` can be part of the sample.
+    pub fn exhausted(&self, row: u64) -> bool {
+        matches!(*self, Sample::Head(n) if row >= n)
+    }
+}
+
+impl FromStr for Sample {
+    type Err = String;
+
+    /// Parses the fraction given to `--sample`, such as `0.01`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().parse::<f64>() {
+            Ok(rate) if rate > 0.0 && rate <= 1.0 => Ok(Sample::Clients { rate, seed: 0 }),
+            _ => Err(format!("sample rate must be in (0, 1], got {:?}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_sample_is_deterministic_and_roughly_sized() {
+        let sample: Sample = "0.1".parse().unwrap();
+        let picked: Vec<_> = (0..10_000).filter(|c| sample.includes(0, *c)).collect();
+        let again: Vec<_> = (0..10_000).filter(|c| sample.includes(7, *c)).collect();
+        assert_eq!(picked, again);
+        assert!((800..1200).contains(&picked.len()));
+        assert!("1.5".parse::<Sample>().is_err());
+    }
+}
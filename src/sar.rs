@@ -0,0 +1,346 @@
+use crate::{
+    clock::Timestamp, limits::parse_duration, open_file_read_csv, schema::Schema, ClientId, Engine,
+    Result, TransactionOutcome, TransactionType,
+};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{BufReader, Write},
+    time::Duration,
+};
+
+fn window<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Duration, D::Error> {
+    parse_duration(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Deposits kept just under a reporting threshold, several times in a
+/// short while.
+pub struct Structuring {
+    /// The amount deposits are kept under.
+    pub threshold: f64,
+    /// How far under the threshold a deposit may be and still count, as a
+    /// fraction of it.
+    pub margin: f64,
+    /// How many such deposits make the pattern.
+    pub count: usize,
+    /// The time, e.g. `"24h"`, they have to fall within.
+    #[serde(deserialize_with = "window")]
+    pub window: Duration,
+}
+
+impl Default for Structuring {
+    fn default() -> Self {
+        Structuring {
+            threshold: 10_000.0,
+            margin: 0.1,
+            count: 3,
+            window: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Money withdrawn again soon after it was deposited.
+pub struct RapidInOut {
+    /// How soon after the deposit the withdrawal has to come.
+    #[serde(deserialize_with = "window")]
+    pub window: Duration,
+    /// How much of the deposit has to be withdrawn, as a fraction of it.
+    pub fraction: f64,
+}
+
+impl Default for RapidInOut {
+    fn default() -> Self {
+        RapidInOut {
+            window: Duration::from_secs(60 * 60),
+            fraction: 0.9,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Several chargebacks of one client in a short while.
+pub struct ChargebackCluster {
+    /// How many chargebacks make the pattern.
+    pub count: usize,
+    /// The time they have to fall within.
+    #[serde(deserialize_with = "window")]
+    pub window: Duration,
+}
+
+impl Default for ChargebackCluster {
+    fn default() -> Self {
+        ChargebackCluster {
+            count: 3,
+            window: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The suspicious patterns to look for. A patterns file only looks for
+/// the patterns it lists, without one all of them are looked for with
+/// their default settings.
+pub struct Patterns {
+    #[serde(default)]
+    pub structuring: Option<Structuring>,
+    #[serde(default)]
+    pub rapid_in_out: Option<RapidInOut>,
+    #[serde(default)]
+    pub chargeback_cluster: Option<ChargebackCluster>,
+}
+
+impl Default for Patterns {
+    fn default() -> Self {
+        Patterns {
+            structuring: Some(Structuring::default()),
+            rapid_in_out: Some(RapidInOut::default()),
+            chargeback_cluster: Some(ChargebackCluster::default()),
+        }
+    }
+}
+
+impl Patterns {
+    /// Reads the patterns from the JSON file at `path`.
+    pub fn load(path: String) -> Result<Self> {
+        let file = File::open(&path).map_err(|x| format!("error opening {}: {}", path, x))?;
+        Ok(serde_json::from_reader(BufReader::new(file))
+            .map_err(|x| format!("{} is not a valid patterns file: {}", path, x))?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// The suspicious pattern a transaction was extracted for.
+pub enum Pattern {
+    Structuring,
+    RapidInOut,
+    ChargebackCluster,
+}
+
+impl Pattern {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Pattern::Structuring => "STRUCTURING",
+            Pattern::RapidInOut => "RAPID_IN_OUT",
+            Pattern::ChargebackCluster => "CHARGEBACK_CLUSTER",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An applied transaction, as the patterns see it.
+pub struct Activity {
+    pub transaction_type: TransactionType,
+    pub client_id: ClientId,
+    pub txn_id: u32,
+    /// For chargebacks, the amount of the transaction charged back.
+    pub amount: f64,
+    pub timestamp: Timestamp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A transaction that is part of a suspicious pattern.
+pub struct Flagged {
+    pub pattern: Pattern,
+    pub activity: Activity,
+}
+
+/// Runs `input` through the engine and returns the transactions it
+/// applied that are part of any of `patterns`, by client and time. A
+/// transaction part of several patterns is returned once for each.
+pub fn sar_extract(input: String, patterns: &Patterns) -> Result<Vec<Flagged>> {
+    let mut reader = open_file_read_csv(input)?;
+    let schema = Schema::from_headers(reader.headers()?, &[])?;
+    let engine = Engine::default();
+    let mut clients: BTreeMap<ClientId, Vec<Activity>> = BTreeMap::new();
+    for record in reader.records() {
+        let mut txn = schema.parse(&record?)?;
+        let timestamp = *txn.timestamp.get_or_insert_with(|| engine.clock.now());
+        let (transaction_type, client_id, txn_id) =
+            (txn.transaction_type, txn.client_id, txn.txn_id);
+        if engine.process(txn)? != TransactionOutcome::Applied {
+            continue;
+        }
+        let amount = engine
+            .db
+            .transactions
+            .get(&txn_id)
+            .and_then(|txn| txn.amount)
+            .unwrap_or_default();
+        clients.entry(client_id).or_default().push(Activity {
+            transaction_type,
+            client_id,
+            txn_id,
+            amount,
+            timestamp,
+        });
+    }
+    let mut flagged = Vec::new();
+    for activity in clients.values() {
+        let mut hits = BTreeSet::new();
+        if let Some(structuring) = &patterns.structuring {
+            let floor = structuring.threshold * (1.0 - structuring.margin);
+            let deposits = positions(activity, |a| {
+                a.transaction_type == TransactionType::Deposit
+                    && a.amount >= floor
+                    && a.amount < structuring.threshold
+            });
+            for i in clusters(activity, &deposits, structuring.count, structuring.window) {
+                hits.insert((i, Pattern::Structuring));
+            }
+        }
+        if let Some(rapid) = &patterns.rapid_in_out {
+            let window = rapid.window.as_secs();
+            for (i, deposit) in activity.iter().enumerate() {
+                if deposit.transaction_type != TransactionType::Deposit {
+                    continue;
+                }
+                for (j, withdrawal) in activity.iter().enumerate().skip(i + 1) {
+                    if withdrawal.timestamp.saturating_sub(deposit.timestamp) > window {
+                        break;
+                    }
+                    if withdrawal.transaction_type == TransactionType::Withdrawal
+                        && withdrawal.amount >= deposit.amount * rapid.fraction
+                    {
+                        hits.insert((i, Pattern::RapidInOut));
+                        hits.insert((j, Pattern::RapidInOut));
+                    }
+                }
+            }
+        }
+        if let Some(cluster) = &patterns.chargeback_cluster {
+            let chargebacks = positions(activity, |a| {
+                a.transaction_type == TransactionType::ChargeBack
+            });
+            for i in clusters(activity, &chargebacks, cluster.count, cluster.window) {
+                hits.insert((i, Pattern::ChargebackCluster));
+            }
+        }
+        flagged.extend(hits.into_iter().map(|(i, pattern)| Flagged {
+            pattern,
+            activity: activity[i].clone(),
+        }));
+    }
+    Ok(flagged)
+}
+
+fn positions(activity: &[Activity], matches: impl Fn(&Activity) -> bool) -> Vec<usize> {
+    (0..activity.len())
+        .filter(|&i| matches(&activity[i]))
+        .collect()
+}
+
+/// The positions of `candidates` that fall in a run of at least `count`
+/// of them within `window`.
+fn clusters<'a>(
+    activity: &'a [Activity],
+    candidates: &'a [usize],
+    count: usize,
+    window: Duration,
+) -> impl Iterator<Item = usize> + 'a {
+    let count = count.max(1);
+    candidates
+        .windows(count)
+        .filter(move |run| {
+            let (first, last) = (&activity[run[0]], &activity[run[count - 1]]);
+            last.timestamp.saturating_sub(first.timestamp) <= window.as_secs()
+        })
+        .flatten()
+        .copied()
+}
+
+/// Writes the flagged transactions in the fixed layout filed with the
+/// regulator, one 80 character record per line:
+///
+/// | columns | field                                  |
+/// |---------|----------------------------------------|
+/// | 1-20    | pattern, left aligned                  |
+/// | 21-25   | client, zero padded                    |
+/// | 26-35   | transaction id, zero padded            |
+/// | 36-45   | transaction type, left aligned         |
+/// | 46-60   | amount with 4 decimals, right aligned  |
+/// | 61-80   | unix timestamp, zero padded            |
+pub fn write_sar(flagged: &[Flagged], mut out: impl Write) -> Result<()> {
+    let error = |x: std::io::Error| format!("error writing extract: {}", x);
+    for Flagged { pattern, activity } in flagged {
+        writeln!(
+            out,
+            "{:<20}{:05}{:010}{:<10}{:>15.4}{:020}",
+            pattern.as_str(),
+            activity.client_id,
+            activity.txn_id,
+            activity.transaction_type.as_str(),
+            activity.amount,
+            activity.timestamp
+        )
+        .map_err(error)?;
+    }
+    out.flush().map_err(error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_each_pattern() -> Result<()> {
+        let path = crate::temp_path("payments-engine-sar-test.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount,timestamp\n\
+             deposit,1,1,9500,0\n\
+             deposit,1,2,9800,3600\n\
+             deposit,1,3,9900,7200\n\
+             deposit,2,4,500,0\n\
+             withdrawal,2,5,480,600\n\
+             deposit,3,6,500,0\n\
+             withdrawal,3,7,480,7200\n\
+             deposit,4,8,10,0\n\
+             deposit,4,9,10,0\n\
+             dispute,4,8,,10\n\
+             chargeback,4,8,,20\n",
+        )
+        .map_err(|x| x.to_string())?;
+        let flagged = sar_extract(path.to_string_lossy().to_string(), &Patterns::default())?;
+        let found: Vec<_> = flagged
+            .iter()
+            .map(|f| (f.pattern, f.activity.txn_id))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (Pattern::Structuring, 1),
+                (Pattern::Structuring, 2),
+                (Pattern::Structuring, 3),
+                (Pattern::RapidInOut, 4),
+                (Pattern::RapidInOut, 5),
+            ]
+        );
+
+        let chargebacks = Patterns {
+            structuring: None,
+            rapid_in_out: None,
+            chargeback_cluster: Some(ChargebackCluster {
+                count: 1,
+                ..ChargebackCluster::default()
+            }),
+        };
+        let flagged_chargebacks = sar_extract(path.to_string_lossy().to_string(), &chargebacks)?;
+        assert_eq!(flagged_chargebacks.len(), 1);
+        assert_eq!(flagged_chargebacks[0].activity.amount, 10.0);
+
+        let mut out = Vec::new();
+        write_sar(&flagged[..1], &mut out)?;
+        let line = String::from_utf8_lossy(&out);
+        assert_eq!(line.trim_end_matches('\n').len(), 80);
+        assert!(line.starts_with("STRUCTURING         000010000000001deposit"));
+        Ok(())
+    }
+}
@@ -0,0 +1,498 @@
+use crate::{
+    clock::Timestamp, currency::transaction_currency, precision::Precision, ClientId, Result,
+    Transaction, TransactionType,
+};
+use csv::{ByteRecord, StringRecord};
+use std::{borrow::Cow, io::Read};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// How rows with another number of fields than the header are read.
+pub enum Fields {
+    /// Every row has exactly the fields of the header.
+    Exact,
+    /// Fields left out at the end of a row are empty, as partners leave
+    /// out the amount of a dispute, and empty fields past the header's,
+    /// as trailing commas leave, are ignored.
+    #[default]
+    Trailing,
+    /// Like `trailing`, and fields past the header's are ignored even if
+    /// they aren't empty.
+    Any,
+}
+
+/// A csv reader of transactions. It reads rows of any length, the schema
+/// checks them against the header, see [`Fields`].
+pub fn csv_reader<R: Read>(input: R) -> csv::Reader<R> {
+    csv::ReaderBuilder::new().flexible(true).from_reader(input)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Where each field of a transaction lives in the input, worked out from
+/// the header row.
+///
+/// Columns are found by name rather than position, so partner files
+/// with their columns in another order, or with extra columns we don't
+/// know about, parse just the same. Unknown
+/// columns are ignored unless they are asked to be carried along.
+pub struct Schema {
+    kind: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    to_client: Option<usize>,
+    timestamp: Option<usize>,
+    memo: Option<usize>,
+    category: Option<usize>,
+    currency: Option<usize>,
+    idempotency_key: Option<usize>,
+    /// How amounts are rounded as they are read.
+    precision: Precision,
+    /// Memos longer than this many characters are truncated.
+    memo_max_len: usize,
+    /// Positions of the extra columns carried with each transaction.
+    carried: Vec<usize>,
+    /// The fields of the header.
+    columns: usize,
+    /// How rows with another number of fields are read.
+    fields: Fields,
+}
+
+/// The default for `--memo-max-len`.
+pub const DEFAULT_MEMO_MAX_LEN: usize = 140;
+
+/// Cleans up a free text memo before it is stored: control characters
+/// (including embedded newlines) become spaces, runs of whitespace are
+/// collapsed and the result is cut to at most `max_len` characters.
+pub fn sanitize_memo(memo: &str, max_len: usize) -> Option<Box<str>> {
+    let memo = memo
+        .split(|c: char| c.is_whitespace() || c.is_control())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let memo: String = memo.chars().take(max_len).collect();
+    match memo.trim_end() {
+        "" => None,
+        memo => Some(memo.into()),
+    }
+}
+
+/// Categories longer than this many characters are truncated.
+const CATEGORY_MAX_LEN: usize = 64;
+
+/// Cleans up a category like a memo, lowercased and with its own length limit.
+pub fn sanitize_category(category: &str) -> Option<Box<str>> {
+    sanitize_memo(&category.to_lowercase(), CATEGORY_MAX_LEN)
+}
+
+/// The fields of one csv line, for inputs read a line at a time.
+pub(crate) fn line_record(line: &str) -> Result<StringRecord> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(line.as_bytes());
+    Ok(reader.records().next().transpose()?.unwrap_or_default())
+}
+
+/// Normalizes a header so ` Client` and `client` name the same column.
+fn normalize(header: &str) -> String {
+    header.trim().to_lowercase()
+}
+
+/// The fields of a transaction a column can be mapped to.
+pub const FIELDS: [&str; 10] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "to_client",
+    "timestamp",
+    "memo",
+    "category",
+    "currency",
+    "idempotency_key",
+];
+
+/// Parses a `--map` mapping, `<field>=<column>`, reading a transaction's
+/// field from an input column of another name.
+pub fn parse_mapping(s: &str) -> std::result::Result<(String, String), String> {
+    let (field, column) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <field>=<column>, got {:?}", s))?;
+    let (field, column) = (normalize(field), column.trim());
+    if !FIELDS.contains(&field.as_str()) {
+        return Err(format!(
+            "unknown field {:?}, expected one of {}",
+            field,
+            FIELDS.join(", ")
+        ));
+    }
+    if column.is_empty() {
+        return Err(format!("mapping {:?} has no column", s));
+    }
+    Ok((field, column.to_string()))
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// The input columns fields are read from when they aren't named like the
+/// field, e.g. the `type` from an `operation` column. Fields not mapped
+/// are read from the column of their own name.
+pub struct ColumnMap(pub Vec<(String, String)>);
+
+impl ColumnMap {
+    /// `headers` with each mapped column renamed to its field. A column
+    /// named like a field that is mapped to another column is renamed
+    /// away, so it isn't read instead. Fails if a mapped column is
+    /// missing.
+    pub fn rename(&self, headers: &StringRecord) -> Result<StringRecord> {
+        if self.0.is_empty() {
+            return Ok(headers.clone());
+        }
+        for (field, column) in &self.0 {
+            if !headers.iter().any(|h| normalize(h) == normalize(column)) {
+                return Err(format!(
+                    "input is missing the column {:?}, mapped to {:?}",
+                    column, field
+                )
+                .into());
+            }
+        }
+        Ok(headers
+            .iter()
+            .map(|header| {
+                let mapped = self
+                    .0
+                    .iter()
+                    .find(|(_, column)| normalize(column) == normalize(header));
+                match mapped {
+                    Some((field, _)) => field.as_str(),
+                    None if self.0.iter().any(|(field, _)| *field == normalize(header)) => "",
+                    None => header,
+                }
+            })
+            .collect())
+    }
+}
+
+impl Schema {
+    /// Maps the columns named in `headers`. Every column in `carry` must
+    /// be present and is kept with each transaction.
+    pub fn from_headers(headers: &StringRecord, carry: &[String]) -> Result<Self> {
+        let find = |name: &str| headers.iter().position(|h| normalize(h) == normalize(name));
+        let require = |name: &str| {
+            find(name).ok_or_else(|| {
+                format!(
+                    "input is missing the required column {:?}, map another column to it with --map {}=<column>",
+                    name, name
+                )
+            })
+        };
+        Ok(Schema {
+            kind: require("type")?,
+            client: require("client")?,
+            tx: require("tx")?,
+            amount: find("amount"),
+            to_client: find("to_client").or_else(|| find("to")),
+            timestamp: find("timestamp"),
+            memo: find("memo"),
+            category: find("category").or_else(|| find("merchant")),
+            currency: find("currency"),
+            idempotency_key: find("idempotency_key"),
+            precision: Precision::default(),
+            memo_max_len: DEFAULT_MEMO_MAX_LEN,
+            carried: carry
+                .iter()
+                .map(|name| require(name))
+                .collect::<std::result::Result<_, _>>()?,
+            columns: headers.len(),
+            fields: Fields::default(),
+        })
+    }
+
+    /// Sets the maximum length of a stored memo.
+    pub fn with_memo_max_len(mut self, memo_max_len: usize) -> Self {
+        self.memo_max_len = memo_max_len;
+        self
+    }
+
+    /// Sets how amounts are rounded as they are read.
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Sets how rows with another number of fields than the header are
+    /// read.
+    pub fn with_fields(mut self, fields: Fields) -> Self {
+        self.fields = fields;
+        self
+    }
+
+    /// Parses a single row into a transaction.
+    pub fn parse(&self, record: &StringRecord) -> Result<Transaction> {
+        self.parse_row(record)
+    }
+
+    /// Like [`Schema::parse`], for a row read as bytes, which a reader
+    /// reads into the same record row after row without allocating. Only
+    /// the fields the schema reads are checked to be UTF-8.
+    pub fn parse_bytes(&self, record: &ByteRecord) -> Result<Transaction> {
+        self.parse_row(record)
+    }
+
+    fn parse_row(&self, row: &impl Row) -> Result<Transaction> {
+        let fits = match self.fields {
+            Fields::Exact => row.len() == self.columns,
+            Fields::Trailing => (self.columns..row.len()).all(|i| {
+                row.field(i)
+                    .is_ok_and(|field| field.is_none_or(|x| x.trim().is_empty()))
+            }),
+            Fields::Any => true,
+        };
+        if !fits {
+            return Err(format!(
+                "row has {} fields, but the header has {}",
+                row.len(),
+                self.columns
+            )
+            .into());
+        }
+        let text = |i: Option<usize>| -> Result<Option<&str>> {
+            Ok(match i {
+                Some(i) => row.field(i)?,
+                None => None,
+            })
+        };
+        let field =
+            |i: Option<usize>| -> Result<Option<Cow<'_, str>>> { Ok(text(i)?.and_then(compact)) };
+        let transaction_type: TransactionType = field(Some(self.kind))?
+            .unwrap_or_default()
+            .as_ref()
+            .try_into()?;
+        let currency = transaction_currency(transaction_type, field(self.currency)?.as_deref())?;
+        Ok(Transaction {
+            transaction_type,
+            client_id: field(Some(self.client))?
+                .unwrap_or_default()
+                .parse::<ClientId>()?,
+            txn_id: field(Some(self.tx))?.unwrap_or_default().parse::<u32>()?,
+            amount: field(self.amount)?
+                .map(|x| x.parse::<f64>().map(|amount| self.precision.round(amount)))
+                .transpose()?,
+            to_client: field(self.to_client)?
+                .map(|x| x.parse::<ClientId>())
+                .transpose()?,
+            timestamp: field(self.timestamp)?
+                .map(|x| x.parse::<Timestamp>())
+                .transpose()?,
+            memo: text(self.memo)?.and_then(|memo| sanitize_memo(memo, self.memo_max_len)),
+            category: text(self.category)?.and_then(sanitize_category),
+            currency,
+            idempotency_key: field(self.idempotency_key)?.map(Into::into),
+            extras: self
+                .carried
+                .iter()
+                .map(|i| Ok(text(Some(*i))?.unwrap_or("").trim().to_string()))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// The fields of a row, however it was read.
+trait Row {
+    fn len(&self) -> usize;
+    fn field(&self, i: usize) -> Result<Option<&str>>;
+}
+
+impl Row for StringRecord {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn field(&self, i: usize) -> Result<Option<&str>> {
+        Ok(self.get(i))
+    }
+}
+
+impl Row for ByteRecord {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn field(&self, i: usize) -> Result<Option<&str>> {
+        self.get(i)
+            .map(|field| {
+                std::str::from_utf8(field)
+                    .map_err(|_| format!("field {} is not valid UTF-8", i + 1).into())
+            })
+            .transpose()
+    }
+}
+
+/// `field` without its spaces, or `None` if that leaves nothing. Only
+/// copied if there were spaces inside it, which few rows have.
+fn compact(field: &str) -> Option<Cow<'_, str>> {
+    let field = field.trim_matches(' ');
+    match field {
+        "" => None,
+        _ if field.contains(' ') => Some(Cow::Owned(field.replace(' ', ""))),
+        _ => Some(Cow::Borrowed(field)),
+    }
+}
+
+impl TryFrom<&str> for TransactionType {
+    type Error = crate::PaymentsEngineError;
+    fn try_from(kind: &str) -> Result<Self> {
+        Ok(match kind {
+            "deposit" => TransactionType::Deposit,
+            "withdrawal" => TransactionType::Withdrawal,
+            "dispute" => TransactionType::Dispute,
+            "resolve" => TransactionType::Resolve,
+            "chargeback" => TransactionType::ChargeBack,
+            "transfer" => TransactionType::Transfer,
+            "unlock" => TransactionType::Unlock,
+            "credit_adjustment" => TransactionType::CreditAdjustment,
+            "debit_adjustment" => TransactionType::DebitAdjustment,
+            "close" => TransactionType::Close,
+            _ => {
+                return Err(crate::PaymentsEngineError::InvalidTransactionType(
+                    kind.to_string(),
+                ))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_columns_by_name() -> Result<()> {
+        let headers =
+            StringRecord::from(vec!["memo", " tx", "client", "type", "merchant", "amount"]);
+        let schema = Schema::from_headers(&headers, &["Merchant".to_string()])?;
+        let txn = schema.parse(&StringRecord::from(vec![
+            "rent", "7", " 3", "deposit", "acme", " 1.5",
+        ]))?;
+        assert_eq!(txn.category.as_deref(), Some("acme"));
+        assert_eq!(txn.transaction_type, TransactionType::Deposit);
+        assert_eq!((txn.client_id, txn.txn_id, txn.amount), (3, 7, Some(1.5)));
+        assert_eq!(&*txn.extras, ["acme".to_string()]);
+        assert_eq!(txn.memo.as_deref(), Some("rent"));
+
+        // Read as bytes, the same row gives the same transaction, and a
+        // field that isn't UTF-8 is refused.
+        let bytes = ByteRecord::from(vec!["rent", "7", " 3", "deposit", "acme", "1 .5"]);
+        assert_eq!(schema.parse_bytes(&bytes)?, txn);
+        let mut bytes = ByteRecord::new();
+        for field in [&b"rent"[..], b"7", b"3", b"deposit", b"acme", b"1.\xff"] {
+            bytes.push_field(field);
+        }
+        assert!(schema.parse_bytes(&bytes).is_err());
+
+        // Client ids go well past the 16 bits they once had.
+        let wide = schema.parse(&StringRecord::from(vec![
+            "",
+            "8",
+            "4294967296",
+            "deposit",
+            "",
+            "1",
+        ]))?;
+        assert_eq!(wide.client_id, 1 << 32);
+        Ok(())
+    }
+
+    #[test]
+    fn memos_are_sanitized() {
+        assert_eq!(
+            sanitize_memo("  rent\n for\t\u{7}may ", 140).as_deref(),
+            Some("rent for may")
+        );
+        assert_eq!(
+            sanitize_memo("rent for may", 8).as_deref(),
+            Some("rent for")
+        );
+        assert_eq!(sanitize_memo("été à Paris", 4).as_deref(), Some("été"));
+        assert_eq!(sanitize_memo(" \t ", 140), None);
+    }
+
+    #[test]
+    fn ragged_rows_are_read_as_configured() -> Result<()> {
+        let mut reader = csv_reader(std::fs::File::open("test-files/ragged_rows.csv")?);
+        let schema = Schema::from_headers(reader.headers()?, &[])?;
+        let records: Vec<_> = reader.records().collect::<std::result::Result<_, _>>()?;
+        let parsed = |fields| -> Vec<_> {
+            let schema = schema.clone().with_fields(fields);
+            records
+                .iter()
+                .map(|record| {
+                    schema
+                        .parse(record)
+                        .map(|txn| (txn.txn_id, txn.amount))
+                        .ok()
+                })
+                .collect()
+        };
+        let trailing = vec![
+            Some((1, Some(2.0))),
+            Some((2, Some(1.5))),
+            Some((1, None)),
+            Some((1, None)),
+            None,
+        ];
+        assert_eq!(parsed(Fields::Trailing), trailing);
+        assert_eq!(
+            parsed(Fields::Exact),
+            [Some((1, Some(2.0))), None, None, None, None]
+        );
+        let any = parsed(Fields::Any);
+        assert_eq!(any[..4], trailing[..4]);
+        assert_eq!(any[4], Some((3, Some(4.0))));
+        Ok(())
+    }
+
+    #[test]
+    fn columns_are_read_as_mapped() -> Result<()> {
+        let map = ColumnMap(
+            ["type=operation", "client=Customer_ID", "amount=amt"]
+                .iter()
+                .map(|s| parse_mapping(s))
+                .collect::<std::result::Result<_, _>>()?,
+        );
+        let headers = StringRecord::from(vec!["amt", "tx", "customer_id", "type", "operation"]);
+        let schema = Schema::from_headers(&map.rename(&headers)?, &[])?;
+        let txn = schema.parse(&StringRecord::from(vec![
+            "2.5",
+            "4",
+            "9",
+            "ignored",
+            "withdrawal",
+        ]))?;
+        assert_eq!(txn.transaction_type, TransactionType::Withdrawal);
+        assert_eq!((txn.client_id, txn.txn_id, txn.amount), (9, 4, Some(2.5)));
+
+        let err = map
+            .rename(&StringRecord::from(vec![
+                "amt",
+                "tx",
+                "client",
+                "operation",
+            ]))
+            .expect_err("customer_id is mapped");
+        assert!(err
+            .to_string()
+            .contains("\"Customer_ID\", mapped to \"client\""));
+        assert!(parse_mapping("kind=operation").is_err());
+        assert!(parse_mapping("type").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_required_column_is_an_error() {
+        let headers = StringRecord::from(vec!["type", "tx", "amount"]);
+        let err = Schema::from_headers(&headers, &[]).expect_err("client is required");
+        assert!(err.to_string().contains("\"client\""));
+    }
+}
@@ -0,0 +1,404 @@
+use crate::{
+    clone::{send_state, CLONE_COMMAND},
+    handoff::{hand_over, InFlight},
+    http::{handle_request, is_request_line},
+    schema::Schema,
+    Engine, Result, TransactionOutcome,
+};
+use std::{
+    env,
+    io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::net::UnixListener,
+    },
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The first socket systemd passes to a socket activated service.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// How often an idle listener checks for new connections.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The listening socket systemd passed to this process, if it was socket
+/// activated. Only the first of the sockets is used.
+///
+/// The `LISTEN_*` variables are cleared so processes started from here,
+/// such as plugins, don't take the socket for theirs.
+pub fn activated_listener() -> Result<Option<TcpListener>> {
+    let ours = env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if !ours {
+        return Ok(None);
+    }
+    match fds.as_deref().map(str::parse::<usize>) {
+        Some(Ok(0)) | None => Ok(None),
+        // SAFETY: systemd passes the sockets open, starting at fd 3, to
+        // the process named by LISTEN_PID, which was checked to be this
+        // one, and nothing else here uses the descriptor.
+        Some(Ok(_)) => Ok(Some(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) })),
+        Some(Err(x)) => Err(format!("invalid LISTEN_FDS: {}", x).into()),
+    }
+}
+
+/// Processes the csv transactions read from `input` as they arrive, and
+/// answers each on `output` with one line: `applied`, `rejected,<reason
+/// code>` or `error,<message>` for a row that can't be parsed. The first
+/// line is the header naming the columns, as in an input file.
+///
+/// A connection whose first line is `clone <after>` gets the engine's
+/// state instead, see [`send_state`], and one whose first line is an HTTP
+/// request line is answered over HTTP, see [`handle_request`].
+///
+/// Returns once `input` ends.
+pub fn handle_connection(engine: &Engine, input: impl Read, output: impl Write) -> Result<()> {
+    let mut input = BufReader::new(input);
+    let mut first = String::new();
+    input
+        .read_line(&mut first)
+        .map_err(|x| format!("error reading the request: {}", x))?;
+    if sends_rows(&first) {
+        answer_rows(engine, Cursor::new(first).chain(input), output)?;
+        return Ok(());
+    }
+    if let Some(after) = first.trim_end().strip_prefix(CLONE_COMMAND) {
+        let after = match after.trim() {
+            "-" => None,
+            after => Some(
+                after
+                    .parse()
+                    .map_err(|_| format!("invalid client to clone after: {}", after))?,
+            ),
+        };
+        return send_state(engine, after, output);
+    }
+    handle_request(engine, &first, input, output)
+}
+
+/// Whether a connection whose first line is `first` sends rows, rather
+/// than clone the state or make an HTTP request.
+fn sends_rows(first: &str) -> bool {
+    !first.starts_with(CLONE_COMMAND) && !is_request_line(first)
+}
+
+/// Applies the csv rows of `input`, its header first, answering each on
+/// `output` as [`handle_connection`] describes. Returns the number of rows
+/// answered.
+pub(crate) fn answer_rows(engine: &Engine, input: impl Read, output: impl Write) -> Result<u64> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(input);
+    let schema = Schema::from_headers(reader.headers()?, &[])?;
+    let mut output = BufWriter::new(output);
+    let error = |x: io::Error| format!("error answering: {}", x);
+    let mut answered = 0;
+    for record in reader.records() {
+        let answer = match record
+            .map_err(Into::into)
+            .and_then(|record| schema.parse(&record))
+            .and_then(|txn| engine.process(txn))
+        {
+            Ok(TransactionOutcome::Applied) => "applied".to_string(),
+            Ok(TransactionOutcome::Duplicate) => "duplicate".to_string(),
+            Ok(TransactionOutcome::Rejected(reason)) => format!("rejected,{}", reason.code()),
+            Err(x) => format!("error,{}", x.to_string().replace('\n', " ")),
+        };
+        writeln!(output, "{}", answer).map_err(error)?;
+        output.flush().map_err(error)?;
+        answered += 1;
+    }
+    Ok(answered)
+}
+
+/// Reads a connection, ending it early between two lines once a handoff
+/// is requested.
+struct Interruptible<'a> {
+    stream: &'a TcpStream,
+    requested: &'a AtomicBool,
+    /// Whether the connection may be ended early, only those sending rows
+    /// are.
+    interruptible: bool,
+    /// Whether the last byte read ended a line.
+    at_line_start: bool,
+    interrupted: bool,
+}
+
+impl Read for Interruptible<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Ok(read) => {
+                    if read > 0 {
+                        self.at_line_start = buf[read - 1] == b'\n';
+                    }
+                    return Ok(read);
+                }
+                // The stream times out every poll interval to look at the
+                // handoff.
+                Err(x)
+                    if matches!(
+                        x.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if self.interruptible
+                        && self.at_line_start
+                        && self.requested.load(Ordering::SeqCst)
+                    {
+                        self.interrupted = true;
+                        return Ok(0);
+                    }
+                }
+                Err(x) => return Err(x),
+            }
+        }
+    }
+}
+
+/// Answers `stream` as [`handle_connection`] does, or from where it was
+/// handed over if `resumed`, until it ends or, once a handoff is
+/// `requested`, until it is between two rows. Returns where it is then,
+/// to hand over, or `None` if it ended.
+fn answer_until_handoff(
+    engine: &Engine,
+    stream: &TcpStream,
+    requested: &AtomicBool,
+    resumed: Option<InFlight>,
+) -> Result<Option<InFlight>> {
+    stream
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .map_err(|x| format!("error reading the request: {}", x))?;
+    let mut input = BufReader::new(Interruptible {
+        stream,
+        requested,
+        interruptible: true,
+        at_line_start: true,
+        interrupted: false,
+    });
+    let (header, rows) = match resumed {
+        Some(InFlight {
+            header: Some(header),
+            rows,
+        }) => (header, rows),
+        _ => {
+            let mut first = String::new();
+            input
+                .read_line(&mut first)
+                .map_err(|x| format!("error reading the request: {}", x))?;
+            if input.get_ref().interrupted {
+                let header = None;
+                return Ok(Some(InFlight { header, rows: 0 }));
+            }
+            if !sends_rows(&first) {
+                input.get_mut().interruptible = false;
+                handle_connection(engine, Cursor::new(first).chain(input), stream)?;
+                return Ok(None);
+            }
+            (first, 0)
+        }
+    };
+    let answered = answer_rows(
+        engine,
+        Cursor::new(header.clone()).chain(&mut input),
+        stream,
+    )?;
+    Ok(input.get_ref().interrupted.then(|| InFlight {
+        header: Some(header),
+        rows: rows + answered,
+    }))
+}
+
+#[derive(Debug, Default)]
+/// How [`serve`] hands over to a new process, and what it took over from
+/// an old one, see [`crate::handoff`].
+pub struct Handoff {
+    /// Where a new process connects to take over.
+    pub socket: Option<UnixListener>,
+    /// The connections taken over from the old process, answered from
+    /// where it stopped.
+    pub adopted: Vec<(TcpStream, InFlight)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How [`serve`] ended.
+pub struct Served {
+    /// The connections accepted, not counting those taken over.
+    pub connections: u64,
+    /// Whether a new process took over.
+    pub handed_off: bool,
+}
+
+/// Serves connections on `listener`, each on its own thread, see
+/// [`handle_connection`]. Returns once no connection has been open for
+/// `idle_exit`, or never if unset, or once a new process has taken over.
+///
+/// A new process connecting to the handoff socket is handed over to
+/// without turning anything away: no more connections are accepted, and
+/// once every open one has ended or is between two rows, the state,
+/// those connections and the listener go to the new process, which
+/// accepts the connections that arrived meanwhile. If it doesn't take
+/// over, serving carries on.
+///
+/// Connections are applied concurrently, so each client's transactions
+/// should come in over one connection for their order to be kept.
+pub fn serve(
+    engine: &Engine,
+    listener: TcpListener,
+    idle_exit: Option<Duration>,
+    handoff: Handoff,
+) -> Result<Served> {
+    listener
+        .set_nonblocking(true)
+        .map_err(|x| format!("error listening: {}", x))?;
+    if let Some(socket) = &handoff.socket {
+        socket
+            .set_nonblocking(true)
+            .map_err(|x| format!("error listening for a handoff: {}", x))?;
+    }
+    let open = AtomicUsize::new(0);
+    let idle_since = Mutex::new(Instant::now());
+    let requested = AtomicBool::new(false);
+    let parked = Mutex::new(Vec::new());
+    let mut successor = None;
+    let mut served = 0;
+    thread::scope(|scope| {
+        let spawn = |stream: TcpStream, peer: String, resumed: Option<InFlight>| {
+            open.fetch_add(1, Ordering::SeqCst);
+            let (open, idle_since, requested, parked) = (&open, &idle_since, &requested, &parked);
+            scope.spawn(move || {
+                match answer_until_handoff(engine, &stream, requested, resumed) {
+                    Ok(Some(at)) => match parked.lock() {
+                        Ok(mut parked) => parked.push((stream, at)),
+                        Err(_) => tracing::error!(peer, "parked lock poisoned"),
+                    },
+                    Ok(None) => {}
+                    Err(x) => tracing::warn!(peer, "connection failed: {}", x),
+                }
+                if let Ok(mut idle_since) = idle_since.lock() {
+                    *idle_since = Instant::now();
+                }
+                open.fetch_sub(1, Ordering::SeqCst);
+            });
+        };
+        for (stream, at) in handoff.adopted {
+            let peer = stream
+                .peer_addr()
+                .map_or_else(|_| "a handed over client".to_string(), |x| x.to_string());
+            spawn(stream, peer, Some(at));
+        }
+        loop {
+            if let Some(new) = &successor {
+                if open.load(Ordering::SeqCst) > 0 {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                let mut parked = parked.lock().map_err(|_| "parked lock poisoned")?;
+                match hand_over(engine, new, &listener, &parked) {
+                    Ok(()) => {
+                        return Ok(Served {
+                            connections: served,
+                            handed_off: true,
+                        })
+                    }
+                    Err(x) => tracing::warn!("Carrying on serving, {}", x),
+                }
+                successor = None;
+                requested.store(false, Ordering::SeqCst);
+                for (stream, at) in parked.drain(..) {
+                    let peer = stream
+                        .peer_addr()
+                        .map_or_else(|_| "a parked client".to_string(), |x| x.to_string());
+                    spawn(stream, peer, Some(at));
+                }
+            }
+            if let Some(socket) = &handoff.socket {
+                match socket.accept() {
+                    Ok((new, _)) => {
+                        requested.store(true, Ordering::SeqCst);
+                        successor = Some(new);
+                        continue;
+                    }
+                    Err(x) if x.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(x) => return Err(format!("error accepting a handoff: {}", x).into()),
+                }
+            }
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    stream
+                        .set_nonblocking(false)
+                        .map_err(|x| format!("error accepting {}: {}", peer, x))?;
+                    served += 1;
+                    spawn(stream, peer.to_string(), None);
+                }
+                Err(x) if x.kind() == io::ErrorKind::WouldBlock => {
+                    let idle = open.load(Ordering::SeqCst) == 0
+                        && idle_since
+                            .lock()
+                            .map_err(|_| "idle lock poisoned")?
+                            .elapsed()
+                            >= idle_exit.unwrap_or(Duration::MAX);
+                    if idle {
+                        return Ok(Served {
+                            connections: served,
+                            handed_off: false,
+                        });
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                }
+                Err(x) => return Err(format!("error accepting a connection: {}", x).into()),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpStream,
+    };
+
+    #[test]
+    fn answers_each_row_and_exits_when_idle() -> Result<()> {
+        let engine = Engine::default();
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|x| x.to_string())?;
+        let addr = listener.local_addr().map_err(|x| x.to_string())?;
+        let served = thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                serve(
+                    &engine,
+                    listener,
+                    Some(Duration::from_millis(200)),
+                    Handoff::default(),
+                )
+            });
+            let mut stream = TcpStream::connect(addr).map_err(|x| x.to_string())?;
+            let mut answers = BufReader::new(stream.try_clone().map_err(|x| x.to_string())?);
+            writeln!(stream, "type,client,tx,amount").map_err(|x| x.to_string())?;
+            let mut ask = |row: &str| -> Result<String> {
+                writeln!(stream, "{}", row).map_err(|x| x.to_string())?;
+                let mut answer = String::new();
+                answers.read_line(&mut answer).map_err(|x| x.to_string())?;
+                Ok(answer.trim_end().to_string())
+            };
+            assert_eq!(ask("deposit,1,1,2.0")?, "applied");
+            assert_eq!(ask("withdrawal,1,2,5.0")?, "rejected,insufficient_funds");
+            assert!(ask("deposit,1,3,lots")?.starts_with("error,"));
+            // Closing the connection leaves the server idle.
+            drop((stream, answers));
+            server.join().expect("server panicked")
+        })?;
+        assert_eq!(served.connections, 1);
+        assert_eq!(engine.account(1)?.map(|a| a.available()), Some(2.0));
+        Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+use crate::{generator::Rng, ClientId, Engine, Result, Transaction, TransactionType};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+};
+
+/// Amounts are described by this many evenly spaced quantiles, from the
+/// smallest to the largest.
+const QUANTILES: usize = 11;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The statistical shape of a run's input, without any of its data:
+/// enough to generate fake transactions that look like it.
+pub struct Shape {
+    pub rows: u64,
+    pub clients: u64,
+    /// The fraction of rows of each transaction type, which includes how
+    /// often deposits and withdrawals are disputed and how disputes end.
+    pub mix: BTreeMap<String, f64>,
+    /// Evenly spaced quantiles of deposit amounts, the first the smallest
+    /// and the last the largest.
+    pub deposit_amounts: Vec<f64>,
+    /// Evenly spaced quantiles of withdrawal amounts.
+    pub withdrawal_amounts: Vec<f64>,
+}
+
+impl Shape {
+    /// The shape of what `engine` has processed. Amounts are those of the
+    /// deposits and withdrawals it stored.
+    pub fn of(engine: &Engine) -> Result<Self> {
+        let metrics = engine.metrics()?;
+        let rows: u64 = metrics.processed.iter().map(|(_, count)| count).sum();
+        let mix = metrics
+            .processed
+            .iter()
+            .map(|(transaction_type, count)| {
+                let share = *count as f64 / rows.max(1) as f64;
+                (transaction_type.as_str().to_string(), share)
+            })
+            .collect();
+        let (mut deposits, mut withdrawals) = (Vec::new(), Vec::new());
+        engine
+            .db
+            .for_each_transaction(|txn| match (txn.transaction_type, txn.amount) {
+                (TransactionType::Deposit, Some(amount)) => deposits.push(amount),
+                (TransactionType::Withdrawal, Some(amount)) => withdrawals.push(amount),
+                _ => {}
+            })?;
+        let mut clients = 0;
+        engine.db.for_each_client(|_, _| clients += 1)?;
+        Ok(Shape {
+            rows,
+            clients,
+            mix,
+            deposit_amounts: quantiles(deposits),
+            withdrawal_amounts: quantiles(withdrawals),
+        })
+    }
+
+    /// Reads a shape written by [`Shape::save`].
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).map_err(|x| format!("error opening {}: {}", path, x))?;
+        Ok(serde_json::from_reader(BufReader::new(file))
+            .map_err(|x| format!("{} is not a valid profile: {}", path, x))?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let error = |x: std::io::Error| format!("error writing {}: {}", path, x);
+        let mut out = BufWriter::new(File::create(path).map_err(error)?);
+        serde_json::to_writer_pretty(&mut out, self).map_err(|x| x.to_string())?;
+        writeln!(out).map_err(error)?;
+        out.flush().map_err(error)?;
+        Ok(())
+    }
+
+    /// The share of rows of `transaction_type`.
+    fn share(&self, transaction_type: TransactionType) -> f64 {
+        self.mix
+            .get(transaction_type.as_str())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// `QUANTILES` evenly spaced quantiles of `amounts`, none if it's empty.
+fn quantiles(mut amounts: Vec<f64>) -> Vec<f64> {
+    if amounts.is_empty() {
+        return Vec::new();
+    }
+    amounts.sort_by(f64::total_cmp);
+    let last = amounts.len() - 1;
+    (0..QUANTILES)
+        .map(|i| amounts[(i * last + (QUANTILES - 1) / 2) / (QUANTILES - 1)])
+        .collect()
+}
+
+/// A random amount distributed as the `quantiles` describe, interpolating
+/// between them, with four decimal places.
+fn sample(rng: &mut Rng, quantiles: &[f64]) -> f64 {
+    let amount = match quantiles {
+        [] => 1.0,
+        [only] => *only,
+        _ => {
+            let unit = (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+            let position = unit * (quantiles.len() - 1) as f64;
+            let i = position as usize;
+            let (low, high) = (quantiles[i], quantiles[(i + 1).min(quantiles.len() - 1)]);
+            low + (high - low) * (position - i as f64)
+        }
+    };
+    (amount * 10_000.0).round() / 10_000.0
+}
+
+/// Generates `rows` fake transactions shaped like `shape`: the same mix of
+/// types over as many clients, with amounts drawn from its distributions.
+///
+/// Disputes refer to one of the client's own deposits or withdrawals,
+/// and resolves and chargebacks to one of its open disputes. When a
+/// client has nothing to refer to, a deposit is generated instead.
+pub fn generate(shape: &Shape, rows: u64, seed: u64) -> Vec<Transaction> {
+    let mut rng = Rng::new(seed);
+    let clients = shape.clients.max(1);
+    let mut posted = vec![Vec::new(); clients as usize];
+    let mut disputed = vec![Vec::new(); clients as usize];
+    let shares: Vec<_> = TransactionType::ALL
+        .into_iter()
+        .map(|transaction_type| (transaction_type, shape.share(transaction_type)))
+        .collect();
+    let total: f64 = shares.iter().map(|(_, share)| share).sum();
+    let mut txns = Vec::new();
+    let mut next_txn_id = 1;
+    for _ in 0..rows {
+        let client = 1 + rng.below(clients) as ClientId;
+        let index = client as usize - 1;
+        let mut pick = (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64 * total;
+        let transaction_type = shares
+            .iter()
+            .find(|(_, share)| {
+                pick -= share;
+                pick < 0.0
+            })
+            .map_or(TransactionType::Deposit, |(transaction_type, _)| {
+                *transaction_type
+            });
+        let referenced = match transaction_type {
+            TransactionType::Dispute if !posted[index].is_empty() => {
+                let ids: &mut Vec<u32> = &mut posted[index];
+                let txn_id = ids.swap_remove(rng.below(ids.len() as u64) as usize);
+                disputed[index].push(txn_id);
+                Some(txn_id)
+            }
+            TransactionType::Resolve | TransactionType::ChargeBack
+                if !disputed[index].is_empty() =>
+            {
+                let ids: &mut Vec<u32> = &mut disputed[index];
+                Some(ids.swap_remove(rng.below(ids.len() as u64) as usize))
+            }
+            _ => None,
+        };
+        let txn = match (transaction_type, referenced) {
+            (TransactionType::Dispute, Some(txn_id)) => Transaction::dispute(client, txn_id),
+            (TransactionType::Resolve, Some(txn_id)) => {
+                posted[index].push(txn_id);
+                Transaction::resolve(client, txn_id)
+            }
+            (TransactionType::ChargeBack, Some(txn_id)) => Transaction::chargeback(client, txn_id),
+            (TransactionType::Withdrawal, _) => {
+                let amount = sample(&mut rng, &shape.withdrawal_amounts);
+                posted[index].push(next_txn_id);
+                next_txn_id += 1;
+                Transaction::withdrawal(client, next_txn_id - 1, amount)
+            }
+            _ => {
+                let amount = sample(&mut rng, &shape.deposit_amounts);
+                posted[index].push(next_txn_id);
+                next_txn_id += 1;
+                Transaction::deposit(client, next_txn_id - 1, amount)
+            }
+        };
+        txns.push(txn);
+    }
+    txns
+}
+
+/// Writes `txns` as an input csv to `out`.
+pub fn write_csv(txns: &[Transaction], out: impl Write) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["type", "client", "tx", "amount"])?;
+    for txn in txns {
+        writer.write_record([
+            txn.transaction_type.as_str().to_string(),
+            txn.client_id.to_string(),
+            txn.txn_id.to_string(),
+            txn.amount
+                .map_or(String::new(), |amount| amount.to_string()),
+        ])?;
+    }
+    writer
+        .flush()
+        .map_err(|x| format!("error writing transactions: {}", x))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{open_file_read_csv, run_engine};
+
+    #[test]
+    fn generated_data_has_the_profiled_shape() -> Result<()> {
+        let engine = Engine::default();
+        run_engine(
+            open_file_read_csv("test-files/long_transaction_history.csv".to_string())?,
+            &engine,
+        )?;
+        let profiled = Shape::of(&engine)?;
+        assert_eq!(profiled.rows, 18);
+        assert_eq!(profiled.deposit_amounts.len(), QUANTILES);
+
+        let shape = Shape {
+            clients: 50,
+            mix: [
+                ("deposit", 0.6),
+                ("withdrawal", 0.2),
+                ("dispute", 0.12),
+                ("resolve", 0.06),
+                ("chargeback", 0.02),
+            ]
+            .map(|(name, share)| (name.to_string(), share))
+            .into(),
+            ..profiled
+        };
+        let generated = Engine::default();
+        for txn in generate(&shape, 20_000, 7) {
+            generated.process(txn)?;
+        }
+        let regenerated = Shape::of(&generated)?;
+        assert_eq!(regenerated.rows, 20_000);
+        assert!(regenerated.clients <= shape.clients);
+        for (transaction_type, share) in &shape.mix {
+            let generated_share = regenerated.mix[transaction_type];
+            assert!(
+                (generated_share - share).abs() < 0.02,
+                "{} {} vs {}",
+                transaction_type,
+                generated_share,
+                share
+            );
+        }
+        let (low, high) = (
+            shape.deposit_amounts[0],
+            shape.deposit_amounts[QUANTILES - 1],
+        );
+        assert!(regenerated
+            .deposit_amounts
+            .iter()
+            .all(|amount| (low..=high).contains(amount)));
+        assert_eq!(generate(&shape, 100, 7), generate(&shape, 100, 7));
+        Ok(())
+    }
+}
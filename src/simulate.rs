@@ -0,0 +1,140 @@
+use crate::{
+    clock::{Clock, SimulatedClock, Timestamp},
+    generator::Generator,
+    Client, ClientId, Engine, Result,
+};
+
+/// The time every simulation starts at, so runs with the same seed
+/// produce identical timestamps.
+const SIMULATION_EPOCH: Timestamp = 1_600_000_000;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+/// Tolerance for floating point drift when comparing balances.
+const EPSILON: f64 = 1e-9;
+
+#[derive(Debug, Clone, PartialEq)]
+/// The knobs of a simulation run.
+pub struct SimulationConfig {
+    pub seed: u64,
+    pub days: u32,
+    pub clients: ClientId,
+    pub transactions_per_day: u32,
+}
+
+#[derive(Debug, PartialEq)]
+/// What a successful simulation did.
+pub struct SimulationReport {
+    pub transactions: u64,
+    pub clients: usize,
+    pub locked: usize,
+}
+
+/// Balances of a client before a transaction was applied.
+pub struct Before {
+    available: f64,
+    held: f64,
+    locked: bool,
+}
+
+impl Before {
+    pub fn of(client: &Client) -> Self {
+        Before {
+            available: client.available,
+            held: client.held,
+            locked: client.locked,
+        }
+    }
+}
+
+/// Checks the invariants that must hold for a client after every
+/// transaction, beyond those of [`crate::Database::verify_client`] the
+/// engine checks itself.
+pub fn check_invariants(
+    before: Option<&Before>,
+    client: &Client,
+) -> std::result::Result<(), String> {
+    if !client.available.is_finite() || !client.held.is_finite() {
+        return Err(format!(
+            "balances are not finite (available {}, held {})",
+            client.available, client.held
+        ));
+    }
+    if client.held < -EPSILON {
+        return Err(format!("held balance is negative ({})", client.held));
+    }
+    if let Some(before) = before.filter(|before| before.locked) {
+        if !client.locked || before.available != client.available || before.held != client.held {
+            return Err("a locked account was modified".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Runs a long randomized scenario through the engine on a simulated clock,
+/// checking the invariants after every single transaction.
+///
+/// The first violation aborts the run with the step that caused it, which
+/// together with the seed is enough to reproduce the failure.
+pub fn run_simulation(config: &SimulationConfig) -> Result<SimulationReport> {
+    let clock = SimulatedClock::new(SIMULATION_EPOCH);
+    let engine = Engine::with_clock(clock.clone()).with_invariant_checks();
+    let mut generator = Generator::new(config.seed, config.clients);
+    let per_day = config.transactions_per_day.max(1);
+    let step = SECONDS_PER_DAY / per_day as u64;
+    let mut transactions = 0;
+
+    for day in 0..config.days {
+        for _ in 0..per_day {
+            let txn = generator.next_transaction();
+            let description = format!("{:?}", txn);
+            let client_id = txn.client_id;
+            let before = engine
+                .db
+                .clients
+                .get(&client_id)
+                .map(|client| Before::of(&client));
+
+            engine.process(txn)?;
+            transactions += 1;
+
+            if let Some(client) = engine.db.clients.get(&client_id) {
+                check_invariants(before.as_ref(), &client).map_err(|violation| {
+                    format!(
+                        "invariant violated at step {} (day {}, t={}, seed {}): {} after {}",
+                        transactions,
+                        day,
+                        clock.now(),
+                        config.seed,
+                        violation,
+                        description
+                    )
+                })?;
+            }
+            clock.advance(step);
+        }
+    }
+
+    Ok(SimulationReport {
+        transactions,
+        clients: engine.db.clients.len(),
+        locked: engine.db.clients.iter().filter(|c| c.locked).count(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulation_holds_invariants() -> Result<()> {
+        let config = SimulationConfig {
+            seed: 42,
+            days: 30,
+            clients: 20,
+            transactions_per_day: 200,
+        };
+        let report = run_simulation(&config)?;
+        assert_eq!(report.transactions, 6_000);
+        assert_eq!(run_simulation(&config)?, report);
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use crate::{
+    account::AccountView,
+    format::Format,
+    output::{open_output, ReportWriter},
+    Database, Result,
+};
+use std::{
+    io::Write,
+    process::{Child, Command, Stdio},
+};
+
+/// Where the accounts of a report go, see [`crate::output::write_to_sink`].
+/// [`ReportWriter`] writes them as csv or JSON Lines; embedders implement
+/// this to send them anywhere else, such as a database table.
+pub trait Sink {
+    /// Takes the next account of the report.
+    fn write(&mut self, account: &AccountView) -> Result<()>;
+    /// Told of an account left out of the report, such as a dormant one,
+    /// whose funds the ledger still holds.
+    fn leave_out(&mut self, _account: &AccountView) {}
+    /// Ends the report once every account was written.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Writes the same report to several sinks at once, so one run can leave
+/// it in several places and formats.
+pub struct Sinks<'a>(pub Vec<Box<dyn Sink + 'a>>);
+
+impl Sink for Sinks<'_> {
+    fn write(&mut self, account: &AccountView) -> Result<()> {
+        self.0.iter_mut().try_for_each(|sink| sink.write(account))
+    }
+
+    fn leave_out(&mut self, account: &AccountView) {
+        self.0.iter_mut().for_each(|sink| sink.leave_out(account));
+    }
+
+    /// Finishes every sink, even after one fails, and returns the first
+    /// error.
+    fn finish(self: Box<Self>) -> Result<()> {
+        let mut first = Ok(());
+        for sink in self.0 {
+            let finished = sink.finish();
+            if first.is_ok() {
+                first = finished;
+            }
+        }
+        first
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Where a `--sink` writes to.
+pub enum SinkTarget {
+    Stdout,
+    File(String),
+    /// A command run as `sh -c '<command>'`, fed the report on its
+    /// standard input, e.g. a client uploading it to object storage or
+    /// producing it to a topic.
+    Command(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A report destination given on the command line.
+pub struct SinkSpec {
+    pub format: Format,
+    pub target: SinkTarget,
+}
+
+/// Parses a `--sink`, `<format>:<target>`: the format is `csv` or `json`,
+/// and the target a file, `-` for stdout, or `|` followed by a command to
+/// pipe the report to.
+pub fn parse_sink(s: &str) -> std::result::Result<SinkSpec, String> {
+    let (format, target) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected <format>:<target>, got {:?}", s))?;
+    let format = match format.trim() {
+        "csv" => Format::Csv,
+        "json" => Format::Json,
+        other => {
+            return Err(format!(
+                "unknown sink format {:?}, expected csv or json",
+                other
+            ))
+        }
+    };
+    let target = match target.trim() {
+        "" => return Err(format!("sink {:?} has no target", s)),
+        "-" => SinkTarget::Stdout,
+        target => match target.strip_prefix('|') {
+            Some(command) => SinkTarget::Command(command.trim().to_string()),
+            None => SinkTarget::File(target.to_string()),
+        },
+    };
+    Ok(SinkSpec { format, target })
+}
+
+impl SinkSpec {
+    /// Starts a report of the accounts of `db` on this sink, ending with
+    /// their totals if `totals` is set, see [`ReportWriter::with_totals`].
+    pub fn open<'a>(&self, db: &'a Database, totals: bool) -> Result<Box<dyn Sink + 'a>> {
+        let (out, child): (Box<dyn Write>, _) = match &self.target {
+            SinkTarget::Stdout => (open_output(None)?, None),
+            SinkTarget::File(path) => (open_output(Some(path))?, None),
+            SinkTarget::Command(command) => {
+                let mut child = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .map_err(|x| format!("error running sink {}: {}", command, x))?;
+                let stdin = child.stdin.take().ok_or("sink command has no stdin")?;
+                (Box::new(stdin), Some((child, command.clone())))
+            }
+        };
+        let mut report = ReportWriter::new(db, out, self.format)?;
+        if totals {
+            report = report.with_totals();
+        }
+        Ok(match child {
+            Some((child, command)) => Box::new(PipeSink {
+                report,
+                child,
+                command,
+            }),
+            None => Box::new(report),
+        })
+    }
+}
+
+/// A report piped to a command, which must exit successfully once it has
+/// read all of it.
+struct PipeSink<'a> {
+    report: ReportWriter<'a, Box<dyn Write>>,
+    child: Child,
+    command: String,
+}
+
+impl Sink for PipeSink<'_> {
+    fn write(&mut self, account: &AccountView) -> Result<()> {
+        Sink::write(&mut self.report, account)
+    }
+
+    fn leave_out(&mut self, account: &AccountView) {
+        self.report.leave_out(account);
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let PipeSink {
+            report,
+            mut child,
+            command,
+        } = *self;
+        // Finishing the report drops the pipe, which ends the command's
+        // input.
+        let finished = report.finish();
+        let status = child
+            .wait()
+            .map_err(|x| format!("error waiting for sink {}: {}", command, x))?;
+        // A command that failed is why the pipe broke, if it did.
+        match status.success() {
+            true => finished,
+            false => Err(format!("sink {} failed: {}", command, status).into()),
+        }
+    }
+}
+
+impl<W: Write> Sink for ReportWriter<'_, W> {
+    fn write(&mut self, account: &AccountView) -> Result<()> {
+        ReportWriter::write(self, account)
+    }
+
+    fn leave_out(&mut self, account: &AccountView) {
+        ReportWriter::leave_out(self, account);
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        ReportWriter::finish(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{output::write_to_sink, Engine, Transaction};
+
+    #[test]
+    fn one_report_goes_to_every_sink() -> Result<()> {
+        assert_eq!(
+            parse_sink("json:|gzip > r.gz"),
+            Ok(SinkSpec {
+                format: Format::Json,
+                target: SinkTarget::Command("gzip > r.gz".to_string()),
+            })
+        );
+        assert_eq!(
+            parse_sink("csv:-").map(|sink| sink.target),
+            Ok(SinkTarget::Stdout)
+        );
+        assert!(parse_sink("xml:out.xml").is_err());
+        assert!(parse_sink("out.csv").is_err());
+
+        let engine = Engine::default();
+        engine.process(Transaction::deposit(1, 1, 1.5))?;
+        let piped = crate::temp_path("payments-engine-sink-test.csv");
+        let command = format!("csv:|cat > {}", piped.display());
+        let (mut csv, mut json) = (Vec::new(), Vec::new());
+        let sinks: Vec<Box<dyn Sink>> = vec![
+            Box::new(ReportWriter::new(&engine.db, &mut csv, Format::Csv)?),
+            Box::new(ReportWriter::new(&engine.db, &mut json, Format::Json)?.with_totals()),
+            parse_sink(&command)?.open(&engine.db, false)?,
+        ];
+        write_to_sink(&engine.db, Box::new(Sinks(sinks)), false)?;
+        let csv = String::from_utf8_lossy(&csv).to_string();
+        assert_eq!(
+            csv,
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+        assert_eq!(String::from_utf8_lossy(&json).lines().count(), 2);
+        assert_eq!(std::fs::read_to_string(&piped)?, csv);
+        std::fs::remove_file(&piped)?;
+
+        let failing = parse_sink("csv:|exit 3")?.open(&engine.db, false)?;
+        assert!(write_to_sink(&engine.db, failing, false).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+use crate::{
+    append::replace_state,
+    outbox::Event,
+    state::{
+        export_state, import_into, read_json, AccountState, StateFile, TransactionState,
+        STATE_VERSION,
+    },
+    Client, ClientId, Database, Result, Transaction,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+#[derive(Debug, Clone, PartialEq)]
+/// Where and how often a run snapshots its state in the background.
+pub struct SnapshotOptions {
+    /// Take a snapshot after every this many rows.
+    pub every: u64,
+    /// The directory snapshots are written to, as
+    /// `snapshot-<rows>.json`.
+    pub dir: PathBuf,
+    /// Compress the snapshots with zstd at this level.
+    pub compression: Option<u32>,
+}
+
+#[derive(Debug)]
+/// What changed since the boundary the snapshot being taken is at.
+struct Epoch {
+    /// The rows handled before the boundary.
+    rows: u64,
+    /// The pending events at the boundary.
+    outbox: Vec<Event>,
+    /// Clients and stored transactions as they were at the boundary, kept
+    /// the first time each one is changed after it. `None` for those that
+    /// didn't exist yet.
+    clients: HashMap<ClientId, Option<Client>>,
+    transactions: HashMap<u32, Option<Transaction>>,
+    /// The transfers applied after the boundary.
+    transfers: Vec<u32>,
+}
+
+#[derive(Debug, Default)]
+/// Copy-on-write bookkeeping for snapshots taken in the background.
+///
+/// Starting a snapshot at a boundary between two transactions copies
+/// nothing. Instead, the first time a client or stored transaction is
+/// changed after the boundary, the value it had before is kept. The
+/// thread taking the snapshot reads the live maps and puts the kept
+/// values back over whatever changed meanwhile, which gives the state at
+/// the boundary while transactions keep being applied.
+pub struct Snapshots {
+    /// Whether a snapshot is being taken, checked before locking `epoch`
+    /// so transactions pay for the bookkeeping only while one is.
+    active: AtomicBool,
+    epoch: Mutex<Option<Epoch>>,
+}
+
+impl Snapshots {
+    /// Whether a snapshot is being taken.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Keeps the value of a client about to be changed, if a snapshot is
+    /// being taken and it wasn't kept yet. Must be called while the
+    /// client's entry is locked.
+    pub fn client(&self, client_id: ClientId, before: Option<&Client>) {
+        if !self.is_active() {
+            return;
+        }
+        if let Ok(mut epoch) = self.epoch.lock() {
+            if let Some(epoch) = epoch.as_mut() {
+                epoch
+                    .clients
+                    .entry(client_id)
+                    .or_insert_with(|| before.cloned());
+            }
+        }
+    }
+
+    /// Like [`Snapshots::client`], for a stored transaction about to be
+    /// inserted or replaced.
+    pub fn transaction(&self, txn_id: u32, before: Option<&Transaction>) {
+        if !self.is_active() {
+            return;
+        }
+        if let Ok(mut epoch) = self.epoch.lock() {
+            if let Some(epoch) = epoch.as_mut() {
+                epoch
+                    .transactions
+                    .entry(txn_id)
+                    .or_insert_with(|| before.cloned());
+            }
+        }
+    }
+
+    /// Notes a transfer applied, if a snapshot is being taken, so it is
+    /// left out of it.
+    pub fn transfer(&self, txn_id: u32) {
+        if !self.is_active() {
+            return;
+        }
+        if let Ok(mut epoch) = self.epoch.lock() {
+            if let Some(epoch) = epoch.as_mut() {
+                epoch.transfers.push(txn_id);
+            }
+        }
+    }
+}
+
+/// Starts a snapshot of `db` at the boundary after `rows` rows. Returns
+/// false, starting nothing, while the previous snapshot is still being
+/// taken. Must be called between transactions by the thread applying them.
+///
+/// Evicted clients are not part of the snapshot.
+pub fn begin(db: &Database, rows: u64) -> Result<bool> {
+    let snapshots = &db.snapshots;
+    if snapshots.is_active() {
+        return Ok(false);
+    }
+    let epoch = Epoch {
+        rows,
+        outbox: db.outbox.pending()?,
+        clients: HashMap::new(),
+        transactions: HashMap::new(),
+        transfers: Vec::new(),
+    };
+    *snapshots
+        .epoch
+        .lock()
+        .map_err(|_| "snapshot lock poisoned")? = Some(epoch);
+    snapshots.active.store(true, Ordering::SeqCst);
+    Ok(true)
+}
+
+/// Takes the snapshot started by [`begin`], returning the state at its
+/// boundary and the rows handled before it. Meant to run on its own
+/// thread while transactions keep being applied.
+///
+/// Each map is read one shard at a time, so a transaction only ever
+/// waits for the shard it needs to be read.
+pub fn take(db: &Database) -> Result<(u64, StateFile)> {
+    let mut accounts: BTreeMap<_, _> = db
+        .clients
+        .iter()
+        .map(|entry| (*entry.key(), AccountState::of(*entry.key(), entry.value())))
+        .collect();
+    let mut transactions: BTreeMap<_, _> = db
+        .transactions
+        .iter()
+        .map(|entry| (*entry.key(), TransactionState::from(entry.value())))
+        .collect();
+    let mut transfers: BTreeSet<_> = db.transfers.iter().map(|txn_id| *txn_id).collect();
+    // Whatever changed while the maps were read was kept, and ending the
+    // epoch only after reading them means nothing changed unnoticed.
+    let epoch = {
+        let mut epoch = db
+            .snapshots
+            .epoch
+            .lock()
+            .map_err(|_| "snapshot lock poisoned")?;
+        db.snapshots.active.store(false, Ordering::SeqCst);
+        epoch.take().ok_or("no snapshot was started")?
+    };
+    for (client_id, before) in epoch.clients {
+        match before {
+            Some(client) => accounts.insert(client_id, AccountState::of(client_id, &client)),
+            None => accounts.remove(&client_id),
+        };
+    }
+    for (txn_id, before) in epoch.transactions {
+        match before {
+            Some(txn) => transactions.insert(txn_id, TransactionState::from(&txn)),
+            None => transactions.remove(&txn_id),
+        };
+    }
+    for txn_id in epoch.transfers {
+        transfers.remove(&txn_id);
+    }
+    let state = StateFile {
+        version: STATE_VERSION,
+        generation: 0,
+        rows: 0,
+        accounts: accounts.into_values().collect(),
+        transactions: transactions.into_values().collect(),
+        transfers: transfers.into_iter().collect(),
+        outbox: epoch.outbox,
+    };
+    Ok((epoch.rows, state))
+}
+
+/// Takes the snapshot started by [`begin`] and writes it to the
+/// directory of `options`.
+pub fn write(db: &Database, options: &SnapshotOptions) -> Result<()> {
+    let (rows, mut state) = take(db)?;
+    state.rows = rows;
+    std::fs::create_dir_all(&options.dir)
+        .map_err(|x| format!("error creating {}: {}", options.dir.display(), x))?;
+    let path = options.dir.join(format!("snapshot-{}.json", rows));
+    replace_state(&state, &path, options.compression)
+}
+
+impl Database {
+    /// Writes the state to `path` at once, as a snapshot taken after
+    /// `rows` rows of the input. Nothing may be applied meanwhile, unlike
+    /// with [`begin`] and [`take`].
+    pub fn snapshot(&self, path: &Path, rows: u64) -> Result<()> {
+        let state = StateFile {
+            rows,
+            ..export_state(self)?
+        };
+        replace_state(&state, path, None)
+    }
+
+    /// Loads the snapshot at `path`, compressed or not, into this empty
+    /// database, returning the rows of the input handled before it.
+    pub fn restore(&self, path: &Path) -> Result<u64> {
+        let state: StateFile = read_json(path)?;
+        let rows = state.rows;
+        import_into(self, state)?;
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, generator::Generator, Engine, RunOptions};
+
+    #[test]
+    fn snapshot_is_the_state_at_its_boundary() -> Result<()> {
+        let engine = Engine::with_clock(SimulatedClock::new(0));
+        engine.process(Transaction::deposit(1, 1, 5.0))?;
+        engine.process(Transaction::deposit(2, 2, 3.0))?;
+        let expected = export_state(&engine.db)?;
+        assert!(begin(&engine.db, 2)?);
+        assert!(!begin(&engine.db, 2)?);
+        // Changes after the boundary, to existing and new entries.
+        engine.process(Transaction::withdrawal(1, 3, 1.0))?;
+        engine.process(Transaction::dispute(2, 2))?;
+        engine.process(Transaction::deposit(3, 4, 1.0))?;
+        let (rows, state) = take(&engine.db)?;
+        assert_eq!(rows, 2);
+        assert_eq!(state, expected);
+        // Once taken, changes are no longer kept.
+        engine.process(Transaction::deposit(3, 5, 1.0))?;
+        assert!(engine.db.snapshots.epoch.lock().unwrap().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_taken_while_transactions_are_applied() -> Result<()> {
+        let txns: Vec<_> = {
+            let mut generator = Generator::new(7, 50);
+            (0..20_000).map(|_| generator.next_transaction()).collect()
+        };
+        let (head, tail) = txns.split_at(5_000);
+        let reference = Engine::with_clock(SimulatedClock::new(0));
+        let engine = Engine::with_clock(SimulatedClock::new(0));
+        for txn in head {
+            reference.process(txn.clone())?;
+            engine.process(txn.clone())?;
+        }
+        assert!(begin(&engine.db, head.len() as u64)?);
+        let (_, state) = std::thread::scope(|scope| {
+            let snapshot = scope.spawn(|| take(&engine.db));
+            for txn in tail {
+                engine.process(txn.clone())?;
+            }
+            snapshot.join().expect("snapshot thread panicked")
+        })?;
+        assert_eq!(state, export_state(&reference.db)?);
+        Ok(())
+    }
+
+    #[test]
+    fn an_interrupted_run_resumes_from_its_snapshot() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-resume-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let txns: Vec<_> = {
+            let mut generator = Generator::new(11, 30);
+            (0..3_000).map(|_| generator.next_transaction()).collect()
+        };
+        let rows = || txns.iter().cloned().map(Ok);
+        let reference = Engine::with_clock(SimulatedClock::new(0));
+        let options = RunOptions {
+            snapshots: Some(SnapshotOptions {
+                every: 1_000,
+                dir: dir.clone(),
+                compression: None,
+            }),
+            ..RunOptions::default()
+        };
+        crate::run_transactions(rows(), &reference, &options)?;
+
+        // A boundary reached while the previous snapshot is still being
+        // written is skipped, so the latest one may be the first.
+        let boundary = [2_000, 1_000]
+            .into_iter()
+            .find(|rows| dir.join(format!("snapshot-{}.json", rows)).exists())
+            .ok_or("no snapshot was taken")?;
+        let resumed = Engine::with_clock(SimulatedClock::new(0));
+        let resume_after = resumed
+            .db
+            .restore(&dir.join(format!("snapshot-{}.json", boundary)))?;
+        assert_eq!(resume_after, boundary);
+        let options = RunOptions {
+            resume_after,
+            ..RunOptions::default()
+        };
+        let summary = crate::run_transactions(rows(), &resumed, &options)?;
+        assert_eq!(summary.rows, 3_000);
+        assert_eq!(summary.processed, 3_000 - boundary);
+        assert_eq!(export_state(&resumed.db)?, export_state(&reference.db)?);
+        assert_eq!(resumed.db.digest(), reference.db.digest());
+
+        let path = dir.join("checkpoint.json");
+        reference.db.snapshot(&path, 3_000)?;
+        let restored = Engine::default();
+        assert_eq!(restored.db.restore(&path)?, 3_000);
+        assert_eq!(export_state(&restored.db)?, export_state(&reference.db)?);
+        std::fs::remove_dir_all(&dir).map_err(|x| x.to_string())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use crate::{
+    generator::Generator,
+    simulate::{check_invariants, Before},
+    ClientId, Engine, Result,
+};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often the soak catches up with its target rate.
+const TICK: Duration = Duration::from_millis(10);
+
+/// Parses a rate such as `50k/s`, `2m/s` or `1000`, in transactions per
+/// second.
+pub fn parse_rate(s: &str) -> std::result::Result<u64, String> {
+    let number = s.trim().trim_end_matches("/s");
+    let (number, scale) = match number.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&number[..i], 1_000.0),
+        Some((i, 'm' | 'M')) => (&number[..i], 1_000_000.0),
+        _ => (number, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(rate) if rate * scale >= 1.0 => Ok((rate * scale) as u64),
+        _ => Err(format!("invalid rate {:?}, expected e.g. 50k/s", s)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The knobs of a soak run.
+pub struct SoakConfig {
+    pub seed: u64,
+    pub clients: ClientId,
+    /// Transactions per second to drive through the engine.
+    pub rate: u64,
+    pub duration: Duration,
+    /// How often progress is reported.
+    pub interval: Duration,
+    /// Fails the run once an interval's p99 latency is above this.
+    pub max_p99: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Latencies and size of the engine over one interval of a soak run.
+pub struct IntervalStats {
+    /// Time since the soak started.
+    pub elapsed: Duration,
+    pub transactions: u64,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    /// See [`crate::metrics::Metrics::estimated_memory_bytes`].
+    pub memory_bytes: usize,
+}
+
+/// Latencies sorted in place, at percentile `p`.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    match sorted.len() {
+        0 => Duration::ZERO,
+        n => sorted[((n - 1) as f64 * p).round() as usize],
+    }
+}
+
+/// Drives generated transactions through `engine` at `config.rate` for
+/// `config.duration`, timing every one and checking the engine's
+/// invariants after it. Each interval's stats are handed to `report`.
+///
+/// Fails on the first broken invariant, or the first interval whose p99
+/// latency is above `config.max_p99`. When the engine can't keep up with
+/// the rate, it runs as fast as it can.
+pub fn run_soak(
+    config: &SoakConfig,
+    engine: &Engine,
+    mut report: impl FnMut(&IntervalStats),
+) -> Result<Vec<IntervalStats>> {
+    let mut generator = Generator::new(config.seed, config.clients);
+    let start = Instant::now();
+    let mut intervals = Vec::new();
+    let mut latencies = Vec::new();
+    let mut next_report = config.interval;
+    let mut transactions = 0u64;
+
+    loop {
+        let due = start.elapsed().min(config.duration).as_secs_f64() * config.rate as f64;
+        let due = due as u64;
+        while transactions < due {
+            let txn = generator.next_transaction();
+            let description = format!("{:?}", txn);
+            let client_id = txn.client_id;
+            let before = engine
+                .db
+                .clients
+                .get(&client_id)
+                .map(|client| Before::of(&client));
+            let started = Instant::now();
+            engine.process(txn)?;
+            latencies.push(started.elapsed());
+            transactions += 1;
+            if let Some(client) = engine.db.clients.get(&client_id) {
+                check_invariants(before.as_ref(), &client).map_err(|violation| {
+                    format!(
+                        "invariant violated after {} transactions (seed {}): {} after {}",
+                        transactions, config.seed, violation, description
+                    )
+                })?;
+            }
+        }
+
+        let elapsed = start.elapsed();
+        let done = elapsed >= config.duration;
+        if done || elapsed >= next_report {
+            next_report += config.interval;
+            engine.db.evict_inactive()?;
+            latencies.sort();
+            let stats = IntervalStats {
+                elapsed,
+                transactions: latencies.len() as u64,
+                p50: percentile(&latencies, 0.5),
+                p99: percentile(&latencies, 0.99),
+                max: latencies.last().copied().unwrap_or_default(),
+                memory_bytes: engine.metrics()?.estimated_memory_bytes,
+            };
+            latencies.clear();
+            report(&stats);
+            if let Some(max_p99) = config.max_p99.filter(|max| stats.p99 > *max) {
+                return Err(format!(
+                    "p99 latency of {:?} after {:?} is over the limit of {:?}",
+                    stats.p99, elapsed, max_p99
+                )
+                .into());
+            }
+            intervals.push(stats);
+        }
+        if done {
+            return Ok(intervals);
+        }
+        thread::sleep(TICK.min(config.duration.saturating_sub(elapsed)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimulatedClock;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("50k/s"), Ok(50_000));
+        assert_eq!(parse_rate("1.5M/s"), Ok(1_500_000));
+        assert_eq!(parse_rate("200"), Ok(200));
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("0/s").is_err());
+    }
+
+    #[test]
+    fn soak_holds_the_rate_and_reports_intervals() -> Result<()> {
+        let config = SoakConfig {
+            seed: 7,
+            clients: 50,
+            rate: 20_000,
+            duration: Duration::from_millis(300),
+            interval: Duration::from_millis(100),
+            max_p99: None,
+        };
+        let engine = Engine::with_clock(SimulatedClock::new(0));
+        let intervals = run_soak(&config, &engine, |_| {})?;
+        assert!(intervals.len() >= 3, "{:?}", intervals);
+        let transactions: u64 = intervals.iter().map(|stats| stats.transactions).sum();
+        // 6000 are due over the run, allow for a slow machine.
+        assert!(
+            transactions <= 6_000 && transactions > 1_000,
+            "{}",
+            transactions
+        );
+        Ok(())
+    }
+}
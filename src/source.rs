@@ -0,0 +1,260 @@
+use crate::{format::parse_json, Engine, Result, RunOptions, TransactionOutcome};
+use std::{
+    io::BufRead,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+/// A message taken from a [`MessageSource`]: a transaction as a JSON
+/// object, named like the csv columns, and where it sits in the source.
+pub struct Message {
+    /// Increases from one message to the next, as within a Kafka
+    /// partition, though not necessarily by one.
+    pub offset: u64,
+    pub payload: Vec<u8>,
+}
+
+/// Where a streaming run takes its transactions from, such as one
+/// partition of a Kafka topic.
+pub trait MessageSource {
+    /// The next message, or `None` if none arrived within `timeout`.
+    fn poll(&mut self, timeout: Duration) -> Result<Option<Message>>;
+    /// Tells the source the messages before `next_offset` are done with,
+    /// so a restarted consumer starts from there.
+    fn commit(&mut self, next_offset: u64) -> Result<()>;
+    /// Whether the source has ended. Topics don't, files do.
+    fn ended(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// How a streaming run checkpoints its state.
+pub struct StreamOptions {
+    /// Where the state is checkpointed, see [`crate::Database::snapshot`].
+    pub checkpoint: PathBuf,
+    /// Checkpoints after this many messages, and whenever the source goes
+    /// quiet.
+    pub checkpoint_every: u64,
+    /// How long to wait for a message before checkpointing what came so
+    /// far.
+    pub poll_timeout: Duration,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+/// What a streaming run did.
+pub struct StreamSummary {
+    pub messages: u64,
+    pub rejected: u64,
+    /// Messages that weren't a transaction, skipped with a warning.
+    pub errors: u64,
+    /// Messages below the checkpoint's offset, which were already applied
+    /// before a restart and were skipped.
+    pub replayed: u64,
+    pub checkpoints: u64,
+}
+
+/// Applies the messages of `source` to `engine` until the source ends,
+/// checkpointing the state to [`StreamOptions::checkpoint`] and only then
+/// committing the offset of the messages it holds.
+///
+/// A restarted job restores the checkpoint into a fresh engine, see
+/// [`restore_checkpoint`]. The source may hand it messages again that
+/// were applied after the checkpoint was committed, or even before, if
+/// the job stopped between writing a checkpoint and committing it. The
+/// checkpoint records the offset it was taken at, and messages below it
+/// are skipped, so each message is applied exactly once.
+pub fn run_stream(
+    engine: &Engine,
+    source: &mut dyn MessageSource,
+    options: &StreamOptions,
+    resume_at: u64,
+) -> Result<StreamSummary> {
+    let mut summary = StreamSummary::default();
+    let (mut next_offset, mut pending) = (resume_at, 0);
+    let run_options = RunOptions::default();
+    loop {
+        let message = source.poll(options.poll_timeout)?;
+        if let Some(message) = &message {
+            if message.offset < resume_at {
+                summary.replayed += 1;
+                continue;
+            }
+            next_offset = message.offset + 1;
+            pending += 1;
+            summary.messages += 1;
+            match parse_json(message.payload.as_slice(), &run_options).next() {
+                Some(Ok(txn)) => {
+                    let outcome = engine.process(txn)?;
+                    summary.rejected += matches!(outcome, TransactionOutcome::Rejected(_)) as u64;
+                }
+                Some(Err(x)) => {
+                    tracing::warn!(offset = message.offset, "skipped message: {}", x);
+                    summary.errors += 1;
+                }
+                None => {
+                    tracing::warn!(offset = message.offset, "skipped empty message");
+                    summary.errors += 1;
+                }
+            }
+            if pending < options.checkpoint_every {
+                continue;
+            }
+        }
+        if pending > 0 {
+            engine.db.snapshot(&options.checkpoint, next_offset)?;
+            source.commit(next_offset)?;
+            summary.checkpoints += 1;
+            pending = 0;
+        }
+        if message.is_none() && source.ended() {
+            return Ok(summary);
+        }
+    }
+}
+
+/// Loads the checkpoint at `path` into `engine`, returning the offset to
+/// resume at, or 0 without a checkpoint yet.
+pub fn restore_checkpoint(engine: &Engine, path: &Path) -> Result<u64> {
+    match path.exists() {
+        true => engine.db.restore(path),
+        false => Ok(0),
+    }
+}
+
+/// A source reading one message per line, with the line number as its
+/// offset, e.g. a topic dumped to a file. Commits are ignored.
+pub struct LineSource<R> {
+    lines: std::io::Lines<R>,
+    offset: u64,
+    ended: bool,
+}
+
+impl<R: BufRead> LineSource<R> {
+    pub fn new(reader: R) -> Self {
+        LineSource {
+            lines: reader.lines(),
+            offset: 0,
+            ended: false,
+        }
+    }
+}
+
+impl<R: BufRead> MessageSource for LineSource<R> {
+    fn poll(&mut self, _timeout: Duration) -> Result<Option<Message>> {
+        let Some(line) = self.lines.next() else {
+            self.ended = true;
+            return Ok(None);
+        };
+        let line = line.map_err(|x| format!("error reading a message: {}", x))?;
+        self.offset += 1;
+        Ok(Some(Message {
+            offset: self.offset - 1,
+            payload: line.into_bytes(),
+        }))
+    }
+
+    fn commit(&mut self, _next_offset: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn ended(&self) -> bool {
+        self.ended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, state::export_state};
+
+    /// Hands out its messages in order, recording commits, and can
+    /// fail once after a number of messages like a crashed consumer.
+    struct Topic {
+        messages: Vec<String>,
+        next: usize,
+        committed: u64,
+        crash_after: Option<usize>,
+    }
+
+    impl MessageSource for Topic {
+        fn poll(&mut self, _timeout: Duration) -> Result<Option<Message>> {
+            if self.crash_after.is_some_and(|after| self.next == after) {
+                self.crash_after = None;
+                return Err("consumer crashed".into());
+            }
+            let message = self.messages.get(self.next).map(|payload| Message {
+                offset: self.next as u64,
+                payload: payload.clone().into_bytes(),
+            });
+            self.next += message.is_some() as usize;
+            Ok(message)
+        }
+
+        fn commit(&mut self, next_offset: u64) -> Result<()> {
+            self.committed = next_offset;
+            Ok(())
+        }
+
+        fn ended(&self) -> bool {
+            self.next == self.messages.len()
+        }
+    }
+
+    #[test]
+    fn offsets_are_committed_after_their_checkpoint() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-source-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).map_err(|x| x.to_string())?;
+        let messages: Vec<_> = (1..=7)
+            .map(|tx| {
+                format!(
+                    r#"{{"type":"deposit","client":1,"tx":{},"amount":1.0}}"#,
+                    tx
+                )
+            })
+            .chain(["not json".to_string()])
+            .collect();
+        let options = StreamOptions {
+            checkpoint: dir.join("checkpoint.json"),
+            checkpoint_every: 3,
+            poll_timeout: Duration::ZERO,
+        };
+        let mut topic = Topic {
+            messages,
+            next: 0,
+            committed: 0,
+            crash_after: Some(5),
+        };
+        let engine = Engine::with_clock(SimulatedClock::new(0));
+        assert!(run_stream(&engine, &mut topic, &options, 0).is_err());
+        assert_eq!(topic.committed, 3);
+
+        // The restarted consumer starts from the committed offset, and
+        // the checkpoint holds exactly the messages before it.
+        topic.next = topic.committed as usize;
+        let engine = Engine::with_clock(SimulatedClock::new(0));
+        let resume_at = restore_checkpoint(&engine, &options.checkpoint)?;
+        assert_eq!(resume_at, 3);
+        let summary = run_stream(&engine, &mut topic, &options, resume_at)?;
+        assert_eq!((summary.messages, summary.errors), (5, 1));
+        assert_eq!(topic.committed, 8);
+        assert_eq!(
+            engine.account(1)?.map(|account| account.available()),
+            Some(7.0)
+        );
+
+        // Messages handed out again below the checkpoint are skipped.
+        let mut lines = LineSource::new(
+            "{\"type\":\"deposit\",\"client\":2,\"tx\":9,\"amount\":1.0}\n".as_bytes(),
+        );
+        let again = run_stream(&engine, &mut lines, &options, 1)?;
+        assert_eq!((again.replayed, again.messages), (1, 0));
+        let restored = Engine::with_clock(SimulatedClock::new(0));
+        restore_checkpoint(&restored, &options.checkpoint)?;
+        assert_eq!(export_state(&restored.db)?, export_state(&engine.db)?);
+        std::fs::remove_dir_all(&dir).map_err(|x| x.to_string())?;
+        Ok(())
+    }
+}
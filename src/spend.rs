@@ -0,0 +1,119 @@
+use crate::{
+    clock::Timestamp, open_file_read_csv, schema::Schema, ClientId, Engine, Result,
+    TransactionOutcome, TransactionType,
+};
+use std::collections::BTreeMap;
+
+/// The category used for withdrawals without one.
+const UNCATEGORIZED: &str = "uncategorized";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+/// The time range a report covers, `from` inclusive, `to` exclusive.
+pub struct Period {
+    pub from: Option<Timestamp>,
+    pub to: Option<Timestamp>,
+}
+
+impl Period {
+    pub fn contains(&self, timestamp: Timestamp) -> bool {
+        self.from.is_none_or(|from| timestamp >= from) && self.to.is_none_or(|to| timestamp < to)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+/// Spending of one client in one category.
+pub struct Spend {
+    pub withdrawals: u64,
+    pub total: f64,
+}
+
+/// Spending per client and category, ordered by client then category.
+pub type SpendReport = BTreeMap<(ClientId, String), Spend>;
+
+/// Runs `input` through the engine and sums the withdrawals it applied
+/// within `period` by client and category.
+///
+/// Withdrawals the engine refused (for insufficient funds, or because the
+/// account was locked) weren't spent, so they aren't counted.
+pub fn spend_report(input: String, period: Period) -> Result<SpendReport> {
+    let mut reader = open_file_read_csv(input)?;
+    let schema = Schema::from_headers(reader.headers()?, &[])?;
+    let engine = Engine::default();
+    let mut report = SpendReport::new();
+    for record in reader.records() {
+        let mut txn = schema.parse(&record?)?;
+        let timestamp = *txn.timestamp.get_or_insert_with(|| engine.clock.now());
+        let client_id = txn.client_id;
+        let spend = match (&txn.transaction_type, txn.amount) {
+            (TransactionType::Withdrawal, Some(amount)) => Some((
+                amount,
+                txn.category.as_deref().unwrap_or(UNCATEGORIZED).to_string(),
+            )),
+            _ => None,
+        };
+        let outcome = engine.process(txn)?;
+
+        if let Some((amount, category)) = spend {
+            if outcome == TransactionOutcome::Applied && period.contains(timestamp) {
+                let spend = report.entry((client_id, category)).or_default();
+                spend.withdrawals += 1;
+                spend.total += amount;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Prints a spend report as csv to stdout.
+pub fn print_spend_report(report: &SpendReport) {
+    println!(
+        "{:>7}, {:>20}, {:>12}, {:>12}",
+        "client", "category", "withdrawals", "total"
+    );
+    for ((client_id, category), spend) in report {
+        println!(
+            "{:>7}, {:>20}, {:>12}, {:>12.4}",
+            client_id, category, spend.withdrawals, spend.total
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_applied_withdrawals_by_category() -> Result<()> {
+        let report = spend_report(
+            "test-files/spend_by_category.csv".to_string(),
+            Period::default(),
+        )?;
+        let totals: Vec<_> = report
+            .iter()
+            .map(|((client, category), spend)| {
+                (*client, category.as_str(), spend.withdrawals, spend.total)
+            })
+            .collect();
+        assert_eq!(
+            totals,
+            vec![
+                (1, "groceries", 2, 3.0),
+                (1, "travel", 1, 4.0),
+                (2, "uncategorized", 1, 1.0),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn only_counts_withdrawals_in_period() -> Result<()> {
+        let period = Period {
+            from: Some(200),
+            to: Some(300),
+        };
+        let report = spend_report("test-files/spend_by_category.csv".to_string(), period)?;
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[&(1, "groceries".to_string())].total, 2.0);
+        Ok(())
+    }
+}
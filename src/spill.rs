@@ -0,0 +1,216 @@
+use crate::{state::TransactionState, Result, Transaction};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// How many stored transactions a [`Spill`] keeps in memory by default.
+pub const DEFAULT_MAX_RESIDENT: usize = 1_000_000;
+
+/// The name of the file a [`DirTransactionStore`] spills to.
+pub const SPILL_FILE: &str = "transactions.spill";
+
+/// Where stored transactions go once there are too many to keep in
+/// memory. Spilled transactions are still looked up by disputes, resolves
+/// and chargebacks, and are still part of the state.
+pub trait TransactionStore: Debug + Send + Sync {
+    /// Stores a transaction that is being spilled from memory, replacing
+    /// any spilled earlier with the same id.
+    fn store(&self, txn: &Transaction) -> Result<()>;
+    /// Reads a transaction, leaving it in the store.
+    fn get(&self, txn_id: u32) -> Result<Option<Transaction>>;
+    fn contains(&self, txn_id: u32) -> Result<bool>;
+    /// How many transactions are in the store.
+    fn count(&self) -> Result<usize>;
+    /// Visits every transaction in the store.
+    fn for_each(&self, f: &mut dyn FnMut(&Transaction)) -> Result<()>;
+}
+
+#[derive(Debug)]
+/// Spills the least recently stored transactions of an engine to a
+/// [`TransactionStore`], keeping at most `max_resident` of them in memory,
+/// give or take what is stored between two sweeps.
+///
+/// Disputes mostly refer to recent transactions, so the ones spilled are
+/// those least likely to be needed again, and those that are cost a read
+/// from the store.
+pub struct Spill {
+    pub store: Box<dyn TransactionStore>,
+    pub max_resident: usize,
+}
+
+#[derive(Debug, Default)]
+struct Spilled {
+    /// Where each transaction's line starts in the file, and its length.
+    index: HashMap<u32, (u64, usize)>,
+    end: u64,
+}
+
+#[derive(Debug)]
+/// A transaction store appending one JSON line per transaction to a file
+/// in a directory, with the offset of each line kept in memory: a dozen
+/// bytes or so per spilled transaction instead of the transaction.
+///
+/// The file only lives as long as the run, it is emptied when the store
+/// is opened. The state of the run, spilled transactions included, is
+/// what outlives it.
+pub struct DirTransactionStore {
+    path: PathBuf,
+    file: Mutex<File>,
+    spilled: Mutex<Spilled>,
+}
+
+impl DirTransactionStore {
+    /// Spills to [`SPILL_FILE`] in `dir`, creating the directory if needed.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)
+            .map_err(|x| format!("error creating spill dir {}: {}", dir.display(), x))?;
+        let path = dir.join(SPILL_FILE);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .map_err(|x| format!("error opening {}: {}", path.display(), x))?;
+        Ok(DirTransactionStore {
+            path,
+            file: Mutex::new(file),
+            spilled: Mutex::new(Spilled::default()),
+        })
+    }
+
+    fn error(&self, x: impl std::fmt::Display) -> String {
+        format!("error spilling to {}: {}", self.path.display(), x)
+    }
+
+    fn decode(&self, line: &[u8]) -> Result<Transaction> {
+        let state: TransactionState = serde_json::from_slice(line)
+            .map_err(|x| format!("{} is corrupt: {}", self.path.display(), x))?;
+        Transaction::try_from(state)
+    }
+}
+
+impl TransactionStore for DirTransactionStore {
+    fn store(&self, txn: &Transaction) -> Result<()> {
+        let mut line =
+            serde_json::to_vec(&TransactionState::from(txn)).map_err(|x| self.error(x))?;
+        line.push(b'\n');
+        let mut spilled = self.spilled.lock().map_err(|_| "spill lock poisoned")?;
+        let mut file = self.file.lock().map_err(|_| "spill lock poisoned")?;
+        file.seek(SeekFrom::Start(spilled.end))
+            .and_then(|_| file.write_all(&line))
+            .map_err(|x| self.error(x))?;
+        let at = spilled.end;
+        spilled.index.insert(txn.txn_id, (at, line.len()));
+        spilled.end += line.len() as u64;
+        Ok(())
+    }
+
+    fn get(&self, txn_id: u32) -> Result<Option<Transaction>> {
+        let Some((at, len)) = self
+            .spilled
+            .lock()
+            .map_err(|_| "spill lock poisoned")?
+            .index
+            .get(&txn_id)
+            .copied()
+        else {
+            return Ok(None);
+        };
+        let mut line = vec![0; len];
+        let mut file = self.file.lock().map_err(|_| "spill lock poisoned")?;
+        file.seek(SeekFrom::Start(at))
+            .and_then(|_| file.read_exact(&mut line))
+            .map_err(|x| self.error(x))?;
+        self.decode(&line).map(Some)
+    }
+
+    fn contains(&self, txn_id: u32) -> Result<bool> {
+        let spilled = self.spilled.lock().map_err(|_| "spill lock poisoned")?;
+        Ok(spilled.index.contains_key(&txn_id))
+    }
+
+    fn count(&self) -> Result<usize> {
+        let spilled = self.spilled.lock().map_err(|_| "spill lock poisoned")?;
+        Ok(spilled.index.len())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&Transaction)) -> Result<()> {
+        let spilled = self.spilled.lock().map_err(|_| "spill lock poisoned")?;
+        let mut file = self.file.lock().map_err(|_| "spill lock poisoned")?;
+        file.seek(SeekFrom::Start(0)).map_err(|x| self.error(x))?;
+        let mut lines = BufReader::new(&mut *file).take(spilled.end);
+        let (mut at, mut line) = (0, Vec::new());
+        loop {
+            line.clear();
+            let len = lines
+                .read_until(b'\n', &mut line)
+                .map_err(|x| self.error(x))?;
+            if len == 0 {
+                return Ok(());
+            }
+            let txn = self.decode(&line)?;
+            // A transaction spilled again later is only visited once.
+            if spilled.index.get(&txn.txn_id) == Some(&(at, len)) {
+                f(&txn);
+            }
+            at += len as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, state::export_state, Engine, TransactionOutcome};
+
+    #[test]
+    fn disputes_reach_spilled_transactions() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-spill-test");
+        let _ = fs::remove_dir_all(&dir);
+        let spilled = Engine::with_clock(SimulatedClock::new(0)).with_spill(Spill {
+            store: Box::new(DirTransactionStore::open(&dir)?),
+            max_resident: 2,
+        });
+        let resident = Engine::with_clock(SimulatedClock::new(0));
+        for engine in [&spilled, &resident] {
+            for txn_id in 1..=5 {
+                let mut txn = Transaction::deposit(1, txn_id, txn_id as f64);
+                txn.timestamp = Some(txn_id as u64);
+                engine.process(txn)?;
+            }
+        }
+        assert_eq!(spilled.db.spill_oldest()?, 3);
+        assert_eq!(spilled.db.transactions.len(), 2);
+        assert!(spilled.db.transactions.contains_key(&5));
+        assert_eq!(spilled.metrics()?.spilled_transactions, 3);
+        assert_eq!(
+            export_state(&spilled.db)?.transactions,
+            export_state(&resident.db)?.transactions
+        );
+        for engine in [&spilled, &resident] {
+            assert_eq!(
+                engine.process(Transaction::deposit(1, 1, 9.0))?,
+                TransactionOutcome::Rejected(crate::i18n::Reason::DuplicateTxnId)
+            );
+            engine.process(Transaction::dispute(1, 2))?;
+            engine.process(Transaction::chargeback(1, 2))?;
+        }
+        assert_eq!(
+            export_state(&spilled.db)?.accounts,
+            export_state(&resident.db)?.accounts
+        );
+        assert_eq!(
+            spilled.account(1)?.map(|account| account.total()),
+            Some(13.0)
+        );
+        fs::remove_dir_all(&dir).map_err(|x| x.to_string())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,482 @@
+use crate::{
+    clock::Timestamp,
+    currency::{parse_currency, Balance},
+    digest::state_digest,
+    disputes::TxnState,
+    locked::Lock,
+    outbox::Event,
+    Client, ClientId, Database, Engine, PaymentsEngineError, Result, Transaction, TransactionType,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+    sync::atomic::Ordering,
+};
+
+/// The version of the state schema written by this engine. Files of any
+/// other version are refused rather than guessed at.
+pub const STATE_VERSION: u32 = 1;
+/// The first bytes of every zstd frame, how compressed state files are
+/// told apart from plain JSON ones.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+/// The level compressed files are rewritten at when the one they were
+/// first written at is not known, zstd's own default.
+pub const DEFAULT_COMPRESSION: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The whole state of an engine in its canonical JSON form.
+///
+/// Accounts are ordered by client and transactions by id, so the same
+/// state always exports to the same bytes no matter how it was built.
+pub struct StateFile {
+    pub version: u32,
+    /// Counts the full snapshots of an append directory, so deltas written
+    /// on top of an older one are never applied to a newer one.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub generation: u64,
+    /// In a snapshot, the rows of the input handled before it, which a
+    /// run resuming from it skips.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub rows: u64,
+    pub accounts: Vec<AccountState>,
+    /// The deposits and withdrawals kept so they can be disputed.
+    pub transactions: Vec<TransactionState>,
+    /// The ids of the transfers applied, in ascending order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transfers: Vec<u32>,
+    /// Events recorded but not published yet, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outbox: Vec<Event>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountState {
+    pub client: ClientId,
+    pub available: f64,
+    pub held: f64,
+    pub locked: bool,
+    /// Transactions under dispute, in ascending order.
+    pub disputed: Vec<u32>,
+    /// When each dispute was opened, by transaction. Disputes missing here
+    /// were opened at time 0.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dispute_opened: BTreeMap<u32, Timestamp>,
+    /// What locked the account, if anything is known about it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock: Option<LockState>,
+    /// Closed after review, left out unless set.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub closed: bool,
+    /// What disputes still have to hold, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending_holds: Vec<PendingHoldState>,
+    /// Deposits received while the account was locked, to be credited once
+    /// it is unlocked, in the order they came in.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub queued: Vec<u32>,
+    /// The funds in currencies other than the account's own, by currency.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub currencies: BTreeMap<String, Balance>,
+    /// Transactions whose dispute was resolved or charged back, with which
+    /// of the two, see [`crate::disputes::TxnState`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub settled: BTreeMap<u32, String>,
+    /// What was disputed of transactions disputed for less than their
+    /// amount, whether or not the dispute is settled.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub disputed_amounts: BTreeMap<u32, f64>,
+}
+
+impl AccountState {
+    /// The canonical form of a client's account.
+    pub fn of(client_id: ClientId, client: &Client) -> Self {
+        let dispute_opened: BTreeMap<_, _> = client
+            .disputed
+            .iter()
+            .map(|(txn_id, opened)| (*txn_id, *opened))
+            .filter(|(_, opened)| *opened != 0)
+            .collect();
+        let mut disputed: Vec<_> = client.disputed.keys().copied().collect();
+        disputed.sort();
+        AccountState {
+            client: client_id,
+            available: client.available,
+            held: client.held,
+            locked: client.locked,
+            disputed,
+            dispute_opened,
+            lock: client.lock.map(|lock| LockState {
+                reason: lock.reason.as_str().to_string(),
+                tx: lock.txn_id,
+                timestamp: lock.timestamp,
+            }),
+            closed: client.closed,
+            pending_holds: client
+                .pending_holds
+                .iter()
+                .map(|&(tx, amount)| PendingHoldState { tx, amount })
+                .collect(),
+            queued: client.queued.clone(),
+            currencies: client
+                .currencies
+                .iter()
+                .map(|(currency, balance)| (currency.to_string(), *balance))
+                .collect(),
+            settled: client
+                .settled
+                .iter()
+                .map(|(txn_id, state)| (*txn_id, state.as_str().to_string()))
+                .collect(),
+            disputed_amounts: client
+                .disputed_amounts
+                .iter()
+                .map(|(txn_id, amount)| (*txn_id, *amount))
+                .collect(),
+        }
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LockState {
+    /// `chargeback`, the only reason accounts are locked for so far.
+    pub reason: String,
+    /// The transaction that caused the lock.
+    pub tx: u32,
+    pub timestamp: Timestamp,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PendingHoldState {
+    /// The disputed deposit.
+    pub tx: u32,
+    /// What is still to be held of it.
+    pub amount: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransactionState {
+    pub tx: u32,
+    pub client: ClientId,
+    /// `deposit` or `withdrawal`, as in the input csv.
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub amount: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+}
+
+impl From<&Transaction> for TransactionState {
+    /// Extra carried columns are left out.
+    fn from(txn: &Transaction) -> Self {
+        TransactionState {
+            tx: txn.txn_id,
+            client: txn.client_id,
+            kind: txn.transaction_type.as_str().to_string(),
+            amount: txn.amount,
+            timestamp: txn.timestamp,
+            memo: txn.memo.as_deref().map(str::to_string),
+            category: txn.category.as_deref().map(str::to_string),
+            currency: txn.currency.as_deref().map(str::to_string),
+        }
+    }
+}
+
+impl TryFrom<TransactionState> for Transaction {
+    type Error = PaymentsEngineError;
+
+    fn try_from(txn: TransactionState) -> Result<Self> {
+        Ok(Transaction {
+            transaction_type: TransactionType::try_from(txn.kind.as_str())?,
+            client_id: txn.client,
+            txn_id: txn.tx,
+            amount: txn.amount,
+            to_client: None,
+            timestamp: txn.timestamp,
+            memo: txn.memo.map(Into::into),
+            category: txn.category.map(Into::into),
+            currency: txn.currency.map(Into::into),
+            idempotency_key: None,
+            extras: Box::default(),
+        })
+    }
+}
+
+/// The canonical form of the state in `db`, evicted clients and spilled
+/// transactions included.
+/// Extra carried columns are not part of the state.
+pub fn export_state(db: &Database) -> Result<StateFile> {
+    let mut accounts = Vec::new();
+    db.for_each_client(|client_id, client| accounts.push(AccountState::of(client_id, client)))?;
+    accounts.sort_by_key(|account| account.client);
+    let mut transactions = Vec::new();
+    db.for_each_transaction(|txn| transactions.push(TransactionState::from(txn)))?;
+    transactions.sort_by_key(|txn| txn.tx);
+    let mut transfers: Vec<_> = db.transfers.iter().map(|txn_id| *txn_id).collect();
+    transfers.sort();
+    Ok(StateFile {
+        version: STATE_VERSION,
+        generation: 0,
+        rows: 0,
+        accounts,
+        transactions,
+        transfers,
+        outbox: db.outbox.pending()?,
+    })
+}
+
+/// Loads `state` into an empty engine, checking that it is consistent:
+/// every id appears once and every dispute refers to a stored
+/// transaction of the same client.
+pub fn import_state(engine: &Engine, state: StateFile) -> Result<()> {
+    import_into(&engine.db, state)
+}
+
+/// [`import_state`] into a bare database.
+pub(crate) fn import_into(db: &Database, state: StateFile) -> Result<()> {
+    if state.version != STATE_VERSION {
+        return Err(format!(
+            "state version {} is not supported, expected {}",
+            state.version, STATE_VERSION
+        )
+        .into());
+    }
+    if !db.clients.is_empty() || !db.transactions.is_empty() {
+        return Err("state can only be imported into an empty engine".into());
+    }
+    for txn in state.transactions {
+        let (txn_id, kind) = (txn.tx, txn.kind.clone());
+        let transaction = Transaction::try_from(txn)?;
+        if !matches!(
+            transaction.transaction_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) {
+            return Err(format!("transaction {} can't be a {}", txn_id, kind).into());
+        }
+        if db.transactions.insert(txn_id, transaction).is_some() {
+            return Err(PaymentsEngineError::DuplicateTxnId(txn_id));
+        }
+    }
+    for txn_id in state.transfers {
+        if db.transactions.contains_key(&txn_id) || !db.transfers.insert(txn_id) {
+            return Err(PaymentsEngineError::DuplicateTxnId(txn_id));
+        }
+    }
+    let mut last_activity = HashMap::<ClientId, Timestamp>::new();
+    for txn in db.transactions.iter() {
+        let latest = last_activity.entry(txn.client_id).or_default();
+        *latest = (*latest).max(txn.timestamp.unwrap_or_default());
+    }
+    for account in state.accounts {
+        for txn_id in account.disputed.iter().chain(&account.queued) {
+            if db
+                .transactions
+                .get(txn_id)
+                .is_none_or(|txn| txn.client_id != account.client)
+            {
+                return Err(format!(
+                    "client {} disputes or queued transaction {}, which is not one of its transactions",
+                    account.client, txn_id
+                )
+                .into());
+            }
+        }
+        if let Some(hold) = account
+            .pending_holds
+            .iter()
+            .find(|hold| !account.disputed.contains(&hold.tx))
+        {
+            return Err(format!(
+                "client {} has a pending hold for transaction {}, which is not disputed",
+                account.client, hold.tx
+            )
+            .into());
+        }
+        if let Some(txn_id) = account.disputed_amounts.keys().find(|txn_id| {
+            !account.disputed.contains(txn_id) && !account.settled.contains_key(txn_id)
+        }) {
+            return Err(format!(
+                "client {} has a disputed amount for transaction {}, which was never disputed",
+                account.client, txn_id
+            )
+            .into());
+        }
+        let lock = match account.lock {
+            Some(lock) => Some(Lock {
+                reason: lock.reason.parse()?,
+                txn_id: lock.tx,
+                timestamp: lock.timestamp,
+            }),
+            None => None,
+        };
+        let client = Client {
+            available: account.available,
+            held: account.held,
+            locked: account.locked,
+            lock,
+            closed: account.closed,
+            disputed: account
+                .disputed
+                .into_iter()
+                .map(|txn_id| {
+                    let opened = account.dispute_opened.get(&txn_id);
+                    (txn_id, opened.copied().unwrap_or_default())
+                })
+                .collect(),
+            pending_holds: account
+                .pending_holds
+                .into_iter()
+                .map(|hold| (hold.tx, hold.amount))
+                .collect(),
+            queued: account.queued,
+            currencies: account
+                .currencies
+                .into_iter()
+                .map(|(currency, balance)| Ok((parse_currency(&currency)?, balance)))
+                .collect::<Result<_>>()?,
+            settled: account
+                .settled
+                .into_iter()
+                .map(|(txn_id, state)| match state.parse()? {
+                    state @ (TxnState::Resolved | TxnState::ChargedBack) => Ok((txn_id, state)),
+                    _ => {
+                        Err(format!("transaction {} can't be settled as {}", txn_id, state).into())
+                    }
+                })
+                .collect::<Result<_>>()?,
+            disputed_amounts: account.disputed_amounts.into_iter().collect(),
+            last_activity: last_activity
+                .get(&account.client)
+                .copied()
+                .unwrap_or_default(),
+        };
+        db.ledger.open(&client)?;
+        db.note_currencies(&client);
+        if db.clients.insert(account.client, client).is_some() {
+            return Err(format!("client {} appears twice", account.client).into());
+        }
+    }
+    db.outbox.restore(state.outbox)?;
+    let latest = last_activity.into_values().max().unwrap_or_default();
+    db.latest_activity.fetch_max(latest, Ordering::SeqCst);
+    db.digest.store(state_digest(db)?, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Writes `state` as pretty printed JSON to `path`, or to stdout if unset.
+pub fn write_state(state: &StateFile, path: Option<String>) -> Result<()> {
+    let mut out: Box<dyn Write> = match &path {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).map_err(|x| format!("error creating {}: {}", path, x))?,
+        )),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    serde_json::to_writer_pretty(&mut out, state).map_err(|x| x.to_string())?;
+    writeln!(out).map_err(|x| x.to_string())?;
+    Ok(())
+}
+
+/// Writes the state of `db` over the state file at `path`, compressed
+/// if the file was.
+pub fn rewrite_state(db: &Database, path: &str) -> Result<()> {
+    let state = export_state(db)?;
+    match is_compressed(Path::new(path))? {
+        true => write_state_compressed(&state, Path::new(path), DEFAULT_COMPRESSION),
+        false => write_state(&state, Some(path.to_string())),
+    }
+}
+
+/// Writes `state` to `path` compressed with zstd at `level` (1 to 19).
+/// The JSON is streamed through the `zstd` command rather than built in
+/// memory first.
+pub fn write_state_compressed(state: &StateFile, path: &Path, level: u32) -> Result<()> {
+    let mut child = Command::new("zstd")
+        .arg(format!("-{}", level))
+        .args(["-q", "-f", "-o"])
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|x| format!("error running zstd: {}", x))?;
+    let written = {
+        let mut input = BufWriter::new(child.stdin.take().expect("stdin is piped"));
+        serde_json::to_writer(&mut input, state)
+            .map_err(|x| x.to_string())
+            .and_then(|_| input.flush().map_err(|x| x.to_string()))
+    };
+    let status = child
+        .wait()
+        .map_err(|x| format!("error running zstd: {}", x))?;
+    if !status.success() {
+        return Err(format!("zstd failed to write {}", path.display()).into());
+    }
+    Ok(written?)
+}
+
+/// Whether the file at `path` was written by [`write_state_compressed`].
+pub fn is_compressed(path: &Path) -> Result<bool> {
+    let mut magic = [0; ZSTD_MAGIC.len()];
+    let mut file =
+        File::open(path).map_err(|x| format!("error opening {}: {}", path.display(), x))?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == ZSTD_MAGIC),
+        Err(x) if x.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(x) => Err(format!("error reading {}: {}", path.display(), x).into()),
+    }
+}
+
+/// Parses the JSON in the file at `path`, decompressing it as it is
+/// parsed if it was written by [`write_state_compressed`].
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let invalid =
+        |x: serde_json::Error| format!("{} is not a valid state file: {}", path.display(), x);
+    if !is_compressed(path)? {
+        let file =
+            File::open(path).map_err(|x| format!("error opening {}: {}", path.display(), x))?;
+        return Ok(serde_json::from_reader(BufReader::new(file)).map_err(invalid)?);
+    }
+    let mut child = Command::new("zstd")
+        .args(["-d", "-c", "-q"])
+        .arg(path)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|x| format!("error running zstd: {}", x))?;
+    let value = serde_json::from_reader(BufReader::new(
+        child.stdout.take().expect("stdout is piped"),
+    ));
+    let status = child
+        .wait()
+        .map_err(|x| format!("error running zstd: {}", x))?;
+    let value = value.map_err(invalid)?;
+    if !status.success() {
+        return Err(format!("zstd failed to read {}", path.display()).into());
+    }
+    Ok(value)
+}
+
+/// Reads a state file written by [`write_state`] or
+/// [`write_state_compressed`].
+pub fn read_state(path: String) -> Result<StateFile> {
+    read_json(Path::new(&path))
+}
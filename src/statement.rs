@@ -0,0 +1,311 @@
+use crate::{
+    clock::Timestamp, dates::Date, history::HistoryEntry, open_file_read_csv, schema::Schema,
+    spend::Period, ClientId, Engine, Result, TransactionOutcome, TransactionType,
+};
+use std::fmt::Write as _;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// The file formats personal finance tools import statements from.
+pub enum StatementFormat {
+    /// Open Financial Exchange 2.2, XML.
+    Ofx,
+    /// Quicken Interchange Format.
+    Qif,
+    /// Every transaction applied to the client, disputes included, with
+    /// the running balance, see [`statement_history`].
+    Csv,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A movement of a client's funds, positive when they were credited.
+pub struct StatementEntry {
+    /// The id of the transaction, unique within the statement: a
+    /// chargeback has the id of the transaction it reverses, with a
+    /// `-chargeback` suffix.
+    pub id: String,
+    pub timestamp: Timestamp,
+    pub amount: f64,
+    pub transaction_type: TransactionType,
+    pub memo: Option<Box<str>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// What a client's funds went through over a period.
+pub struct Statement {
+    pub client: ClientId,
+    pub period: Period,
+    pub entries: Vec<StatementEntry>,
+    /// The client's total funds, available and held, at the end of the
+    /// period.
+    pub balance: f64,
+}
+
+/// Runs `input` through the engine and lists the transactions it applied
+/// within `period` that moved funds of `client` in or out.
+///
+/// Deposits, credit adjustments, transfers received and chargebacks of
+/// withdrawals are credits, withdrawals, debit adjustments, transfers
+/// sent and chargebacks of deposits are debits. Disputes and resolves only
+/// move funds between available and held, which the client still owns,
+/// so they are left out, as are funds in other currencies than the
+/// account's own.
+pub fn statement(input: String, client: ClientId, period: Period) -> Result<Statement> {
+    let mut reader = open_file_read_csv(input)?;
+    let schema = Schema::from_headers(reader.headers()?, &[])?;
+    let engine = Engine::default();
+    let mut entries = Vec::new();
+    let mut balance = 0.0;
+    for record in reader.records() {
+        let mut txn = schema.parse(&record?)?;
+        let timestamp = *txn.timestamp.get_or_insert_with(|| engine.clock.now());
+        let id = txn.txn_id.to_string();
+        let amount = txn.amount.unwrap_or_default();
+        let referenced = engine
+            .db
+            .transactions
+            .get(&txn.txn_id)
+            .filter(|referenced| referenced.currency.is_none())
+            .map(|referenced| (referenced.transaction_type, referenced.amount));
+        let movement = match txn.transaction_type {
+            _ if txn.currency.is_some() => None,
+            TransactionType::Deposit | TransactionType::CreditAdjustment
+                if txn.client_id == client =>
+            {
+                Some((id, amount))
+            }
+            TransactionType::Withdrawal | TransactionType::DebitAdjustment
+                if txn.client_id == client =>
+            {
+                Some((id, -amount))
+            }
+            TransactionType::Transfer if txn.client_id == client => Some((id, -amount)),
+            TransactionType::Transfer if txn.to_client == Some(client) => Some((id, amount)),
+            TransactionType::ChargeBack if txn.client_id == client => match referenced {
+                Some((TransactionType::Deposit, Some(amount))) => {
+                    Some((format!("{}-chargeback", id), -amount))
+                }
+                Some((TransactionType::Withdrawal, Some(amount))) => {
+                    Some((format!("{}-chargeback", id), amount))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        let (transaction_type, memo) = (txn.transaction_type, txn.memo.clone());
+        if engine.process(txn)? != TransactionOutcome::Applied {
+            continue;
+        }
+        let Some((id, amount)) = movement else {
+            continue;
+        };
+        if period.to.is_none_or(|to| timestamp < to) {
+            balance += amount;
+        }
+        if period.contains(timestamp) {
+            entries.push(StatementEntry {
+                id,
+                timestamp,
+                amount,
+                transaction_type,
+                memo,
+            });
+        }
+    }
+    Ok(Statement {
+        client,
+        period,
+        entries,
+        balance,
+    })
+}
+
+/// Runs `input` through the engine and lists every transaction it applied
+/// to `client` within `period`, in the order it applied them, with the
+/// client's balances after each. Unlike [`statement`], disputes, resolves
+/// and the transactions of other currencies are listed too.
+pub fn statement_history(
+    input: String,
+    client: ClientId,
+    period: Period,
+) -> Result<Vec<HistoryEntry>> {
+    let mut reader = open_file_read_csv(input)?;
+    let schema = Schema::from_headers(reader.headers()?, &[])?;
+    let engine = Engine::default().with_history();
+    for record in reader.records() {
+        engine.process(schema.parse(&record?)?)?;
+    }
+    Ok(engine.history(client, period))
+}
+
+/// `timestamp` as OFX writes dates, `YYYYMMDDHHMMSS` in UTC.
+fn ofx_date(timestamp: Timestamp) -> String {
+    let (year, month, day) = Date::from_timestamp(timestamp).ymd();
+    let seconds = timestamp % crate::dates::SECONDS_PER_DAY;
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Escapes the characters XML gives a meaning to.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl Statement {
+    /// The statement as an OFX bank statement in `currency`, with the
+    /// client id as the account id.
+    pub fn to_ofx(&self, currency: &str) -> String {
+        let first = self.entries.first().map(|entry| entry.timestamp);
+        let last = self.entries.last().map(|entry| entry.timestamp);
+        let start = self.period.from.or(first).unwrap_or_default();
+        let end = self.period.to.or(last).unwrap_or_default();
+        let mut ofx = String::new();
+        ofx.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        ofx.push_str("<?OFX OFXHEADER=\"200\" VERSION=\"220\" SECURITY=\"NONE\" OLDFILEUID=\"NONE\" NEWFILEUID=\"NONE\"?>\n");
+        ofx.push_str("<OFX>\n<SIGNONMSGSRSV1><SONRS>");
+        ofx.push_str("<STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>");
+        let _ = writeln!(
+            ofx,
+            "<DTSERVER>{}</DTSERVER><LANGUAGE>ENG</LANGUAGE></SONRS></SIGNONMSGSRSV1>",
+            ofx_date(end)
+        );
+        ofx.push_str("<BANKMSGSRSV1><STMTTRNRS><TRNUID>0</TRNUID>");
+        ofx.push_str("<STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>\n<STMTRS>");
+        let _ = writeln!(
+            ofx,
+            "<CURDEF>{}</CURDEF><BANKACCTFROM><BANKID>payments-engine</BANKID><ACCTID>{}</ACCTID><ACCTTYPE>CHECKING</ACCTTYPE></BANKACCTFROM>",
+            escape(currency),
+            self.client
+        );
+        let _ = writeln!(
+            ofx,
+            "<BANKTRANLIST><DTSTART>{}</DTSTART><DTEND>{}</DTEND>",
+            ofx_date(start),
+            ofx_date(end)
+        );
+        for entry in &self.entries {
+            let kind = match entry.transaction_type {
+                TransactionType::Transfer => "XFER",
+                _ if entry.amount < 0.0 => "DEBIT",
+                _ => "CREDIT",
+            };
+            let _ = write!(
+                ofx,
+                "<STMTTRN><TRNTYPE>{}</TRNTYPE><DTPOSTED>{}</DTPOSTED><TRNAMT>{:.4}</TRNAMT><FITID>{}</FITID><NAME>{}</NAME>",
+                kind,
+                ofx_date(entry.timestamp),
+                entry.amount,
+                entry.id,
+                entry.transaction_type.as_str()
+            );
+            if let Some(memo) = &entry.memo {
+                let _ = write!(ofx, "<MEMO>{}</MEMO>", escape(memo));
+            }
+            ofx.push_str("</STMTTRN>\n");
+        }
+        ofx.push_str("</BANKTRANLIST>\n");
+        let _ = writeln!(
+            ofx,
+            "<LEDGERBAL><BALAMT>{:.4}</BALAMT><DTASOF>{}</DTASOF></LEDGERBAL>",
+            self.balance,
+            ofx_date(end)
+        );
+        ofx.push_str("</STMTRS></STMTTRNRS></BANKMSGSRSV1>\n</OFX>\n");
+        ofx
+    }
+
+    /// The statement as a QIF bank account, dates as `MM/DD/YYYY`.
+    pub fn to_qif(&self) -> String {
+        let mut qif = String::from("!Type:Bank\n");
+        for entry in &self.entries {
+            let (year, month, day) = Date::from_timestamp(entry.timestamp).ymd();
+            let _ = writeln!(qif, "D{:02}/{:02}/{:04}", month, day, year);
+            let _ = writeln!(qif, "T{:.4}", entry.amount);
+            let _ = writeln!(qif, "N{}", entry.id);
+            let _ = writeln!(qif, "P{}", entry.transaction_type.as_str());
+            if let Some(memo) = &entry.memo {
+                let _ = writeln!(qif, "M{}", memo);
+            }
+            qif.push_str("^\n");
+        }
+        qif
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statements_sign_movements_from_the_clients_side() -> Result<()> {
+        let statement = statement("test-files/statement.csv".to_string(), 1, Period::default())?;
+        let entries: Vec<_> = statement
+            .entries
+            .iter()
+            .map(|entry| (entry.id.as_str(), entry.amount))
+            .collect();
+        // The withdrawal that bounced and the dispute and resolve are not
+        // movements.
+        assert_eq!(
+            entries,
+            [
+                ("1", 100.0),
+                ("2", -30.0),
+                ("5", 5.0),
+                ("6", 10.0),
+                ("6-chargeback", -10.0)
+            ]
+        );
+        assert_eq!(statement.balance, 75.0);
+
+        let qif = statement.to_qif();
+        assert!(qif.starts_with("!Type:Bank\nD05/01/2024\nT100.0000\nN1\nPdeposit\nMsalary\n^\n"));
+        let ofx = statement.to_ofx("EUR");
+        assert!(ofx.contains("<TRNTYPE>DEBIT</TRNTYPE><DTPOSTED>20240502000000</DTPOSTED><TRNAMT>-30.0000</TRNAMT><FITID>2</FITID><NAME>withdrawal</NAME><MEMO>rent &amp; bills</MEMO>"));
+        assert!(ofx.contains("<LEDGERBAL><BALAMT>75.0000</BALAMT>"));
+
+        let period = Period {
+            from: Some(1_714_694_400),
+            to: Some(1_714_953_600),
+        };
+        let statement = statement_of(period)?;
+        assert_eq!(statement.entries.len(), 2);
+        assert_eq!(statement.balance, 85.0);
+        Ok(())
+    }
+
+    #[test]
+    fn histories_list_disputes_with_the_running_balance() -> Result<()> {
+        let history =
+            statement_history("test-files/statement.csv".to_string(), 1, Period::default())?;
+        let entries: Vec<_> = history
+            .iter()
+            .map(|entry| (entry.txn_id, entry.transaction_type, entry.total()))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (1, TransactionType::Deposit, 100.0),
+                (2, TransactionType::Withdrawal, 70.0),
+                (5, TransactionType::Transfer, 75.0),
+                (6, TransactionType::Deposit, 85.0),
+                (6, TransactionType::Dispute, 85.0),
+                (6, TransactionType::ChargeBack, 75.0),
+            ]
+        );
+        Ok(())
+    }
+
+    fn statement_of(period: Period) -> Result<Statement> {
+        statement("test-files/statement.csv".to_string(), 1, period)
+    }
+}
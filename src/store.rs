@@ -0,0 +1,286 @@
+use crate::{
+    append::{
+        generation, read_chain, replace_state, write_conflicts, write_snapshot, CONFLICTS_FILE,
+        STATE_FILE,
+    },
+    state::StateFile,
+    Result, Transaction,
+};
+use std::{
+    fmt::Debug,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Where the state is kept from one run to the next, see
+/// [`crate::append::load_from`] and [`crate::append::save_to`].
+///
+/// The state is a full snapshot with a chain of deltas on top of it,
+/// each holding only what one run changed.
+pub trait StateStore: Debug + Send + Sync {
+    /// The snapshot with every delta applied, and the number of deltas.
+    /// `None` before the first run.
+    fn load(&self) -> Result<Option<(StateFile, usize)>>;
+    /// Replaces the snapshot and the chain of `deltas` deltas on top of it
+    /// with `state`.
+    fn save_snapshot(&self, state: StateFile, deltas: usize) -> Result<()>;
+    /// Adds `delta` to the chain as its `n`th delta.
+    fn save_delta(&self, delta: &StateFile, n: usize) -> Result<()>;
+    /// Keeps the transactions of the latest run that conflicted with
+    /// earlier runs, replacing those of the run before.
+    fn save_conflicts(&self, conflicts: &[Transaction]) -> Result<()>;
+    /// Makes everything saved since the last commit part of the state at
+    /// once. Stores that save in place have nothing to do.
+    fn commit(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The directory inside a [`DirStore`] where a staged save waits for its
+/// commit.
+pub const STAGING_DIR: &str = "staging";
+/// The file marking a staged save as committed, so an interrupted commit
+/// is finished rather than discarded.
+const COMMITTED: &str = "COMMITTED";
+
+#[derive(Debug)]
+/// Keeps the state as JSON files in a directory, the `--append-state`
+/// layout. Every file is written next to its final name and renamed, so
+/// an interrupted save leaves the previous state intact.
+///
+/// With staging, a save goes to [`STAGING_DIR`] first and only moves into
+/// place on commit, so a run saves all of its files or none of them. A
+/// staged save left uncommitted is discarded when the store is next
+/// loaded, and a commit that was interrupted is finished.
+pub struct DirStore {
+    dir: PathBuf,
+    /// Compress the files with zstd at this level.
+    compression: Option<u32>,
+    staging: bool,
+}
+
+impl DirStore {
+    pub fn new(dir: impl AsRef<Path>, compression: Option<u32>) -> Self {
+        DirStore {
+            dir: dir.as_ref().to_path_buf(),
+            compression,
+            staging: false,
+        }
+    }
+
+    /// Stages saves until [`StateStore::commit`].
+    pub fn with_staging(mut self) -> Self {
+        self.staging = true;
+        self
+    }
+
+    /// The directory saves are written to.
+    fn target(&self) -> Result<PathBuf> {
+        let target = match self.staging {
+            true => self.dir.join(STAGING_DIR),
+            false => self.dir.clone(),
+        };
+        fs::create_dir_all(&target)
+            .map_err(|x| format!("error creating {}: {}", target.display(), x))?;
+        Ok(target)
+    }
+
+    /// Finishes or discards a staged save left behind by an earlier run.
+    fn recover(&self) -> Result<()> {
+        let staging = self.dir.join(STAGING_DIR);
+        if staging.join(COMMITTED).exists() {
+            self.finish_commit()
+        } else if staging.exists() {
+            fs::remove_dir_all(&staging)
+                .map_err(|x| format!("error removing {}: {}", staging.display(), x).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Moves the files of a committed save into place and removes the
+    /// deltas a staged full snapshot replaced. Safe to repeat if it is
+    /// interrupted.
+    fn finish_commit(&self) -> Result<()> {
+        let staging = self.dir.join(STAGING_DIR);
+        let error =
+            |path: &Path, x: std::io::Error| format!("error committing {}: {}", path.display(), x);
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&staging).map_err(|x| error(&staging, x))? {
+            let name = entry.map_err(|x| error(&staging, x))?.file_name();
+            if name != COMMITTED && !name.to_string_lossy().ends_with(".tmp") {
+                files.push(name);
+            }
+        }
+        let snapshot = files.iter().any(|name| name == STATE_FILE);
+        for name in files {
+            let path = self.dir.join(&name);
+            fs::rename(staging.join(&name), &path).map_err(|x| error(&path, x))?;
+        }
+        if snapshot {
+            let generation = generation(&self.dir)?;
+            let current = format!("state.{}.delta-", generation);
+            for entry in fs::read_dir(&self.dir).map_err(|x| error(&self.dir, x))? {
+                let path = entry.map_err(|x| error(&self.dir, x))?.path();
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                if name.contains(".delta-") && !name.starts_with(&current) {
+                    fs::remove_file(&path).map_err(|x| error(&path, x))?;
+                }
+            }
+        }
+        fs::remove_dir_all(&staging).map_err(|x| error(&staging, x).into())
+    }
+}
+
+impl StateStore for DirStore {
+    fn load(&self) -> Result<Option<(StateFile, usize)>> {
+        self.recover()?;
+        read_chain(&self.dir)
+    }
+
+    fn save_snapshot(&self, state: StateFile, deltas: usize) -> Result<()> {
+        write_snapshot(&self.target()?, state, deltas, self.compression)
+    }
+
+    fn save_delta(&self, delta: &StateFile, n: usize) -> Result<()> {
+        let path = self
+            .target()?
+            .join(crate::append::delta_file(delta.generation, n));
+        replace_state(delta, &path, self.compression)
+    }
+
+    fn save_conflicts(&self, conflicts: &[Transaction]) -> Result<()> {
+        write_conflicts(conflicts, &self.target()?.join(CONFLICTS_FILE))
+    }
+
+    fn commit(&self) -> Result<()> {
+        if !self.staging {
+            return Ok(());
+        }
+        let marker = self.dir.join(STAGING_DIR).join(COMMITTED);
+        fs::write(&marker, "")
+            .map_err(|x| format!("error committing {}: {}", marker.display(), x))?;
+        self.finish_commit()
+    }
+}
+
+#[derive(Debug, Default)]
+/// Keeps the state in memory, for embedders running several batches in
+/// one process and for tests. Nothing outlives the process.
+pub struct MemoryStore {
+    /// The snapshot and its deltas, in order.
+    chain: Mutex<Option<(StateFile, Vec<StateFile>)>>,
+    pub conflicts: Mutex<Vec<Transaction>>,
+}
+
+impl StateStore for MemoryStore {
+    fn load(&self) -> Result<Option<(StateFile, usize)>> {
+        let chain = self.chain.lock().map_err(|_| "store lock poisoned")?;
+        Ok(chain.as_ref().map(|(snapshot, deltas)| {
+            let mut state = snapshot.clone();
+            for delta in deltas {
+                crate::append::merge(&mut state, delta.clone());
+            }
+            (state, deltas.len())
+        }))
+    }
+
+    fn save_snapshot(&self, mut state: StateFile, deltas: usize) -> Result<()> {
+        if deltas > 0 {
+            state.generation += 1;
+        }
+        *self.chain.lock().map_err(|_| "store lock poisoned")? = Some((state, Vec::new()));
+        Ok(())
+    }
+
+    fn save_delta(&self, delta: &StateFile, n: usize) -> Result<()> {
+        let mut chain = self.chain.lock().map_err(|_| "store lock poisoned")?;
+        match chain.as_mut() {
+            Some((_, deltas)) if deltas.len() + 1 == n => {
+                deltas.push(delta.clone());
+                Ok(())
+            }
+            _ => Err(format!("delta {} doesn't follow the chain", n).into()),
+        }
+    }
+
+    fn save_conflicts(&self, conflicts: &[Transaction]) -> Result<()> {
+        *self.conflicts.lock().map_err(|_| "store lock poisoned")? = conflicts.to_vec();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        append::{load_from, save_to},
+        clock::SimulatedClock,
+        state::export_state,
+        Engine,
+    };
+
+    #[test]
+    fn runs_continue_from_a_memory_store() -> Result<()> {
+        let store = MemoryStore::default();
+        let runs = [
+            vec![Transaction::deposit(1, 1, 2.0)],
+            vec![Transaction::deposit(2, 2, 3.0)],
+            vec![Transaction::dispute(1, 1), Transaction::deposit(1, 2, 1.0)],
+        ];
+        let mut last = None;
+        for txns in runs {
+            let mut engine = Engine::with_clock(SimulatedClock::new(0));
+            load_from(&mut engine, &store)?;
+            for txn in txns {
+                engine.process(txn)?;
+            }
+            save_to(&engine, &store, Some(5))?;
+            last = Some(export_state(&engine.db)?);
+        }
+        let (state, deltas) = store.load()?.expect("state was saved");
+        assert_eq!(deltas, 2);
+        assert_eq!(Some(state.accounts), last.map(|state| state.accounts));
+        // The last deposit reused the id of the second run's.
+        let conflicts = store.conflicts.lock().map_err(|_| "store lock poisoned")?;
+        assert_eq!(conflicts.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn staged_saves_commit_or_leave_no_trace() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-staging-test");
+        let _ = fs::remove_dir_all(&dir);
+        let save = |txn, commit| -> Result<()> {
+            let mut engine = Engine::with_clock(SimulatedClock::new(0));
+            load_from(&mut engine, &DirStore::new(&dir, None))?;
+            engine.process(txn)?;
+            let store = DirStore::new(&dir, None).with_staging();
+            let state = export_state(&engine.db)?;
+            store.save_snapshot(state, 0)?;
+            store.save_conflicts(&[])?;
+            if commit {
+                store.commit()?;
+            }
+            Ok(())
+        };
+        let clients = || -> Result<usize> {
+            let state = DirStore::new(&dir, None).load()?;
+            Ok(state.map_or(0, |(state, _)| state.accounts.len()))
+        };
+        save(Transaction::deposit(1, 1, 1.0), true)?;
+        assert!(!dir.join(STAGING_DIR).exists());
+        assert_eq!(clients()?, 1);
+        // A run that never committed is rolled back.
+        save(Transaction::deposit(2, 2, 1.0), false)?;
+        assert_eq!(clients()?, 1);
+        assert!(!dir.join(STAGING_DIR).exists());
+        // A commit interrupted after its marker is finished.
+        save(Transaction::deposit(2, 2, 1.0), false)?;
+        fs::write(dir.join(STAGING_DIR).join(COMMITTED), "").map_err(|x| x.to_string())?;
+        assert_eq!(clients()?, 2);
+        fs::remove_dir_all(&dir).map_err(|x| x.to_string())?;
+        Ok(())
+    }
+}
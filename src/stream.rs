@@ -0,0 +1,191 @@
+use crate::{
+    errors::ErrorLog,
+    inputs::schema_for,
+    schema::{line_record as record, Schema},
+    Engine, PaymentsEngineError, RecordError, Result, RunOptions, RunSummary, Transaction,
+    TransactionOutcome,
+};
+use futures::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    stream, Stream, StreamExt,
+};
+
+impl Engine {
+    /// Processes transactions as they arrive on an async stream, such as a
+    /// Kafka or Kinesis consumer, yielding the outcome of each.
+    ///
+    /// Like [`Engine::process_reader`], a transaction is only taken from
+    /// `transactions` when the next outcome is polled for. Applying it is
+    /// quick and doesn't block on anything but the client's own entry, so
+    /// it is done inline rather than on a blocking thread.
+    pub fn process_stream<'a>(
+        &'a self,
+        transactions: impl Stream<Item = Transaction> + 'a,
+    ) -> impl Stream<Item = Result<TransactionOutcome>> + 'a {
+        transactions.map(move |txn| self.process(txn))
+    }
+}
+
+/// The transactions of the csv read from `input`, its header first, as
+/// they arrive, parsed as [`crate::inputs::parse_csv`] parses a file. The
+/// reader can be anything async, such as an HTTP body, an object store
+/// download or a socket; tokio's readers adapt to it with `tokio-util`'s
+/// `compat`. Rows are read a line at a time, so a quoted field can't span
+/// lines. A row that can't be parsed comes out as a
+/// [`PaymentsEngineError::Record`] and the stream goes on, an unreadable
+/// header or input ends it.
+pub fn csv_stream<'a>(
+    input: impl AsyncRead + Unpin + 'a,
+    options: &RunOptions,
+) -> impl Stream<Item = Result<Transaction>> + 'a {
+    let options = options.clone();
+    let lines = BufReader::new(input).lines();
+    let state = (lines, None::<Schema>, 0, false);
+    stream::unfold(state, move |(mut lines, mut schema, mut row, ended)| {
+        let options = options.clone();
+        async move {
+            if ended {
+                return None;
+            }
+            loop {
+                let line = match lines.next().await? {
+                    Ok(line) if line.trim().is_empty() => continue,
+                    Ok(line) => line,
+                    Err(x) => {
+                        let error = format!("error reading the input: {}", x).into();
+                        return Some((Err(error), (lines, schema, row, true)));
+                    }
+                };
+                let Some(parser) = &schema else {
+                    match record(&line).and_then(|headers| schema_for(&headers, &options)) {
+                        Ok(parser) => schema = Some(parser),
+                        Err(x) => return Some((Err(x), (lines, schema, row, true))),
+                    }
+                    continue;
+                };
+                row += 1;
+                let txn = record(&line)
+                    .and_then(|record| parser.parse(&record))
+                    .map_err(|error| {
+                        PaymentsEngineError::Record(Box::new(RecordError {
+                            input: None,
+                            row,
+                            error,
+                        }))
+                    });
+                return Some((txn, (lines, schema, row, false)));
+            }
+        }
+    })
+}
+
+/// Runs the transactions of an async stream through `engine` as they
+/// arrive, counting them in the summary like [`crate::run_transactions`]
+/// does. A row that can't be parsed is skipped and reported as
+/// `options.errors` says, or fails the run with `options.mode` strict.
+/// Limits, sampling, threads, snapshots and outcome files are only
+/// applied to batch runs.
+pub async fn run_transactions_async(
+    transactions: impl Stream<Item = Result<Transaction>>,
+    engine: &Engine,
+    options: &RunOptions,
+) -> Result<RunSummary> {
+    let mut summary = RunSummary::default();
+    let mut skipped = ErrorLog::open(options.errors.as_deref())?;
+    let mut transactions = std::pin::pin!(transactions);
+    while let Some(txn) = transactions.next().await {
+        summary.rows += 1;
+        match txn {
+            Ok(txn) => {
+                summary.processed += 1;
+                match engine.process(txn)? {
+                    TransactionOutcome::Applied => {}
+                    TransactionOutcome::Rejected(_) => summary.rejected += 1,
+                    TransactionOutcome::Duplicate => summary.duplicates += 1,
+                }
+            }
+            Err(PaymentsEngineError::Record(error)) => {
+                options.mode.check(&error)?;
+                summary.errors += 1;
+                skipped.skip(&error)?;
+            }
+            Err(x) => return Err(x),
+        }
+    }
+    skipped.finish()?;
+    Ok(summary)
+}
+
+/// Runs the csv read from an async reader through `engine`, see
+/// [`csv_stream`] and [`run_transactions_async`], so the engine can sit
+/// inside an async service taking files over the network.
+pub async fn run_engine_async(
+    input: impl AsyncRead + Unpin,
+    engine: &Engine,
+    options: &RunOptions,
+) -> Result<RunSummary> {
+    run_transactions_async(csv_stream(input, options), engine, options).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::i18n::Reason;
+    use futures::{executor::block_on, io::Cursor, stream};
+
+    #[test]
+    fn stream_yields_each_outcome() -> Result<()> {
+        let engine = Engine::default();
+        let transactions = stream::iter([
+            Transaction::deposit(1, 1, 2.0),
+            Transaction::withdrawal(1, 2, 3.0),
+            Transaction::dispute(1, 1),
+        ]);
+        let outcomes: Vec<_> = block_on(engine.process_stream(transactions).collect::<Vec<_>>())
+            .into_iter()
+            .collect::<Result<_>>()?;
+        assert_eq!(
+            outcomes,
+            vec![
+                TransactionOutcome::Applied,
+                TransactionOutcome::Rejected(Reason::InsufficientFunds),
+                TransactionOutcome::Applied,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn csv_is_read_from_an_async_reader() -> Result<()> {
+        let input = "client, type,tx,amount\r\n1,deposit,1,2.0\n\n1,withdrawal,2,3.0\n1,refund,3,1.0\n1,dispute,1,";
+        let engine = Engine::default();
+        let summary = block_on(run_engine_async(
+            Cursor::new(input.as_bytes()),
+            &engine,
+            &RunOptions::default(),
+        ))?;
+        assert_eq!(
+            (
+                summary.rows,
+                summary.processed,
+                summary.rejected,
+                summary.errors
+            ),
+            (4, 3, 1, 1)
+        );
+        let account = engine.account(1)?.ok_or("no account")?;
+        assert_eq!((account.available(), account.held()), (0.0, 2.0));
+
+        let strict = RunOptions {
+            mode: crate::validation::Mode::Strict,
+            ..RunOptions::default()
+        };
+        let failed = block_on(run_engine_async(
+            Cursor::new(input.as_bytes()),
+            &Engine::default(),
+            &strict,
+        ));
+        assert!(failed.is_err_and(|x| x.to_string().contains("row 3")));
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use crate::{digest::state_digest, ClientId, Engine, Result, Transaction};
+use std::{sync::Barrier, thread};
+
+/// A workload hammering a few clients of one engine from many threads at
+/// once, and what must hold once every thread is done.
+///
+/// New scenarios go in [`SCENARIOS`], which the tests run.
+pub struct Scenario {
+    pub name: &'static str,
+    pub threads: usize,
+    /// Steps each thread takes.
+    pub rounds: usize,
+    /// Deposits made before the threads start.
+    pub setup: fn(&Engine) -> Result<()>,
+    /// The transaction thread `thread` sends at step `round`, with an id
+    /// unique to both.
+    pub step: fn(thread: usize, round: usize, txn_id: u32) -> Transaction,
+    /// Checks the engine once every thread is done.
+    pub check: fn(&Engine, &Scenario) -> Result<()>,
+}
+
+/// The id of the transaction thread `thread` sends at step `round`, above
+/// those of the setup.
+fn txn_id(scenario: &Scenario, thread: usize, round: usize) -> u32 {
+    (1_000 + thread * scenario.rounds + round) as u32
+}
+
+/// Runs `scenario` on a fresh engine: every thread waits for the others,
+/// then sends its transactions as fast as it can. Fails if a thread does,
+/// if the check does, or if the digest kept as transactions are applied
+/// no longer matches the state.
+pub fn run(scenario: &Scenario) -> Result<Engine> {
+    let engine = Engine::default();
+    (scenario.setup)(&engine)?;
+    let start = Barrier::new(scenario.threads);
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..scenario.threads)
+            .map(|thread| {
+                let (engine, start) = (&engine, &start);
+                scope.spawn(move || -> Result<()> {
+                    start.wait();
+                    for round in 0..scenario.rounds {
+                        let id = txn_id(scenario, thread, round);
+                        engine.process((scenario.step)(thread, round, id))?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("stress thread panicked"))
+    })?;
+    (scenario.check)(&engine, scenario)?;
+    if engine.db.digest() != state_digest(&engine.db)? {
+        return Err(format!("{}: the state digest drifted", scenario.name).into());
+    }
+    Ok(engine)
+}
+
+/// The available and held funds of `client`.
+fn balances(engine: &Engine, client: ClientId) -> (f64, f64) {
+    engine
+        .db
+        .clients
+        .get(&client)
+        .map_or((0.0, 0.0), |client| (client.available, client.held))
+}
+
+fn expect(scenario: &Scenario, what: &str, found: f64, expected: f64) -> Result<()> {
+    if found != expected {
+        return Err(format!(
+            "{}: {} is {} instead of {}, an update was lost",
+            scenario.name, what, found, expected
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Whole amounts throughout, so sums are exact in any order.
+pub const SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "deposits to one client",
+        threads: 8,
+        rounds: 500,
+        setup: |_| Ok(()),
+        step: |_, _, txn_id| Transaction::deposit(1, txn_id, 1.0),
+        check: |engine, scenario| {
+            let deposited = (scenario.threads * scenario.rounds) as f64;
+            expect(scenario, "available", balances(engine, 1).0, deposited)
+        },
+    },
+    Scenario {
+        name: "deposits and withdrawals on one client",
+        threads: 8,
+        rounds: 500,
+        setup: |engine| {
+            engine.process(Transaction::deposit(1, 1, 1_000_000.0))?;
+            Ok(())
+        },
+        step: |thread, _, txn_id| match thread % 2 {
+            0 => Transaction::deposit(1, txn_id, 3.0),
+            _ => Transaction::withdrawal(1, txn_id, 2.0),
+        },
+        check: |engine, scenario| {
+            let pairs = (scenario.threads / 2 * scenario.rounds) as f64;
+            let expected = 1_000_000.0 + pairs * (3.0 - 2.0);
+            expect(scenario, "available", balances(engine, 1).0, expected)
+        },
+    },
+    Scenario {
+        name: "transfers back and forth",
+        threads: 8,
+        rounds: 300,
+        setup: |engine| {
+            engine.process(Transaction::deposit(1, 1, 10_000.0))?;
+            engine.process(Transaction::deposit(2, 2, 10_000.0))?;
+            Ok(())
+        },
+        step: |thread, _, txn_id| match thread % 2 {
+            0 => Transaction::transfer(1, txn_id, 2, 1.0),
+            _ => Transaction::transfer(2, txn_id, 1, 1.0),
+        },
+        check: |engine, scenario| {
+            expect(scenario, "client 1", balances(engine, 1).0, 10_000.0)?;
+            expect(scenario, "client 2", balances(engine, 2).0, 10_000.0)
+        },
+    },
+    Scenario {
+        name: "disputes racing deposits",
+        threads: 4,
+        rounds: 400,
+        setup: |engine| {
+            for txn_id in 1..=400 {
+                engine.process(Transaction::deposit(1, txn_id, 1.0))?;
+            }
+            Ok(())
+        },
+        // Half the threads dispute and resolve the setup's deposits, the
+        // other half deposit more.
+        step: |thread, round, txn_id| match (thread % 2, round % 2) {
+            (0, 0) => Transaction::dispute(1, (round / 2 * 2 + thread / 2 + 1) as u32),
+            (0, _) => Transaction::resolve(1, (round / 2 * 2 + thread / 2 + 1) as u32),
+            _ => Transaction::deposit(1, txn_id, 1.0),
+        },
+        check: |engine, scenario| {
+            let deposited = 400.0 + (scenario.threads / 2 * scenario.rounds) as f64;
+            let (available, held) = balances(engine, 1);
+            expect(scenario, "held", held, 0.0)?;
+            expect(scenario, "available", available, deposited)
+        },
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_update_is_lost_under_contention() -> Result<()> {
+        for scenario in SCENARIOS {
+            run(scenario)?;
+        }
+        Ok(())
+    }
+}
+
+/// A model of the locking protocol of [`Engine::process`], checked by loom
+/// over every interleaving: transactions of one client share the batch
+/// lock and take their client's lock, a transfer takes the batch lock
+/// exclusively and then both clients'. Run with
+/// `RUSTFLAGS="--cfg loom" cargo test --release --lib stress::model`.
+#[cfg(all(test, loom))]
+mod model {
+    use loom::{
+        sync::{Arc, Mutex, RwLock},
+        thread,
+    };
+
+    struct Model {
+        batch: RwLock<()>,
+        clients: [Mutex<i64>; 2],
+    }
+
+    impl Model {
+        fn deposit(&self, client: usize, amount: i64) {
+            let _shared = self.batch.read().unwrap();
+            *self.clients[client].lock().unwrap() += amount;
+        }
+
+        fn transfer(&self, from: usize, to: usize, amount: i64) {
+            let _exclusive = self.batch.write().unwrap();
+            let mut source = self.clients[from].lock().unwrap();
+            if *source >= amount {
+                *source -= amount;
+                drop(source);
+                *self.clients[to].lock().unwrap() += amount;
+            }
+        }
+
+        fn total(&self) -> i64 {
+            let _exclusive = self.batch.write().unwrap();
+            self.clients.iter().map(|c| *c.lock().unwrap()).sum()
+        }
+    }
+
+    #[test]
+    fn transfers_and_deposits_neither_lose_funds_nor_deadlock() {
+        loom::model(|| {
+            let model = Arc::new(Model {
+                batch: RwLock::new(()),
+                clients: [Mutex::new(5), Mutex::new(5)],
+            });
+            let threads = [
+                thread::spawn({
+                    let model = model.clone();
+                    move || model.transfer(0, 1, 3)
+                }),
+                thread::spawn({
+                    let model = model.clone();
+                    move || model.transfer(1, 0, 4)
+                }),
+                thread::spawn({
+                    let model = model.clone();
+                    move || model.deposit(0, 1)
+                }),
+            ];
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(model.total(), 11);
+        });
+    }
+}
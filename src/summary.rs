@@ -0,0 +1,129 @@
+use crate::{Engine, Result, RunSummary};
+use serde::Serialize;
+use std::{collections::BTreeMap, time::Duration};
+
+/// The exit status of a run that handled every row it was handed.
+pub const EXIT_CLEAN: u8 = 0;
+/// The exit status of a run that got to the end, but rejected or skipped
+/// rows, or was stopped early by a limit.
+pub const EXIT_REJECTED: u8 = 1;
+/// The exit status of a run that failed, whatever it got through before.
+pub const EXIT_FATAL: u8 = 2;
+
+/// The exit status of a run that ended with `summary`.
+pub fn exit_status(summary: &RunSummary) -> u8 {
+    match summary.rejected > 0 || summary.errors > 0 || summary.stopped.is_some() {
+        true => EXIT_REJECTED,
+        false => EXIT_CLEAN,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+/// How long a run took and how fast it went.
+pub struct Runtime {
+    pub seconds: f64,
+    pub rows_per_second: f64,
+    /// See [`crate::metrics::Metrics::estimated_memory_bytes`].
+    pub estimated_memory_bytes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+/// What a batch run did, written with `--summary-json` for orchestration
+/// systems to act on without parsing logs.
+pub struct JsonSummary {
+    /// The status the process exits with, see [`exit_status`].
+    pub exit_code: u8,
+    pub rows: u64,
+    pub processed: u64,
+    pub rejected: u64,
+    pub duplicates: u64,
+    /// Rows that couldn't be parsed or broke a consistency rule.
+    pub skipped: u64,
+    /// Why the run stopped early, if a limit stopped it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stopped: Option<String>,
+    /// The rejected transactions by reason code.
+    pub rejections: BTreeMap<&'static str, u64>,
+    /// Every account, those evicted to the cold store included.
+    pub accounts: usize,
+    pub locked_accounts: usize,
+    pub open_disputes: usize,
+    pub runtime: Runtime,
+}
+
+impl JsonSummary {
+    /// The summary of a run of `engine` that took `elapsed` and ended with
+    /// `summary`.
+    pub fn new(engine: &Engine, summary: &RunSummary, elapsed: Duration) -> Result<Self> {
+        let metrics = engine.metrics()?;
+        let seconds = elapsed.as_secs_f64();
+        Ok(JsonSummary {
+            exit_code: exit_status(summary),
+            rows: summary.rows,
+            processed: summary.processed,
+            rejected: summary.rejected,
+            duplicates: summary.duplicates,
+            skipped: summary.errors,
+            stopped: summary.stopped.map(|stopped| stopped.reason.to_string()),
+            rejections: metrics
+                .rejected
+                .iter()
+                .filter(|(_, count)| *count > 0)
+                .map(|(reason, count)| (reason.code(), *count))
+                .collect(),
+            accounts: metrics.accounts + metrics.cold_accounts,
+            locked_accounts: metrics.locked_accounts,
+            open_disputes: metrics.open_disputes,
+            runtime: Runtime {
+                seconds,
+                rows_per_second: match seconds > 0.0 {
+                    true => summary.rows as f64 / seconds,
+                    false => 0.0,
+                },
+                estimated_memory_bytes: metrics.estimated_memory_bytes,
+            },
+        })
+    }
+
+    /// Writes the summary to `path` as pretty JSON.
+    pub fn write(&self, path: &str) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(|x| x.to_string())?;
+        std::fs::write(path, json + "\n")
+            .map_err(|x| format!("error writing summary {}: {}", path, x).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, run_transactions, RunOptions, Transaction};
+
+    #[test]
+    fn rejections_make_the_run_exit_with_1() -> Result<()> {
+        let engine = Engine::with_clock(SimulatedClock::new(100));
+        let batch = [
+            Transaction::deposit(1, 1, 10.0),
+            Transaction::deposit(2, 2, 5.0),
+            Transaction::dispute(2, 2),
+            Transaction::chargeback(2, 2),
+        ];
+        let summary = run_transactions(batch.into_iter().map(Ok), &engine, &RunOptions::default())?;
+        assert_eq!(exit_status(&summary), EXIT_CLEAN);
+
+        let batch = [
+            Transaction::withdrawal(1, 3, 50.0),
+            Transaction::deposit(2, 4, 1.0),
+        ];
+        let summary = run_transactions(batch.into_iter().map(Ok), &engine, &RunOptions::default())?;
+        let json = JsonSummary::new(&engine, &summary, Duration::from_secs(1))?;
+        assert_eq!(json.exit_code, EXIT_REJECTED);
+        assert_eq!((json.processed, json.rejected), (2, 2));
+        assert_eq!(
+            json.rejections,
+            BTreeMap::from([("account_locked", 1), ("insufficient_funds", 1)])
+        );
+        assert_eq!((json.accounts, json.locked_accounts), (2, 1));
+        assert_eq!(json.runtime.rows_per_second, 2.0);
+        Ok(())
+    }
+}
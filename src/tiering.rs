@@ -0,0 +1,329 @@
+use crate::{clock::Timestamp, currency::Balance, locked::Lock, Client, ClientId, Result};
+use std::{
+    fmt::Debug,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// How often, in input rows, the batch run sweeps for inactive clients.
+pub const SWEEP_EVERY_ROWS: u64 = 10_000;
+
+/// How long a client stays in memory without a transaction by default.
+pub const DEFAULT_EVICT_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Where the state of inactive clients is kept while it isn't in memory.
+pub trait ColdStore: Debug + Send + Sync {
+    /// Stores a client that is being evicted from memory.
+    fn store(&self, client_id: ClientId, client: &Client) -> Result<()>;
+    /// Reads a client, leaving it in the store.
+    fn get(&self, client_id: ClientId) -> Result<Option<Client>>;
+    /// Loads a client back, removing it from the store.
+    fn load(&self, client_id: ClientId) -> Result<Option<Client>>;
+    /// Visits every client in the store, without removing them.
+    fn for_each(&self, f: &mut dyn FnMut(ClientId, &Client)) -> Result<()>;
+}
+
+#[derive(Debug)]
+/// Evicts clients which have been inactive for a while to a [`ColdStore`].
+///
+/// Most clients go quiet after their first few transactions, so keeping
+/// only the active ones in memory bounds the resident size of the engine
+/// by activity rather than by the total number of accounts.
+pub struct Tiering {
+    pub store: Box<dyn ColdStore>,
+    /// Clients without a transaction for this long are evicted.
+    pub evict_after: Duration,
+}
+
+impl Tiering {
+    /// The oldest activity a client may have at `now` and stay in memory.
+    pub fn cutoff(&self, now: Timestamp) -> Timestamp {
+        now.saturating_sub(self.evict_after.as_secs())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// How much of a cold store is loaded into memory at startup.
+///
+/// Loading more makes startup slower, but spares the first transactions
+/// of each preloaded client the trip to the cold store.
+pub enum Preload {
+    /// Load clients only when they transact.
+    #[default]
+    None,
+    /// Load the clients that were still active when the store was last
+    /// written, those that wouldn't have been evicted yet.
+    Hot,
+    /// Load every client.
+    All,
+}
+
+#[derive(Debug)]
+/// A cold store keeping one small file per client in a directory.
+pub struct DirColdStore {
+    dir: PathBuf,
+}
+
+impl DirColdStore {
+    /// Uses `dir` as the store, creating it if needed. Clients already in
+    /// the directory are part of the store.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .map_err(|x| format!("error creating cold store {}: {}", dir.display(), x))?;
+        Ok(DirColdStore { dir })
+    }
+
+    fn path(&self, client_id: ClientId) -> PathBuf {
+        self.dir.join(format!("{}.client", client_id))
+    }
+}
+
+/// Encodes a client as a single line. Balances are written as their bit
+/// patterns so they come back exactly as they were.
+fn encode(client: &Client) -> String {
+    let mut disputed: Vec<_> = client
+        .disputed
+        .iter()
+        .map(|(txn_id, opened)| format!("{}@{}", txn_id, opened))
+        .collect();
+    disputed.sort();
+    let lock = match &client.lock {
+        Some(lock) => format!(
+            "{}@{}@{}",
+            lock.reason.as_str(),
+            lock.txn_id,
+            lock.timestamp
+        ),
+        None => "-".to_string(),
+    };
+    let pending: Vec<_> = client
+        .pending_holds
+        .iter()
+        .map(|(txn_id, pending)| format!("{}@{:016x}", txn_id, pending.to_bits()))
+        .collect();
+    let currencies: Vec<_> = client
+        .currencies
+        .iter()
+        .map(|(currency, balance)| {
+            format!(
+                "{}@{:016x}@{:016x}",
+                currency,
+                balance.available.to_bits(),
+                balance.held.to_bits()
+            )
+        })
+        .collect();
+    let mut settled: Vec<_> = client
+        .settled
+        .iter()
+        .map(|(txn_id, state)| format!("{}@{}", txn_id, state.as_str()))
+        .collect();
+    settled.sort();
+    let queued: Vec<_> = client.queued.iter().map(u32::to_string).collect();
+    let mut disputed_amounts: Vec<_> = client
+        .disputed_amounts
+        .iter()
+        .map(|(txn_id, amount)| format!("{}@{:016x}", txn_id, amount.to_bits()))
+        .collect();
+    disputed_amounts.sort();
+    format!(
+        "{:016x} {:016x} {} {} {} {} {} {} {} {} {} {}",
+        client.available.to_bits(),
+        client.held.to_bits(),
+        client.locked,
+        client.last_activity,
+        disputed.join(","),
+        lock,
+        client.closed,
+        pending.join(","),
+        currencies.join(","),
+        settled.join(","),
+        queued.join(","),
+        disputed_amounts.join(",")
+    )
+}
+
+fn decode(line: &str) -> Option<Client> {
+    let mut fields = line.trim_end_matches('\n').split(' ');
+    let bits = |x: &str| u64::from_str_radix(x, 16).ok().map(f64::from_bits);
+    Some(Client {
+        available: bits(fields.next()?)?,
+        held: bits(fields.next()?)?,
+        locked: fields.next()?.parse().ok()?,
+        last_activity: fields.next()?.parse().ok()?,
+        disputed: fields
+            .next()?
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                let (txn_id, opened) = x.split_once('@')?;
+                Some((txn_id.parse().ok()?, opened.parse().ok()?))
+            })
+            .collect::<Option<_>>()?,
+        lock: match fields.next()? {
+            "-" => None,
+            lock => {
+                let mut parts = lock.split('@');
+                Some(Lock {
+                    reason: parts.next()?.parse().ok()?,
+                    txn_id: parts.next()?.parse().ok()?,
+                    timestamp: parts.next()?.parse().ok()?,
+                })
+            }
+        },
+        closed: fields.next()?.parse().ok()?,
+        // Missing from clients evicted before there were pending holds.
+        pending_holds: fields
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                let (txn_id, pending) = x.split_once('@')?;
+                Some((txn_id.parse().ok()?, bits(pending)?))
+            })
+            .collect::<Option<_>>()?,
+        currencies: fields
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                let mut parts = x.split('@');
+                let currency = parts.next()?.into();
+                let available = bits(parts.next()?)?;
+                let held = bits(parts.next()?)?;
+                Some((currency, Balance { available, held }))
+            })
+            .collect::<Option<_>>()?,
+        settled: fields
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                let (txn_id, state) = x.split_once('@')?;
+                Some((txn_id.parse().ok()?, state.parse().ok()?))
+            })
+            .collect::<Option<_>>()?,
+        queued: fields
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .map(|x| x.parse().ok())
+            .collect::<Option<_>>()?,
+        disputed_amounts: fields
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                let (txn_id, amount) = x.split_once('@')?;
+                Some((txn_id.parse().ok()?, bits(amount)?))
+            })
+            .collect::<Option<_>>()?,
+    })
+}
+
+impl DirColdStore {
+    fn read(&self, client_id: ClientId, path: &Path) -> Result<Client> {
+        let line = fs::read_to_string(path)
+            .map_err(|x| format!("error reading cold client {}: {}", client_id, x))?;
+        Ok(decode(&line).ok_or_else(|| format!("cold client {} is corrupt", client_id))?)
+    }
+}
+
+impl ColdStore for DirColdStore {
+    fn store(&self, client_id: ClientId, client: &Client) -> Result<()> {
+        fs::write(self.path(client_id), encode(client))
+            .map_err(|x| format!("error evicting client {}: {}", client_id, x))?;
+        Ok(())
+    }
+
+    fn get(&self, client_id: ClientId) -> Result<Option<Client>> {
+        let path = self.path(client_id);
+        if !path.exists() {
+            return Ok(None);
+        }
+        self.read(client_id, &path).map(Some)
+    }
+
+    fn load(&self, client_id: ClientId) -> Result<Option<Client>> {
+        let client = self.get(client_id)?;
+        if client.is_some() {
+            fs::remove_file(self.path(client_id))
+                .map_err(|x| format!("error loading cold client {}: {}", client_id, x))?;
+        }
+        Ok(client)
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(ClientId, &Client)) -> Result<()> {
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|x| format!("error listing cold store {}: {}", self.dir.display(), x))?;
+        for entry in entries {
+            let path = entry.map_err(|x| x.to_string())?.path();
+            let client_id = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".client"))
+                .and_then(|id| id.parse::<ClientId>().ok());
+            if let Some(client_id) = client_id {
+                f(client_id, &self.read(client_id, &path)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{disputes::TxnState, locked::LockReason};
+
+    #[test]
+    fn clients_round_trip_exactly() -> Result<()> {
+        let dir = crate::temp_path("payments-engine-cold-store-test");
+        let _ = fs::remove_dir_all(&dir);
+        let store = DirColdStore::open(&dir)?;
+        let client = Client {
+            available: 0.1 + 0.2,
+            held: 3.5,
+            locked: true,
+            disputed: [(4, 10), (2, 0)].into_iter().collect(),
+            settled: [(1, TxnState::ChargedBack), (3, TxnState::Resolved)]
+                .into_iter()
+                .collect(),
+            lock: Some(Lock {
+                reason: LockReason::Chargeback,
+                txn_id: 4,
+                timestamp: 12,
+            }),
+            closed: false,
+            pending_holds: vec![(4, 0.5)],
+            queued: vec![6, 5],
+            disputed_amounts: [(4, 1.5)].into_iter().collect(),
+            currencies: [(
+                "EUR".into(),
+                Balance {
+                    available: 1.25,
+                    held: 0.0,
+                },
+            )]
+            .into_iter()
+            .collect(),
+            last_activity: 99,
+        };
+        store.store(7, &client)?;
+        let mut seen = Vec::new();
+        store.for_each(&mut |client_id, _| seen.push(client_id))?;
+        assert_eq!(seen, vec![7]);
+        assert_eq!(store.get(7)?.as_ref(), Some(&client));
+        assert_eq!(store.load(7)?, Some(client));
+        assert_eq!(store.load(7)?, None);
+        Ok(())
+    }
+}
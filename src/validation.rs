@@ -0,0 +1,186 @@
+use crate::{PaymentsEngineError, RecordError, Result, Transaction, TransactionType};
+use std::{collections::HashSet, fmt};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Which relationships between the fields of a row, and between rows of
+/// the same input, are enforced as it is read.
+pub struct Consistency {
+    /// Lets resolves and chargebacks carry an amount, which the engine
+    /// ignores as they settle what their dispute held. Without it such a
+    /// row is an error, as the partner probably meant to settle a part of
+    /// a dispute, which the engine doesn't support. The amount of a dispute
+    /// is what it holds, see [`crate::handle_transaction`].
+    pub allow_dispute_amounts: bool,
+    /// Disputes, resolves and chargebacks must refer to a deposit or
+    /// withdrawal earlier in the same input.
+    pub require_intra_file_refs: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+/// What a run does with a row that can't be parsed or breaks a
+/// [`Consistency`] rule.
+pub enum Mode {
+    /// Skips the row and goes on. The rows skipped are counted and listed,
+    /// see [`crate::errors::ErrorLog`].
+    #[default]
+    Lenient,
+    /// Stops the run at the row, failing with its row number.
+    Strict,
+}
+
+impl Mode {
+    /// Fails with `error` in strict mode, so the run stops at its row.
+    pub fn check(&self, error: &RecordError) -> Result<()> {
+        match self {
+            Mode::Lenient => Ok(()),
+            Mode::Strict => Err(format!("stopped at an invalid row, {}", error).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A row breaking one of the [`Consistency`] rules.
+pub enum Inconsistency {
+    DisputeAmount(TransactionType),
+    ReferenceNotInFile(TransactionType, u32),
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::DisputeAmount(transaction_type) => write!(
+                f,
+                "{} rows must have an empty amount",
+                transaction_type.as_str()
+            ),
+            Inconsistency::ReferenceNotInFile(transaction_type, txn_id) => write!(
+                f,
+                "{} of transaction {}, which is not earlier in the same input",
+                transaction_type.as_str(),
+                txn_id
+            ),
+        }
+    }
+}
+
+/// Checks the rows of one input against the [`Consistency`] rules, in
+/// order.
+#[derive(Debug)]
+pub struct Checker {
+    rules: Consistency,
+    /// The deposits and withdrawals seen so far, when references must stay
+    /// within the input.
+    seen: HashSet<u32>,
+}
+
+impl Checker {
+    pub fn new(rules: Consistency) -> Self {
+        Checker {
+            rules,
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn check(&mut self, txn: &Transaction) -> Option<Inconsistency> {
+        match txn.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                if self.rules.require_intra_file_refs {
+                    self.seen.insert(txn.txn_id);
+                }
+                None
+            }
+            TransactionType::Transfer => None,
+            transaction_type if transaction_type.is_admin() => None,
+            // A dispute's amount is the part of the transaction it holds.
+            transaction_type
+                if transaction_type != TransactionType::Dispute
+                    && txn.amount.is_some()
+                    && !self.rules.allow_dispute_amounts =>
+            {
+                Some(Inconsistency::DisputeAmount(transaction_type))
+            }
+            transaction_type
+                if self.rules.require_intra_file_refs && !self.seen.contains(&txn.txn_id) =>
+            {
+                Some(Inconsistency::ReferenceNotInFile(
+                    transaction_type,
+                    txn.txn_id,
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The transactions of the input named `input`, with every row that
+/// can't be parsed or breaks a rule of `rules` failing as a
+/// [`RecordError`] with its name and row number. Failures to read the
+/// input are passed on as they are.
+pub fn checked<'a>(
+    transactions: impl Iterator<Item = Result<Transaction>> + 'a,
+    rules: Consistency,
+    input: &str,
+) -> impl Iterator<Item = Result<Transaction>> + 'a {
+    let input = input.to_string();
+    let mut checker = Checker::new(rules);
+    transactions.enumerate().map(move |(i, txn)| {
+        let located = |error| {
+            PaymentsEngineError::Record(Box::new(RecordError {
+                input: Some(input.clone()),
+                row: i as u64 + 1,
+                error,
+            }))
+        };
+        match txn {
+            Err(x @ PaymentsEngineError::IoError(_)) => Err(x),
+            Err(x) => Err(located(x)),
+            Ok(txn) => match checker.check(&txn) {
+                Some(inconsistency) => {
+                    Err(located(PaymentsEngineError::Inconsistent(inconsistency)))
+                }
+                None => Ok(txn),
+            },
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_are_checked_against_each_other() {
+        let rows = || {
+            [
+                Transaction::deposit(1, 1, 5.0),
+                Transaction {
+                    amount: Some(2.0),
+                    ..Transaction::dispute(1, 1)
+                },
+                Transaction {
+                    amount: Some(2.0),
+                    ..Transaction::resolve(1, 1)
+                },
+                Transaction::resolve(1, 7),
+            ]
+        };
+        let errors = |rules| -> Vec<String> {
+            checked(rows().into_iter().map(Ok), rules, "in.csv")
+                .filter_map(|txn| txn.err().map(|x| x.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            errors(Consistency::default()),
+            ["in.csv, row 3: resolve rows must have an empty amount"]
+        );
+        let rules = Consistency {
+            allow_dispute_amounts: true,
+            require_intra_file_refs: true,
+        };
+        assert_eq!(
+            errors(rules),
+            ["in.csv, row 4: resolve of transaction 7, which is not earlier in the same input"]
+        );
+    }
+}
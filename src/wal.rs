@@ -0,0 +1,209 @@
+use crate::{schema::Schema, Engine, Result, Transaction};
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+/// The columns of a write-ahead log: the input row each transaction came
+/// from, whether the engine generated it, then those of the input csv.
+const HEADERS: [&str; 12] = [
+    "row",
+    "internal",
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "timestamp",
+    "memo",
+    "to_client",
+    "currency",
+    "category",
+    "idempotency_key",
+];
+
+/// A log every transaction is appended to before the engine applies it,
+/// see [`Engine::with_wal`], so a process killed mid-run can get back the
+/// state it had with [`recover`].
+///
+/// Each entry is flushed to the file before its transaction is applied,
+/// so it survives the process being killed, though not the machine losing
+/// power before the system writes it out. The log is emptied with
+/// [`Wal::commit`] once the state its entries led to is saved.
+pub struct Wal {
+    path: PathBuf,
+    writer: csv::Writer<File>,
+    /// The input row the transactions being appended come from, if they
+    /// come from an input.
+    row: Option<u64>,
+}
+
+impl fmt::Debug for Wal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Wal")
+            .field("path", &self.path)
+            .field("row", &self.row)
+            .finish()
+    }
+}
+
+impl Wal {
+    /// Opens the log at `path` to append to, creating it if needed. The
+    /// entries already in it stay, for [`recover`] to find them again
+    /// should the process be killed before the next commit.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let error = |x: std::io::Error| format!("error opening {}: {}", path.display(), x);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(error)?;
+        let empty = file.metadata().map_err(error)?.len() == 0;
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(file);
+        if empty {
+            writer.write_record(HEADERS)?;
+            writer.flush().map_err(error)?;
+        }
+        Ok(Wal {
+            path,
+            writer,
+            row: None,
+        })
+    }
+
+    /// Creates (or empties) the log at `path`.
+    pub fn create(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        File::create(&path).map_err(|x| format!("error creating {}: {}", path.display(), x))?;
+        Wal::open(path)
+    }
+
+    /// Tags the transactions appended from now on with the input row they
+    /// come from, the rows of the input handled with them included.
+    pub fn at_row(&mut self, row: u64) {
+        self.row = Some(row);
+    }
+
+    /// Appends `txn` and flushes it to the file.
+    pub fn append(&mut self, txn: &Transaction, internal: bool) -> Result<()> {
+        let optional = |value: Option<&str>| value.unwrap_or("").to_string();
+        self.writer.write_record([
+            self.row.map_or(String::new(), |row| row.to_string()),
+            internal.to_string(),
+            txn.transaction_type.as_str().to_string(),
+            txn.client_id.to_string(),
+            txn.txn_id.to_string(),
+            txn.amount
+                .map_or(String::new(), |amount| amount.to_string()),
+            txn.timestamp.map_or(String::new(), |ts| ts.to_string()),
+            optional(txn.memo.as_deref()),
+            txn.to_client
+                .map_or(String::new(), |client| client.to_string()),
+            optional(txn.currency.as_deref()),
+            optional(txn.category.as_deref()),
+            optional(txn.idempotency_key.as_deref()),
+        ])?;
+        self.writer
+            .flush()
+            .map_err(|x| format!("error writing {}: {}", self.path.display(), x).into())
+    }
+
+    /// Empties the log, once the state its entries led to was saved or
+    /// the run they belong to is over.
+    pub fn commit(&mut self) -> Result<()> {
+        *self = Wal::create(&self.path)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+/// What [`recover`] replayed.
+pub struct Recovered {
+    /// The transactions replayed.
+    pub entries: u64,
+    /// The input rows handled by the last of them, for the run to carry on
+    /// after, like [`crate::RunOptions::resume_after`]. 0 if they didn't
+    /// come from an input.
+    pub rows: u64,
+}
+
+/// Replays the transactions of the write-ahead log at `path` into
+/// `engine`, which holds the state the log was last committed on, in the
+/// order they were applied. Those of input rows up to `after`, already
+/// part of a snapshot the engine was restored from, are skipped. `engine`
+/// mustn't log to `path` itself yet.
+///
+/// A last entry cut short by the process being killed as it was written
+/// was never applied, so it is left out.
+pub fn recover(path: &Path, engine: &Engine, after: u64) -> Result<Recovered> {
+    let mut log = match std::fs::read(path) {
+        Ok(log) => log,
+        Err(x) if x.kind() == std::io::ErrorKind::NotFound => return Ok(Recovered::default()),
+        Err(x) => return Err(format!("error reading {}: {}", path.display(), x).into()),
+    };
+    log.truncate(
+        log.iter()
+            .rposition(|x| *x == b'\n')
+            .map_or(0, |end| end + 1),
+    );
+    let mut reader = csv::Reader::from_reader(log.as_slice());
+    let headers = reader.headers()?.clone();
+    let schema = Schema::from_headers(&headers, &[])?
+        .with_precision(engine.db.policy.precision)
+        .with_memo_max_len(usize::MAX);
+    let mut recovered = Recovered::default();
+    for record in reader.records() {
+        let record = record?;
+        let row = match record.get(0).unwrap_or("") {
+            "" => None,
+            row => Some(row.parse::<u64>()?),
+        };
+        if row.is_some_and(|row| row <= after) {
+            continue;
+        }
+        let txn = schema.parse(&record)?;
+        match record.get(1) == Some("true") {
+            true => engine.process_internal(txn)?,
+            false => engine.process(txn)?,
+        };
+        recovered.entries += 1;
+        recovered.rows = recovered.rows.max(row.unwrap_or_default());
+    }
+    Ok(recovered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::SimulatedClock, state::export_state};
+    use std::io::Write;
+
+    #[test]
+    fn a_killed_run_is_recovered_from_its_log() -> Result<()> {
+        let path = crate::temp_path("payments-engine-wal-test.csv");
+        let engine = Engine::with_clock(SimulatedClock::new(100)).with_wal(Wal::create(&path)?);
+        engine.process(Transaction::deposit(1, 1, 10.0))?;
+        engine.process(Transaction::withdrawal(1, 2, 3.0))?;
+        engine.process(Transaction::withdrawal(1, 3, 30.0))?;
+        engine.process(Transaction::dispute(1, 1))?;
+        // Killed while writing the next entry.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b",false,deposit,1,4,5")?;
+
+        let recovering = Engine::with_clock(SimulatedClock::new(500));
+        let recovered = recover(&path, &recovering, 0)?;
+        assert_eq!(recovered.entries, 4);
+        assert_eq!(export_state(&recovering.db)?, export_state(&engine.db)?);
+
+        engine.commit_wal()?;
+        let empty = Engine::default();
+        assert_eq!(recover(&path, &empty, 0)?.entries, 0);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{
+    account::AccountView, clock::SimulatedClock, disputes::auto_chargeback, open_file_read_csv,
+    policy::Policy, run_engine, ClientId, Engine, Result,
+};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+/// A client whose account ends up differently under another policy.
+/// Either side is `None` if the client doesn't exist in that run.
+pub struct AccountDiff {
+    pub client_id: ClientId,
+    pub original: Option<AccountView>,
+    pub what_if: Option<AccountView>,
+}
+
+/// Replays `capture` under the default policy and under `policy`, and
+/// returns the clients whose accounts differ between the two, by client.
+pub fn what_if(capture: String, policy: Policy) -> Result<Vec<AccountDiff>> {
+    let replay = |policy: Policy| -> Result<Engine> {
+        let engine = Engine::with_clock(SimulatedClock::new(0)).with_policy(policy);
+        run_engine(open_file_read_csv(capture.clone())?, &engine)?;
+        auto_chargeback(&engine)?;
+        Ok(engine)
+    };
+    diff_accounts(&replay(Policy::default())?, &replay(policy)?)
+}
+
+/// The account of every client of `engine`, evicted ones included, by
+/// client.
+pub fn accounts(engine: &Engine) -> Result<BTreeMap<ClientId, AccountView>> {
+    let mut accounts = BTreeMap::new();
+    engine.db.for_each_client(|client_id, client| {
+        accounts.insert(client_id, AccountView::from_client(client_id, client));
+    })?;
+    Ok(accounts)
+}
+
+/// The clients whose accounts differ between two engines, by client.
+pub fn diff_accounts(original: &Engine, what_if: &Engine) -> Result<Vec<AccountDiff>> {
+    let mut original = accounts(original)?;
+    let mut what_if = accounts(what_if)?;
+    let clients: std::collections::BTreeSet<ClientId> =
+        original.keys().chain(what_if.keys()).copied().collect();
+    Ok(clients
+        .into_iter()
+        .map(|client_id| AccountDiff {
+            client_id,
+            original: original.remove(&client_id),
+            what_if: what_if.remove(&client_id),
+        })
+        .filter(|diff| diff.original != diff.what_if)
+        .collect())
+}
+
+/// Prints the differences as csv to stdout, each balance of a client
+/// in the original run followed by its value in the other one, in the
+/// columns named `label`.
+pub fn print_what_if(diffs: &[AccountDiff], label: &str) {
+    println!(
+        "{:>7}, {:>12}, {:>12}, {:>12}, {:>12}, {:>12}, {:>12}",
+        "client", "available", label, "held", label, "locked", label
+    );
+    let balance =
+        |account: &Option<AccountView>, f: fn(&AccountView) -> f64| account.as_ref().map_or(0.0, f);
+    let locked = |account: &Option<AccountView>| account.as_ref().is_some_and(|a| a.locked());
+    for diff in diffs {
+        println!(
+            "{:>7}, {:>12.4}, {:>12.4}, {:>12.4}, {:>12.4}, {:>12}, {:>12}",
+            diff.client_id,
+            balance(&diff.original, AccountView::available),
+            balance(&diff.what_if, AccountView::available),
+            balance(&diff.original, AccountView::held),
+            balance(&diff.what_if, AccountView::held),
+            locked(&diff.original),
+            locked(&diff.what_if)
+        );
+    }
+}